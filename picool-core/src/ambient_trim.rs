@@ -0,0 +1,62 @@
+use crate::temperature::{Celsius, DegreesDelta};
+
+/// Feed-forward on the ambient temperature: on a hot day pull-down takes longer, so cooling starts earlier.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AmbientTrimConfig {
+    /// Degrees the thresholds move per degree of ambient away from the reference, 0 disables the trim.
+    pub gain: f32,
+    pub reference: Celsius,
+    /// The most the thresholds move either way.
+    pub limit: DegreesDelta,
+}
+
+impl Default for AmbientTrimConfig {
+    fn default() -> Self {
+        Self {
+            gain: 0.0,
+            reference: Celsius(20.0),
+            limit: DegreesDelta(0.5),
+        }
+    }
+}
+
+impl AmbientTrimConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.gain != 0.0
+    }
+
+    // Pure
+    /// The shift applied to both thresholds, negative when the ambient is warmer than the reference. Zero without an
+    /// ambient reading.
+    pub fn trim(&self, ambient: Option<Celsius>) -> DegreesDelta {
+        match ambient {
+            Some(ambient) if self.is_enabled() => {
+                let limit = self.limit.abs().0;
+                DegreesDelta((-self.gain * (ambient - self.reference).0).clamp(-limit, limit))
+            }
+            _ => DegreesDelta::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trims_against_the_ambient() {
+        let config = AmbientTrimConfig {
+            gain: 0.05,
+            ..AmbientTrimConfig::default()
+        };
+        assert!((config.trim(Some(Celsius(30.0))).0 + 0.5).abs() < 1e-6);
+        assert!((config.trim(Some(Celsius(16.0))).0 - 0.2).abs() < 1e-6);
+        assert_eq!(DegreesDelta(-0.5), config.trim(Some(Celsius(45.0))));
+        assert_eq!(DegreesDelta(0.5), config.trim(Some(Celsius(-30.0))));
+        assert_eq!(DegreesDelta::ZERO, config.trim(None));
+        assert_eq!(
+            DegreesDelta::ZERO,
+            AmbientTrimConfig::default().trim(Some(Celsius(35.0)))
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::temperature::Celsius;
+use anyhow::{anyhow, bail, Result};
+use std::{fmt, ops::Range, str::FromStr, time::Duration};
+
+/// A temporary band, held until it runs out or is cancelled, e.g. to pull a fresh load down quickly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Boost {
+    pub band: Range<Celsius>,
+    pub remaining: Duration,
+}
+
+impl Boost {
+    pub fn validate(&self) -> Result<()> {
+        if !self.band.start.is_finite() || !self.band.end.is_finite() || self.band.start >= self.band.end {
+            bail!(
+                "A boost band needs a bottom below its top, got {} to {}.",
+                self.band.start.0,
+                self.band.end.0
+            );
+        }
+        if self.remaining == Duration::from_secs(0) {
+            bail!("A boost needs a duration.");
+        }
+        Ok(())
+    }
+}
+
+/// Sent on the control socket, temperatures in Celsius whatever the display units.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoostRequest {
+    Start(Boost),
+    Cancel,
+}
+
+/// `<bottom> <top> <seconds>` or `cancel`.
+impl fmt::Display for BoostRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BoostRequest::Start(boost) => write!(
+                f,
+                "{:.3} {:.3} {}",
+                boost.band.start.0,
+                boost.band.end.0,
+                boost.remaining.as_secs()
+            ),
+            BoostRequest::Cancel => write!(f, "cancel"),
+        }
+    }
+}
+
+impl FromStr for BoostRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let request = match fields.as_slice() {
+            ["cancel"] => BoostRequest::Cancel,
+            [bottom, top, seconds] => {
+                let temperature = |t: &str| {
+                    t.parse()
+                        .map(Celsius)
+                        .map_err(|e| anyhow!("Invalid boost '{}'. {}", s, e))
+                };
+                BoostRequest::Start(Boost {
+                    band: temperature(bottom)?..temperature(top)?,
+                    remaining: Duration::from_secs(
+                        seconds.parse().map_err(|e| anyhow!("Invalid boost '{}'. {}", s, e))?,
+                    ),
+                })
+            }
+            _ => bail!("A boost is '<bottom> <top> <seconds>' or 'cancel', got '{}'.", s),
+        };
+        if let BoostRequest::Start(boost) = &request {
+            boost.validate()?;
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_over_the_wire() {
+        let start = BoostRequest::Start(Boost {
+            band: Celsius(0.5)..Celsius(1.5),
+            remaining: Duration::from_secs(3 * 3600),
+        });
+        assert_eq!("0.500 1.500 10800", start.to_string());
+        assert_eq!(start, start.to_string().parse().unwrap());
+        assert_eq!(BoostRequest::Cancel, "cancel".parse().unwrap());
+        assert!("2 1 60".parse::<BoostRequest>().is_err());
+        assert!("1 2 0".parse::<BoostRequest>().is_err());
+        assert!("1 NaN 60".parse::<BoostRequest>().is_err());
+        assert!("1 2".parse::<BoostRequest>().is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::temperature::DegreesDelta;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Margin over the measured overshoot, a cap right at it would be hit again by the next slightly longer tail.
+const SUGGESTION_MARGIN: f32 = 1.25;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CapAlertConfig {
+    /// Consecutive capped cycles before the alert, 0 disables it.
+    pub cycles: u32,
+    /// Consecutive cycles off the cap before the alert clears.
+    pub clear_after: u32,
+    /// How often the alert repeats while the compensator stays capped.
+    pub repeat: Duration,
+}
+
+impl Default for CapAlertConfig {
+    fn default() -> Self {
+        Self {
+            cycles: 10,
+            clear_after: 3,
+            repeat: Duration::from_secs(60 * 60 * 24),
+        }
+    }
+}
+
+/// Which threshold a compensator moves.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compensation {
+    Cooling,
+    Heating,
+}
+
+impl fmt::Display for Compensation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compensation::Cooling => write!(f, "Cooling"),
+            Compensation::Heating => write!(f, "Heating"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CapChange {
+    /// Raised, or repeated while still capped, with the measured median overshoot.
+    Capped { cycles: u32, overshoot: DegreesDelta },
+    /// Off the cap again after `cycles` capped ones.
+    Cleared { cycles: u32 },
+}
+
+/// Counts the cycles a compensator ends at its cap. Sitting there means the cap, not the chamber, decides the
+/// threshold, and the temperature carries past the band every cycle.
+pub struct CapAlert {
+    config: CapAlertConfig,
+    capped: u32,
+    uncapped: u32,
+    alerted_at: Option<Instant>,
+}
+
+impl CapAlert {
+    pub fn new(config: CapAlertConfig) -> Self {
+        Self {
+            config,
+            capped: 0,
+            uncapped: 0,
+            alerted_at: None,
+        }
+    }
+
+    /// Called after each observation the compensator is fed, `overshoot` the median it measured.
+    pub fn observe(&mut self, now: Instant, capped: bool, overshoot: DegreesDelta) -> Option<CapChange> {
+        if self.config.cycles == 0 {
+            return None;
+        }
+        if capped {
+            self.capped += 1;
+            self.uncapped = 0;
+            if self.capped < self.config.cycles || self.alerted_at.is_some_and(|at| now - at < self.config.repeat) {
+                return None;
+            }
+            self.alerted_at = Some(now);
+            return Some(CapChange::Capped {
+                cycles: self.capped,
+                overshoot,
+            });
+        }
+        self.uncapped += 1;
+        if self.alerted_at.is_none() {
+            self.capped = 0;
+            return None;
+        }
+        if self.uncapped < self.config.clear_after {
+            return None;
+        }
+        self.alerted_at = None;
+        Some(CapChange::Cleared {
+            cycles: std::mem::take(&mut self.capped),
+        })
+    }
+}
+
+// Pure
+/// A cap that would have covered `overshoot`, rounded up to a tenth of a degree.
+pub fn suggested_cap(overshoot: DegreesDelta) -> DegreesDelta {
+    DegreesDelta((overshoot.0.abs() * SUGGESTION_MARGIN * 10.0).ceil() / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hours(h: u64) -> Duration {
+        Duration::from_secs(h * 3600)
+    }
+
+    #[test]
+    fn raises_after_consecutive_capped_cycles_and_repeats_daily() {
+        let start = Instant::now();
+        let mut alert = CapAlert::new(CapAlertConfig::default());
+        let overshoot = DegreesDelta(2.4);
+        // An uncapped cycle before the alert starts the count over.
+        let mut pattern = vec![true; 9];
+        pattern.push(false);
+        pattern.extend(vec![true; 60]);
+        let changes: Vec<_> = pattern
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &capped)| Some((i, alert.observe(start + hours(i as u64), capped, overshoot)?)))
+            .collect();
+        assert_eq!(
+            vec![
+                (19, CapChange::Capped { cycles: 10, overshoot }),
+                (43, CapChange::Capped { cycles: 34, overshoot }),
+                (67, CapChange::Capped { cycles: 58, overshoot }),
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn clears_after_several_uncapped_cycles() {
+        let start = Instant::now();
+        let mut alert = CapAlert::new(CapAlertConfig::default());
+        for i in 0..10 {
+            alert.observe(start + hours(i), true, DegreesDelta(2.0));
+        }
+        assert_eq!(None, alert.observe(start + hours(10), false, DegreesDelta(1.5)));
+        assert_eq!(None, alert.observe(start + hours(11), false, DegreesDelta(1.5)));
+        // Back on the cap, the clearing count starts over.
+        assert_eq!(None, alert.observe(start + hours(12), true, DegreesDelta(2.0)));
+        assert_eq!(None, alert.observe(start + hours(13), false, DegreesDelta(1.5)));
+        assert_eq!(None, alert.observe(start + hours(14), false, DegreesDelta(1.5)));
+        assert_eq!(
+            Some(CapChange::Cleared { cycles: 11 }),
+            alert.observe(start + hours(15), false, DegreesDelta(1.5))
+        );
+        assert_eq!(None, alert.observe(start + hours(16), false, DegreesDelta(1.5)));
+    }
+
+    #[test]
+    fn disabled_with_zero_cycles() {
+        let mut alert = CapAlert::new(CapAlertConfig {
+            cycles: 0,
+            ..CapAlertConfig::default()
+        });
+        let start = Instant::now();
+        assert!((0..100).all(|i| alert.observe(start + hours(i), true, DegreesDelta(3.0)).is_none()));
+    }
+
+    #[test]
+    fn suggests_a_rounded_up_cap_with_margin() {
+        assert_eq!(DegreesDelta(3.0), suggested_cap(DegreesDelta(2.4)));
+        assert_eq!(DegreesDelta(3.1), suggested_cap(DegreesDelta(-2.41)));
+    }
+}
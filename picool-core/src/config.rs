@@ -0,0 +1,416 @@
+use crate::{
+    ambient_trim::AmbientTrimConfig,
+    cap_alert::CapAlertConfig,
+    cooling_monitor::CoolingMonitorConfig,
+    current::CurrentConfig,
+    differential::DifferentialConfig,
+    duty_alert::DutyAlertConfig,
+    early_shutoff::TailEstimate,
+    energy::EnergyConfig,
+    failover::FailoverConfig,
+    failsafe::FailsafeConfig,
+    fan::FanConfig,
+    incidents::IncidentConfig,
+    peak::PeakConfig,
+    pump::PumpConfig,
+    quiet_hours::QuietHoursConfig,
+    rest::RestConfig,
+    runaway::RunawayConfig,
+    sampling::SamplingConfig,
+    shed::ShedConfig,
+    staging::StagingConfig,
+    temperature::{Celsius, DegreesDelta, Millidegrees},
+    termination::StorageConfig,
+    timing_advisor::TimingConfig,
+    tpc::TpcConfig,
+    Edge, MinimumIntervals, Thresholds, LOW_COMPENSATION_RESET_MARGIN, MAX_COMPENSATION, TARGET_RANGE,
+};
+use anyhow::{bail, Result};
+use std::{fmt, ops::Range, str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub band: Band,
+    pub hysteresis: Hysteresis,
+    pub intervals: MinimumIntervals,
+    pub sensor_failsafe: FailsafeConfig,
+    pub runaway: RunawayConfig,
+    pub duty_alert: DutyAlertConfig,
+    pub cooling: CoolingMonitorConfig,
+    pub cap_alert: CapAlertConfig,
+    pub incidents: IncidentConfig,
+    pub stall: StallConfig,
+    pub storage: StorageConfig,
+    pub energy: EnergyConfig,
+    pub ambient_trim: AmbientTrimConfig,
+    pub differential: DifferentialConfig,
+    pub fan: FanConfig,
+    pub early_shutoff: TailEstimate,
+    pub mode: ControlMode,
+    pub tpc: TpcConfig,
+    pub sampling: SamplingConfig,
+    pub timing: TimingConfig,
+    pub rest: RestConfig,
+    pub quiet_hours: QuietHoursConfig,
+    pub peak: PeakConfig,
+    pub shed: ShedConfig,
+    pub staging: StagingConfig,
+    pub pump: PumpConfig,
+    pub current: CurrentConfig,
+    pub failover: FailoverConfig,
+}
+
+impl Config {
+    /// Combinations of options that can't work together.
+    pub fn validate(&self) -> Result<()> {
+        if self.mode == ControlMode::Tpc {
+            self.tpc.validate(self.intervals)?;
+        }
+        self.hysteresis.validate()?;
+        if !self.band.target.start.0.is_finite() || !self.band.target.end.0.is_finite() {
+            bail!(
+                "The target range has to be finite, got {} to {}.",
+                self.band.target.start.0,
+                self.band.target.end.0
+            );
+        }
+        if self.band.target.is_empty() {
+            bail!(
+                "The target range has to run from a lower to a higher temperature, got {} to {}.",
+                self.band.target.start,
+                self.band.target.end
+            );
+        }
+        if self.band.max_compensation.0.is_nan() || self.band.max_compensation.0 < 0.01 {
+            bail!(
+                "Maximum compensation must be at least 0.01C, got {}.",
+                self.band.max_compensation.0
+            );
+        }
+        self.differential.validate()?;
+        self.fan.validate()?;
+        self.sampling.validate()?;
+        if self.timing.cycles == Some(0) {
+            bail!("The timing advisor needs at least one cycle between recommendations.");
+        }
+        if self.timing.cycles.is_some() && self.mode != ControlMode::Hysteresis {
+            bail!("The timing advisor only tunes hysteresis control, the duty window sets its own timing.");
+        }
+        if self.timing.auto_apply && self.timing.cycles.is_none() {
+            bail!("Applying timing recommendations needs the timing advisor enabled.");
+        }
+        if !self.peak.windows.is_empty() && self.mode != ControlMode::Hysteresis {
+            bail!("Peak windows only shift hysteresis control, the duty window sets its own timing.");
+        }
+        if self.peak.excursion.is_nan() || self.peak.excursion.0 < 0.0 {
+            bail!("The peak excursion can't be negative, got {}.", self.peak.excursion.0);
+        }
+        if self.quiet_hours.guard.is_nan() || self.quiet_hours.guard.0 < 0.0 {
+            bail!(
+                "The quiet hours guard can't be negative, got {}.",
+                self.quiet_hours.guard.0
+            );
+        }
+        if self.shed.excursion.is_nan() || self.shed.excursion.0 < 0.0 {
+            bail!("The shed excursion can't be negative, got {}.", self.shed.excursion.0);
+        }
+        if self.shed.max_duration.is_zero() {
+            bail!("A load shed needs a maximum duration.");
+        }
+        if self.current.enabled {
+            self.current.validate()?;
+        }
+        if self.failover.enabled && !self.current.enabled {
+            bail!("Failing over to a backup output needs a current sensor to tell the primary failed.");
+        }
+        if self.failover.after == 0 {
+            bail!("Failing over needs at least one failed check.");
+        }
+        if self.staging.enabled {
+            self.staging.validate(self.band.target.end - self.band.target.start)?;
+        }
+        if self.rest.skip_boost && self.rest.after.is_none() {
+            bail!("Skipping the rest after boosted runs needs a rest enabled.");
+        }
+        if self.rest.after.is_some() && self.rest.rest <= self.intervals.off {
+            bail!(
+                "A rest has to be longer than the minimum off interval of {}m, got {}m.",
+                self.intervals.off.as_secs() / 60,
+                self.rest.rest.as_secs() / 60
+            );
+        }
+        Ok(())
+    }
+
+    /// Takes the band, the compensation cap, the hysteresis, the minimum intervals and the sampling from `reloaded`,
+    /// everything else keeps running as it is.
+    pub fn reload(&self, reloaded: &Config) -> Result<Reloaded> {
+        let config = Config {
+            band: reloaded.band.clone(),
+            hysteresis: reloaded.hysteresis,
+            intervals: reloaded.intervals,
+            sampling: reloaded.sampling,
+            ..self.clone()
+        };
+        config.validate()?;
+        let mut changes = Vec::new();
+        if config.band.target != self.band.target {
+            changes.push(format!(
+                "band {} to {}, was {} to {}",
+                config.band.target.start, config.band.target.end, self.band.target.start, self.band.target.end
+            ));
+        }
+        if config.band.max_compensation != self.band.max_compensation {
+            changes.push(format!(
+                "compensation up to {}, was {}",
+                config.band.max_compensation, self.band.max_compensation
+            ));
+        }
+        if config.hysteresis != self.hysteresis {
+            let hysteresis = config.hysteresis;
+            changes.push(format!(
+                "hysteresis {} and {} past the low threshold, {} and {} past the high",
+                hysteresis.low_engage, hysteresis.low_release, hysteresis.high_engage, hysteresis.high_release
+            ));
+        }
+        let minutes = |duration: Duration| duration.as_secs() / 60;
+        if config.intervals != self.intervals {
+            changes.push(format!(
+                "minimum on {}m off {}m, was {}m and {}m",
+                minutes(config.intervals.on),
+                minutes(config.intervals.off),
+                minutes(self.intervals.on),
+                minutes(self.intervals.off)
+            ));
+        }
+        if config.sampling != self.sampling {
+            changes.push(format!(
+                "sampling every {}s, was {}s",
+                config.sampling.interval.as_secs_f32(),
+                self.sampling.interval.as_secs_f32()
+            ));
+        }
+        Ok(Reloaded {
+            needs_restart: config != *reloaded,
+            config,
+            changes,
+        })
+    }
+}
+
+/// A configuration re-read while running, as far as it applies without a restart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloaded {
+    /// The running configuration with what changed in the band, the hysteresis and the intervals.
+    pub config: Config,
+    /// What changed, one description each.
+    pub changes: Vec<String>,
+    /// Something else changed too, it only takes effect after a restart.
+    pub needs_restart: bool,
+}
+
+/// How readings become relay positions.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum ControlMode {
+    /// On above the high threshold, off below the low one.
+    #[default]
+    Hysteresis,
+    /// A PI duty over fixed windows, held around the middle of the band.
+    Tpc,
+}
+
+impl FromStr for ControlMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hysteresis" => Ok(ControlMode::Hysteresis),
+            "tpc" => Ok(ControlMode::Tpc),
+            _ => bail!("Unknown control mode '{}', expected hysteresis or tpc.", s),
+        }
+    }
+}
+
+impl fmt::Display for ControlMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlMode::Hysteresis => write!(f, "hysteresis"),
+            ControlMode::Tpc => write!(f, "tpc"),
+        }
+    }
+}
+
+/// The range the chamber is held in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Band {
+    pub target: Range<Celsius>,
+    /// How far the learned compensation may move either threshold into the band. Kept to half the width, the
+    /// thresholds can't cross.
+    pub max_compensation: DegreesDelta,
+}
+
+impl Default for Band {
+    fn default() -> Self {
+        Self {
+            target: TARGET_RANGE,
+            max_compensation: MAX_COMPENSATION,
+        }
+    }
+}
+
+impl Band {
+    /// Warmer than this the cooling compensation is dropped, the chamber is being loaded or was opened.
+    pub fn low_compensation_reset(&self) -> Celsius {
+        self.target.end + LOW_COMPENSATION_RESET_MARGIN
+    }
+
+    pub fn width(&self) -> DegreesDelta {
+        self.target.end - self.target.start
+    }
+
+    pub fn middle(&self) -> Celsius {
+        self.target.start + self.width() / 2.0
+    }
+
+    /// The compensation cap in millidegrees, rounded like every other conversion but no more than half the band, so
+    /// the fully compensated thresholds can't cross.
+    pub fn compensation_cap(&self) -> Millidegrees {
+        let half = Millidegrees((self.target.end.millidegrees().0 - self.target.start.millidegrees().0) / 2);
+        self.max_compensation.millidegrees().min(half)
+    }
+}
+
+/// How far past each compensated threshold the relay switches, and how far back the temperature has to come before
+/// that side counts as recovered. All zero switches and recovers at the thresholds.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Hysteresis {
+    /// Below the low threshold before the relay switches off.
+    pub low_engage: DegreesDelta,
+    /// Back above the low threshold before the relay may switch on.
+    pub low_release: DegreesDelta,
+    /// Above the high threshold before the relay switches on.
+    pub high_engage: DegreesDelta,
+    /// Back below the high threshold before the relay may switch off.
+    pub high_release: DegreesDelta,
+}
+
+impl Hysteresis {
+    fn validate(&self) -> Result<()> {
+        for offset in &[self.low_engage, self.low_release, self.high_engage, self.high_release] {
+            if offset.0.is_nan() || offset.0 < 0.0 {
+                bail!("Hysteresis offsets must be zero or more, got {}.", offset.0);
+            }
+        }
+        Ok(())
+    }
+
+    /// The engage and release pairs around the compensated thresholds, so both move with the compensation.
+    pub fn thresholds(&self, low: Millidegrees, high: Millidegrees) -> Thresholds {
+        Thresholds {
+            low: Edge {
+                engage: low - self.low_engage.millidegrees(),
+                release: low + self.low_release.millidegrees(),
+            },
+            high: Edge {
+                engage: high + self.high_engage.millidegrees(),
+                release: high - self.high_release.millidegrees(),
+            },
+        }
+    }
+}
+
+/// Defaults for a kind of chamber, options given alongside override them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Preset {
+    Fridge,
+    Freezer,
+    /// A chest freezer holding kegs at serving temperature.
+    Keezer,
+    /// A fridge holding an ale's fermentation temperature in a warmer room.
+    Fermenter,
+}
+
+impl FromStr for Preset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fridge" => Ok(Preset::Fridge),
+            "freezer" => Ok(Preset::Freezer),
+            "keezer" => Ok(Preset::Keezer),
+            "fermenter" => Ok(Preset::Fermenter),
+            "incubator" => bail!("There is no incubator preset, picool only switches cooling."),
+            _ => bail!("Unknown preset '{}', expected fridge, freezer, keezer or fermenter.", s),
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Preset::Fridge => write!(f, "fridge"),
+            Preset::Freezer => write!(f, "freezer"),
+            Preset::Keezer => write!(f, "keezer"),
+            Preset::Fermenter => write!(f, "fermenter"),
+        }
+    }
+}
+
+impl Preset {
+    pub const ALL: [Preset; 4] = [Preset::Fridge, Preset::Freezer, Preset::Keezer, Preset::Fermenter];
+
+    pub fn config(self) -> Config {
+        let band = |target: Range<Celsius>| Band {
+            max_compensation: (target.end - target.start) / 2.0,
+            target,
+        };
+        match self {
+            Preset::Fridge => Config::default(),
+            Preset::Freezer => {
+                let mut config = Config {
+                    band: band(Celsius(-20.0)..Celsius(-17.0)),
+                    ..Config::default()
+                };
+                // A chest freezer runs long cycles and takes hours to pull down from room temperature.
+                config.runaway.max_on = Duration::from_secs(60 * 120);
+                config.duty_alert.pull_down_grace = Duration::from_secs(60 * 60 * 12);
+                config
+            }
+            Preset::Keezer => {
+                let mut config = Config {
+                    band: band(Celsius(2.0)..Celsius(4.5)),
+                    ..Config::default()
+                };
+                // A freezer's compressor is oversized for the load, longer rests spare it short cycling, and a
+                // fresh load of room temperature kegs takes most of a day to pull down.
+                config.intervals.off = Duration::from_secs(60 * 10);
+                config.duty_alert.pull_down_grace = Duration::from_secs(60 * 60 * 18);
+                config
+            }
+            Preset::Fermenter => {
+                let mut config = Config {
+                    band: band(Celsius(18.0)..Celsius(20.0)),
+                    ..Config::default()
+                };
+                // Active fermentation makes its own heat, the relay runs long while it peaks.
+                config.runaway.max_on = Duration::from_secs(60 * 90);
+                config.duty_alert.pull_down_grace = Duration::from_secs(60 * 60 * 6);
+                config
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StallConfig {
+    /// Poll intervals without progress before the loop counts as stalled, 0 disables the monitor.
+    pub polls: u32,
+    /// Abort so the supervisor restarts us, the relay is then left to the pin's reset-on-exit behavior.
+    pub abort: bool,
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self { polls: 6, abort: false }
+    }
+}
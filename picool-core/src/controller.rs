@@ -0,0 +1,2884 @@
+use crate::{
+    ambient_trim::AmbientTrimConfig,
+    boost::{Boost, BoostRequest},
+    cap_alert::{suggested_cap, CapAlert, CapChange, Compensation},
+    config::{Band, Config, ControlMode, Hysteresis},
+    cooling_monitor::{CoolingChange, CoolingMonitor},
+    current::{CurrentMonitor, LoadChange, LoadFault},
+    cycle_stats::CycleStats,
+    determine_initial_state,
+    differential::{Clamp, DifferentialConfig},
+    duty_alert::{DutyAlert, DutyAlertChange},
+    early_shutoff::ShutoffPredictor,
+    energy::{EnergyMeter, EnergyTotals, LocalTime},
+    failover::{Failover, FailoverChange, Output},
+    failsafe::SensorFailsafe,
+    fan::Fan,
+    heartbeat::HeartbeatStatus,
+    incidents::Incidents,
+    notify::Event,
+    overshoot::{overshoot, undershoot, End, OvershootTracker, Overshoots},
+    peak::{PeakShift, PeakShifter},
+    pump::{Pump, PumpChange, PumpGate},
+    quiet_hours::{QuietHours, QuietStats},
+    rest::{RestPeriod, RestRule},
+    runaway::RunawayDetector,
+    runtime::RuntimeCounter,
+    sampling::Sampler,
+    shed::{LoadShed, ShedChange, ShedEnd, ShedPeriod},
+    staging::SecondStage,
+    starts::{StartCounter, StartCounts},
+    temperature::{Celsius, DegreesDelta, Millidegrees, Rate},
+    termination::Termination,
+    timing_advisor::TimingAdvisor,
+    tpc::{DutyWindow, PiController},
+    transition,
+    trend::TrendEstimator,
+    world_error::{SensorError, SensorErrorCounts},
+    Compensator, CycleExtremes, ExtremeTracker, MinimumIntervals, RestoredPowerState, Seed, State, CYCLE_HISTORY,
+    EXTREMES_CHECKPOINT_INTERVAL, TREND_WINDOW,
+};
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    mem::replace,
+    ops::Range,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use strum_macros::Display;
+
+/// One attempt at reading the sensor.
+pub struct Input {
+    pub temperature: Result<Celsius, SensorError>,
+    pub now: Instant,
+    pub local: LocalTime,
+    /// From the ambient sensor when one is configured and read successfully.
+    pub ambient: Option<Celsius>,
+    /// A fault acknowledgement arrived on the control socket since the last reading.
+    pub acknowledged: bool,
+    /// A runtime counter reset arrived on the control socket since the last reading.
+    pub reset_runtime: bool,
+    /// A different sensor took the place of the configured one since the last reading.
+    pub sensor_replaced: bool,
+    /// The last boost request that arrived on the control socket since the last reading.
+    pub boost: Option<BoostRequest>,
+    /// A request to go back to the configured minimum intervals arrived on the control socket since the last reading.
+    pub revert_timing: bool,
+    /// The demand response signal is asserted.
+    pub shed: bool,
+    /// What the pump output reads back, None without a pump or when it can't be read.
+    pub pump: Option<bool>,
+    /// Amps from the current sensor when one is configured and read successfully.
+    pub current: Option<f32>,
+    /// A request to clear the primary output's fault arrived on the control socket since the last reading.
+    pub reset_failover: bool,
+    /// The configuration re-read since the last reading, only what applies without a restart changed.
+    pub reload: Option<Config>,
+}
+
+/// Why the state machine moved.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display)]
+pub enum TransitionReason {
+    #[strum(serialize = "threshold")]
+    Threshold,
+    /// A minimum on or off interval ran out.
+    #[strum(serialize = "minimum-interval")]
+    MinimumInterval,
+    /// The time proportional window.
+    #[strum(serialize = "duty")]
+    Duty,
+    #[strum(serialize = "sensor-failsafe")]
+    SensorFailsafe,
+    #[strum(serialize = "sensor-recovered")]
+    SensorRecovered,
+    #[strum(serialize = "thermal-runaway")]
+    ThermalRunaway,
+    #[strum(serialize = "runaway-cleared")]
+    RunawayCleared,
+    /// A load shed ended the run early.
+    #[strum(serialize = "load-shed")]
+    LoadShed,
+    /// A start that waited on the pump's lead.
+    #[strum(serialize = "pump-primed")]
+    PumpPrimed,
+}
+
+impl FromStr for TransitionReason {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use TransitionReason::*;
+        [
+            Threshold,
+            MinimumInterval,
+            Duty,
+            SensorFailsafe,
+            SensorRecovered,
+            ThermalRunaway,
+            RunawayCleared,
+            LoadShed,
+            PumpPrimed,
+        ]
+        .iter()
+        .find(|reason| reason.to_string() == s)
+        .copied()
+        .ok_or_else(|| anyhow!("Unknown transition reason '{}'.", s))
+    }
+}
+
+/// A state change, for whatever the driver runs on one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub from: State,
+    pub to: State,
+    pub reason: TransitionReason,
+    /// None when the sensor failed.
+    pub temperature: Option<Celsius>,
+}
+
+/// An on cycle that ended, for the cycle history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleRecord {
+    /// How long the compressor ran.
+    pub on: Duration,
+    /// How long it was off before, None for the first start since control began.
+    pub off: Option<Duration>,
+    /// The extremes of the readings over the run, None without any.
+    pub min: Option<Celsius>,
+    pub max: Option<Celsius>,
+    /// How far the peak went past the top of the band.
+    pub overshoot: Option<DegreesDelta>,
+    /// Degrees per minute it cooled at, when the run was long and ordinary enough to measure.
+    pub cooling_rate: Option<f32>,
+    /// Why the compressor stopped.
+    pub reason: TransitionReason,
+    /// What made the cycle unfit to learn from, e.g. a boost.
+    pub abnormal: Option<String>,
+}
+
+/// What the driver has to carry out in the world, in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    SetPower(bool),
+    /// The circulation fan output, only when one is configured.
+    SetFan(bool),
+    /// The second compressor, only when a stage 2 output is configured.
+    SetStage2(bool),
+    /// The glycol pump, only when a pump output is configured.
+    SetPump(bool),
+    /// Which relay switches the compressor, only when a backup output is configured.
+    SelectOutput(Output),
+    PersistLastOff,
+    PersistCompensation {
+        cooling: DegreesDelta,
+        heating: DegreesDelta,
+    },
+    PersistCoolingRates(Vec<f32>),
+    PersistEnergy(EnergyTotals),
+    PersistRuntime(Duration),
+    PersistStarts(StartCounts),
+    /// Kept against the wall clock so a restart honors what is left of it, None clears it.
+    PersistBoost(Option<Boost>),
+    /// The minimum intervals the timing advisor applied, None clears them.
+    PersistIntervals(Option<MinimumIntervals>),
+    PersistOvershoots(Overshoots),
+    /// A rest that started, None clears it once it is over.
+    PersistRest(Option<RestPeriod>),
+    /// A shed that started or was spent, None clears it once the signal is released.
+    PersistShed(Option<ShedPeriod>),
+    /// The cycle in progress, None clears it once the cycle ends.
+    PersistExtremes(Option<CycleExtremes>),
+    /// Appended to the cycle history.
+    RecordCycle(CycleRecord),
+    /// Whether the primary output is faulted, a restart keeps to the backup while it is.
+    PersistFailover(bool),
+    Notify(Event),
+    Transition(Transition),
+    Status(String),
+    Heartbeat(HeartbeatStatus),
+    ReinitializeSensor,
+    /// Nothing follows, the driver turns the relay off and exits.
+    Terminate(Termination),
+}
+
+impl Effect {
+    /// Whether carrying it out writes state to the store.
+    pub fn persists(&self) -> bool {
+        matches!(
+            self,
+            Effect::PersistLastOff
+                | Effect::PersistCompensation { .. }
+                | Effect::PersistCoolingRates(_)
+                | Effect::PersistEnergy(_)
+                | Effect::PersistRuntime(_)
+                | Effect::PersistStarts(_)
+                | Effect::PersistBoost(_)
+                | Effect::PersistIntervals(_)
+                | Effect::PersistOvershoots(_)
+                | Effect::PersistRest(_)
+                | Effect::PersistShed(_)
+                | Effect::PersistExtremes(_)
+                | Effect::RecordCycle(_)
+                | Effect::PersistFailover(_)
+        )
+    }
+}
+
+/// A change in trim that is logged at info, smaller ones follow the ambient quietly.
+const TRIM_LOG_STEP: DegreesDelta = DegreesDelta(0.05);
+
+/// A boost in progress, its band is the one in effect.
+struct ActiveBoost {
+    band: Range<Celsius>,
+    until: Instant,
+}
+
+/// Everything the control loop carries from one reading to the next.
+pub struct ControllerState {
+    state: State,
+    state_since: Instant,
+    /// Why the state machine last moved.
+    last_reason: TransitionReason,
+    band: Band,
+    /// The band to go back to when a boost ends.
+    configured_band: Range<Celsius>,
+    boost: Option<ActiveBoost>,
+    intervals: MinimumIntervals,
+    /// The intervals to go back to when tuned ones are reverted.
+    configured_intervals: MinimumIntervals,
+    timing: TimingAdvisor,
+    /// Whether the timing advisor's recommendations are applied, until they are reverted.
+    apply_timing: bool,
+    hysteresis: Hysteresis,
+    mode: ControlMode,
+    pi: PiController,
+    window: DutyWindow,
+    power_on: bool,
+    low_compensator: Compensator,
+    high_compensator: Compensator,
+    low_cap_alert: CapAlert,
+    high_cap_alert: CapAlert,
+    low_threshold: Millidegrees,
+    high_threshold: Millidegrees,
+    ambient_trim: AmbientTrimConfig,
+    ambient: Option<Celsius>,
+    /// Applied on top of the compensated thresholds.
+    trim: DegreesDelta,
+    logged_trim: DegreesDelta,
+    differential: DifferentialConfig,
+    /// Whether the limits hold the differential band, as last logged along with the bottom of the band.
+    clamp: Clamp,
+    logged_band: Celsius,
+    extremes: ExtremeTracker,
+    /// When the relay last switched, or the start of a resumed cycle.
+    cycle_since: Instant,
+    /// When the relay last switched off, None until it has since control began.
+    last_stop: Option<Instant>,
+    /// When the cycle in progress was last checkpointed, None until it is.
+    extremes_checkpoint: Option<Instant>,
+    sampler: Sampler,
+    cycles: u64,
+    completed_cycles: u32,
+    failsafe: SensorFailsafe,
+    reinitialize_attempts: u32,
+    reinitializations: u32,
+    sensor_errors: SensorErrorCounts,
+    trend: TrendEstimator,
+    runaway: RunawayDetector,
+    cycle_stats: CycleStats,
+    duty_alert: DutyAlert,
+    cooling_monitor: CoolingMonitor,
+    incidents: Incidents,
+    overshoots: OvershootTracker,
+    rest: RestRule,
+    quiet: QuietHours,
+    peak: PeakShifter,
+    shed: LoadShed,
+    /// A start was held back since the relay last turned off, the chamber got warmer than the thresholds allow.
+    held_start: bool,
+    predictor: ShutoffPredictor,
+    energy: Option<EnergyMeter>,
+    runtime: RuntimeCounter,
+    starts: StartCounter,
+    fan: Fan,
+    stage2: SecondStage,
+    pump: Pump,
+    current: CurrentMonitor,
+    failover: Failover,
+}
+
+impl ControllerState {
+    pub fn new(seed: Seed, config: &Config, now: Instant) -> Self {
+        info!(
+            "Initial state: {} Cooling Comp: {} Heating Comp: {}",
+            seed.state, seed.compensation.0, seed.compensation.1
+        );
+        let (low_compensator, high_compensator) = seed_compensators(&config.band, seed.compensation);
+        let power_on = seed.state.is_on();
+        let mut duty_alert = DutyAlert::new(config.duty_alert);
+        duty_alert.begin_pull_down(now);
+        let switched = match seed.state {
+            State::MinimumIntervalOn(since) => Some((true, since)),
+            State::MinimumIntervalOff(since) => Some((false, since)),
+            _ => None,
+        };
+        let intervals = match seed.intervals {
+            Some(tuned) if config.timing.auto_apply => {
+                let tuned = config.timing.limit(tuned);
+                info!(
+                    "Minimum intervals as tuned, {}m on and {}m off",
+                    tuned.on.as_secs() / 60,
+                    tuned.off.as_secs() / 60
+                );
+                tuned
+            }
+            Some(_) => {
+                info!("Tuned minimum intervals ignored, they are only applied with --auto-apply-timing");
+                config.intervals
+            }
+            None => config.intervals,
+        };
+        let resumed = match seed.extremes {
+            Some(extremes) if extremes.age > config.storage.checkpoint_max_age => {
+                info!(
+                    "Cycle extremes checkpointed {}m ago are too stale to resume from",
+                    extremes.age.as_secs() / 60
+                );
+                None
+            }
+            Some(extremes) => {
+                info!(
+                    "Resuming the {} cycle of {}m so far, from {} to {}",
+                    match extremes.on {
+                        true => "on",
+                        false => "off",
+                    },
+                    extremes.running.as_secs() / 60,
+                    extremes.min,
+                    extremes.max
+                );
+                Some(extremes)
+            }
+            None => None,
+        };
+        if config.mode == ControlMode::Tpc {
+            info!(
+                "Time-proportional control around {} over {}s windows",
+                config.band.middle(),
+                config.tpc.period.as_secs()
+            );
+        }
+        let mut controller = Self {
+            state: seed.state,
+            state_since: now,
+            last_reason: TransitionReason::Threshold,
+            band: config.band.clone(),
+            configured_band: config.band.target.clone(),
+            boost: None,
+            intervals,
+            configured_intervals: config.intervals,
+            timing: TimingAdvisor::new(config.timing),
+            apply_timing: config.timing.auto_apply,
+            hysteresis: config.hysteresis,
+            mode: config.mode,
+            pi: PiController::new(config.tpc),
+            window: DutyWindow::new(config.tpc, intervals, switched),
+            power_on,
+            low_threshold: low_compensator.get_threshold(),
+            high_threshold: high_compensator.get_threshold(),
+            low_compensator,
+            high_compensator,
+            low_cap_alert: CapAlert::new(config.cap_alert),
+            high_cap_alert: CapAlert::new(config.cap_alert),
+            ambient_trim: config.ambient_trim,
+            ambient: None,
+            trim: DegreesDelta::ZERO,
+            logged_trim: DegreesDelta::ZERO,
+            differential: config.differential,
+            clamp: Clamp::Free,
+            logged_band: config.band.target.start,
+            extremes: resumed
+                .as_ref()
+                .map_or_else(ExtremeTracker::new, ExtremeTracker::resume),
+            cycle_since: resumed
+                .and_then(|extremes| now.checked_sub(extremes.running))
+                .unwrap_or(now),
+            last_stop: None,
+            extremes_checkpoint: resumed.map(|_| now),
+            sampler: Sampler::new(config.sampling),
+            // A resumed cycle was already one to learn from.
+            cycles: match resumed {
+                Some(_) => 2,
+                None => 0,
+            },
+            completed_cycles: 0,
+            failsafe: SensorFailsafe::new(config.sensor_failsafe),
+            reinitialize_attempts: config.sensor_failsafe.reinitialize_attempts,
+            reinitializations: 0,
+            sensor_errors: SensorErrorCounts::default(),
+            trend: TrendEstimator::new(TREND_WINDOW),
+            runaway: RunawayDetector::new(config.runaway),
+            cycle_stats: CycleStats::new(now, power_on, CYCLE_HISTORY.max(config.duty_alert.window)),
+            duty_alert,
+            cooling_monitor: CoolingMonitor::new(config.cooling, seed.cooling_rates),
+            incidents: Incidents::new(config.incidents),
+            overshoots: OvershootTracker::new(seed.overshoots),
+            rest: RestRule::new(config.rest, seed.rest, now),
+            quiet: QuietHours::new(config.quiet_hours),
+            peak: PeakShifter::new(config.peak.clone()),
+            shed: LoadShed::new(config.shed, seed.shed, now),
+            held_start: false,
+            predictor: ShutoffPredictor::new(config.early_shutoff),
+            energy: EnergyMeter::new(config.energy, seed.energy, now),
+            runtime: RuntimeCounter::new(seed.runtime, now, power_on),
+            starts: StartCounter::new(seed.starts),
+            fan: Fan::new(config.fan, now),
+            stage2: SecondStage::new(config.staging, now),
+            pump: Pump::new(config.pump, now),
+            current: CurrentMonitor::new(config.current, now, power_on),
+            failover: Failover::new(config.failover, seed.failover, now),
+        };
+        if let Some(boost) = seed.boost {
+            controller.start_boost(boost, now);
+        }
+        if let Some(rest) = controller.rest.rest(now) {
+            info!(
+                "Resting the compressor {}m more after a {}m run",
+                rest.remaining.as_secs() / 60,
+                rest.run.as_secs() / 60
+            );
+        }
+        if let Some(shed) = controller.shed.shed(now) {
+            info!(
+                "Shedding load {}m more, {}m in",
+                shed.remaining.as_secs() / 60,
+                shed.since.as_secs() / 60
+            );
+        }
+        controller
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn power_on(&self) -> bool {
+        self.power_on
+    }
+
+    pub fn fan_on(&self) -> bool {
+        self.fan.is_on()
+    }
+
+    /// Whether stage 2 is running, with its runtime and starts since this run began. None unless a stage 2 output
+    /// is configured.
+    pub fn stage2(&self, now: Instant) -> Option<(bool, Duration, u32)> {
+        match self.stage2.is_enabled() {
+            true => Some((self.stage2.is_on(), self.stage2.runtime(now), self.stage2.starts())),
+            false => None,
+        }
+    }
+
+    /// Whether the pump is running and whether its output failed to read back, None unless a pump output is
+    /// configured.
+    pub fn pump(&self) -> Option<(bool, bool)> {
+        match self.pump.is_enabled() {
+            true => Some((self.pump.is_on(), self.pump.is_failed())),
+            false => None,
+        }
+    }
+
+    /// The compressor's current, None when the last read failed, and the fault it shows. None unless a current
+    /// sensor is configured.
+    pub fn current(&self) -> Option<(Option<f32>, Option<LoadFault>)> {
+        match self.current.is_enabled() {
+            true => Some((self.current.amps(), self.current.fault())),
+            false => None,
+        }
+    }
+
+    /// The relay switching the compressor and whether the primary is faulted, None unless a backup output is
+    /// configured.
+    pub fn output(&self) -> Option<(Output, bool)> {
+        match self.failover.is_enabled() {
+            true => Some((self.failover.active(), self.failover.is_faulted())),
+            false => None,
+        }
+    }
+
+    pub fn completed_cycles(&self) -> u32 {
+        self.completed_cycles
+    }
+
+    /// Accounts energy up to `now`, None when accounting is disabled. For persisting on the way out.
+    pub fn energy_checkpoint(&mut self, now: Instant, local: LocalTime) -> Option<EnergyTotals> {
+        let power_on = self.power_on;
+        let meter = self.energy.as_mut()?;
+        meter.update(now, local, power_on);
+        Some(meter.totals())
+    }
+
+    /// Lifetime compressor runtime up to `now`. For persisting on the way out.
+    pub fn runtime(&self, now: Instant) -> Duration {
+        self.runtime.total(now)
+    }
+
+    pub fn starts_today(&self) -> u32 {
+        self.starts.counts().today
+    }
+
+    /// The cooling rate of the last measurable on cycle in degrees per minute, and its fraction of the baseline.
+    pub fn cooling_rate(&self) -> Option<(f32, Option<f32>)> {
+        Some((self.cooling_monitor.latest()?, self.cooling_monitor.ratio()))
+    }
+
+    /// How far the latest learned cycles went past the top and below the bottom of the band.
+    pub fn overshoots(&self) -> (Option<DegreesDelta>, Option<DegreesDelta>) {
+        self.overshoots.latest()
+    }
+
+    /// The target band, as moved by differential mode or a boost.
+    pub fn band(&self) -> Range<Celsius> {
+        self.band.target.clone()
+    }
+
+    /// The boost band and how long it has left, None unless boosting.
+    pub fn boost(&self, now: Instant) -> Option<(Range<Celsius>, Duration)> {
+        let boost = self.boost.as_ref()?;
+        Some((boost.band.clone(), boost.until.saturating_duration_since(now)))
+    }
+
+    /// (low, high), the temperatures the relay switches at, compensated and trimmed for the ambient. Both move by
+    /// the same trim, so they never cross.
+    pub fn thresholds(&self) -> (Celsius, Celsius) {
+        let trim = self.trim.millidegrees();
+        (
+            (self.low_threshold + trim).celsius(),
+            (self.high_threshold + trim).celsius(),
+        )
+    }
+
+    /// The rest holding the relay off, None unless resting.
+    pub fn rest(&self, now: Instant) -> Option<RestPeriod> {
+        self.rest.rest(now)
+    }
+
+    /// The load shed holding off starts, None unless shedding.
+    pub fn shed(&self, now: Instant) -> Option<ShedPeriod> {
+        self.shed.shed(now)
+    }
+
+    /// The starts quiet hours held back, None unless quiet hours are configured.
+    pub fn quiet_hours(&self, now: Instant) -> Option<QuietStats> {
+        self.quiet.stats(now)
+    }
+
+    /// The cycle in progress as it would be learned from at `now`, None while it wouldn't be. For checkpointing.
+    pub fn cycle_extremes(&self, now: Instant) -> Option<CycleExtremes> {
+        if self.cycles < 2
+            || self.state == State::Fault
+            || self.mode != ControlMode::Hysteresis
+            || self.extremes.learning_inhibited().is_some()
+        {
+            return None;
+        }
+        Some(CycleExtremes {
+            on: self.power_on,
+            min: self.extremes.min()?,
+            max: self.extremes.max()?,
+            running: now.saturating_duration_since(self.cycle_since),
+            age: Duration::from_secs(0),
+        })
+    }
+
+    /// Everything worth knowing when debugging a live controller, as `<key> <value>` lines. Transitions count from
+    /// when control last resumed, compensation learning skips the first two. A resumed cycle starts at two.
+    pub fn diagnostics(&self, now: Instant) -> String {
+        let temperature = |t: Celsius| format!("{:.3}", t.0);
+        let compensator = |c: &Compensator| {
+            let mut observations: Vec<String> = c.observations().map(|o| temperature(o.celsius())).collect();
+            if observations.is_empty() {
+                observations.push("-".into());
+            }
+            format!(
+                "applied {} measured {} capped {} observations {}",
+                temperature(c.get_compensation().celsius()),
+                temperature(c.measured().celsius()),
+                c.is_capped(),
+                observations.join(" ")
+            )
+        };
+        let mut lines = vec![
+            format!(
+                "state_for {}",
+                now.saturating_duration_since(self.state_since).as_secs()
+            ),
+            format!("mode {:?}", self.mode),
+            format!("low_compensator {}", compensator(&self.low_compensator)),
+            format!("high_compensator {}", compensator(&self.high_compensator)),
+            format!("trim {:.3}", self.trim.0),
+            format!("ambient {}", self.ambient.map_or_else(|| "-".into(), temperature)),
+            format!("clamp {:?}", self.clamp),
+            format!("transitions {}", self.cycles),
+            format!("reinitializations {}", self.reinitializations),
+            match self.sensor_errors == SensorErrorCounts::default() {
+                true => "sensor_errors_pending -".into(),
+                false => format!("sensor_errors_pending {}", self.sensor_errors),
+            },
+            format!("runtime_seconds {}", self.runtime.total(now).as_secs()),
+            format!("starts_lifetime {}", self.starts.counts().lifetime),
+        ];
+        if let Some((band, left)) = self.boost(now) {
+            lines.push(format!(
+                "boost {} {} {}",
+                temperature(band.start),
+                temperature(band.end),
+                left.as_secs()
+            ));
+        }
+        if let Some(rest) = self.rest(now) {
+            lines.push(format!("rest {} {}", rest.remaining.as_secs(), rest.run.as_secs()));
+        }
+        if let Some(shed) = self.shed(now) {
+            lines.push(format!("shed {} {}", shed.remaining.as_secs(), shed.since.as_secs()));
+        }
+        if let Some((on, runtime, starts)) = self.stage2(now) {
+            lines.push(format!("stage2 {} {} {}", on, runtime.as_secs(), starts));
+        }
+        if let Some((on, failed)) = self.pump() {
+            lines.push(format!("pump {} {}", on, failed));
+        }
+        if let Some((output, faulted)) = self.output() {
+            lines.push(format!("output {} {}", output, faulted));
+        }
+        if let Some((amps, fault)) = self.current() {
+            lines.push(format!(
+                "current {} {}",
+                amps.map_or_else(|| "-".into(), |amps| format!("{:.3}", amps)),
+                fault.map_or_else(|| "none".into(), |fault| fault.to_string())
+            ));
+        }
+        if let Some(energy) = &self.energy {
+            let totals = energy.totals();
+            lines.push(format!(
+                "energy today {:.3} yesterday {:.3} lifetime {:.3}",
+                totals.today, totals.yesterday, totals.lifetime
+            ));
+        }
+        lines.join("\n")
+    }
+
+    fn observe_ambient(&mut self, ambient: Option<Celsius>) {
+        self.ambient = ambient;
+        self.trim = self.ambient_trim.trim(ambient);
+        if (self.trim - self.logged_trim).abs() < TRIM_LOG_STEP {
+            return;
+        }
+        self.logged_trim = self.trim;
+        let (low, high) = self.thresholds();
+        match ambient {
+            Some(ambient) => info!(
+                "Ambient {}, thresholds trimmed by {:+} to {} and {}",
+                ambient, self.trim, low, high
+            ),
+            None => info!("Ambient unknown, thresholds untrimmed at {} and {}", low, high),
+        }
+    }
+
+    /// Moves the band along with the ambient in differential mode, the compensated thresholds move with it. Without
+    /// an ambient reading, or while boosting, the band stays where it was.
+    fn follow_ambient(&mut self, ambient: Option<Celsius>, temperature: Option<Celsius>) {
+        if self.boost.is_some() {
+            return;
+        }
+        let (ambient, (target, clamp)) = match ambient.and_then(|a| Some((a, self.differential.band(a)?))) {
+            Some(followed) => followed,
+            None => return,
+        };
+        self.configured_band = target.clone();
+        self.set_band(target);
+        if (self.band.target.start - self.logged_band).abs() < TRIM_LOG_STEP && clamp == self.clamp {
+            return;
+        }
+        self.logged_band = self.band.target.start;
+        self.clamp = clamp;
+        let chamber = match temperature {
+            Some(temperature) => temperature.to_string(),
+            None => "unknown".into(),
+        };
+        let (low, high) = self.thresholds();
+        info!(
+            "Chamber {} against ambient {}, differential band {} to {} {}, thresholds {} and {}",
+            chamber, ambient, self.band.target.start, self.band.target.end, clamp, low, high
+        );
+    }
+
+    /// Takes the reloaded band, hysteresis, intervals and sampling, keeping the state, what the compensators learned
+    /// and the counters. A boost or differential mode keeps its band, the reloaded one comes back after it.
+    fn reload(&mut self, config: &Config) {
+        let max_compensation = config.band.compensation_cap();
+        self.low_compensator.set_max_compensation(max_compensation);
+        self.high_compensator
+            .set_max_compensation(Millidegrees::ZERO - max_compensation);
+        self.band.max_compensation = config.band.max_compensation;
+        self.configured_band = config.band.target.clone();
+        let band = match self.boost.is_some() || self.differential.below.is_some() {
+            true => self.band.target.clone(),
+            false => config.band.target.clone(),
+        };
+        self.set_band(band);
+        self.hysteresis = config.hysteresis;
+        // Tuned intervals stay until they're reverted, back to the reloaded ones.
+        if self.intervals == self.configured_intervals {
+            self.intervals = config.intervals;
+            self.window.set_intervals(config.intervals);
+        }
+        self.configured_intervals = config.intervals;
+        self.sampler = Sampler::new(config.sampling);
+        let (low, high) = self.thresholds();
+        info!("Configuration reloaded, thresholds {} and {}", low, high);
+    }
+
+    /// Moves the compensated thresholds to `band`, keeping what the compensators learned.
+    fn set_band(&mut self, band: Range<Celsius>) {
+        self.low_compensator.set_target(band.start.millidegrees());
+        self.high_compensator.set_target(band.end.millidegrees());
+        self.low_threshold = self.low_compensator.get_threshold();
+        self.high_threshold = self.high_compensator.get_threshold();
+        self.band.target = band;
+    }
+
+    /// Starts, cancels or expires a boost. The configured band comes back when it ends, differential mode moves it on
+    /// from there with the next ambient reading.
+    fn manage_boost(&mut self, request: Option<BoostRequest>, now: Instant, effects: &mut Vec<Effect>) {
+        match request {
+            Some(BoostRequest::Start(boost)) => {
+                let duration = boost.remaining;
+                let band = self.start_boost(boost, now);
+                effects.push(Effect::Notify(Event::BoostStarted {
+                    band: band.clone(),
+                    duration,
+                }));
+                effects.push(Effect::PersistBoost(Some(Boost {
+                    band,
+                    remaining: duration,
+                })));
+            }
+            Some(BoostRequest::Cancel) if self.boost.is_some() => self.end_boost(true, effects),
+            Some(BoostRequest::Cancel) => info!("No boost to cancel."),
+            None if self.boost.as_ref().is_some_and(|boost| now >= boost.until) => self.end_boost(false, effects),
+            None => {}
+        }
+    }
+
+    /// Starts or ends a load shed on the demand response signal.
+    fn manage_shed(&mut self, asserted: bool, temperature: Celsius, now: Instant, effects: &mut Vec<Effect>) {
+        let (_, high) = self.thresholds();
+        match self.shed.update(asserted, temperature, high, now) {
+            Some(ShedChange::Started { max_duration }) => {
+                info!(
+                    "Shedding load at {}, holding off starts for up to {}m",
+                    temperature,
+                    max_duration.as_secs() / 60
+                );
+                effects.push(Effect::Notify(Event::ShedStarted { max_duration }));
+                effects.push(Effect::PersistShed(self.shed.shed(now)));
+                effects.push(self.status(now, self.state.to_string()));
+            }
+            Some(ShedChange::Ended { lasted, end }) => {
+                info!(
+                    "Load shed over after {}m at {}, {}",
+                    lasted.as_secs() / 60,
+                    temperature,
+                    end
+                );
+                effects.push(Effect::Notify(Event::ShedEnded { lasted, end }));
+                // Spent while still asserted, kept so a restart doesn't shed again on the same assertion.
+                effects.push(Effect::PersistShed(match end {
+                    ShedEnd::Released => None,
+                    ShedEnd::TimedOut | ShedEnd::Excursion => Some(ShedPeriod {
+                        since: lasted,
+                        remaining: Duration::from_secs(0),
+                    }),
+                }));
+                effects.push(self.status(now, self.state.to_string()));
+            }
+            Some(ShedChange::Rearmed) => {
+                debug!("Shed signal released, the next assertion sheds again");
+                effects.push(Effect::PersistShed(None));
+            }
+            None => {}
+        }
+    }
+
+    /// Applies the boost band, held within the differential limits when they apply, and returns it.
+    fn start_boost(&mut self, boost: Boost, now: Instant) -> Range<Celsius> {
+        let band = match self.differential.is_enabled() {
+            true => self.differential.limit(boost.band).0,
+            false => boost.band,
+        };
+        info!(
+            "Boosting to {} to {} for {}m, compensation learning suspended",
+            band.start,
+            band.end,
+            boost.remaining.as_secs() / 60
+        );
+        self.set_band(band.clone());
+        // The cycle in progress straddles two bands, it says nothing about either.
+        self.extremes.reset();
+        self.boost = Some(ActiveBoost {
+            band: band.clone(),
+            until: now + boost.remaining,
+        });
+        band
+    }
+
+    fn end_boost(&mut self, cancelled: bool, effects: &mut Vec<Effect>) {
+        self.boost = None;
+        let band = self.configured_band.clone();
+        info!("Boost ended, back to {} to {}", band.start, band.end);
+        self.set_band(band.clone());
+        self.extremes.reset();
+        effects.push(Effect::Notify(Event::BoostEnded { band, cancelled }));
+        effects.push(Effect::PersistBoost(None));
+    }
+
+    /// Reports what the timing advisor made of the last round of cycles, and applies it when allowed.
+    fn advise_timing(&mut self, effects: &mut Vec<Effect>) {
+        let advice = match self.timing.advise(self.intervals) {
+            Some(advice) => advice,
+            None => return,
+        };
+        if !advice.is_change() {
+            info!("Minimum intervals hold up: {}", advice);
+            return;
+        }
+        if self.apply_timing {
+            info!("Minimum intervals tuned: {}", advice);
+            self.intervals = advice.recommended;
+            effects.push(Effect::PersistIntervals(Some(advice.recommended)));
+        } else {
+            info!("Minimum intervals could be tuned: {}", advice);
+        }
+        effects.push(Effect::Notify(Event::TimingAdvice {
+            advice,
+            applied: self.apply_timing,
+        }));
+    }
+
+    /// Back to the configured minimum intervals, the advisor only recommends for the rest of the run.
+    fn fail_over(&mut self, change: FailoverChange, now: Instant, effects: &mut Vec<Effect>) {
+        match change {
+            FailoverChange::FailedOver { failures } => {
+                effects.push(Effect::SelectOutput(Output::Backup));
+                effects.push(Effect::PersistFailover(true));
+                effects.push(Effect::Notify(Event::FailedOver { failures }));
+            }
+            FailoverChange::Retrying => effects.push(Effect::SelectOutput(Output::Primary)),
+            FailoverChange::Restored => {
+                effects.push(Effect::PersistFailover(false));
+                effects.push(Effect::Notify(Event::PrimaryRestored { reset: false }));
+            }
+            FailoverChange::Reset => {
+                effects.push(Effect::SelectOutput(Output::Primary));
+                effects.push(Effect::PersistFailover(false));
+                effects.push(Effect::Notify(Event::PrimaryRestored { reset: true }));
+            }
+        }
+        effects.push(self.status(now, self.state.to_string()));
+    }
+
+    fn revert_timing(&mut self, effects: &mut Vec<Effect>) {
+        self.apply_timing = false;
+        if self.intervals == self.configured_intervals {
+            info!("No tuned minimum intervals to revert.");
+            return;
+        }
+        let intervals = self.configured_intervals;
+        info!(
+            "Tuned minimum intervals reverted to {}m on and {}m off",
+            intervals.on.as_secs() / 60,
+            intervals.off.as_secs() / 60
+        );
+        self.intervals = intervals;
+        self.timing.restart();
+        effects.push(Effect::PersistIntervals(None));
+        effects.push(Effect::Notify(Event::TimingReverted { intervals }));
+    }
+
+    fn sensor_failed(&mut self, e: SensorError, now: Instant, effects: &mut Vec<Effect>) {
+        error!("Could not read temperature ({}). {}", e.class(), e);
+        self.sensor_errors.record(e.kind());
+        if let Some(failing_for) = self.failsafe.record_failure(now, e.class()) {
+            info!("State changed: {} -> {}", self.state, State::Fault);
+            let from = replace(&mut self.state, State::Fault);
+            self.transitioned(from, TransitionReason::SensorFailsafe, now, None, effects);
+            effects.push(self.status(now, "Fault, sensor failsafe engaged".into()));
+            effects.push(Effect::Notify(Event::SensorFailsafeEngaged {
+                failing_for,
+                policy: self.failsafe.policy(),
+                cause: e.kind(),
+            }));
+        }
+        if self.failsafe.is_engaged() {
+            self.cooling_monitor.mark_abnormal();
+            self.predictor.abandon();
+            self.timing.interrupt();
+        }
+        if self.failsafe.is_engaged() && !self.runaway.is_tripped() && self.failsafe.output(now) != self.power_on {
+            self.power_on = self.failsafe.output(now);
+            self.extremes.inhibit_learning("sensor failsafe");
+            if let Some(cycle) = self.set_power(now, effects) {
+                effects.push(Effect::RecordCycle(cycle));
+            }
+            self.window.interrupt(now, self.power_on);
+        }
+        if self.failsafe.budget_exhausted(now) {
+            if self.reinitializations >= self.reinitialize_attempts {
+                effects.push(Effect::Terminate(Termination::SensorFailed {
+                    failing_for: self.failsafe.failing_for(now),
+                    reinitializations: self.reinitializations,
+                }));
+                return;
+            }
+            self.reinitializations += 1;
+            warn!(
+                "Failsafe budget exhausted, re-initializing sensor (attempt {}).",
+                self.reinitializations
+            );
+            effects.push(Effect::ReinitializeSensor);
+            self.failsafe.restart_budget(now);
+        }
+        if self.failsafe.is_engaged() {
+            effects.push(Effect::Heartbeat(HeartbeatStatus::Failsafe));
+        }
+    }
+
+    fn control(
+        &mut self,
+        temperature: Celsius,
+        now: Instant,
+        local: LocalTime,
+        acknowledged: bool,
+        effects: &mut Vec<Effect>,
+    ) {
+        let errors = self.sensor_errors.take();
+        if let Some(outage) = self.failsafe.record_success(now) {
+            effects.push(Effect::Notify(Event::SensorRecovered { outage, errors }));
+            if !self.runaway.is_tripped() {
+                // Resume as if restarting with the relay in its failsafe posture.
+                let restored = match self.power_on {
+                    true => RestoredPowerState::CurrentlyOn,
+                    false => RestoredPowerState::OffForUnknownDuration,
+                };
+                let from = replace(
+                    &mut self.state,
+                    determine_initial_state(Ok(restored), now, self.intervals),
+                );
+                info!("Sensor recovered, resuming control in state {}", self.state);
+                self.transitioned(from, TransitionReason::SensorRecovered, now, Some(temperature), effects);
+                effects.push(self.status(now, format!("{} at {}", self.state, temperature)));
+                self.extremes.reset();
+                self.sampler.restart();
+                self.cycles = 0;
+                self.reinitializations = 0;
+            }
+        }
+
+        trace!("Read temperature: {}", temperature);
+        self.extremes.push(temperature);
+        if self.boost.is_some() {
+            self.extremes.inhibit_learning("boost");
+            self.cooling_monitor.mark_abnormal();
+            self.timing.interrupt();
+            if self.rest.boosting() {
+                info!("Rest cut short by the boost");
+                effects.push(Effect::PersistRest(None));
+            }
+        }
+        if self.rest.expire(now) {
+            info!("Rest over, the thresholds have the relay again");
+            effects.push(Effect::PersistRest(None));
+        }
+        match self.fan.mixing(now) {
+            true => trace!("Stirred moments ago, {} left out of the trend", temperature),
+            false => self.trend.push(now, temperature),
+        }
+        self.cooling_monitor.observe(now, temperature);
+        self.predictor.observe(temperature);
+
+        if self.runaway.is_tripped() && (acknowledged || self.runaway.retry_due(now)) {
+            if let Some(locked_out_for) = self.runaway.clear(now) {
+                effects.push(Effect::Notify(Event::RunawayCleared {
+                    locked_out_for,
+                    acknowledged,
+                }));
+                // The relay has been off since the lockout started.
+                let restored = RestoredPowerState::OffFor(locked_out_for);
+                let from = replace(
+                    &mut self.state,
+                    determine_initial_state(Ok(restored), now, self.intervals),
+                );
+                info!(
+                    "Thermal runaway lockout cleared, resuming control in state {}",
+                    self.state
+                );
+                self.transitioned(from, TransitionReason::RunawayCleared, now, Some(temperature), effects);
+                effects.push(self.status(now, format!("{} at {}", self.state, temperature)));
+                self.trend.reset();
+                self.extremes.reset();
+                self.sampler.restart();
+                self.cycles = 0;
+            }
+        }
+
+        if let Some(evidence) = self.runaway.update(now, self.power_on, self.trend.slope_per_hour()) {
+            error!(
+                "Thermal runaway: relay on for {}m while temperature rising {} at {}. Locking out.",
+                evidence.on_for.as_secs() / 60,
+                Rate::new(evidence.slope_per_hour, "/h"),
+                temperature
+            );
+            effects.push(Effect::Notify(Event::ThermalRunaway(evidence)));
+            info!("State changed: {} -> {}", self.state, State::Fault);
+            let from = replace(&mut self.state, State::Fault);
+            self.transitioned(from, TransitionReason::ThermalRunaway, now, Some(temperature), effects);
+            effects.push(self.status(now, "Fault, thermal runaway lockout".into()));
+            self.power_on = false;
+            self.cooling_monitor.mark_abnormal();
+            self.predictor.abandon();
+            self.timing.interrupt();
+            self.extremes.inhibit_learning("thermal runaway");
+            if let Some(cycle) = self.set_power(now, effects) {
+                effects.push(Effect::RecordCycle(cycle));
+            }
+            self.window.interrupt(now, self.power_on);
+        }
+
+        let window = self.duty_alert.window();
+        match self.duty_alert.update(now, self.cycle_stats.duty(now, window)) {
+            Some(DutyAlertChange::Raised(duty)) => effects.push(Effect::Notify(Event::DutyCycleHigh { duty, window })),
+            Some(DutyAlertChange::Cleared(duty)) => {
+                effects.push(Effect::Notify(Event::DutyCycleCleared { duty, window }))
+            }
+            None => {}
+        }
+
+        if let Some(decided) = self.sampler.push(temperature) {
+            self.decide(decided, now, local, effects);
+        }
+
+        effects.push(Effect::Heartbeat(match self.runaway.is_tripped() {
+            true => HeartbeatStatus::Lockout,
+            false => HeartbeatStatus::Controlling,
+        }));
+    }
+
+    /// Switches on the temperature aggregated over the decision interval.
+    fn decide(&mut self, temperature: Celsius, now: Instant, local: LocalTime, effects: &mut Vec<Effect>) {
+        // A start held back lets the chamber warm past the reset on purpose, that says nothing about the compensation.
+        if self.boost.is_none() && !self.held_start && temperature > self.band.low_compensation_reset() {
+            info!("Temperature {} exceeded low compensation reset threshold", temperature);
+            if !self.low_compensator.is_zero() {
+                info!("Low compensator and threshold reset");
+                self.low_compensator.reset();
+                self.low_threshold = self.low_compensator.get_threshold();
+                effects.push(self.persist_compensation());
+            }
+        }
+
+        let (low, high) = self.thresholds();
+        // Trimmed like the thresholds, and only ever an earlier shutoff.
+        let early_low = self
+            .predictor
+            .shutoff_threshold(&self.band, now)
+            .map(|threshold| threshold + self.trim)
+            .filter(|&threshold| threshold > low && threshold < high);
+        let mut transition_thresholds = self.hysteresis.thresholds(low.millidegrees(), high.millidegrees());
+        // The prediction already allows for the coast down, it replaces the low side's engage offset.
+        if let Some(early_low) = early_low {
+            transition_thresholds.low.engage = early_low.millidegrees();
+        }
+        let new_state = match self.mode {
+            ControlMode::Hysteresis => transition(
+                self.state,
+                temperature.millidegrees(),
+                transition_thresholds,
+                now,
+                self.intervals,
+            ),
+            ControlMode::Tpc => self.proportional(temperature, now),
+        };
+        // A rest holds the relay off whatever the thresholds say. Control has started all the same, the loop only
+        // paces itself out of InitiallyOff.
+        let new_state = match self.rest.rest(now) {
+            Some(rest) if new_state.is_on() && !self.state.is_on() => {
+                debug!(
+                    "Resting {}m more, holding the relay off at {}",
+                    rest.remaining.as_secs() / 60,
+                    temperature
+                );
+                match self.state {
+                    State::InitiallyOff => State::Off,
+                    held => held,
+                }
+            }
+            _ => new_state,
+        };
+        // Peak windows and quiet hours only ever move a start, neither turns the relay off nor breaks an interval.
+        let held = match self.state {
+            State::InitiallyOff => State::Off,
+            held => held,
+        };
+        let starting = new_state.is_on() && !self.state.is_on();
+        let free = new_state == State::Off && self.rest.rest(now).is_none();
+        let new_state = match self.peak.shift(starting, free, temperature, (low, high), now, local) {
+            PeakShift::Normal => new_state,
+            PeakShift::Defer => {
+                self.extremes.inhibit_learning("peak deferral");
+                self.held_start = true;
+                held
+            }
+            PeakShift::Advance => {
+                self.extremes.inhibit_learning("pre-chill");
+                State::MinimumIntervalOn(now)
+            }
+        };
+        let starting = new_state.is_on() && !self.state.is_on();
+        let new_state = match self.quiet.hold(starting, temperature, high, now, local) {
+            true => {
+                self.extremes.inhibit_learning("quiet hours");
+                self.held_start = true;
+                held
+            }
+            false => new_state,
+        };
+        // A load shed holds off starts too, and with --shed-terminate ends a run once it had its minimum on time.
+        let starting = new_state.is_on() && !self.state.is_on();
+        let shed_off = self.shed.is_shedding() && self.shed.terminates() && new_state == State::On;
+        let new_state = match self.shed.is_shedding() {
+            true if starting => {
+                self.extremes.inhibit_learning("load shed");
+                self.held_start = true;
+                held
+            }
+            true if shed_off => {
+                self.extremes.inhibit_learning("load shed");
+                State::MinimumIntervalOff(now)
+            }
+            _ => new_state,
+        };
+        // The pump runs its lead ahead of the compressor, which never starts against a pump that didn't come on.
+        let starting = new_state.is_on() && !self.state.is_on();
+        let (new_state, primed) = match self.pump.gate(starting, now) {
+            PumpGate::Ready => (new_state, false),
+            PumpGate::Primed => {
+                info!("Pump primed, compressor starting at {}", temperature);
+                (new_state, true)
+            }
+            PumpGate::Leading { remaining } => {
+                debug!("Pump leading, compressor start in {}s", remaining.as_secs());
+                (held, false)
+            }
+            PumpGate::Interlocked => {
+                warn!("Pump interlock holding the compressor off at {}", temperature);
+                self.extremes.inhibit_learning("pump interlock");
+                self.held_start = true;
+                (held, false)
+            }
+        };
+        let previous_state = replace(&mut self.state, new_state);
+        if self.timing.is_enabled() && new_state == previous_state {
+            // What the thresholds alone would have done, for the timing advisor.
+            let unheld = match new_state {
+                State::MinimumIntervalOn(_) => Some(State::On),
+                State::MinimumIntervalOff(_) => Some(State::Off),
+                _ => None,
+            };
+            let switches = |unheld: State| {
+                transition(
+                    unheld,
+                    temperature.millidegrees(),
+                    transition_thresholds,
+                    now,
+                    self.intervals,
+                )
+                .is_on()
+                    != unheld.is_on()
+            };
+            if unheld.is_some_and(switches) {
+                self.timing.held();
+            }
+        }
+
+        if previous_state != new_state {
+            info!("State changed: {} -> {}", previous_state, new_state);
+            effects.push(self.status(now, format!("{} at {}", new_state, temperature)));
+            let reason = match (self.mode, previous_state, new_state) {
+                _ if shed_off => TransitionReason::LoadShed,
+                _ if primed => TransitionReason::PumpPrimed,
+                (ControlMode::Tpc, ..) => TransitionReason::Duty,
+                (_, State::MinimumIntervalOn(_), State::On) | (_, State::MinimumIntervalOff(_), State::Off) => {
+                    TransitionReason::MinimumInterval
+                }
+                _ => TransitionReason::Threshold,
+            };
+            self.transitioned(previous_state, reason, now, Some(temperature), effects);
+        }
+
+        if self.power_on != new_state.is_on() {
+            self.power_on = new_state.is_on();
+            let mut ended = self.set_power(now, effects);
+            self.timing.switched(now, self.power_on);
+            if self.rest.rest(now).is_some() {
+                // The rest, not the minimum, decides how long this off phase lasts.
+                self.timing.interrupt();
+            }
+            self.advise_timing(effects);
+            match self.power_on {
+                true => {
+                    self.cooling_monitor.start_cycle(now, temperature);
+                    self.audit_prediction(now);
+                }
+                false => {
+                    if early_low.is_some() && temperature >= low {
+                        info!(
+                            "Shutting off early at {}, predicting a further drop of {} (threshold {})",
+                            temperature,
+                            self.predictor.tail().unwrap_or(DegreesDelta::ZERO),
+                            low
+                        );
+                    }
+                    self.predictor.relay_off(temperature);
+                    self.held_start = false;
+                    let rate = self.end_cooling_cycle(now, effects);
+                    if let Some(cycle) = &mut ended {
+                        cycle.cooling_rate = rate;
+                    }
+                    self.completed_cycles += 1;
+                }
+            }
+
+            self.cycles += 1;
+            if let Some(mut cycle) = ended {
+                if self.cycles <= 2 && self.mode == ControlMode::Hysteresis {
+                    // Not learned from, the extremes go back to before the run.
+                    cycle.abnormal.get_or_insert_with(|| "first cycles".into());
+                }
+                effects.push(Effect::RecordCycle(cycle));
+            }
+
+            if self.cycles > 2 {
+                match self.extremes.learning_inhibited() {
+                    _ if self.mode != ControlMode::Hysteresis => {}
+                    Some(reason) => {
+                        if let (Some(min), Some(max)) = (self.extremes.min(), self.extremes.max()) {
+                            info!("Cycle from {} to {} not learned from, {} during it", min, max, reason);
+                        }
+                    }
+                    None => {
+                        self.record_overshoot(new_state, effects);
+                        if self.update_compensation(new_state, now, effects) {
+                            effects.push(self.persist_compensation());
+                        }
+                    }
+                }
+                self.extremes.reset();
+            }
+        }
+    }
+
+    /// The window's position instead of the thresholds. Only the fault latch and the bottom of the band override the
+    /// duty, the minimum intervals still hold.
+    fn proportional(&mut self, temperature: Celsius, now: Instant) -> State {
+        if self.state == State::Fault {
+            return State::Fault;
+        }
+        let mut duty = self.pi.update(now, temperature - self.band.middle());
+        if temperature < self.band.target.start {
+            self.window.cut(now);
+            duty = 0.0;
+        }
+        let (on, start) = self.window.output(now, duty);
+        if let Some(start) = start {
+            info!(
+                "Window: {:.0}% duty at {}, on for {}s (integral {:.2})",
+                start.duty * 100.0,
+                temperature,
+                start.on_for.as_secs(),
+                self.pi.integral()
+            );
+        }
+        match on {
+            true => State::On,
+            false => State::Off,
+        }
+    }
+
+    /// How far the cycle that just ended went past its end of the band itself, what the compensators drive to zero.
+    fn record_overshoot(&mut self, new_state: State, effects: &mut Vec<Effect>) {
+        let miss = match new_state.is_off() {
+            true => self
+                .extremes
+                .max()
+                .map(|max| (End::Over, overshoot(max, self.band.target.end))),
+            false => self
+                .extremes
+                .min()
+                .map(|min| (End::Under, undershoot(min, self.band.target.start))),
+        };
+        if let Some((end, by)) = miss {
+            let miss = self.overshoots.record(end, by);
+            info!("Cycle {}", miss);
+            effects.push(Effect::PersistOvershoots(self.overshoots.overshoots()));
+        }
+    }
+
+    /// Feeds the extreme of the cycle that just ended to its compensator, true if a threshold moved.
+    fn update_compensation(&mut self, new_state: State, now: Instant, effects: &mut Vec<Effect>) -> bool {
+        let mut updated: bool = false;
+        if new_state.is_off() {
+            // On -> Off
+            if let Some(max_temp_during_on_cycle) = self.extremes.max() {
+                trace!("Max temp seen during on cycle: {}", max_temp_during_on_cycle);
+                self.high_compensator
+                    .push_observation(max_temp_during_on_cycle.millidegrees());
+                if self.high_compensator.is_capped() {
+                    warn!("Heating compenstation is capped at maximum compensation.");
+                }
+                self.watch_cap(Compensation::Heating, now, effects);
+                let old_threshold = replace(&mut self.high_threshold, self.high_compensator.get_threshold());
+                if old_threshold != self.high_threshold {
+                    debug!(
+                        "Updated heating threshold: {} -> {} (target: {})",
+                        old_threshold.celsius(),
+                        self.high_threshold.celsius(),
+                        self.band.target.end
+                    );
+                    updated = true;
+                }
+            }
+        } else {
+            // Off -> On
+            if let Some(min_temp_during_off_cycle) = self.extremes.min() {
+                trace!("Min temp seen during off cycle: {}", min_temp_during_off_cycle);
+                self.low_compensator
+                    .push_observation(min_temp_during_off_cycle.millidegrees());
+                let old_threshold = replace(&mut self.low_threshold, self.low_compensator.get_threshold());
+                if self.low_compensator.is_capped() {
+                    warn!("Cooling compenstation is capped at maximum compensation.");
+                }
+                self.watch_cap(Compensation::Cooling, now, effects);
+                if old_threshold != self.low_threshold {
+                    debug!(
+                        "Updated cooling threshold: {} -> {} (target: {})",
+                        old_threshold.celsius(),
+                        self.low_threshold.celsius(),
+                        self.band.target.start
+                    );
+                    updated = true;
+                }
+            }
+        }
+        updated
+    }
+
+    /// Notifies when a compensator has sat at its cap for too many cycles, with the cap that would have covered the
+    /// overshoot, and again once it comes off.
+    fn watch_cap(&mut self, compensation: Compensation, now: Instant, effects: &mut Vec<Effect>) {
+        let (compensator, alert) = match compensation {
+            Compensation::Cooling => (&self.low_compensator, &mut self.low_cap_alert),
+            Compensation::Heating => (&self.high_compensator, &mut self.high_cap_alert),
+        };
+        let overshoot = compensator.measured().degrees().abs();
+        match alert.observe(now, compensator.is_capped(), overshoot) {
+            Some(CapChange::Capped { cycles, overshoot }) => effects.push(Effect::Notify(Event::CompensationCapped {
+                compensation,
+                cycles,
+                overshoot,
+                cap: self.band.max_compensation,
+                suggested: suggested_cap(overshoot),
+            })),
+            Some(CapChange::Cleared { cycles }) => {
+                effects.push(Effect::Notify(Event::CompensationUncapped { compensation, cycles }))
+            }
+            None => {}
+        }
+    }
+
+    /// Logs how the off cycle that just ended compared to the tail predicted when it started.
+    fn audit_prediction(&mut self, now: Instant) {
+        let observation = match self.predictor.relay_on(now) {
+            Some(observation) => observation,
+            None => return,
+        };
+        match observation.predicted {
+            Some(predicted) => info!(
+                "Off cycle from {} bottomed out at {} against a predicted {}",
+                observation.shutoff_at,
+                observation.minimum,
+                observation.shutoff_at - predicted
+            ),
+            None => info!(
+                "Off cycle from {} bottomed out at {}, still learning the tail",
+                observation.shutoff_at, observation.minimum
+            ),
+        }
+    }
+
+    /// With today's energy use when it is being accounted, the compressor runtime and today's starts.
+    fn status(&self, now: Instant, status: String) -> Effect {
+        let status = match &self.energy {
+            Some(meter) => format!("{}, {:.2} kWh today", status, meter.totals().today),
+            None => status,
+        };
+        let status = match self.ambient {
+            Some(ambient) if self.differential.is_enabled() => format!(
+                "{}, ambient {} band {} to {} {}",
+                status, ambient, self.band.target.start, self.band.target.end, self.clamp
+            ),
+            Some(ambient) if self.ambient_trim.is_enabled() => {
+                format!("{}, ambient {} trimming {:+}", status, ambient, self.trim)
+            }
+            _ => status,
+        };
+        let status = match self.boost(now) {
+            Some((band, remaining)) => format!(
+                "{}, boosted to {} to {} for {}m more",
+                status,
+                band.start,
+                band.end,
+                remaining.as_secs() / 60
+            ),
+            None => status,
+        };
+        let status = match self.rest(now) {
+            Some(rest) => format!(
+                "{}, resting {}m more after a {}m run",
+                status,
+                rest.remaining.as_secs() / 60,
+                rest.run.as_secs() / 60
+            ),
+            None => status,
+        };
+        let status = match self.shed(now) {
+            Some(shed) => format!("{}, shedding load {}m more", status, shed.remaining.as_secs() / 60),
+            None => status,
+        };
+        let status = match self.fan.stirs() {
+            0 => status,
+            stirs => format!("{}, {} idle stirs", status, stirs),
+        };
+        let status = match self.stage2(now) {
+            Some((on, runtime, starts)) => format!(
+                "{}, stage 2 {} {:.1}h {} starts",
+                status,
+                if on { "on" } else { "off" },
+                runtime.as_secs_f64() / 3600.0,
+                starts
+            ),
+            None => status,
+        };
+        let status = match self.pump() {
+            Some((_, true)) => format!("{}, pump interlocked", status),
+            _ => status,
+        };
+        let status = match self.output() {
+            Some((Output::Backup, _)) => format!("{}, on the backup output", status),
+            _ => status,
+        };
+        let status = match self.current() {
+            Some((Some(amps), None)) => format!("{}, drawing {:.1}A", status, amps),
+            Some((Some(amps), Some(fault))) => format!("{}, drawing {:.1}A {}", status, amps, fault),
+            _ => status,
+        };
+        let hours = self.runtime.total(now).as_secs_f64() / 3600.0;
+        Effect::Status(format!(
+            "{}, {:.1}h compressor runtime, {} starts today",
+            status,
+            hours,
+            self.starts.counts().today
+        ))
+    }
+
+    fn account_energy(&mut self, now: Instant, local: LocalTime, effects: &mut Vec<Effect>) {
+        let power_on = self.power_on;
+        let meter = match &mut self.energy {
+            Some(meter) => meter,
+            None => return,
+        };
+        let ended = meter.update(now, local, power_on);
+        if let Some(daily) = ended {
+            info!("Energy: {}", meter.totals());
+            effects.push(Effect::Notify(Event::DailyEnergy(daily)));
+        }
+        if ended.is_some() || meter.checkpoint_due(now) {
+            effects.push(Effect::PersistEnergy(meter.totals()));
+        }
+    }
+
+    fn account_runtime(&mut self, now: Instant, reset: bool, effects: &mut Vec<Effect>) {
+        if reset {
+            let previous = self.runtime.reset(now);
+            info!("Compressor runtime counter reset from {:?}.", previous);
+            effects.push(Effect::Notify(Event::RuntimeReset { previous }));
+            effects.push(Effect::PersistRuntime(self.runtime.total(now)));
+        } else if self.runtime.checkpoint_due(now) {
+            effects.push(Effect::PersistRuntime(self.runtime.total(now)));
+        }
+    }
+
+    /// Keeps the extremes of a cycle that will be learned from, so a restart part way through can carry on with it.
+    fn checkpoint_extremes(&mut self, now: Instant, effects: &mut Vec<Effect>) {
+        let since = self.extremes_checkpoint.unwrap_or(self.cycle_since);
+        if now.saturating_duration_since(since) < EXTREMES_CHECKPOINT_INTERVAL {
+            return;
+        }
+        if let Some(extremes) = self.cycle_extremes(now) {
+            self.extremes_checkpoint = Some(now);
+            effects.push(Effect::PersistExtremes(Some(extremes)));
+        }
+    }
+
+    fn account_starts(&mut self, local: LocalTime, effects: &mut Vec<Effect>) {
+        if let Some(daily) = self.starts.update(local) {
+            info!("Starts: {}", self.starts.counts());
+            effects.push(Effect::Notify(Event::DailyStarts(daily)));
+            effects.push(Effect::PersistStarts(self.starts.counts().clone()));
+        }
+    }
+
+    /// The extremes of another sensor say nothing about this one, learn the compensation over.
+    fn forget_compensation(&mut self, effects: &mut Vec<Effect>) {
+        info!("Sensor replaced, compensation and thresholds reset");
+        self.low_compensator.reset();
+        self.high_compensator.reset();
+        self.low_threshold = self.low_compensator.get_threshold();
+        self.high_threshold = self.high_compensator.get_threshold();
+        self.extremes.reset();
+        effects.push(self.persist_compensation());
+    }
+
+    fn persist_compensation(&self) -> Effect {
+        Effect::PersistCompensation {
+            cooling: self.low_compensator.get_compensation().degrees(),
+            heating: self.high_compensator.get_compensation().degrees(),
+        }
+    }
+
+    /// Tells the driver the state moved from `from` at `now`, if it did.
+    fn transitioned(
+        &mut self,
+        from: State,
+        reason: TransitionReason,
+        now: Instant,
+        temperature: Option<Celsius>,
+        effects: &mut Vec<Effect>,
+    ) {
+        if from != self.state {
+            self.state_since = now;
+            self.last_reason = reason;
+            effects.push(Effect::Transition(Transition {
+                from,
+                to: self.state,
+                reason,
+                temperature,
+            }));
+        }
+    }
+
+    /// Drives the relay to `power_on`, the on cycle that ended when switching off. Its cooling rate is left to the
+    /// caller.
+    fn set_power(&mut self, now: Instant, effects: &mut Vec<Effect>) -> Option<CycleRecord> {
+        debug!("Updating power state: {}", self.power_on);
+        effects.push(Effect::SetPower(self.power_on));
+        self.cycle_stats.record(now, self.power_on);
+        self.runtime.record(now, self.power_on);
+        let started = replace(&mut self.cycle_since, now);
+        let ended = match self.power_on {
+            true => None,
+            false => Some(CycleRecord {
+                on: now.saturating_duration_since(started),
+                off: self
+                    .last_stop
+                    .replace(now)
+                    .map(|stop| started.saturating_duration_since(stop)),
+                min: self.extremes.min(),
+                max: self.extremes.max(),
+                overshoot: self.extremes.max().map(|max| overshoot(max, self.band.target.end)),
+                cooling_rate: None,
+                reason: self.last_reason,
+                abnormal: self.extremes.learning_inhibited().map(String::from),
+            }),
+        };
+        if self.extremes_checkpoint.take().is_some() {
+            effects.push(Effect::PersistExtremes(None));
+        }
+        if self.power_on {
+            self.starts.record_start();
+            effects.push(Effect::PersistStarts(self.starts.counts().clone()));
+        } else {
+            // On -> Off
+            debug!("Persisting last off transition.");
+            effects.push(Effect::PersistLastOff);
+            effects.push(Effect::PersistRuntime(self.runtime.total(now)));
+        }
+        if let Some(rest) = self.rest.switched(now, self.power_on) {
+            info!(
+                "Resting the compressor for {}m after a {}m run",
+                rest.remaining.as_secs() / 60,
+                rest.run.as_secs() / 60
+            );
+            effects.push(Effect::PersistRest(Some(rest)));
+            effects.push(self.status(now, self.state.to_string()));
+        }
+        ended
+    }
+
+    /// The rate the cycle cooled at, when it was measurable.
+    fn end_cooling_cycle(&mut self, now: Instant, effects: &mut Vec<Effect>) -> Option<f32> {
+        let (rate, change) = self.cooling_monitor.end_cycle(now);
+        if let Some(rate) = rate {
+            info!(
+                "On cycle cooled at {:.3} ({})",
+                Rate::new(rate, "/min"),
+                match (self.cooling_monitor.baseline(), self.cooling_monitor.ratio()) {
+                    (Some(baseline), Some(ratio)) => {
+                        format!(
+                            "{:.0}% of the baseline {:.3}",
+                            ratio * 100.0,
+                            Rate::new(baseline, "/min")
+                        )
+                    }
+                    _ => "no baseline yet".into(),
+                }
+            );
+            effects.push(Effect::PersistCoolingRates(self.cooling_monitor.rates()));
+        }
+        match change {
+            Some(CoolingChange::Degraded { rate, baseline }) => {
+                effects.push(Effect::Notify(Event::CoolingDegraded { rate, baseline }))
+            }
+            Some(CoolingChange::Recovered { rate, baseline }) => {
+                effects.push(Effect::Notify(Event::CoolingRecovered { rate, baseline }))
+            }
+            None => {}
+        }
+        rate
+    }
+}
+
+// Pure
+/// The (low, high) compensators for `band`, seeded with the persisted (cooling, heating) compensation.
+pub fn seed_compensators(band: &Band, (cooling, heating): (DegreesDelta, DegreesDelta)) -> (Compensator, Compensator) {
+    let seed_compensation = |seed: DegreesDelta| match seed.is_nan() {
+        true => {
+            error!("Compensator ignoring invalid seed compensation.");
+            Millidegrees::ZERO
+        }
+        false => seed.millidegrees(),
+    };
+    let max_compensation = band.compensation_cap();
+    let low = Compensator::new(
+        band.target.start.millidegrees(),
+        seed_compensation(cooling),
+        max_compensation,
+    );
+    let high = Compensator::new(
+        band.target.end.millidegrees(),
+        seed_compensation(heating),
+        Millidegrees::ZERO - max_compensation,
+    );
+    (low, high)
+}
+
+// Pure
+pub fn step(mut controller: ControllerState, input: Input) -> (ControllerState, Vec<Effect>) {
+    let mut effects = Vec::new();
+    let reading = input.temperature.as_ref().ok().copied();
+    controller.account_energy(input.now, input.local, &mut effects);
+    controller.account_runtime(input.now, input.reset_runtime, &mut effects);
+    controller.account_starts(input.local, &mut effects);
+    controller.observe_ambient(input.ambient);
+    if let Some(config) = &input.reload {
+        controller.reload(config);
+    }
+    controller.manage_boost(input.boost, input.now, &mut effects);
+    controller.follow_ambient(input.ambient, reading);
+    if input.sensor_replaced {
+        controller.forget_compensation(&mut effects);
+    }
+    if input.revert_timing {
+        controller.revert_timing(&mut effects);
+    }
+    match controller.pump.verify(input.pump) {
+        Some(PumpChange::Failed) => effects.push(Effect::Notify(Event::PumpFailed {
+            commanded: controller.pump.is_on(),
+        })),
+        Some(PumpChange::Recovered) => effects.push(Effect::Notify(Event::PumpRecovered)),
+        None => {}
+    }
+    match input.temperature {
+        Ok(temperature) => {
+            controller.manage_shed(input.shed, temperature, input.now, &mut effects);
+            controller.control(temperature, input.now, input.local, input.acknowledged, &mut effects)
+        }
+        Err(e) => controller.sensor_failed(e, input.now, &mut effects),
+    }
+    if !matches!(effects.last(), Some(Effect::Terminate(_))) {
+        controller.checkpoint_extremes(input.now, &mut effects);
+        if let Some(on) = controller.fan.update(input.now, controller.power_on) {
+            effects.push(Effect::SetFan(on));
+        }
+        let (_, high) = controller.thresholds();
+        if let Some(on) = controller.stage2.update(input.now, controller.power_on, reading, high) {
+            effects.push(Effect::SetStage2(on));
+            effects.push(controller.status(input.now, controller.state.to_string()));
+        }
+        if let Some(on) = controller.pump.update(input.now, controller.power_on) {
+            effects.push(Effect::SetPump(on));
+        }
+        match controller
+            .current
+            .observe(input.now, controller.power_on, input.current)
+        {
+            Some(LoadChange::Raised { fault, amps }) => {
+                effects.push(Effect::Notify(Event::LoadFault { fault, amps }));
+                effects.push(controller.status(input.now, controller.state.to_string()));
+            }
+            Some(LoadChange::Cleared(fault)) => {
+                effects.push(Effect::Notify(Event::LoadFaultCleared { fault }));
+                effects.push(controller.status(input.now, controller.state.to_string()));
+            }
+            None => {}
+        }
+        let change = match input.reset_failover {
+            true => controller.failover.reset(),
+            false => controller.failover.observe(
+                input.now,
+                controller.power_on,
+                controller.current.fault(),
+                controller.current.is_drawing(),
+            ),
+        };
+        if let Some(change) = change {
+            controller.fail_over(change, input.now, &mut effects);
+        }
+        controller.incidents.filter(input.now, input.local, &mut effects);
+    }
+    (controller, effects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Preset,
+        energy::{DailyEnergy, EnergyConfig},
+        failsafe::FailsafeConfig,
+        sampling::{Aggregate, SamplingConfig},
+        starts::DailyStarts,
+        world_error::SensorErrorKind,
+        TARGET_RANGE,
+    };
+    use std::{path::PathBuf, time::Duration};
+
+    fn controller(config: &Config, start: Instant) -> ControllerState {
+        let seed = Seed::new(State::Off);
+        ControllerState::new(seed, config, start)
+    }
+
+    fn reading(temperature: f32, start: Instant, secs: u64) -> Input {
+        Input {
+            temperature: Ok(Celsius(temperature)),
+            now: start + Duration::from_secs(secs),
+            local: LocalTime::default(),
+            ambient: None,
+            acknowledged: false,
+            reset_runtime: false,
+            sensor_replaced: false,
+            boost: None,
+            revert_timing: false,
+            shed: false,
+            pump: None,
+            current: None,
+            reset_failover: false,
+            reload: None,
+        }
+    }
+
+    #[test]
+    fn relay_cycle() {
+        let start = Instant::now();
+        let mut controller = controller(&Config::default(), start);
+        let table = vec![
+            (
+                reading(6.0, start, 10),
+                vec![
+                    Effect::Status("MinimumIntervalOn at 6.00C 42.80F, 0.0h compressor runtime, 0 starts today".into()),
+                    Effect::Transition(Transition {
+                        from: State::Off,
+                        to: State::MinimumIntervalOn(start + Duration::from_secs(10)),
+                        reason: TransitionReason::Threshold,
+                        temperature: Some(Celsius(6.0)),
+                    }),
+                    Effect::SetPower(true),
+                    Effect::PersistStarts(StartCounts {
+                        lifetime: 1,
+                        today: 1,
+                        ..StartCounts::default()
+                    }),
+                    Effect::Heartbeat(HeartbeatStatus::Controlling),
+                ],
+            ),
+            (
+                reading(0.0, start, 20),
+                vec![Effect::Heartbeat(HeartbeatStatus::Controlling)],
+            ),
+            (
+                reading(0.0, start, 200),
+                vec![
+                    Effect::Status(
+                        "MinimumIntervalOff at 0.00C 32.00F, 0.1h compressor runtime, 1 starts today".into(),
+                    ),
+                    Effect::Transition(Transition {
+                        from: State::MinimumIntervalOn(start + Duration::from_secs(10)),
+                        to: State::MinimumIntervalOff(start + Duration::from_secs(200)),
+                        reason: TransitionReason::Threshold,
+                        temperature: Some(Celsius(0.0)),
+                    }),
+                    Effect::SetPower(false),
+                    Effect::PersistLastOff,
+                    Effect::PersistRuntime(Duration::from_secs(190)),
+                    Effect::RecordCycle(CycleRecord {
+                        on: Duration::from_secs(190),
+                        off: None,
+                        min: Some(Celsius(0.0)),
+                        max: Some(Celsius(6.0)),
+                        overshoot: Some(overshoot(Celsius(6.0), Config::default().band.target.end)),
+                        cooling_rate: None,
+                        reason: TransitionReason::Threshold,
+                        abnormal: Some("first cycles".into()),
+                    }),
+                    Effect::Heartbeat(HeartbeatStatus::Controlling),
+                ],
+            ),
+        ];
+        for (input, expected) in table {
+            let (next, effects) = step(controller, input);
+            controller = next;
+            assert_eq!(expected, effects);
+        }
+        assert_eq!(1, controller.completed_cycles());
+    }
+
+    #[test]
+    fn decides_on_the_mean_with_minimum_intervals_on_the_clock() {
+        let config = Config {
+            sampling: SamplingConfig {
+                interval: Duration::from_secs(5),
+                decision_interval: Some(Duration::from_secs(60)),
+                aggregate: Aggregate::Mean,
+            },
+            ..Config::default()
+        };
+        let start = Instant::now();
+        let mut controller = controller(&config, start);
+        let mut transitions = vec![];
+        // A loop that falls behind, samples 30s apart so 12 of them take 6 minutes rather than one.
+        for i in 0..36 {
+            // Cold after the first, with a spike every 6th that the latest sample would have switched on.
+            let temperature = match i {
+                0 => 6.0,
+                i if i % 6 == 0 => 8.0,
+                _ => -1.0,
+            };
+            let (next, effects) = step(controller, reading(temperature, start, 30 * i));
+            controller = next;
+            transitions.extend(effects.into_iter().filter_map(|e| match e {
+                Effect::Transition(t) => Some((30 * i, t.to)),
+                _ => None,
+            }));
+        }
+        assert_eq!(
+            vec![
+                (0, State::MinimumIntervalOn(start)),
+                (360, State::MinimumIntervalOff(start + Duration::from_secs(360))),
+            ],
+            transitions
+        );
+    }
+
+    #[test]
+    fn freezer_band_resets_compensation_just_above_it() {
+        let config = Preset::Freezer.config();
+        let start = Instant::now();
+        let seed = Seed {
+            compensation: (DegreesDelta(0.5), DegreesDelta::ZERO),
+            ..Seed::new(State::Off)
+        };
+        let controller = ControllerState::new(seed, &config, start);
+        assert_eq!((Celsius(-19.5), Celsius(-17.0)), controller.thresholds());
+        let (controller, effects) = step(controller, reading(-16.95, start, 10));
+        assert!(effects.contains(&Effect::SetPower(true)));
+        assert_eq!((Celsius(-19.5), Celsius(-17.0)), controller.thresholds());
+        let (controller, effects) = step(controller, reading(-16.8, start, 20));
+        assert!(effects.contains(&Effect::PersistCompensation {
+            cooling: DegreesDelta::ZERO,
+            heating: DegreesDelta::ZERO,
+        }));
+        assert_eq!((Celsius(-20.0), Celsius(-17.0)), controller.thresholds());
+    }
+
+    #[test]
+    fn differential_band_carries_the_compensation() {
+        use crate::differential::DifferentialConfig;
+        let config = Config {
+            differential: DifferentialConfig {
+                below: Some((DegreesDelta(4.0), DegreesDelta(2.0))),
+                floor: Celsius(8.0),
+                ceiling: Celsius(16.0),
+            },
+            ..Config::default()
+        };
+        let start = Instant::now();
+        let seed = Seed {
+            compensation: (DegreesDelta(0.5), DegreesDelta(-0.25)),
+            ..Seed::new(State::Off)
+        };
+        let controller = ControllerState::new(seed, &config, start);
+        let at = |temperature: f32, ambient: f32, seconds: u64| Input {
+            ambient: Some(Celsius(ambient)),
+            ..reading(temperature, start, seconds)
+        };
+        let (controller, effects) = step(controller, at(13.0, 15.0, 10));
+        assert_eq!((Celsius(11.5), Celsius(12.75)), controller.thresholds());
+        assert!(effects.contains(&Effect::Status(
+            "MinimumIntervalOn at 13.00C 55.40F, ambient 15.00C 59.00F band 11.00C 51.80F to 13.00C 55.40F unclamped, \
+             0.0h compressor runtime, 0 starts today"
+                .into()
+        )));
+        // A heat wave stops at the ceiling.
+        let (controller, _) = step(controller, at(13.0, 30.0, 20));
+        assert_eq!((Celsius(14.5), Celsius(15.75)), controller.thresholds());
+        // Without an ambient reading the band stays put.
+        let (controller, _) = step(controller, reading(13.0, start, 30));
+        assert_eq!((Celsius(14.5), Celsius(15.75)), controller.thresholds());
+    }
+
+    #[test]
+    fn replaced_sensor_starts_compensation_over() {
+        let start = Instant::now();
+        let seed = Seed {
+            compensation: (DegreesDelta(0.4), DegreesDelta(-0.3)),
+            ..Seed::new(State::Off)
+        };
+        let controller = ControllerState::new(seed, &Config::default(), start);
+        let target = Config::default().band.target;
+        let target = (
+            target.start.millidegrees().celsius(),
+            target.end.millidegrees().celsius(),
+        );
+        assert_ne!(target, controller.thresholds());
+        let input = Input {
+            sensor_replaced: true,
+            boost: None,
+            revert_timing: false,
+            ..reading(3.0, start, 10)
+        };
+        let (controller, effects) = step(controller, input);
+        assert_eq!(
+            Some(&Effect::PersistCompensation {
+                cooling: DegreesDelta::ZERO,
+                heating: DegreesDelta::ZERO,
+            }),
+            effects.first()
+        );
+        assert_eq!(target, controller.thresholds());
+    }
+
+    #[test]
+    fn energy_is_checkpointed_and_summarized() {
+        let config = Config {
+            energy: EnergyConfig {
+                compressor_watts: Some(120.0),
+                ..EnergyConfig::default()
+            },
+            ..Config::default()
+        };
+        let start = Instant::now();
+        // Starting half an hour before midnight.
+        let input = |temperature: f32, secs: u64| Input {
+            local: LocalTime::from_local_seconds(60 * 60 * 24 - 30 * 60).plus(Duration::from_secs(secs)),
+            ..reading(temperature, start, secs)
+        };
+        let (controller, effects) = step(controller(&config, start), input(6.0, 10));
+        assert!(effects.contains(&Effect::Status(
+            "MinimumIntervalOn at 6.00C 42.80F, 0.00 kWh today, 0.0h compressor runtime, 0 starts today".into()
+        )));
+        // On from 10s, the first checkpoint after 15 minutes.
+        let (controller, effects) = step(controller, input(3.0, 15 * 60));
+        let totals = match effects.as_slice() {
+            [Effect::PersistEnergy(totals), ..] => *totals,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!((totals.today - 0.12 * 890.0 / 3600.0).abs() < 1e-6);
+        let (mut controller, effects) = step(controller, input(3.0, 40 * 60));
+        assert!(matches!(
+            effects.as_slice(),
+            [
+                Effect::Notify(Event::DailyEnergy(DailyEnergy { cost: None, .. })),
+                Effect::PersistEnergy(EnergyTotals { day: 1, .. }),
+                Effect::PersistRuntime(_),
+                Effect::Notify(Event::DailyStarts(DailyStarts { starts: 1, trend: None })),
+                Effect::PersistStarts(_),
+                Effect::Heartbeat(_)
+            ]
+        ));
+        let totals = controller.energy_checkpoint(start + Duration::from_secs(50 * 60), input(3.0, 50 * 60).local);
+        // 20 of the 50 minutes on after midnight.
+        assert!((totals.unwrap().today - 0.12 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn runtime_is_checkpointed_and_reset() {
+        let start = Instant::now();
+        let seed = Seed {
+            runtime: Duration::from_secs(36_000),
+            ..Seed::new(State::Off)
+        };
+        let controller = ControllerState::new(seed, &Config::default(), start);
+        let (controller, effects) = step(controller, reading(6.0, start, 10));
+        assert!(effects.contains(&Effect::Status(
+            "MinimumIntervalOn at 6.00C 42.80F, 10.0h compressor runtime, 0 starts today".into()
+        )));
+        let (controller, effects) = step(controller, reading(6.0, start, 15 * 60 + 10));
+        assert_eq!(
+            Some(&Effect::PersistRuntime(Duration::from_secs(36_900))),
+            effects.first()
+        );
+        let input = Input {
+            reset_runtime: true,
+            ..reading(6.0, start, 20 * 60 + 10)
+        };
+        let (controller, effects) = step(controller, input);
+        assert_eq!(
+            vec![
+                Effect::Notify(Event::RuntimeReset {
+                    previous: Duration::from_secs(37_200)
+                }),
+                Effect::PersistRuntime(Duration::from_secs(0)),
+                Effect::Heartbeat(HeartbeatStatus::Controlling)
+            ],
+            effects
+        );
+        assert_eq!(
+            Duration::from_secs(60),
+            controller.runtime(start + Duration::from_secs(21 * 60 + 10))
+        );
+    }
+
+    #[test]
+    fn a_faulted_primary_is_reset_by_command() {
+        use crate::{current::CurrentConfig, failover::FailoverConfig};
+        let start = Instant::now();
+        let config = Config {
+            current: CurrentConfig {
+                enabled: true,
+                ..CurrentConfig::default()
+            },
+            failover: FailoverConfig {
+                enabled: true,
+                ..FailoverConfig::default()
+            },
+            ..Config::default()
+        };
+        let seed = Seed {
+            failover: true,
+            ..Seed::new(State::Off)
+        };
+        let controller = ControllerState::new(seed, &config, start);
+        assert_eq!(Some((Output::Backup, true)), controller.output());
+        let input = Input {
+            reset_failover: true,
+            ..reading(6.0, start, 10)
+        };
+        let (controller, effects) = step(controller, input);
+        assert_eq!(
+            vec![
+                Effect::SelectOutput(Output::Primary),
+                Effect::PersistFailover(false),
+                Effect::Notify(Event::PrimaryRestored { reset: true }),
+            ],
+            effects
+                .into_iter()
+                .filter(|effect| matches!(
+                    effect,
+                    Effect::SelectOutput(_) | Effect::PersistFailover(_) | Effect::Notify(_)
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some((Output::Primary, false)), controller.output());
+    }
+
+    fn boost(bottom: f32, top: f32, minutes: u64) -> Boost {
+        Boost {
+            band: Celsius(bottom)..Celsius(top),
+            remaining: Duration::from_secs(minutes * 60),
+        }
+    }
+
+    #[test]
+    fn boost_reverts_when_it_expires() {
+        let start = Instant::now();
+        let controller = controller(&Config::default(), start);
+        let input = Input {
+            boost: Some(BoostRequest::Start(boost(1.0, 2.0, 60))),
+            ..reading(3.0, start, 10)
+        };
+        let (controller, effects) = step(controller, input);
+        assert_eq!(
+            &[
+                Effect::Notify(Event::BoostStarted {
+                    band: Celsius(1.0)..Celsius(2.0),
+                    duration: Duration::from_secs(3600)
+                }),
+                Effect::PersistBoost(Some(boost(1.0, 2.0, 60))),
+            ],
+            &effects[..2]
+        );
+        assert_eq!((Celsius(1.0), Celsius(2.0)), controller.thresholds());
+        assert!(effects.contains(&Effect::Status(
+            "MinimumIntervalOn at 3.00C 37.40F, boosted to 1.00C 33.80F to 2.00C 35.60F for 60m more, 0.0h \
+             compressor runtime, 0 starts today"
+                .into()
+        )));
+        let (controller, effects) = step(controller, reading(1.5, start, 59 * 60));
+        assert!(!effects
+            .iter()
+            .any(|e| matches!(e, Effect::Notify(Event::BoostEnded { .. }))));
+        assert_eq!(
+            Some((Celsius(1.0)..Celsius(2.0), Duration::from_secs(10))),
+            controller.boost(start + Duration::from_secs(60 * 60))
+        );
+        let (controller, effects) = step(controller, reading(1.5, start, 60 * 60 + 10));
+        assert_eq!(
+            &[
+                Effect::Notify(Event::BoostEnded {
+                    band: TARGET_RANGE,
+                    cancelled: false
+                }),
+                Effect::PersistBoost(None),
+            ],
+            &effects[..2]
+        );
+        assert_eq!(TARGET_RANGE, controller.band());
+        assert_eq!(None, controller.boost(start + Duration::from_secs(60 * 60 + 10)));
+    }
+
+    #[test]
+    fn cancelled_boost_learned_nothing() {
+        let start = Instant::now();
+        let seed = Seed {
+            compensation: (DegreesDelta(0.5), DegreesDelta::ZERO),
+            ..Seed::new(State::Off)
+        };
+        let mut controller = ControllerState::new(seed, &Config::default(), start);
+        let input = Input {
+            boost: Some(BoostRequest::Start(boost(1.0, 2.0, 6 * 60))),
+            ..reading(3.0, start, 10)
+        };
+        // Cycles overshooting both ends, well above where the cooling compensation would be reset.
+        let mut inputs = vec![input];
+        inputs.extend((1..20).map(|i| reading([3.0, 0.5][i % 2], start, 10 + i as u64 * 600)));
+        for input in inputs {
+            let (next, effects) = step(controller, input);
+            controller = next;
+            assert!(!effects.iter().any(|e| matches!(e, Effect::PersistCompensation { .. })));
+        }
+        assert!(controller.completed_cycles() >= 8);
+        let input = Input {
+            boost: Some(BoostRequest::Cancel),
+            ..reading(3.0, start, 4 * 60 * 60)
+        };
+        let (controller, effects) = step(controller, input);
+        assert!(effects.contains(&Effect::Notify(Event::BoostEnded {
+            band: TARGET_RANGE,
+            cancelled: true
+        })));
+        assert!(effects.contains(&Effect::PersistBoost(None)));
+        // Back on the configured band, the learned compensation is still there.
+        assert_eq!((Celsius(1.056), Celsius(4.333)), controller.thresholds());
+    }
+
+    #[test]
+    fn a_reload_keeps_the_state() {
+        let start = Instant::now();
+        let config = Config::default();
+        let (controller, _) = step(controller(&config, start), reading(6.0, start, 10));
+        let on = State::MinimumIntervalOn(start + Duration::from_secs(10));
+        assert_eq!(on, controller.state());
+        let mut moved = config.clone();
+        moved.band.target = Celsius(2.0)..Celsius(5.0);
+        moved.band.max_compensation = DegreesDelta(1.5);
+        moved.intervals.on = Duration::from_secs(5 * 60);
+        moved.stall.polls += 1;
+        let reloaded = config.reload(&moved).unwrap();
+        assert_eq!(3, reloaded.changes.len(), "{:?}", reloaded.changes);
+        assert!(reloaded.needs_restart);
+        assert_eq!(config.stall, reloaded.config.stall);
+        let input = Input {
+            reload: Some(reloaded.config),
+            ..reading(6.0, start, 20)
+        };
+        let (controller, _) = step(controller, input);
+        assert_eq!(on, controller.state());
+        assert_eq!((Celsius(2.0), Celsius(5.0)), controller.thresholds());
+        // Cold enough to stop, but the reloaded minimum on time isn't up.
+        let (controller, effects) = step(controller, reading(1.0, start, 200));
+        assert!(!effects.contains(&Effect::SetPower(false)));
+        assert_eq!(on, controller.state());
+        let (_, effects) = step(controller, reading(1.0, start, 320));
+        assert!(effects.contains(&Effect::SetPower(false)));
+
+        let mut inverted = config.clone();
+        inverted.band.target = Celsius(5.0)..Celsius(2.0);
+        assert!(config.reload(&inverted).is_err());
+        let unchanged = config.reload(&config).unwrap();
+        assert!(unchanged.changes.is_empty());
+        assert!(!unchanged.needs_restart);
+    }
+
+    #[test]
+    fn a_reload_caps_compensation_to_the_millidegree() {
+        let mut config = Config::default();
+        config.band.target = Celsius(2.0)..Celsius(5.0);
+        config.band.max_compensation = DegreesDelta(1.0);
+        let seed = Seed {
+            compensation: (DegreesDelta(1.0), DegreesDelta(-1.0)),
+            ..Seed::new(State::Off)
+        };
+        let start = Instant::now();
+        let controller = ControllerState::new(seed, &config, start);
+        assert_eq!((Celsius(3.0), Celsius(4.0)), controller.thresholds());
+        let mut reloaded = config.clone();
+        // A hair under in f32, truncated it would be 250.
+        reloaded.band.max_compensation = DegreesDelta(0.251);
+        let input = Input {
+            reload: Some(reloaded.clone()),
+            ..reading(3.5, start, 10)
+        };
+        let (controller, _) = step(controller, input);
+        assert_eq!((Celsius(2.251), Celsius(4.749)), controller.thresholds());
+
+        // Never past the middle of the band, however the width rounds.
+        reloaded.band.target = Celsius(2.0)..Celsius(2.003);
+        reloaded.band.max_compensation = DegreesDelta(0.0015);
+        assert_eq!(Millidegrees(1), reloaded.band.compensation_cap());
+    }
+
+    #[test]
+    fn applies_timing_advice_until_reverted() {
+        let mut config = Config::default();
+        config.timing.cycles = Some(2);
+        config.timing.auto_apply = true;
+        let start = Instant::now();
+        let mut controller = controller(&config, start);
+        let tuned = MinimumIntervals {
+            on: Duration::from_secs(10 * 60),
+            off: Duration::from_secs(10 * 60),
+        };
+        let mut timing = vec![];
+        for i in 0..10 {
+            let input = Input {
+                revert_timing: i == 5 || i == 6,
+                ..reading([6.0, 0.0][i % 2], start, i as u64 * 20 * 60)
+            };
+            let (next, effects) = step(controller, input);
+            controller = next;
+            timing.extend(effects.into_iter().filter_map(|effect| match effect {
+                Effect::PersistIntervals(intervals) => Some((i, format!("{:?}", intervals))),
+                Effect::Notify(event @ Event::TimingAdvice { .. })
+                | Effect::Notify(event @ Event::TimingReverted { .. }) => Some((i, event.to_string())),
+                _ => None,
+            }));
+        }
+        assert_eq!(
+            vec![
+                (4, format!("{:?}", Some(tuned))),
+                (
+                    4,
+                    "Minimum intervals tuned: on 2m -> 10m (0 of 2 held by the minimum, naturally 1200s), off 8m -> \
+                     10m (0 of 2 held by the minimum, naturally 1200s)."
+                        .into()
+                ),
+                (5, "None".into()),
+                (
+                    5,
+                    "Tuned minimum intervals reverted to the configured 2m on and 8m off.".into()
+                ),
+                (
+                    8,
+                    "Minimum intervals could be tuned: on 2m -> 10m (0 of 2 held by the minimum, naturally 1200s), \
+                     off 8m -> 10m (0 of 2 held by the minimum, naturally 1200s)."
+                        .into()
+                ),
+            ],
+            timing
+        );
+    }
+
+    #[test]
+    fn cycles_after_a_boost_are_learned_from() {
+        let start = Instant::now();
+        let mut controller = controller(&Config::default(), start);
+        let input = Input {
+            boost: Some(BoostRequest::Start(boost(1.0, 2.0, 6 * 60))),
+            ..reading(3.0, start, 10)
+        };
+        let mut inputs = vec![input];
+        inputs.extend((1..10).map(|i| reading([3.0, 0.5][i % 2], start, 10 + i as u64 * 600)));
+        inputs.push(Input {
+            boost: Some(BoostRequest::Cancel),
+            ..reading(5.0, start, 10 + 10 * 600)
+        });
+        inputs.extend((11..16).map(|i| reading([5.0, 0.0][i % 2], start, 10 + i as u64 * 600)));
+        let (mut learned, mut rated) = (vec![], vec![]);
+        for (i, input) in inputs.into_iter().enumerate() {
+            let (next, effects) = step(controller, input);
+            controller = next;
+            if effects.iter().any(|e| matches!(e, Effect::PersistCompensation { .. })) {
+                learned.push(i);
+            }
+            if effects.iter().any(|e| matches!(e, Effect::PersistCoolingRates(_))) {
+                rated.push(i);
+            }
+        }
+        // Overshooting the boost band by a degree taught nothing, the undershoots back on the configured band did.
+        assert_eq!(vec![12, 14], learned);
+        assert_eq!(vec![11, 13, 15], rated);
+        assert!(controller.thresholds().0 > TARGET_RANGE.start);
+    }
+
+    #[test]
+    fn records_how_far_each_cycle_missed_the_band() {
+        let start = Instant::now();
+        let seed = Seed {
+            // Switching on a quarter degree inside the top of the band.
+            compensation: (DegreesDelta::ZERO, DegreesDelta(-0.25)),
+            ..Seed::new(State::Off)
+        };
+        let mut controller = ControllerState::new(seed, &Config::default(), start);
+        let mut persisted = vec![];
+        for i in 0..8 {
+            let (next, effects) = step(controller, reading([4.2, 0.0][i % 2], start, i as u64 * 600));
+            controller = next;
+            persisted.extend(effects.into_iter().filter_map(|effect| match effect {
+                Effect::PersistOvershoots(overshoots) => Some((i, overshoots)),
+                _ => None,
+            }));
+        }
+        // Below the bottom of the band rather than the threshold, and never above a top it didn't reach.
+        let under = TARGET_RANGE.start - Celsius(0.0);
+        assert_eq!(
+            vec![
+                (
+                    2,
+                    Overshoots {
+                        over: vec![],
+                        under: vec![under],
+                    }
+                ),
+                (
+                    3,
+                    Overshoots {
+                        over: vec![DegreesDelta::ZERO],
+                        under: vec![under],
+                    }
+                ),
+            ],
+            persisted
+        );
+        assert_eq!((Some(DegreesDelta::ZERO), Some(under)), controller.overshoots());
+    }
+
+    #[test]
+    fn a_long_run_rests_the_compressor_until_the_rest_runs_out() {
+        let start = Instant::now();
+        let mut config = Config::default();
+        config.rest.after = Some(Duration::from_secs(60 * 60));
+        let mut controller = controller(&config, start);
+        let mut rests = vec![];
+        let mut switches = vec![];
+        // Warm for 70 minutes, cold, then warm again from 80 minutes on.
+        for i in 1..=12 {
+            let secs = i * 600;
+            let temperature = match secs {
+                4200 => 0.0,
+                _ => 10.0,
+            };
+            let (next, effects) = step(controller, reading(temperature, start, secs));
+            controller = next;
+            for effect in effects {
+                match effect {
+                    Effect::PersistRest(rest) => rests.push((secs, rest)),
+                    Effect::SetPower(on) => switches.push((secs, on)),
+                    _ => {}
+                }
+            }
+            if secs == 4800 {
+                assert_eq!(
+                    Some(RestPeriod {
+                        run: Duration::from_secs(3600),
+                        remaining: Duration::from_secs(20 * 60),
+                    }),
+                    controller.rest(start + Duration::from_secs(secs))
+                );
+                assert!(controller
+                    .diagnostics(start + Duration::from_secs(secs))
+                    .contains("\nrest 1200 3600"));
+            }
+        }
+        assert_eq!(vec![(600, true), (4200, false), (6000, true)], switches);
+        assert_eq!(
+            vec![
+                (
+                    4200,
+                    Some(RestPeriod {
+                        run: Duration::from_secs(3600),
+                        remaining: Duration::from_secs(30 * 60),
+                    })
+                ),
+                (6000, None),
+            ],
+            rests
+        );
+    }
+
+    type ByMinute<T> = (u64, T);
+
+    /// Steps through (minute, degrees past the high threshold, shed signal), the relay switches and what the shed
+    /// notified and persisted.
+    fn shed(config: &Config, script: &[(u64, f32, bool)]) -> (Vec<(u64, bool)>, Vec<ByMinute<Effect>>) {
+        let start = Instant::now();
+        let mut controller = controller(config, start);
+        let (_, high) = controller.thresholds();
+        let mut switches = vec![];
+        let mut sheds = vec![];
+        for &(minute, excess, asserted) in script {
+            let input = Input {
+                shed: asserted,
+                ..reading((high + DegreesDelta(excess)).0, start, minute * 60)
+            };
+            let (next, effects) = step(controller, input);
+            controller = next;
+            for effect in effects {
+                match effect {
+                    Effect::SetPower(on) => switches.push((minute, on)),
+                    Effect::PersistShed(_)
+                    | Effect::Notify(Event::ShedStarted { .. })
+                    | Effect::Notify(Event::ShedEnded { .. })
+                    | Effect::Transition(Transition {
+                        reason: TransitionReason::LoadShed,
+                        ..
+                    }) => sheds.push((minute, effect)),
+                    _ => {}
+                }
+            }
+        }
+        (switches, sheds)
+    }
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    #[test]
+    fn records_each_on_cycle_with_the_off_phase_ahead_of_it() {
+        let start = Instant::now();
+        let mut controller = controller(&Config::default(), start);
+        let mut cycles = vec![];
+        for poll in 0..270 {
+            let temperature = if poll % 90 < 30 { 6.0 } else { 0.0 };
+            let (next, effects) = step(controller, reading(temperature, start, poll * 10));
+            controller = next;
+            cycles.extend(effects.into_iter().filter_map(|effect| match effect {
+                Effect::RecordCycle(cycle) => Some(cycle),
+                _ => None,
+            }));
+        }
+        let summary: Vec<_> = cycles
+            .iter()
+            .map(|cycle| {
+                (
+                    cycle.on.as_secs(),
+                    cycle.off.map(|off| off.as_secs()),
+                    cycle.abnormal.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (300, None, Some("first cycles")),
+                (300, Some(600), None),
+                (300, Some(600), None)
+            ],
+            summary
+        );
+        assert!(cycles[2].cooling_rate.is_some());
+        assert_eq!(
+            Ok(TransitionReason::LoadShed),
+            "load-shed".parse().map_err(|_: anyhow::Error| ())
+        );
+        assert!("door".parse::<TransitionReason>().is_err());
+    }
+
+    #[test]
+    fn a_start_waits_out_the_pump_lead() {
+        let mut config = Config::default();
+        config.pump.enabled = true;
+        let start = Instant::now();
+        let mut controller = controller(&config, start);
+        let mut read_back = None;
+        let mut switches = vec![];
+        let mut reasons = vec![];
+        for secs in (600..=660).step_by(10) {
+            let input = Input {
+                pump: read_back,
+                ..reading(6.0, start, secs)
+            };
+            let (next, effects) = step(controller, input);
+            controller = next;
+            for effect in effects {
+                match effect {
+                    Effect::SetPump(on) => {
+                        read_back = Some(on);
+                        switches.push((secs, "pump", on));
+                    }
+                    Effect::SetPower(on) => switches.push((secs, "relay", on)),
+                    Effect::Transition(transition) => reasons.push((secs, transition.reason)),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(vec![(600, "pump", true), (630, "relay", true)], switches);
+        assert_eq!(vec![(630, TransitionReason::PumpPrimed)], reasons);
+    }
+
+    #[test]
+    fn a_shed_holds_off_starts_and_ends_a_run_past_its_minimum() {
+        let mut config = Config::default();
+        config.shed.terminate = true;
+        let (switches, sheds) = shed(
+            &config,
+            &[
+                (10, 1.0, false),
+                (11, 1.0, true),
+                // The minimum on time is up.
+                (13, 1.0, true),
+                (30, 1.5, true),
+                (40, 1.5, false),
+            ],
+        );
+        assert_eq!(vec![(10, true), (13, false), (40, true)], switches);
+        let kinds: Vec<(u64, String)> = sheds
+            .iter()
+            .map(|(minute, effect)| (*minute, format!("{:?}", effect)))
+            .collect();
+        assert_eq!(5, kinds.len(), "{:?}", kinds);
+        assert_eq!(
+            (
+                11,
+                Effect::Notify(Event::ShedStarted {
+                    max_duration: minutes(120)
+                })
+            ),
+            sheds[0]
+        );
+        assert_eq!(
+            (
+                11,
+                Effect::PersistShed(Some(ShedPeriod {
+                    since: minutes(0),
+                    remaining: minutes(120)
+                }))
+            ),
+            sheds[1]
+        );
+        assert!(kinds[2].0 == 13 && kinds[2].1.contains("LoadShed"), "{:?}", kinds);
+        assert_eq!(
+            vec![
+                (
+                    40,
+                    Effect::Notify(Event::ShedEnded {
+                        lasted: minutes(29),
+                        end: ShedEnd::Released
+                    })
+                ),
+                (40, Effect::PersistShed(None)),
+            ],
+            sheds[3..]
+        );
+        // Left to finish without --shed-terminate.
+        let (switches, _) = shed(
+            &Config::default(),
+            &[(10, 1.0, false), (11, 1.0, true), (13, 1.0, true)],
+        );
+        assert_eq!(vec![(10, true)], switches);
+    }
+
+    #[test]
+    fn a_shed_gives_way_to_the_excursion_and_its_maximum() {
+        let mut config = Config::default();
+        config.shed.max_duration = minutes(60);
+        let (switches, sheds) = shed(
+            &config,
+            &[
+                (0, 1.0, true),
+                (30, 1.9, true),
+                (40, 2.1, true),
+                (45, -5.0, true),
+                // Spent, the same assertion doesn't shed again.
+                (60, 1.0, true),
+                (70, -5.0, false),
+                (80, 1.0, true),
+                (140, 1.0, true),
+            ],
+        );
+        assert_eq!(
+            vec![(40, true), (45, false), (60, true), (70, false), (140, true)],
+            switches
+        );
+        let spent = |since| {
+            Effect::PersistShed(Some(ShedPeriod {
+                since: minutes(since),
+                remaining: minutes(0),
+            }))
+        };
+        let ended = |lasted, end| {
+            Effect::Notify(Event::ShedEnded {
+                lasted: minutes(lasted),
+                end,
+            })
+        };
+        assert_eq!(
+            vec![
+                (40, ended(40, ShedEnd::Excursion)),
+                (40, spent(40)),
+                (70, Effect::PersistShed(None)),
+            ],
+            sheds[2..5]
+        );
+        assert_eq!(vec![(140, ended(60, ShedEnd::TimedOut)), (140, spent(60))], sheds[7..]);
+    }
+
+    #[test]
+    fn quiet_hours_hold_starts_within_the_guard_after_the_minimum_interval() {
+        let start = Instant::now();
+        let mut config = Config::default();
+        config.quiet_hours.window = Some("23:00-06:00".parse().unwrap());
+        let mut controller = controller(&config, start);
+        let (_, high) = controller.thresholds();
+        let mut switches = vec![];
+        // From 22:00, minutes in and degrees past the high threshold.
+        for (minute, excess) in [
+            (10, 1.0),
+            (20, -5.0),
+            (70, 1.0),
+            (80, 1.4),
+            (90, 1.6),
+            (100, -5.0),
+            // Still within the minimum off interval, it holds the relay rather than quiet hours.
+            (104, 0.5),
+            (110, 0.5),
+            (115, 1.6),
+            (125, -5.0),
+            (470, 0.5),
+            (480, 0.5),
+        ] {
+            let mut input = reading((high + DegreesDelta(excess)).0, start, minute * 60);
+            input.local = LocalTime::default().plus(Duration::from_secs((22 * 60 + minute) * 60));
+            let (next, effects) = step(controller, input);
+            controller = next;
+            switches.extend(effects.into_iter().filter_map(|effect| match effect {
+                Effect::SetPower(on) => Some((minute, on)),
+                _ => None,
+            }));
+        }
+        assert_eq!(
+            vec![
+                (10, true),
+                (20, false),
+                (90, true),
+                (100, false),
+                (115, true),
+                (125, false),
+                (480, true)
+            ],
+            switches
+        );
+        let stats = controller.quiet_hours(start + Duration::from_secs(480 * 60)).unwrap();
+        assert_eq!((3, Duration::from_secs(35 * 60)), (stats.suppressed, stats.held));
+        assert!((stats.excess.unwrap().0 - 1.4).abs() < 0.001, "{:?}", stats.excess);
+        assert_eq!(None, self::controller(&Config::default(), start).quiet_hours(start));
+    }
+
+    #[test]
+    fn restart_holds_what_is_left_of_a_rest() {
+        let start = Instant::now();
+        let mut config = Config::default();
+        config.rest.after = Some(Duration::from_secs(60 * 60));
+        let seed = Seed {
+            rest: Some(RestPeriod {
+                run: Duration::from_secs(2 * 3600),
+                remaining: Duration::from_secs(10 * 60),
+            }),
+            ..Seed::new(State::Off)
+        };
+        let controller = ControllerState::new(seed, &config, start);
+        let (controller, effects) = step(controller, reading(10.0, start, 10));
+        assert!(!effects.contains(&Effect::SetPower(true)));
+        assert_eq!(State::Off, controller.state());
+        assert_eq!(
+            Effect::Status("Off, resting 9m more after a 120m run, 0.0h compressor runtime, 0 starts today".into()),
+            controller.status(start + Duration::from_secs(10), "Off".into())
+        );
+        let (controller, effects) = step(controller, reading(10.0, start, 10 * 60));
+        assert!(effects.contains(&Effect::PersistRest(None)));
+        assert!(effects.contains(&Effect::SetPower(true)));
+        assert_eq!(None, controller.rest(start + Duration::from_secs(10 * 60)));
+    }
+
+    #[test]
+    fn restart_mid_off_cycle_learns_the_undershoot_an_uninterrupted_run_would() {
+        let start = Instant::now();
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        // Down from 6C to -1C and back every 40 minutes, the chamber coasting on past the low threshold.
+        let chamber = |minute: u64| match minute % 40 {
+            phase if phase < 20 => 6.0 - 0.35 * phase as f32,
+            phase => -1.0 + 0.35 * (phase - 20) as f32,
+        };
+        let config = Config::default();
+        let mut uninterrupted = controller(&config, start);
+        let (mut checkpoint, mut off_at) = (None, 0);
+        for minute in 0..150 {
+            let (next, effects) = step(uninterrupted, reading(chamber(minute), start, minute * 60));
+            uninterrupted = next;
+            for effect in effects {
+                match effect {
+                    Effect::PersistExtremes(extremes) => checkpoint = extremes.map(|e| (minute, e)),
+                    Effect::SetPower(false) => off_at = minute,
+                    _ => {}
+                }
+            }
+        }
+        // Down since minute 136, past the bottom at 140 and checkpointed at 146.
+        let (checkpointed_at, mut extremes) = checkpoint.unwrap();
+        assert_eq!((false, Celsius(-1.0)), (extremes.on, extremes.min));
+        extremes.age = minutes(150 - checkpointed_at);
+        extremes.running += extremes.age;
+        let power_state = RestoredPowerState::OffFor(minutes(150 - off_at));
+        assert!(extremes.continues(power_state));
+        let now = start + minutes(150);
+        let restart = |config: &Config| {
+            let seed = Seed {
+                extremes: Some(extremes),
+                ..Seed::new(determine_initial_state(Ok(power_state), now, config.intervals))
+            };
+            ControllerState::new(seed, config, now)
+        };
+        // What each run fed the low compensator as the relay came back on.
+        let until_on = |mut controller: ControllerState| {
+            for minute in 150..180 {
+                let (next, effects) = step(controller, reading(chamber(minute), start, minute * 60));
+                controller = next;
+                if effects.contains(&Effect::SetPower(true)) {
+                    let cleared = effects.contains(&Effect::PersistExtremes(None));
+                    return (controller.low_compensator.observations().last(), cleared);
+                }
+            }
+            panic!("Relay never came back on.");
+        };
+        let (observed, _) = until_on(uninterrupted);
+        // The bottom at -1C, under the band's 0.556C.
+        assert_eq!(Some(Millidegrees(1556)), observed);
+        assert_eq!((observed, true), until_on(restart(&config)));
+        let mut stale_config = config.clone();
+        stale_config.storage.checkpoint_max_age = minutes(2);
+        assert_eq!((None, false), until_on(restart(&stale_config)));
+    }
+
+    #[test]
+    fn restart_resumes_the_rest_of_a_boost() {
+        let start = Instant::now();
+        let seed = Seed {
+            boost: Some(boost(1.0, 2.0, 20)),
+            ..Seed::new(State::Off)
+        };
+        let controller = ControllerState::new(seed, &Config::default(), start);
+        assert_eq!(Celsius(1.0)..Celsius(2.0), controller.band());
+        let (controller, effects) = step(controller, reading(1.5, start, 10));
+        assert!(!effects
+            .iter()
+            .any(|e| matches!(e, Effect::PersistBoost(_) | Effect::Notify(_))));
+        let (controller, effects) = step(controller, reading(1.5, start, 20 * 60));
+        assert!(effects.contains(&Effect::Notify(Event::BoostEnded {
+            band: TARGET_RANGE,
+            cancelled: false
+        })));
+        assert_eq!(TARGET_RANGE, controller.band());
+    }
+
+    #[test]
+    fn missing_sensor_terminates_without_budget() {
+        let config = Config {
+            sensor_failsafe: FailsafeConfig {
+                budget: Duration::from_secs(0),
+                reinitialize_attempts: 0,
+                ..FailsafeConfig::default()
+            },
+            ..Config::default()
+        };
+        let start = Instant::now();
+        let input = Input {
+            temperature: Err(SensorError::Missing(PathBuf::from("/missing"))),
+            now: start,
+            local: LocalTime::default(),
+            ambient: None,
+            acknowledged: false,
+            reset_runtime: false,
+            sensor_replaced: false,
+            boost: None,
+            revert_timing: false,
+            shed: false,
+            pump: None,
+            current: None,
+            reset_failover: false,
+            reload: None,
+        };
+        let (controller, effects) = step(controller(&config, start), input);
+        assert!(controller.state() == State::Fault);
+        assert_eq!(
+            vec![
+                Effect::Transition(Transition {
+                    from: State::Off,
+                    to: State::Fault,
+                    reason: TransitionReason::SensorFailsafe,
+                    temperature: None,
+                }),
+                Effect::Status("Fault, sensor failsafe engaged, 0.0h compressor runtime, 0 starts today".into()),
+                Effect::Notify(Event::SensorFailsafeEngaged {
+                    failing_for: Duration::from_secs(0),
+                    policy: config.sensor_failsafe.policy,
+                    cause: SensorErrorKind::Missing
+                }),
+                Effect::Terminate(Termination::SensorFailed {
+                    failing_for: Duration::from_secs(0),
+                    reinitializations: 0
+                }),
+            ],
+            effects
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use crate::temperature::Celsius;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const BASELINE_CYCLES: usize = 20;
+const MINIMUM_BASELINE_CYCLES: usize = 5;
+/// Cycles noticeably slower than the baseline don't join it, or a slow decline would drag the baseline down with it.
+const BASELINE_ADMISSION: f32 = 0.8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CoolingMonitorConfig {
+    /// A cycle cooling slower than this fraction of the baseline is degraded.
+    pub degraded_fraction: f32,
+    /// A degraded monitor only recovers on a cycle this much more of the baseline than `degraded_fraction`.
+    pub clear_margin: f32,
+    /// Shorter on cycles are too noisy to measure.
+    pub minimum_cycle: Duration,
+}
+
+impl Default for CoolingMonitorConfig {
+    fn default() -> Self {
+        Self {
+            degraded_fraction: 0.5,
+            clear_margin: 0.0,
+            minimum_cycle: Duration::from_secs(60 * 5),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CoolingChange {
+    Degraded { rate: f32, baseline: f32 },
+    Recovered { rate: f32, baseline: f32 },
+}
+
+struct OnCycle {
+    started: Instant,
+    start_temperature: Celsius,
+    minimum: Celsius,
+    minimum_at: Instant,
+    abnormal: bool,
+}
+
+/// Tracks how fast each on cycle pulls the temperature down (degrees per minute from the compressor start to the
+/// lowest reading) against the median of recent normal cycles.
+pub struct CoolingMonitor {
+    config: CoolingMonitorConfig,
+    rates: VecDeque<f32>,
+    cycle: Option<OnCycle>,
+    /// The rate of the last measurable cycle, abnormal or not admitted to the baseline as it may be.
+    latest: Option<f32>,
+    degraded: bool,
+}
+
+impl CoolingMonitor {
+    pub fn new(config: CoolingMonitorConfig, seed_rates: Vec<f32>) -> Self {
+        let mut rates: VecDeque<f32> = seed_rates.into_iter().filter(|r| r.is_finite()).collect();
+        while rates.len() > BASELINE_CYCLES {
+            rates.pop_front();
+        }
+        Self {
+            config,
+            rates,
+            cycle: None,
+            latest: None,
+            degraded: false,
+        }
+    }
+
+    pub fn rates(&self) -> Vec<f32> {
+        self.rates.iter().copied().collect()
+    }
+
+    pub fn baseline(&self) -> Option<f32> {
+        if self.rates.len() < MINIMUM_BASELINE_CYCLES {
+            return None;
+        }
+        let mut sorted: Vec<f32> = self.rates.iter().copied().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("Invariant: Never contains NaN rates."));
+        let len = sorted.len();
+        Some(match len % 2 {
+            0 => (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0,
+            _ => sorted[len / 2],
+        })
+    }
+
+    pub fn latest(&self) -> Option<f32> {
+        self.latest
+    }
+
+    /// The latest rate as a fraction of the baseline, None until there are both.
+    pub fn ratio(&self) -> Option<f32> {
+        match (self.latest, self.baseline()) {
+            (Some(latest), Some(baseline)) if baseline > 0.0 => Some(latest / baseline),
+            _ => None,
+        }
+    }
+
+    pub fn start_cycle(&mut self, now: Instant, temperature: Celsius) {
+        self.cycle = Some(OnCycle {
+            started: now,
+            start_temperature: temperature,
+            minimum: temperature,
+            minimum_at: now,
+            abnormal: false,
+        });
+    }
+
+    pub fn observe(&mut self, now: Instant, temperature: Celsius) {
+        if let Some(cycle) = &mut self.cycle {
+            if temperature < cycle.minimum {
+                cycle.minimum = temperature;
+                cycle.minimum_at = now;
+            }
+        }
+    }
+
+    /// Exclude the current cycle, it isn't representative of normal cooling.
+    pub fn mark_abnormal(&mut self) {
+        if let Some(cycle) = &mut self.cycle {
+            cycle.abnormal = true;
+        }
+    }
+
+    /// Returns the measured rate (if the cycle was measurable) and any change in the degraded status.
+    pub fn end_cycle(&mut self, now: Instant) -> (Option<f32>, Option<CoolingChange>) {
+        let cycle = match self.cycle.take() {
+            Some(cycle) if !cycle.abnormal && now - cycle.started >= self.config.minimum_cycle => cycle,
+            _ => return (None, None),
+        };
+        let minutes = (cycle.minimum_at - cycle.started).as_secs_f32() / 60.0;
+        let rate = match minutes > 0.0 {
+            true => (cycle.start_temperature - cycle.minimum).0 / minutes,
+            false => 0.0,
+        };
+        self.latest = Some(rate);
+
+        let baseline = self.baseline();
+        let mut change = None;
+        if let Some(baseline) = baseline {
+            let limit = match self.degraded {
+                true => self.config.degraded_fraction + self.config.clear_margin,
+                false => self.config.degraded_fraction,
+            };
+            let degraded = rate < baseline * limit;
+            change = match (self.degraded, degraded) {
+                (false, true) => Some(CoolingChange::Degraded { rate, baseline }),
+                (true, false) => Some(CoolingChange::Recovered { rate, baseline }),
+                _ => None,
+            };
+            self.degraded = degraded;
+        }
+        if baseline.is_none_or(|baseline| rate >= baseline * BASELINE_ADMISSION) {
+            self.rates.push_back(rate);
+            if self.rates.len() > BASELINE_CYCLES {
+                self.rates.pop_front();
+            }
+        }
+        (Some(rate), change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    /// A 10 minute on cycle starting at 4C that bottoms out after 8 minutes.
+    fn cycle(monitor: &mut CoolingMonitor, start: Instant, rate: f32) -> Option<CoolingChange> {
+        monitor.start_cycle(start, Celsius(4.0));
+        for m in 1..=10 {
+            let t = 4.0 - rate * m.min(8) as f32;
+            monitor.observe(start + minutes(m), Celsius(t));
+        }
+        monitor.end_cycle(start + minutes(10)).1
+    }
+
+    #[test]
+    fn measures_rate_to_minimum() {
+        let start = Instant::now();
+        let mut monitor = CoolingMonitor::new(CoolingMonitorConfig::default(), vec![]);
+        monitor.start_cycle(start, Celsius(4.0));
+        monitor.observe(start + minutes(4), Celsius(3.0));
+        monitor.observe(start + minutes(8), Celsius(2.0));
+        monitor.observe(start + minutes(9), Celsius(2.5));
+        assert_eq!((Some(0.25), None), monitor.end_cycle(start + minutes(10)));
+    }
+
+    #[test]
+    fn short_and_abnormal_cycles_are_ignored() {
+        let start = Instant::now();
+        let mut monitor = CoolingMonitor::new(CoolingMonitorConfig::default(), vec![]);
+        monitor.start_cycle(start, Celsius(4.0));
+        monitor.observe(start + minutes(2), Celsius(3.0));
+        assert_eq!((None, None), monitor.end_cycle(start + minutes(4)));
+
+        monitor.start_cycle(start, Celsius(4.0));
+        monitor.observe(start + minutes(8), Celsius(2.0));
+        monitor.mark_abnormal();
+        assert_eq!((None, None), monitor.end_cycle(start + minutes(10)));
+        assert!(monitor.rates().is_empty());
+        assert_eq!(None, monitor.latest());
+    }
+
+    #[test]
+    fn ratio_waits_for_a_baseline() {
+        let start = Instant::now();
+        let mut monitor = CoolingMonitor::new(CoolingMonitorConfig::default(), vec![]);
+        for i in 0..MINIMUM_BASELINE_CYCLES as u64 - 1 {
+            cycle(&mut monitor, start + minutes(i * 60), 0.125);
+            assert_eq!(None, monitor.ratio());
+        }
+        cycle(&mut monitor, start + minutes(240), 0.125);
+        assert_eq!(Some(1.0), monitor.ratio());
+        // Too slow to join the baseline, still the latest.
+        cycle(&mut monitor, start + minutes(300), 0.0625);
+        assert_eq!(Some(0.0625), monitor.latest());
+        assert_eq!(Some(0.5), monitor.ratio());
+        assert_eq!(MINIMUM_BASELINE_CYCLES, monitor.rates().len());
+    }
+
+    #[test]
+    fn gradually_degrading_fridge() {
+        let start = Instant::now();
+        let mut monitor = CoolingMonitor::new(CoolingMonitorConfig::default(), vec![]);
+        let mut changes = Vec::new();
+        let mut rate = 0.1;
+        for i in 0..80 {
+            if i >= 20 {
+                rate *= 0.96;
+            }
+            if let Some(change) = cycle(&mut monitor, start + minutes(i * 60), rate) {
+                changes.push((i, change));
+            }
+        }
+        match changes.as_slice() {
+            [(i, CoolingChange::Degraded { rate, baseline })] => {
+                assert!(*rate < baseline * 0.5);
+                // Caught well before the compressor stops cooling altogether.
+                assert!(*rate > 0.01, "detected too late at cycle {}", i);
+            }
+            other => panic!("unexpected changes {:?}", other),
+        }
+        assert_eq!(BASELINE_CYCLES, monitor.rates().len());
+    }
+
+    #[test]
+    fn recovery_and_seeded_baseline() {
+        let start = Instant::now();
+        let mut monitor = CoolingMonitor::new(CoolingMonitorConfig::default(), vec![0.1; 25]);
+        assert_eq!(BASELINE_CYCLES, monitor.rates().len());
+        assert!(matches!(
+            cycle(&mut monitor, start, 0.04),
+            Some(CoolingChange::Degraded { .. })
+        ));
+        assert_eq!(None, cycle(&mut monitor, start + minutes(60), 0.03));
+        assert!(matches!(
+            cycle(&mut monitor, start + minutes(120), 0.09),
+            Some(CoolingChange::Recovered { .. })
+        ));
+    }
+
+    #[test]
+    fn recovery_needs_the_clear_margin() {
+        let start = Instant::now();
+        let config = CoolingMonitorConfig {
+            clear_margin: 0.2,
+            ..CoolingMonitorConfig::default()
+        };
+        let mut monitor = CoolingMonitor::new(config, vec![0.1; 25]);
+        let changes: Vec<_> = [0.045, 0.055, 0.045, 0.065, 0.075, 0.065]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &rate)| Some((i, cycle(&mut monitor, start + minutes(i as u64 * 60), rate)?)))
+            .collect();
+        // Hovering just over half the baseline doesn't count as recovered until it is back to 70%.
+        match changes.as_slice() {
+            [(0, CoolingChange::Degraded { .. }), (4, CoolingChange::Recovered { .. })] => {}
+            other => panic!("unexpected changes {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::{bail, Result};
+use log::*;
+use std::{
+    fmt,
+    mem::replace,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Checks the relay against the current the compressor draws, from a sensor on its control circuit.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CurrentConfig {
+    /// A current sensor is configured, without one nothing is checked.
+    pub enabled: bool,
+    /// Amps the compressor draws at least while running.
+    pub running: f32,
+    /// Amps drawn at most with the relay off.
+    pub idle: f32,
+    /// How long the compressor has to come up to its load after the relay switched on.
+    pub start_grace: Duration,
+    /// How long the load has to take to go after the relay switched off.
+    pub stop_grace: Duration,
+}
+
+impl Default for CurrentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            running: 0.5,
+            idle: 0.2,
+            start_grace: Duration::from_secs(30),
+            stop_grace: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CurrentConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.idle.is_finite() && self.idle >= 0.0) {
+            bail!("The idle current can't be negative, got {}A.", self.idle);
+        }
+        if !(self.running.is_finite() && self.running > self.idle) {
+            bail!(
+                "The running current has to be over the idle current of {}A, got {}A.",
+                self.idle,
+                self.running
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What the current says about the relay.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadFault {
+    /// Commanded on but not drawing its load, a dead start capacitor or a tripped thermal overload.
+    NoLoad,
+    /// Drawing a load while commanded off, a welded relay.
+    Unexpected,
+}
+
+impl fmt::Display for LoadFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadFault::NoLoad => write!(f, "no-load"),
+            LoadFault::Unexpected => write!(f, "unexpected-load"),
+        }
+    }
+}
+
+impl FromStr for LoadFault {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "no-load" => Ok(LoadFault::NoLoad),
+            "unexpected-load" => Ok(LoadFault::Unexpected),
+            _ => bail!("Unknown load fault '{}'.", s),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LoadChange {
+    Raised { fault: LoadFault, amps: f32 },
+    Cleared(LoadFault),
+}
+
+pub struct CurrentMonitor {
+    config: CurrentConfig,
+    power_on: bool,
+    /// When the relay last switched, the grace periods count from it.
+    since: Instant,
+    amps: Option<f32>,
+    fault: Option<LoadFault>,
+    /// The last reading was past the start grace and the compressor was drawing its load.
+    drawing: bool,
+}
+
+impl CurrentMonitor {
+    pub fn new(config: CurrentConfig, now: Instant, power_on: bool) -> Self {
+        Self {
+            config,
+            power_on,
+            since: now,
+            amps: None,
+            fault: None,
+            drawing: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// The last reading, None when it failed.
+    pub fn amps(&self) -> Option<f32> {
+        self.amps
+    }
+
+    pub fn fault(&self) -> Option<LoadFault> {
+        self.fault
+    }
+
+    /// Whether the last reading showed the relay switching the compressor on.
+    pub fn is_drawing(&self) -> bool {
+        self.drawing
+    }
+
+    // Pure
+    /// Checks `amps` against the relay, a fault is raised once the grace period after the relay switched is up. A
+    /// fault is about one position of the relay, switching clears it. `amps` is None when the read failed, nothing
+    /// changes without a reading.
+    pub fn observe(&mut self, now: Instant, power_on: bool, amps: Option<f32>) -> Option<LoadChange> {
+        if !self.config.enabled {
+            return None;
+        }
+        self.amps = amps;
+        self.drawing = false;
+        if power_on != self.power_on {
+            self.power_on = power_on;
+            self.since = now;
+            if let Some(fault) = self.fault.take() {
+                info!(
+                    "Relay switched {}, {} cleared",
+                    if power_on { "on" } else { "off" },
+                    fault
+                );
+                return Some(LoadChange::Cleared(fault));
+            }
+        }
+        let amps = amps?;
+        let grace = match power_on {
+            true => self.config.start_grace,
+            false => self.config.stop_grace,
+        };
+        if now.saturating_duration_since(self.since) < grace {
+            return None;
+        }
+        let fault = match power_on {
+            true if amps < self.config.running => Some(LoadFault::NoLoad),
+            false if amps > self.config.idle => Some(LoadFault::Unexpected),
+            _ => None,
+        };
+        self.drawing = power_on && fault.is_none();
+        match (fault, replace(&mut self.fault, fault)) {
+            (Some(fault), None) => {
+                error!(
+                    "Compressor drawing {:.2}A {}s after the relay switched {}, {}",
+                    amps,
+                    now.saturating_duration_since(self.since).as_secs(),
+                    if power_on { "on" } else { "off" },
+                    fault
+                );
+                Some(LoadChange::Raised { fault, amps })
+            }
+            (None, Some(fault)) => {
+                info!("Compressor drawing {:.2}A, {} cleared", amps, fault);
+                Some(LoadChange::Cleared(fault))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CurrentConfig {
+        CurrentConfig {
+            enabled: true,
+            ..CurrentConfig::default()
+        }
+    }
+
+    /// The changes over (seconds, relay, amps) readings.
+    fn run(readings: &[(u64, bool, Option<f32>)]) -> Vec<(u64, LoadChange)> {
+        let start = Instant::now();
+        let mut monitor = CurrentMonitor::new(config(), start, false);
+        readings
+            .iter()
+            .filter_map(|&(secs, power_on, amps)| {
+                let change = monitor.observe(start + Duration::from_secs(secs), power_on, amps)?;
+                Some((secs, change))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn raises_no_load_after_the_start_grace() {
+        assert_eq!(
+            vec![
+                (
+                    40,
+                    LoadChange::Raised {
+                        fault: LoadFault::NoLoad,
+                        amps: 0.1
+                    }
+                ),
+                (60, LoadChange::Cleared(LoadFault::NoLoad))
+            ],
+            run(&[
+                (0, false, Some(0.0)),
+                (10, true, Some(0.0)),
+                (30, true, Some(0.1)),
+                (40, true, Some(0.1)),
+                // A failed read changes nothing.
+                (50, true, None),
+                (60, true, Some(2.5)),
+            ])
+        );
+        // A healthy start.
+        assert!(run(&[(10, true, Some(0.0)), (20, true, Some(2.5)), (60, true, Some(2.4))]).is_empty());
+    }
+
+    #[test]
+    fn drawing_only_past_the_start_grace() {
+        let start = Instant::now();
+        let mut monitor = CurrentMonitor::new(config(), start, false);
+        let at = |secs| start + Duration::from_secs(secs);
+        monitor.observe(at(10), true, Some(2.5));
+        assert!(!monitor.is_drawing());
+        monitor.observe(at(40), true, Some(2.5));
+        assert!(monitor.is_drawing());
+        monitor.observe(at(50), true, None);
+        assert!(!monitor.is_drawing());
+        monitor.observe(at(60), true, Some(0.1));
+        assert!(!monitor.is_drawing());
+    }
+
+    #[test]
+    fn raises_unexpected_load_after_the_stop_grace_and_clears_on_switching() {
+        assert_eq!(
+            vec![
+                (
+                    50,
+                    LoadChange::Raised {
+                        fault: LoadFault::Unexpected,
+                        amps: 2.5
+                    }
+                ),
+                (60, LoadChange::Cleared(LoadFault::Unexpected)),
+                (
+                    100,
+                    LoadChange::Raised {
+                        fault: LoadFault::Unexpected,
+                        amps: 2.4
+                    }
+                ),
+            ],
+            run(&[
+                (0, true, Some(2.5)),
+                (10, false, Some(2.5)),
+                (30, false, Some(2.5)),
+                (50, false, Some(2.5)),
+                // Switching on clears it, the load is expected now.
+                (60, true, Some(2.5)),
+                (70, false, Some(2.5)),
+                (100, false, Some(2.4)),
+            ])
+        );
+    }
+
+    #[test]
+    fn validates_the_thresholds() {
+        assert!(config().validate().is_ok());
+        let inverted = CurrentConfig {
+            running: 0.1,
+            ..config()
+        };
+        assert!(inverted.validate().is_err());
+        let negative = CurrentConfig { idle: -0.1, ..config() };
+        assert!(negative.validate().is_err());
+        assert_eq!(Ok(LoadFault::NoLoad), "no-load".parse().map_err(|_: anyhow::Error| ()));
+        assert_eq!("unexpected-load", LoadFault::Unexpected.to_string());
+    }
+}
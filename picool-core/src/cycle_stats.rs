@@ -0,0 +1,120 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Relay history over a trailing horizon, for duty cycle and cycle length statistics.
+pub struct CycleStats {
+    horizon: Duration,
+    started: Instant,
+    initially_on: bool,
+    transitions: VecDeque<(Instant, bool)>,
+}
+
+impl CycleStats {
+    pub fn new(now: Instant, power_on: bool, horizon: Duration) -> Self {
+        Self {
+            horizon,
+            started: now,
+            initially_on: power_on,
+            transitions: VecDeque::new(),
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.transitions.back().map_or(self.initially_on, |&(_, on)| on)
+    }
+
+    pub fn record(&mut self, now: Instant, power_on: bool) {
+        if power_on == self.is_on() {
+            return;
+        }
+        self.transitions.push_back((now, power_on));
+        while self.transitions.len() > 1 && now - self.transitions[1].0 > self.horizon {
+            let (at, on) = self.transitions.pop_front().expect("Checked length.");
+            self.started = at;
+            self.initially_on = on;
+        }
+    }
+
+    /// Fraction of `window` the relay was on. None until the history covers the whole window.
+    pub fn duty(&self, now: Instant, window: Duration) -> Option<f32> {
+        if window > self.horizon || now - self.started < window {
+            return None;
+        }
+        let window_start = now - window;
+        let mut on_time = Duration::from_secs(0);
+        let mut segment_start = self.started;
+        let mut segment_on = self.initially_on;
+        for &(at, on) in self.transitions.iter().chain(std::iter::once(&(now, !self.is_on()))) {
+            if segment_on && at > window_start {
+                on_time += at - segment_start.max(window_start);
+            }
+            segment_start = at;
+            segment_on = on;
+        }
+        Some(on_time.as_secs_f32() / window.as_secs_f32())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    /// Cycles of `on` then `off` minutes for `hours`.
+    fn cycling(on: u64, off: u64, hours: u64) -> (CycleStats, Instant) {
+        let start = Instant::now();
+        let mut stats = CycleStats::new(start, false, minutes(24 * 60));
+        let mut t = start;
+        while t < start + minutes(hours * 60) {
+            stats.record(t, true);
+            t += minutes(on);
+            stats.record(t, false);
+            t += minutes(off);
+        }
+        (stats, t)
+    }
+
+    #[test]
+    fn no_duty_until_window_covered() {
+        let start = Instant::now();
+        let stats = CycleStats::new(start, true, minutes(600));
+        assert_eq!(None, stats.duty(start + minutes(59), minutes(60)));
+        assert_eq!(Some(1.0), stats.duty(start + minutes(60), minutes(60)));
+        assert_eq!(None, stats.duty(start + minutes(700), minutes(700)));
+    }
+
+    #[test]
+    fn steady_cycling() {
+        let (stats, end) = cycling(15, 45, 12);
+        let duty = stats.duty(end, minutes(6 * 60)).unwrap();
+        assert!((duty - 0.25).abs() < 0.001, "{}", duty);
+    }
+
+    #[test]
+    fn partial_segments_at_window_edge() {
+        let start = Instant::now();
+        let mut stats = CycleStats::new(start, false, minutes(600));
+        stats.record(start + minutes(30), true);
+        stats.record(start + minutes(90), false);
+        // Window 60..120: on 60..90.
+        assert_eq!(Some(0.5), stats.duty(start + minutes(120), minutes(60)));
+        // Window 45..105: on 45..90.
+        assert_eq!(Some(0.75), stats.duty(start + minutes(105), minutes(60)));
+    }
+
+    #[test]
+    fn history_is_pruned_to_horizon() {
+        let start = Instant::now();
+        let mut stats = CycleStats::new(start, false, minutes(60));
+        for i in 0..100 {
+            stats.record(start + minutes(i * 10), i % 2 == 0);
+        }
+        assert!(stats.transitions.len() <= 8);
+        assert_eq!(Some(0.5), stats.duty(start + minutes(1000), minutes(60)));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::temperature::{Celsius, DegreesDelta};
+use anyhow::{bail, Result};
+use std::{fmt, ops::Range};
+
+/// A band that follows the ambient, for a cellar held a few degrees below whatever the room is.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DifferentialConfig {
+    /// How far below the ambient the bottom and the top of the band are, None holds the absolute band.
+    pub below: Option<(DegreesDelta, DegreesDelta)>,
+    /// The band never goes below this, however cold the room.
+    pub floor: Celsius,
+    /// Nor above this, however hot.
+    pub ceiling: Celsius,
+}
+
+impl Default for DifferentialConfig {
+    fn default() -> Self {
+        Self {
+            below: None,
+            floor: Celsius(0.0),
+            ceiling: Celsius(20.0),
+        }
+    }
+}
+
+/// Whether a limit moved the band.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Clamp {
+    Free,
+    Floor,
+    Ceiling,
+}
+
+impl fmt::Display for Clamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clamp::Free => write!(f, "unclamped"),
+            Clamp::Floor => write!(f, "clamped to the floor"),
+            Clamp::Ceiling => write!(f, "clamped to the ceiling"),
+        }
+    }
+}
+
+impl DifferentialConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.below.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let (bottom, top) = match self.below {
+            Some(below) => below,
+            None => return Ok(()),
+        };
+        if !(bottom.0.is_finite() && top.0.is_finite() && self.floor.is_finite() && self.ceiling.is_finite()) {
+            bail!("Differential offsets and limits must be finite.");
+        }
+        if bottom <= top {
+            bail!(
+                "The bottom of a differential band must be further below the ambient than the top, got {} and {}.",
+                bottom,
+                top
+            );
+        }
+        if self.ceiling - self.floor < bottom - top {
+            bail!(
+                "The differential band is {} wide and doesn't fit between {} and {}.",
+                bottom - top,
+                self.floor,
+                self.ceiling
+            );
+        }
+        Ok(())
+    }
+
+    // Pure
+    /// The band for `ambient`, moved whole to stay within the floor and the ceiling. None while disabled.
+    pub fn band(&self, ambient: Celsius) -> Option<(Range<Celsius>, Clamp)> {
+        let (bottom, top) = self.below?;
+        Some(self.limit((ambient - bottom)..(ambient - top)))
+    }
+
+    // Pure
+    /// `band` moved whole to stay within the floor and the ceiling.
+    pub fn limit(&self, band: Range<Celsius>) -> (Range<Celsius>, Clamp) {
+        if band.end > self.ceiling {
+            let shift = band.end - self.ceiling;
+            return ((band.start - shift)..self.ceiling, Clamp::Ceiling);
+        }
+        if band.start < self.floor {
+            let shift = self.floor - band.start;
+            return (self.floor..(band.end + shift), Clamp::Floor);
+        }
+        (band, Clamp::Free)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DifferentialConfig {
+        DifferentialConfig {
+            below: Some((DegreesDelta(4.0), DegreesDelta(2.0))),
+            floor: Celsius(8.0),
+            ceiling: Celsius(16.0),
+        }
+    }
+
+    #[test]
+    fn follows_the_ambient_within_limits() {
+        let config = config();
+        assert_eq!(
+            Some((Celsius(10.0)..Celsius(12.0), Clamp::Free)),
+            config.band(Celsius(14.0))
+        );
+        // A heat wave holds the band below the ceiling, a cold snap above the floor, both keeping its width.
+        assert_eq!(
+            Some((Celsius(14.0)..Celsius(16.0), Clamp::Ceiling)),
+            config.band(Celsius(35.0))
+        );
+        assert_eq!(
+            Some((Celsius(8.0)..Celsius(10.0), Clamp::Floor)),
+            config.band(Celsius(5.0))
+        );
+        assert_eq!(None, DifferentialConfig::default().band(Celsius(14.0)));
+    }
+
+    #[test]
+    fn refuses_bands_that_cannot_work() {
+        assert!(config().validate().is_ok());
+        assert!(DifferentialConfig::default().validate().is_ok());
+        let inverted = DifferentialConfig {
+            below: Some((DegreesDelta(2.0), DegreesDelta(4.0))),
+            ..config()
+        };
+        assert!(inverted.validate().is_err());
+        let narrow = DifferentialConfig {
+            ceiling: Celsius(9.0),
+            ..config()
+        };
+        assert!(narrow.validate().is_err());
+    }
+}
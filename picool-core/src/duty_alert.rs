@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DutyAlertConfig {
+    /// Fraction of the window, 0.0 disables the alert.
+    pub threshold: f32,
+    pub window: Duration,
+    /// How far under the threshold the duty has to drop before the alert clears.
+    pub clear_margin: f32,
+    /// High duty is expected while pulling down after a start or a setpoint drop.
+    pub pull_down_grace: Duration,
+}
+
+impl Default for DutyAlertConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.95,
+            window: Duration::from_secs(60 * 60 * 6),
+            clear_margin: 0.05,
+            pull_down_grace: Duration::from_secs(60 * 60 * 3),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DutyAlertChange {
+    Raised(f32),
+    Cleared(f32),
+}
+
+pub struct DutyAlert {
+    config: DutyAlertConfig,
+    active: bool,
+    suppressed_until: Option<Instant>,
+}
+
+impl DutyAlert {
+    pub fn new(config: DutyAlertConfig) -> Self {
+        Self {
+            config,
+            active: false,
+            suppressed_until: None,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.config.window
+    }
+
+    /// Start a pull-down period during which the alert won't be raised.
+    pub fn begin_pull_down(&mut self, now: Instant) {
+        self.suppressed_until = Some(now + self.config.pull_down_grace);
+    }
+
+    pub fn update(&mut self, now: Instant, duty: Option<f32>) -> Option<DutyAlertChange> {
+        let duty = duty?;
+        if self.config.threshold <= 0.0 {
+            return None;
+        }
+        if !self.active {
+            if self.suppressed_until.is_some_and(|until| now < until) || duty < self.config.threshold {
+                return None;
+            }
+            self.active = true;
+            return Some(DutyAlertChange::Raised(duty));
+        }
+        if duty < self.config.threshold - self.config.clear_margin {
+            self.active = false;
+            return Some(DutyAlertChange::Cleared(duty));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    #[test]
+    fn raises_once_and_clears_with_hysteresis() {
+        let start = Instant::now();
+        let mut alert = DutyAlert::new(DutyAlertConfig::default());
+        let duties = [0.5, 0.94, 0.96, 0.99, 0.93, 0.91, 0.89, 0.95, 0.97];
+        let changes: Vec<_> = duties
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &duty)| alert.update(start + minutes(i as u64), Some(duty)))
+            .collect();
+        assert_eq!(
+            vec![
+                DutyAlertChange::Raised(0.96),
+                DutyAlertChange::Cleared(0.89),
+                DutyAlertChange::Raised(0.95)
+            ],
+            changes
+        );
+        let mut alert = DutyAlert::new(DutyAlertConfig {
+            clear_margin: 0.1,
+            ..DutyAlertConfig::default()
+        });
+        let changes: Vec<_> = duties
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &duty)| alert.update(start + minutes(i as u64), Some(duty)))
+            .collect();
+        assert_eq!(vec![DutyAlertChange::Raised(0.96)], changes);
+        assert_eq!(
+            Some(DutyAlertChange::Cleared(0.84)),
+            alert.update(start + minutes(10), Some(0.84))
+        );
+    }
+
+    #[test]
+    fn synthetic_cycle_patterns() {
+        use crate::cycle_stats::CycleStats;
+
+        let start = Instant::now();
+        let mut stats = CycleStats::new(start, false, minutes(24 * 60));
+        let mut alert = DutyAlert::new(DutyAlertConfig::default());
+        let mut changes = Vec::new();
+        // 8h at 50% duty, 8h running flat out, 8h back at 50%.
+        for minute in 0..(24 * 60) {
+            let on = match minute / (8 * 60) {
+                1 => true,
+                _ => minute % 30 < 15,
+            };
+            let now = start + minutes(minute);
+            stats.record(now, on);
+            if let Some(change) = alert.update(now, stats.duty(now, alert.window())) {
+                changes.push((minute / 60, change));
+            }
+        }
+        match changes.as_slice() {
+            [(raised_hour, DutyAlertChange::Raised(_)), (cleared_hour, DutyAlertChange::Cleared(_))] => {
+                assert_eq!(13, *raised_hour);
+                assert_eq!(17, *cleared_hour);
+            }
+            other => panic!("unexpected changes {:?}", other),
+        }
+    }
+
+    #[test]
+    fn suppressed_during_pull_down() {
+        let start = Instant::now();
+        let mut alert = DutyAlert::new(DutyAlertConfig::default());
+        alert.begin_pull_down(start);
+        assert_eq!(None, alert.update(start + minutes(179), Some(1.0)));
+        assert_eq!(
+            Some(DutyAlertChange::Raised(1.0)),
+            alert.update(start + minutes(180), Some(1.0))
+        );
+    }
+
+    #[test]
+    fn unknown_duty_and_disabled() {
+        let start = Instant::now();
+        let mut alert = DutyAlert::new(DutyAlertConfig::default());
+        assert_eq!(None, alert.update(start, None));
+        let mut disabled = DutyAlert::new(DutyAlertConfig {
+            threshold: 0.0,
+            ..DutyAlertConfig::default()
+        });
+        assert_eq!(None, disabled.update(start, Some(1.0)));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{
+    config::Band,
+    temperature::{Celsius, DegreesDelta},
+    MAX_COMPENSATION, MINIMUM_ON_DURATION,
+};
+use anyhow::{bail, Result};
+use std::{collections::VecDeque, fmt, str::FromStr, time::Instant};
+
+/// Off cycles a learned tail is the median of.
+const TAIL_HISTORY: usize = 5;
+/// Off cycles observed before a learned tail is trusted.
+const MINIMUM_TAIL_HISTORY: usize = 3;
+
+/// How far the chamber is expected to keep dropping once the compressor stops.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum TailEstimate {
+    /// Shut off at the threshold only.
+    #[default]
+    Off,
+    Fixed(DegreesDelta),
+    /// The median drop after shutoff of recent off cycles.
+    Learned,
+}
+
+/// Degrees are Celsius, the command line converts them first.
+impl FromStr for TailEstimate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(TailEstimate::Off),
+            "learned" => Ok(TailEstimate::Learned),
+            _ => match s.parse::<f32>() {
+                Ok(degrees) if degrees.is_finite() && degrees >= 0.0 => Ok(TailEstimate::Fixed(DegreesDelta(degrees))),
+                _ => bail!("Invalid tail estimate '{}', expected off, learned, or degrees.", s),
+            },
+        }
+    }
+}
+
+impl fmt::Display for TailEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TailEstimate::Off => write!(f, "off"),
+            TailEstimate::Fixed(tail) => write!(f, "fixed {}", tail),
+            TailEstimate::Learned => write!(f, "learned"),
+        }
+    }
+}
+
+/// An off cycle that ended, what was predicted at shutoff against what happened.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TailObservation {
+    pub shutoff_at: Celsius,
+    /// None while a learned tail was still learning.
+    pub predicted: Option<DegreesDelta>,
+    pub minimum: Celsius,
+}
+
+impl TailObservation {
+    pub fn drop(&self) -> DegreesDelta {
+        self.shutoff_at - self.minimum
+    }
+}
+
+/// Predicts the latent cooling tail so an on cycle can end before the raw threshold, and checks each prediction
+/// against the lowest reading of the off cycle that follows.
+pub struct ShutoffPredictor {
+    estimate: TailEstimate,
+    drops: VecDeque<DegreesDelta>,
+    on_since: Option<Instant>,
+    off_cycle: Option<TailObservation>,
+}
+
+impl ShutoffPredictor {
+    pub fn new(estimate: TailEstimate) -> Self {
+        Self {
+            estimate,
+            drops: VecDeque::new(),
+            on_since: None,
+            off_cycle: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.estimate != TailEstimate::Off
+    }
+
+    /// The further drop expected after shutting off, capped like the compensation. None while disabled or still
+    /// learning.
+    pub fn tail(&self) -> Option<DegreesDelta> {
+        let tail = match self.estimate {
+            TailEstimate::Off => return None,
+            TailEstimate::Fixed(tail) => tail,
+            TailEstimate::Learned if self.drops.len() < MINIMUM_TAIL_HISTORY => return None,
+            TailEstimate::Learned => median(&self.drops),
+        };
+        match tail > MAX_COMPENSATION {
+            true => Some(MAX_COMPENSATION),
+            false => Some(tail),
+        }
+    }
+
+    /// Below this the relay can turn off and coast down to the bottom of the band. None before the relay has been
+    /// on for the minimum on duration, whatever the configured interval.
+    pub fn shutoff_threshold(&self, band: &Band, now: Instant) -> Option<Celsius> {
+        let on_since = self.on_since?;
+        if now - on_since < MINIMUM_ON_DURATION {
+            return None;
+        }
+        Some(band.target.start + self.tail()?)
+    }
+
+    /// The relay turned on, the off cycle before it is over.
+    pub fn relay_on(&mut self, now: Instant) -> Option<TailObservation> {
+        self.on_since = Some(now);
+        let observation = self.off_cycle.take()?;
+        self.drops.push_back(match observation.drop() < DegreesDelta::ZERO {
+            true => DegreesDelta::ZERO,
+            false => observation.drop(),
+        });
+        if self.drops.len() > TAIL_HISTORY {
+            self.drops.pop_front();
+        }
+        Some(observation)
+    }
+
+    pub fn relay_off(&mut self, temperature: Celsius) {
+        self.on_since = None;
+        if self.is_enabled() {
+            self.off_cycle = Some(TailObservation {
+                shutoff_at: temperature,
+                predicted: self.tail(),
+                minimum: temperature,
+            });
+        }
+    }
+
+    pub fn observe(&mut self, temperature: Celsius) {
+        if let Some(cycle) = &mut self.off_cycle {
+            if temperature < cycle.minimum {
+                cycle.minimum = temperature;
+            }
+        }
+    }
+
+    /// The relay moved for something other than the thresholds, the current cycle tells nothing about the tail.
+    pub fn abandon(&mut self) {
+        self.on_since = None;
+        self.off_cycle = None;
+    }
+}
+
+// Pure
+fn median(drops: &VecDeque<DegreesDelta>) -> DegreesDelta {
+    let mut sorted: Vec<DegreesDelta> = drops.iter().copied().collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("Invariant: Never contains NaN drops."));
+    let len = sorted.len();
+    match len % 2 {
+        0 => (sorted[len / 2 - 1] + sorted[len / 2]) / 2.0,
+        _ => sorted[len / 2],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn off_cycle(predictor: &mut ShutoffPredictor, start: Instant, shutoff_at: f32, minimum: f32) -> TailObservation {
+        predictor.relay_off(Celsius(shutoff_at));
+        predictor.observe(Celsius(minimum + 0.2));
+        predictor.observe(Celsius(minimum));
+        predictor.observe(Celsius(minimum + 0.5));
+        predictor.relay_on(start).unwrap()
+    }
+
+    #[test]
+    fn fixed_tail_waits_for_minimum_on() {
+        let band = Band::default();
+        let start = Instant::now();
+        let mut predictor = ShutoffPredictor::new(TailEstimate::Fixed(DegreesDelta(0.5)));
+        assert_eq!(None, predictor.shutoff_threshold(&band, start));
+        assert_eq!(None, predictor.relay_on(start));
+        assert_eq!(
+            None,
+            predictor.shutoff_threshold(&band, start + Duration::from_secs(60))
+        );
+        assert_eq!(
+            Some(band.target.start + DegreesDelta(0.5)),
+            predictor.shutoff_threshold(&band, start + MINIMUM_ON_DURATION)
+        );
+        let observation = off_cycle(&mut predictor, start, 1.2, 0.6);
+        assert_eq!(Some(DegreesDelta(0.5)), observation.predicted);
+        assert!((observation.drop().0 - 0.6).abs() < 1e-6);
+        // Capped like the compensation.
+        let predictor = ShutoffPredictor::new(TailEstimate::Fixed(DegreesDelta(5.0)));
+        assert_eq!(Some(MAX_COMPENSATION), predictor.tail());
+    }
+
+    #[test]
+    fn learns_the_median_drop() {
+        let start = Instant::now();
+        let mut predictor = ShutoffPredictor::new(TailEstimate::Learned);
+        predictor.relay_on(start);
+        assert_eq!(None, off_cycle(&mut predictor, start, 2.0, 1.0).predicted);
+        off_cycle(&mut predictor, start, 2.0, 0.8);
+        assert_eq!(None, predictor.tail());
+        // Rising straight after shutoff counts as no tail.
+        off_cycle(&mut predictor, start, 2.0, 2.5);
+        assert!((predictor.tail().unwrap().0 - 1.0).abs() < 1e-6);
+        off_cycle(&mut predictor, start, 2.0, 1.1);
+        assert!((predictor.tail().unwrap().0 - 0.95).abs() < 1e-6);
+        for _ in 0..TAIL_HISTORY {
+            off_cycle(&mut predictor, start, 2.0, 1.6);
+        }
+        assert!((predictor.tail().unwrap().0 - 0.4).abs() < 1e-6);
+        predictor.relay_off(Celsius(2.0));
+        predictor.abandon();
+        assert_eq!(None, predictor.relay_on(start));
+    }
+
+    #[test]
+    fn disabled_predicts_nothing() {
+        let start = Instant::now();
+        let mut predictor = ShutoffPredictor::new(TailEstimate::default());
+        predictor.relay_on(start);
+        assert_eq!(
+            None,
+            predictor.shutoff_threshold(&Band::default(), start + MINIMUM_ON_DURATION)
+        );
+        predictor.relay_off(Celsius(1.0));
+        assert_eq!(None, predictor.relay_on(start));
+    }
+
+    #[test]
+    fn parses_estimates() {
+        assert_eq!(TailEstimate::Off, "off".parse().unwrap());
+        assert_eq!(TailEstimate::Learned, "learned".parse().unwrap());
+        assert_eq!(TailEstimate::Fixed(DegreesDelta(0.8)), "0.8".parse().unwrap());
+        assert!("-0.5".parse::<TailEstimate>().is_err());
+        assert!("soon".parse::<TailEstimate>().is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const SECONDS_PER_DAY: i64 = 60 * 60 * 24;
+/// How much accumulated energy a crash can lose.
+pub const ENERGY_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60 * 15);
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct EnergyConfig {
+    /// Draw while the relay is on, None disables accounting.
+    pub compressor_watts: Option<f32>,
+    /// Draw while the relay is off.
+    pub standby_watts: f32,
+    pub price_per_kwh: Option<f32>,
+}
+
+/// Wall-clock time in the local zone. Only used to bucket energy into days, amounts come from the monotonic clock.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct LocalTime {
+    /// Days since 1970-01-01.
+    pub day: i64,
+    pub since_midnight: Duration,
+}
+
+impl LocalTime {
+    /// From seconds since the epoch already shifted into the local zone.
+    pub fn from_local_seconds(seconds: i64) -> Self {
+        Self {
+            day: seconds.div_euclid(SECONDS_PER_DAY),
+            since_midnight: Duration::from_secs(seconds.rem_euclid(SECONDS_PER_DAY) as u64),
+        }
+    }
+
+    pub fn plus(self, elapsed: Duration) -> Self {
+        let seconds = self.day * SECONDS_PER_DAY + self.since_midnight.as_secs() as i64;
+        Self::from_local_seconds(seconds + elapsed.as_secs() as i64)
+    }
+}
+
+/// `23:00-06:00`, local wall clock time of day. Looked up against the clock every reading rather than timed, so a daylight
+/// saving change moves it with the clock.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DailyWindow {
+    /// Since local midnight.
+    pub start: Duration,
+    pub end: Duration,
+}
+
+impl DailyWindow {
+    /// Wraps past midnight when the window ends earlier in the day than it starts.
+    pub fn contains(&self, local: LocalTime) -> bool {
+        let time = local.since_midnight;
+        match self.start < self.end {
+            true => self.start <= time && time < self.end,
+            false => time >= self.start || time < self.end,
+        }
+    }
+
+    /// How long until the window next starts, zero right at its start.
+    pub fn until_start(&self, local: LocalTime) -> Duration {
+        let time = local.since_midnight;
+        match self.start >= time {
+            true => self.start - time,
+            false => self.start + Duration::from_secs(SECONDS_PER_DAY as u64) - time,
+        }
+    }
+}
+
+impl FromStr for DailyWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (time_of_day(start)?, time_of_day(end)?),
+            None => bail!("Expected a daily window as <HH:MM>-<HH:MM>, got '{}'.", s),
+        };
+        if start == end {
+            bail!("Daily window '{}' starts and ends at the same time.", s);
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for DailyWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hhmm = |time: Duration| format!("{:02}:{:02}", time.as_secs() / 3600, time.as_secs() / 60 % 60);
+        write!(f, "{}-{}", hhmm(self.start), hhmm(self.end))
+    }
+}
+
+pub(crate) fn time_of_day(s: &str) -> Result<Duration> {
+    let parse = || -> Result<Duration> {
+        let (hours, minutes) = s.split_once(':').context("expected HH:MM")?;
+        let (hours, minutes): (u64, u64) = (hours.parse()?, minutes.parse()?);
+        if hours > 23 || minutes > 59 {
+            bail!("out of range");
+        }
+        Ok(Duration::from_secs(hours * 3600 + minutes * 60))
+    };
+    parse().with_context(|| format!("Invalid time of day '{}'.", s))
+}
+
+/// Accumulated kWh, what gets persisted. `today` and `yesterday` are relative to `day`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct EnergyTotals {
+    pub day: i64,
+    pub today: f64,
+    pub yesterday: f64,
+    pub lifetime: f64,
+}
+
+impl EnergyTotals {
+    /// Damaged values restart from zero rather than poisoning every total after them.
+    fn sanitized(self) -> Self {
+        let valid = |kwh: f64| kwh.is_finite() && kwh >= 0.0;
+        match valid(self.today) && valid(self.yesterday) && valid(self.lifetime) {
+            true => self,
+            false => Self {
+                day: self.day,
+                ..Self::default()
+            },
+        }
+    }
+}
+
+impl fmt::Display for EnergyTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} kWh today, {:.2} kWh yesterday, {:.1} kWh lifetime",
+            self.today, self.yesterday, self.lifetime
+        )
+    }
+}
+
+/// Energy used in a day that just ended.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DailyEnergy {
+    pub kwh: f64,
+    pub cost: Option<f64>,
+}
+
+/// Estimates energy use from the time the relay spends in each position.
+pub struct EnergyMeter {
+    config: EnergyConfig,
+    totals: EnergyTotals,
+    last_update: Instant,
+    last_checkpoint: Instant,
+}
+
+impl EnergyMeter {
+    /// None when no compressor wattage is configured. Whatever was used while we were down is unknown, the first
+    /// update only moves the restored totals on to the current day.
+    pub fn new(config: EnergyConfig, restored: EnergyTotals, now: Instant) -> Option<Self> {
+        config.compressor_watts?;
+        Some(Self {
+            config,
+            totals: restored.sanitized(),
+            last_update: now,
+            last_checkpoint: now,
+        })
+    }
+
+    pub fn totals(&self) -> EnergyTotals {
+        self.totals
+    }
+
+    pub fn cost(&self, kwh: f64) -> Option<f64> {
+        self.config.price_per_kwh.map(|price| kwh * price as f64)
+    }
+
+    /// Accounts the time since the last update at the draw the relay was at, splitting it at midnight. The previous
+    /// day's use if this crossed into the next day.
+    pub fn update(&mut self, now: Instant, local: LocalTime, was_on: bool) -> Option<DailyEnergy> {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        self.last_update = now;
+        let watts = match was_on {
+            true => self.config.compressor_watts.unwrap_or_default(),
+            false => self.config.standby_watts,
+        };
+        let kwh = watts as f64 * elapsed.as_secs_f64() / 3_600_000.0;
+        self.totals.lifetime += kwh;
+        // A wall clock set backwards keeps filling the current day rather than reopening an old one.
+        if local.day <= self.totals.day {
+            self.totals.today += kwh;
+            return None;
+        }
+        let after_midnight = match elapsed.is_zero() {
+            true => 0.0,
+            false => kwh * local.since_midnight.min(elapsed).as_secs_f64() / elapsed.as_secs_f64(),
+        };
+        if local.day == self.totals.day + 1 {
+            self.totals.today += kwh - after_midnight;
+        }
+        let ended = self.roll_to(local.day);
+        self.totals.today += after_midnight;
+        ended
+    }
+
+    /// True once per checkpoint interval.
+    pub fn checkpoint_due(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.last_checkpoint) < ENERGY_CHECKPOINT_INTERVAL {
+            return false;
+        }
+        self.last_checkpoint = now;
+        true
+    }
+
+    fn roll_to(&mut self, day: i64) -> Option<DailyEnergy> {
+        if day <= self.totals.day {
+            return None;
+        }
+        let ended = self.totals.today;
+        let consecutive = day == self.totals.day + 1;
+        self.totals.yesterday = match consecutive {
+            true => ended,
+            false => 0.0,
+        };
+        self.totals.today = 0.0;
+        self.totals.day = day;
+        match consecutive {
+            true => Some(DailyEnergy {
+                kwh: ended,
+                cost: self.cost(ended),
+            }),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: EnergyConfig = EnergyConfig {
+        compressor_watts: Some(100.0),
+        standby_watts: 2.0,
+        price_per_kwh: Some(0.25),
+    };
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    fn at(hhmm: &str) -> LocalTime {
+        LocalTime {
+            day: 20_000,
+            since_midnight: time_of_day(hhmm).unwrap(),
+        }
+    }
+
+    #[test]
+    fn daily_windows_wrap_past_midnight() {
+        let night: DailyWindow = "23:00-06:00".parse().unwrap();
+        assert_eq!("23:00-06:00", night.to_string());
+        for (time, quiet) in [
+            ("22:59", false),
+            ("23:00", true),
+            ("23:30", true),
+            ("00:00", true),
+            ("05:59", true),
+            ("06:00", false),
+            ("12:00", false),
+        ] {
+            assert_eq!(quiet, night.contains(at(time)), "{}", time);
+        }
+        let afternoon: DailyWindow = "13:30-15:00".parse().unwrap();
+        assert!(!afternoon.contains(at("13:29")));
+        assert!(afternoon.contains(at("13:30")));
+        assert!(!afternoon.contains(at("15:00")));
+        assert!(!afternoon.contains(at("00:00")));
+        assert_eq!(minutes(90), afternoon.until_start(at("12:00")));
+        assert_eq!(minutes(0), afternoon.until_start(at("13:30")));
+        assert_eq!(minutes(24 * 60 - 1), afternoon.until_start(at("13:31")));
+        assert_eq!(minutes(30), night.until_start(at("22:30")));
+        for invalid in ["23:00", "23:00-24:00", "23:00-6", "7:60-08:00", "06:00-06:00", "-"] {
+            assert!(invalid.parse::<DailyWindow>().is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            Duration::from_secs(6 * 3600),
+            "7:00-6:00".parse::<DailyWindow>().unwrap().end
+        );
+    }
+
+    fn close(expected: f64, actual: f64) -> bool {
+        (expected - actual).abs() < 1e-6
+    }
+
+    #[test]
+    fn disabled_without_compressor_wattage() {
+        let config = EnergyConfig::default();
+        assert!(EnergyMeter::new(config, EnergyTotals::default(), Instant::now()).is_none());
+    }
+
+    #[test]
+    fn local_time_buckets_by_day() {
+        let late = LocalTime::from_local_seconds(SECONDS_PER_DAY * 3 - 60);
+        assert_eq!(2, late.day);
+        assert_eq!(Duration::from_secs(SECONDS_PER_DAY as u64 - 60), late.since_midnight);
+        let after = late.plus(minutes(2));
+        assert_eq!((3, minutes(1)), (after.day, after.since_midnight));
+        assert_eq!(-1, LocalTime::from_local_seconds(-1).day);
+    }
+
+    /// Three days of 15 minutes on, 45 off, polled every minute from midnight of day 10.
+    #[test]
+    fn days_of_cycling() {
+        let start = Instant::now();
+        let midnight = LocalTime {
+            day: 10,
+            since_midnight: Duration::from_secs(0),
+        };
+        let totals = EnergyTotals {
+            day: midnight.day,
+            ..Default::default()
+        };
+        let mut meter = EnergyMeter::new(CONFIG, totals, start).unwrap();
+        let mut summaries = Vec::new();
+        for minute in 1..=3 * 24 * 60 {
+            let was_on = (minute - 1) % 60 < 15;
+            let elapsed = minutes(minute);
+            if let Some(summary) = meter.update(start + elapsed, midnight.plus(elapsed), was_on) {
+                summaries.push(summary);
+            }
+        }
+        // A quarter of each day at 100W, the rest at 2W.
+        let daily = 24.0 * (0.25 * 0.1 + 0.75 * 0.002);
+        assert_eq!(3, summaries.len());
+        assert!(summaries.iter().all(|s| close(daily, s.kwh)), "{:?}", summaries);
+        assert!(close(daily * 0.25, summaries[0].cost.unwrap()));
+        let totals = meter.totals();
+        assert_eq!(13, totals.day);
+        assert!(close(0.0, totals.today));
+        assert!(close(daily, totals.yesterday));
+        assert!(close(3.0 * daily, totals.lifetime));
+    }
+
+    #[test]
+    fn interval_across_midnight_is_split() {
+        let start = Instant::now();
+        let before = LocalTime {
+            day: 10,
+            since_midnight: Duration::from_secs(SECONDS_PER_DAY as u64) - minutes(30),
+        };
+        let totals = EnergyTotals {
+            day: before.day,
+            ..Default::default()
+        };
+        let mut meter = EnergyMeter::new(CONFIG, totals, start).unwrap();
+        let summary = meter
+            .update(start + minutes(60), before.plus(minutes(60)), true)
+            .unwrap();
+        assert!(close(0.05, summary.kwh));
+        let totals = meter.totals();
+        assert!(close(0.05, totals.today));
+        assert!(close(0.05, totals.yesterday));
+        assert!(close(0.1, totals.lifetime));
+    }
+
+    #[test]
+    fn restored_totals_roll_to_today() {
+        let restored = EnergyTotals {
+            day: 10,
+            today: 1.5,
+            yesterday: 2.0,
+            lifetime: 40.0,
+        };
+        let next_day = LocalTime {
+            day: 11,
+            since_midnight: minutes(60),
+        };
+        let start = Instant::now();
+        let mut meter = EnergyMeter::new(CONFIG, restored, start).unwrap();
+        let summary = meter.update(start, next_day, false).unwrap();
+        assert!(close(1.5, summary.kwh));
+        assert_eq!(
+            EnergyTotals {
+                day: 11,
+                today: 0.0,
+                yesterday: 1.5,
+                lifetime: 40.0
+            },
+            meter.totals()
+        );
+        // Days later yesterday's use is unknown, and so not summarized.
+        let mut meter = EnergyMeter::new(CONFIG, restored, start).unwrap();
+        assert_eq!(None, meter.update(start, LocalTime { day: 14, ..next_day }, false));
+        assert!(close(0.0, meter.totals().yesterday));
+        let damaged = EnergyTotals {
+            lifetime: f64::NAN,
+            ..restored
+        };
+        let mut meter = EnergyMeter::new(CONFIG, damaged, start).unwrap();
+        meter.update(start, next_day, false);
+        assert!(close(0.0, meter.totals().lifetime));
+    }
+
+    #[test]
+    fn wall_clock_set_back_stays_on_the_current_day() {
+        let start = Instant::now();
+        let today = LocalTime {
+            day: 10,
+            since_midnight: minutes(60),
+        };
+        let totals = EnergyTotals {
+            day: today.day,
+            ..Default::default()
+        };
+        let mut meter = EnergyMeter::new(CONFIG, totals, start).unwrap();
+        let yesterday = LocalTime { day: 9, ..today };
+        assert_eq!(None, meter.update(start + minutes(60), yesterday, true));
+        assert_eq!(10, meter.totals().day);
+        assert!(close(0.1, meter.totals().today));
+    }
+
+    #[test]
+    fn checkpoints_every_interval() {
+        let start = Instant::now();
+        let mut meter = EnergyMeter::new(CONFIG, EnergyTotals::default(), start).unwrap();
+        assert!(!meter.checkpoint_due(start + minutes(14)));
+        assert!(meter.checkpoint_due(start + minutes(15)));
+        assert!(!meter.checkpoint_due(start + minutes(20)));
+        assert!(meter.checkpoint_due(start + minutes(30)));
+    }
+}
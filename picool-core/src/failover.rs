@@ -0,0 +1,303 @@
+use crate::current::LoadFault;
+use anyhow::{bail, Result};
+use log::*;
+use std::{
+    fmt,
+    mem::replace,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// A backup relay wired in parallel with the primary, driven instead once the current says the primary isn't
+/// switching the compressor.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FailoverConfig {
+    /// A backup output is wired up, without one the primary is never given up.
+    pub enabled: bool,
+    /// Consecutive no-load checks on the primary before failing over.
+    pub after: u32,
+    /// How long the backup runs before the primary is tried again, never without.
+    pub retry: Option<Duration>,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            after: 3,
+            retry: None,
+        }
+    }
+}
+
+/// Which relay switches the compressor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Output {
+    Primary,
+    Backup,
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Primary => write!(f, "primary"),
+            Output::Backup => write!(f, "backup"),
+        }
+    }
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "primary" => Ok(Output::Primary),
+            "backup" => Ok(Output::Backup),
+            _ => bail!("Unknown output '{}'.", s),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FailoverChange {
+    /// The primary failed its checks, the backup switches the compressor.
+    FailedOver { failures: u32 },
+    /// The backup ran out its retry period with the compressor off, the primary is given another start.
+    Retrying,
+    /// A retried primary drew the compressor's load, its fault is cleared.
+    Restored,
+    /// The fault was cleared by hand, back to the primary.
+    Reset,
+}
+
+pub struct Failover {
+    config: FailoverConfig,
+    active: Output,
+    /// The primary failed over and hasn't been restored or reset since, persisted so a restart keeps to the backup.
+    faulted: bool,
+    /// Consecutive no-load checks on the primary.
+    failures: u32,
+    /// When the backup took over, the retry counts from it.
+    since: Instant,
+}
+
+impl Failover {
+    /// Starting on the backup when a previous run left the primary `faulted`.
+    pub fn new(config: FailoverConfig, faulted: bool, now: Instant) -> Self {
+        let faulted = faulted && config.enabled;
+        if faulted {
+            warn!("The primary output was faulted by a previous run, starting on the backup");
+        }
+        Self {
+            config,
+            active: match faulted {
+                true => Output::Backup,
+                false => Output::Primary,
+            },
+            faulted,
+            failures: 0,
+            since: now,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn active(&self) -> Output {
+        self.active
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.faulted
+    }
+
+    // Pure
+    /// Counts the current checks against the primary, `fault` and `drawing` as the current monitor has them after
+    /// this reading. The backup is only given up for a retry while the compressor is off.
+    pub fn observe(
+        &mut self,
+        now: Instant,
+        power_on: bool,
+        fault: Option<LoadFault>,
+        drawing: bool,
+    ) -> Option<FailoverChange> {
+        if !self.config.enabled {
+            return None;
+        }
+        match self.active {
+            Output::Primary if power_on && fault == Some(LoadFault::NoLoad) => {
+                self.failures += 1;
+                if self.failures < self.config.after {
+                    return None;
+                }
+                error!(
+                    "Primary output failed {} current checks in a row, failing over to the backup",
+                    self.failures
+                );
+                self.active = Output::Backup;
+                self.faulted = true;
+                self.since = now;
+                Some(FailoverChange::FailedOver {
+                    failures: replace(&mut self.failures, 0),
+                })
+            }
+            Output::Primary if drawing => {
+                self.failures = 0;
+                match replace(&mut self.faulted, false) {
+                    true => {
+                        info!("Retried primary output is drawing the compressor's load, fault cleared");
+                        Some(FailoverChange::Restored)
+                    }
+                    false => None,
+                }
+            }
+            Output::Primary => None,
+            Output::Backup => {
+                let retry = self.config.retry?;
+                if power_on || now.saturating_duration_since(self.since) < retry {
+                    return None;
+                }
+                info!(
+                    "Retrying the primary output after {}m on the backup",
+                    now.saturating_duration_since(self.since).as_secs() / 60
+                );
+                self.active = Output::Primary;
+                Some(FailoverChange::Retrying)
+            }
+        }
+    }
+
+    /// Back to the primary, None unless it was faulted.
+    pub fn reset(&mut self) -> Option<FailoverChange> {
+        if !self.faulted {
+            info!("The primary output isn't faulted, nothing to reset");
+            return None;
+        }
+        info!("Primary output fault reset, back to the primary");
+        self.active = Output::Primary;
+        self.faulted = false;
+        self.failures = 0;
+        Some(FailoverChange::Reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(retry: Option<u64>) -> FailoverConfig {
+        FailoverConfig {
+            enabled: true,
+            after: 3,
+            retry: retry.map(Duration::from_secs),
+        }
+    }
+
+    /// The changes over (seconds, relay, fault, drawing) readings.
+    fn run(
+        failover: &mut Failover,
+        start: Instant,
+        readings: &[(u64, bool, bool, bool)],
+    ) -> Vec<(u64, FailoverChange)> {
+        readings
+            .iter()
+            .filter_map(|&(secs, power_on, no_load, drawing)| {
+                let fault = Some(LoadFault::NoLoad).filter(|_| no_load);
+                let change = failover.observe(start + Duration::from_secs(secs), power_on, fault, drawing)?;
+                Some((secs, change))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fails_over_after_consecutive_no_load_checks() {
+        let start = Instant::now();
+        let mut failover = Failover::new(config(None), false, start);
+        let changes = run(
+            &mut failover,
+            start,
+            &[
+                (0, true, false, false),
+                (10, true, true, false),
+                (20, true, true, false),
+                // A check the primary passes starts the count over.
+                (30, true, false, true),
+                (40, true, true, false),
+                (50, true, true, false),
+                (60, true, true, false),
+                (70, true, true, false),
+                (4000, false, false, false),
+            ],
+        );
+        assert_eq!(vec![(60, FailoverChange::FailedOver { failures: 3 })], changes);
+        assert_eq!(Output::Backup, failover.active());
+        assert!(failover.is_faulted());
+    }
+
+    #[test]
+    fn retries_the_primary_while_off() {
+        let start = Instant::now();
+        let mut failover = Failover::new(config(Some(3600)), false, start);
+        let changes = run(
+            &mut failover,
+            start,
+            &[
+                (10, true, true, false),
+                (20, true, true, false),
+                (30, true, true, false),
+                // Held on the backup through a run past the retry period.
+                (3700, true, false, true),
+                (3710, false, false, false),
+                // The retried primary fails again.
+                (4000, true, true, false),
+                (4010, true, true, false),
+                (4020, true, true, false),
+                (7620, false, false, false),
+                // And works this time.
+                (7630, true, false, true),
+            ],
+        );
+        assert_eq!(
+            vec![
+                (30, FailoverChange::FailedOver { failures: 3 }),
+                (3710, FailoverChange::Retrying),
+                (4020, FailoverChange::FailedOver { failures: 3 }),
+                (7620, FailoverChange::Retrying),
+                (7630, FailoverChange::Restored),
+            ],
+            changes
+        );
+        assert!(!failover.is_faulted());
+    }
+
+    #[test]
+    fn a_restored_fault_starts_on_the_backup_until_reset() {
+        let start = Instant::now();
+        let mut failover = Failover::new(config(None), true, start);
+        assert_eq!(Output::Backup, failover.active());
+        assert_eq!(
+            Vec::<(u64, FailoverChange)>::new(),
+            run(
+                &mut failover,
+                start,
+                &[(10, true, true, false), (86_400, false, false, false)]
+            )
+        );
+        assert_eq!(Some(FailoverChange::Reset), failover.reset());
+        assert_eq!(Output::Primary, failover.active());
+        assert_eq!(None, failover.reset());
+
+        // Without a backup a persisted fault means nothing.
+        let disabled = Failover::new(FailoverConfig::default(), true, start);
+        assert_eq!(Output::Primary, disabled.active());
+        assert!(!disabled.is_faulted());
+    }
+
+    #[test]
+    fn parses_outputs() {
+        assert_eq!(Output::Backup, "backup".parse().unwrap());
+        assert_eq!("primary", Output::Primary.to_string());
+        assert!("spare".parse::<Output>().is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use crate::world_error::ErrorClass;
+use anyhow::{bail, Context, Result};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const DUTY_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// What to do with the relay once the sensor has been unreadable for too long.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FailsafePolicy {
+    Off,
+    On,
+    /// Run this long per hour, off first, to roughly hold temperature blind.
+    DutyCycle(Duration),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FailsafeConfig {
+    pub after: Duration,
+    pub policy: FailsafePolicy,
+    /// How long to stay engaged before re-initializing the sensor or giving up.
+    pub budget: Duration,
+    pub reinitialize_attempts: u32,
+}
+
+impl Default for FailsafeConfig {
+    fn default() -> Self {
+        Self {
+            after: Duration::from_secs(60 * 10),
+            policy: FailsafePolicy::Off,
+            budget: Duration::from_secs(60 * 60),
+            reinitialize_attempts: 3,
+        }
+    }
+}
+
+impl FromStr for FailsafePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(FailsafePolicy::Off),
+            "on" => Ok(FailsafePolicy::On),
+            _ if s.starts_with("duty:") => {
+                let minutes: u64 = s[5..]
+                    .parse()
+                    .with_context(|| format!("Invalid duty cycle minutes in '{}'.", s))?;
+                if minutes == 0 || minutes >= 60 {
+                    bail!("Duty cycle must be between 1 and 59 minutes per hour, got {}.", minutes);
+                }
+                Ok(FailsafePolicy::DutyCycle(Duration::from_secs(minutes * 60)))
+            }
+            _ => bail!("Invalid failsafe policy '{}', expected off, on, or duty:<minutes>.", s),
+        }
+    }
+}
+
+impl fmt::Display for FailsafePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailsafePolicy::Off => write!(f, "off"),
+            FailsafePolicy::On => write!(f, "on"),
+            FailsafePolicy::DutyCycle(on) => write!(f, "duty {}m/h", on.as_secs() / 60),
+        }
+    }
+}
+
+pub struct SensorFailsafe {
+    config: FailsafeConfig,
+    failing_since: Option<Instant>,
+    engaged_at: Option<Instant>,
+    budget_started: Option<Instant>,
+}
+
+impl SensorFailsafe {
+    pub fn new(config: FailsafeConfig) -> Self {
+        Self {
+            config,
+            failing_since: None,
+            engaged_at: None,
+            budget_started: None,
+        }
+    }
+
+    pub fn policy(&self) -> FailsafePolicy {
+        self.config.policy
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged_at.is_some()
+    }
+
+    /// Returns how long the sensor has been failing at the moment the failsafe engages, None otherwise. Permanent
+    /// failures engage straight away, there's no point waiting for them to clear.
+    pub fn record_failure(&mut self, now: Instant, class: ErrorClass) -> Option<Duration> {
+        let failing_since = *self.failing_since.get_or_insert(now);
+        let failing_for = now - failing_since;
+        if self.engaged_at.is_none() && (failing_for >= self.config.after || class == ErrorClass::Permanent) {
+            self.engaged_at = Some(now);
+            self.budget_started = Some(now);
+            return Some(failing_for);
+        }
+        None
+    }
+
+    /// Returns the outage duration if the failsafe was engaged.
+    pub fn record_success(&mut self, now: Instant) -> Option<Duration> {
+        let failing_since = self.failing_since.take();
+        self.budget_started = None;
+        self.engaged_at
+            .take()
+            .and(failing_since)
+            .map(|failing_since| now - failing_since)
+    }
+
+    pub fn failing_for(&self, now: Instant) -> Duration {
+        self.failing_since.map(|since| now - since).unwrap_or_default()
+    }
+
+    pub fn budget_exhausted(&self, now: Instant) -> bool {
+        self.budget_started
+            .is_some_and(|started| now - started >= self.config.budget)
+    }
+
+    /// Give a re-initialized sensor a fresh budget, the output posture carries on undisturbed.
+    pub fn restart_budget(&mut self, now: Instant) {
+        if self.budget_started.is_some() {
+            self.budget_started = Some(now);
+        }
+    }
+
+    /// The output posture while engaged.
+    pub fn output(&self, now: Instant) -> bool {
+        match self.config.policy {
+            FailsafePolicy::Off => false,
+            FailsafePolicy::On => true,
+            FailsafePolicy::DutyCycle(on) => {
+                let engaged_at = self.engaged_at.unwrap_or(now);
+                let into_period = (now - engaged_at).as_secs() % DUTY_PERIOD.as_secs();
+                into_period >= (DUTY_PERIOD - on).as_secs()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    fn failsafe(policy: FailsafePolicy) -> SensorFailsafe {
+        SensorFailsafe::new(FailsafeConfig {
+            after: minutes(5),
+            policy,
+            budget: minutes(60),
+            reinitialize_attempts: 1,
+        })
+    }
+
+    #[test]
+    fn parse_policies() {
+        assert_eq!(FailsafePolicy::Off, "off".parse().unwrap());
+        assert_eq!(FailsafePolicy::On, "on".parse().unwrap());
+        assert_eq!(FailsafePolicy::DutyCycle(minutes(20)), "duty:20".parse().unwrap());
+        assert!("duty:0".parse::<FailsafePolicy>().is_err());
+        assert!("duty:60".parse::<FailsafePolicy>().is_err());
+        assert!("sometimes".parse::<FailsafePolicy>().is_err());
+    }
+
+    #[test]
+    fn engages_once_after_threshold() {
+        let start = Instant::now();
+        let mut failsafe = failsafe(FailsafePolicy::Off);
+        assert_eq!(None, failsafe.record_failure(start, ErrorClass::Transient));
+        assert_eq!(None, failsafe.record_failure(start + minutes(4), ErrorClass::Transient));
+        assert!(!failsafe.is_engaged());
+        assert_eq!(
+            Some(minutes(5)),
+            failsafe.record_failure(start + minutes(5), ErrorClass::Transient)
+        );
+        assert_eq!(None, failsafe.record_failure(start + minutes(6), ErrorClass::Transient));
+        assert!(failsafe.is_engaged());
+        assert!(!failsafe.output(start + minutes(6)));
+    }
+
+    #[test]
+    fn success_before_threshold_resets() {
+        let start = Instant::now();
+        let mut failsafe = failsafe(FailsafePolicy::Off);
+        failsafe.record_failure(start, ErrorClass::Transient);
+        assert_eq!(None, failsafe.record_success(start + minutes(4)));
+        assert_eq!(None, failsafe.record_failure(start + minutes(8), ErrorClass::Transient));
+        assert!(!failsafe.is_engaged());
+    }
+
+    #[test]
+    fn permanent_failure_engages_immediately() {
+        let start = Instant::now();
+        let mut failsafe = failsafe(FailsafePolicy::Off);
+        assert_eq!(None, failsafe.record_failure(start, ErrorClass::Transient));
+        assert_eq!(
+            Some(minutes(1)),
+            failsafe.record_failure(start + minutes(1), ErrorClass::Permanent)
+        );
+        assert!(failsafe.is_engaged());
+    }
+
+    #[test]
+    fn recovery_reports_outage() {
+        let start = Instant::now();
+        let mut failsafe = failsafe(FailsafePolicy::On);
+        failsafe.record_failure(start, ErrorClass::Transient);
+        failsafe.record_failure(start + minutes(5), ErrorClass::Transient);
+        assert!(failsafe.output(start + minutes(5)));
+        assert_eq!(Some(minutes(12)), failsafe.record_success(start + minutes(12)));
+        assert!(!failsafe.is_engaged());
+    }
+
+    #[test]
+    fn duty_cycle_runs_off_then_on_each_hour() {
+        let start = Instant::now();
+        let mut failsafe = failsafe(FailsafePolicy::DutyCycle(minutes(15)));
+        failsafe.record_failure(start, ErrorClass::Transient);
+        failsafe.record_failure(start + minutes(5), ErrorClass::Transient);
+        let engaged = start + minutes(5);
+        let on_minutes: Vec<u64> = (0..120).filter(|m| failsafe.output(engaged + minutes(*m))).collect();
+        assert_eq!(30, on_minutes.len());
+        assert!(!failsafe.output(engaged));
+        assert!(!failsafe.output(engaged + minutes(44)));
+        assert!(failsafe.output(engaged + minutes(45)));
+        assert!(failsafe.output(engaged + minutes(59)));
+        assert!(!failsafe.output(engaged + minutes(60)));
+    }
+
+    #[test]
+    fn budget_runs_from_engagement_and_restarts() {
+        let start = Instant::now();
+        let mut failsafe = failsafe(FailsafePolicy::Off);
+        failsafe.record_failure(start, ErrorClass::Transient);
+        assert!(!failsafe.budget_exhausted(start + minutes(100)));
+        failsafe.record_failure(start + minutes(5), ErrorClass::Transient);
+        assert!(!failsafe.budget_exhausted(start + minutes(64)));
+        assert!(failsafe.budget_exhausted(start + minutes(65)));
+        failsafe.restart_budget(start + minutes(65));
+        assert!(!failsafe.budget_exhausted(start + minutes(124)));
+        assert!(failsafe.budget_exhausted(start + minutes(125)));
+        assert_eq!(minutes(125), failsafe.failing_for(start + minutes(125)));
+        failsafe.record_success(start + minutes(126));
+        assert!(!failsafe.budget_exhausted(start + minutes(500)));
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{bail, Result};
+use log::*;
+use std::time::{Duration, Instant};
+
+/// The optional circulation fan. It runs with the compressor, and long off periods leave the chamber stratified, so
+/// it can also stir the air on a schedule while the compressor is idle.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FanConfig {
+    /// A fan output is wired up, without one nothing is switched.
+    pub enabled: bool,
+    /// From the start of one idle stir to the next, None only runs the fan with the compressor.
+    pub stir_every: Option<Duration>,
+    pub stir_for: Duration,
+    /// Readings during a stir and this long after it are flagged as mixing, None flags nothing.
+    pub settle: Option<Duration>,
+}
+
+impl Default for FanConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stir_every: None,
+            stir_for: Duration::from_secs(60 * 2),
+            settle: None,
+        }
+    }
+}
+
+impl FanConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(every) = self.stir_every {
+            if self.stir_for == Duration::from_secs(0) || self.stir_for >= every {
+                bail!(
+                    "A stir of {}s has to be shorter than the {}s between stirs, and not zero.",
+                    self.stir_for.as_secs(),
+                    every.as_secs()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The fan output and its own timing, the idle time is counted from whenever it last stopped.
+pub struct Fan {
+    config: FanConfig,
+    on: bool,
+    /// Running for a stir rather than with the compressor.
+    stirring: bool,
+    /// When the fan last started or stopped.
+    since: Instant,
+    last_stir_end: Option<Instant>,
+    stirs: u32,
+}
+
+impl Fan {
+    /// A fan that was left running by a previous run is switched to match the compressor on the first update.
+    pub fn new(config: FanConfig, now: Instant) -> Self {
+        Self {
+            config,
+            on: false,
+            stirring: false,
+            since: now,
+            last_stir_end: None,
+            stirs: 0,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    /// Idle stirs started since this run began.
+    pub fn stirs(&self) -> u32 {
+        self.stirs
+    }
+
+    // Pure
+    /// The new position of the fan output when it changes.
+    pub fn update(&mut self, now: Instant, compressor_on: bool) -> Option<bool> {
+        if !self.config.enabled {
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(self.since);
+        let on = if compressor_on {
+            self.stirring = false;
+            true
+        } else if self.stirring {
+            elapsed < self.config.stir_for
+        } else if self.on {
+            false
+        } else {
+            let due = self
+                .config
+                .stir_every
+                .is_some_and(|every| elapsed + self.config.stir_for >= every);
+            if due {
+                self.stirring = true;
+                self.stirs += 1;
+                debug!(
+                    "Stirring the chamber air after {}m idle, stir {}",
+                    elapsed.as_secs() / 60,
+                    self.stirs
+                );
+            }
+            due
+        };
+        if on == self.on {
+            return None;
+        }
+        if !on && self.stirring {
+            debug!("Stir {} done", self.stirs);
+            self.stirring = false;
+            self.last_stir_end = Some(now);
+        }
+        self.on = on;
+        self.since = now;
+        Some(on)
+    }
+
+    /// A stir is running or only just ended, readings are of air that was mixed moments ago.
+    pub fn mixing(&self, now: Instant) -> bool {
+        let settle = match self.config.settle {
+            Some(settle) => settle,
+            None => return false,
+        };
+        self.stirring
+            || self
+                .last_stir_end
+                .is_some_and(|end| now.saturating_duration_since(end) < settle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLL: Duration = Duration::from_secs(10);
+
+    fn config() -> FanConfig {
+        FanConfig {
+            enabled: true,
+            stir_every: Some(Duration::from_secs(60 * 30)),
+            settle: Some(Duration::from_secs(60)),
+            ..FanConfig::default()
+        }
+    }
+
+    /// The starts and stops of the fan over `polls` readings, as poll indices.
+    fn run(fan: &mut Fan, start: Instant, polls: u32, compressor_on: impl Fn(u32) -> bool) -> Vec<(u32, bool)> {
+        (0..polls)
+            .filter_map(|poll| Some((poll, fan.update(start + POLL * poll, compressor_on(poll))?)))
+            .collect()
+    }
+
+    #[test]
+    fn stirs_on_a_cadence_over_an_idle_day() {
+        let start = Instant::now();
+        let mut fan = Fan::new(config(), start);
+        let day = 6 * 60 * 24;
+        let changes = run(&mut fan, start, day, |_| false);
+        // Two minutes every half hour, the first ending half an hour in.
+        assert_eq!(48 * 2 - 1, changes.len());
+        assert_eq!(48, fan.stirs());
+        for (i, pair) in changes.chunks(2).enumerate() {
+            let started = 6 * 28 + i as u32 * 6 * 30;
+            assert_eq!((started, true), pair[0]);
+            if let Some(&stopped) = pair.get(1) {
+                assert_eq!((started + 12, false), stopped);
+            }
+        }
+    }
+
+    #[test]
+    fn runs_with_the_compressor_and_counts_idle_time_from_its_stop() {
+        let start = Instant::now();
+        let mut fan = Fan::new(config(), start);
+        // On for the first hour, then idle.
+        let changes = run(&mut fan, start, 6 * 120, |poll| poll < 6 * 60);
+        assert_eq!(
+            vec![
+                (0, true),
+                (360, false),
+                (360 + 6 * 28, true),
+                (360 + 6 * 28 + 12, false)
+            ],
+            changes[..4]
+        );
+        assert_eq!(2, fan.stirs());
+        // The compressor taking over a stir keeps the fan on and ends the stir.
+        let mut fan = Fan::new(config(), start);
+        let changes = run(&mut fan, start, 6 * 31, |poll| poll >= 6 * 28 + 6);
+        assert_eq!(vec![(6 * 28, true)], changes);
+        assert!(!fan.mixing(start + POLL * (6 * 31)));
+    }
+
+    #[test]
+    fn flags_readings_during_and_just_after_a_stir() {
+        let start = Instant::now();
+        let mut fan = Fan::new(config(), start);
+        let stir = 6 * 28;
+        let mixing: Vec<u32> = (0..6 * 40)
+            .filter(|&poll| {
+                let now = start + POLL * poll;
+                fan.update(now, false);
+                fan.mixing(now)
+            })
+            .collect();
+        assert_eq!((stir..stir + 12 + 6).collect::<Vec<_>>(), mixing);
+        let mut unflagged = Fan::new(
+            FanConfig {
+                settle: None,
+                ..config()
+            },
+            start,
+        );
+        assert!((0..6 * 40).all(|poll| {
+            let now = start + POLL * poll;
+            unflagged.update(now, false);
+            !unflagged.mixing(now)
+        }));
+    }
+
+    #[test]
+    fn disabled_or_unscheduled_fans() {
+        let start = Instant::now();
+        let mut fan = Fan::new(FanConfig::default(), start);
+        assert!(run(&mut fan, start, 6 * 60, |poll| poll < 10).is_empty());
+        let mut fan = Fan::new(
+            FanConfig {
+                stir_every: None,
+                ..config()
+            },
+            start,
+        );
+        assert_eq!(
+            vec![(0, true), (10, false)],
+            run(&mut fan, start, 6 * 60 * 24, |poll| poll < 10)
+        );
+        assert!(config().validate().is_ok());
+        assert!(FanConfig {
+            stir_for: Duration::from_secs(60 * 30),
+            ..config()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+use strum_macros::Display;
+
+const WARNING_INTERVAL: Duration = Duration::from_secs(60 * 10);
+
+/// Written next to the timestamp so a fresh file from a faulted controller is still distinguishable.
+#[derive(Debug, Copy, Clone, PartialEq, Display)]
+pub enum HeartbeatStatus {
+    #[strum(serialize = "controlling")]
+    Controlling,
+    #[strum(serialize = "failsafe")]
+    Failsafe,
+    #[strum(serialize = "lockout")]
+    Lockout,
+}
+
+/// The heartbeat file contents, seconds since the epoch and the status word.
+pub fn format_heartbeat(since_epoch: Duration, status: HeartbeatStatus) -> String {
+    format!("{} {}\n", since_epoch.as_secs(), status)
+}
+
+/// Heartbeat failures aren't fatal, but they shouldn't flood the log every poll either.
+#[derive(Default)]
+pub struct WarningLimiter {
+    last_warned: Option<Instant>,
+}
+
+impl WarningLimiter {
+    pub fn should_warn(&mut self, now: Instant) -> bool {
+        match self.last_warned {
+            Some(last) if now - last < WARNING_INTERVAL => false,
+            _ => {
+                self.last_warned = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_timestamp_and_status() {
+        assert_eq!(
+            "1600000000 controlling\n",
+            format_heartbeat(Duration::from_secs(1_600_000_000), HeartbeatStatus::Controlling)
+        );
+        assert_eq!(
+            "1600000000 failsafe\n",
+            format_heartbeat(Duration::from_millis(1_600_000_000_900), HeartbeatStatus::Failsafe)
+        );
+    }
+
+    #[test]
+    fn warnings_are_rate_limited() {
+        let start = Instant::now();
+        let mut limiter = WarningLimiter::default();
+        assert!(limiter.should_warn(start));
+        assert!(!limiter.should_warn(start + Duration::from_secs(10)));
+        assert!(!limiter.should_warn(start + Duration::from_secs(599)));
+        assert!(limiter.should_warn(start + Duration::from_secs(600)));
+    }
+}
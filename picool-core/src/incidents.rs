@@ -0,0 +1,314 @@
+use crate::{cap_alert::Compensation, controller::Effect, energy::LocalTime, notify::Event, temperature::DegreesDelta};
+use log::*;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IncidentConfig {
+    /// An alarm clearing sooner after it was raised only says so once it has been active this long.
+    pub min_active: Duration,
+    /// Raised again this soon after clearing, an alarm carries on its incident instead of opening a new one.
+    pub cooldown: Duration,
+}
+
+impl Default for IncidentConfig {
+    /// Every raise and clear is notified as the detectors see it.
+    fn default() -> Self {
+        Self {
+            min_active: Duration::from_secs(0),
+            cooldown: Duration::from_secs(0),
+        }
+    }
+}
+
+impl IncidentConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.min_active.is_zero() || !self.cooldown.is_zero()
+    }
+}
+
+/// The advisory alarms, each tracked on its own. The runaway lockout and the sensor failsafe change what the relay
+/// does, so they are always notified straight away.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Alarm {
+    DutyCycle,
+    Cooling,
+    CompensationCap(Compensation),
+}
+
+/// The worst an incident got.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Peak {
+    /// The highest duty cycle raised at.
+    Duty(f32),
+    /// The slowest pull down, degrees per minute.
+    CoolingRate(f32),
+    /// The furthest the chamber carried on past the capped threshold.
+    Overshoot(DegreesDelta),
+}
+
+impl Peak {
+    fn worse_than(&self, other: &Peak) -> bool {
+        match (self, other) {
+            (Peak::Duty(a), Peak::Duty(b)) => a > b,
+            (Peak::CoolingRate(a), Peak::CoolingRate(b)) => a < b,
+            (Peak::Overshoot(a), Peak::Overshoot(b)) => a > b,
+            _ => false,
+        }
+    }
+}
+
+// Pure
+/// The alarm `event` raises or clears, and the value it raised at.
+fn classify(event: &Event) -> Option<(Alarm, Option<Peak>)> {
+    match event {
+        Event::DutyCycleHigh { duty, .. } => Some((Alarm::DutyCycle, Some(Peak::Duty(*duty)))),
+        Event::DutyCycleCleared { .. } => Some((Alarm::DutyCycle, None)),
+        Event::CoolingDegraded { rate, .. } => Some((Alarm::Cooling, Some(Peak::CoolingRate(*rate)))),
+        Event::CoolingRecovered { .. } => Some((Alarm::Cooling, None)),
+        Event::CompensationCapped {
+            compensation,
+            overshoot,
+            ..
+        } => Some((Alarm::CompensationCap(*compensation), Some(Peak::Overshoot(*overshoot)))),
+        Event::CompensationUncapped { compensation, .. } => Some((Alarm::CompensationCap(*compensation), None)),
+        _ => None,
+    }
+}
+
+struct Incident {
+    alarm: Alarm,
+    started: Instant,
+    started_at: LocalTime,
+    peak: Peak,
+    /// Raised again while clearing.
+    reraised: u32,
+    /// When the detector cleared it and what it said, held until the incident is over.
+    cleared: Option<(Instant, Event)>,
+}
+
+/// Between the detectors and the notifiers, so an alarm flapping about its limit is one incident rather than a
+/// notification every time it crosses.
+pub struct Incidents {
+    config: IncidentConfig,
+    open: Vec<Incident>,
+}
+
+impl Incidents {
+    pub fn new(config: IncidentConfig) -> Self {
+        Self { config, open: vec![] }
+    }
+
+    /// Holds back the alarm notifications among `effects` that only continue an incident, and adds the summaries of
+    /// incidents that are over at `now`.
+    pub fn filter(&mut self, now: Instant, local: LocalTime, effects: &mut Vec<Effect>) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        effects.retain(|effect| match effect {
+            Effect::Notify(event) => self.notify(now, local, event),
+            _ => true,
+        });
+        let config = self.config;
+        let (over, open) = self.open.drain(..).partition(|incident| match &incident.cleared {
+            Some((cleared, _)) => now >= (*cleared + config.cooldown).max(incident.started + config.min_active),
+            None => false,
+        });
+        self.open = open;
+        for incident in over {
+            let Incident {
+                alarm,
+                started,
+                started_at,
+                peak,
+                reraised,
+                cleared,
+            } = incident;
+            let (cleared_at, cleared) = cleared.expect("Only cleared incidents are over.");
+            effects.push(Effect::Notify(Event::IncidentOver {
+                cleared: Box::new(cleared),
+                started_at,
+                lasted: cleared_at - started,
+                peak,
+                reraised,
+            }));
+            debug!("{:?} incident over", alarm);
+        }
+    }
+
+    /// Whether `event` is notified as it is.
+    fn notify(&mut self, now: Instant, local: LocalTime, event: &Event) -> bool {
+        let (alarm, raised) = match classify(event) {
+            Some(classified) => classified,
+            None => return true,
+        };
+        let incident = self.open.iter_mut().find(|incident| incident.alarm == alarm);
+        match (incident, raised) {
+            (None, Some(peak)) => {
+                self.open.push(Incident {
+                    alarm,
+                    started: now,
+                    started_at: local,
+                    peak,
+                    reraised: 0,
+                    cleared: None,
+                });
+                true
+            }
+            (Some(incident), Some(peak)) => {
+                if peak.worse_than(&incident.peak) {
+                    incident.peak = peak;
+                }
+                if incident.cleared.take().is_some() {
+                    incident.reraised += 1;
+                    info!("{}, carrying on the incident since it was first raised", event);
+                }
+                false
+            }
+            (Some(incident), None) => {
+                incident.cleared = Some((now, event.clone()));
+                info!("{}, held until the incident is over", event);
+                false
+            }
+            // A clear without a raise, e.g. restored state the detector started from.
+            (None, None) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    fn config(min_active: u64, cooldown: u64) -> IncidentConfig {
+        IncidentConfig {
+            min_active: minutes(min_active),
+            cooldown: minutes(cooldown),
+        }
+    }
+
+    fn duty(high: bool, duty: f32) -> Event {
+        let window = Duration::from_secs(6 * 3600);
+        match high {
+            true => Event::DutyCycleHigh { duty, window },
+            false => Event::DutyCycleCleared { duty, window },
+        }
+    }
+
+    /// Feeds one event, or none, a minute, the notifications that got through with the minute they did.
+    fn run(incidents: &mut Incidents, events: Vec<Option<Event>>, until: u64) -> Vec<(u64, Event)> {
+        let start = Instant::now();
+        let mut events = events.into_iter();
+        let mut notified = vec![];
+        for minute in 0..until {
+            let mut effects: Vec<Effect> = events.next().flatten().map(Effect::Notify).into_iter().collect();
+            effects.push(Effect::SetPower(true));
+            incidents.filter(
+                start + minutes(minute),
+                LocalTime::default().plus(minutes(minute)),
+                &mut effects,
+            );
+            assert!(effects.contains(&Effect::SetPower(true)));
+            notified.extend(effects.into_iter().filter_map(|effect| match effect {
+                Effect::Notify(event) => Some((minute, event)),
+                _ => None,
+            }));
+        }
+        notified
+    }
+
+    #[test]
+    fn an_oscillating_alarm_is_one_incident() {
+        let mut incidents = Incidents::new(config(0, 10));
+        // Raised and cleared every other minute for 20 minutes, peaking at 99%.
+        let events = (0..20)
+            .map(|minute| Some(duty(minute % 2 == 0, 0.95 + if minute == 8 { 0.04 } else { 0.0 })))
+            .collect();
+        let notified = run(&mut incidents, events, 40);
+        assert_eq!(2, notified.len(), "{:?}", notified);
+        assert_eq!((0, duty(true, 0.95)), notified[0]);
+        // Ten minutes after the last clear.
+        assert_eq!(
+            (
+                29,
+                Event::IncidentOver {
+                    cleared: Box::new(duty(false, 0.95)),
+                    started_at: LocalTime::default(),
+                    lasted: minutes(19),
+                    peak: Peak::Duty(0.99),
+                    reraised: 9,
+                }
+            ),
+            notified[1]
+        );
+        assert_eq!(
+            "Relay duty cycle back to 95% over the last 6h. Raised 00:00, lasted 19m, peaked at duty 99%, raised \
+             again 9 times.",
+            notified[1].1.to_string()
+        );
+    }
+
+    #[test]
+    fn a_short_lived_alarm_waits_out_the_minimum_before_clearing() {
+        let mut incidents = Incidents::new(config(30, 0));
+        let mut events = vec![Some(duty(true, 0.96)), None, Some(duty(false, 0.9))];
+        // A new incident once the first is over, its clear only after another 30 minutes.
+        events.resize(40, None);
+        events.push(Some(duty(true, 0.97)));
+        events.push(Some(duty(false, 0.9)));
+        let notified: Vec<u64> = run(&mut incidents, events, 80).into_iter().map(|(at, _)| at).collect();
+        assert_eq!(vec![0, 30, 40, 70], notified);
+        // Raised again before the minimum is up, it carries on.
+        let mut incidents = Incidents::new(config(30, 0));
+        let events = vec![Some(duty(true, 0.96)), Some(duty(false, 0.9)), Some(duty(true, 0.96))];
+        assert_eq!(1, run(&mut incidents, events, 60).len());
+    }
+
+    #[test]
+    fn alarms_are_tracked_apart_and_others_pass_through() {
+        let mut incidents = Incidents::new(config(0, 10));
+        let degraded = Event::CoolingDegraded {
+            rate: 0.02,
+            baseline: 0.1,
+        };
+        let recovered = Event::CoolingRecovered {
+            rate: 0.09,
+            baseline: 0.1,
+        };
+        let slower = Event::CoolingDegraded {
+            rate: 0.01,
+            baseline: 0.1,
+        };
+        let stalled = Event::LoopStalled {
+            stalled_for: minutes(1),
+        };
+        let events = vec![
+            Some(degraded.clone()),
+            Some(duty(true, 0.96)),
+            Some(recovered.clone()),
+            Some(stalled.clone()),
+            Some(slower),
+            Some(stalled.clone()),
+            Some(recovered.clone()),
+        ];
+        let notified = run(&mut incidents, events, 30);
+        assert_eq!(
+            vec![(0, degraded), (1, duty(true, 0.96)), (3, stalled.clone()), (5, stalled)],
+            notified[..4].to_vec()
+        );
+        match &notified[4..] {
+            [(16, Event::IncidentOver { peak, reraised, .. })] => {
+                assert_eq!((Peak::CoolingRate(0.01), 1), (*peak, *reraised))
+            }
+            other => panic!("{:?}", other),
+        }
+        // Disabled, everything passes.
+        let mut incidents = Incidents::new(IncidentConfig::default());
+        let events = vec![Some(duty(true, 0.96)), Some(duty(false, 0.9)), Some(duty(true, 0.96))];
+        assert_eq!(3, run(&mut incidents, events, 3).len());
+    }
+}
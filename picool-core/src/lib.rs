@@ -0,0 +1,867 @@
+//! The control logic of picool without any I/O: the relay state machine, the threshold compensation and the
+//! monitors feeding them. A driver reads the sensor, hands each reading to [`controller::step`] and carries out
+//! the effects it returns against the hardware.
+
+use anyhow::{bail, Result};
+use log::*;
+use std::{
+    collections::VecDeque,
+    ops::Range,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use strum_macros::Display;
+
+pub mod ambient_trim;
+pub mod boost;
+pub mod cap_alert;
+pub mod config;
+pub mod controller;
+pub mod cooling_monitor;
+pub mod current;
+pub mod cycle_stats;
+pub mod differential;
+pub mod duty_alert;
+pub mod early_shutoff;
+pub mod energy;
+pub mod failover;
+pub mod failsafe;
+pub mod fan;
+pub mod heartbeat;
+pub mod incidents;
+pub mod notify;
+pub mod overshoot;
+pub mod peak;
+pub mod pump;
+pub mod quiet_hours;
+pub mod rest;
+pub mod rng;
+pub mod runaway;
+pub mod runtime;
+pub mod sampling;
+pub mod shed;
+pub mod staging;
+pub mod starts;
+pub mod temperature;
+pub mod termination;
+pub mod timing_advisor;
+pub mod tpc;
+pub mod trend;
+pub mod world_error;
+
+#[cfg(test)]
+mod properties;
+
+use boost::Boost;
+use energy::EnergyTotals;
+use overshoot::Overshoots;
+use rest::RestPeriod;
+use shed::ShedPeriod;
+use starts::StartCounts;
+use temperature::{Celsius, DegreesDelta, Millidegrees};
+use world_error::SensorError;
+
+pub const TARGET_RANGE: Range<Celsius> = Celsius(0.555556)..Celsius(4.333333); // 33.0 to 39.8F
+pub const LOW_COMPENSATION_RESET_MARGIN: DegreesDelta = DegreesDelta(0.111111); // 40.0F for the default range
+pub const MAX_COMPENSATION: DegreesDelta = DegreesDelta(1.888888);
+pub const MINIMUM_ON_DURATION: Duration = Duration::from_secs(60 * 2);
+pub const MINIMUM_OFF_DURATION: Duration = Duration::from_secs(60 * 8);
+/// How long the relay holds a position before it may switch back, protecting the compressor.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MinimumIntervals {
+    pub on: Duration,
+    pub off: Duration,
+}
+
+impl Default for MinimumIntervals {
+    fn default() -> Self {
+        Self {
+            on: MINIMUM_ON_DURATION,
+            off: MINIMUM_OFF_DURATION,
+        }
+    }
+}
+
+pub const TREND_WINDOW: Duration = Duration::from_secs(60 * 15);
+pub const CYCLE_HISTORY: Duration = Duration::from_secs(60 * 60 * 24);
+pub const EXTREMES_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60 * 5);
+/// How far the start of a checkpointed off cycle may be from the persisted last off transition, both are kept in
+/// whole seconds and written one after the other.
+const CYCLE_START_SLACK: Duration = Duration::from_secs(60);
+
+/// The relay state machine, minimum intervals carry when they began.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Display)]
+pub enum State {
+    InitiallyOff,
+    MinimumIntervalOn(Instant),
+    MinimumIntervalOff(Instant),
+    On,
+    Off,
+    Fault,
+}
+
+/// How the previous run left the relay.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Display)]
+pub enum RestoredPowerState {
+    CurrentlyOn,
+    OffFor(Duration),
+    OffForUnknownDuration,
+}
+
+/// Where a controller picks up from, restored from the previous run where possible.
+pub struct Seed {
+    pub state: State,
+    pub compensation: (DegreesDelta, DegreesDelta),
+    pub cooling_rates: Vec<f32>,
+    pub energy: EnergyTotals,
+    /// Lifetime compressor runtime.
+    pub runtime: Duration,
+    pub starts: StartCounts,
+    /// A boost that was still running when the process stopped, with what is left of it.
+    pub boost: Option<Boost>,
+    /// Minimum intervals the timing advisor applied, in place of the configured ones.
+    pub intervals: Option<MinimumIntervals>,
+    pub overshoots: Overshoots,
+    /// A rest that was still holding the relay off when the process stopped, with what is left of it.
+    pub rest: Option<RestPeriod>,
+    /// A load shed that was still holding off starts when the process stopped, with what is left of it.
+    pub shed: Option<ShedPeriod>,
+    /// The extremes of the cycle the relay is still in, checked against how the previous run left it.
+    pub extremes: Option<CycleExtremes>,
+    /// A previous run failed over from the primary output and it hasn't been reset since.
+    pub failover: bool,
+}
+
+impl Seed {
+    /// Starting in `state` with nothing learned or carried over, as when the state files can't be read.
+    pub fn new(state: State) -> Self {
+        Self {
+            state,
+            compensation: (DegreesDelta::ZERO, DegreesDelta::ZERO),
+            cooling_rates: vec![],
+            energy: EnergyTotals::default(),
+            runtime: Duration::from_secs(0),
+            starts: StartCounts::default(),
+            boost: None,
+            intervals: None,
+            overshoots: Overshoots::default(),
+            rest: None,
+            shed: None,
+            extremes: None,
+            failover: false,
+        }
+    }
+}
+
+impl State {
+    pub fn is_on(&self) -> bool {
+        match self {
+            State::InitiallyOff => false,
+            State::MinimumIntervalOn(_) => true,
+            State::MinimumIntervalOff(_) => false,
+            State::On => true,
+            State::Off => false,
+            State::Fault => false,
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        !self.is_on()
+    }
+}
+
+// Pure
+pub fn determine_initial_state(
+    maybe_restored_state: Result<RestoredPowerState>,
+    now: Instant,
+    intervals: MinimumIntervals,
+) -> State {
+    match maybe_restored_state {
+        Ok(restored_state) => {
+            debug!("Restored state: {}", restored_state);
+            match restored_state {
+                RestoredPowerState::CurrentlyOn => State::MinimumIntervalOn(now),
+                RestoredPowerState::OffFor(duration) => match duration > intervals.off {
+                    true => State::InitiallyOff,
+                    false => State::MinimumIntervalOff(now - duration),
+                },
+                RestoredPowerState::OffForUnknownDuration => State::MinimumIntervalOff(now),
+            }
+        }
+        Err(e) => {
+            warn!("Failed get last off transition: {:?}", e);
+            State::MinimumIntervalOff(now)
+        }
+    }
+}
+
+/// Where the relay state machine starts, overriding what the previous run left when the operator knows better.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Display, Default)]
+pub enum StartMode {
+    /// From how the previous run left the relay.
+    #[default]
+    #[strum(serialize = "auto")]
+    Auto,
+    /// Off with the full minimum off interval ahead, e.g. after replacing the relay board.
+    #[strum(serialize = "off-locked")]
+    OffLocked,
+    /// Off and free to switch on straight away, for a bench test.
+    #[strum(serialize = "off-free")]
+    OffFree,
+    /// From the relay's current level alone, a relay found off is free to switch on straight away.
+    #[strum(serialize = "follow-relay")]
+    FollowRelay,
+}
+
+impl StartMode {
+    /// May switch the compressor on before its minimum off interval since it last stopped is up.
+    pub fn skips_lockout(self) -> bool {
+        matches!(self, StartMode::OffFree | StartMode::FollowRelay)
+    }
+}
+
+impl FromStr for StartMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(StartMode::Auto),
+            "off-locked" => Ok(StartMode::OffLocked),
+            "off-free" => Ok(StartMode::OffFree),
+            "follow-relay" => Ok(StartMode::FollowRelay),
+            _ => bail!(
+                "Unknown initial state '{}', expected auto, off-locked, off-free or follow-relay.",
+                s
+            ),
+        }
+    }
+}
+
+// Pure
+/// The state `mode` starts from. A failed restore still found the relay off, only the last off transition is
+/// missing.
+pub fn initial_state(
+    mode: StartMode,
+    maybe_restored_state: Result<RestoredPowerState>,
+    now: Instant,
+    intervals: MinimumIntervals,
+) -> State {
+    let state = match mode {
+        StartMode::Auto => determine_initial_state(maybe_restored_state, now, intervals),
+        StartMode::OffLocked => State::MinimumIntervalOff(now),
+        StartMode::OffFree => State::InitiallyOff,
+        StartMode::FollowRelay => match maybe_restored_state {
+            Ok(RestoredPowerState::CurrentlyOn) => State::MinimumIntervalOn(now),
+            _ => State::InitiallyOff,
+        },
+    };
+    info!("Initial state {}, starting {}", mode, state);
+    state
+}
+
+// Pure
+pub fn validate_temperature(temperature: Celsius) -> Result<Celsius, SensorError> {
+    match temperature.is_finite() {
+        true => Ok(temperature),
+        false => Err(SensorError::NonFinite(temperature.0)),
+    }
+}
+
+/// Where one side of the band switches the relay, and where the temperature has to come back to before that side
+/// counts as recovered.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Edge {
+    pub engage: Millidegrees,
+    pub release: Millidegrees,
+}
+
+/// Both sides of the band, as compensated.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Thresholds {
+    pub low: Edge,
+    pub high: Edge,
+}
+
+impl Thresholds {
+    /// Engage and release at the same temperature on each side.
+    pub fn exact(low: Millidegrees, high: Millidegrees) -> Self {
+        Self {
+            low: Edge {
+                engage: low,
+                release: low,
+            },
+            high: Edge {
+                engage: high,
+                release: high,
+            },
+        }
+    }
+}
+
+// Pure
+/// The relay switches on once the high side engages, provided the low side has released, and off once the low side
+/// engages, provided the high side has released. With engage and release equal the release conditions always hold,
+/// the thresholds never cross.
+pub fn transition(
+    initial: State,
+    current_temperature: Millidegrees,
+    thresholds: Thresholds,
+    now: Instant,
+    intervals: MinimumIntervals,
+) -> State {
+    let Thresholds { low, high } = thresholds;
+    match initial {
+        // Latched, only the run loop can leave a fault.
+        State::Fault => State::Fault,
+        State::MinimumIntervalOn(s) if now - s < intervals.on => State::MinimumIntervalOn(s),
+        State::MinimumIntervalOff(s) if now - s < intervals.off => State::MinimumIntervalOff(s),
+        State::On | State::MinimumIntervalOn(_) => {
+            match is_too_cold(current_temperature, low.engage) && !is_too_hot(current_temperature, high.release) {
+                true => State::MinimumIntervalOff(now),
+                false => State::On,
+            }
+        }
+        State::Off | State::InitiallyOff | State::MinimumIntervalOff(_) => {
+            match is_too_hot(current_temperature, high.engage) && !is_too_cold(current_temperature, low.release) {
+                true => State::MinimumIntervalOn(now),
+                false => State::Off,
+            }
+        }
+    }
+}
+
+// Pure
+fn is_too_cold(temperature: Millidegrees, threshold: Millidegrees) -> bool {
+    temperature < threshold
+}
+
+// Pure
+fn is_too_hot(temperature: Millidegrees, threshold: Millidegrees) -> bool {
+    temperature > threshold
+}
+
+/// Learns how far past a threshold the temperature carries on after the relay switches, in millidegrees so the
+/// median and the "did it move" checks are exact.
+pub struct Compensator {
+    target: Millidegrees,
+    observations: VecDeque<Millidegrees>,
+    compensation: Millidegrees,
+    max_compensation: Millidegrees,
+}
+
+impl Compensator {
+    pub fn new(target: Millidegrees, seed_compensation: Millidegrees, max_compensation: Millidegrees) -> Self {
+        if max_compensation == Millidegrees::ZERO {
+            panic!("max_compensation can not be 0.");
+        }
+        let observations = VecDeque::new();
+        Self {
+            target,
+            observations,
+            compensation: seed_compensation,
+            max_compensation,
+        }
+    }
+
+    pub fn get_compensation(&self) -> Millidegrees {
+        if self.is_capped() {
+            return self.max_compensation;
+        }
+        if self.is_inverted() {
+            return Millidegrees::ZERO;
+        }
+        self.compensation
+    }
+
+    /// The median overshoot as measured, before the cap.
+    pub fn measured(&self) -> Millidegrees {
+        self.compensation
+    }
+
+    pub fn is_capped(&self) -> bool {
+        if self.max_compensation < Millidegrees::ZERO {
+            self.compensation < self.max_compensation
+        } else {
+            self.compensation > self.max_compensation
+        }
+    }
+
+    fn is_inverted(&self) -> bool {
+        if self.max_compensation < Millidegrees::ZERO {
+            self.compensation > Millidegrees::ZERO
+        } else {
+            self.compensation < Millidegrees::ZERO
+        }
+    }
+
+    pub fn get_threshold(&self) -> Millidegrees {
+        self.target + self.get_compensation()
+    }
+
+    /// Moves the cap, what was measured is kept and capped anew.
+    pub fn set_max_compensation(&mut self, max_compensation: Millidegrees) {
+        if max_compensation == Millidegrees::ZERO {
+            panic!("max_compensation can not be 0.");
+        }
+        self.max_compensation = max_compensation;
+    }
+
+    /// Moves the setpoint, the compensation learned against it moves along.
+    pub fn set_target(&mut self, target: Millidegrees) {
+        self.target = target;
+    }
+
+    pub fn reset(&mut self) {
+        self.compensation = Millidegrees::ZERO;
+        self.observations.clear();
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.compensation == Millidegrees::ZERO
+    }
+
+    /// The overshoots the compensation is currently the median of, oldest first.
+    pub fn observations(&self) -> impl Iterator<Item = Millidegrees> + '_ {
+        self.observations.iter().copied()
+    }
+
+    pub fn push_observation(&mut self, value: Millidegrees) {
+        const MAX_OBSERVATIONS: u8 = 4;
+        const MIN_UPDATE: Millidegrees = Millidegrees(10);
+
+        let delta = self.get_threshold() - value;
+        self.observations.push_back(delta);
+        if self.observations.len() > MAX_OBSERVATIONS as usize {
+            self.observations.pop_front();
+        }
+        let mut sorted_observations: Vec<Millidegrees> = self.observations.iter().copied().collect();
+        sorted_observations.sort();
+        let median_delta = match sorted_observations.len() {
+            1 => sorted_observations[0],
+            len if len % 2 == 0 => {
+                let m1 = sorted_observations[(len / 2) - 1];
+                let m2 = sorted_observations[len / 2];
+                // Half a millidegree down at most, the same way whichever sign.
+                Millidegrees((m1.0 + m2.0).div_euclid(2))
+            }
+            len => sorted_observations[len / 2],
+        };
+        let update = median_delta - self.compensation;
+
+        if update.abs() > MIN_UPDATE {
+            self.compensation = median_delta;
+        }
+    }
+}
+
+/// The extremes of the cycle in progress, as checkpointed. Kept against the wall clock so a restart can tell
+/// whether the relay is still in the same cycle.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CycleExtremes {
+    /// The relay during the cycle.
+    pub on: bool,
+    pub min: Celsius,
+    pub max: Celsius,
+    /// How long the cycle has been going.
+    pub running: Duration,
+    /// How long ago it was checkpointed, zero when persisting.
+    pub age: Duration,
+}
+
+impl CycleExtremes {
+    // Pure
+    /// Whether the relay as the previous run left it is still in this cycle. An off cycle began with the last off
+    /// transition, one of unknown length can't be told from a later one.
+    pub fn continues(&self, power_state: RestoredPowerState) -> bool {
+        match power_state {
+            RestoredPowerState::CurrentlyOn => self.on,
+            RestoredPowerState::OffFor(off) => {
+                let apart = match off > self.running {
+                    true => off - self.running,
+                    false => self.running - off,
+                };
+                !self.on && apart <= CYCLE_START_SLACK
+            }
+            RestoredPowerState::OffForUnknownDuration => false,
+        }
+    }
+}
+
+/// The lowest and highest reading of a cycle, and whether anything made them unfit to learn compensation from.
+pub struct ExtremeTracker {
+    min: Celsius,
+    max: Celsius,
+    measured: bool,
+    /// Why, set by whatever was going on at any point since the last reset.
+    inhibited: Option<&'static str>,
+}
+
+impl Default for ExtremeTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtremeTracker {
+    pub fn new() -> Self {
+        Self {
+            min: Celsius(f32::MAX),
+            max: Celsius(f32::MIN),
+            measured: false,
+            inhibited: None,
+        }
+    }
+
+    /// Carries on from the extremes checkpointed before a restart.
+    pub fn resume(checkpoint: &CycleExtremes) -> Self {
+        let mut extremes = Self::new();
+        extremes.push(checkpoint.min);
+        extremes.push(checkpoint.max);
+        extremes
+    }
+
+    /// Keeps these extremes out of the compensators, until the next reset.
+    pub fn inhibit_learning(&mut self, reason: &'static str) {
+        self.inhibited.get_or_insert(reason);
+    }
+
+    pub fn learning_inhibited(&self) -> Option<&'static str> {
+        self.inhibited
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new()
+    }
+
+    pub fn push(&mut self, value: Celsius) {
+        debug_assert!(value.is_finite(), "Unvalidated temperature {:?}", value);
+        if value < self.min {
+            self.min = value;
+        }
+        if value > self.max {
+            self.max = value;
+        }
+        self.measured = true;
+    }
+
+    pub fn min(&self) -> Option<Celsius> {
+        match self.measured {
+            true => Some(self.min),
+            false => None,
+        }
+    }
+
+    pub fn max(&self) -> Option<Celsius> {
+        match self.measured {
+            true => Some(self.max),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Hysteresis;
+
+    /// The relay after each reading, a minute apart and without minimum intervals so only the thresholds hold it.
+    fn relay(initial: State, readings: &[i32], thresholds: Thresholds) -> Vec<bool> {
+        let start = Instant::now();
+        let intervals = MinimumIntervals {
+            on: Duration::from_secs(0),
+            off: Duration::from_secs(0),
+        };
+        let mut state = initial;
+        readings
+            .iter()
+            .enumerate()
+            .map(|(i, &reading)| {
+                let now = start + Duration::from_secs(60 * i as u64);
+                state = transition(state, Millidegrees(reading), thresholds, now, intervals);
+                state.is_on()
+            })
+            .collect()
+    }
+
+    fn offsets(low_engage: f32, low_release: f32, high_engage: f32, high_release: f32) -> Hysteresis {
+        Hysteresis {
+            low_engage: DegreesDelta(low_engage),
+            low_release: DegreesDelta(low_release),
+            high_engage: DegreesDelta(high_engage),
+            high_release: DegreesDelta(high_release),
+        }
+    }
+
+    #[test]
+    fn start_modes_override_the_restored_power_state() {
+        let now = Instant::now();
+        let intervals = MinimumIntervals::default();
+        let off_for = |minutes: u64| Ok(RestoredPowerState::OffFor(Duration::from_secs(60 * minutes)));
+        let start = |mode, restored| initial_state(mode, restored, now, intervals);
+        assert_eq!(
+            State::MinimumIntervalOff(now - Duration::from_secs(60)),
+            start(StartMode::Auto, off_for(1))
+        );
+        assert_eq!(State::InitiallyOff, start(StartMode::Auto, off_for(60)));
+        for restored in [
+            off_for(60),
+            Ok(RestoredPowerState::CurrentlyOn),
+            Err(anyhow::anyhow!("corrupt")),
+        ] {
+            assert_eq!(State::MinimumIntervalOff(now), start(StartMode::OffLocked, restored));
+        }
+        for restored in [off_for(1), Ok(RestoredPowerState::OffForUnknownDuration)] {
+            assert_eq!(State::InitiallyOff, start(StartMode::OffFree, restored));
+        }
+        assert_eq!(
+            State::MinimumIntervalOn(now),
+            start(StartMode::FollowRelay, Ok(RestoredPowerState::CurrentlyOn))
+        );
+        for restored in [off_for(1), Err(anyhow::anyhow!("corrupt"))] {
+            assert_eq!(State::InitiallyOff, start(StartMode::FollowRelay, restored));
+        }
+        assert_eq!(StartMode::FollowRelay, "follow-relay".parse().unwrap());
+        assert_eq!("off-locked", StartMode::OffLocked.to_string());
+        assert!("locked".parse::<StartMode>().is_err());
+        assert!(!StartMode::OffLocked.skips_lockout());
+    }
+
+    #[test]
+    fn zero_offsets_are_the_exact_thresholds() {
+        assert_eq!(
+            Thresholds::exact(Millidegrees(1000), Millidegrees(4000)),
+            Hysteresis::default().thresholds(Millidegrees(1000), Millidegrees(4000))
+        );
+        let thresholds = offsets(0.1, 0.2, 0.3, 0.4).thresholds(Millidegrees(1000), Millidegrees(4000));
+        assert_eq!(
+            (Millidegrees(900), Millidegrees(1200)),
+            (thresholds.low.engage, thresholds.low.release)
+        );
+        assert_eq!(
+            (Millidegrees(4300), Millidegrees(3600)),
+            (thresholds.high.engage, thresholds.high.release)
+        );
+    }
+
+    #[test]
+    fn plateau_at_the_high_edge() {
+        let plateau = [4010, 3990, 4050, 4200, 3950, 4250, 4310, 4100];
+        let exact = Thresholds::exact(Millidegrees(1000), Millidegrees(4000));
+        assert_eq!(
+            vec![true, true, true, true, true, true, true, true],
+            relay(State::Off, &plateau, exact)
+        );
+        let engaged = offsets(0.0, 0.0, 0.3, 0.2).thresholds(Millidegrees(1000), Millidegrees(4000));
+        assert_eq!(
+            vec![false, false, false, false, false, false, true, true],
+            relay(State::Off, &plateau, engaged)
+        );
+    }
+
+    #[test]
+    fn plateau_at_the_low_edge() {
+        let plateau = [990, 1010, 800, 950, 710, 690, 900, 1100];
+        let exact = Thresholds::exact(Millidegrees(1000), Millidegrees(4000));
+        assert_eq!(
+            vec![false, false, false, false, false, false, false, false],
+            relay(State::On, &plateau, exact)
+        );
+        let engaged = offsets(0.3, 0.2, 0.0, 0.0).thresholds(Millidegrees(1000), Millidegrees(4000));
+        assert_eq!(
+            vec![true, true, true, true, true, false, false, false],
+            relay(State::On, &plateau, engaged)
+        );
+    }
+
+    #[test]
+    fn plateau_where_compensated_thresholds_meet() {
+        // Fully compensated in a narrow band, both thresholds at 19C.
+        let plateau = [19010, 18990, 19010, 18990, 19400, 19010, 18990, 18700, 19010, 19200];
+        let exact = Thresholds::exact(Millidegrees(19000), Millidegrees(19000));
+        assert_eq!(
+            vec![true, false, true, false, true, true, false, false, true, true],
+            relay(State::Off, &plateau, exact)
+        );
+        // Only releasing the high side below 18.8C stops the chatter, engaging the relay at 19.3C.
+        let released = offsets(0.0, 0.0, 0.3, 0.2).thresholds(Millidegrees(19000), Millidegrees(19000));
+        assert_eq!(
+            vec![false, false, false, false, true, true, true, false, false, false],
+            relay(State::Off, &plateau, released)
+        );
+    }
+
+    #[test]
+    fn compensate_default() {
+        let compensator = Compensator::new(Millidegrees(40000), Millidegrees(0), Millidegrees(-3000));
+        assert_eq!(Millidegrees(0), compensator.get_compensation());
+    }
+
+    #[test]
+    fn heat_compensate_one_exact_measure() {
+        let mut compensator = Compensator::new(Millidegrees(40000), Millidegrees(0), Millidegrees(-3000));
+        compensator.push_observation(Millidegrees(40000));
+        assert_eq!(Millidegrees(0), compensator.get_compensation());
+        assert_eq!(Millidegrees(40000), compensator.get_threshold());
+    }
+
+    #[test]
+    fn heat_compensate_one_high_measure() {
+        let mut compensator = Compensator::new(Millidegrees(40000), Millidegrees(0), Millidegrees(-3000));
+        compensator.push_observation(Millidegrees(41000));
+        assert_eq!(Millidegrees(-1000), compensator.get_compensation());
+        assert_eq!(Millidegrees(39000), compensator.get_threshold());
+    }
+
+    #[test]
+    fn heat_compensate_two_high_measure() {
+        let mut compensator = Compensator::new(Millidegrees(40000), Millidegrees(0), Millidegrees(-3000));
+        compensator.push_observation(Millidegrees(42000));
+        assert_eq!(Millidegrees(-2000), compensator.get_compensation());
+        assert_eq!(Millidegrees(38000), compensator.get_threshold());
+        compensator.push_observation(Millidegrees(40000));
+        assert_eq!(Millidegrees(-2000), compensator.get_compensation());
+        assert_eq!(Millidegrees(38000), compensator.get_threshold());
+    }
+
+    #[test]
+    fn heat_compensate_one_high_measure_capped() {
+        let mut compensator = Compensator::new(Millidegrees(40000), Millidegrees(0), Millidegrees(-500));
+        compensator.push_observation(Millidegrees(41000));
+        assert_eq!(Millidegrees(-500), compensator.get_compensation());
+        assert_eq!(Millidegrees(39500), compensator.get_threshold());
+        assert!(compensator.is_capped());
+    }
+
+    #[test]
+    fn heat_compensate_one_inverted_measure() {
+        let mut compensator = Compensator::new(Millidegrees(40000), Millidegrees(0), Millidegrees(-500));
+        compensator.push_observation(Millidegrees(39500));
+        assert_eq!(Millidegrees(0), compensator.get_compensation());
+        assert_eq!(Millidegrees(40000), compensator.get_threshold());
+        assert!(!compensator.is_capped());
+    }
+
+    #[test]
+    fn heat_compensate_one_high_measure_adjust() {
+        let mut compensator = Compensator::new(Millidegrees(40000), Millidegrees(-1000), Millidegrees(-3000));
+        compensator.push_observation(Millidegrees(40500));
+        assert_eq!(Millidegrees(-1500), compensator.get_compensation());
+        assert_eq!(Millidegrees(38500), compensator.get_threshold());
+    }
+
+    #[test]
+    fn heat_compensate_one_low_measure_adjust() {
+        let mut compensator = Compensator::new(Millidegrees(40000), Millidegrees(-3000), Millidegrees(-3000));
+        compensator.push_observation(Millidegrees(39000));
+        assert_eq!(Millidegrees(-2000), compensator.get_compensation());
+        assert_eq!(Millidegrees(38000), compensator.get_threshold());
+    }
+
+    #[test]
+    fn cool_compensate_one_low_measure() {
+        let mut compensator = Compensator::new(Millidegrees(33000), Millidegrees(0), Millidegrees(3000));
+        compensator.push_observation(Millidegrees(32000));
+        assert_eq!(Millidegrees(1000), compensator.get_compensation());
+        assert_eq!(Millidegrees(34000), compensator.get_threshold());
+    }
+
+    #[test]
+    fn cool_compensate_two_low_measure() {
+        let mut compensator = Compensator::new(Millidegrees(33000), Millidegrees(0), Millidegrees(3000));
+        compensator.push_observation(Millidegrees(32000));
+        assert_eq!(Millidegrees(1000), compensator.get_compensation());
+        assert_eq!(Millidegrees(34000), compensator.get_threshold());
+        compensator.push_observation(Millidegrees(33000));
+        assert_eq!(Millidegrees(1000), compensator.get_compensation());
+        assert_eq!(Millidegrees(34000), compensator.get_threshold());
+    }
+
+    #[test]
+    fn cool_compensate_five_low_measure_adjust() {
+        let mut compensator = Compensator::new(Millidegrees(33000), Millidegrees(0), Millidegrees(3000));
+        // Start 1 true swing
+        compensator.push_observation(Millidegrees(32000));
+        assert_eq!(Millidegrees(1000), compensator.get_compensation());
+        assert_eq!(Millidegrees(34000), compensator.get_threshold());
+        compensator.push_observation(Millidegrees(33000));
+        assert_eq!(Millidegrees(1000), compensator.get_compensation());
+        assert_eq!(Millidegrees(34000), compensator.get_threshold());
+        // Swing changes to 1.5
+        compensator.push_observation(Millidegrees(32500));
+        assert_eq!(Millidegrees(1000), compensator.get_compensation());
+        assert_eq!(Millidegrees(34000), compensator.get_threshold());
+        compensator.push_observation(Millidegrees(32500));
+        assert_eq!(Millidegrees(1250), compensator.get_compensation());
+        assert_eq!(Millidegrees(34250), compensator.get_threshold());
+        compensator.push_observation(Millidegrees(32750));
+        assert_eq!(Millidegrees(1500), compensator.get_compensation());
+        assert_eq!(Millidegrees(34500), compensator.get_threshold());
+    }
+
+    #[test]
+    fn inhibited_learning_lasts_until_the_extremes_reset() {
+        let mut extremes = ExtremeTracker::new();
+        extremes.push(Celsius(3.0));
+        extremes.inhibit_learning("boost");
+        extremes.push(Celsius(1.0));
+        extremes.inhibit_learning("other");
+        assert_eq!(Some("boost"), extremes.learning_inhibited());
+        assert_eq!(
+            (Some(Celsius(1.0)), Some(Celsius(3.0))),
+            (extremes.min(), extremes.max())
+        );
+        extremes.reset();
+        assert_eq!(None, extremes.learning_inhibited());
+        assert_eq!(None, extremes.min());
+    }
+
+    #[test]
+    fn checkpointed_extremes_only_continue_the_same_cycle() {
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        let off = CycleExtremes {
+            on: false,
+            min: Celsius(1.0),
+            max: Celsius(4.0),
+            running: minutes(20),
+            age: minutes(2),
+        };
+        assert!(off.continues(RestoredPowerState::OffFor(minutes(20) + Duration::from_secs(30))));
+        assert!(off.continues(RestoredPowerState::OffFor(minutes(20) - Duration::from_secs(30))));
+        // The relay went off again since, a later off cycle.
+        assert!(!off.continues(RestoredPowerState::OffFor(minutes(5))));
+        assert!(!off.continues(RestoredPowerState::OffForUnknownDuration));
+        assert!(!off.continues(RestoredPowerState::CurrentlyOn));
+        let on = CycleExtremes { on: true, ..off };
+        assert!(on.continues(RestoredPowerState::CurrentlyOn));
+        assert!(!on.continues(RestoredPowerState::OffFor(minutes(20))));
+        let resumed = ExtremeTracker::resume(&off);
+        assert_eq!((Some(Celsius(1.0)), Some(Celsius(4.0))), (resumed.min(), resumed.max()));
+        assert_eq!(None, resumed.learning_inhibited());
+    }
+
+    #[test]
+    fn cool_compensate_one_low_measure_capped() {
+        let mut compensator = Compensator::new(Millidegrees(33000), Millidegrees(0), Millidegrees(500));
+        compensator.push_observation(Millidegrees(32000));
+        assert_eq!(Millidegrees(500), compensator.get_compensation());
+        assert_eq!(Millidegrees(33500), compensator.get_threshold());
+        assert!(compensator.is_capped());
+    }
+
+    #[test]
+    fn cool_compensate_one_inverted_measure() {
+        let mut compensator = Compensator::new(Millidegrees(33000), Millidegrees(0), Millidegrees(500));
+        compensator.push_observation(Millidegrees(33500));
+        assert_eq!(Millidegrees(0), compensator.get_compensation());
+        assert_eq!(Millidegrees(33000), compensator.get_threshold());
+        assert!(!compensator.is_capped());
+    }
+
+    #[test]
+    fn cool_compensate_one_high_measure_adjust() {
+        let mut compensator = Compensator::new(Millidegrees(33000), Millidegrees(3000), Millidegrees(3000));
+        compensator.push_observation(Millidegrees(33500));
+        assert_eq!(Millidegrees(2500), compensator.get_compensation());
+        assert_eq!(Millidegrees(35500), compensator.get_threshold());
+    }
+}
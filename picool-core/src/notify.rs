@@ -0,0 +1,449 @@
+use crate::{
+    cap_alert::Compensation,
+    current::LoadFault,
+    energy::DailyEnergy,
+    energy::LocalTime,
+    failsafe::FailsafePolicy,
+    incidents::Peak,
+    runaway::RunawayEvidence,
+    shed::ShedEnd,
+    starts::DailyStarts,
+    temperature::{display_units, Celsius, DegreesDelta, Rate, Units},
+    timing_advisor::TimingAdvice,
+    world_error::{SensorErrorCounts, SensorErrorKind},
+    MinimumIntervals,
+};
+use log::*;
+use std::{fmt, ops::Range, time::Duration};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    SensorFailsafeEngaged {
+        failing_for: Duration,
+        policy: FailsafePolicy,
+        cause: SensorErrorKind,
+    },
+    SensorRecovered {
+        outage: Duration,
+        errors: SensorErrorCounts,
+    },
+    ThermalRunaway(RunawayEvidence),
+    RunawayCleared {
+        locked_out_for: Duration,
+        acknowledged: bool,
+    },
+    DutyCycleHigh {
+        duty: f32,
+        window: Duration,
+    },
+    DutyCycleCleared {
+        duty: f32,
+        window: Duration,
+    },
+    CoolingDegraded {
+        rate: f32,
+        baseline: f32,
+    },
+    CoolingRecovered {
+        rate: f32,
+        baseline: f32,
+    },
+    /// A compensator sat at its cap, the thresholds are missing the band by `overshoot - cap` every cycle.
+    CompensationCapped {
+        compensation: Compensation,
+        cycles: u32,
+        overshoot: DegreesDelta,
+        cap: DegreesDelta,
+        suggested: DegreesDelta,
+    },
+    CompensationUncapped {
+        compensation: Compensation,
+        cycles: u32,
+    },
+    LoopStalled {
+        stalled_for: Duration,
+    },
+    /// The daily summary, sent when the local day rolls over.
+    DailyEnergy(DailyEnergy),
+    /// The compressor runtime counter was reset from the control socket.
+    RuntimeReset {
+        previous: Duration,
+    },
+    /// Sent alongside the energy summary when the local day rolls over.
+    DailyStarts(DailyStarts),
+    /// A temporary band from the control socket took over from the configured one.
+    BoostStarted {
+        band: Range<Celsius>,
+        duration: Duration,
+    },
+    /// The boost ran out or was cancelled, back on `band`.
+    BoostEnded {
+        band: Range<Celsius>,
+        cancelled: bool,
+    },
+    /// Writes kept reading back wrong, state is only kept in memory until a retry verifies.
+    StorageUnreliable {
+        failures: u32,
+        retry: Duration,
+    },
+    StorageRecovered {
+        skipped: u32,
+    },
+    /// The timing advisor has new minimum intervals, applied or only recommended.
+    TimingAdvice {
+        advice: TimingAdvice,
+        applied: bool,
+    },
+    /// Intervals the advisor applied were reverted from the control socket.
+    TimingReverted {
+        intervals: MinimumIntervals,
+    },
+    /// The demand response signal asked for load to be shed, starts are held off for up to `max_duration`.
+    ShedStarted {
+        max_duration: Duration,
+    },
+    ShedEnded {
+        lasted: Duration,
+        end: ShedEnd,
+    },
+    /// The pump output read back other than `commanded`, compressor starts are interlocked until it recovers.
+    PumpFailed {
+        commanded: bool,
+    },
+    PumpRecovered,
+    /// The compressor's current doesn't match the relay.
+    LoadFault {
+        fault: LoadFault,
+        amps: f32,
+    },
+    LoadFaultCleared {
+        fault: LoadFault,
+    },
+    /// The primary output failed its current checks, the backup switches the compressor.
+    FailedOver {
+        failures: u32,
+    },
+    /// The primary output switches the compressor again, after a retry that drew the load or a reset by hand.
+    PrimaryRestored {
+        reset: bool,
+    },
+    /// An alarm cleared and stayed clear, held back until then so a flapping alarm is one incident.
+    IncidentOver {
+        cleared: Box<Event>,
+        started_at: LocalTime,
+        lasted: Duration,
+        peak: Peak,
+        /// Raised again while clearing.
+        reraised: u32,
+    },
+}
+
+impl Event {
+    /// The message with temperatures in `units`, `Display` uses the process wide units.
+    pub fn message(&self, units: Units) -> String {
+        Message(self, units).to_string()
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Message(self, display_units()).fmt(f)
+    }
+}
+
+struct Message<'a>(&'a Event, Units);
+
+impl fmt::Display for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = self.1;
+        match self.0 {
+            Event::SensorFailsafeEngaged {
+                failing_for,
+                policy,
+                cause,
+            } => write!(
+                f,
+                "Sensor failing ({}) for {}s, failsafe engaged with policy {}.",
+                cause,
+                failing_for.as_secs(),
+                policy
+            ),
+            Event::SensorRecovered { outage, errors } => {
+                write!(f, "Sensor recovered after a {}s outage ({}).", outage.as_secs(), errors)
+            }
+            Event::ThermalRunaway(evidence) => write!(
+                f,
+                "Thermal runaway: relay on for {}m while temperature rising {}, locked out.",
+                evidence.on_for.as_secs() / 60,
+                units.show(Rate::new(evidence.slope_per_hour, "/h"))
+            ),
+            Event::RunawayCleared {
+                locked_out_for,
+                acknowledged,
+            } => write!(
+                f,
+                "Thermal runaway lockout cleared after {}m ({}).",
+                locked_out_for.as_secs() / 60,
+                match acknowledged {
+                    true => "acknowledged",
+                    false => "auto retry",
+                }
+            ),
+            Event::DutyCycleHigh { duty, window } => write!(
+                f,
+                "Relay duty cycle {:.0}% over the last {}h, the fridge can't keep up.",
+                duty * 100.0,
+                window.as_secs() / 3600
+            ),
+            Event::DutyCycleCleared { duty, window } => write!(
+                f,
+                "Relay duty cycle back to {:.0}% over the last {}h.",
+                duty * 100.0,
+                window.as_secs() / 3600
+            ),
+            Event::CoolingDegraded { rate, baseline } => write!(
+                f,
+                "Cooling degraded: on cycle pulled down {:.3} against a baseline of {:.3}.",
+                units.show(Rate::new(*rate, "/min")),
+                units.show(Rate::new(*baseline, "/min"))
+            ),
+            Event::CoolingRecovered { rate, baseline } => write!(
+                f,
+                "Cooling recovered: on cycle pulled down {:.3} against a baseline of {:.3}.",
+                units.show(Rate::new(*rate, "/min")),
+                units.show(Rate::new(*baseline, "/min"))
+            ),
+            Event::CompensationCapped {
+                compensation,
+                cycles,
+                overshoot,
+                cap,
+                suggested,
+            } => write!(
+                f,
+                "{} compensation held at its {} cap for {} cycles, the chamber carries on {} past the threshold. \
+                 Raise --max-compensation to {}.",
+                compensation,
+                units.show(*cap),
+                cycles,
+                units.show(*overshoot),
+                units.show(*suggested)
+            ),
+            Event::CompensationUncapped { compensation, cycles } => write!(
+                f,
+                "{} compensation back under its cap after {} capped cycles.",
+                compensation, cycles
+            ),
+            Event::LoopStalled { stalled_for } => {
+                write!(f, "Control loop made no progress for {}s.", stalled_for.as_secs())
+            }
+            Event::DailyEnergy(DailyEnergy { kwh, cost }) => match cost {
+                Some(cost) => write!(f, "Used an estimated {:.2} kWh yesterday, costing {:.2}.", kwh, cost),
+                None => write!(f, "Used an estimated {:.2} kWh yesterday.", kwh),
+            },
+            Event::RuntimeReset { previous } => write!(
+                f,
+                "Compressor runtime counter reset from {:.1}h.",
+                previous.as_secs_f64() / 3600.0
+            ),
+            Event::DailyStarts(DailyStarts { starts, trend }) => {
+                write!(f, "Compressor started {} times yesterday", starts)?;
+                match trend {
+                    Some(trend) if trend.is_significant_increase() => write!(
+                        f,
+                        ", averaging {:.1}/day over the last week against {:.1}/day the month before. Check the door \
+                         seal and the thermostat band.",
+                        trend.week, trend.month
+                    ),
+                    Some(trend) => write!(
+                        f,
+                        ", averaging {:.1}/day over the last week against {:.1}/day the month before.",
+                        trend.week, trend.month
+                    ),
+                    None => write!(f, "."),
+                }
+            }
+            Event::BoostStarted { band, duration } => write!(
+                f,
+                "Boosting to {} to {} for {}m.",
+                units.show(band.start),
+                units.show(band.end),
+                duration.as_secs() / 60
+            ),
+            Event::BoostEnded { band, cancelled } => write!(
+                f,
+                "Boost {}, back to {} to {}.",
+                match cancelled {
+                    true => "cancelled",
+                    false => "expired",
+                },
+                units.show(band.start),
+                units.show(band.end)
+            ),
+            Event::StorageUnreliable { failures, retry } => write!(
+                f,
+                "Storage unreliable: {} writes in a row read back wrong, keeping state in memory only and retrying \
+                 every {}m.",
+                failures,
+                retry.as_secs() / 60
+            ),
+            Event::StorageRecovered { skipped } => write!(
+                f,
+                "Storage verified again, persisting resumed after {} skipped writes.",
+                skipped
+            ),
+            Event::TimingAdvice { advice, applied } => write!(
+                f,
+                "Minimum intervals {}: {}.",
+                match applied {
+                    true => "tuned",
+                    false => "could be tuned",
+                },
+                advice
+            ),
+            Event::TimingReverted { intervals } => write!(
+                f,
+                "Tuned minimum intervals reverted to the configured {}m on and {}m off.",
+                intervals.on.as_secs() / 60,
+                intervals.off.as_secs() / 60
+            ),
+            Event::ShedStarted { max_duration } => write!(
+                f,
+                "Shedding load, compressor starts held off for up to {}m.",
+                max_duration.as_secs() / 60
+            ),
+            Event::ShedEnded { lasted, end } => write!(f, "Load shed over after {}m, {}.", lasted.as_secs() / 60, end),
+            Event::PumpFailed { commanded } => write!(
+                f,
+                "Pump output reads {} while commanded {}, compressor starts interlocked.",
+                match commanded {
+                    true => "off",
+                    false => "on",
+                },
+                match commanded {
+                    true => "on",
+                    false => "off",
+                }
+            ),
+            Event::PumpRecovered => write!(f, "Pump output reads back as commanded again, interlock released."),
+            Event::LoadFault {
+                fault: LoadFault::NoLoad,
+                amps,
+            } => write!(
+                f,
+                "Compressor commanded on but drawing only {:.2}A. Check the start capacitor and the thermal overload.",
+                amps
+            ),
+            Event::LoadFault {
+                fault: LoadFault::Unexpected,
+                amps,
+            } => write!(
+                f,
+                "Compressor drawing {:.2}A while commanded off, the relay may be welded.",
+                amps
+            ),
+            Event::LoadFaultCleared { fault } => {
+                write!(f, "Compressor current matches the relay again, {} cleared.", fault)
+            }
+            Event::FailedOver { failures } => write!(
+                f,
+                "Primary relay failed {} current checks in a row, switching the compressor on the backup relay. \
+                 Send reset-failover once the primary is repaired.",
+                failures
+            ),
+            Event::PrimaryRestored { reset: false } => {
+                write!(
+                    f,
+                    "Retried primary relay is switching the compressor again, fault cleared."
+                )
+            }
+            Event::PrimaryRestored { reset: true } => {
+                write!(
+                    f,
+                    "Primary relay fault reset, switching the compressor on the primary again."
+                )
+            }
+            Event::IncidentOver {
+                cleared,
+                started_at,
+                lasted,
+                peak,
+                reraised,
+            } => {
+                let since_midnight = started_at.since_midnight.as_secs() / 60;
+                write!(
+                    f,
+                    "{} Raised {:02}:{:02}, lasted {}m, peaked at ",
+                    Message(cleared, units),
+                    since_midnight / 60,
+                    since_midnight % 60,
+                    lasted.as_secs() / 60
+                )?;
+                match peak {
+                    Peak::Duty(duty) => write!(f, "duty {:.0}%", duty * 100.0)?,
+                    Peak::CoolingRate(rate) => write!(f, "a pull down of {:.3}", units.show(Rate::new(*rate, "/min")))?,
+                    Peak::Overshoot(overshoot) => write!(f, "{} past the threshold", units.show(*overshoot))?,
+                }
+                match reraised {
+                    0 => write!(f, "."),
+                    1 => write!(f, ", raised again once."),
+                    n => write!(f, ", raised again {} times.", n),
+                }
+            }
+        }
+    }
+}
+
+pub trait Notifier {
+    fn notify(&mut self, event: &Event);
+}
+
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&mut self, event: &Event) {
+        warn!("Notification: {}", event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_in_each_unit_setting() {
+        let runaway = Event::ThermalRunaway(RunawayEvidence {
+            on_for: Duration::from_secs(60 * 45),
+            slope_per_hour: 1.5,
+        });
+        assert_eq!(
+            "Thermal runaway: relay on for 45m while temperature rising 1.50C/h, locked out.",
+            runaway.message(Units::Celsius)
+        );
+        assert_eq!(
+            "Thermal runaway: relay on for 45m while temperature rising 2.70F/h, locked out.",
+            runaway.message(Units::Fahrenheit)
+        );
+        assert_eq!(
+            "Thermal runaway: relay on for 45m while temperature rising 1.50C/h 2.70F/h, locked out.",
+            runaway.message(Units::Both)
+        );
+        let degraded = Event::CoolingDegraded {
+            rate: 0.05,
+            baseline: 0.1,
+        };
+        assert_eq!(
+            "Cooling degraded: on cycle pulled down 0.050C/min against a baseline of 0.100C/min.",
+            degraded.message(Units::Celsius)
+        );
+        assert_eq!(
+            "Cooling degraded: on cycle pulled down 0.090F/min against a baseline of 0.180F/min.",
+            degraded.message(Units::Fahrenheit)
+        );
+        assert_eq!(
+            "Cooling degraded: on cycle pulled down 0.050C/min 0.090F/min against a baseline of 0.100C/min 0.180F/min.",
+            degraded.message(Units::Both)
+        );
+        assert_eq!(runaway.message(Units::Both), runaway.to_string());
+    }
+}
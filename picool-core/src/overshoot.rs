@@ -0,0 +1,183 @@
+use crate::temperature::{Celsius, DegreesDelta};
+use std::{collections::VecDeque, fmt};
+
+/// Cycles averaged over, and kept across restarts.
+const HISTORY: usize = 10;
+/// Within this of the average, a cycle missed the band about as usual.
+const STEADY: DegreesDelta = DegreesDelta(0.05);
+
+/// How far recent cycles went past each end of the band, oldest first, as persisted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overshoots {
+    /// Past the top, after the compressor started.
+    pub over: Vec<DegreesDelta>,
+    /// Below the bottom, after it stopped.
+    pub under: Vec<DegreesDelta>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum End {
+    /// The on cycle's pull down lagged and the temperature kept rising past the top of the band.
+    Over,
+    /// The evaporator kept cooling after the off transition and the temperature fell below the bottom.
+    Under,
+}
+
+/// How far one cycle missed its end of the band, against the recent ones.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Miss {
+    pub end: End,
+    pub by: DegreesDelta,
+    /// Of the last `cycles`, this one included.
+    pub average: DegreesDelta,
+    pub cycles: usize,
+}
+
+impl Miss {
+    pub fn trend(&self) -> &'static str {
+        match self.by - self.average {
+            delta if delta.0 < -STEADY.0 => "improving",
+            delta if delta.0 > STEADY.0 => "worsening",
+            _ => "steady",
+        }
+    }
+}
+
+/// `overshoot 0.42C 0.76F (avg last 10: 0.51C 0.92F, improving)`, the line in the cycle summary.
+impl fmt::Display for Miss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (avg last {}: {}, {})",
+            match self.end {
+                End::Over => "overshoot",
+                End::Under => "undershoot",
+            },
+            self.by,
+            self.cycles,
+            self.average,
+            self.trend()
+        )
+    }
+}
+
+// Pure
+/// How far the highest reading of an on cycle went past `top`, zero when it stayed in the band.
+pub fn overshoot(max: Celsius, top: Celsius) -> DegreesDelta {
+    DegreesDelta((max - top).0.max(0.0))
+}
+
+// Pure
+/// How far the lowest reading of an off cycle fell below `bottom`, zero when it stayed in the band.
+pub fn undershoot(min: Celsius, bottom: Celsius) -> DegreesDelta {
+    DegreesDelta((bottom - min).0.max(0.0))
+}
+
+/// The misses of the last `HISTORY` cycles at each end of the band, what the compensators are driving to zero.
+pub struct OvershootTracker {
+    over: VecDeque<DegreesDelta>,
+    under: VecDeque<DegreesDelta>,
+}
+
+impl OvershootTracker {
+    pub fn new(seed: Overshoots) -> Self {
+        let history = |misses: Vec<DegreesDelta>| {
+            let mut misses: VecDeque<DegreesDelta> = misses.into_iter().filter(|m| m.0.is_finite()).collect();
+            while misses.len() > HISTORY {
+                misses.pop_front();
+            }
+            misses
+        };
+        Self {
+            over: history(seed.over),
+            under: history(seed.under),
+        }
+    }
+
+    pub fn record(&mut self, end: End, by: DegreesDelta) -> Miss {
+        let misses = match end {
+            End::Over => &mut self.over,
+            End::Under => &mut self.under,
+        };
+        misses.push_back(by);
+        if misses.len() > HISTORY {
+            misses.pop_front();
+        }
+        let total = misses.iter().fold(DegreesDelta::ZERO, |total, &miss| total + miss);
+        Miss {
+            end,
+            by,
+            average: total / misses.len() as f32,
+            cycles: misses.len(),
+        }
+    }
+
+    /// The latest miss at each end, over then under.
+    pub fn latest(&self) -> (Option<DegreesDelta>, Option<DegreesDelta>) {
+        (self.over.back().copied(), self.under.back().copied())
+    }
+
+    pub fn overshoots(&self) -> Overshoots {
+        Overshoots {
+            over: self.over.iter().copied().collect(),
+            under: self.under.iter().copied().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_against_the_band_not_the_thresholds() {
+        assert_eq!(DegreesDelta(0.5), overshoot(Celsius(8.5), Celsius(8.0)));
+        assert_eq!(DegreesDelta(0.25), undershoot(Celsius(3.75), Celsius(4.0)));
+        // Turned around inside the band.
+        assert_eq!(DegreesDelta::ZERO, overshoot(Celsius(7.5), Celsius(8.0)));
+        assert_eq!(DegreesDelta::ZERO, undershoot(Celsius(4.0), Celsius(4.0)));
+    }
+
+    #[test]
+    fn averages_the_last_cycles_of_each_end() {
+        let mut tracker = OvershootTracker::new(Overshoots::default());
+        let first = tracker.record(End::Over, DegreesDelta(0.5));
+        assert_eq!(
+            (DegreesDelta(0.5), 1, "steady"),
+            (first.average, first.cycles, first.trend())
+        );
+        for _ in 0..HISTORY {
+            tracker.record(End::Over, DegreesDelta(1.0));
+        }
+        let zero = tracker.record(End::Under, DegreesDelta::ZERO);
+        assert_eq!((DegreesDelta::ZERO, 1), (zero.average, zero.cycles));
+        let better = tracker.record(End::Over, DegreesDelta(0.5));
+        assert_eq!(HISTORY, better.cycles);
+        assert_eq!(DegreesDelta(0.95), better.average);
+        assert_eq!(
+            "overshoot 0.50C 0.90F (avg last 10: 0.95C 1.71F, improving)",
+            better.to_string()
+        );
+        assert_eq!((Some(DegreesDelta(0.5)), Some(DegreesDelta::ZERO)), tracker.latest());
+        let worse = tracker.record(End::Under, DegreesDelta(0.5));
+        assert_eq!("worsening", worse.trend());
+    }
+
+    #[test]
+    fn restores_the_history_it_persisted() {
+        let mut tracker = OvershootTracker::new(Overshoots::default());
+        for i in 0..12 {
+            tracker.record(End::Over, DegreesDelta(i as f32));
+        }
+        tracker.record(End::Under, DegreesDelta(0.25));
+        let persisted = tracker.overshoots();
+        assert_eq!(HISTORY, persisted.over.len());
+        assert_eq!(DegreesDelta(2.0), persisted.over[0]);
+        let restored = OvershootTracker::new(Overshoots {
+            over: vec![DegreesDelta(f32::NAN), DegreesDelta(1.0)],
+            ..persisted.clone()
+        });
+        assert_eq!(vec![DegreesDelta(1.0)], restored.overshoots().over);
+        assert_eq!(persisted.under, restored.overshoots().under);
+    }
+}
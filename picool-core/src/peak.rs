@@ -0,0 +1,201 @@
+use crate::{
+    energy::{DailyWindow, LocalTime},
+    temperature::{Celsius, DegreesDelta},
+};
+use log::*;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakConfig {
+    /// The daily windows electricity costs more in, none disables peak shifting.
+    pub windows: Vec<DailyWindow>,
+    /// How long before a window the chamber is chilled toward the bottom of the band.
+    pub lead: Duration,
+    /// How far past the high threshold the chamber may warm during a window before a start goes ahead anyway.
+    pub excursion: DegreesDelta,
+}
+
+impl Default for PeakConfig {
+    fn default() -> Self {
+        Self {
+            windows: vec![],
+            lead: Duration::from_secs(60 * 60),
+            excursion: DegreesDelta(1.0),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PeakShift {
+    /// The thresholds decide.
+    Normal,
+    /// Hold off the start the thresholds want.
+    Defer,
+    /// Start now, ahead of a window.
+    Advance,
+}
+
+/// Moves compressor starts out of the peak windows: one start ahead of each window to chill the chamber toward the
+/// bottom of the band, then none during it while the chamber stays within the excursion of the high threshold. Once
+/// that slack is used up control is back to normal.
+pub struct PeakShifter {
+    config: PeakConfig,
+    /// When the start being deferred was first wanted.
+    deferring: Option<Instant>,
+    /// The local minute of the window start last chilled ahead of, so a window only gets one.
+    advanced_for: Option<i64>,
+}
+
+impl PeakShifter {
+    pub fn new(config: PeakConfig) -> Self {
+        Self {
+            config,
+            deferring: None,
+            advanced_for: None,
+        }
+    }
+
+    /// `starting` when the thresholds want the relay on, `free` when it is off and could start without breaking a
+    /// minimum interval or a rest.
+    pub fn shift(
+        &mut self,
+        starting: bool,
+        free: bool,
+        temperature: Celsius,
+        (low, high): (Celsius, Celsius),
+        now: Instant,
+        local: LocalTime,
+    ) -> PeakShift {
+        if let Some(window) = self.config.windows.iter().find(|window| window.contains(local)) {
+            if !starting {
+                self.resume(now, "no start wanted any more");
+                return PeakShift::Normal;
+            }
+            let excess = temperature - high;
+            if excess > self.config.excursion {
+                self.resume(now, "the excursion is used up");
+                return PeakShift::Normal;
+            }
+            if self.deferring.is_none() {
+                self.deferring = Some(now);
+                info!(
+                    "Deferring a start at {} in the {} peak, up to {} allowed",
+                    temperature,
+                    window,
+                    high + self.config.excursion
+                );
+            }
+            return PeakShift::Defer;
+        }
+        self.resume(now, "the peak is over");
+        if starting || !free {
+            return PeakShift::Normal;
+        }
+        let ahead = self
+            .config
+            .windows
+            .iter()
+            .map(|window| (window, window.until_start(local)))
+            .filter(|&(_, until)| until <= self.config.lead)
+            .min_by_key(|&(_, until)| until);
+        let (window, until) = match ahead {
+            Some(ahead) => ahead,
+            None => return PeakShift::Normal,
+        };
+        let minute = |local: LocalTime| local.day * 24 * 60 + local.since_midnight.as_secs() as i64 / 60;
+        let window_start = minute(local.plus(until));
+        // Too close to the bottom for a run to bank anything.
+        let middle = Celsius((low.0 + high.0) / 2.0);
+        if self.advanced_for == Some(window_start) || temperature <= middle {
+            return PeakShift::Normal;
+        }
+        self.advanced_for = Some(window_start);
+        info!(
+            "Starting early at {}, chilling toward {} {}m ahead of the {} peak",
+            temperature,
+            low,
+            until.as_secs() / 60,
+            window
+        );
+        PeakShift::Advance
+    }
+
+    fn resume(&mut self, now: Instant, why: &str) {
+        if let Some(since) = self.deferring.take() {
+            info!(
+                "Peak deferral over after {}m, {}",
+                now.saturating_duration_since(since).as_secs() / 60,
+                why
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::time_of_day;
+
+    fn at(hhmm: &str) -> LocalTime {
+        LocalTime {
+            day: 20_000,
+            since_midnight: time_of_day(hhmm).unwrap(),
+        }
+    }
+
+    fn shifter() -> PeakShifter {
+        PeakShifter::new(PeakConfig {
+            windows: vec!["07:00-09:00".parse().unwrap(), "17:00-20:00".parse().unwrap()],
+            lead: Duration::from_secs(90 * 60),
+            excursion: DegreesDelta(1.0),
+        })
+    }
+
+    const THRESHOLDS: (Celsius, Celsius) = (Celsius(1.0), Celsius(4.0));
+
+    #[test]
+    fn defers_starts_in_a_peak_until_the_excursion_is_used_up() {
+        let now = Instant::now();
+        let mut peak = shifter();
+        let shift = |peak: &mut PeakShifter, starting, temperature, time| {
+            peak.shift(starting, true, Celsius(temperature), THRESHOLDS, now, at(time))
+        };
+        assert_eq!(PeakShift::Defer, shift(&mut peak, true, 4.2, "17:00"));
+        assert_eq!(PeakShift::Defer, shift(&mut peak, true, 5.0, "18:00"));
+        assert_eq!(PeakShift::Normal, shift(&mut peak, true, 5.1, "18:10"));
+        // Turning off is never shifted.
+        assert_eq!(PeakShift::Normal, shift(&mut peak, false, 1.0, "18:30"));
+        assert_eq!(PeakShift::Defer, shift(&mut peak, true, 4.1, "08:59"));
+        assert_eq!(PeakShift::Normal, shift(&mut peak, true, 4.1, "09:00"));
+        assert_eq!(PeakShift::Normal, shift(&mut peak, true, 4.1, "12:00"));
+    }
+
+    #[test]
+    fn advances_one_start_ahead_of_each_peak() {
+        let now = Instant::now();
+        let mut peak = shifter();
+        let mut shift = |starting, free, temperature, time| {
+            peak.shift(starting, free, Celsius(temperature), THRESHOLDS, now, at(time))
+        };
+        // Too early, then too cold to bother.
+        assert_eq!(PeakShift::Normal, shift(false, true, 3.0, "15:29"));
+        assert_eq!(PeakShift::Normal, shift(false, true, 2.5, "15:30"));
+        // Held off by a minimum interval, or wanted anyway.
+        assert_eq!(PeakShift::Normal, shift(false, false, 3.0, "15:40"));
+        assert_eq!(PeakShift::Normal, shift(true, true, 4.2, "15:45"));
+        assert_eq!(PeakShift::Advance, shift(false, true, 2.6, "15:50"));
+        assert_eq!(PeakShift::Normal, shift(false, true, 3.5, "16:30"));
+        // The morning peak gets one of its own.
+        assert_eq!(PeakShift::Advance, shift(false, true, 3.5, "06:00"));
+        assert_eq!(PeakShift::Normal, shift(false, true, 3.5, "06:30"));
+    }
+
+    #[test]
+    fn no_windows_no_shift() {
+        let mut peak = PeakShifter::new(PeakConfig::default());
+        assert_eq!(
+            PeakShift::Normal,
+            peak.shift(true, true, Celsius(4.2), THRESHOLDS, Instant::now(), at("18:00"))
+        );
+    }
+}
@@ -0,0 +1,382 @@
+//! Property tests for the relay state machine. Each case is a random temperature walk polled at random intervals,
+//! with random minimum intervals, compensated thresholds, ambient trim and early shutoff, run through [`step`] against a fake clock. A failing
+//! case is shrunk to the fewest polls that still fail and printed in full.
+
+use crate::{
+    ambient_trim::AmbientTrimConfig,
+    config::Config,
+    controller::{step, ControllerState, Effect, Input},
+    early_shutoff::TailEstimate,
+    energy::LocalTime,
+    rng::Rng,
+    temperature::{Celsius, DegreesDelta},
+    MinimumIntervals, Seed, State, MAX_COMPENSATION, MINIMUM_ON_DURATION,
+};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+const CASES: u64 = 200;
+const POLLS: usize = 400;
+const MAX_SHRINK_PASSES: usize = 20;
+
+/// The poll a property failed at and why.
+type Property = fn(&Case, &[Decision]) -> Result<(), (usize, String)>;
+
+#[derive(Clone)]
+struct Case {
+    seed: u64,
+    intervals: MinimumIntervals,
+    /// (cooling, heating), within the caps so the thresholds stay ordered.
+    compensation: (DegreesDelta, DegreesDelta),
+    ambient_trim: AmbientTrimConfig,
+    early_shutoff: TailEstimate,
+    /// (time since the previous poll, reading, ambient)
+    polls: Vec<(Duration, Celsius, Option<Celsius>)>,
+}
+
+impl Case {
+    fn generate(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let intervals = MinimumIntervals {
+            on: Duration::from_secs(rng.range(0.0..600.0) as u64),
+            off: Duration::from_secs(rng.range(0.0..1200.0) as u64),
+        };
+        let compensation = (
+            DegreesDelta(rng.range(0.0..MAX_COMPENSATION.0)),
+            DegreesDelta(-rng.range(0.0..MAX_COMPENSATION.0)),
+        );
+        let ambient_trim = AmbientTrimConfig {
+            gain: rng.range(0.0..0.2),
+            reference: Celsius(20.0),
+            limit: DegreesDelta(rng.range(0.0..2.0)),
+        };
+        let early_shutoff = match rng.range(0.0..3.0) as u32 {
+            0 => TailEstimate::Off,
+            1 => TailEstimate::Fixed(DegreesDelta(rng.range(0.0..3.0))),
+            _ => TailEstimate::Learned,
+        };
+        let mut temperature = rng.range(-5.0..15.0);
+        let mut ambient = rng.range(0.0..40.0);
+        let polls = (0..POLLS)
+            .map(|_| {
+                let interval = Duration::from_secs(1 + rng.range(0.0..120.0) as u64);
+                // Mostly a slow walk, now and then a door opening or a glitch.
+                temperature += match rng.chance(0.05) {
+                    true => rng.range(-5.0..5.0),
+                    false => rng.range(-0.3..0.3),
+                };
+                ambient += rng.range(-0.5..0.5);
+                let read = match rng.chance(0.05) {
+                    true => None,
+                    false => Some(Celsius(ambient)),
+                };
+                (interval, Celsius(temperature), read)
+            })
+            .collect();
+        Self {
+            seed,
+            intervals,
+            compensation,
+            ambient_trim,
+            early_shutoff,
+            polls,
+        }
+    }
+}
+
+/// What the controller did with one poll.
+struct Decision {
+    at: Duration,
+    temperature: Celsius,
+    state: State,
+    power_on: bool,
+    thresholds: (Celsius, Celsius),
+    switches: Vec<bool>,
+}
+
+fn run(case: &Case) -> Vec<Decision> {
+    let start = Instant::now();
+    let config = Config {
+        intervals: case.intervals,
+        ambient_trim: case.ambient_trim,
+        early_shutoff: case.early_shutoff,
+        ..Config::default()
+    };
+    let seed = Seed {
+        compensation: case.compensation,
+        ..Seed::new(State::Off)
+    };
+    let mut controller = ControllerState::new(seed, &config, start);
+    let mut at = Duration::from_secs(0);
+    let mut decisions = Vec::new();
+    for &(interval, temperature, ambient) in &case.polls {
+        at += interval;
+        let input = Input {
+            temperature: Ok(temperature),
+            now: start + at,
+            local: LocalTime::default(),
+            ambient,
+            acknowledged: false,
+            reset_runtime: false,
+            sensor_replaced: false,
+            boost: None,
+            revert_timing: false,
+            shed: false,
+            pump: None,
+            current: None,
+            reset_failover: false,
+            reload: None,
+        };
+        let (next, effects) = step(controller, input);
+        controller = next;
+        let switches = effects
+            .iter()
+            .filter_map(|effect| match effect {
+                Effect::SetPower(on) => Some(*on),
+                _ => None,
+            })
+            .collect();
+        decisions.push(Decision {
+            at,
+            temperature,
+            state: controller.state(),
+            power_on: controller.power_on(),
+            thresholds: controller.thresholds(),
+            switches,
+        });
+    }
+    decisions
+}
+
+fn failure(case: &Case, property: Property) -> Option<(usize, String)> {
+    property(case, &run(case)).err()
+}
+
+/// Drops the polls after the failure, then every earlier poll whose removal keeps it failing. A removed poll's
+/// interval moves to the next one so the rest keep their times.
+fn shrink(mut case: Case, property: Property) -> Case {
+    let (at, _) = failure(&case, property).expect("Only failing cases shrink.");
+    case.polls.truncate(at + 1);
+    for _ in 0..MAX_SHRINK_PASSES {
+        let mut shrunk = false;
+        let mut i = 0;
+        while i < case.polls.len() {
+            let mut candidate = case.clone();
+            let (interval, _, _) = candidate.polls.remove(i);
+            if let Some(next) = candidate.polls.get_mut(i) {
+                next.0 += interval;
+            }
+            match failure(&candidate, property) {
+                Some((at, _)) => {
+                    candidate.polls.truncate(at + 1);
+                    case = candidate;
+                    shrunk = true;
+                }
+                None => i += 1,
+            }
+        }
+        if !shrunk {
+            break;
+        }
+    }
+    case
+}
+
+struct Counterexample {
+    case: Case,
+    decisions: Vec<Decision>,
+    failure: (usize, String),
+}
+
+impl Counterexample {
+    fn find(property: Property) -> Option<Self> {
+        let case = (1..=CASES)
+            .map(Case::generate)
+            .find(|case| failure(case, property).is_some())?;
+        let case = shrink(case, property);
+        let decisions = run(&case);
+        let failure = property(&case, &decisions).expect_err("Shrinking keeps the case failing.");
+        Some(Self {
+            case,
+            decisions,
+            failure,
+        })
+    }
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let case = &self.case;
+        writeln!(
+            f,
+            "Seed {} failed at poll {}: {}",
+            case.seed,
+            self.failure.0 + 1,
+            self.failure.1
+        )?;
+        writeln!(
+            f,
+            "Minimum on {}s, minimum off {}s, compensation {} cooling {} heating, ambient trim {} limit {}, early \
+             shutoff {}",
+            case.intervals.on.as_secs(),
+            case.intervals.off.as_secs(),
+            case.compensation.0,
+            case.compensation.1,
+            case.ambient_trim.gain,
+            case.ambient_trim.limit,
+            case.early_shutoff
+        )?;
+        for decision in &self.decisions {
+            write!(
+                f,
+                "{:>6}s {} -> {} relay {}",
+                decision.at.as_secs(),
+                decision.temperature,
+                decision.state,
+                if decision.power_on { "on" } else { "off" }
+            )?;
+            for on in &decision.switches {
+                write!(f, ", switched {}", if *on { "on" } else { "off" })?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn check(property: Property) {
+    if let Some(counterexample) = Counterexample::find(property) {
+        panic!("{}", counterexample);
+    }
+}
+
+/// Each position holds for its minimum interval, per the fake clock. The seeded Off has no known start.
+fn holds_minimum_intervals(case: &Case, decisions: &[Decision]) -> Result<(), (usize, String)> {
+    let mut last_switch: Option<Duration> = None;
+    for (i, decision) in decisions.iter().enumerate() {
+        for &on in &decision.switches {
+            let (held, minimum) = match on {
+                true => ("off", case.intervals.off),
+                false => ("on", case.intervals.on),
+            };
+            if let Some(since) = last_switch.map(|switched| decision.at - switched) {
+                if since < minimum {
+                    let why = format!("{} for {}s, minimum {}s", held, since.as_secs(), minimum.as_secs());
+                    return Err((i, why));
+                }
+            }
+            last_switch = Some(decision.at);
+        }
+    }
+    Ok(())
+}
+
+fn state_matches_relay(_case: &Case, decisions: &[Decision]) -> Result<(), (usize, String)> {
+    match decisions.iter().position(|d| d.state.is_on() != d.power_on) {
+        Some(i) => Err((
+            i,
+            format!(
+                "state {} with the relay on {}",
+                decisions[i].state, decisions[i].power_on
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Compensation and the ambient trim together never put the low threshold above the high one.
+fn thresholds_never_cross(_case: &Case, decisions: &[Decision]) -> Result<(), (usize, String)> {
+    match decisions.iter().position(|d| d.thresholds.0 > d.thresholds.1) {
+        Some(i) => Err((
+            i,
+            format!(
+                "thresholds {} and {}",
+                decisions[i].thresholds.0, decisions[i].thresholds.1
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// A relay on for less than the minimum on duration only turns off below the low threshold, never on a prediction.
+/// The seeded Off has no known start.
+fn early_shutoff_waits_for_minimum_on(_case: &Case, decisions: &[Decision]) -> Result<(), (usize, String)> {
+    let mut on_since: Option<Duration> = None;
+    for (i, decision) in decisions.iter().enumerate() {
+        for &on in &decision.switches {
+            match (on, on_since) {
+                (true, _) => on_since = Some(decision.at),
+                (false, Some(since)) if decision.at - since < MINIMUM_ON_DURATION => {
+                    if decision.temperature >= decision.thresholds.0 {
+                        let why = format!(
+                            "off at {} after {}s on, threshold {}",
+                            decision.temperature,
+                            (decision.at - since).as_secs(),
+                            decision.thresholds.0
+                        );
+                        return Err((i, why));
+                    }
+                }
+                (false, _) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn switches_at_most_once_per_poll(_case: &Case, decisions: &[Decision]) -> Result<(), (usize, String)> {
+    match decisions.iter().position(|d| d.switches.len() > 1) {
+        Some(i) => Err((i, format!("switched {} times", decisions[i].switches.len()))),
+        None => Ok(()),
+    }
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_holds_minimum_intervals() {
+        check(holds_minimum_intervals);
+    }
+
+    #[test]
+    fn state_always_matches_relay() {
+        check(state_matches_relay);
+    }
+
+    #[test]
+    fn thresholds_stay_ordered() {
+        check(thresholds_never_cross);
+    }
+
+    #[test]
+    fn early_shutoff_never_cuts_the_minimum_on() {
+        check(early_shutoff_waits_for_minimum_on);
+    }
+
+    #[test]
+    fn relay_switches_at_most_once_per_poll() {
+        check(switches_at_most_once_per_poll);
+    }
+
+    #[test]
+    fn shrinks_to_the_failing_poll() {
+        fn never_switches_on(_case: &Case, decisions: &[Decision]) -> Result<(), (usize, String)> {
+            match decisions.iter().position(|d| d.switches.contains(&true)) {
+                Some(i) => Err((i, "switched on".into())),
+                None => Ok(()),
+            }
+        }
+        let counterexample = Counterexample::find(never_switches_on).expect("Some walk gets warm enough.");
+        assert_eq!(1, counterexample.case.polls.len());
+        let rendered = counterexample.to_string();
+        assert!(rendered.contains("failed at poll 1: switched on"), "{}", rendered);
+        assert!(
+            rendered.contains("MinimumIntervalOn relay on, switched on"),
+            "{}",
+            rendered
+        );
+    }
+}
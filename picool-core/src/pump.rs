@@ -0,0 +1,262 @@
+use log::*;
+use std::{
+    mem::replace,
+    time::{Duration, Instant},
+};
+
+/// The circulation pump of a glycol chiller, paired with the compressor. It starts a lead ahead of each compressor
+/// start and keeps running an overrun after each stop.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PumpConfig {
+    /// A pump output is wired up, without one nothing is switched or held.
+    pub enabled: bool,
+    /// How long the pump runs before the compressor may start.
+    pub lead: Duration,
+    /// How long the pump keeps running after the compressor stops.
+    pub overrun: Duration,
+}
+
+impl Default for PumpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lead: Duration::from_secs(30),
+            overrun: Duration::from_secs(60 * 2),
+        }
+    }
+}
+
+/// What a compressor start has to wait for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PumpGate {
+    /// No pump, or no start waiting on it.
+    Ready,
+    /// The pump has run its lead and reads back on, the start waited on it.
+    Primed,
+    /// The pump is running its lead.
+    Leading { remaining: Duration },
+    /// The pump output didn't read back on, the compressor isn't energized.
+    Interlocked,
+}
+
+/// A change in whether the pump output reads back as commanded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PumpChange {
+    Failed,
+    Recovered,
+}
+
+pub struct Pump {
+    config: PumpConfig,
+    on: bool,
+    /// When the pump last started or stopped.
+    since: Instant,
+    /// A compressor start is waiting on the pump.
+    pending: bool,
+    /// When the compressor last stopped, the overrun counts from it.
+    stopped: Option<Instant>,
+    /// The output last read back other than commanded.
+    failed: bool,
+    /// The start pending was held for the lead.
+    waited: bool,
+}
+
+impl Pump {
+    pub fn new(config: PumpConfig, now: Instant) -> Self {
+        Self {
+            config,
+            on: false,
+            since: now,
+            pending: false,
+            stopped: None,
+            failed: false,
+            waited: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Checks the output against what was commanded. `read_back` is None when the world can't tell, that counts as
+    /// verified.
+    pub fn verify(&mut self, read_back: Option<bool>) -> Option<PumpChange> {
+        if !self.config.enabled {
+            return None;
+        }
+        let failed = read_back.is_some_and(|on| on != self.on);
+        match (self.failed, failed) {
+            (false, true) => {
+                error!(
+                    "Pump output reads {} while commanded {}",
+                    on_off(!self.on),
+                    on_off(self.on)
+                );
+                self.failed = true;
+                Some(PumpChange::Failed)
+            }
+            (true, false) => {
+                info!("Pump output reads back {} again", on_off(self.on));
+                self.failed = false;
+                Some(PumpChange::Recovered)
+            }
+            _ => None,
+        }
+    }
+
+    // Pure
+    /// Whether a compressor start can go ahead at `now`. A start that is no longer wanted lets the pump go.
+    pub fn gate(&mut self, starting: bool, now: Instant) -> PumpGate {
+        self.pending = starting && self.config.enabled;
+        if !self.pending {
+            self.waited = false;
+            return PumpGate::Ready;
+        }
+        let running = match self.on {
+            true => now.saturating_duration_since(self.since),
+            false => Duration::from_secs(0),
+        };
+        if running < self.config.lead || !self.on {
+            self.waited = true;
+            PumpGate::Leading {
+                remaining: self.config.lead - running,
+            }
+        } else if self.failed {
+            self.waited = true;
+            PumpGate::Interlocked
+        } else if replace(&mut self.waited, false) {
+            PumpGate::Primed
+        } else {
+            PumpGate::Ready
+        }
+    }
+
+    // Pure
+    /// The new position of the pump output when it changes. It runs with the compressor, for a start waiting on it
+    /// and for the overrun after a stop.
+    pub fn update(&mut self, now: Instant, compressor_on: bool) -> Option<bool> {
+        if !self.config.enabled {
+            return None;
+        }
+        if compressor_on {
+            self.stopped = None;
+        } else if self.on && self.stopped.is_none() && !self.pending {
+            self.stopped = Some(now);
+        }
+        let overrunning = self
+            .stopped
+            .is_some_and(|stopped| now.saturating_duration_since(stopped) < self.config.overrun);
+        let on = compressor_on || self.pending || overrunning;
+        if on == self.on {
+            return None;
+        }
+        match on {
+            true if compressor_on => warn!("Pump started with the compressor, no lead"),
+            true => info!("Pump on, leading the compressor by {}s", self.config.lead.as_secs()),
+            false => info!("Pump off after its {}s overrun", self.config.overrun.as_secs()),
+        }
+        self.on = on;
+        self.since = now;
+        if !on {
+            self.stopped = None;
+        }
+        Some(on)
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    match on {
+        true => "on",
+        false => "off",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLL: Duration = Duration::from_secs(10);
+
+    fn config() -> PumpConfig {
+        PumpConfig {
+            enabled: true,
+            ..PumpConfig::default()
+        }
+    }
+
+    #[test]
+    fn leads_the_compressor_and_overruns_it() {
+        let start = Instant::now();
+        let at = |polls: u32| start + POLL * polls;
+        let mut pump = Pump::new(config(), start);
+        assert_eq!(PumpGate::Ready, pump.gate(false, at(0)));
+        assert_eq!(None, pump.update(at(0), false));
+        assert_eq!(
+            PumpGate::Leading {
+                remaining: Duration::from_secs(30)
+            },
+            pump.gate(true, at(1))
+        );
+        assert_eq!(Some(true), pump.update(at(1), false));
+        assert_eq!(None, pump.verify(Some(true)));
+        assert_eq!(
+            PumpGate::Leading {
+                remaining: Duration::from_secs(10)
+            },
+            pump.gate(true, at(3))
+        );
+        assert_eq!(PumpGate::Primed, pump.gate(true, at(4)));
+        assert_eq!(None, pump.update(at(4), true));
+        assert_eq!(PumpGate::Ready, pump.gate(false, at(20)));
+        assert_eq!(None, pump.update(at(20), false));
+        assert_eq!(None, pump.update(at(31), false));
+        assert_eq!(Some(false), pump.update(at(32), false));
+    }
+
+    #[test]
+    fn a_start_no_longer_wanted_lets_the_pump_go() {
+        let start = Instant::now();
+        let mut pump = Pump::new(config(), start);
+        pump.gate(true, start);
+        assert_eq!(Some(true), pump.update(start, false));
+        pump.gate(false, start + POLL);
+        // Overrunning from when the start was dropped.
+        assert_eq!(None, pump.update(start + POLL, false));
+        assert_eq!(Some(false), pump.update(start + POLL * 13, false));
+        // Back within the overrun, a start has no lead to wait for.
+        let mut pump = Pump::new(config(), start);
+        pump.gate(true, start);
+        pump.update(start, false);
+        pump.gate(true, start + POLL * 3);
+        pump.update(start + POLL * 3, true);
+        pump.gate(false, start + POLL * 10);
+        pump.update(start + POLL * 10, false);
+        assert_eq!(PumpGate::Ready, pump.gate(true, start + POLL * 11));
+    }
+
+    #[test]
+    fn interlocks_while_the_output_reads_back_wrong() {
+        let start = Instant::now();
+        let mut pump = Pump::new(config(), start);
+        pump.gate(true, start);
+        pump.update(start, false);
+        assert_eq!(Some(PumpChange::Failed), pump.verify(Some(false)));
+        assert_eq!(None, pump.verify(Some(false)));
+        assert_eq!(PumpGate::Interlocked, pump.gate(true, start + POLL * 3));
+        assert_eq!(Some(PumpChange::Recovered), pump.verify(Some(true)));
+        assert_eq!(PumpGate::Primed, pump.gate(true, start + POLL * 4));
+        // Nothing to read back from.
+        assert_eq!(None, pump.verify(None));
+        let mut disabled = Pump::new(PumpConfig::default(), start);
+        assert_eq!(PumpGate::Ready, disabled.gate(true, start));
+        assert_eq!(None, disabled.update(start, true));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    energy::{DailyWindow, LocalTime},
+    temperature::{Celsius, DegreesDelta},
+};
+use log::*;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct QuietHoursConfig {
+    /// None disables quiet hours.
+    pub window: Option<DailyWindow>,
+    /// How far past the high threshold the chamber may warm before a start goes ahead anyway.
+    pub guard: DegreesDelta,
+}
+
+impl Default for QuietHoursConfig {
+    fn default() -> Self {
+        Self {
+            window: None,
+            guard: DegreesDelta(1.5),
+        }
+    }
+}
+
+/// What quiet hours have held back since the daemon started.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct QuietStats {
+    /// Starts held back, each counted once however long it was held.
+    pub suppressed: u64,
+    /// How long starts were held back for altogether.
+    pub held: Duration,
+    /// The furthest the chamber went past the high threshold while a start was held back.
+    pub excess: Option<DegreesDelta>,
+}
+
+/// Holds off starting the compressor during quiet hours while the chamber is within the guard of the high threshold.
+/// Only ever delays a start, turning off and the safety latches are left alone.
+pub struct QuietHours {
+    config: QuietHoursConfig,
+    stats: QuietStats,
+    /// When the start being held back was first wanted.
+    holding: Option<Instant>,
+}
+
+impl QuietHours {
+    pub fn new(config: QuietHoursConfig) -> Self {
+        Self {
+            config,
+            stats: QuietStats::default(),
+            holding: None,
+        }
+    }
+
+    /// None while disabled.
+    pub fn stats(&self, now: Instant) -> Option<QuietStats> {
+        self.config.window?;
+        let mut stats = self.stats;
+        if let Some(since) = self.holding {
+            stats.held += now.saturating_duration_since(since);
+        }
+        Some(stats)
+    }
+
+    /// The thresholds want the relay on at `temperature` when `starting`. True when the start is held back.
+    pub fn hold(
+        &mut self,
+        starting: bool,
+        temperature: Celsius,
+        high: Celsius,
+        now: Instant,
+        local: LocalTime,
+    ) -> bool {
+        let window = match self.config.window {
+            Some(window) if starting => window,
+            _ => {
+                self.release(now, "no start wanted any more");
+                return false;
+            }
+        };
+        if !window.contains(local) {
+            self.release(now, "quiet hours over");
+            return false;
+        }
+        let excess = temperature - high;
+        if excess > self.config.guard {
+            warn!(
+                "Quiet hours guard of {} exceeded at {}, starting anyway",
+                self.config.guard, temperature
+            );
+            self.release(now, "guard exceeded");
+            return false;
+        }
+        if self.holding.is_none() {
+            self.holding = Some(now);
+            self.stats.suppressed += 1;
+            info!(
+                "Quiet hours {}, holding off a start at {} until above {}, {} held so far",
+                window,
+                temperature,
+                high + self.config.guard,
+                self.stats.suppressed
+            );
+        }
+        if self.stats.excess.is_none_or(|worst| excess > worst) {
+            self.stats.excess = Some(excess);
+        }
+        true
+    }
+
+    fn release(&mut self, now: Instant, why: &str) {
+        if let Some(since) = self.holding.take() {
+            let held = now.saturating_duration_since(since);
+            self.stats.held += held;
+            info!("Quiet hours start held {}m, {}", held.as_secs() / 60, why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::time_of_day;
+
+    fn at(hhmm: &str) -> LocalTime {
+        LocalTime {
+            day: 20_000,
+            since_midnight: time_of_day(hhmm).unwrap(),
+        }
+    }
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    #[test]
+    fn holds_starts_within_the_guard() {
+        let start = Instant::now();
+        let mut quiet = QuietHours::new(QuietHoursConfig {
+            window: Some("23:00-06:00".parse().unwrap()),
+            guard: DegreesDelta(1.5),
+        });
+        let high = Celsius(4.0);
+        assert_eq!(Some(QuietStats::default()), quiet.stats(start));
+        // Not quiet yet.
+        assert!(!quiet.hold(true, Celsius(4.2), high, start, at("22:50")));
+        assert!(quiet.hold(true, Celsius(4.2), high, start + minutes(10), at("23:00")));
+        assert!(quiet.hold(true, Celsius(5.5), high, start + minutes(40), at("23:30")));
+        // Past the guard it runs normally.
+        assert!(!quiet.hold(true, Celsius(5.6), high, start + minutes(50), at("23:40")));
+        assert_eq!(
+            Some(QuietStats {
+                suppressed: 1,
+                held: minutes(40),
+                excess: Some(Celsius(5.5) - high),
+            }),
+            quiet.stats(start + minutes(50))
+        );
+        // Turning off is never held back, nor is a start once quiet hours are over.
+        assert!(!quiet.hold(false, Celsius(2.0), high, start + minutes(60), at("00:00")));
+        assert!(quiet.hold(true, Celsius(4.1), high, start + minutes(300), at("04:00")));
+        // A hold in progress counts up to now.
+        assert_eq!(minutes(40 + 60), quiet.stats(start + minutes(360)).unwrap().held);
+        assert!(!quiet.hold(true, Celsius(4.1), high, start + minutes(420), at("06:00")));
+        let stats = quiet.stats(start + minutes(500)).unwrap();
+        assert_eq!((2, minutes(40 + 120)), (stats.suppressed, stats.held));
+    }
+
+    #[test]
+    fn disabled_holds_nothing() {
+        let mut quiet = QuietHours::new(QuietHoursConfig::default());
+        assert!(!quiet.hold(true, Celsius(4.1), Celsius(4.0), Instant::now(), at("00:00")));
+        assert_eq!(None, quiet.stats(Instant::now()));
+    }
+}
@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RestConfig {
+    /// An on cycle at least this long earns the compressor a rest, None disables the rule.
+    pub after: Option<Duration>,
+    /// How long the relay is then held off, whatever the temperature does.
+    pub rest: Duration,
+    /// Runs with a boost in them are a declared pull down and earn no rest.
+    pub skip_boost: bool,
+}
+
+impl Default for RestConfig {
+    fn default() -> Self {
+        Self {
+            after: None,
+            rest: Duration::from_secs(60 * 30),
+            skip_boost: false,
+        }
+    }
+}
+
+/// A rest in progress, as persisted. Kept against the wall clock so a restart holds what is left of it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RestPeriod {
+    /// The on cycle that earned it.
+    pub run: Duration,
+    pub remaining: Duration,
+}
+
+/// Holds the relay off for a while after an on cycle long enough to have worked the compressor hard, e.g. pulling
+/// down a warm load. Separate from the runaway lockout, nothing is wrong.
+pub struct RestRule {
+    config: RestConfig,
+    /// When the relay last switched on, and whether a boost was seen since, None while off.
+    run: Option<(Instant, bool)>,
+    /// The run that earned the rest in progress, and when the rest ends.
+    resting: Option<(Duration, Instant)>,
+}
+
+impl RestRule {
+    /// A restored rest is only held while the rule is still enabled.
+    pub fn new(config: RestConfig, seed: Option<RestPeriod>, now: Instant) -> Self {
+        Self {
+            config,
+            run: None,
+            resting: seed
+                .filter(|_| config.after.is_some())
+                .map(|rest| (rest.run, now + rest.remaining)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.after.is_some()
+    }
+
+    /// A boost is in effect. Exempts the run in progress, and any rest, when boosts are declared pull downs. True
+    /// when that cut a rest short.
+    pub fn boosting(&mut self) -> bool {
+        if !self.config.skip_boost {
+            return false;
+        }
+        if let Some((_, boosted)) = &mut self.run {
+            *boosted = true;
+        }
+        self.resting.take().is_some()
+    }
+
+    /// The relay switched `on` at `now`. The rest the run that just ended earned, if it did.
+    pub fn switched(&mut self, now: Instant, on: bool) -> Option<RestPeriod> {
+        if on {
+            self.run = Some((now, false));
+            return None;
+        }
+        let (since, boosted) = self.run.take()?;
+        let run = now - since;
+        match self.config.after {
+            Some(after) if run >= after && !boosted => {
+                self.resting = Some((run, now + self.config.rest));
+                Some(RestPeriod {
+                    run,
+                    remaining: self.config.rest,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The rest in progress at `now`.
+    pub fn rest(&self, now: Instant) -> Option<RestPeriod> {
+        let (run, until) = self.resting?;
+        match until > now {
+            true => Some(RestPeriod {
+                run,
+                remaining: until - now,
+            }),
+            false => None,
+        }
+    }
+
+    /// True once, when the rest in progress has run out at `now`.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        match self.resting {
+            Some((_, until)) if now >= until => {
+                self.resting = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    fn config(skip_boost: bool) -> RestConfig {
+        RestConfig {
+            after: Some(minutes(90)),
+            rest: minutes(30),
+            skip_boost,
+        }
+    }
+
+    fn rule(skip_boost: bool) -> RestRule {
+        RestRule::new(config(skip_boost), None, Instant::now())
+    }
+
+    #[test]
+    fn rests_after_a_long_run_until_it_runs_out() {
+        let start = Instant::now();
+        let mut rule = rule(false);
+        rule.switched(start, true);
+        assert_eq!(None, rule.switched(start + minutes(89), false));
+        rule.switched(start + minutes(100), true);
+        let rest = rule.switched(start + minutes(200), false).unwrap();
+        assert_eq!((minutes(100), minutes(30)), (rest.run, rest.remaining));
+        assert_eq!(Some(minutes(10)), rule.rest(start + minutes(220)).map(|r| r.remaining));
+        assert!(!rule.expire(start + minutes(229)));
+        assert!(rule.expire(start + minutes(230)));
+        assert!(!rule.expire(start + minutes(231)));
+        assert_eq!(None, rule.rest(start + minutes(230)));
+    }
+
+    #[test]
+    fn a_boosted_run_earns_no_rest_when_configured() {
+        let start = Instant::now();
+        let mut skipping = rule(true);
+        skipping.switched(start, true);
+        assert!(!skipping.boosting());
+        assert_eq!(None, skipping.switched(start + minutes(120), false));
+        // Nor does a boost that starts during a rest leave it in force.
+        skipping.switched(start + minutes(130), true);
+        assert!(skipping.switched(start + minutes(230), false).is_some());
+        assert!(skipping.boosting());
+        assert_eq!(None, skipping.rest(start + minutes(231)));
+        let mut resting = rule(false);
+        resting.switched(start, true);
+        assert!(!resting.boosting());
+        assert!(resting.switched(start + minutes(120), false).is_some());
+    }
+
+    #[test]
+    fn a_restored_rest_holds_what_is_left() {
+        let start = Instant::now();
+        let seed = RestPeriod {
+            run: minutes(120),
+            remaining: minutes(12),
+        };
+        let rule = RestRule::new(config(false), Some(seed), start);
+        assert_eq!(Some(seed), rule.rest(start));
+        assert_eq!(None, rule.rest(start + minutes(12)));
+        let disabled = RestRule::new(RestConfig::default(), Some(seed), start);
+        assert_eq!(None, disabled.rest(start));
+    }
+}
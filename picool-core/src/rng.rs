@@ -0,0 +1,55 @@
+use std::ops::Range;
+
+const SEED_MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// xorshift64*, small and reproducible from a seed. Nothing here needs to be unpredictable.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // A zero state would only ever produce zero.
+        match seed ^ SEED_MIX {
+            0 => Self(SEED_MIX),
+            state => Self(state),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform in the range.
+    pub fn range(&mut self, range: Range<f32>) -> f32 {
+        range.start + self.next_f32() * (range.end - range.start)
+    }
+
+    pub fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reproducible_and_roughly_uniform() {
+        let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+        let draws: Vec<f32> = (0..10_000).map(|_| a.next_f32()).collect();
+        assert!(draws.iter().all(|&x| x == b.next_f32() && (0.0..1.0).contains(&x)));
+        let mean = draws.iter().sum::<f32>() / draws.len() as f32;
+        assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+        assert_ne!(Rng::new(7).next_u64(), Rng::new(8).next_u64());
+        assert_ne!(0, Rng::new(SEED_MIX).next_u64());
+    }
+}
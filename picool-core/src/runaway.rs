@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RunawayConfig {
+    /// How long the relay may be on before a still-rising temperature is treated as a runaway.
+    pub max_on: Duration,
+    /// Without a retry the lockout is latched until acknowledged over the control socket.
+    pub auto_retry_after: Option<Duration>,
+}
+
+impl Default for RunawayConfig {
+    fn default() -> Self {
+        Self {
+            max_on: Duration::from_secs(60 * 60),
+            auto_retry_after: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RunawayEvidence {
+    pub on_for: Duration,
+    pub slope_per_hour: f32,
+}
+
+/// Detects a compressor that runs without cooling (dead compressor, welded relay, probe out of the chamber).
+pub struct RunawayDetector {
+    config: RunawayConfig,
+    on_since: Option<Instant>,
+    tripped_at: Option<Instant>,
+}
+
+impl RunawayDetector {
+    pub fn new(config: RunawayConfig) -> Self {
+        Self {
+            config,
+            on_since: None,
+            tripped_at: None,
+        }
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped_at.is_some()
+    }
+
+    /// Returns the evidence the moment a runaway is detected.
+    pub fn update(&mut self, now: Instant, power_on: bool, slope_per_hour: Option<f32>) -> Option<RunawayEvidence> {
+        if !power_on {
+            self.on_since = None;
+            return None;
+        }
+        let on_for = now - *self.on_since.get_or_insert(now);
+        match slope_per_hour {
+            Some(slope_per_hour) if !self.is_tripped() && on_for >= self.config.max_on && slope_per_hour > 0.0 => {
+                self.tripped_at = Some(now);
+                self.on_since = None;
+                Some(RunawayEvidence { on_for, slope_per_hour })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn retry_due(&self, now: Instant) -> bool {
+        match (self.tripped_at, self.config.auto_retry_after) {
+            (Some(tripped_at), Some(after)) => now - tripped_at >= after,
+            _ => false,
+        }
+    }
+
+    /// Clears the lockout, returning how long it lasted.
+    pub fn clear(&mut self, now: Instant) -> Option<Duration> {
+        self.tripped_at.take().map(|tripped_at| now - tripped_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    #[test]
+    fn rising_temperature_trips_after_max_on() {
+        let start = Instant::now();
+        let mut detector = RunawayDetector::new(RunawayConfig::default());
+        assert_eq!(None, detector.update(start, true, Some(1.0)));
+        assert_eq!(None, detector.update(start + minutes(59), true, Some(1.0)));
+        assert_eq!(
+            Some(RunawayEvidence {
+                on_for: minutes(60),
+                slope_per_hour: 1.0
+            }),
+            detector.update(start + minutes(60), true, Some(1.0))
+        );
+        assert!(detector.is_tripped());
+        assert_eq!(None, detector.update(start + minutes(61), true, Some(1.0)));
+    }
+
+    #[test]
+    fn slow_but_working_fridge_never_trips() {
+        let start = Instant::now();
+        let mut detector = RunawayDetector::new(RunawayConfig::default());
+        for minute in 0..600 {
+            assert_eq!(None, detector.update(start + minutes(minute), true, Some(-0.2)));
+        }
+        assert_eq!(None, detector.update(start + minutes(600), true, None));
+    }
+
+    #[test]
+    fn off_resets_on_time() {
+        let start = Instant::now();
+        let mut detector = RunawayDetector::new(RunawayConfig::default());
+        detector.update(start, true, Some(1.0));
+        detector.update(start + minutes(50), false, Some(1.0));
+        assert_eq!(None, detector.update(start + minutes(51), true, Some(1.0)));
+        assert_eq!(None, detector.update(start + minutes(100), true, Some(1.0)));
+        assert!(detector.update(start + minutes(111), true, Some(1.0)).is_some());
+    }
+
+    #[test]
+    fn latched_without_auto_retry() {
+        let start = Instant::now();
+        let mut detector = RunawayDetector::new(RunawayConfig::default());
+        detector.update(start, true, Some(1.0));
+        detector.update(start + minutes(60), true, Some(1.0));
+        assert!(!detector.retry_due(start + minutes(60 * 24)));
+        assert_eq!(Some(minutes(30)), detector.clear(start + minutes(90)));
+        assert!(!detector.is_tripped());
+    }
+
+    #[test]
+    fn auto_retry_after_cool_down() {
+        let start = Instant::now();
+        let mut detector = RunawayDetector::new(RunawayConfig {
+            auto_retry_after: Some(minutes(120)),
+            ..RunawayConfig::default()
+        });
+        detector.update(start, true, Some(1.0));
+        detector.update(start + minutes(60), true, Some(1.0));
+        assert!(!detector.retry_due(start + minutes(179)));
+        assert!(detector.retry_due(start + minutes(180)));
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+/// How much compressor runtime a crash can lose.
+pub const RUNTIME_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60 * 15);
+
+/// A lifetime odometer of the time the relay has spent on, carried across runs.
+pub struct RuntimeCounter {
+    /// Completed on intervals, including every previous run.
+    total: Duration,
+    on_since: Option<Instant>,
+    last_checkpoint: Instant,
+}
+
+impl RuntimeCounter {
+    pub fn new(restored: Duration, now: Instant, power_on: bool) -> Self {
+        Self {
+            total: restored,
+            on_since: match power_on {
+                true => Some(now),
+                false => None,
+            },
+            last_checkpoint: now,
+        }
+    }
+
+    /// Including the on interval in progress.
+    pub fn total(&self, now: Instant) -> Duration {
+        match self.on_since {
+            Some(since) => self.total + now.saturating_duration_since(since),
+            None => self.total,
+        }
+    }
+
+    /// The relay moved to `power_on`.
+    pub fn record(&mut self, now: Instant, power_on: bool) {
+        match (self.on_since, power_on) {
+            (None, true) => {
+                self.on_since = Some(now);
+                self.last_checkpoint = now;
+            }
+            (Some(_), false) => {
+                self.total = self.total(now);
+                self.on_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// True once per checkpoint interval into an on interval, nothing accumulates while the relay is off.
+    pub fn checkpoint_due(&mut self, now: Instant) -> bool {
+        if self.on_since.is_none() || now.saturating_duration_since(self.last_checkpoint) < RUNTIME_CHECKPOINT_INTERVAL
+        {
+            return false;
+        }
+        self.last_checkpoint = now;
+        true
+    }
+
+    /// Back to zero, an on interval in progress counts from `now`. The total before the reset.
+    pub fn reset(&mut self, now: Instant) -> Duration {
+        let previous = self.total(now);
+        self.total = Duration::from_secs(0);
+        if self.on_since.is_some() {
+            self.on_since = Some(now);
+        }
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    #[test]
+    fn accumulates_on_intervals() {
+        let start = Instant::now();
+        let mut counter = RuntimeCounter::new(minutes(600), start, false);
+        counter.record(start + minutes(5), true);
+        assert_eq!(minutes(603), counter.total(start + minutes(8)));
+        counter.record(start + minutes(10), false);
+        counter.record(start + minutes(20), false);
+        assert_eq!(minutes(605), counter.total(start + minutes(30)));
+        counter.record(start + minutes(30), true);
+        counter.record(start + minutes(31), true);
+        counter.record(start + minutes(35), false);
+        assert_eq!(minutes(610), counter.total(start + minutes(60)));
+    }
+
+    #[test]
+    fn left_on_counts_from_start() {
+        let start = Instant::now();
+        let counter = RuntimeCounter::new(minutes(1), start, true);
+        assert_eq!(minutes(3), counter.total(start + minutes(2)));
+    }
+
+    #[test]
+    fn checkpoints_only_while_on() {
+        let start = Instant::now();
+        let mut counter = RuntimeCounter::new(Duration::from_secs(0), start, false);
+        assert!(!counter.checkpoint_due(start + minutes(20)));
+        counter.record(start + minutes(20), true);
+        assert!(!counter.checkpoint_due(start + minutes(34)));
+        assert!(counter.checkpoint_due(start + minutes(35)));
+        assert!(!counter.checkpoint_due(start + minutes(40)));
+        assert!(counter.checkpoint_due(start + minutes(50)));
+    }
+
+    #[test]
+    fn reset_restarts_from_zero() {
+        let start = Instant::now();
+        let mut counter = RuntimeCounter::new(minutes(600), start, true);
+        assert_eq!(minutes(610), counter.reset(start + minutes(10)));
+        assert_eq!(minutes(5), counter.total(start + minutes(15)));
+        counter.record(start + minutes(15), false);
+        assert_eq!(minutes(5), counter.reset(start + minutes(20)));
+        assert_eq!(Duration::from_secs(0), counter.total(start + minutes(30)));
+    }
+}
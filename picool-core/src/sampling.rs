@@ -0,0 +1,175 @@
+use crate::temperature::Celsius;
+use anyhow::{bail, Result};
+use std::{str::FromStr, time::Duration};
+
+/// How the samples since the last decision become the temperature it's made on.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Aggregate {
+    #[default]
+    Latest,
+    Mean,
+    /// Rides out a single spike.
+    Median,
+}
+
+impl FromStr for Aggregate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "latest" => Ok(Aggregate::Latest),
+            "mean" => Ok(Aggregate::Mean),
+            "median" => Ok(Aggregate::Median),
+            _ => bail!("Unknown aggregate '{}', expected latest, mean or median.", s),
+        }
+    }
+}
+
+/// The sensor is read every `interval`, the relay decided on every `decision_interval`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SamplingConfig {
+    pub interval: Duration,
+    /// None decides on every sample.
+    pub decision_interval: Option<Duration>,
+    pub aggregate: Aggregate,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            decision_interval: None,
+            aggregate: Aggregate::Latest,
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.interval.is_zero() {
+            bail!("The sample interval must be positive.");
+        }
+        match self.decision_interval {
+            Some(decision) if decision.is_zero() || decision.as_millis() % self.interval.as_millis() != 0 => bail!(
+                "The decision interval must be a multiple of the sample interval, got {}s and {}s.",
+                decision.as_secs_f32(),
+                self.interval.as_secs_f32()
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Samples per decision.
+    pub fn decide_every(&self) -> usize {
+        match self.decision_interval {
+            Some(decision) => (decision.as_millis() / self.interval.as_millis()).max(1) as usize,
+            None => 1,
+        }
+    }
+}
+
+/// Collects samples until a decision is due. Decisions fall on sample counts, the minimum intervals they're
+/// checked against are still measured on the clock.
+pub struct Sampler {
+    decide_every: usize,
+    aggregate: Aggregate,
+    samples: Vec<Celsius>,
+    /// Decide on the next sample, at startup and after an outage.
+    due: bool,
+}
+
+impl Sampler {
+    pub fn new(config: SamplingConfig) -> Self {
+        Self {
+            decide_every: config.decide_every(),
+            aggregate: config.aggregate,
+            samples: Vec::with_capacity(config.decide_every()),
+            due: true,
+        }
+    }
+
+    /// The temperature to decide on when `sample` completes a decision interval.
+    pub fn push(&mut self, sample: Celsius) -> Option<Celsius> {
+        self.samples.push(sample);
+        if !self.due && self.samples.len() < self.decide_every {
+            return None;
+        }
+        self.due = false;
+        let decided = aggregate(self.aggregate, &self.samples);
+        self.samples.clear();
+        Some(decided)
+    }
+
+    /// Drops what was collected and decides on the next sample, the samples before are stale.
+    pub fn restart(&mut self) {
+        self.samples.clear();
+        self.due = true;
+    }
+}
+
+// Pure
+fn aggregate(aggregate: Aggregate, samples: &[Celsius]) -> Celsius {
+    match aggregate {
+        Aggregate::Latest => samples[samples.len() - 1],
+        Aggregate::Mean => Celsius(samples.iter().map(|s| s.0).sum::<f32>() / samples.len() as f32),
+        Aggregate::Median => {
+            let mut sorted = samples.to_vec();
+            sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let middle = sorted.len() / 2;
+            match sorted.len() % 2 {
+                0 => Celsius((sorted[middle - 1].0 + sorted[middle].0) / 2.0),
+                _ => sorted[middle],
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(interval: u64, decision: Option<u64>, aggregate: Aggregate) -> SamplingConfig {
+        SamplingConfig {
+            interval: Duration::from_secs(interval),
+            decision_interval: decision.map(Duration::from_secs),
+            aggregate,
+        }
+    }
+
+    #[test]
+    fn aggregates_the_samples_since_the_last_decision() {
+        let samples = [Celsius(4.0), Celsius(9.0), Celsius(5.0), Celsius(6.0)];
+        assert_eq!(Celsius(6.0), aggregate(Aggregate::Latest, &samples));
+        assert_eq!(Celsius(6.0), aggregate(Aggregate::Mean, &samples));
+        assert_eq!(Celsius(5.5), aggregate(Aggregate::Median, &samples));
+        assert_eq!(Celsius(5.0), aggregate(Aggregate::Median, &samples[..3]));
+
+        let mut sampler = Sampler::new(config(5, Some(15), Aggregate::Median));
+        // The first sample decides at once.
+        assert_eq!(Some(Celsius(3.0)), sampler.push(Celsius(3.0)));
+        assert_eq!(None, sampler.push(Celsius(4.0)));
+        assert_eq!(None, sampler.push(Celsius(20.0)));
+        assert_eq!(Some(Celsius(5.0)), sampler.push(Celsius(5.0)));
+        assert_eq!(None, sampler.push(Celsius(6.0)));
+        sampler.restart();
+        assert_eq!(Some(Celsius(7.0)), sampler.push(Celsius(7.0)));
+    }
+
+    #[test]
+    fn decides_on_every_sample_by_default() {
+        let mut sampler = Sampler::new(SamplingConfig::default());
+        assert_eq!(Some(Celsius(3.0)), sampler.push(Celsius(3.0)));
+        assert_eq!(Some(Celsius(4.0)), sampler.push(Celsius(4.0)));
+    }
+
+    #[test]
+    fn decision_interval_is_a_multiple_of_the_sample_interval() {
+        assert!(config(5, Some(60), Aggregate::Mean).validate().is_ok());
+        assert_eq!(12, config(5, Some(60), Aggregate::Mean).decide_every());
+        assert!(config(5, Some(12), Aggregate::Mean).validate().is_err());
+        assert!(config(5, Some(0), Aggregate::Mean).validate().is_err());
+        assert!(config(0, None, Aggregate::Mean).validate().is_err());
+        assert_eq!(Aggregate::Median, "median".parse().unwrap());
+        assert!("mode".parse::<Aggregate>().is_err());
+    }
+}
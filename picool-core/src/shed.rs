@@ -0,0 +1,242 @@
+use crate::temperature::{Celsius, DegreesDelta};
+use log::*;
+use std::{
+    mem::replace,
+    time::{Duration, Instant},
+};
+use strum_macros::Display;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ShedConfig {
+    /// The longest one assertion of the signal holds off the compressor for.
+    pub max_duration: Duration,
+    /// How far past the high threshold the chamber may warm before control resumes regardless.
+    pub excursion: DegreesDelta,
+    /// A cycle already running is ended once it has had its minimum on time, rather than left to finish.
+    pub terminate: bool,
+}
+
+impl Default for ShedConfig {
+    fn default() -> Self {
+        Self {
+            max_duration: Duration::from_secs(2 * 60 * 60),
+            excursion: DegreesDelta(2.0),
+            terminate: false,
+        }
+    }
+}
+
+/// A shed in progress, as persisted. Kept against the wall clock so a restart holds what is left of it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ShedPeriod {
+    /// How long it has been shedding.
+    pub since: Duration,
+    /// Zero once it timed out or hit the excursion with the signal still asserted, that assertion is spent.
+    pub remaining: Duration,
+}
+
+/// Why a shed ended.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display)]
+pub enum ShedEnd {
+    #[strum(serialize = "signal released")]
+    Released,
+    #[strum(serialize = "maximum duration reached")]
+    TimedOut,
+    #[strum(serialize = "excursion exceeded")]
+    Excursion,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShedChange {
+    Started {
+        max_duration: Duration,
+    },
+    Ended {
+        lasted: Duration,
+        end: ShedEnd,
+    },
+    /// The signal dropped after its shed was spent, the next assertion sheds again.
+    Rearmed,
+}
+
+/// Holds off compressor starts while an external demand response signal is asserted, for at most the maximum
+/// duration and only while the chamber stays within the excursion of the high threshold.
+pub struct LoadShed {
+    config: ShedConfig,
+    /// When the shed in progress started and when it runs out, None while not shedding.
+    shedding: Option<(Instant, Instant)>,
+    /// The signal as last seen, it takes a fresh assertion to shed.
+    asserted: bool,
+}
+
+impl LoadShed {
+    /// A restored shed has the signal asserted until the first reading says otherwise.
+    pub fn new(config: ShedConfig, seed: Option<ShedPeriod>, now: Instant) -> Self {
+        Self {
+            config,
+            shedding: seed
+                .filter(|shed| !shed.remaining.is_zero())
+                .map(|shed| (now.checked_sub(shed.since).unwrap_or(now), now + shed.remaining)),
+            asserted: seed.is_some(),
+        }
+    }
+
+    pub fn terminates(&self) -> bool {
+        self.config.terminate
+    }
+
+    /// The shed in progress at `now`.
+    pub fn shed(&self, now: Instant) -> Option<ShedPeriod> {
+        let (since, until) = self.shedding?;
+        Some(ShedPeriod {
+            since: now.saturating_duration_since(since),
+            remaining: until.saturating_duration_since(now),
+        })
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.is_some()
+    }
+
+    /// The signal is `asserted` at `now` with the chamber at `temperature`.
+    pub fn update(&mut self, asserted: bool, temperature: Celsius, high: Celsius, now: Instant) -> Option<ShedChange> {
+        let was = replace(&mut self.asserted, asserted);
+        let excess = temperature - high;
+        let (since, until) = match self.shedding {
+            Some(shedding) => shedding,
+            None if asserted && !was && excess > self.config.excursion => {
+                warn!(
+                    "Shed signal asserted at {}, already past the excursion, not shedding",
+                    temperature
+                );
+                return None;
+            }
+            None if asserted && !was => {
+                self.shedding = Some((now, now + self.config.max_duration));
+                return Some(ShedChange::Started {
+                    max_duration: self.config.max_duration,
+                });
+            }
+            None if !asserted && was => return Some(ShedChange::Rearmed),
+            None => return None,
+        };
+        let end = if !asserted {
+            ShedEnd::Released
+        } else if now >= until {
+            ShedEnd::TimedOut
+        } else if excess > self.config.excursion {
+            ShedEnd::Excursion
+        } else {
+            return None;
+        };
+        self.shedding = None;
+        Some(ShedChange::Ended {
+            lasted: now.saturating_duration_since(since),
+            end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    const HIGH: Celsius = Celsius(4.0);
+
+    #[test]
+    fn sheds_while_asserted_up_to_the_maximum() {
+        let start = Instant::now();
+        let mut shed = LoadShed::new(ShedConfig::default(), None, start);
+        let at = |m| start + minutes(m);
+        assert_eq!(None, shed.update(false, Celsius(3.0), HIGH, at(0)));
+        assert_eq!(
+            Some(ShedChange::Started {
+                max_duration: minutes(120)
+            }),
+            shed.update(true, Celsius(3.0), HIGH, at(1))
+        );
+        assert_eq!(None, shed.update(true, Celsius(5.0), HIGH, at(30)));
+        assert_eq!(
+            Some(ShedPeriod {
+                since: minutes(29),
+                remaining: minutes(91)
+            }),
+            shed.shed(at(30))
+        );
+        assert_eq!(
+            Some(ShedChange::Ended {
+                lasted: minutes(120),
+                end: ShedEnd::TimedOut
+            }),
+            shed.update(true, Celsius(5.0), HIGH, at(121))
+        );
+        // Spent until the signal drops.
+        assert_eq!(None, shed.update(true, Celsius(5.0), HIGH, at(130)));
+        assert!(!shed.is_shedding());
+        assert_eq!(
+            Some(ShedChange::Rearmed),
+            shed.update(false, Celsius(5.0), HIGH, at(140))
+        );
+        assert!(matches!(
+            shed.update(true, Celsius(5.0), HIGH, at(150)),
+            Some(ShedChange::Started { .. })
+        ));
+        assert_eq!(
+            Some(ShedChange::Ended {
+                lasted: minutes(5),
+                end: ShedEnd::Released
+            }),
+            shed.update(false, Celsius(5.0), HIGH, at(155))
+        );
+    }
+
+    #[test]
+    fn the_excursion_ends_a_shed() {
+        let start = Instant::now();
+        let mut shed = LoadShed::new(ShedConfig::default(), None, start);
+        assert!(shed.update(true, Celsius(5.0), HIGH, start).is_some());
+        assert_eq!(None, shed.update(true, Celsius(6.0), HIGH, start + minutes(10)));
+        assert_eq!(
+            Some(ShedChange::Ended {
+                lasted: minutes(20),
+                end: ShedEnd::Excursion
+            }),
+            shed.update(true, Celsius(6.1), HIGH, start + minutes(20))
+        );
+        // Already too warm to start one.
+        let mut shed = LoadShed::new(ShedConfig::default(), None, start);
+        assert_eq!(None, shed.update(true, Celsius(6.5), HIGH, start));
+        assert!(!shed.is_shedding());
+    }
+
+    #[test]
+    fn a_restored_shed_carries_on() {
+        let start = Instant::now() + minutes(60);
+        let restored = ShedPeriod {
+            since: minutes(30),
+            remaining: minutes(90),
+        };
+        let mut shed = LoadShed::new(ShedConfig::default(), Some(restored), start);
+        assert_eq!(Some(restored), shed.shed(start));
+        assert_eq!(None, shed.update(true, Celsius(3.0), HIGH, start));
+        assert_eq!(
+            Some(ShedChange::Ended {
+                lasted: minutes(40),
+                end: ShedEnd::Released
+            }),
+            shed.update(false, Celsius(3.0), HIGH, start + minutes(10))
+        );
+        // A spent one doesn't shed again on the same assertion.
+        let spent = ShedPeriod {
+            since: minutes(120),
+            remaining: Duration::from_secs(0),
+        };
+        let mut shed = LoadShed::new(ShedConfig::default(), Some(spent), start);
+        assert_eq!(None, shed.update(true, Celsius(3.0), HIGH, start));
+        assert!(!shed.is_shedding());
+    }
+}
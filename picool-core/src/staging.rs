@@ -0,0 +1,291 @@
+use crate::{
+    runtime::RuntimeCounter,
+    temperature::{Celsius, DegreesDelta},
+    MinimumIntervals,
+};
+use anyhow::{bail, Result};
+use log::*;
+use std::time::{Duration, Instant};
+
+/// The optional second compressor, staged in behind the first when one isn't keeping up.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StagingConfig {
+    /// A stage 2 output is wired up, without one nothing is switched.
+    pub enabled: bool,
+    /// How far past the high threshold the chamber has to warm for stage 2 to join.
+    pub offset: DegreesDelta,
+    /// Stage 2 also joins once stage 1 has run this long without getting the chamber under the high threshold, None
+    /// leaves it to the offset.
+    pub after: Option<Duration>,
+    /// How far under the high threshold stage 2 drops out, ahead of stage 1 turning off at the low threshold.
+    pub release: DegreesDelta,
+    /// Stage 2's own compressor protection.
+    pub intervals: MinimumIntervals,
+}
+
+impl Default for StagingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            offset: DegreesDelta(2.0),
+            after: Some(Duration::from_secs(60 * 60)),
+            release: DegreesDelta(1.0),
+            intervals: MinimumIntervals::default(),
+        }
+    }
+}
+
+impl StagingConfig {
+    /// Stage 2 has to drop out within a band `width` wide.
+    pub fn validate(&self, width: DegreesDelta) -> Result<()> {
+        if self.offset.is_nan() || self.offset.0 < 0.0 {
+            bail!("The stage 2 offset can't be negative, got {}.", self.offset.0);
+        }
+        if self.release.is_nan() || self.release.0 < 0.0 || self.release >= width {
+            bail!(
+                "Stage 2 has to drop out within the {} band ahead of stage 1, got a release of {}.",
+                width,
+                self.release
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Why stage 2 joined.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Join {
+    Offset,
+    Runtime,
+}
+
+/// The stage 2 output. Stage 1 follows the state machine, stage 2 only ever runs alongside it and keeps its own
+/// minimum intervals, runtime and starts.
+pub struct SecondStage {
+    config: StagingConfig,
+    on: bool,
+    /// When stage 2 last started or stopped. The minimum off interval counts from the start of the run, the relay
+    /// may have been switched moments before.
+    since: Instant,
+    /// When stage 1 last started, None while it is off.
+    stage1_since: Option<Instant>,
+    runtime: RuntimeCounter,
+    starts: u32,
+}
+
+impl SecondStage {
+    pub fn new(config: StagingConfig, now: Instant) -> Self {
+        Self {
+            config,
+            on: false,
+            since: now,
+            stage1_since: None,
+            runtime: RuntimeCounter::new(Duration::from_secs(0), now, false),
+            starts: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    /// Stage 2 runtime since this run began, up to `now`.
+    pub fn runtime(&self, now: Instant) -> Duration {
+        self.runtime.total(now)
+    }
+
+    /// Stage 2 starts since this run began.
+    pub fn starts(&self) -> u32 {
+        self.starts
+    }
+
+    // Pure
+    /// The new position of the stage 2 output when it changes. `temperature` is None when the sensor failed, stage 2
+    /// drops out without a reading to go on.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        stage1_on: bool,
+        temperature: Option<Celsius>,
+        high: Celsius,
+    ) -> Option<bool> {
+        if !self.config.enabled {
+            return None;
+        }
+        self.stage1_since = match (self.stage1_since, stage1_on) {
+            (None, true) => Some(now),
+            (_, false) => None,
+            (since, true) => since,
+        };
+        let join = match (self.stage1_since, temperature) {
+            (Some(since), Some(temperature)) => {
+                if temperature - high > self.config.offset {
+                    Some(Join::Offset)
+                } else if temperature > high
+                    && self
+                        .config
+                        .after
+                        .is_some_and(|after| now.saturating_duration_since(since) >= after)
+                {
+                    Some(Join::Runtime)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let on = match (self.on, self.stage1_since, temperature) {
+            (false, _, _) => join.is_some(),
+            (true, Some(_), Some(temperature)) => high - temperature < self.config.release,
+            (true, _, _) => false,
+        };
+        if on == self.on {
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(self.since);
+        let interval = match on {
+            true => self.config.intervals.off,
+            false => self.config.intervals.on,
+        };
+        if elapsed < interval {
+            return None;
+        }
+        match (on, join, temperature) {
+            (true, Some(Join::Offset), Some(temperature)) => {
+                info!(
+                    "Stage 2 joining at {}, past the high threshold {} by more than {}",
+                    temperature, high, self.config.offset
+                )
+            }
+            (true, _, _) => info!(
+                "Stage 2 joining, stage 1 running {}m without reaching the band",
+                self.stage1_since
+                    .map_or(0, |since| now.saturating_duration_since(since).as_secs() / 60)
+            ),
+            (false, _, Some(temperature)) => info!(
+                "Stage 2 dropping out at {} after {}m",
+                temperature,
+                elapsed.as_secs() / 60
+            ),
+            (false, _, None) => info!(
+                "Stage 2 dropping out without a reading after {}m",
+                elapsed.as_secs() / 60
+            ),
+        }
+        if on {
+            self.starts += 1;
+        }
+        self.on = on;
+        self.since = now;
+        self.runtime.record(now, on);
+        Some(on)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HIGH: Celsius = Celsius(4.0);
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    fn config() -> StagingConfig {
+        StagingConfig {
+            enabled: true,
+            ..StagingConfig::default()
+        }
+    }
+
+    /// The stage 2 switches over (minute, stage 1, temperature) readings.
+    fn run(config: StagingConfig, readings: &[(u64, bool, Option<f32>)]) -> (SecondStage, Vec<(u64, bool)>) {
+        let start = Instant::now();
+        let mut stage = SecondStage::new(config, start);
+        let mut switches = vec![];
+        for &(minute, stage1_on, temperature) in readings {
+            if let Some(on) = stage.update(start + minutes(minute), stage1_on, temperature.map(Celsius), HIGH) {
+                switches.push((minute, on));
+            }
+        }
+        (stage, switches)
+    }
+
+    #[test]
+    fn joins_past_the_offset_and_drops_out_first() {
+        let (stage, switches) = run(
+            config(),
+            &[
+                (10, true, Some(6.5)),
+                (11, true, Some(5.5)),
+                (12, true, Some(4.5)),
+                (13, true, Some(3.2)),
+                (14, true, Some(2.9)),
+                // Below the offset, stage 1 alone.
+                (40, true, Some(5.9)),
+                (41, false, Some(1.0)),
+            ],
+        );
+        assert_eq!(vec![(10, true), (14, false)], switches);
+        assert_eq!(1, stage.starts());
+        assert_eq!(minutes(4), stage.runtime(Instant::now() + minutes(60)));
+    }
+
+    #[test]
+    fn joins_a_long_run_that_is_not_reaching_the_band() {
+        let (_, switches) = run(
+            config(),
+            &[
+                (0, true, Some(5.0)),
+                (59, true, Some(4.5)),
+                (60, true, Some(4.5)),
+                (70, true, Some(2.5)),
+                // A run that gets under the high threshold in time goes on alone.
+                (80, false, Some(1.0)),
+                (90, true, Some(4.2)),
+                (120, true, Some(3.9)),
+                (150, true, Some(3.0)),
+            ],
+        );
+        assert_eq!(vec![(60, true), (70, false)], switches);
+    }
+
+    #[test]
+    fn keeps_its_own_minimum_intervals() {
+        let (_, switches) = run(
+            config(),
+            &[
+                // Within the minimum off interval from the start.
+                (5, true, Some(7.0)),
+                (8, true, Some(7.0)),
+                // Stage 1 stopping takes stage 2 with it, after its minimum on time.
+                (9, false, Some(7.0)),
+                (10, false, Some(7.0)),
+                (12, true, Some(7.0)),
+                (18, true, Some(7.0)),
+                (20, true, None),
+            ],
+        );
+        assert_eq!(vec![(8, true), (10, false), (18, true), (20, false)], switches);
+    }
+
+    #[test]
+    fn validates_the_release_within_the_band() {
+        assert!(config().validate(DegreesDelta(3.7)).is_ok());
+        let wide = StagingConfig {
+            release: DegreesDelta(4.0),
+            ..config()
+        };
+        assert!(wide.validate(DegreesDelta(3.7)).is_err());
+        let negative = StagingConfig {
+            offset: DegreesDelta(-1.0),
+            ..config()
+        };
+        assert!(negative.validate(DegreesDelta(3.7)).is_err());
+    }
+}
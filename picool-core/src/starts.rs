@@ -0,0 +1,217 @@
+use crate::energy::LocalTime;
+use std::fmt;
+
+/// Days of per-day start counts kept.
+pub const START_HISTORY_DAYS: usize = 90;
+const TREND_WEEK_DAYS: usize = 7;
+const TREND_MONTH_DAYS: usize = 28;
+/// How much of the week and of the month before it has to be known for a trend.
+const TREND_MIN_WEEK_DAYS: usize = 4;
+const TREND_MIN_MONTH_DAYS: usize = 14;
+/// Starts per day over the month's average that make an increase significant, both have to be exceeded.
+const SIGNIFICANT_RATIO: f32 = 1.5;
+const SIGNIFICANT_STARTS: f32 = 3.0;
+
+/// Compressor starts, what gets persisted. `today` is relative to `day`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StartCounts {
+    pub lifetime: u64,
+    pub day: i64,
+    pub today: u32,
+    /// The days before `day`, oldest first. None for a day the controller wasn't running.
+    pub history: Vec<Option<u32>>,
+}
+
+impl fmt::Display for StartCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} starts today, {} lifetime", self.today, self.lifetime)
+    }
+}
+
+/// Average starts per day over the last week against the month before it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StartTrend {
+    pub week: f32,
+    pub month: f32,
+}
+
+impl StartTrend {
+    /// Usually a failing door seal or a thermostat band that's too tight.
+    pub fn is_significant_increase(&self) -> bool {
+        self.week >= self.month * SIGNIFICANT_RATIO && self.week - self.month >= SIGNIFICANT_STARTS
+    }
+}
+
+/// Starts in a day that just ended.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DailyStarts {
+    pub starts: u32,
+    pub trend: Option<StartTrend>,
+}
+
+/// Counts relay off to on transitions, bucketed into local days.
+pub struct StartCounter {
+    counts: StartCounts,
+}
+
+impl StartCounter {
+    pub fn new(mut restored: StartCounts) -> Self {
+        let excess = restored.history.len().saturating_sub(START_HISTORY_DAYS);
+        restored.history.drain(..excess);
+        Self { counts: restored }
+    }
+
+    pub fn counts(&self) -> &StartCounts {
+        &self.counts
+    }
+
+    pub fn record_start(&mut self) {
+        self.counts.today += 1;
+        self.counts.lifetime += 1;
+    }
+
+    /// Moves on to the day of `local`, the previous day's starts if it was yesterday. Days in between are recorded as
+    /// missing.
+    pub fn update(&mut self, local: LocalTime) -> Option<DailyStarts> {
+        let counts = &mut self.counts;
+        if local.day <= counts.day {
+            return None;
+        }
+        // Day 0 is 1970, only counts that were never persisted start from it.
+        if counts.day == 0 && counts.lifetime == 0 && counts.history.is_empty() {
+            counts.day = local.day;
+            return None;
+        }
+        let ended = counts.today;
+        let missing = (local.day - counts.day - 1).min(START_HISTORY_DAYS as i64) as usize;
+        counts.history.push(Some(ended));
+        counts.history.extend(std::iter::repeat_n(None, missing));
+        let excess = counts.history.len().saturating_sub(START_HISTORY_DAYS);
+        counts.history.drain(..excess);
+        counts.today = 0;
+        counts.day = local.day;
+        match missing {
+            0 => Some(DailyStarts {
+                starts: ended,
+                trend: trend(&counts.history),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Pure
+fn trend(history: &[Option<u32>]) -> Option<StartTrend> {
+    let week_start = history.len().saturating_sub(TREND_WEEK_DAYS);
+    let month_start = week_start.saturating_sub(TREND_MONTH_DAYS);
+    let week = average(&history[week_start..], TREND_MIN_WEEK_DAYS)?;
+    let month = average(&history[month_start..week_start], TREND_MIN_MONTH_DAYS)?;
+    Some(StartTrend { week, month })
+}
+
+// Pure
+fn average(days: &[Option<u32>], min_known: usize) -> Option<f32> {
+    let known = days.iter().flatten().collect::<Vec<_>>();
+    match known.len() >= min_known {
+        true => Some(known.iter().copied().sum::<u32>() as f32 / known.len() as f32),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn on_day(day: i64) -> LocalTime {
+        LocalTime {
+            day,
+            since_midnight: Duration::from_secs(60),
+        }
+    }
+
+    fn counter_with(history: Vec<Option<u32>>) -> StartCounter {
+        StartCounter::new(StartCounts {
+            lifetime: 1000,
+            day: 100,
+            today: 0,
+            history,
+        })
+    }
+
+    #[test]
+    fn counts_starts_per_day() {
+        let mut counter = StartCounter::new(StartCounts::default());
+        assert_eq!(None, counter.update(on_day(100)));
+        assert_eq!(100, counter.counts().day);
+        for _ in 0..3 {
+            counter.record_start();
+        }
+        assert_eq!(None, counter.update(on_day(100)));
+        let summary = counter.update(on_day(101)).unwrap();
+        assert_eq!(3, summary.starts);
+        assert_eq!(None, summary.trend);
+        counter.record_start();
+        assert_eq!(
+            &StartCounts {
+                lifetime: 4,
+                day: 101,
+                today: 1,
+                history: vec![Some(3)],
+            },
+            counter.counts()
+        );
+    }
+
+    #[test]
+    fn days_not_running_are_missing() {
+        let mut counter = counter_with(vec![Some(20)]);
+        counter.record_start();
+        // Down for the two days in between, so yesterday is unknown.
+        assert_eq!(None, counter.update(on_day(103)));
+        assert_eq!(vec![Some(20), Some(1), None, None], counter.counts().history);
+        let mut counter = counter_with(vec![Some(20)]);
+        counter.update(on_day(1000));
+        assert_eq!(START_HISTORY_DAYS, counter.counts().history.len());
+        assert!(counter.counts().history.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let mut counter = counter_with(vec![Some(1); START_HISTORY_DAYS + 10]);
+        assert_eq!(START_HISTORY_DAYS, counter.counts().history.len());
+        counter.update(on_day(101));
+        assert_eq!(START_HISTORY_DAYS, counter.counts().history.len());
+        assert_eq!(Some(&Some(0)), counter.counts().history.last());
+    }
+
+    #[test]
+    fn trend_flags_significant_increase() {
+        let month = vec![Some(20); 28];
+        let steady = [month.clone(), vec![Some(22); 6]].concat();
+        let summary = counter_with(steady).update(on_day(101)).unwrap();
+        // Today's 0 starts pulls the week down.
+        let trend = summary.trend.unwrap();
+        assert!((trend.week - 22.0 * 6.0 / 7.0).abs() < 1e-4);
+        assert_eq!(20.0, trend.month);
+        assert!(!trend.is_significant_increase());
+
+        let mut counter = counter_with([month, vec![Some(40); 6]].concat());
+        for _ in 0..40 {
+            counter.record_start();
+        }
+        let trend = counter.update(on_day(101)).unwrap().trend.unwrap();
+        assert_eq!((40.0, 20.0), (trend.week, trend.month));
+        assert!(trend.is_significant_increase());
+        // A big ratio on a handful of starts isn't.
+        assert!(!StartTrend { week: 3.0, month: 1.0 }.is_significant_increase());
+    }
+
+    #[test]
+    fn trend_needs_enough_known_days() {
+        let sparse = [vec![None; 20], vec![Some(20); 8], vec![Some(20); 6]].concat();
+        assert_eq!(None, counter_with(sparse).update(on_day(101)).unwrap().trend);
+        let gaps = [vec![Some(20); 28], vec![None; 4], vec![Some(40); 2]].concat();
+        assert_eq!(None, counter_with(gaps).update(on_day(101)).unwrap().trend);
+    }
+}
@@ -0,0 +1,374 @@
+use anyhow::{bail, Result};
+use std::{
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// The units temperatures are shown to people in. Everything is Celsius internally.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Units {
+    Celsius,
+    Fahrenheit,
+    #[default]
+    Both,
+}
+
+impl FromStr for Units {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "c" => Ok(Units::Celsius),
+            "f" => Ok(Units::Fahrenheit),
+            "both" => Ok(Units::Both),
+            _ => bail!("Unknown units '{}', expected c, f or both.", s),
+        }
+    }
+}
+
+impl Units {
+    pub fn show<T: Quantity>(self, value: T) -> InUnits<T> {
+        InUnits { value, units: self }
+    }
+}
+
+static DISPLAY_UNITS: AtomicU8 = AtomicU8::new(Units::Both as u8);
+
+/// Process wide, what `Display` on temperatures uses. Set once at startup.
+pub fn set_display_units(units: Units) {
+    DISPLAY_UNITS.store(units as u8, Ordering::Relaxed);
+}
+
+pub fn display_units() -> Units {
+    match DISPLAY_UNITS.load(Ordering::Relaxed) {
+        u if u == Units::Celsius as u8 => Units::Celsius,
+        u if u == Units::Fahrenheit as u8 => Units::Fahrenheit,
+        _ => Units::Both,
+    }
+}
+
+/// Something measured in degrees that can be shown in either unit.
+pub trait Quantity {
+    fn celsius(&self) -> f32;
+    fn fahrenheit(&self) -> f32;
+    /// Follows each unit, "/h" for a rate per hour.
+    fn suffix(&self) -> &str {
+        ""
+    }
+}
+
+/// An absolute temperature. Two of them can't be added, only their difference taken.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Celsius(pub f32);
+
+/// The difference between two temperatures, or an offset applied to one.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default)]
+pub struct DegreesDelta(pub f32);
+
+/// Thousandths of a degree Celsius, a temperature or a difference. What the thresholds and the compensation are
+/// kept in, so they compare and persist exactly.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Millidegrees(pub i32);
+
+impl Millidegrees {
+    pub const ZERO: Millidegrees = Millidegrees(0);
+
+    pub fn celsius(self) -> Celsius {
+        Celsius(self.0 as f32 / 1000.0)
+    }
+
+    pub fn degrees(self) -> DegreesDelta {
+        DegreesDelta(self.0 as f32 / 1000.0)
+    }
+
+    pub fn abs(self) -> Self {
+        Millidegrees(self.0.abs())
+    }
+}
+
+impl Celsius {
+    /// To the nearest millidegree.
+    pub fn millidegrees(self) -> Millidegrees {
+        Millidegrees((self.0 * 1000.0).round() as i32)
+    }
+
+    pub fn from_fahrenheit(fahrenheit: f32) -> Self {
+        Celsius((fahrenheit - 32.0) * 5.0 / 9.0)
+    }
+
+    pub fn fahrenheit(self) -> f32 {
+        (self.0 * 9.0 / 5.0) + 32.0
+    }
+
+    pub fn is_finite(self) -> bool {
+        self.0.is_finite()
+    }
+}
+
+impl DegreesDelta {
+    pub const ZERO: DegreesDelta = DegreesDelta(0.0);
+
+    pub fn from_fahrenheit(fahrenheit: f32) -> Self {
+        DegreesDelta(fahrenheit * 5.0 / 9.0)
+    }
+
+    pub fn fahrenheit(self) -> f32 {
+        self.0 * 9.0 / 5.0
+    }
+
+    /// To the nearest millidegree.
+    pub fn millidegrees(self) -> Millidegrees {
+        Millidegrees((self.0 * 1000.0).round() as i32)
+    }
+
+    pub fn abs(self) -> Self {
+        DegreesDelta(self.0.abs())
+    }
+
+    /// Also true for negative zero.
+    pub fn is_zero(self) -> bool {
+        self.0 == 0.0
+    }
+
+    pub fn is_nan(self) -> bool {
+        self.0.is_nan()
+    }
+}
+
+/// A change in temperature over `per`, a unit of time like "h".
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rate {
+    pub delta: DegreesDelta,
+    pub per: &'static str,
+}
+
+impl Rate {
+    pub fn new(delta: f32, per: &'static str) -> Self {
+        Rate {
+            delta: DegreesDelta(delta),
+            per,
+        }
+    }
+}
+
+impl Quantity for Celsius {
+    fn celsius(&self) -> f32 {
+        self.0
+    }
+
+    fn fahrenheit(&self) -> f32 {
+        Celsius::fahrenheit(*self)
+    }
+}
+
+impl Quantity for DegreesDelta {
+    fn celsius(&self) -> f32 {
+        self.0
+    }
+
+    fn fahrenheit(&self) -> f32 {
+        DegreesDelta::fahrenheit(*self)
+    }
+}
+
+impl Quantity for Rate {
+    fn celsius(&self) -> f32 {
+        self.delta.0
+    }
+
+    fn fahrenheit(&self) -> f32 {
+        self.delta.fahrenheit()
+    }
+
+    fn suffix(&self) -> &str {
+        self.per
+    }
+}
+
+impl Sub for Celsius {
+    type Output = DegreesDelta;
+
+    fn sub(self, other: Celsius) -> DegreesDelta {
+        DegreesDelta(self.0 - other.0)
+    }
+}
+
+impl Add<DegreesDelta> for Celsius {
+    type Output = Celsius;
+
+    fn add(self, delta: DegreesDelta) -> Celsius {
+        Celsius(self.0 + delta.0)
+    }
+}
+
+impl Sub<DegreesDelta> for Celsius {
+    type Output = Celsius;
+
+    fn sub(self, delta: DegreesDelta) -> Celsius {
+        Celsius(self.0 - delta.0)
+    }
+}
+
+impl Add for DegreesDelta {
+    type Output = DegreesDelta;
+
+    fn add(self, other: DegreesDelta) -> DegreesDelta {
+        DegreesDelta(self.0 + other.0)
+    }
+}
+
+impl Sub for DegreesDelta {
+    type Output = DegreesDelta;
+
+    fn sub(self, other: DegreesDelta) -> DegreesDelta {
+        DegreesDelta(self.0 - other.0)
+    }
+}
+
+impl Div<f32> for DegreesDelta {
+    type Output = DegreesDelta;
+
+    fn div(self, divisor: f32) -> DegreesDelta {
+        DegreesDelta(self.0 / divisor)
+    }
+}
+
+impl Mul<f32> for DegreesDelta {
+    type Output = DegreesDelta;
+
+    fn mul(self, factor: f32) -> DegreesDelta {
+        DegreesDelta(self.0 * factor)
+    }
+}
+
+impl Add for Millidegrees {
+    type Output = Millidegrees;
+
+    fn add(self, other: Millidegrees) -> Millidegrees {
+        Millidegrees(self.0 + other.0)
+    }
+}
+
+impl Sub for Millidegrees {
+    type Output = Millidegrees;
+
+    fn sub(self, other: Millidegrees) -> Millidegrees {
+        Millidegrees(self.0 - other.0)
+    }
+}
+
+impl Neg for DegreesDelta {
+    type Output = DegreesDelta;
+
+    fn neg(self) -> DegreesDelta {
+        DegreesDelta(-self.0)
+    }
+}
+
+/// A quantity in explicit units. The precision (2 by default), `+` flag and width of the formatter apply.
+pub struct InUnits<T> {
+    value: T,
+    units: Units,
+}
+
+/// Every temperature shown to people goes through here.
+impl<T: Quantity> fmt::Display for InUnits<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(2);
+        let unit = |value: f32, unit: char| match f.sign_plus() {
+            true => format!("{:+.*}{}{}", precision, value, unit, self.value.suffix()),
+            false => format!("{:.*}{}{}", precision, value, unit, self.value.suffix()),
+        };
+        let shown = match self.units {
+            Units::Celsius => unit(self.value.celsius(), 'C'),
+            Units::Fahrenheit => unit(self.value.fahrenheit(), 'F'),
+            Units::Both => format!(
+                "{} {}",
+                unit(self.value.celsius(), 'C'),
+                unit(self.value.fahrenheit(), 'F')
+            ),
+        };
+        // Not pad(), it would truncate to the precision.
+        match (f.width(), f.align()) {
+            (None, _) => f.write_str(&shown),
+            (Some(width), Some(fmt::Alignment::Left)) => write!(f, "{:<1$}", shown, width),
+            (Some(width), Some(fmt::Alignment::Center)) => write!(f, "{:^1$}", shown, width),
+            (Some(width), _) => write!(f, "{:>1$}", shown, width),
+        }
+    }
+}
+
+impl fmt::Display for Celsius {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&display_units().show(*self), f)
+    }
+}
+
+impl fmt::Display for DegreesDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&display_units().show(*self), f)
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&display_units().show(*self), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_keeps_units_apart() {
+        let threshold = Celsius(4.0) + DegreesDelta(-0.5);
+        assert_eq!(Celsius(3.5), threshold);
+        assert_eq!(DegreesDelta(1.5), threshold - Celsius(2.0));
+        assert_eq!(DegreesDelta(0.25), (DegreesDelta(1.0) - DegreesDelta(0.5)) / 2.0);
+        assert!(Celsius(3.0) < threshold);
+    }
+
+    #[test]
+    fn millidegrees_round_trip_exactly() {
+        assert_eq!(Millidegrees(556), Celsius(0.555556).millidegrees());
+        assert_eq!(Millidegrees(-1889), (-DegreesDelta(1.888888)).millidegrees());
+        for m in [-40_000, -1, 0, 1, 556, 4_333, 85_000] {
+            assert_eq!(Millidegrees(m), Millidegrees(m).celsius().millidegrees());
+            assert_eq!(Millidegrees(m), Millidegrees(m).degrees().millidegrees());
+        }
+        assert_eq!(Millidegrees(4_333), Millidegrees(4_500) + Millidegrees(-167));
+    }
+
+    #[test]
+    fn displays_celsius_and_fahrenheit() {
+        assert_eq!("4.00C 39.20F", Celsius(4.0).to_string());
+        assert_eq!("-1.00C -1.80F", DegreesDelta(-1.0).to_string());
+        assert_eq!("-18.50C -1.30F", Celsius(-18.5).to_string());
+        assert_eq!("-20.00C -4.00F", Celsius::from_fahrenheit(-4.0).to_string());
+    }
+
+    #[test]
+    fn shows_each_unit_setting() {
+        let shown = |units: Units| {
+            format!(
+                "{} | {:+.1} | {:.3} | [{:>8}]",
+                units.show(Celsius(4.0)),
+                units.show(DegreesDelta(0.3)),
+                units.show(Rate::new(-0.5, "/h")),
+                units.show(Celsius(-1.0))
+            )
+        };
+        assert_eq!("4.00C | +0.3C | -0.500C/h | [  -1.00C]", shown(Units::Celsius));
+        assert_eq!("39.20F | +0.5F | -0.900F/h | [  30.20F]", shown(Units::Fahrenheit));
+        assert_eq!(
+            "4.00C 39.20F | +0.3C +0.5F | -0.500C/h -0.900F/h | [-1.00C 30.20F]",
+            shown(Units::Both)
+        );
+        assert_eq!(Units::Fahrenheit, "f".parse().unwrap());
+        assert_eq!(Units::Both, "both".parse().unwrap());
+        assert!("kelvin".parse::<Units>().is_err());
+    }
+}
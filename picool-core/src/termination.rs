@@ -0,0 +1,299 @@
+use crate::{
+    notify::Event,
+    world_error::{ErrorClass, PersistError, PersistErrorKind},
+};
+use log::*;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+pub const EXIT_SENSOR: i32 = 4;
+pub const EXIT_PERSISTENCE: i32 = 5;
+const PERSISTENCE_FAILURE_LIMIT: u32 = 5;
+
+/// Why the control loop gave up, each maps to an exit code a supervisor can key its restart policy on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Termination {
+    SensorFailed {
+        failing_for: Duration,
+        reinitializations: u32,
+    },
+    PersistenceUnusable {
+        failures: u32,
+        cause: PersistErrorKind,
+    },
+}
+
+impl Termination {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Termination::SensorFailed { .. } => EXIT_SENSOR,
+            Termination::PersistenceUnusable { .. } => EXIT_PERSISTENCE,
+        }
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Termination::SensorFailed {
+                failing_for,
+                reinitializations,
+            } => write!(
+                f,
+                "Sensor unreadable for {}m despite {} re-initializations, giving up.",
+                failing_for.as_secs() / 60,
+                reinitializations
+            ),
+            Termination::PersistenceUnusable { failures, cause } => write!(
+                f,
+                "State persistence failed {} times in a row ({}), giving up.",
+                failures, cause
+            ),
+        }
+    }
+}
+
+/// How run() ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    ShutdownRequested,
+    CycleLimitReached { cycles: u32 },
+    Fatal(Termination),
+}
+
+impl RunOutcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunOutcome::ShutdownRequested | RunOutcome::CycleLimitReached { .. } => 0,
+            RunOutcome::Fatal(termination) => termination.exit_code(),
+        }
+    }
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunOutcome::ShutdownRequested => write!(f, "Shutdown requested."),
+            RunOutcome::CycleLimitReached { cycles } => write!(f, "Completed {} cooling cycles.", cycles),
+            RunOutcome::Fatal(termination) => termination.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StorageConfig {
+    /// Consecutive writes that read back wrong before persisting stops, 0 keeps writing regardless.
+    pub unreliable_after: u32,
+    /// How long to run from memory before trying the storage again.
+    pub retry: Duration,
+    /// Checkpointed cycle extremes older than this are not resumed from, whatever went on meanwhile went unseen.
+    pub checkpoint_max_age: Duration,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            unreliable_after: 3,
+            retry: Duration::from_secs(60 * 60),
+            checkpoint_max_age: Duration::from_secs(60 * 30),
+        }
+    }
+}
+
+struct MemoryOnly {
+    /// Set by the first check after a failed attempt.
+    retry_at: Option<Instant>,
+    skipped: u32,
+}
+
+/// Counts consecutive persistence failures, any success resets it. A permanent failure (full or read-only storage)
+/// won't go away by retrying and is unusable straight away.
+///
+/// Writes that go through but read back wrong are counted apart. Storage that keeps doing that is lying rather
+/// than failing, so instead of giving up the controller runs from memory and tries it again now and then.
+#[derive(Default)]
+pub struct PersistenceHealth {
+    storage: StorageConfig,
+    consecutive_failures: u32,
+    last_failure: Option<(PersistErrorKind, ErrorClass)>,
+    unverified: u32,
+    memory_only: Option<MemoryOnly>,
+    events: Vec<Event>,
+}
+
+impl PersistenceHealth {
+    pub fn new(storage: StorageConfig) -> Self {
+        Self {
+            storage,
+            ..Self::default()
+        }
+    }
+
+    /// Whether to write at all, false while running from memory until the next retry is due.
+    pub fn should_persist(&mut self, now: Instant) -> bool {
+        let retry = self.storage.retry;
+        let memory_only = match &mut self.memory_only {
+            Some(memory_only) => memory_only,
+            None => return true,
+        };
+        let retry_at = *memory_only.retry_at.get_or_insert(now + retry);
+        if now < retry_at {
+            memory_only.skipped += 1;
+            return false;
+        }
+        memory_only.retry_at = None;
+        true
+    }
+
+    pub fn record(&mut self, result: Result<(), PersistError>, what: &str) {
+        match result {
+            Ok(()) => {
+                self.consecutive_failures = 0;
+                self.last_failure = None;
+                self.unverified = 0;
+                if let Some(memory_only) = self.memory_only.take() {
+                    self.events.push(Event::StorageRecovered {
+                        skipped: memory_only.skipped,
+                    });
+                }
+            }
+            Err(e) if e.kind() == PersistErrorKind::Unverified => {
+                self.consecutive_failures = 0;
+                self.last_failure = None;
+                self.unverified += 1;
+                warn!("Failed to persist {}. {}", what, e);
+                if self.memory_only.is_none()
+                    && self.storage.unreliable_after > 0
+                    && self.unverified >= self.storage.unreliable_after
+                {
+                    self.memory_only = Some(MemoryOnly {
+                        retry_at: None,
+                        skipped: 0,
+                    });
+                    self.events.push(Event::StorageUnreliable {
+                        failures: self.unverified,
+                        retry: self.storage.retry,
+                    });
+                }
+            }
+            Err(e) => {
+                self.consecutive_failures += 1;
+                self.last_failure = Some((e.kind(), e.class()));
+                warn!("Failed to persist {}. {}", what, e);
+            }
+        }
+    }
+
+    /// Storage reliability changes since the last call, to notify.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn termination(&self) -> Option<Termination> {
+        let (cause, class) = self.last_failure?;
+        match class == ErrorClass::Permanent || self.consecutive_failures >= PERSISTENCE_FAILURE_LIMIT {
+            true => Some(Termination::PersistenceUnusable {
+                failures: self.consecutive_failures,
+                cause,
+            }),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn failure(errno: i32) -> Result<(), PersistError> {
+        Err(io::Error::from_raw_os_error(errno).into())
+    }
+
+    #[test]
+    fn persistence_unusable_after_consecutive_transient_failures() {
+        let mut health = PersistenceHealth::default();
+        for _ in 0..4 {
+            health.record(failure(libc::EIO), "test");
+        }
+        health.record(Ok(()), "test");
+        for _ in 0..4 {
+            health.record(failure(libc::EIO), "test");
+        }
+        assert_eq!(None, health.termination());
+        health.record(failure(libc::EIO), "test");
+        let termination = health.termination().unwrap();
+        assert_eq!(
+            Termination::PersistenceUnusable {
+                failures: 5,
+                cause: PersistErrorKind::Io
+            },
+            termination
+        );
+        assert_eq!(EXIT_PERSISTENCE, termination.exit_code());
+    }
+
+    #[test]
+    fn runs_from_memory_while_storage_reads_back_wrong() {
+        let storage = StorageConfig::default();
+        let mut health = PersistenceHealth::new(storage);
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert!(health.should_persist(start));
+            health.record(Err(PersistError::unverified("runtime")), "runtime");
+        }
+        assert!(health.take_events().is_empty());
+        health.record(Err(PersistError::unverified("runtime")), "runtime");
+        assert_eq!(
+            vec![Event::StorageUnreliable {
+                failures: 3,
+                retry: storage.retry
+            }],
+            health.take_events()
+        );
+        assert_eq!(None, health.termination());
+
+        // Skipped until the retry is due, a retry that reads back wrong again waits another period.
+        assert!(!health.should_persist(start));
+        assert!(!health.should_persist(start + storage.retry / 2));
+        assert!(health.should_persist(start + storage.retry));
+        health.record(Err(PersistError::unverified("runtime")), "runtime");
+        assert!(health.take_events().is_empty());
+        assert!(!health.should_persist(start + storage.retry));
+        assert!(!health.should_persist(start + storage.retry * 3 / 2));
+        assert!(health.should_persist(start + storage.retry * 2));
+        health.record(Ok(()), "runtime");
+        assert_eq!(vec![Event::StorageRecovered { skipped: 4 }], health.take_events());
+        assert!(health.should_persist(start + storage.retry * 2));
+    }
+
+    #[test]
+    fn keeps_writing_unverified_storage_when_disabled() {
+        let mut health = PersistenceHealth::new(StorageConfig {
+            unreliable_after: 0,
+            ..StorageConfig::default()
+        });
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert!(health.should_persist(start));
+            health.record(Err(PersistError::unverified("runtime")), "runtime");
+        }
+        assert!(health.take_events().is_empty());
+        assert_eq!(None, health.termination());
+    }
+
+    #[test]
+    fn persistence_unusable_on_permanent_failure() {
+        let mut health = PersistenceHealth::default();
+        health.record(failure(libc::ENOSPC), "test");
+        assert_eq!(
+            Some(Termination::PersistenceUnusable {
+                failures: 1,
+                cause: PersistErrorKind::StorageFull
+            }),
+            health.termination()
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use crate::MinimumIntervals;
+use std::{
+    fmt,
+    mem::{replace, take},
+    time::{Duration, Instant},
+};
+
+/// Held by the minimum in more than this share of a side's phases, the minimum rather than the thresholds decides
+/// when the relay switches, and the band is overshot.
+const BOUND_SHARE: f32 = 0.25;
+/// A bound minimum is shortened by this much per recommendation.
+const SHORTEN: f32 = 0.75;
+/// An unbound minimum is set to this fraction of the median natural phase, normal cycling never meets it while a
+/// short cycle still does.
+const NATURAL_FRACTION: f32 = 0.5;
+/// Longer minimums would hold the relay through an open door or a warm load.
+const LONGEST: Duration = Duration::from_secs(60 * 30);
+const MINUTE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimingConfig {
+    /// Observed cycles between recommendations, None disables the advisor.
+    pub cycles: Option<u32>,
+    /// Apply each recommendation instead of only reporting it.
+    pub auto_apply: bool,
+    /// Never recommended below these, whatever the cycling says.
+    pub floor: MinimumIntervals,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            cycles: None,
+            auto_apply: false,
+            floor: MinimumIntervals {
+                on: Duration::from_secs(60),
+                off: Duration::from_secs(60 * 3),
+            },
+        }
+    }
+}
+
+impl TimingConfig {
+    /// Tuned intervals as restored or recommended, held within the floor and the longest allowed.
+    pub fn limit(&self, intervals: MinimumIntervals) -> MinimumIntervals {
+        MinimumIntervals {
+            on: intervals.on.min(LONGEST).max(self.floor.on),
+            off: intervals.off.min(LONGEST).max(self.floor.off),
+        }
+    }
+}
+
+/// How one side of the relay ran over a round.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PhaseSummary {
+    pub phases: u32,
+    /// Phases the minimum interval held past where the thresholds would have switched.
+    pub bound: u32,
+    /// Of the phases the thresholds ended.
+    pub median: Option<Duration>,
+}
+
+impl PhaseSummary {
+    // Pure
+    fn recommend(&self, current: Duration) -> Duration {
+        let recommended = match self.median {
+            _ if self.bound as f32 > self.phases as f32 * BOUND_SHARE => current.mul_f32(SHORTEN),
+            Some(median) => median.mul_f32(NATURAL_FRACTION),
+            None => return current,
+        };
+        // Whole minutes, nobody sets `--min-on 7m13s`.
+        let recommended = MINUTE * (recommended.as_secs() / MINUTE.as_secs()) as u32;
+        match recommended.max(current) - recommended.min(current) < MINUTE {
+            true => current,
+            false => recommended,
+        }
+    }
+}
+
+impl fmt::Display for PhaseSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} held by the minimum", self.bound, self.phases)?;
+        match self.median {
+            Some(median) => write!(f, ", naturally {}s", median.as_secs()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A recommendation from one round of cycles, with what it was based on.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimingAdvice {
+    pub current: MinimumIntervals,
+    pub recommended: MinimumIntervals,
+    pub on: PhaseSummary,
+    pub off: PhaseSummary,
+}
+
+impl TimingAdvice {
+    pub fn is_change(&self) -> bool {
+        self.current != self.recommended
+    }
+}
+
+/// `on 2m -> 5m (0 of 10 held by the minimum, naturally 600s), off ...`, the justification logged and sent along.
+impl fmt::Display for TimingAdvice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = |d: Duration| d.as_secs() / 60;
+        write!(
+            f,
+            "on {}m -> {}m ({}), off {}m -> {}m ({})",
+            minutes(self.current.on),
+            minutes(self.recommended.on),
+            self.on,
+            minutes(self.current.off),
+            minutes(self.recommended.off),
+            self.off
+        )
+    }
+}
+
+#[derive(Default)]
+struct Side {
+    natural: Vec<Duration>,
+    bound: u32,
+}
+
+impl Side {
+    fn phases(&self) -> u32 {
+        self.natural.len() as u32 + self.bound
+    }
+
+    fn summary(mut self) -> PhaseSummary {
+        self.natural.sort();
+        PhaseSummary {
+            phases: self.phases(),
+            bound: self.bound,
+            median: self.natural.get(self.natural.len() / 2).copied(),
+        }
+    }
+}
+
+/// Measures how long the relay stays on and off between switches, and how often a minimum interval held it, and
+/// recommends minimum intervals from that every `cycles` cycles.
+pub struct TimingAdvisor {
+    config: TimingConfig,
+    /// When the relay last switched and which way, None until a switch starts a phase worth measuring.
+    phase: Option<(Instant, bool)>,
+    /// The thresholds called for a switch during the phase in progress that its minimum interval held back.
+    held: bool,
+    on: Side,
+    off: Side,
+}
+
+impl TimingAdvisor {
+    pub fn new(config: TimingConfig) -> Self {
+        Self {
+            config,
+            phase: None,
+            held: false,
+            on: Side::default(),
+            off: Side::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.cycles.is_some()
+    }
+
+    /// A decision the thresholds would have switched on, had the minimum interval not held the relay.
+    pub fn held(&mut self) {
+        self.held = true;
+    }
+
+    /// The relay switched `on` at `now`, ending the phase in progress.
+    pub fn switched(&mut self, now: Instant, on: bool) {
+        let held = replace(&mut self.held, false);
+        if !self.is_enabled() {
+            return;
+        }
+        // The first phase began before anything was watching.
+        if let Some((since, was_on)) = self.phase.replace((now, on)) {
+            let side = match was_on {
+                true => &mut self.on,
+                false => &mut self.off,
+            };
+            match held {
+                true => side.bound += 1,
+                false => side.natural.push(now - since),
+            }
+        }
+    }
+
+    /// The failsafe, a lockout or a boost took over the relay, the phase in progress says nothing about the minimums.
+    pub fn interrupt(&mut self) {
+        self.phase = None;
+        self.held = false;
+    }
+
+    /// Once both sides ran `cycles` phases, the recommendation against `current` and a fresh round.
+    pub fn advise(&mut self, current: MinimumIntervals) -> Option<TimingAdvice> {
+        let cycles = self.config.cycles?;
+        if self.on.phases() < cycles || self.off.phases() < cycles {
+            return None;
+        }
+        let (on, off) = (take(&mut self.on).summary(), take(&mut self.off).summary());
+        Some(TimingAdvice {
+            current,
+            recommended: self.config.limit(MinimumIntervals {
+                on: on.recommend(current.on),
+                off: off.recommend(current.off),
+            }),
+            on,
+            off,
+        })
+    }
+
+    /// Starts the round over, what was measured ran under other intervals.
+    pub fn restart(&mut self) {
+        self.on = Side::default();
+        self.off = Side::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    fn advisor(cycles: u32) -> TimingAdvisor {
+        TimingAdvisor::new(TimingConfig {
+            cycles: Some(cycles),
+            ..TimingConfig::default()
+        })
+    }
+
+    /// `cycles` on and off phases of `on` and `off` minutes starting at `start`, each held by its minimum or not.
+    fn cycle(advisor: &mut TimingAdvisor, start: Instant, cycles: u64, (on, off): (u64, u64), held: (bool, bool)) {
+        for i in 0..cycles {
+            let at = start + minutes(i * (on + off));
+            if held.1 {
+                advisor.held();
+            }
+            advisor.switched(at, true);
+            if held.0 {
+                advisor.held();
+            }
+            advisor.switched(at + minutes(on), false);
+        }
+    }
+
+    #[test]
+    fn recommends_half_the_natural_phases() {
+        let start = Instant::now();
+        let mut advisor = advisor(3);
+        cycle(&mut advisor, start, 3, (12, 40), (false, false));
+        assert_eq!(None, advisor.advise(MinimumIntervals::default()));
+        advisor.switched(start + minutes(3 * 52), true);
+        let advice = advisor.advise(MinimumIntervals::default()).unwrap();
+        assert_eq!(
+            MinimumIntervals {
+                on: minutes(6),
+                off: minutes(20),
+            },
+            advice.recommended
+        );
+        assert_eq!(
+            "on 2m -> 6m (0 of 3 held by the minimum, naturally 720s), off 8m -> 20m (0 of 3 held by the minimum, \
+             naturally 2400s)",
+            advice.to_string()
+        );
+        // The next round starts over.
+        assert_eq!(None, advisor.advise(MinimumIntervals::default()));
+    }
+
+    #[test]
+    fn shortens_a_minimum_that_keeps_holding() {
+        let start = Instant::now();
+        let mut advisor = advisor(4);
+        let current = MinimumIntervals {
+            on: minutes(20),
+            off: minutes(8),
+        };
+        cycle(&mut advisor, start, 5, (20, 30), (true, false));
+        let advice = advisor.advise(current).unwrap();
+        assert_eq!(minutes(15), advice.recommended.on);
+        assert_eq!(minutes(15), advice.recommended.off);
+        assert_eq!(5, advice.on.bound);
+        assert_eq!(None, advice.on.median);
+    }
+
+    #[test]
+    fn stays_within_the_floor_and_ignores_small_changes() {
+        let start = Instant::now();
+        let mut held = advisor(2);
+        // The thresholds would have switched sooner every time.
+        cycle(&mut held, start, 3, (2, 8), (true, true));
+        let advice = held.advise(MinimumIntervals::default()).unwrap();
+        assert_eq!(
+            MinimumIntervals {
+                on: minutes(1),
+                off: minutes(6),
+            },
+            advice.recommended
+        );
+        let mut natural = advisor(2);
+        cycle(&mut natural, start, 3, (5, 17), (false, false));
+        let advice = natural.advise(MinimumIntervals::default()).unwrap();
+        assert!(!advice.is_change());
+    }
+
+    #[test]
+    fn interrupted_phases_are_not_measured() {
+        let start = Instant::now();
+        let mut advisor = advisor(1);
+        advisor.switched(start, true);
+        advisor.interrupt();
+        advisor.switched(start + minutes(90), false);
+        advisor.switched(start + minutes(120), true);
+        assert_eq!(None, advisor.advise(MinimumIntervals::default()));
+        advisor.switched(start + minutes(130), false);
+        let advice = advisor.advise(MinimumIntervals::default()).unwrap();
+        assert_eq!(Some(minutes(10)), advice.on.median);
+        assert_eq!(Some(minutes(30)), advice.off.median);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{temperature::DegreesDelta, MinimumIntervals};
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+
+const SECONDS_PER_HOUR: f32 = 3600.0;
+
+/// Time-proportional control, for coolers without a compressor to protect.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TpcConfig {
+    /// The relay is on for the start of each window, for the fraction the PI law asks for.
+    pub period: Duration,
+    /// Duty per degree above the setpoint.
+    pub gain: f32,
+    /// Duty per degree hour above the setpoint.
+    pub integral_gain: f32,
+}
+
+impl Default for TpcConfig {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_secs(60 * 10),
+            gain: 0.5,
+            integral_gain: 0.1,
+        }
+    }
+}
+
+impl TpcConfig {
+    /// A window has to fit a minimum on and a minimum off, or some duties could never be honored.
+    pub fn validate(&self, intervals: MinimumIntervals) -> Result<()> {
+        if self.period.as_secs() == 0 {
+            bail!("The time-proportional period must be positive.");
+        }
+        if intervals.on + intervals.off > self.period {
+            bail!(
+                "Minimum on {}s and off {}s don't fit the {}s time-proportional period, shorten them.",
+                intervals.on.as_secs(),
+                intervals.off.as_secs(),
+                self.period.as_secs()
+            );
+        }
+        if !(self.gain.is_finite() && self.gain >= 0.0 && self.integral_gain.is_finite() && self.integral_gain >= 0.0) {
+            bail!("Time-proportional gains must be positive.");
+        }
+        Ok(())
+    }
+}
+
+/// Proportional plus integral duty, the integral only winds while the output isn't saturated.
+pub struct PiController {
+    config: TpcConfig,
+    integral: f32,
+    last: Option<Instant>,
+}
+
+impl PiController {
+    pub fn new(config: TpcConfig) -> Self {
+        Self {
+            config,
+            integral: 0.0,
+            last: None,
+        }
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// The duty for `error`, degrees above the setpoint, between 0 and 1.
+    pub fn update(&mut self, now: Instant, error: DegreesDelta) -> f32 {
+        let hours = match self.last.replace(now) {
+            Some(last) => (now - last).as_secs_f32() / SECONDS_PER_HOUR,
+            None => 0.0,
+        };
+        let proportional = self.config.gain * error.0;
+        let integral = (self.integral + self.config.integral_gain * error.0 * hours).clamp(0.0, 1.0);
+        let output = proportional + integral;
+        // Conditional integration: winding further into saturation is what makes a PI loop overshoot on the way back.
+        let saturated = (output > 1.0 && error.0 > 0.0) || (output < 0.0 && error.0 < 0.0);
+        if !saturated {
+            self.integral = integral;
+        }
+        (proportional + self.integral).clamp(0.0, 1.0)
+    }
+}
+
+/// A window that just started.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WindowStart {
+    pub duty: f32,
+    pub on_for: Duration,
+}
+
+/// Turns a duty into on time at the start of fixed windows, never switching within a minimum interval.
+pub struct DutyWindow {
+    period: Duration,
+    intervals: MinimumIntervals,
+    started: Option<Instant>,
+    on_for: Duration,
+    /// The relay position and when it was taken.
+    switched: Option<(bool, Instant)>,
+}
+
+impl DutyWindow {
+    /// `switched` is the relay position the controller starts from, if its start is known.
+    pub fn new(config: TpcConfig, intervals: MinimumIntervals, switched: Option<(bool, Instant)>) -> Self {
+        Self {
+            period: config.period,
+            intervals,
+            started: None,
+            on_for: Duration::from_secs(0),
+            switched,
+        }
+    }
+
+    /// The minimum intervals the windows are held to from the next one.
+    pub fn set_intervals(&mut self, intervals: MinimumIntervals) {
+        self.intervals = intervals;
+    }
+
+    /// Whether the relay should be on at `now`, and the window if one started. A new window takes `duty` once the
+    /// last one is over and the relay has held its position for the minimum interval.
+    pub fn output(&mut self, now: Instant, duty: f32) -> (bool, Option<WindowStart>) {
+        let (current, settled) = match self.switched {
+            Some((on, at)) => {
+                let minimum = match on {
+                    true => self.intervals.on,
+                    false => self.intervals.off,
+                };
+                (on, now - at >= minimum)
+            }
+            None => (false, true),
+        };
+        let due = self.started.is_none_or(|started| now - started >= self.period);
+        let mut start = None;
+        if due && settled {
+            self.started = Some(now);
+            self.on_for = on_time(self.period, duty, self.intervals);
+            start = Some(WindowStart {
+                duty,
+                on_for: self.on_for,
+            });
+        }
+        let wanted = match self.started {
+            Some(started) if now - started < self.period => now - started < self.on_for,
+            _ => current,
+        };
+        let on = match settled {
+            true => wanted,
+            false => current,
+        };
+        if self.switched.map(|(on, _)| on) != Some(on) {
+            self.switched = Some((on, now));
+        }
+        (on, start)
+    }
+
+    /// Ends the on part of the window early, as soon as the minimum on allows.
+    pub fn cut(&mut self, now: Instant) {
+        if let Some(started) = self.started {
+            self.on_for = self.on_for.min(now - started);
+        }
+    }
+
+    /// The relay was moved for something other than the window, start over from there.
+    pub fn interrupt(&mut self, now: Instant, on: bool) {
+        self.started = None;
+        self.switched = Some((on, now));
+    }
+}
+
+// Pure
+/// The on time for `duty`, rounded so both the on and the off part are either empty or at least their minimum.
+pub fn on_time(period: Duration, duty: f32, intervals: MinimumIntervals) -> Duration {
+    let on = Duration::from_secs((period.as_secs_f32() * duty.clamp(0.0, 1.0)).round() as u64);
+    let off = period - on;
+    if on < intervals.on {
+        return match on * 2 >= intervals.on {
+            true => intervals.on,
+            false => Duration::from_secs(0),
+        };
+    }
+    if off < intervals.off {
+        return match off * 2 >= intervals.off {
+            true => period - intervals.off,
+            false => period,
+        };
+    }
+    on
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn intervals(on: u32, off: u32) -> MinimumIntervals {
+        MinimumIntervals {
+            on: MINUTE * on,
+            off: MINUTE * off,
+        }
+    }
+
+    #[test]
+    fn on_time_honors_minimum_intervals() {
+        let period = MINUTE * 10;
+        assert_eq!(MINUTE * 4, on_time(period, 0.4, intervals(0, 0)));
+        assert_eq!(period, on_time(period, 1.5, intervals(0, 0)));
+        assert_eq!(Duration::from_secs(0), on_time(period, -0.5, intervals(0, 0)));
+        // Too short an on part rounds to nothing or to the minimum, whichever is closer.
+        assert_eq!(Duration::from_secs(0), on_time(period, 0.05, intervals(2, 3)));
+        assert_eq!(MINUTE * 2, on_time(period, 0.15, intervals(2, 3)));
+        // Likewise the off part.
+        assert_eq!(MINUTE * 7, on_time(period, 0.85, intervals(2, 3)));
+        assert_eq!(period, on_time(period, 0.95, intervals(2, 3)));
+    }
+
+    #[test]
+    fn windows_repeat_the_duty() {
+        let start = Instant::now();
+        let mut window = DutyWindow::new(TpcConfig::default(), intervals(0, 0), None);
+        let at = |minutes: u64| start + MINUTE * minutes as u32;
+        let (on, started) = window.output(at(0), 0.3);
+        assert!(on);
+        assert_eq!(
+            Some(WindowStart {
+                duty: 0.3,
+                on_for: MINUTE * 3
+            }),
+            started
+        );
+        // The duty is only taken at the start of a window.
+        assert_eq!((true, None), window.output(at(2), 0.0));
+        assert_eq!((false, None), window.output(at(3), 1.0));
+        assert_eq!((false, None), window.output(at(9), 1.0));
+        let (on, started) = window.output(at(10), 0.6);
+        assert!(on);
+        assert_eq!(MINUTE * 6, started.unwrap().on_for);
+        assert_eq!((true, None), window.output(at(15), 0.0));
+        assert_eq!((false, None), window.output(at(16), 0.0));
+    }
+
+    #[test]
+    fn window_waits_for_minimum_intervals() {
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+        // Restored as just turned off, the first window waits out the minimum off.
+        let mut window = DutyWindow::new(TpcConfig::default(), intervals(2, 3), Some((false, start)));
+        assert_eq!((false, None), window.output(at(60), 1.0));
+        let (on, started) = window.output(at(180), 0.7);
+        assert!(on && started.is_some());
+        // A late poll ends the on part late, the next window waits for the full minimum off after it.
+        assert_eq!((false, None), window.output(at(610), 0.7));
+        assert_eq!((false, None), window.output(at(780), 0.7));
+        let (on, started) = window.output(at(790), 0.5);
+        assert!(on && started.is_some());
+        // Cut short when too cold, but not within the minimum on.
+        window.cut(at(850));
+        assert_eq!((true, None), window.output(at(850), 0.5));
+        assert_eq!((false, None), window.output(at(910), 0.5));
+    }
+
+    #[test]
+    fn pi_integrates_the_error() {
+        let start = Instant::now();
+        let mut pi = PiController::new(TpcConfig::default());
+        let hour = Duration::from_secs(3600);
+        assert_eq!(0.25, pi.update(start, DegreesDelta(0.5)));
+        // Half a degree for an hour adds 0.05 of duty.
+        assert!((pi.update(start + hour, DegreesDelta(0.5)) - 0.3).abs() < 1e-6);
+        assert!((pi.integral() - 0.05).abs() < 1e-6);
+        // Below the setpoint only the integral holds the duty up.
+        assert!((pi.update(start + hour, DegreesDelta(-0.05)) - 0.025).abs() < 1e-6);
+        assert_eq!(0.0, pi.update(start + hour, DegreesDelta(-1.0)));
+    }
+
+    #[test]
+    fn pi_does_not_wind_up() {
+        let start = Instant::now();
+        let mut pi = PiController::new(TpcConfig::default());
+        let hour = Duration::from_secs(3600);
+        pi.update(start, DegreesDelta(5.0));
+        // Saturated for ten hours of a warm chamber, the integral stays where it was.
+        assert_eq!(1.0, pi.update(start + hour * 10, DegreesDelta(5.0)));
+        assert_eq!(0.0, pi.integral());
+        // So duty follows the error straight back down.
+        assert_eq!(0.0, pi.update(start + hour * 10, DegreesDelta(-0.1)));
+    }
+
+    #[test]
+    fn refuses_windows_too_short_for_minimum_intervals() {
+        let config = TpcConfig::default();
+        assert!(config.validate(intervals(2, 8)).is_ok());
+        assert!(config.validate(intervals(2, 9)).is_err());
+        let zero = TpcConfig {
+            period: Duration::from_secs(0),
+            ..config
+        };
+        assert!(zero.validate(intervals(0, 0)).is_err());
+        let negative = TpcConfig { gain: -1.0, ..config };
+        assert!(negative.validate(intervals(0, 0)).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::temperature::Celsius;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const MINIMUM_SAMPLES: usize = 3;
+
+/// Least-squares slope of the readings within a trailing time window.
+pub struct TrendEstimator {
+    window: Duration,
+    samples: VecDeque<(Instant, Celsius)>,
+}
+
+impl TrendEstimator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, now: Instant, temperature: Celsius) {
+        self.samples.push_back((now, temperature));
+        while let Some(&(oldest, _)) = self.samples.front() {
+            if now - oldest <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Degrees per hour. None until the window is at least half covered.
+    pub fn slope_per_hour(&self) -> Option<f32> {
+        let &(first, _) = self.samples.front()?;
+        let &(last, _) = self.samples.back()?;
+        if self.samples.len() < MINIMUM_SAMPLES || (last - first) < self.window / 2 {
+            return None;
+        }
+
+        let n = self.samples.len() as f32;
+        let hours = |at: Instant| (at - first).as_secs_f32() / 3600.0;
+        let mean_x = self.samples.iter().map(|&(at, _)| hours(at)).sum::<f32>() / n;
+        let mean_y = self.samples.iter().map(|&(_, t)| t.0).sum::<f32>() / n;
+        let (covariance, variance) = self.samples.iter().fold((0.0, 0.0), |(c, v), &(at, t)| {
+            let dx = hours(at) - mean_x;
+            (c + dx * (t.0 - mean_y), v + dx * dx)
+        });
+        Some(covariance / variance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_half_a_window() {
+        let start = Instant::now();
+        let mut trend = TrendEstimator::new(Duration::from_secs(600));
+        trend.push(start, Celsius(1.0));
+        trend.push(start + Duration::from_secs(100), Celsius(1.0));
+        trend.push(start + Duration::from_secs(200), Celsius(1.0));
+        assert_eq!(None, trend.slope_per_hour());
+        trend.push(start + Duration::from_secs(300), Celsius(1.0));
+        assert_eq!(Some(0.0), trend.slope_per_hour());
+    }
+
+    #[test]
+    fn linear_rise() {
+        let start = Instant::now();
+        let mut trend = TrendEstimator::new(Duration::from_secs(3600));
+        for minute in 0..=60 {
+            trend.push(
+                start + Duration::from_secs(minute * 60),
+                Celsius(2.0 + minute as f32 * 0.05),
+            );
+        }
+        let slope = trend.slope_per_hour().unwrap();
+        assert!((slope - 3.0).abs() < 0.001, "{}", slope);
+    }
+
+    #[test]
+    fn old_samples_expire() {
+        let start = Instant::now();
+        let mut trend = TrendEstimator::new(Duration::from_secs(600));
+        for minute in 0..30 {
+            let t = if minute < 15 { 10.0 - minute as f32 } else { 0.0 };
+            trend.push(start + Duration::from_secs(minute * 60), Celsius(t));
+        }
+        assert_eq!(Some(0.0), trend.slope_per_hour());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{collections::BTreeMap, error, fmt, io, path::PathBuf};
+use strum_macros::Display;
+
+/// Whether retrying the same operation can be expected to succeed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display)]
+pub enum ErrorClass {
+    Transient,
+    Permanent,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Display)]
+pub enum SensorErrorKind {
+    #[strum(serialize = "unreadable")]
+    Unreadable,
+    #[strum(serialize = "malformed")]
+    Malformed,
+    #[strum(serialize = "non-finite")]
+    NonFinite,
+    #[strum(serialize = "missing")]
+    Missing,
+    #[strum(serialize = "permission denied")]
+    PermissionDenied,
+}
+
+#[derive(Debug)]
+pub enum SensorError {
+    /// A failed read, w1 reports CRC and bus errors this way.
+    Unreadable(PathBuf, io::Error),
+    Malformed(String),
+    /// NaN or infinity, which would compare false against every threshold and freeze the state machine.
+    NonFinite(f32),
+    Missing(PathBuf),
+    PermissionDenied(PathBuf),
+}
+
+impl SensorError {
+    pub fn from_io(path: PathBuf, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => SensorError::Missing(path),
+            io::ErrorKind::PermissionDenied => SensorError::PermissionDenied(path),
+            _ => SensorError::Unreadable(path, e),
+        }
+    }
+
+    pub fn kind(&self) -> SensorErrorKind {
+        match self {
+            SensorError::Unreadable(..) => SensorErrorKind::Unreadable,
+            SensorError::Malformed(_) => SensorErrorKind::Malformed,
+            SensorError::NonFinite(_) => SensorErrorKind::NonFinite,
+            SensorError::Missing(_) => SensorErrorKind::Missing,
+            SensorError::PermissionDenied(_) => SensorErrorKind::PermissionDenied,
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            SensorError::Unreadable(..) | SensorError::Malformed(_) | SensorError::NonFinite(_) => {
+                ErrorClass::Transient
+            }
+            SensorError::Missing(_) | SensorError::PermissionDenied(_) => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::Unreadable(path, e) => write!(f, "Reading {} failed: {}", path.display(), e),
+            SensorError::Malformed(value) => write!(f, "Sensor returned malformed value '{}'.", value),
+            SensorError::NonFinite(value) => write!(f, "Sensor returned non-finite value {}.", value),
+            SensorError::Missing(path) => write!(f, "Sensor {} is missing.", path.display()),
+            SensorError::PermissionDenied(path) => write!(f, "Permission denied reading {}.", path.display()),
+        }
+    }
+}
+
+impl error::Error for SensorError {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display)]
+pub enum PersistErrorKind {
+    #[strum(serialize = "storage full")]
+    StorageFull,
+    #[strum(serialize = "read-only")]
+    ReadOnly,
+    #[strum(serialize = "permission denied")]
+    PermissionDenied,
+    #[strum(serialize = "i/o")]
+    Io,
+    /// The write went through but reading it back gave something else.
+    #[strum(serialize = "unverified")]
+    Unverified,
+}
+
+#[derive(Debug)]
+pub struct PersistError(pub io::Error);
+
+impl PersistError {
+    /// Reading `what` back didn't give what was written.
+    pub fn unverified(what: &str) -> Self {
+        PersistError(io::Error::other(ReadBackMismatch(what.to_string())))
+    }
+
+    pub fn kind(&self) -> PersistErrorKind {
+        if self.0.get_ref().is_some_and(|e| e.is::<ReadBackMismatch>()) {
+            return PersistErrorKind::Unverified;
+        }
+        match self.0.kind() {
+            io::ErrorKind::StorageFull => PersistErrorKind::StorageFull,
+            io::ErrorKind::ReadOnlyFilesystem => PersistErrorKind::ReadOnly,
+            io::ErrorKind::PermissionDenied => PersistErrorKind::PermissionDenied,
+            _ => PersistErrorKind::Io,
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self.kind() {
+            PersistErrorKind::Io | PersistErrorKind::Unverified => ErrorClass::Transient,
+            _ => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl From<io::Error> for PersistError {
+    fn from(e: io::Error) -> Self {
+        PersistError(e)
+    }
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Persisting failed ({}): {}", self.kind(), self.0)
+    }
+}
+
+impl error::Error for PersistError {}
+
+#[derive(Debug)]
+struct ReadBackMismatch(String);
+
+impl fmt::Display for ReadBackMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} read back differently from what was written.", self.0)
+    }
+}
+
+impl error::Error for ReadBackMismatch {}
+
+/// Sensor errors seen during an outage, by kind.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SensorErrorCounts(BTreeMap<SensorErrorKind, u32>);
+
+impl SensorErrorCounts {
+    pub fn record(&mut self, kind: SensorErrorKind) {
+        *self.0.entry(kind).or_default() += 1;
+    }
+
+    pub fn take(&mut self) -> Self {
+        std::mem::take(self)
+    }
+}
+
+impl fmt::Display for SensorErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts: Vec<String> = self.0.iter().map(|(kind, n)| format!("{} {}", n, kind)).collect();
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensor_errors_classified() {
+        let path = PathBuf::from("/sys/bus/w1/devices/28-00/temperature");
+        let missing = SensorError::from_io(path.clone(), io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(SensorErrorKind::Missing, missing.kind());
+        assert_eq!(ErrorClass::Permanent, missing.class());
+        let denied = SensorError::from_io(path.clone(), io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(ErrorClass::Permanent, denied.class());
+        let crc = SensorError::from_io(path, io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(SensorErrorKind::Unreadable, crc.kind());
+        assert_eq!(ErrorClass::Transient, crc.class());
+        assert_eq!(ErrorClass::Transient, SensorError::Malformed("".into()).class());
+    }
+
+    #[test]
+    fn persist_errors_classified() {
+        let full = PersistError::from(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(PersistErrorKind::StorageFull, full.kind());
+        assert_eq!(ErrorClass::Permanent, full.class());
+        let read_only = PersistError::from(io::Error::from_raw_os_error(libc::EROFS));
+        assert_eq!(PersistErrorKind::ReadOnly, read_only.kind());
+        assert_eq!(ErrorClass::Permanent, read_only.class());
+        let interrupted = PersistError::from(io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(ErrorClass::Transient, interrupted.class());
+        let unverified = PersistError::unverified("runtime");
+        assert_eq!(PersistErrorKind::Unverified, unverified.kind());
+        assert_eq!(ErrorClass::Transient, unverified.class());
+        assert_eq!(
+            PersistErrorKind::Io,
+            PersistError::from(io::Error::other("other")).kind()
+        );
+    }
+
+    #[test]
+    fn counts_by_kind() {
+        let mut counts = SensorErrorCounts::default();
+        counts.record(SensorErrorKind::Malformed);
+        counts.record(SensorErrorKind::Unreadable);
+        counts.record(SensorErrorKind::Unreadable);
+        assert_eq!("2 unreadable, 1 malformed", counts.take().to_string());
+        assert_eq!("", counts.to_string());
+    }
+}
@@ -0,0 +1,120 @@
+//! The last line of defence for the relay contacts and the compressor: whatever the controller, a debounce or a
+//! stream of override commands asks for, two actuations are never closer together than the spacing.
+
+use log::*;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Bumped by the switch for every change it had to hold back, read by the status board.
+#[derive(Clone, Default)]
+pub struct ActuationDeferrals(Arc<AtomicU64>);
+
+impl ActuationDeferrals {
+    fn bump(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Spaces the actuations of one output, a change requested too soon is carried out late rather than dropped.
+pub struct ActuationGuard {
+    spacing: Duration,
+    last: Option<Instant>,
+    deferrals: ActuationDeferrals,
+}
+
+impl ActuationGuard {
+    pub fn new(spacing: Duration) -> Self {
+        Self {
+            spacing,
+            last: None,
+            deferrals: ActuationDeferrals::default(),
+        }
+    }
+
+    pub fn deferrals(&self) -> ActuationDeferrals {
+        self.deferrals.clone()
+    }
+
+    // Pure
+    /// How long a change requested at `now` has to wait.
+    fn wait(&self, now: Instant) -> Duration {
+        match self.last {
+            Some(last) => (last + self.spacing).saturating_duration_since(now),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Carries out `actuate` at the earliest allowed instant by the `now` clock, sleeping with `sleep` until then.
+    pub fn actuate(&mut self, now: impl Fn() -> Instant, sleep: impl FnOnce(Duration), actuate: impl FnOnce()) {
+        let wait = self.wait(now());
+        if wait > Duration::from_secs(0) {
+            self.deferrals.bump();
+            info!(
+                "Relay actuated less than {}ms ago, deferring the change by {}ms",
+                self.spacing.as_millis(),
+                wait.as_millis()
+            );
+            sleep(wait);
+        }
+        actuate();
+        self.last = Some(now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    #[test]
+    fn rapid_alternating_requests_are_spaced_not_dropped() {
+        let start = Instant::now();
+        let clock = Cell::new(start);
+        let mut guard = ActuationGuard::new(Duration::from_secs(5));
+        let deferrals = guard.deferrals();
+        let actuations = RefCell::new(vec![]);
+        for i in 0..100 {
+            // A request every 100ms, more often on a burst.
+            clock.set(clock.get().max(start + Duration::from_millis(100 * i)));
+            guard.actuate(
+                || clock.get(),
+                |wait| clock.set(clock.get() + wait),
+                || actuations.borrow_mut().push((clock.get(), i % 2 == 0)),
+            );
+        }
+        let actuations = actuations.into_inner();
+        assert_eq!(100, actuations.len());
+        assert!(actuations
+            .windows(2)
+            .all(|pair| pair[1].0 - pair[0].0 >= Duration::from_secs(5) && pair[1].1 != pair[0].1));
+        assert_eq!(99, deferrals.count());
+        // Each deferred to the earliest allowed instant, not later.
+        assert_eq!(start + Duration::from_secs(5 * 99), actuations[99].0);
+    }
+
+    #[test]
+    fn spaced_requests_go_straight_through() {
+        let start = Instant::now();
+        let clock = Cell::new(start);
+        let mut guard = ActuationGuard::new(Duration::from_secs(5));
+        for i in 0..10 {
+            clock.set(start + Duration::from_secs(5 * i));
+            guard.actuate(|| clock.get(), |_| panic!("Slept."), || {});
+        }
+        assert_eq!(0, guard.deferrals().count());
+        let mut unguarded = ActuationGuard::new(Duration::from_secs(0));
+        for _ in 0..10 {
+            unguarded.actuate(|| start, |_| panic!("Slept."), || {});
+        }
+        assert_eq!(0, unguarded.deferrals().count());
+    }
+}
@@ -0,0 +1,349 @@
+//! `--candidate "<options>"` runs a second, independent controller on the same readings under a candidate
+//! configuration while the primary drives the relay, and reports how the two differ. The chamber only ever follows
+//! the primary, so the candidate's time in range is the primary's readings against the candidate's band.
+
+use crate::log_file;
+use log::*;
+use picool_core::{
+    config::Config,
+    controller::{step, ControllerState, Effect, Input},
+    temperature::Celsius,
+    world_error::SensorError,
+    Seed,
+};
+use std::{
+    fmt, io,
+    io::Write,
+    ops::Range,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// What the candidate logs is prefixed with it.
+const LABEL: &str = "candidate";
+const REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const REPORT_HEADER: &str = "timestamp,period_seconds,disagreed_seconds,primary_starts,candidate_starts,\
+                             primary_on_seconds,candidate_on_seconds,primary_in_range,candidate_in_range";
+
+/// How one controller ran, or would have run, the relay over a report period.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Tally {
+    pub starts: u32,
+    pub on_time: Duration,
+    readings: u32,
+    in_range: u32,
+}
+
+impl Tally {
+    /// `elapsed` since the last observation, during which the relay was `was_on`.
+    fn observe(&mut self, elapsed: Duration, was_on: bool, on: bool, reading: Option<Celsius>, band: Range<Celsius>) {
+        if was_on {
+            self.on_time += elapsed;
+        }
+        if on && !was_on {
+            self.starts += 1;
+        }
+        if let Some(reading) = reading {
+            self.readings += 1;
+            if band.contains(&reading) {
+                self.in_range += 1;
+            }
+        }
+    }
+
+    /// Fraction of successful readings inside the band.
+    pub fn time_in_range(&self) -> f32 {
+        match self.readings {
+            0 => 0.0,
+            readings => self.in_range as f32 / readings as f32,
+        }
+    }
+}
+
+/// The comparison over one period.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Report {
+    pub period: Duration,
+    /// How long the two wanted the relay in different states.
+    pub disagreed: Duration,
+    pub primary: Tally,
+    pub candidate: Tally,
+}
+
+impl Report {
+    // Pure
+    fn row(&self, timestamp: f64) -> String {
+        format!(
+            "{:.0},{},{},{},{},{},{},{:.4},{:.4}",
+            timestamp,
+            self.period.as_secs(),
+            self.disagreed.as_secs(),
+            self.primary.starts,
+            self.candidate.starts,
+            self.primary.on_time.as_secs(),
+            self.candidate.on_time.as_secs(),
+            self.primary.time_in_range(),
+            self.candidate.time_in_range()
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = |d: Duration| d.as_secs() / 60;
+        write!(
+            f,
+            "Candidate over the last {}m: disagreed with the primary for {}m, {} starts (primary {}), {}m on \
+             (primary {}m), {:.1}% in range (primary {:.1}%)",
+            minutes(self.period),
+            minutes(self.disagreed),
+            self.candidate.starts,
+            self.primary.starts,
+            minutes(self.candidate.on_time),
+            minutes(self.primary.on_time),
+            self.candidate.time_in_range() * 100.0,
+            self.primary.time_in_range() * 100.0
+        )
+    }
+}
+
+/// Stepped with a copy of every input the primary gets. Its effects are never carried out, it persists nothing.
+pub struct Candidate {
+    /// None once it terminated, the primary carries on without it.
+    controller: Option<ControllerState>,
+    /// Each report as a CSV row.
+    reports: Option<Box<dyn Write>>,
+    primary: Tally,
+    candidate: Tally,
+    disagreed: Duration,
+    /// When the two were last compared, and whether each had the relay on.
+    last: (Instant, bool, bool),
+    period_start: Instant,
+    /// Unix seconds at `started`, the loop's clock may be a simulation's.
+    epoch: f64,
+    started: Instant,
+}
+
+impl Candidate {
+    pub fn new(seed: Seed, config: &Config, now: Instant, mut reports: Option<Box<dyn Write>>) -> Self {
+        let controller = log_file::labelled(LABEL, || ControllerState::new(seed, config, now));
+        // Both start from the same restored state, so with the relay the same way.
+        let on = controller.power_on();
+        if let Some(reports) = &mut reports {
+            if let Err(e) = writeln!(reports, "{}", REPORT_HEADER) {
+                warn!("Failed to write candidate report. {}", e);
+            }
+        }
+        Self {
+            controller: Some(controller),
+            reports,
+            primary: Tally::default(),
+            candidate: Tally::default(),
+            disagreed: Duration::from_secs(0),
+            last: (now, on, on),
+            period_start: now,
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            started: now,
+        }
+    }
+
+    /// Before the primary steps with `input`.
+    pub fn step(&mut self, input: &Input) {
+        let controller = match self.controller.take() {
+            Some(controller) => controller,
+            None => return,
+        };
+        self.controller = log_file::labelled(LABEL, || {
+            let (next, effects) = step(controller, copy(input));
+            for effect in effects {
+                match effect {
+                    Effect::Notify(event) => info!("Would notify: {}", event),
+                    Effect::Terminate(termination) => {
+                        warn!("Stopped comparing, the candidate would have exited. {}", termination);
+                        return None;
+                    }
+                    _ => {}
+                }
+            }
+            Some(next)
+        });
+    }
+
+    /// After the primary's effects are carried out, with the reading both stepped on.
+    pub fn compare(&mut self, now: Instant, primary: &ControllerState, reading: Option<Celsius>) {
+        let candidate = match &self.controller {
+            Some(candidate) => candidate,
+            None => return,
+        };
+        let (primary_on, candidate_on) = (primary.power_on(), candidate.power_on());
+        let (last, was_primary_on, was_candidate_on) = self.last;
+        let elapsed = now - last;
+        self.primary
+            .observe(elapsed, was_primary_on, primary_on, reading, primary.band());
+        self.candidate
+            .observe(elapsed, was_candidate_on, candidate_on, reading, candidate.band());
+        if was_primary_on != was_candidate_on {
+            self.disagreed += elapsed;
+        }
+        match (was_primary_on == was_candidate_on, primary_on == candidate_on) {
+            (true, false) => info!(
+                "Candidate would have the relay {} while the primary has it {}",
+                if candidate_on { "ON" } else { "OFF" },
+                if primary_on { "ON" } else { "OFF" }
+            ),
+            (false, true) => info!("Candidate agrees with the primary again"),
+            _ => {}
+        }
+        self.last = (now, primary_on, candidate_on);
+        if now - self.period_start >= REPORT_INTERVAL {
+            self.report(now);
+        }
+    }
+
+    fn report(&mut self, now: Instant) {
+        let report = Report {
+            period: now - self.period_start,
+            disagreed: self.disagreed,
+            primary: self.primary,
+            candidate: self.candidate,
+        };
+        info!("{}", report);
+        if let Some(reports) = &mut self.reports {
+            let timestamp = self.epoch + (now - self.started).as_secs_f64();
+            let written = writeln!(reports, "{}", report.row(timestamp));
+            if let Err(e) = written.and_then(|()| reports.flush()) {
+                warn!("Failed to write candidate report. {}", e);
+            }
+        }
+        self.primary = Tally::default();
+        self.candidate = Tally::default();
+        self.disagreed = Duration::from_secs(0);
+        self.period_start = now;
+    }
+}
+
+/// The same input for the candidate, the sensor error rebuilt as the original isn't Clone.
+fn copy(input: &Input) -> Input {
+    let temperature = match &input.temperature {
+        Ok(temperature) => Ok(*temperature),
+        Err(e) => Err(match e {
+            SensorError::Unreadable(path, e) => {
+                SensorError::Unreadable(path.clone(), io::Error::new(e.kind(), e.to_string()))
+            }
+            SensorError::Malformed(text) => SensorError::Malformed(text.clone()),
+            SensorError::NonFinite(value) => SensorError::NonFinite(*value),
+            SensorError::Missing(path) => SensorError::Missing(path.clone()),
+            SensorError::PermissionDenied(path) => SensorError::PermissionDenied(path.clone()),
+        }),
+    };
+    Input {
+        temperature,
+        now: input.now,
+        local: input.local,
+        ambient: input.ambient,
+        acknowledged: input.acknowledged,
+        reset_runtime: input.reset_runtime,
+        sensor_replaced: input.sensor_replaced,
+        boost: input.boost.clone(),
+        revert_timing: input.revert_timing,
+        shed: input.shed,
+        pump: input.pump,
+        current: input.current,
+        reset_failover: input.reset_failover,
+        reload: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use picool_core::{energy::LocalTime, State};
+    use std::{cell::RefCell, rc::Rc};
+
+    struct Rows(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Rows {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reading(temperature: f32, now: Instant) -> Input {
+        Input {
+            temperature: Ok(Celsius(temperature)),
+            now,
+            local: LocalTime::default(),
+            ambient: None,
+            acknowledged: false,
+            reset_runtime: false,
+            sensor_replaced: false,
+            boost: None,
+            revert_timing: false,
+            shed: false,
+            pump: None,
+            current: None,
+            reset_failover: false,
+            reload: None,
+        }
+    }
+
+    #[test]
+    fn reports_how_a_warmer_band_would_have_run() {
+        let start = Instant::now();
+        let primary_config = Config::default();
+        let mut candidate_config = Config::default();
+        candidate_config.band.target = Celsius(8.0)..Celsius(10.0);
+        let rows = Rc::new(RefCell::new(Vec::new()));
+        let mut candidate = Candidate::new(
+            Seed::new(State::Off),
+            &candidate_config,
+            start,
+            Some(Box::new(Rows(rows.clone()))),
+        );
+        let mut primary = ControllerState::new(Seed::new(State::Off), &primary_config, start);
+        // Warm enough for the primary to cool, not the candidate.
+        for i in 0..=120 {
+            let input = reading(6.0, start + Duration::from_secs(i * 30));
+            let now = input.now;
+            candidate.step(&input);
+            primary = step(primary, input).0;
+            candidate.compare(now, &primary, Some(Celsius(6.0)));
+        }
+        let rows = String::from_utf8(rows.borrow().clone()).unwrap();
+        let rows: Vec<&str> = rows.lines().collect();
+        assert_eq!(REPORT_HEADER, rows[0]);
+        assert_eq!(
+            "3600,3600,1,0,3600,0,0.0000,0.0000",
+            &rows[1][rows[1].find(',').unwrap() + 1..]
+        );
+        assert_eq!(2, rows.len());
+    }
+
+    #[test]
+    fn the_report_reads_in_minutes() {
+        let tally = |starts, on: u64, in_range| Tally {
+            starts,
+            on_time: Duration::from_secs(on * 60),
+            readings: 4,
+            in_range,
+        };
+        let report = Report {
+            period: Duration::from_secs(60 * 60),
+            disagreed: Duration::from_secs(12 * 60),
+            primary: tally(3, 30, 4),
+            candidate: tally(2, 24, 3),
+        };
+        assert_eq!(
+            "Candidate over the last 60m: disagreed with the primary for 12m, 2 starts (primary 3), 24m on \
+             (primary 30m), 75.0% in range (primary 100.0%)",
+            report.to_string()
+        );
+        assert_eq!("10,3600,720,3,2,1800,1440,1.0000,0.7500", report.row(10.0));
+    }
+}
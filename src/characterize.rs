@@ -0,0 +1,554 @@
+//! `picool characterize` runs an experiment on an empty chamber and fits the thermal model the simulation uses to
+//! it. The chamber warms with the compressor off, is pulled down for a while, then is followed while the tail of the
+//! cooling settles.
+
+use crate::{
+    demo_world::DemoParameters,
+    shutdown::Shutdown,
+    thermal_model::{Ambient, RcModel, ThermalModel},
+    World,
+};
+use anyhow::{bail, Result};
+use log::*;
+use picool_core::{
+    config::Config,
+    temperature::{Celsius, DegreesDelta, Rate},
+    validate_temperature, MinimumIntervals,
+};
+use std::{fmt, ops::Range, time::Duration};
+
+const SECONDS_PER_HOUR: f32 = 3600.0;
+/// However the phases are set, the chamber isn't left to the experiment for longer.
+const MAX_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+/// Below the band, where the pull down stops unless `--characterize-floor` says otherwise.
+const FLOOR_MARGIN: f32 = 1.0;
+/// Above the band, where the warming stops unless `--characterize-ceiling` says otherwise.
+const CEILING_MARGIN: f32 = 10.0;
+
+/// How long each phase runs at most, and the temperatures the chamber is kept between.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Experiment {
+    pub warm_for: Duration,
+    pub pull_down_for: Duration,
+    pub tail_for: Duration,
+    /// None is a degree below the band.
+    pub floor: Option<Celsius>,
+    /// None is ten degrees above the band.
+    pub ceiling: Option<Celsius>,
+}
+
+impl Default for Experiment {
+    fn default() -> Self {
+        Self {
+            warm_for: Duration::from_secs(60 * 120),
+            pull_down_for: Duration::from_secs(60 * 30),
+            tail_for: Duration::from_secs(60 * 30),
+            floor: None,
+            ceiling: None,
+        }
+    }
+}
+
+impl Experiment {
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        if self.warm_for.is_zero() || self.pull_down_for.is_zero() || self.tail_for.is_zero() {
+            bail!("Each phase of the characterization must last at least a minute.");
+        }
+        if self.warm_for + self.pull_down_for + self.tail_for > MAX_DURATION {
+            bail!(
+                "The characterization can last {}h at most.",
+                MAX_DURATION.as_secs() / 3600
+            );
+        }
+        // The warming is the rest the compressor gets before the pull down starts it.
+        if self.warm_for < config.intervals.off {
+            bail!(
+                "The warming must last at least the minimum off time, {}m.",
+                config.intervals.off.as_secs() / 60
+            );
+        }
+        let (floor, ceiling) = self.limits(&config.band.target);
+        if floor >= ceiling {
+            bail!("The floor {} must be below the ceiling {}.", floor, ceiling);
+        }
+        Ok(())
+    }
+
+    fn limits(&self, band: &Range<Celsius>) -> (Celsius, Celsius) {
+        (
+            self.floor.unwrap_or(Celsius(band.start.0 - FLOOR_MARGIN)),
+            self.ceiling.unwrap_or(Celsius(band.end.0 + CEILING_MARGIN)),
+        )
+    }
+}
+
+/// The readings of each phase as seconds since the experiment started and degrees.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Curves {
+    pub warming: Vec<(f32, f32)>,
+    pub cooling: Vec<(f32, f32)>,
+    pub tail: Vec<(f32, f32)>,
+    /// The mean of the ambient readings, when there is an ambient sensor.
+    pub ambient: Option<Celsius>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Phase {
+    Warming,
+    PullDown,
+    Tail,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Warming => "warming",
+            Phase::PullDown => "pull down",
+            Phase::Tail => "tail",
+        })
+    }
+}
+
+/// Runs the experiment, reading every `interval`. The relay is off whenever this returns.
+pub fn run(
+    world: &mut impl World,
+    experiment: &Experiment,
+    band: &Range<Celsius>,
+    interval: Duration,
+    shutdown: &Shutdown,
+) -> Result<Curves> {
+    let mut curves = Curves::default();
+    let mut ambient = vec![];
+    let phases = [
+        (Phase::Warming, experiment.warm_for),
+        (Phase::PullDown, experiment.pull_down_for),
+        (Phase::Tail, experiment.tail_for),
+    ];
+    let limits = experiment.limits(band);
+    let start = world.now();
+    let result = phases.iter().try_for_each(|&(phase, length)| {
+        world.set_power_state(phase == Phase::PullDown);
+        let began = world.now();
+        info!("Characterizing: the {} for up to {}m.", phase, length.as_secs() / 60);
+        loop {
+            if shutdown.is_requested() {
+                bail!("Interrupted.");
+            }
+            let temperature = world.get_temperature().and_then(validate_temperature)?;
+            if let Some(Ok(reading)) = world.get_ambient_temperature() {
+                ambient.push(reading.0);
+            }
+            let sample = ((world.now() - start).as_secs_f32(), temperature.0);
+            match phase {
+                Phase::Warming => curves.warming.push(sample),
+                Phase::PullDown => curves.cooling.push(sample),
+                Phase::Tail => curves.tail.push(sample),
+            }
+            match phase {
+                Phase::PullDown if temperature <= limits.0 => {
+                    info!("Reached the floor {}, the pull down ends early.", limits.0);
+                    break;
+                }
+                Phase::Warming | Phase::Tail if temperature >= limits.1 => {
+                    info!("Reached the ceiling {}, the {} ends early.", limits.1, phase);
+                    break;
+                }
+                _ => {}
+            }
+            if world.now() - began >= length {
+                break;
+            }
+            world.sleep(interval);
+        }
+        Ok(())
+    });
+    world.set_power_state(false);
+    if !ambient.is_empty() {
+        curves.ambient = Some(Celsius(ambient.iter().sum::<f32>() / ambient.len() as f32));
+    }
+    result.map(|()| curves)
+}
+
+/// What the experiment says about the chamber, the parameters of the simulation's RC model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Characterization {
+    pub initial: Celsius,
+    pub ambient: Celsius,
+    /// How slowly the chamber follows the ambient with the compressor off.
+    pub time_constant: Duration,
+    /// Degrees per hour the compressor removes.
+    pub capacity: f32,
+    /// How far the temperature kept falling after the compressor stopped, and for how long.
+    pub tail: DegreesDelta,
+    pub tail_for: Duration,
+}
+
+/// Settings that suit the chamber and band, None where the chamber doesn't cross the band on its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestions {
+    pub min_on: Option<Duration>,
+    pub min_off: Option<Duration>,
+    pub max_compensation: DegreesDelta,
+    /// Any narrower and the chamber warms across the band before the minimum off time is up.
+    pub narrowest_band: DegreesDelta,
+}
+
+// Pure
+/// Fits the warming to Newton's law of cooling, then the compressor's capacity against it, then measures the tail.
+pub fn fit(curves: &Curves) -> Result<Characterization> {
+    // A compressor that ran just before keeps cooling for a while, the warming starts at the lowest reading.
+    let lowest =
+        curves
+            .warming
+            .iter()
+            .enumerate()
+            .fold(0, |lowest, (i, sample)| match sample.1 < curves.warming[lowest].1 {
+                true => i,
+                false => lowest,
+            });
+    let warming = slopes(curves.warming.get(lowest..).unwrap_or_default());
+    if warming.len() < 2 {
+        bail!("Too few readings while warming to fit, warm for longer.");
+    }
+    let (ambient, per_second) = match curves.ambient {
+        // dT/dt = (ambient - T) / time constant, through the measured ambient.
+        Some(ambient) => {
+            let (xy, xx) = warming.iter().fold((0.0, 0.0), |(xy, xx), &(t, slope)| {
+                let x = ambient.0 - t;
+                (xy + x * slope, xx + x * x)
+            });
+            (ambient, xy / xx)
+        }
+        // dT/dt = ambient / time constant - T / time constant, both fitted.
+        None => {
+            let (slope, intercept) = regression(&warming);
+            (Celsius(-intercept / slope), -slope)
+        }
+    };
+    if !(per_second > 0.0 && per_second.is_finite() && ambient.is_finite()) {
+        bail!("The warming doesn't level off toward an ambient, warm for longer or add an ambient sensor.");
+    }
+    let cooling = slopes(&curves.cooling);
+    if cooling.is_empty() {
+        bail!("Too few readings while pulling down to fit, pull down for longer.");
+    }
+    let removed = cooling
+        .iter()
+        .map(|&(t, slope)| (ambient.0 - t) * per_second - slope)
+        .sum::<f32>()
+        / cooling.len() as f32;
+    if removed <= 0.0 {
+        bail!("The compressor didn't pull the temperature down.");
+    }
+    let (off_at, off) = *curves.cooling.last().expect("Invariant: Cooling has slopes.");
+    let (lowest_at, lowest) =
+        curves
+            .tail
+            .iter()
+            .copied()
+            .fold((off_at, off), |lowest, sample| match sample.1 < lowest.1 {
+                true => sample,
+                false => lowest,
+            });
+    Ok(Characterization {
+        initial: Celsius(curves.warming[0].1),
+        ambient,
+        time_constant: Duration::from_secs_f32(1.0 / per_second),
+        capacity: removed * SECONDS_PER_HOUR,
+        tail: DegreesDelta(off - lowest),
+        tail_for: Duration::from_secs_f32(lowest_at - off_at),
+    })
+}
+
+// Pure
+/// The slope between each pair of readings, at the temperature between them.
+fn slopes(curve: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    curve
+        .windows(2)
+        .filter(|pair| pair[1].0 > pair[0].0)
+        .map(|pair| {
+            (
+                (pair[0].1 + pair[1].1) / 2.0,
+                (pair[1].1 - pair[0].1) / (pair[1].0 - pair[0].0),
+            )
+        })
+        .collect()
+}
+
+// Pure
+/// Least squares (slope, intercept) of y on x.
+fn regression(points: &[(f32, f32)]) -> (f32, f32) {
+    let n = points.len() as f32;
+    let (x, y) = points.iter().fold((0.0, 0.0), |(x, y), p| (x + p.0, y + p.1));
+    let (mean_x, mean_y) = (x / n, y / n);
+    let (xy, xx) = points.iter().fold((0.0, 0.0), |(xy, xx), p| {
+        (
+            xy + (p.0 - mean_x) * (p.1 - mean_y),
+            xx + (p.0 - mean_x) * (p.0 - mean_x),
+        )
+    });
+    let slope = xy / xx;
+    (slope, mean_y - slope * mean_x)
+}
+
+impl Characterization {
+    /// Degrees per hour with the compressor off.
+    fn warming_rate(&self, temperature: Celsius) -> f32 {
+        (self.ambient.0 - temperature.0) / self.time_constant.as_secs_f32() * SECONDS_PER_HOUR
+    }
+
+    // Pure
+    pub fn suggest(&self, band: &Range<Celsius>, intervals: MinimumIntervals) -> Suggestions {
+        let middle = Celsius((band.start.0 + band.end.0) / 2.0);
+        let width = band.end.0 - band.start.0;
+        let (warming, cooling) = (self.warming_rate(middle), self.capacity - self.warming_rate(middle));
+        // Half the time the chamber takes to cross the band, so the minimums never hold the relay past it.
+        let half_crossing = |rate: f32, least: Duration| match rate > 0.0 {
+            true => Some(whole_minutes(width / rate / 2.0 * SECONDS_PER_HOUR).max(least)),
+            false => None,
+        };
+        Suggestions {
+            min_on: half_crossing(cooling, Duration::from_secs(60)),
+            min_off: half_crossing(warming, Duration::from_secs(60 * 5)),
+            // The compensation lifts the low threshold by the tail, with room for the tail to vary.
+            max_compensation: DegreesDelta((self.tail.0 * 1.5 * 10.0).ceil().max(1.0) / 10.0),
+            narrowest_band: DegreesDelta(
+                self.tail.0 + warming.max(0.0) * intervals.off.as_secs_f32() / SECONDS_PER_HOUR,
+            ),
+        }
+    }
+
+    /// The simulation of the chamber, `picool simulate` takes it as the options `demo_options` gives.
+    pub fn demo(&self) -> DemoParameters {
+        DemoParameters {
+            initial_temperature: self.initial,
+            model: ThermalModel::Rc,
+            rc: RcModel {
+                ambient: Ambient {
+                    mean: self.ambient,
+                    ..Ambient::default()
+                },
+                time_constant: self.time_constant,
+                capacity: self.capacity,
+            },
+            latent_cooling: self.tail_for,
+            ..DemoParameters::default()
+        }
+    }
+
+    /// `--demo-*` options for the simulation of the chamber, in Celsius.
+    pub fn demo_options(&self) -> String {
+        let demo = self.demo();
+        let minutes = |duration: Duration| whole_minutes(duration.as_secs_f32()).as_secs() / 60;
+        format!(
+            "--demo-model rc --demo-initial-temp {:.1} --demo-ambient {:.1} --demo-time-constant {} \
+             --demo-capacity {:.1} --demo-latent-cooling {}",
+            demo.initial_temperature.0,
+            demo.rc.ambient.mean.0,
+            minutes(demo.rc.time_constant),
+            demo.rc.capacity,
+            minutes(demo.latent_cooling)
+        )
+    }
+}
+
+fn whole_minutes(seconds: f32) -> Duration {
+    Duration::from_secs((seconds / 60.0).round() as u64 * 60)
+}
+
+impl fmt::Display for Characterization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ambient {}, time constant {}m, compressor capacity {}, tail {} over {}s",
+            self.ambient,
+            self.time_constant.as_secs() / 60,
+            Rate::new(self.capacity, "/h"),
+            self.tail,
+            self.tail_for.as_secs()
+        )
+    }
+}
+
+impl fmt::Display for Suggestions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = |interval: Option<Duration>| match interval {
+            Some(interval) => (interval.as_secs() / 60).to_string(),
+            None => "as configured".into(),
+        };
+        write!(
+            f,
+            "--min-on {} --min-off {} --max-compensation {:.1}, a band at least {} wide",
+            minutes(self.min_on),
+            minutes(self.min_off),
+            self.max_compensation.0,
+            self.narrowest_band
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demo_world::DemoWorld;
+
+    fn minutes(m: u64) -> Duration {
+        Duration::from_secs(m * 60)
+    }
+
+    /// A chamber in a 22C room, slower to warm and stronger than the simulation's defaults.
+    fn chamber() -> DemoParameters {
+        DemoParameters {
+            initial_temperature: Celsius(3.0),
+            model: ThermalModel::Rc,
+            rc: RcModel {
+                ambient: Ambient {
+                    mean: Celsius(22.0),
+                    ..Ambient::default()
+                },
+                time_constant: minutes(150),
+                capacity: 20.0,
+            },
+            latent_cooling: minutes(4),
+            ..DemoParameters::default()
+        }
+    }
+
+    fn characterize(parameters: DemoParameters, experiment: &Experiment) -> Curves {
+        let mut world = DemoWorld::new(parameters).unwrap();
+        let band = Config::default().band.target;
+        run(
+            &mut world,
+            experiment,
+            &band,
+            Duration::from_secs(30),
+            &Shutdown::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn fits_the_simulated_chamber() {
+        let curves = characterize(chamber(), &Experiment::default());
+        assert_eq!(Some(Celsius(22.0)), curves.ambient);
+        // Without an ambient sensor the ambient is where the warming levels off to.
+        let unmeasured = fit(&Curves {
+            ambient: None,
+            ..curves.clone()
+        })
+        .unwrap();
+        assert!((unmeasured.ambient.0 - 22.0).abs() < 0.5, "{}", unmeasured);
+        let found = fit(&curves).unwrap();
+        assert_eq!(Celsius(3.0), found.initial);
+        assert!(
+            (found.time_constant.as_secs_f32() / 60.0 - 150.0).abs() < 5.0,
+            "{}",
+            found
+        );
+        assert!((found.capacity - 20.0).abs() < 0.5, "{}", found);
+        // The minimum falls on the first reading after the latent cooling.
+        assert!(found.tail_for >= minutes(4) && found.tail_for <= minutes(4) + Duration::from_secs(30));
+        let off = curves.cooling.last().unwrap().1;
+        let latent = (20.0 - (22.0 - off) / 2.5) * 4.0 / 60.0;
+        assert!((found.tail.0 - latent).abs() < 0.15, "{} vs {}", found, latent);
+
+        // What's fitted simulates the same chamber.
+        let again = fit(&characterize(found.demo(), &Experiment::default())).unwrap();
+        assert!((again.capacity - found.capacity).abs() < 0.5, "{} vs {}", again, found);
+    }
+
+    #[test]
+    fn keeps_between_the_floor_and_the_ceiling() {
+        let experiment = Experiment {
+            floor: Some(Celsius(2.0)),
+            ceiling: Some(Celsius(5.0)),
+            ..Experiment::default()
+        };
+        let curves = characterize(chamber(), &experiment);
+        let (end_of_warming, end_of_cooling) = (curves.warming.last().unwrap(), curves.cooling.last().unwrap());
+        assert!(
+            end_of_warming.1 >= 5.0 && end_of_warming.1 < 5.1,
+            "{:?}",
+            end_of_warming
+        );
+        assert!(end_of_warming.0 < minutes(120).as_secs_f32());
+        assert!(
+            end_of_cooling.1 <= 2.0 && end_of_cooling.1 > 1.8,
+            "{:?}",
+            end_of_cooling
+        );
+        assert!(curves.warming.iter().chain(&curves.cooling).all(|&(_, t)| t < 5.1));
+    }
+
+    #[test]
+    fn fits_through_a_measured_ambient() {
+        let curve = |from: f32, rate: fn(f32) -> f32| {
+            let mut samples = vec![(0.0, from)];
+            for i in 1..60 {
+                let (_, t) = samples[i - 1];
+                samples.push((i as f32 * 60.0, t + rate(t) * 60.0));
+            }
+            samples
+        };
+        // 20C ambient, 100 minute time constant, 18C/h capacity.
+        let warming = curve(4.0, |t| (20.0 - t) / 6000.0);
+        let start = warming.last().unwrap().1;
+        let mut cooling = curve(start, |t| (20.0 - t) / 6000.0 - 18.0 / 3600.0);
+        for sample in &mut cooling {
+            sample.0 += 3600.0;
+        }
+        let off = cooling.last().unwrap();
+        let tail = vec![
+            (off.0 + 60.0, off.1 - 0.2),
+            (off.0 + 120.0, off.1 - 0.25),
+            (off.0 + 180.0, off.1),
+        ];
+        let curves = Curves {
+            warming,
+            cooling,
+            tail,
+            ambient: Some(Celsius(20.0)),
+        };
+        let found = fit(&curves).unwrap();
+        assert_eq!(Celsius(20.0), found.ambient);
+        assert!((found.time_constant.as_secs_f32() - 6000.0).abs() < 100.0, "{}", found);
+        assert!((found.capacity - 18.0).abs() < 0.2, "{}", found);
+        assert!((found.tail.0 - 0.25).abs() < 0.001);
+        assert_eq!(Duration::from_secs(120), found.tail_for);
+
+        let suggested = found.suggest(&(Celsius(2.0)..Celsius(4.0)), MinimumIntervals::default());
+        // Crossing 2C at 7.8C/h cooling and 10.2C/h warming.
+        assert_eq!(Some(minutes(8)), suggested.min_on);
+        assert_eq!(Some(minutes(6)), suggested.min_off);
+        assert_eq!(DegreesDelta(0.4), suggested.max_compensation);
+
+        let flat = Curves {
+            ambient: None,
+            warming: (0..10).map(|i| (i as f32 * 60.0, 4.0 + i as f32 * 0.1)).collect(),
+            ..curves
+        };
+        assert!(fit(&flat).is_err());
+    }
+
+    #[test]
+    fn experiment_is_bounded() {
+        let config = Config::default();
+        assert!(Experiment::default().validate(&config).is_ok());
+        let long = Experiment {
+            warm_for: minutes(60 * 24),
+            ..Experiment::default()
+        };
+        assert!(long.validate(&config).is_err());
+        let restless = Experiment {
+            warm_for: minutes(1),
+            ..Experiment::default()
+        };
+        assert!(restless.validate(&config).is_err());
+        let inverted = Experiment {
+            floor: Some(Celsius(10.0)),
+            ceiling: Some(Celsius(5.0)),
+            ..Experiment::default()
+        };
+        assert!(inverted.validate(&config).is_err());
+    }
+}
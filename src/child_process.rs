@@ -0,0 +1,164 @@
+//! Running the external commands picool can be configured with, bounded in time and without leaving zombies.
+
+use log::*;
+use std::{
+    ffi::CString,
+    fmt,
+    io::{self, Read},
+    os::unix::{
+        ffi::OsStrExt,
+        io::AsRawFd,
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a running command is checked for having exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// More than any command's answer needs, the rest of a chatty command's output is dropped.
+const MAX_OUTPUT: u64 = 4096;
+
+/// A command that exited in time, successfully or not.
+#[derive(Debug)]
+pub struct Finished {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Finished {
+    /// The exit status with the last line of stderr, unless it succeeded.
+    pub fn failure(&self) -> Option<String> {
+        match self.status.success() {
+            true => None,
+            false => Some(format!("{}{}", describe(self.status), last_line(&self.stderr))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Spawn(io::Error),
+    TimedOut(Duration),
+    Wait(io::Error),
+}
+
+impl RunError {
+    /// As an io::Error of the matching kind, to go where other I/O failures do.
+    pub fn into_io(self) -> io::Error {
+        match self {
+            RunError::Spawn(e) | RunError::Wait(e) => e,
+            RunError::TimedOut(_) => io::Error::new(io::ErrorKind::TimedOut, self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Spawn(e) => write!(f, "failed to start: {}", e),
+            RunError::TimedOut(timeout) => write!(f, "no answer within {}ms", timeout.as_millis()),
+            RunError::Wait(e) => write!(f, "failed waiting for it: {}", e),
+        }
+    }
+}
+
+/// Fails with NotFound or PermissionDenied unless the user picool runs as may execute `command`.
+pub fn executable(command: &Path) -> io::Result<()> {
+    let path = CString::new(command.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    // SAFETY: access only reads the nul terminated path, which outlives the call.
+    match unsafe { libc::access(path.as_ptr(), libc::X_OK) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Runs `command` with stdin closed and its output captured, killing it and anything it started if it outlasts
+/// `timeout`. Files picool opens are close-on-exec, the command inherits none of them.
+pub fn run(mut command: Command, timeout: Duration) -> Result<Finished, RunError> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so a timeout also kills whatever the command started.
+        .process_group(0)
+        .spawn()
+        .map_err(RunError::Spawn)?;
+    let status = match wait_until(&mut child, Instant::now() + timeout) {
+        Ok(Some(status)) => status,
+        Ok(None) => {
+            kill(&mut child);
+            return Err(RunError::TimedOut(timeout));
+        }
+        Err(e) => {
+            kill(&mut child);
+            return Err(RunError::Wait(e));
+        }
+    };
+    Ok(Finished {
+        status,
+        stdout: drain(child.stdout.take()),
+        stderr: drain(child.stderr.take()),
+    })
+}
+
+/// The exit status once the child exits, None if it's still running at `deadline`. Reaps it either way it exits.
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(EXIT_POLL_INTERVAL);
+    }
+}
+
+/// Kills the child's process group and reaps the child, so no zombie is left behind.
+fn kill(child: &mut Child) {
+    // SAFETY: kill takes no pointers, the group was created for this child.
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    if let Err(e) = child.wait() {
+        warn!("Reaping command {} failed. {}", child.id(), e);
+    }
+}
+
+/// Whatever the pipe holds now. A background process the command left behind may keep it open, so this doesn't wait
+/// for the end of it.
+fn drain<P: Read + AsRawFd>(pipe: Option<P>) -> String {
+    let pipe = match pipe {
+        Some(pipe) => pipe,
+        None => return String::new(),
+    };
+    // SAFETY: fcntl only changes the flags of a descriptor the pipe owns.
+    unsafe {
+        let flags = libc::fcntl(pipe.as_raw_fd(), libc::F_GETFL);
+        libc::fcntl(pipe.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+    let mut output = Vec::new();
+    // WouldBlock ends the read with what is already in.
+    let _ = pipe.take(MAX_OUTPUT).read_to_end(&mut output);
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+// Pure
+fn describe(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with code {}", code),
+        (None, Some(signal)) => format!("killed by signal {}", signal),
+        _ => "failed".into(),
+    }
+}
+
+// Pure
+/// `: <line>` for the last line the command wrote to stderr, if any.
+fn last_line(stderr: &str) -> String {
+    match stderr.lines().map(str::trim).rfind(|line| !line.is_empty()) {
+        Some(line) => format!(": {}", line),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,1805 @@
+use crate::{
+    characterize::Experiment,
+    config_file::ConfigFile,
+    cycle_history::CycleFilter,
+    demo_world::DemoParameters,
+    gpio::{BackendKind, GpioConfig},
+    ina219::CurrentSpec,
+    log_file::{LogFormat, Rotation},
+    pushgateway::Gateway,
+    real_world::SensorSpec,
+    replay_world::ReplayParameters,
+    shed_signal::ShedSource,
+    statsd::Dialect,
+    timeline::Format,
+    w1::{Resolution, SensorFormat},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use picool_core::{
+    boost::{Boost, BoostRequest},
+    config::{Config, Preset},
+    early_shutoff::TailEstimate,
+    temperature::{Celsius, DegreesDelta, Units},
+    StartMode,
+};
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+const SIMULATE_COMMAND: &str = "simulate";
+const REPLAY_COMMAND: &str = "replay";
+const COMPARE_COMMAND: &str = "compare";
+const WATCH_COMMAND: &str = "watch";
+const BOOST_COMMAND: &str = "boost";
+const CHARACTERIZE_COMMAND: &str = "characterize";
+const CYCLES_COMMAND: &str = "cycles";
+const CHECK_COMMAND: &str = "check";
+const STATUS_COMMAND: &str = "status";
+const RESET_COMPENSATION_COMMAND: &str = "reset-compensation";
+/// The highest BCM number on the Pi's 40 pin header.
+const HEADER_MAX_PIN: u8 = 27;
+
+pub const USAGE: &str = "\
+Usage:
+  picool --sensor-path <temperature file> --power-pin <gpio> [options]
+  picool --sensor <spec> --power-pin <gpio> [options]
+  picool simulate [options]
+  picool replay <trace> [options]
+  picool characterize --sensor-path <temperature file> --power-pin <gpio> [options]
+  picool check --sensor-path <temperature file> --power-pin <gpio> [options]
+  picool status --sensor-path <temperature file> [options]
+  picool reset-compensation --sensor-path <temperature file> [--set-cooling <degrees> --set-heating <degrees>]
+  picool compare --compare-a <options> --compare-b <options> [options]
+  picool watch <control socket>
+  picool boost <control socket> <bottom> <top> <minutes> | cancel
+  picool cycles <history> [--since <span>] [--min-duration <span>] [--abnormal-only] [--csv]
+
+Chamber:
+  --sensor-path <path>     The DS18B20's temperature file, or any file with a temperature in it
+  --sensor <spec>          modbus:<device>?..., hwmon:<chip>/<label>, exec:<command> or a path
+  --power-pin <gpio>       The compressor relay's GPIO pin
+  --preset <name>          Start from a preset, --list-presets shows them
+  --config <path>          Read the band, intervals, sensor and pin from a TOML file, options override it
+
+Common options:
+  --world <real|demo|replay>, --units <c|f>, --target-low <degrees>, --target-high <degrees>,
+  --min-on <minutes>, --min-off <minutes>,
+  --fan-pin <gpio>, --pump-pin <gpio>, --backup-pin <gpio>, --current-sensor <spec>,
+  --gpio-backend <rppal|cdev|exec:...>, --log-file <path>, --log-format <text|json>, --run-as <user:group>,
+  --state-dir <path>,
+  --self-test, --check-config, --shadow or --dry-run
+
+  -h, --help               Show this and exit
+
+The sensor and the pin may also be given as the first two arguments, `picool <temperature file> <gpio>`.
+Every option is described in the README.
+";
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum WorldKind {
+    #[default]
+    Real,
+    Demo,
+    Replay,
+}
+
+impl FromStr for WorldKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "real" => Ok(WorldKind::Real),
+            "demo" => Ok(WorldKind::Demo),
+            "replay" => Ok(WorldKind::Replay),
+            _ => bail!("Unknown world '{}', expected real, demo or replay.", s),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Args {
+    pub positional: Vec<String>,
+    pub world: WorldKind,
+    pub run_as: Option<String>,
+    pub self_test: bool,
+    pub check_config: bool,
+    pub pulse_relay: Option<Duration>,
+    /// Run the controller without ever switching the relay.
+    pub shadow: bool,
+    /// Where a shadow run writes its decisions, as `timestamp,temperature,power` rows.
+    pub shadow_log: Option<PathBuf>,
+    /// A second configuration, the shared options plus its own, run next to the primary without the relay.
+    pub candidate: Option<Config>,
+    /// Where the hourly comparisons with the candidate are written as CSV rows.
+    pub candidate_report: Option<PathBuf>,
+    pub heartbeat_file: Option<PathBuf>,
+    /// Where the state files are kept, None for systemd's `StateDirectory=` or /var/lib/picool.
+    pub state_dir: Option<PathBuf>,
+    /// A last off transition persisted before this date is treated as garbage, None keeps the default.
+    pub clock_floor: Option<Duration>,
+    /// Where the relay state machine starts, whatever the previous run left.
+    pub initial_state: StartMode,
+    pub log_file: Option<PathBuf>,
+    pub log_rotation: Rotation,
+    pub log_format: LogFormat,
+    /// The chamber sensor, `--sensor-path`, `--sensor` or the first positional argument.
+    pub sensor: Option<SensorSpec>,
+    /// The GPIO pin of the compressor relay, `--power-pin` or the positional argument after the sensor.
+    pub power_pin: Option<u8>,
+    /// Print the usage and exit.
+    pub help: bool,
+    /// How the chamber file writes the temperature, detected from its reading when not given.
+    pub sensor_format: Option<SensorFormat>,
+    /// The DS18B20's resolution, set at startup.
+    pub sensor_resolution: Option<Resolution>,
+    pub ambient_sensor: Option<PathBuf>,
+    /// The GPIO pin of the circulation fan.
+    pub fan_pin: Option<u8>,
+    /// The GPIO pin of the second compressor.
+    pub stage2_pin: Option<u8>,
+    /// The GPIO pin of the glycol pump.
+    pub pump_pin: Option<u8>,
+    /// The GPIO pin of the backup compressor relay.
+    pub backup_pin: Option<u8>,
+    /// Measures the compressor's current.
+    pub current_sensor: Option<CurrentSpec>,
+    pub gpio: GpioConfig,
+    /// Run detached on every state change.
+    pub transition_hook: Option<PathBuf>,
+    /// Sheds load while asserted.
+    pub shed_signal: Option<ShedSource>,
+    /// The `host:port` of a StatsD daemon to send metrics to every poll.
+    pub statsd: Option<String>,
+    pub statsd_dialect: Dialect,
+    /// A Pushgateway to push metrics to every `push_interval`, None for the default.
+    pub push_gateway: Option<Gateway>,
+    pub push_interval: Option<Duration>,
+    /// Holds `<user>:<password>` for the Pushgateway's basic auth.
+    pub push_auth_file: Option<PathBuf>,
+    /// The `host:port` to serve the `/ws` stream of the status and events on.
+    pub ws_listen: Option<String>,
+    pub max_cycles: Option<u32>,
+    pub config: Config,
+    pub demo: DemoParameters,
+    pub scenario: Option<PathBuf>,
+    pub replay: ReplayParameters,
+    pub replay_output: Option<PathBuf>,
+    /// A timeline of a simulation or replay, DOT or HTML by its extension.
+    pub export_timeline: Option<PathBuf>,
+    /// The two configurations to replay a trace under, each the shared options plus its own.
+    pub compare: Option<(Config, Config)>,
+    pub json: bool,
+    pub list_presets: bool,
+    /// The `--config` file the tunables were read from.
+    pub config_file: Option<PathBuf>,
+    /// Show a running daemon's status, from the control socket given as the first positional argument.
+    pub watch: bool,
+    pub tui: bool,
+    /// Sent to a running daemon on the control socket given as the first positional argument.
+    pub boost: Option<BoostRequest>,
+    /// Print what the daemon would restore from the chamber's state files.
+    pub status: bool,
+    /// Clear the chamber's learned compensation.
+    pub reset_compensation: bool,
+    /// What `reset_compensation` seeds the (cooling, heating) compensation with instead of clearing it.
+    pub seed_compensation: Option<(DegreesDelta, DegreesDelta)>,
+    /// Print the cycles the filter lets through from the history given as the first positional argument.
+    pub cycles: Option<CycleFilter>,
+    pub csv: bool,
+    /// How many cycles the daemon keeps in its history, None keeps the default.
+    pub cycle_history: Option<usize>,
+    /// Run an experiment on the empty chamber and fit a thermal model to it.
+    pub characterize: bool,
+    pub experiment: Experiment,
+    /// How temperatures are shown, command line temperatures are read in `--input-units`.
+    pub units: Units,
+}
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args> {
+    parse_onto(Args::default(), args)
+}
+
+fn parse_onto<I: IntoIterator<Item = String>>(mut parsed: Args, args: I) -> Result<Args> {
+    let mut sides = (String::new(), String::new());
+    let mut candidate = None;
+    let mut confirmed_initial_state = false;
+    let mut cycle_filter = None;
+    // A band moved from the preset's keeps the compensation to half its width unless that's given too.
+    let mut band_moved = false;
+    let mut compensation_given = false;
+    let (mut set_cooling, mut set_heating) = (None, None);
+    let args: Vec<String> = args.into_iter().collect();
+    // These shape how the rest are read, whichever order the options come in.
+    if let Some(preset) = last_value(&args, "--preset") {
+        let preset: Preset = preset.parse()?;
+        parsed.config = preset.config();
+        parsed.demo = DemoParameters::for_preset(preset);
+    }
+    if let Some(path) = last_value(&args, "--config") {
+        let path = PathBuf::from(path);
+        let file = ConfigFile::load(&path)?;
+        file.apply(&mut parsed.config);
+        band_moved = file.target_start.is_some() || file.target_end.is_some();
+        compensation_given = file.max_compensation.is_some();
+        parsed.sensor = file.sensor_path.map(SensorSpec::W1);
+        parsed.power_pin = file.power_pin;
+        parsed.config_file = Some(path);
+    }
+    let input = match last_value(&args, "--input-units") {
+        None | Some("c") => Units::Celsius,
+        Some("f") => Units::Fahrenheit,
+        Some(value) => bail!("Option --input-units expects c or f, got '{}'.", value),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" {
+            parsed.help = true;
+            continue;
+        }
+        if !arg.starts_with("--") {
+            parsed.positional.push(arg);
+            continue;
+        }
+        let (flag, inline_value) = match arg.find('=') {
+            Some(i) => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+            None => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| anyhow!("Option {} requires a value.", flag))
+        };
+        match flag.as_str() {
+            "--world" => parsed.world = value()?.parse()?,
+            "--units" => parsed.units = value()?.parse()?,
+            "--preset" | "--config" | "--input-units" => {
+                value()?;
+            }
+            "--min-on" => parsed.config.intervals.on = parse_minutes(&flag, &value()?)?,
+            "--min-off" => parsed.config.intervals.off = parse_minutes(&flag, &value()?)?,
+            "--target-low" => {
+                parsed.config.band.target.start = parse_temperature(&flag, &value()?, input)?;
+                band_moved = true;
+            }
+            "--target-high" => {
+                parsed.config.band.target.end = parse_temperature(&flag, &value()?, input)?;
+                band_moved = true;
+            }
+            "--set-cooling" => set_cooling = Some(DegreesDelta(parse_degrees(&flag, &value()?, input)?)),
+            "--set-heating" => set_heating = Some(DegreesDelta(parse_degrees(&flag, &value()?, input)?)),
+            "--max-compensation" => {
+                parsed.config.band.max_compensation = DegreesDelta(parse_degrees(&flag, &value()?, input)?);
+                compensation_given = true;
+            }
+            "--list-presets" => parsed.list_presets = true,
+            "--low-engage" => {
+                parsed.config.hysteresis.low_engage = DegreesDelta(parse_degrees(&flag, &value()?, input)?)
+            }
+            "--low-release" => {
+                parsed.config.hysteresis.low_release = DegreesDelta(parse_degrees(&flag, &value()?, input)?)
+            }
+            "--high-engage" => {
+                parsed.config.hysteresis.high_engage = DegreesDelta(parse_degrees(&flag, &value()?, input)?)
+            }
+            "--high-release" => {
+                parsed.config.hysteresis.high_release = DegreesDelta(parse_degrees(&flag, &value()?, input)?)
+            }
+            "--run-as" => parsed.run_as = Some(value()?),
+            "--self-test" => parsed.self_test = true,
+            "--shadow" | "--dry-run" => parsed.shadow = true,
+            "--shadow-log" => parsed.shadow_log = Some(PathBuf::from(value()?)),
+            "--candidate" => candidate = Some(value()?),
+            "--candidate-report" => parsed.candidate_report = Some(PathBuf::from(value()?)),
+            "--check-config" => parsed.check_config = true,
+            "--heartbeat-file" => parsed.heartbeat_file = Some(PathBuf::from(value()?)),
+            "--state-dir" => parsed.state_dir = Some(PathBuf::from(value()?)),
+            "--clock-floor" => parsed.clock_floor = Some(parse_date(&flag, &value()?)?),
+            "--initial-state" => parsed.initial_state = value()?.parse()?,
+            "--confirm-initial-state" => confirmed_initial_state = true,
+            "--log-file" => parsed.log_file = Some(PathBuf::from(value()?)),
+            "--log-max-size" => parsed.log_rotation.max_size = parse_megabytes(&flag, &value()?)?,
+            "--log-keep" => parsed.log_rotation.keep = parse_count(&flag, &value()?)?,
+            "--log-format" => parsed.log_format = value()?.parse()?,
+            "--help" => parsed.help = true,
+            "--sensor" => parsed.sensor = Some(value()?.parse()?),
+            "--sensor-path" => parsed.sensor = Some(SensorSpec::W1(PathBuf::from(value()?))),
+            "--power-pin" => parsed.power_pin = Some(parse_pin(&flag, &value()?)?),
+            "--sensor-format" => parsed.sensor_format = Some(value()?.parse()?),
+            "--sensor-resolution" => parsed.sensor_resolution = Some(value()?.parse()?),
+            "--ambient-sensor" => parsed.ambient_sensor = Some(PathBuf::from(value()?)),
+            "--ambient-trim" => parsed.config.ambient_trim.gain = parse_amount(&flag, &value()?)?,
+            "--ambient-reference" => parsed.config.ambient_trim.reference = parse_temperature(&flag, &value()?, input)?,
+            "--ambient-trim-limit" => {
+                parsed.config.ambient_trim.limit = DegreesDelta(parse_degrees(&flag, &value()?, input)?)
+            }
+            "--differential" => parsed.config.differential.below = Some(parse_differential(&value()?, input)?),
+            "--differential-min" => parsed.config.differential.floor = parse_temperature(&flag, &value()?, input)?,
+            "--differential-max" => parsed.config.differential.ceiling = parse_temperature(&flag, &value()?, input)?,
+            "--fan-pin" => {
+                parsed.fan_pin = Some(parse_pin(&flag, &value()?)?);
+                parsed.config.fan.enabled = true;
+            }
+            "--stage2-pin" => {
+                parsed.stage2_pin = Some(parse_pin(&flag, &value()?)?);
+                parsed.config.staging.enabled = true;
+            }
+            "--stage2-offset" => parsed.config.staging.offset = DegreesDelta(parse_degrees(&flag, &value()?, input)?),
+            "--stage2-after" => {
+                parsed.config.staging.after = Some(parse_minutes(&flag, &value()?)?).filter(|after| !after.is_zero())
+            }
+            "--stage2-release" => parsed.config.staging.release = DegreesDelta(parse_degrees(&flag, &value()?, input)?),
+            "--stage2-min-on" => parsed.config.staging.intervals.on = parse_minutes(&flag, &value()?)?,
+            "--stage2-min-off" => parsed.config.staging.intervals.off = parse_minutes(&flag, &value()?)?,
+            "--pump-pin" => {
+                parsed.pump_pin = Some(parse_pin(&flag, &value()?)?);
+                parsed.config.pump.enabled = true;
+            }
+            "--pump-lead" => parsed.config.pump.lead = parse_seconds(&flag, &value()?)?,
+            "--pump-overrun" => parsed.config.pump.overrun = parse_minutes(&flag, &value()?)?,
+            "--backup-pin" => {
+                parsed.backup_pin = Some(parse_pin(&flag, &value()?)?);
+                parsed.config.failover.enabled = true;
+            }
+            "--failover-after" => parsed.config.failover.after = parse_count(&flag, &value()?)?,
+            "--failover-retry" => parsed.config.failover.retry = Some(parse_minutes(&flag, &value()?)?),
+            "--current-sensor" => {
+                parsed.current_sensor = Some(value()?.parse()?);
+                parsed.config.current.enabled = true;
+            }
+            "--current-running" => parsed.config.current.running = parse_amount(&flag, &value()?)?,
+            "--current-idle" => parsed.config.current.idle = parse_amount(&flag, &value()?)?,
+            "--current-start-grace" => parsed.config.current.start_grace = parse_seconds(&flag, &value()?)?,
+            "--current-stop-grace" => parsed.config.current.stop_grace = parse_seconds(&flag, &value()?)?,
+            "--gpio-backend" | "--switch" => parsed.gpio.backend = value()?.parse()?,
+            "--gpio-chip" => parsed.gpio.set_chip(&value()?),
+            "--active-low" => parsed.gpio.active_low = true,
+            "--relay-spacing" => parsed.gpio.spacing = parse_seconds(&flag, &value()?)?,
+            "--statsd" => parsed.statsd = Some(parse_host_port(&flag, &value()?)?),
+            "--statsd-dialect" => parsed.statsd_dialect = value()?.parse()?,
+            "--push-gateway" => parsed.push_gateway = Some(value()?.parse()?),
+            "--push-interval" => match parse_seconds(&flag, &value()?)? {
+                interval if interval.is_zero() => bail!("Option --push-interval expects at least a second."),
+                interval => parsed.push_interval = Some(interval),
+            },
+            "--push-auth-file" => parsed.push_auth_file = Some(PathBuf::from(value()?)),
+            "--ws-listen" => parsed.ws_listen = Some(parse_host_port(&flag, &value()?)?),
+            "--on-transition-hook" => parsed.transition_hook = Some(parse_command(&flag, &value()?)?),
+            "--stir-every" => parsed.config.fan.stir_every = Some(parse_minutes(&flag, &value()?)?),
+            "--stir-for" => parsed.config.fan.stir_for = parse_minutes(&flag, &value()?)?,
+            "--stir-settle" => parsed.config.fan.settle = Some(parse_minutes(&flag, &value()?)?),
+            "--mode" => parsed.config.mode = value()?.parse()?,
+            "--tpc-period" => parsed.config.tpc.period = parse_minutes(&flag, &value()?)?,
+            "--tpc-gain" => parsed.config.tpc.gain = parse_per_degree(&flag, &value()?, input)?,
+            "--tpc-integral" => parsed.config.tpc.integral_gain = parse_per_degree(&flag, &value()?, input)?,
+            "--early-shutoff" => parsed.config.early_shutoff = parse_tail(&value()?, input)?,
+            "--max-cycles" => parsed.max_cycles = Some(parse_count(&flag, &value()?)?),
+            "--pulse-relay" => parsed.pulse_relay = Some(parse_millis(&flag, &value()?)?),
+            "--sensor-failsafe" => parsed.config.sensor_failsafe.policy = value()?.parse()?,
+            "--sensor-failsafe-after" => parsed.config.sensor_failsafe.after = parse_minutes(&flag, &value()?)?,
+            "--sensor-failsafe-budget" => parsed.config.sensor_failsafe.budget = parse_minutes(&flag, &value()?)?,
+            "--sensor-reinit-attempts" => {
+                parsed.config.sensor_failsafe.reinitialize_attempts = parse_count(&flag, &value()?)?
+            }
+            "--runaway-after" => parsed.config.runaway.max_on = parse_minutes(&flag, &value()?)?,
+            "--runaway-retry-after" => parsed.config.runaway.auto_retry_after = Some(parse_minutes(&flag, &value()?)?),
+            "--duty-alert" => parsed.config.duty_alert.threshold = parse_percent(&flag, &value()?)?,
+            "--duty-alert-window" => parsed.config.duty_alert.window = parse_minutes(&flag, &value()?)?,
+            "--duty-alert-grace" => parsed.config.duty_alert.pull_down_grace = parse_minutes(&flag, &value()?)?,
+            "--duty-alert-clear-margin" => parsed.config.duty_alert.clear_margin = parse_percent(&flag, &value()?)?,
+            "--sample-interval" => parsed.config.sampling.interval = parse_seconds(&flag, &value()?)?,
+            "--decision-interval" => parsed.config.sampling.decision_interval = Some(parse_seconds(&flag, &value()?)?),
+            "--decision-aggregate" => parsed.config.sampling.aggregate = value()?.parse()?,
+            "--stall-after" => parsed.config.stall.polls = parse_count(&flag, &value()?)?,
+            "--stall-abort" => parsed.config.stall.abort = true,
+            "--cap-alert-after" => parsed.config.cap_alert.cycles = parse_count(&flag, &value()?)?,
+            "--alarm-min-active" => parsed.config.incidents.min_active = parse_minutes(&flag, &value()?)?,
+            "--alarm-cooldown" => parsed.config.incidents.cooldown = parse_minutes(&flag, &value()?)?,
+            "--timing-advisor" => parsed.config.timing.cycles = Some(parse_count(&flag, &value()?)?),
+            "--auto-apply-timing" => parsed.config.timing.auto_apply = true,
+            "--timing-floor-on" => parsed.config.timing.floor.on = parse_minutes(&flag, &value()?)?,
+            "--timing-floor-off" => parsed.config.timing.floor.off = parse_minutes(&flag, &value()?)?,
+            "--peak-windows" => {
+                parsed.config.peak.windows = value()?.split(',').map(str::parse).collect::<Result<_>>()?
+            }
+            "--peak-lead" => parsed.config.peak.lead = parse_minutes(&flag, &value()?)?,
+            "--peak-excursion" => parsed.config.peak.excursion = DegreesDelta(parse_degrees(&flag, &value()?, input)?),
+            "--quiet-hours" => parsed.config.quiet_hours.window = Some(value()?.parse()?),
+            "--quiet-guard" => parsed.config.quiet_hours.guard = DegreesDelta(parse_degrees(&flag, &value()?, input)?),
+            "--shed-signal" => parsed.shed_signal = Some(value()?.parse()?),
+            "--shed-max" => parsed.config.shed.max_duration = parse_minutes(&flag, &value()?)?,
+            "--shed-excursion" => parsed.config.shed.excursion = DegreesDelta(parse_degrees(&flag, &value()?, input)?),
+            "--shed-terminate" => parsed.config.shed.terminate = true,
+            "--rest-after" => parsed.config.rest.after = Some(parse_minutes(&flag, &value()?)?),
+            "--rest-for" => parsed.config.rest.rest = parse_minutes(&flag, &value()?)?,
+            "--rest-skip-boost" => parsed.config.rest.skip_boost = true,
+            "--storage-unreliable-after" => parsed.config.storage.unreliable_after = parse_count(&flag, &value()?)?,
+            "--storage-retry" => parsed.config.storage.retry = parse_minutes(&flag, &value()?)?,
+            "--checkpoint-max-age" => parsed.config.storage.checkpoint_max_age = parse_minutes(&flag, &value()?)?,
+            "--cooling-degraded" => parsed.config.cooling.degraded_fraction = parse_percent(&flag, &value()?)?,
+            "--cooling-clear-margin" => parsed.config.cooling.clear_margin = parse_percent(&flag, &value()?)?,
+            "--cooling-min-cycle" => parsed.config.cooling.minimum_cycle = parse_minutes(&flag, &value()?)?,
+            "--compressor-watts" => parsed.config.energy.compressor_watts = Some(parse_amount(&flag, &value()?)?),
+            "--standby-watts" => parsed.config.energy.standby_watts = parse_amount(&flag, &value()?)?,
+            "--price-per-kwh" => parsed.config.energy.price_per_kwh = Some(parse_amount(&flag, &value()?)?),
+            "--characterize-warm" => parsed.experiment.warm_for = parse_minutes(&flag, &value()?)?,
+            "--characterize-pull-down" => parsed.experiment.pull_down_for = parse_minutes(&flag, &value()?)?,
+            "--characterize-tail" => parsed.experiment.tail_for = parse_minutes(&flag, &value()?)?,
+            "--characterize-floor" => parsed.experiment.floor = Some(parse_temperature(&flag, &value()?, input)?),
+            "--characterize-ceiling" => parsed.experiment.ceiling = Some(parse_temperature(&flag, &value()?, input)?),
+            "--demo-initial-temp" => parsed.demo.initial_temperature = parse_temperature(&flag, &value()?, input)?,
+            "--demo-model" => parsed.demo.model = value()?.parse()?,
+            "--demo-ambient" => parsed.demo.rc.ambient.mean = parse_temperature(&flag, &value()?, input)?,
+            "--demo-ambient-swing" => parsed.demo.rc.ambient.swing = parse_degrees(&flag, &value()?, input)?,
+            "--demo-ambient-period" => parsed.demo.rc.ambient.period = parse_minutes(&flag, &value()?)?,
+            "--demo-ambient-drift" => parsed.demo.rc.ambient.drift = parse_degrees(&flag, &value()?, input)?,
+            "--demo-time-constant" => parsed.demo.rc.time_constant = parse_minutes(&flag, &value()?)?,
+            "--demo-capacity" => parsed.demo.rc.capacity = parse_degrees(&flag, &value()?, input)?,
+            "--demo-stage2-capacity" => parsed.demo.stage2_capacity = parse_degrees(&flag, &value()?, input)?,
+            "--demo-compressor-current" => parsed.demo.compressor_current = parse_amount(&flag, &value()?)?,
+            "--demo-warming-rate" => parsed.demo.warming_rate = parse_degrees(&flag, &value()?, input)?,
+            "--demo-cooling-rate" => parsed.demo.cooling_rate = parse_degrees(&flag, &value()?, input)?,
+            "--demo-latent-cooling" => parsed.demo.latent_cooling = parse_minutes(&flag, &value()?)?,
+            "--demo-time-warp" => parsed.demo.time_warp = Some(parse_number(&flag, &value()?)?),
+            "--demo-fault-seed" => parsed.demo.faults.seed = parse_seed(&flag, &value()?)?,
+            "--demo-read-failures" => parsed.demo.faults.failure = parse_percent(&flag, &value()?)?,
+            "--demo-failure-bursts" => parsed.demo.faults.burst = parse_percent(&flag, &value()?)?,
+            "--demo-burst-length" => parsed.demo.faults.burst_length = parse_count(&flag, &value()?)?,
+            "--demo-spikes" => parsed.demo.faults.spike = parse_percent(&flag, &value()?)?,
+            "--demo-spike-size" => parsed.demo.faults.spike_size = parse_degrees(&flag, &value()?, input)?,
+            "--demo-reset-values" => parsed.demo.faults.reset = parse_percent(&flag, &value()?)?,
+            "--demo-stuck-values" => parsed.demo.faults.stuck = parse_percent(&flag, &value()?)?,
+            "--demo-stuck-length" => parsed.demo.faults.stuck_length = parse_minutes(&flag, &value()?)?,
+            "--scenario" => parsed.scenario = Some(PathBuf::from(value()?)),
+            "--replay-max-gap" => parsed.replay.max_gap = parse_minutes(&flag, &value()?)?,
+            "--replay-time-warp" => parsed.replay.time_warp = Some(parse_number(&flag, &value()?)?),
+            "--replay-output" => parsed.replay_output = Some(PathBuf::from(value()?)),
+            "--export-timeline" => {
+                let path = PathBuf::from(value()?);
+                Format::of(&path)?;
+                parsed.export_timeline = Some(path);
+            }
+            "--compare-a" => sides.0 = value()?,
+            "--compare-b" => sides.1 = value()?,
+            "--json" => parsed.json = true,
+            "--csv" => parsed.csv = true,
+            "--since" => {
+                cycle_filter.get_or_insert_with(CycleFilter::default).since = Some(parse_span(&flag, &value()?)?)
+            }
+            "--min-duration" => {
+                cycle_filter.get_or_insert_with(CycleFilter::default).min_duration = Some(parse_span(&flag, &value()?)?)
+            }
+            "--abnormal-only" => cycle_filter.get_or_insert_with(CycleFilter::default).abnormal_only = true,
+            "--cycle-history" => match parse_count(&flag, &value()?)? {
+                0 => bail!("Option --cycle-history has to keep at least one cycle."),
+                keep => parsed.cycle_history = Some(keep as usize),
+            },
+            "--tui" => parsed.tui = true,
+            _ => bail!("Unknown option {}.", flag),
+        }
+    }
+    if band_moved && !compensation_given {
+        parsed.config.band.max_compensation = parsed.config.band.width() / 2.0;
+    }
+    parsed.config.validate()?;
+    if parsed.stage2_pin.is_some() && parsed.stage2_pin == parsed.fan_pin {
+        bail!(
+            "Stage 2 needs a pin of its own, the fan is on pin {}.",
+            parsed.stage2_pin.unwrap_or_default()
+        );
+    }
+    let outputs = [("the fan", parsed.fan_pin), ("stage 2", parsed.stage2_pin)];
+    if let Some((other, pin)) = outputs.iter().find(|(_, pin)| pin.is_some() && *pin == parsed.pump_pin) {
+        bail!(
+            "The pump needs a pin of its own, {} is on pin {}.",
+            other,
+            pin.unwrap_or_default()
+        );
+    }
+    let outputs = [
+        ("the fan", parsed.fan_pin),
+        ("stage 2", parsed.stage2_pin),
+        ("the pump", parsed.pump_pin),
+    ];
+    if let Some((other, pin)) = outputs
+        .iter()
+        .find(|(_, pin)| pin.is_some() && *pin == parsed.backup_pin)
+    {
+        bail!(
+            "The backup relay needs a pin of its own, {} is on pin {}.",
+            other,
+            pin.unwrap_or_default()
+        );
+    }
+    if parsed.shadow_log.is_some() && !parsed.shadow {
+        bail!("Option --shadow-log only applies with --shadow.");
+    }
+    if let Some(options) = candidate {
+        parsed.candidate = Some(parse_side(&parsed.config, &options).context("Invalid --candidate")?);
+    }
+    if (parsed.push_interval.is_some() || parsed.push_auth_file.is_some()) && parsed.push_gateway.is_none() {
+        bail!("Options --push-interval and --push-auth-file only apply with --push-gateway.");
+    }
+    if parsed.candidate_report.is_some() && parsed.candidate.is_none() {
+        bail!("Option --candidate-report only applies with --candidate.");
+    }
+    if parsed.initial_state.skips_lockout() && !confirmed_initial_state {
+        bail!(
+            "--initial-state {} may start the compressor before its minimum off interval is up, pass \
+             --confirm-initial-state to accept that.",
+            parsed.initial_state
+        );
+    }
+    if set_cooling.is_some() || set_heating.is_some() {
+        if parsed.positional.first().map(String::as_str) != Some(RESET_COMPENSATION_COMMAND) {
+            bail!("Options --set-cooling and --set-heating only apply to reset-compensation.");
+        }
+        let seed = (
+            set_cooling.unwrap_or(DegreesDelta::ZERO),
+            set_heating.unwrap_or(DegreesDelta::ZERO),
+        );
+        check_seed(seed, parsed.config.band.max_compensation)?;
+        parsed.seed_compensation = Some(seed);
+    }
+    let listing_cycles = parsed.positional.first().map(String::as_str) == Some(CYCLES_COMMAND);
+    if cycle_filter.is_some() && !listing_cycles {
+        bail!("Options --since, --min-duration and --abnormal-only only apply to cycles.");
+    }
+    if parsed.csv && !listing_cycles {
+        bail!("Option --csv only applies to cycles.");
+    }
+    match parsed.positional.first().map(String::as_str) {
+        Some(SIMULATE_COMMAND) => {
+            if let Some(extra) = parsed.positional.get(1) {
+                bail!("Simulate takes only options, got '{}'.", extra);
+            }
+            parsed.world = WorldKind::Demo
+        }
+        Some(REPLAY_COMMAND) => parsed.world = WorldKind::Replay,
+        Some(WATCH_COMMAND) => parsed.watch = true,
+        Some(CHARACTERIZE_COMMAND) => {
+            parsed.experiment.validate(&parsed.config)?;
+            parsed.characterize = true;
+        }
+        Some(CHECK_COMMAND) => parsed.check_config = true,
+        Some(STATUS_COMMAND) => parsed.status = true,
+        Some(RESET_COMPENSATION_COMMAND) => parsed.reset_compensation = true,
+        Some(CYCLES_COMMAND) => {
+            if parsed.positional.len() < 2 {
+                bail!("Cycles expects the daemon's history, /var/lib/picool/cycles_<sensor>.");
+            }
+            parsed.cycles = Some(cycle_filter.unwrap_or_default());
+        }
+        Some(BOOST_COMMAND) => parsed.boost = Some(parse_boost(parsed.positional.get(2..).unwrap_or_default(), input)?),
+        Some(COMPARE_COMMAND) => {
+            let a = parse_side(&parsed.config, &sides.0).context("Invalid --compare-a")?;
+            let b = parse_side(&parsed.config, &sides.1).context("Invalid --compare-b")?;
+            parsed.compare = Some((a, b));
+        }
+        _ => {
+            take_chamber(&mut parsed)?;
+            return Ok(parsed);
+        }
+    }
+    parsed.positional.remove(0);
+    if parsed.characterize || parsed.check_config || parsed.status || parsed.reset_compensation {
+        take_chamber(&mut parsed)?;
+    }
+    Ok(parsed)
+}
+
+/// The sensor and relay pin of the real world when they were given as arguments, `<temperature file> <gpio>`, or
+/// only `<gpio>` after `--sensor`. Anything more is a mistake.
+fn take_chamber(parsed: &mut Args) -> Result<()> {
+    if parsed.world != WorldKind::Real {
+        return Ok(());
+    }
+    let mut positional = parsed.positional.drain(..);
+    if parsed.sensor.is_none() {
+        parsed.sensor = positional.next().map(|path| SensorSpec::W1(PathBuf::from(path)));
+    }
+    if parsed.power_pin.is_none() {
+        if let Some(pin) = positional.next() {
+            let pin = pin
+                .parse()
+                .map_err(|_| anyhow!("Expected the relay's GPIO pin number, got '{}'.", pin))?;
+            parsed.power_pin = Some(pin);
+        }
+    }
+    if let Some(extra) = positional.next() {
+        bail!("Unexpected argument '{}'.", extra);
+    }
+    drop(positional);
+    check_header_pins(parsed)
+}
+
+/// The Pi's own GPIO only reaches BCM 27, a character device or a command may number its lines however it likes.
+fn check_header_pins(parsed: &Args) -> Result<()> {
+    if parsed.gpio.backend != BackendKind::Rppal {
+        return Ok(());
+    }
+    let pins = [
+        ("--power-pin", parsed.power_pin),
+        ("--fan-pin", parsed.fan_pin),
+        ("--stage2-pin", parsed.stage2_pin),
+        ("--pump-pin", parsed.pump_pin),
+        ("--backup-pin", parsed.backup_pin),
+    ];
+    match pins.iter().find(|(_, pin)| pin.is_some_and(|pin| pin > HEADER_MAX_PIN)) {
+        Some((flag, pin)) => bail!(
+            "Option {} has GPIO {}, the Pi's header only has BCM 0 to {}.",
+            flag,
+            pin.unwrap_or_default(),
+            HEADER_MAX_PIN
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Each compensator only ever moves its threshold into the band, cooling raising the low one and heating lowering
+/// the high one, and no further than the cap.
+fn check_seed((cooling, heating): (DegreesDelta, DegreesDelta), max_compensation: DegreesDelta) -> Result<()> {
+    if !(0.0..=max_compensation.0).contains(&cooling.0) {
+        bail!(
+            "Option --set-cooling has to be from 0 to the maximum compensation {}, got {}.",
+            max_compensation,
+            cooling
+        );
+    }
+    if !(-max_compensation.0..=0.0).contains(&heating.0) {
+        bail!(
+            "Option --set-heating has to be from minus the maximum compensation {} to 0, got {}.",
+            max_compensation,
+            heating
+        );
+    }
+    Ok(())
+}
+
+/// `<bottom> <top> <minutes>` in the input units, or `cancel`.
+fn parse_boost(args: &[String], units: Units) -> Result<BoostRequest> {
+    let request = match args {
+        [cancel] if cancel == "cancel" => BoostRequest::Cancel,
+        [bottom, top, minutes] => BoostRequest::Start(Boost {
+            band: parse_temperature("boost", bottom, units)?..parse_temperature("boost", top, units)?,
+            remaining: parse_minutes("boost", minutes)?,
+        }),
+        _ => bail!("Boost expects <socket> <bottom> <top> <minutes> or <socket> cancel."),
+    };
+    if let BoostRequest::Start(boost) = &request {
+        boost.validate()?;
+    }
+    Ok(request)
+}
+
+/// Options for one side of a comparison, on top of the shared ones.
+fn parse_side(shared: &Config, options: &str) -> Result<Config> {
+    let base = Args {
+        config: shared.clone(),
+        ..Args::default()
+    };
+    let side = parse_onto(base, options.split_whitespace().map(String::from))?;
+    if !side.positional.is_empty() {
+        bail!(
+            "Only options can differ between the sides, got '{}'.",
+            side.positional.join(" ")
+        );
+    }
+    if side.sensor.is_some() || side.power_pin.is_some() {
+        bail!("Only options can differ between the sides, not the sensor or the relay pin.");
+    }
+    Ok(side.config)
+}
+
+/// The value of the last `flag`. A missing value is reported with the rest of the options.
+fn last_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let mut found = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix(flag) {
+            Some("") => found = args.next().map(String::as_str).or(found),
+            Some(inline) => found = inline.strip_prefix('=').or(found),
+            None => {}
+        }
+    }
+    found
+}
+
+fn parse_temperature(flag: &str, value: &str, units: Units) -> Result<Celsius> {
+    let value = parse_number(flag, value)?;
+    Ok(match units {
+        Units::Fahrenheit => Celsius::from_fahrenheit(value),
+        _ => Celsius(value),
+    })
+}
+
+/// A difference or a rate, in degrees.
+fn parse_degrees(flag: &str, value: &str, units: Units) -> Result<f32> {
+    let value = parse_number(flag, value)?;
+    Ok(match units {
+        Units::Fahrenheit => DegreesDelta::from_fahrenheit(value).0,
+        _ => value,
+    })
+}
+
+/// `<bottom>,<top>`, degrees below the ambient.
+fn parse_differential(value: &str, units: Units) -> Result<(DegreesDelta, DegreesDelta)> {
+    let (bottom, top) = match value.split_once(',') {
+        Some(offsets) => offsets,
+        None => bail!(
+            "Option --differential expects two offsets below the ambient as <bottom>,<top>, got '{}'.",
+            value
+        ),
+    };
+    Ok((
+        DegreesDelta(parse_degrees("--differential", bottom.trim(), units)?),
+        DegreesDelta(parse_degrees("--differential", top.trim(), units)?),
+    ))
+}
+
+/// Off, learned or a fixed number of degrees.
+fn parse_tail(value: &str, units: Units) -> Result<TailEstimate> {
+    Ok(match (value.parse()?, units) {
+        (TailEstimate::Fixed(tail), Units::Fahrenheit) => TailEstimate::Fixed(DegreesDelta::from_fahrenheit(tail.0)),
+        (estimate, _) => estimate,
+    })
+}
+
+/// Percent of duty per degree, as a fraction per degree Celsius.
+fn parse_per_degree(flag: &str, value: &str, units: Units) -> Result<f32> {
+    let per_degree = parse_amount(flag, value)? / 100.0;
+    Ok(match units {
+        Units::Fahrenheit => per_degree * 1.8,
+        _ => per_degree,
+    })
+}
+
+fn parse_minutes(flag: &str, value: &str) -> Result<Duration> {
+    value
+        .parse::<u64>()
+        .map(|m| Duration::from_secs(m * 60))
+        .map_err(|_| anyhow!("Option {} expects minutes, got '{}'.", flag, value))
+}
+
+fn parse_seconds(flag: &str, value: &str) -> Result<Duration> {
+    value
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|_| anyhow!("Option {} expects seconds, got '{}'.", flag, value))
+}
+
+/// A span like `7d` or `10m`.
+fn parse_span(flag: &str, value: &str) -> Result<Duration> {
+    humantime::parse_duration(value)
+        .map_err(|_| anyhow!("Option {} expects a span like 7d or 10m, got '{}'.", flag, value))
+}
+
+/// A `YYYY-MM-DD` date, as the time since the epoch at its midnight UTC.
+fn parse_date(flag: &str, value: &str) -> Result<Duration> {
+    humantime::parse_rfc3339(&format!("{}T00:00:00Z", value))
+        .ok()
+        .and_then(|date| date.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .with_context(|| format!("Option {} expects a date like 2024-01-01, got '{}'.", flag, value))
+}
+
+fn parse_megabytes(flag: &str, value: &str) -> Result<u64> {
+    match value.parse::<u64>() {
+        Ok(megabytes) if megabytes > 0 => Ok(megabytes * 1024 * 1024),
+        _ => bail!(
+            "Option {} expects a positive number of megabytes, got '{}'.",
+            flag,
+            value
+        ),
+    }
+}
+
+fn parse_pin(flag: &str, value: &str) -> Result<u8> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Option {} expects a GPIO pin number, got '{}'.", flag, value))
+}
+
+fn parse_count(flag: &str, value: &str) -> Result<u32> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Option {} expects a count, got '{}'.", flag, value))
+}
+
+fn parse_seed(flag: &str, value: &str) -> Result<u64> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Option {} expects a whole number seed, got '{}'.", flag, value))
+}
+
+fn parse_number(flag: &str, value: &str) -> Result<f32> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Option {} expects a number, got '{}'.", flag, value))
+}
+
+fn parse_amount(flag: &str, value: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(amount) if amount >= 0.0 && amount.is_finite() => Ok(amount),
+        _ => bail!("Option {} expects a non-negative number, got '{}'.", flag, value),
+    }
+}
+
+fn parse_percent(flag: &str, value: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent / 100.0),
+        _ => bail!(
+            "Option {} expects a percentage between 0 and 100, got '{}'.",
+            flag,
+            value
+        ),
+    }
+}
+
+fn parse_millis(flag: &str, value: &str) -> Result<Duration> {
+    value
+        .parse()
+        .map(Duration::from_millis)
+        .map_err(|_| anyhow!("Option {} expects milliseconds, got '{}'.", flag, value))
+}
+
+fn parse_host_port(flag: &str, value: &str) -> Result<String> {
+    match value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(value.into()),
+        _ => bail!("Option {} expects <host>:<port>, got '{}'.", flag, value),
+    }
+}
+
+fn parse_command(flag: &str, value: &str) -> Result<PathBuf> {
+    if !value.starts_with('/') {
+        bail!("Option {} expects a command's absolute path, got '{}'.", flag, value);
+    }
+    Ok(PathBuf::from(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gpio::BackendKind, test_util::TempDir};
+    use picool_core::sampling::{Aggregate, SamplingConfig};
+
+    fn parse_str(args: &str) -> Result<Args> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn positional_arguments() {
+        let args = parse_str("/sys/bus/w1/devices/28-00/temperature 17").unwrap();
+        assert_eq!(
+            Some(SensorSpec::W1(PathBuf::from("/sys/bus/w1/devices/28-00/temperature"))),
+            args.sensor
+        );
+        assert_eq!(Some(17), args.power_pin);
+        assert!(args.positional.is_empty());
+        assert_eq!(None, args.run_as);
+        assert!(parse_str("/sensor seventeen").is_err());
+        assert!(parse_str("/sensor 17 18").is_err());
+    }
+
+    #[test]
+    fn named_chamber_arguments() {
+        let args = parse_str("--sensor-path /sys/bus/w1/devices/28-00/temperature --power-pin 17").unwrap();
+        assert_eq!(
+            Some(SensorSpec::W1(PathBuf::from("/sys/bus/w1/devices/28-00/temperature"))),
+            args.sensor
+        );
+        assert_eq!(Some(17), args.power_pin);
+        assert!(!args.help);
+        assert!(parse_str("-h").unwrap().help);
+        assert!(parse_str("simulate --help").unwrap().help);
+        assert!(parse_str("--power-pin").is_err());
+        assert!(parse_str("--power-pin 300").is_err());
+        assert_eq!(
+            "Option --power-pin expects a GPIO pin number, got 'abc'.",
+            format!("{:#}", parse_str("--power-pin abc").err().unwrap())
+        );
+        assert_eq!(
+            "Invalid --candidate: Option --min-on expects minutes, got 'x'.",
+            format!(
+                "{:#}",
+                parse(["/sensor", "17", "--candidate", "--min-on x"].map(String::from))
+                    .err()
+                    .unwrap()
+            )
+        );
+        // Past the header, only a character device or a command has lines numbered that high.
+        assert!(parse_str("--power-pin 40").is_err());
+        assert!(parse_str("--fan-pin 28").is_err());
+        assert_eq!(
+            Some(40),
+            parse_str("--gpio-backend cdev --power-pin 40").unwrap().power_pin
+        );
+        assert!(parse_str("simulate --demo-model rc").is_ok());
+        assert!(parse_str("simulate extra").is_err());
+    }
+
+    #[test]
+    fn target_range() {
+        let args = parse_str("--target-low 18 --target-high 20").unwrap();
+        assert_eq!(Celsius(18.0)..Celsius(20.0), args.config.band.target);
+        // Half the band, so the compensated thresholds can't cross.
+        assert_eq!(DegreesDelta(1.0), args.config.band.max_compensation);
+        let args = parse_str("--target-high 20 --max-compensation 0.5 --target-low 18").unwrap();
+        assert_eq!(DegreesDelta(0.5), args.config.band.max_compensation);
+        let args = parse_str("--input-units f --target-low 33.8 --target-high 39.2").unwrap();
+        assert!((args.config.band.target.start.0 - 1.0).abs() < 0.001);
+        assert!((args.config.band.target.end.0 - 4.0).abs() < 0.001);
+        // Options win over a preset.
+        let args = parse_str("--preset freezer --target-high -15").unwrap();
+        assert_eq!(Celsius(-20.0)..Celsius(-15.0), args.config.band.target);
+        assert_eq!(DegreesDelta(2.5), args.config.band.max_compensation);
+        assert_eq!(Config::default().band, parse_str("/sensor 17").unwrap().config.band);
+        for options in [
+            "--target-low 20 --target-high 18",
+            "--target-low 4 --target-high 4",
+            "--target-low 18",
+            "--target-low NaN --target-high 4",
+            "--target-low -inf --target-high 4",
+            "--target-low 1 --target-high inf",
+        ] {
+            assert!(parse_str(options).is_err(), "{}", options);
+        }
+    }
+
+    #[test]
+    fn config_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("picool.toml");
+        std::fs::write(
+            &path,
+            "sensor_path = \"/sys/bus/w1/devices/28-00/temperature\"\npower_pin = 17\nmin_off = \"10m\"\n\
+             poll_interval = \"30s\"\n[target]\nstart = 2.0\nend = 4.5\n",
+        )
+        .unwrap();
+        let args = parse_str(&format!("--config {}", path.display())).unwrap();
+        assert_eq!(Some(path.clone()), args.config_file);
+        assert_eq!(
+            Some(SensorSpec::W1(PathBuf::from("/sys/bus/w1/devices/28-00/temperature"))),
+            args.sensor
+        );
+        assert_eq!(Some(17), args.power_pin);
+        assert_eq!(Celsius(2.0)..Celsius(4.5), args.config.band.target);
+        assert_eq!(Duration::from_secs(10 * 60), args.config.intervals.off);
+        assert_eq!(Duration::from_secs(30), args.config.sampling.interval);
+        // Left out of the file, so still the default.
+        assert_eq!(Config::default().intervals.on, args.config.intervals.on);
+        // Options win whichever side of the file they're on, and the file wins over a preset.
+        for line in [
+            format!(
+                "--min-off 12 --power-pin 22 --config {} --preset freezer",
+                path.display()
+            ),
+            format!(
+                "--preset freezer --config={} --min-off 12 --power-pin 22",
+                path.display()
+            ),
+        ] {
+            let args = parse_str(&line).unwrap();
+            assert_eq!(Duration::from_secs(12 * 60), args.config.intervals.off);
+            assert_eq!(Some(22), args.power_pin);
+            assert_eq!(Celsius(2.0)..Celsius(4.5), args.config.band.target);
+            assert_eq!(Preset::Freezer.config().runaway, args.config.runaway);
+        }
+        // The start of the freezer's band above the end from the file.
+        std::fs::write(&path, "[target]\nend = -18.0\n").unwrap();
+        let error = format!(
+            "{:#}",
+            parse_str(&format!("--config {}", path.display())).err().unwrap()
+        );
+        assert!(error.contains("The target range has to run from a lower"), "{}", error);
+        std::fs::write(&path, "poll_interval = \"0s\"\n").unwrap();
+        let error = format!(
+            "{:#}",
+            parse_str(&format!("--config {}", path.display())).err().unwrap()
+        );
+        assert!(error.starts_with("Invalid config"), "{}", error);
+        assert!(error.contains("Duration must be positive"), "{}", error);
+        assert!(parse_str(&format!("--config {}", dir.path().join("missing.toml").display())).is_err());
+    }
+
+    #[test]
+    fn option_with_separate_and_inline_value() {
+        assert_eq!(
+            Some("picool:gpio".into()),
+            parse_str("--run-as picool:gpio").unwrap().run_as
+        );
+        assert_eq!(
+            Some("picool:gpio".into()),
+            parse_str("--run-as=picool:gpio").unwrap().run_as
+        );
+        assert_eq!(
+            Some(PathBuf::from("/home/pi/.picool")),
+            parse_str("--state-dir /home/pi/.picool").unwrap().state_dir
+        );
+    }
+
+    #[test]
+    fn self_test_flags() {
+        let args = parse_str("--self-test --pulse-relay 250 /sensor 17").unwrap();
+        assert!(args.self_test);
+        assert!(!args.check_config);
+        assert_eq!(Some(Duration::from_millis(250)), args.pulse_relay);
+        assert_eq!(Some(17), args.power_pin);
+        assert!(parse_str("--pulse-relay soon").is_err());
+        assert_eq!(Some(3), parse_str("--max-cycles 3").unwrap().max_cycles);
+    }
+
+    #[test]
+    fn sensor_failsafe_options() {
+        use picool_core::failsafe::FailsafePolicy;
+        let args = parse_str("--sensor-failsafe duty:20 --sensor-failsafe-after 15").unwrap();
+        assert_eq!(
+            FailsafePolicy::DutyCycle(Duration::from_secs(20 * 60)),
+            args.config.sensor_failsafe.policy
+        );
+        assert_eq!(Duration::from_secs(15 * 60), args.config.sensor_failsafe.after);
+        let args = parse_str("--sensor-failsafe-budget 30 --sensor-reinit-attempts 0").unwrap();
+        assert_eq!(Duration::from_secs(30 * 60), args.config.sensor_failsafe.budget);
+        assert_eq!(0, args.config.sensor_failsafe.reinitialize_attempts);
+        assert!(parse_str("--sensor-reinit-attempts -1").is_err());
+        assert!(parse_str("--sensor-failsafe maybe").is_err());
+    }
+
+    #[test]
+    fn energy_options() {
+        let args = parse_str("--compressor-watts 85 --standby-watts 1.5 --price-per-kwh 0.3").unwrap();
+        assert_eq!(Some(85.0), args.config.energy.compressor_watts);
+        assert_eq!(1.5, args.config.energy.standby_watts);
+        assert_eq!(Some(0.3), args.config.energy.price_per_kwh);
+        assert_eq!(None, parse_str("/sensor 17").unwrap().config.energy.compressor_watts);
+        assert!(parse_str("--compressor-watts -5").is_err());
+        assert!(parse_str("--price-per-kwh free").is_err());
+    }
+
+    #[test]
+    fn world_selection() {
+        assert_eq!(WorldKind::Real, parse_str("/sensor 17").unwrap().world);
+        assert_eq!(WorldKind::Demo, parse_str("--world demo").unwrap().world);
+        let args = parse_str("simulate --max-cycles 3 --scenario outage.toml").unwrap();
+        assert_eq!(WorldKind::Demo, args.world);
+        assert!(args.positional.is_empty());
+        assert_eq!(Some(PathBuf::from("outage.toml")), args.scenario);
+        let args = parse_str("replay log.csv --replay-max-gap 30 --replay-output out.csv").unwrap();
+        assert_eq!(WorldKind::Replay, args.world);
+        assert_eq!(vec!["log.csv".to_string()], args.positional);
+        assert_eq!(Duration::from_secs(30 * 60), args.replay.max_gap);
+        assert_eq!(Some(PathBuf::from("out.csv")), args.replay_output);
+        assert!(parse_str("--world mars").is_err());
+        let args = parse_str("watch /var/lib/picool/control_28-00.sock --tui").unwrap();
+        assert!(args.watch && args.tui);
+        assert_eq!(vec!["/var/lib/picool/control_28-00.sock"], args.positional);
+        assert!(!parse_str("/sensor 17").unwrap().watch);
+        let args = parse_str("simulate --export-timeline run.html").unwrap();
+        assert_eq!(Some(PathBuf::from("run.html")), args.export_timeline);
+        assert!(parse_str("replay log.csv --export-timeline run.svg").is_err());
+    }
+
+    #[test]
+    fn boost() {
+        let args = parse_str("boost /var/lib/picool/control_28-00.sock 34 36 90 --input-units f").unwrap();
+        assert_eq!(
+            vec!["/var/lib/picool/control_28-00.sock", "34", "36", "90"],
+            args.positional
+        );
+        match args.boost {
+            Some(BoostRequest::Start(boost)) => {
+                assert!((boost.band.start.0 - 1.111).abs() < 0.001 && (boost.band.end.0 - 2.222).abs() < 0.001);
+                assert_eq!(Duration::from_secs(90 * 60), boost.remaining);
+            }
+            other => panic!("Parsed {:?}.", other),
+        }
+        let args = parse_str("boost control.sock -20 -18 60").unwrap();
+        assert!(matches!(args.boost, Some(BoostRequest::Start(_))));
+        assert_eq!(
+            Some(BoostRequest::Cancel),
+            parse_str("boost control.sock cancel").unwrap().boost
+        );
+        assert!(parse_str("boost control.sock").is_err());
+        assert!(parse_str("boost control.sock 4 2 60").is_err());
+        assert!(parse_str("boost control.sock 2 4 0").is_err());
+        assert!(parse_str("boost control.sock 2 4 soon").is_err());
+        assert!(parse_str("/sensor 17").unwrap().boost.is_none());
+    }
+
+    #[test]
+    fn cycles() {
+        let args = parse_str("cycles /var/lib/picool/cycles_28-00 --since 7d --min-duration 10m --abnormal-only --csv")
+            .unwrap();
+        assert_eq!(vec!["/var/lib/picool/cycles_28-00"], args.positional);
+        assert_eq!(
+            Some(CycleFilter {
+                since: Some(Duration::from_secs(7 * 24 * 3600)),
+                min_duration: Some(Duration::from_secs(10 * 60)),
+                abnormal_only: true,
+            }),
+            args.cycles
+        );
+        assert!(args.csv);
+        assert_eq!(
+            Some(CycleFilter::default()),
+            parse_str("cycles history").unwrap().cycles
+        );
+        assert!(parse_str("cycles").is_err());
+        assert!(parse_str("cycles history --since lately").is_err());
+        assert!(parse_str("/sensor 17 --abnormal-only").is_err());
+        assert!(parse_str("/sensor 17 --csv").is_err());
+        assert_eq!(
+            Some(500),
+            parse_str("/sensor 17 --cycle-history 500").unwrap().cycle_history
+        );
+    }
+
+    #[test]
+    fn compare_sides() {
+        let args = vec![
+            "compare",
+            "log.csv",
+            "--compressor-watts",
+            "90",
+            "--compare-a",
+            "--min-off 10",
+            "--compare-b=--min-off 20 --standby-watts 2",
+            "--json",
+        ];
+        let args = parse(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(vec!["log.csv".to_string()], args.positional);
+        assert!(args.json);
+        let (a, b) = args.compare.unwrap();
+        assert_eq!(Duration::from_secs(10 * 60), a.intervals.off);
+        assert_eq!(Duration::from_secs(20 * 60), b.intervals.off);
+        assert_eq!(
+            (Some(90.0), Some(90.0)),
+            (a.energy.compressor_watts, b.energy.compressor_watts)
+        );
+        assert_eq!((0.0, 2.0), (a.energy.standby_watts, b.energy.standby_watts));
+        assert_eq!(
+            None,
+            parse_str("replay log.csv --compare-a=--min-off=10").unwrap().compare
+        );
+        assert!(parse_str("compare log.csv --compare-a=--min-off=soon").is_err());
+        assert!(parse_str("compare log.csv --compare-b=other.csv").is_err());
+    }
+
+    #[test]
+    fn demo_parameters() {
+        use crate::thermal_model::ThermalModel;
+        let args =
+            parse_str("simulate --demo-initial-temp 6.5 --demo-cooling-rate -12 --demo-latent-cooling 2").unwrap();
+        assert_eq!(Celsius(6.5), args.demo.initial_temperature);
+        assert_eq!(-12.0, args.demo.cooling_rate);
+        assert_eq!(Duration::from_secs(120), args.demo.latent_cooling);
+        assert_eq!(None, args.demo.time_warp);
+        assert_eq!(Some(200.0), parse_str("--demo-time-warp 200").unwrap().demo.time_warp);
+        assert!(parse_str("--demo-warming-rate fast").is_err());
+        let args = parse_str("--demo-model rc --demo-ambient 25 --demo-time-constant 90").unwrap();
+        assert_eq!(ThermalModel::Rc, args.demo.model);
+        assert_eq!(Celsius(25.0), args.demo.rc.ambient.mean);
+        assert_eq!(Duration::from_secs(90 * 60), args.demo.rc.time_constant);
+        let args = parse_str("--demo-ambient-swing 4 --demo-ambient-period 720 --demo-ambient-drift -0.5").unwrap();
+        assert_eq!(4.0, args.demo.rc.ambient.swing);
+        assert_eq!(Duration::from_secs(720 * 60), args.demo.rc.ambient.period);
+        assert_eq!(-0.5, args.demo.rc.ambient.drift);
+    }
+
+    #[test]
+    fn presets() {
+        use picool_core::config::Band;
+        assert_eq!(Band::default(), parse_str("/sensor 17").unwrap().config.band);
+        let args = parse_str("--runaway-after 90 --preset freezer").unwrap();
+        assert_eq!(Celsius(-20.0)..Celsius(-17.0), args.config.band.target);
+        assert_eq!(Duration::from_secs(90 * 60), args.config.runaway.max_on);
+        assert_eq!(Celsius(-16.0), args.demo.initial_temperature);
+        let args = parse_str("--preset=freezer --demo-initial-temp 20").unwrap();
+        assert_eq!(Celsius(20.0), args.demo.initial_temperature);
+        assert!(parse_str("--preset cellar").is_err());
+        assert!(parse_str("--preset incubator").is_err());
+        // Explicit options win whichever side of the preset they're on.
+        for options in [
+            "--preset keezer --min-off 12 --max-compensation 0.5",
+            "--min-off 12 --max-compensation 0.5 --preset keezer",
+        ] {
+            let args = parse_str(options).unwrap();
+            assert_eq!(Celsius(2.0)..Celsius(4.5), args.config.band.target);
+            assert_eq!(Duration::from_secs(12 * 60), args.config.intervals.off);
+            assert_eq!(DegreesDelta(0.5), args.config.band.max_compensation);
+            assert_eq!(Preset::Keezer.config().duty_alert, args.config.duty_alert);
+        }
+        assert!(parse_str("--max-compensation 0").is_err());
+        assert_eq!(0, parse_str("--cap-alert-after 0").unwrap().config.cap_alert.cycles);
+        let config = parse_str(
+            "--duty-alert-clear-margin 10 --cooling-clear-margin 20 --alarm-min-active 30 --alarm-cooldown 45",
+        )
+        .unwrap()
+        .config;
+        assert_eq!(
+            (0.1, 0.2),
+            (config.duty_alert.clear_margin, config.cooling.clear_margin)
+        );
+        assert_eq!(
+            (Duration::from_secs(30 * 60), Duration::from_secs(45 * 60)),
+            (config.incidents.min_active, config.incidents.cooldown)
+        );
+        assert!(parse_str("--cooling-clear-margin 120").is_err());
+        let quiet = parse_str("--quiet-hours 23:00-06:00 --quiet-guard 3 --input-units f")
+            .unwrap()
+            .config
+            .quiet_hours;
+        assert_eq!(Some("23:00-06:00".parse().unwrap()), quiet.window);
+        assert!((quiet.guard.0 - 5.0 / 3.0).abs() < 0.001);
+        assert!(parse_str("--quiet-hours 23:00").is_err());
+        assert!(parse_str("--quiet-guard -1").is_err());
+        let peak = parse_str("--peak-windows 07:00-09:00,17:00-20:00 --peak-lead 45 --peak-excursion 0.5")
+            .unwrap()
+            .config
+            .peak;
+        let windows: Vec<String> = peak.windows.iter().map(ToString::to_string).collect();
+        assert_eq!(vec!["07:00-09:00", "17:00-20:00"], windows);
+        assert_eq!(
+            (Duration::from_secs(45 * 60), DegreesDelta(0.5)),
+            (peak.lead, peak.excursion)
+        );
+        assert!(parse_str("--peak-windows 17:00-20:00,").is_err());
+        assert!(parse_str("--peak-windows 17:00-20:00 --mode tpc").is_err());
+        let shed = parse_str("--shed-signal gpio:22:low --shed-max 90 --shed-excursion 1.5 --shed-terminate").unwrap();
+        assert_eq!(
+            Some(ShedSource::Gpio {
+                pin: 22,
+                active_low: true
+            }),
+            shed.shed_signal
+        );
+        assert_eq!(
+            (Duration::from_secs(90 * 60), DegreesDelta(1.5), true),
+            (
+                shed.config.shed.max_duration,
+                shed.config.shed.excursion,
+                shed.config.shed.terminate
+            )
+        );
+        assert!(parse_str("--shed-signal mqtt:shed").is_err());
+        assert!(parse_str("--shed-max 0").is_err());
+        let storage = parse_str("--storage-unreliable-after 5 --storage-retry 30 --checkpoint-max-age 10")
+            .unwrap()
+            .config
+            .storage;
+        assert_eq!(5, storage.unreliable_after);
+        assert_eq!(Duration::from_secs(30 * 60), storage.retry);
+        assert_eq!(Duration::from_secs(10 * 60), storage.checkpoint_max_age);
+        assert_eq!(
+            Some(Duration::from_secs(1_704_067_200)),
+            parse_str("--clock-floor 2024-01-01").unwrap().clock_floor
+        );
+        assert!(parse_str("--clock-floor 2024-13-01").is_err());
+        assert!(parse_str("--clock-floor yesterday").is_err());
+        assert!(parse_str("--list-presets").unwrap().list_presets);
+    }
+
+    #[test]
+    fn temperature_units() {
+        assert_eq!(Units::Both, parse_str("/sensor 17").unwrap().units);
+        assert_eq!(Units::Fahrenheit, parse_str("--units f").unwrap().units);
+        assert!(parse_str("--units kelvin").is_err());
+        let args = parse_str("--demo-initial-temp 6.5 --demo-cooling-rate -12").unwrap();
+        assert_eq!(
+            (Celsius(6.5), -12.0),
+            (args.demo.initial_temperature, args.demo.cooling_rate)
+        );
+        // Converted whichever side of the temperatures the option is on.
+        for args in [
+            "--demo-initial-temp 50 --demo-cooling-rate -9 --input-units f",
+            "--input-units=f --demo-initial-temp 50 --demo-cooling-rate -9",
+        ] {
+            let args = parse_str(args).unwrap();
+            assert_eq!(Celsius(10.0), args.demo.initial_temperature);
+            assert_eq!(-5.0, args.demo.cooling_rate);
+            assert_eq!(Units::Both, args.units);
+        }
+        assert!(parse_str("--input-units both").is_err());
+        assert!(parse_str("--input-units").is_err());
+    }
+
+    #[test]
+    fn differential() {
+        let args = parse_str("").unwrap();
+        assert!(!args.config.differential.is_enabled());
+        let args = parse_str("--differential 4,2 --differential-min 8 --differential-max 16").unwrap();
+        assert_eq!(
+            Some((DegreesDelta(4.0), DegreesDelta(2.0))),
+            args.config.differential.below
+        );
+        assert_eq!(Celsius(8.0), args.config.differential.floor);
+        assert_eq!(Celsius(16.0), args.config.differential.ceiling);
+        let args = parse_str("--differential 9,5.4 --differential-max 59 --input-units f").unwrap();
+        assert_eq!(
+            Some((DegreesDelta(5.0), DegreesDelta(3.0))),
+            args.config.differential.below
+        );
+        assert_eq!(Celsius(15.0), args.config.differential.ceiling);
+        assert!(parse_str("--differential 3").is_err());
+        assert!(parse_str("--differential 2,4").is_err());
+    }
+
+    #[test]
+    fn log_file() {
+        let args = parse_str("").unwrap();
+        assert_eq!(None, args.log_file);
+        assert_eq!(Rotation::default(), args.log_rotation);
+        let args = parse_str("--log-file /var/log/picool.log --log-max-size 2 --log-keep 3").unwrap();
+        assert_eq!(Some(PathBuf::from("/var/log/picool.log")), args.log_file);
+        assert_eq!(
+            Rotation {
+                max_size: 2 * 1024 * 1024,
+                keep: 3
+            },
+            args.log_rotation
+        );
+        assert!(parse_str("--log-max-size 0").is_err());
+        assert_eq!(LogFormat::Text, parse_str("").unwrap().log_format);
+        assert_eq!(LogFormat::Json, parse_str("--log-format json").unwrap().log_format);
+        assert!(parse_str("--log-format yaml").is_err());
+    }
+
+    #[test]
+    fn fan() {
+        let args = parse_str("").unwrap();
+        assert!(!args.config.fan.enabled);
+        assert_eq!(None, args.fan_pin);
+        let args = parse_str("--fan-pin 27 --stir-every 30 --stir-for 3 --stir-settle 1").unwrap();
+        assert_eq!(Some(27), args.fan_pin);
+        assert!(args.config.fan.enabled);
+        assert_eq!(Some(Duration::from_secs(30 * 60)), args.config.fan.stir_every);
+        assert_eq!(Duration::from_secs(3 * 60), args.config.fan.stir_for);
+        assert_eq!(Some(Duration::from_secs(60)), args.config.fan.settle);
+        assert!(parse_str("--fan-pin 300").is_err());
+        assert!(parse_str("--stir-every 2").is_err());
+    }
+
+    #[test]
+    fn stage2() {
+        let args = parse_str("").unwrap();
+        assert!(!args.config.staging.enabled);
+        let args = parse_str(
+            "--stage2-pin 22 --stage2-offset 1.5 --stage2-after 45 --stage2-release 0.5 --stage2-min-on 5 \
+             --stage2-min-off 10",
+        )
+        .unwrap();
+        assert_eq!(Some(22), args.stage2_pin);
+        let staging = args.config.staging;
+        assert!(staging.enabled);
+        assert_eq!(
+            (DegreesDelta(1.5), Some(Duration::from_secs(45 * 60)), DegreesDelta(0.5)),
+            (staging.offset, staging.after, staging.release)
+        );
+        assert_eq!(
+            (Duration::from_secs(5 * 60), Duration::from_secs(10 * 60)),
+            (staging.intervals.on, staging.intervals.off)
+        );
+        assert_eq!(
+            None,
+            parse_str("--stage2-pin 22 --stage2-after 0")
+                .unwrap()
+                .config
+                .staging
+                .after
+        );
+        assert!(parse_str("--stage2-pin 22 --fan-pin 22").is_err());
+        // Wider than the band, stage 1 would stop first.
+        assert!(parse_str("--stage2-pin 22 --stage2-release 4").is_err());
+    }
+
+    #[test]
+    fn pump() {
+        let args = parse_str("").unwrap();
+        assert!(!args.config.pump.enabled);
+        let args = parse_str("--pump-pin 23 --pump-lead 45 --pump-overrun 5").unwrap();
+        assert_eq!(Some(23), args.pump_pin);
+        let pump = args.config.pump;
+        assert!(pump.enabled);
+        assert_eq!(
+            (Duration::from_secs(45), Duration::from_secs(5 * 60)),
+            (pump.lead, pump.overrun)
+        );
+        assert!(parse_str("--pump-pin 23 --fan-pin 23").is_err());
+        assert!(parse_str("--pump-pin 23 --stage2-pin 23").is_err());
+    }
+
+    #[test]
+    fn failover() {
+        let args = parse_str("").unwrap();
+        assert!(!args.config.failover.enabled);
+        let args =
+            parse_str("--backup-pin 24 --failover-after 5 --failover-retry 120 --current-sensor ina219:/dev/i2c-1")
+                .unwrap();
+        assert_eq!(Some(24), args.backup_pin);
+        let failover = args.config.failover;
+        assert!(failover.enabled);
+        assert_eq!(
+            (5, Some(Duration::from_secs(2 * 3600))),
+            (failover.after, failover.retry)
+        );
+        // Only the current tells the primary failed.
+        assert!(parse_str("--backup-pin 24").is_err());
+        assert!(parse_str("--backup-pin 24 --failover-after 0 --current-sensor ina219:/dev/i2c-1").is_err());
+        assert!(parse_str("--backup-pin 24 --pump-pin 24 --current-sensor ina219:/dev/i2c-1").is_err());
+    }
+
+    #[test]
+    fn current() {
+        use crate::ina219::Ina219Config;
+        let args = parse_str("").unwrap();
+        assert!(!args.config.current.enabled);
+        let args = parse_str(
+            "--current-sensor ina219:/dev/i2c-1?addr=0x41 --current-running 1.5 --current-idle 0.3 \
+             --current-start-grace 20 --current-stop-grace 45",
+        )
+        .unwrap();
+        assert_eq!(
+            Some(CurrentSpec::Ina219(Ina219Config {
+                device: PathBuf::from("/dev/i2c-1"),
+                address: 0x41,
+                shunt: 0.1
+            })),
+            args.current_sensor
+        );
+        let current = args.config.current;
+        assert!(current.enabled);
+        assert_eq!((1.5, 0.3), (current.running, current.idle));
+        assert_eq!(
+            (Duration::from_secs(20), Duration::from_secs(45)),
+            (current.start_grace, current.stop_grace)
+        );
+        // The idle current has to be under the running current.
+        assert!(parse_str("--current-sensor ina219:/dev/i2c-1 --current-running 0.1").is_err());
+    }
+
+    #[test]
+    fn hysteresis() {
+        use picool_core::config::Hysteresis;
+        assert_eq!(
+            Hysteresis::default(),
+            parse_str("/sensor 17").unwrap().config.hysteresis
+        );
+        let args = parse_str("--high-engage 0.3 --high-release 0.2 --low-engage 0.1 --low-release 0.4").unwrap();
+        assert_eq!(
+            Hysteresis {
+                low_engage: DegreesDelta(0.1),
+                low_release: DegreesDelta(0.4),
+                high_engage: DegreesDelta(0.3),
+                high_release: DegreesDelta(0.2),
+            },
+            args.config.hysteresis
+        );
+        assert!(parse_str("--high-engage -0.3").is_err());
+    }
+
+    #[test]
+    fn gpio() {
+        let args = parse_str("/sensor 17").unwrap();
+        assert_eq!(GpioConfig::default(), args.gpio);
+        let args = parse_str("/sensor 17 --gpio-backend cdev --gpio-chip gpiochip4 --active-low").unwrap();
+        assert_eq!(BackendKind::Cdev, args.gpio.backend);
+        assert_eq!(PathBuf::from("/dev/gpiochip4"), args.gpio.chip);
+        assert!(args.gpio.active_low);
+        assert_eq!(Duration::from_secs(5), args.gpio.spacing);
+        let args = parse_str("/sensor 17 --relay-spacing 2").unwrap();
+        assert_eq!(Duration::from_secs(2), args.gpio.spacing);
+        assert!(parse_str("--gpio-backend sysfs").is_err());
+        let args = parse_str("/sensor 17 --switch exec:/usr/local/bin/set-power.sh?retries=1").unwrap();
+        assert!(matches!(args.gpio.backend, BackendKind::Exec(config) if config.retries == 1));
+        assert!(parse_str("--switch exec:set-power.sh").is_err());
+        let args = parse_str("/sensor 17 --on-transition-hook /usr/local/bin/on-change.sh").unwrap();
+        assert_eq!(Some(PathBuf::from("/usr/local/bin/on-change.sh")), args.transition_hook);
+        assert!(parse_str("--on-transition-hook on-change.sh").is_err());
+    }
+
+    #[test]
+    fn statsd() {
+        let args = parse_str("/sensor 17 --statsd localhost:8125 --statsd-dialect dogstatsd").unwrap();
+        assert_eq!(Some("localhost:8125".into()), args.statsd);
+        assert_eq!(Dialect::Tagged, args.statsd_dialect);
+        assert!(parse_str("--statsd localhost").is_err());
+        assert!(parse_str("--statsd :8125").is_err());
+        assert!(parse_str("--statsd localhost:81250").is_err());
+    }
+
+    #[test]
+    fn push_gateway() {
+        let args =
+            parse_str("/sensor 17 --push-gateway http://push.lan --push-interval 30 --push-auth-file /etc/picool/push")
+                .unwrap();
+        assert_eq!(Some("http://push.lan:9091".parse().unwrap()), args.push_gateway);
+        assert_eq!(Some(Duration::from_secs(30)), args.push_interval);
+        assert_eq!(Some(PathBuf::from("/etc/picool/push")), args.push_auth_file);
+        assert!(parse_str("/sensor 17 --push-gateway https://push.lan").is_err());
+        assert!(parse_str("/sensor 17 --push-gateway http://push.lan --push-interval 0").is_err());
+        assert!(parse_str("/sensor 17 --push-interval 30").is_err());
+        assert!(parse_str("/sensor 17 --push-auth-file /etc/picool/push").is_err());
+    }
+
+    #[test]
+    fn ws_listen() {
+        assert_eq!(None, parse_str("/sensor 17").unwrap().ws_listen);
+        let args = parse_str("/sensor 17 --ws-listen 0.0.0.0:8080").unwrap();
+        assert_eq!(Some("0.0.0.0:8080".into()), args.ws_listen);
+        assert!(parse_str("/sensor 17 --ws-listen 8080").is_err());
+    }
+
+    #[test]
+    fn shadow() {
+        let args = parse_str("/sensor 17").unwrap();
+        assert!(!args.shadow);
+        let args = parse_str("/sensor 17 --shadow --shadow-log /var/log/picool/shadow.csv").unwrap();
+        assert!(args.shadow);
+        assert_eq!(Some(PathBuf::from("/var/log/picool/shadow.csv")), args.shadow_log);
+        assert!(parse_str("/sensor 17 --shadow-log shadow.csv").is_err());
+        assert!(parse_str("/sensor 17 --dry-run").unwrap().shadow);
+    }
+
+    #[test]
+    fn initial_state() {
+        assert_eq!(StartMode::Auto, parse_str("/sensor 17").unwrap().initial_state);
+        let locked = parse_str("/sensor 17 --initial-state off-locked").unwrap();
+        assert_eq!(StartMode::OffLocked, locked.initial_state);
+        // Only the modes that may cut the minimum off interval short need confirming.
+        assert!(parse_str("/sensor 17 --initial-state off-free").is_err());
+        assert!(parse_str("/sensor 17 --initial-state follow-relay").is_err());
+        let free = parse_str("/sensor 17 --initial-state off-free --confirm-initial-state").unwrap();
+        assert_eq!(StartMode::OffFree, free.initial_state);
+        assert!(parse_str("/sensor 17 --initial-state on").is_err());
+    }
+
+    #[test]
+    fn characterize() {
+        let args = parse_str(
+            "characterize /sensor 17 --characterize-warm 90 --characterize-pull-down 20 --characterize-floor 1",
+        )
+        .unwrap();
+        assert!(args.characterize);
+        assert_eq!(Some(SensorSpec::W1(PathBuf::from("/sensor"))), args.sensor);
+        assert_eq!(Some(17), args.power_pin);
+        assert_eq!(Duration::from_secs(90 * 60), args.experiment.warm_for);
+        assert_eq!(Duration::from_secs(20 * 60), args.experiment.pull_down_for);
+        assert_eq!(Some(Celsius(1.0)), args.experiment.floor);
+        assert_eq!(None, args.experiment.ceiling);
+        assert!(!parse_str("/sensor 17").unwrap().characterize);
+        let args = parse_str("check --sensor-path /sensor --power-pin 17").unwrap();
+        assert!(args.check_config);
+        assert!(!args.self_test);
+        assert_eq!(Some(SensorSpec::W1(PathBuf::from("/sensor"))), args.sensor);
+        assert_eq!(Some(17), args.power_pin);
+        assert!(args.positional.is_empty());
+        assert!(parse_str("check /sensor 17 18").is_err());
+        let args = parse_str("reset-compensation /sensor").unwrap();
+        assert!(args.reset_compensation);
+        assert_eq!(None, args.seed_compensation);
+        let args = parse_str("reset-compensation --sensor-path /sensor --set-cooling 0.5 --set-heating -0.25").unwrap();
+        assert_eq!(Some(SensorSpec::W1(PathBuf::from("/sensor"))), args.sensor);
+        assert_eq!(Some((DegreesDelta(0.5), DegreesDelta(-0.25))), args.seed_compensation);
+        let args = parse_str("reset-compensation /sensor --set-heating -0.9 --input-units f").unwrap();
+        assert_eq!(Some((DegreesDelta(0.0), DegreesDelta(-0.5))), args.seed_compensation);
+        let error = |args| format!("{:#}", parse_str(args).err().unwrap());
+        assert!(error("reset-compensation /sensor --set-cooling 1.5 --max-compensation 1").contains("from 0 to"));
+        assert!(error("reset-compensation /sensor --set-cooling NaN").contains("--set-cooling has to be"));
+        assert!(error("reset-compensation /sensor --set-heating 0.25").contains("--set-heating has to be"));
+        assert!(error("reset-compensation /sensor --set-heating -inf").contains("--set-heating has to be"));
+        assert!(error("/sensor 17 --set-cooling 0.5").contains("only apply to reset-compensation"));
+        let args = parse_str("status /sensor --state-dir /tmp/picool").unwrap();
+        assert!(args.status);
+        assert_eq!(Some(SensorSpec::W1(PathBuf::from("/sensor"))), args.sensor);
+        assert_eq!(None, args.power_pin);
+        assert!(parse_str("characterize /sensor 17 --characterize-warm 1").is_err());
+        assert!(parse_str("characterize --characterize-floor 5 --characterize-ceiling 4").is_err());
+    }
+
+    #[test]
+    fn timing_advisor() {
+        let args = parse_str("/sensor 17").unwrap();
+        assert_eq!(None, args.config.timing.cycles);
+        let args = parse_str("/sensor 17 --timing-advisor 20 --auto-apply-timing --timing-floor-off 5").unwrap();
+        assert_eq!(Some(20), args.config.timing.cycles);
+        assert!(args.config.timing.auto_apply);
+        assert_eq!(Duration::from_secs(60), args.config.timing.floor.on);
+        assert_eq!(Duration::from_secs(5 * 60), args.config.timing.floor.off);
+        assert!(parse_str("/sensor 17 --auto-apply-timing").is_err());
+        assert!(parse_str("/sensor 17 --timing-advisor 0").is_err());
+        assert!(parse_str("/sensor 17 --timing-advisor 20 --mode tpc").is_err());
+    }
+
+    #[test]
+    fn rest() {
+        assert_eq!(None, parse_str("/sensor 17").unwrap().config.rest.after);
+        let args = parse_str("/sensor 17 --rest-after 120 --rest-for 20 --rest-skip-boost").unwrap();
+        assert_eq!(Some(Duration::from_secs(120 * 60)), args.config.rest.after);
+        assert_eq!(Duration::from_secs(20 * 60), args.config.rest.rest);
+        assert!(args.config.rest.skip_boost);
+        assert!(parse_str("/sensor 17 --rest-skip-boost").is_err());
+        let error = parse_str("/sensor 17 --rest-after 120 --rest-for 10 --min-off 10")
+            .err()
+            .unwrap();
+        assert_eq!(
+            "A rest has to be longer than the minimum off interval of 10m, got 10m.",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn candidate() {
+        let args = vec![
+            "/sensor",
+            "17",
+            "--min-off",
+            "10",
+            "--candidate",
+            "--high-engage 1 --min-on 3",
+            "--candidate-report=candidate.csv",
+        ];
+        let args = parse(args.into_iter().map(String::from)).unwrap();
+        let candidate = args.candidate.unwrap();
+        assert_eq!(Duration::from_secs(10 * 60), candidate.intervals.off);
+        assert_eq!(Duration::from_secs(3 * 60), candidate.intervals.on);
+        assert_eq!(DegreesDelta(1.0), candidate.hysteresis.high_engage);
+        assert_ne!(args.config.intervals.on, candidate.intervals.on);
+        assert_eq!(Some(PathBuf::from("candidate.csv")), args.candidate_report);
+        assert!(parse_str("/sensor 17 --candidate=--min-on=soon").is_err());
+        assert!(parse_str("/sensor 17 --candidate=/other/sensor").is_err());
+        assert!(parse_str("/sensor 17 --candidate-report candidate.csv").is_err());
+    }
+
+    #[test]
+    fn sampling() {
+        let args = parse_str("/sensor 17").unwrap();
+        assert_eq!(SamplingConfig::default(), args.config.sampling);
+        let args =
+            parse_str("/sensor 17 --sample-interval 5 --decision-interval 60 --decision-aggregate median").unwrap();
+        assert_eq!(Duration::from_secs(5), args.config.sampling.interval);
+        assert_eq!(Some(Duration::from_secs(60)), args.config.sampling.decision_interval);
+        assert_eq!(Aggregate::Median, args.config.sampling.aggregate);
+        assert!(parse_str("/sensor 17 --sample-interval 5 --decision-interval 12").is_err());
+        assert!(parse_str("/sensor 17 --sample-interval 0").is_err());
+        assert!(parse_str("/sensor 17 --decision-aggregate mode").is_err());
+    }
+
+    #[test]
+    fn ambient_trim() {
+        let args = parse_str("/sensor 17").unwrap();
+        assert!(!args.config.ambient_trim.is_enabled());
+        assert_eq!(None, args.ambient_sensor);
+        let args = parse_str(
+            "--ambient-sensor /sys/bus/w1/devices/28-2/w1_slave --ambient-trim 0.05 --ambient-reference 22 \
+             --ambient-trim-limit 0.3",
+        )
+        .unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/sys/bus/w1/devices/28-2/w1_slave")),
+            args.ambient_sensor
+        );
+        assert_eq!(0.05, args.config.ambient_trim.gain);
+        assert_eq!(Celsius(22.0), args.config.ambient_trim.reference);
+        assert_eq!(DegreesDelta(0.3), args.config.ambient_trim.limit);
+        let args = parse_str("--ambient-reference 68 --input-units f").unwrap();
+        assert_eq!(Celsius(20.0), args.config.ambient_trim.reference);
+        assert!(parse_str("--ambient-trim warm").is_err());
+    }
+
+    #[test]
+    fn sensor_option() {
+        assert_eq!(
+            Some(SensorSpec::W1(PathBuf::from("/sensor"))),
+            parse_str("/sensor 17").unwrap().sensor
+        );
+        let args = parse_str("--sensor modbus:/dev/ttyUSB0?addr=1&reg=0x0000 17").unwrap();
+        match args.sensor {
+            Some(SensorSpec::Modbus(config)) => assert_eq!(PathBuf::from("/dev/ttyUSB0"), config.device),
+            _ => panic!("Expected a Modbus sensor."),
+        }
+        assert_eq!(Some(17), args.power_pin);
+        assert_eq!(
+            Some(SensorSpec::W1(PathBuf::from("/sys/bus/w1/devices/28-00/temperature"))),
+            parse_str("--sensor /sys/bus/w1/devices/28-00/temperature")
+                .unwrap()
+                .sensor
+        );
+        assert!(parse_str("--sensor modbus:/dev/ttyUSB0?addr=0").is_err());
+        match parse_str("--sensor exec:/usr/local/bin/read-temp.sh?timeout=3000 17")
+            .unwrap()
+            .sensor
+        {
+            Some(SensorSpec::Exec(config)) => assert_eq!(Duration::from_secs(3), config.timeout),
+            _ => panic!("Expected a sensor command."),
+        }
+        assert!(parse_str("--sensor exec:read-temp.sh").is_err());
+        match parse_str("--sensor hwmon:nct6775/SYSTIN 17").unwrap().sensor {
+            Some(SensorSpec::Hwmon(config)) => assert_eq!("nct6775", config.chip),
+            _ => panic!("Expected an hwmon sensor."),
+        }
+        assert!(parse_str("--sensor hwmon:nct6775").is_err());
+        assert_eq!(
+            Some(SensorFormat::Degrees),
+            parse_str("--sensor-format degrees /sensor 17").unwrap().sensor_format
+        );
+        assert_eq!(None, parse_str("/sensor 17").unwrap().sensor_format);
+        assert!(parse_str("--sensor-format celsius /sensor 17").is_err());
+        assert_eq!(
+            Some(10),
+            parse_str("--sensor-resolution 10 /sensor 17")
+                .unwrap()
+                .sensor_resolution
+                .map(Resolution::bits)
+        );
+        assert!(parse_str("--sensor-resolution 8 /sensor 17").is_err());
+    }
+
+    #[test]
+    fn early_shutoff() {
+        assert_eq!(TailEstimate::Off, parse_str("/sensor 17").unwrap().config.early_shutoff);
+        assert_eq!(
+            TailEstimate::Learned,
+            parse_str("--early-shutoff learned").unwrap().config.early_shutoff
+        );
+        assert_eq!(
+            TailEstimate::Fixed(DegreesDelta(0.75)),
+            parse_str("--early-shutoff 0.75").unwrap().config.early_shutoff
+        );
+        assert_eq!(
+            TailEstimate::Fixed(DegreesDelta(0.5)),
+            parse_str("--early-shutoff=0.9 --input-units f")
+                .unwrap()
+                .config
+                .early_shutoff
+        );
+        assert!(parse_str("--early-shutoff sometimes").is_err());
+    }
+
+    #[test]
+    fn time_proportional_mode() {
+        use picool_core::config::ControlMode;
+        assert_eq!(ControlMode::Hysteresis, parse_str("/sensor 17").unwrap().config.mode);
+        let args =
+            parse_str("--mode tpc --min-on 0 --min-off 1 --tpc-period 15 --tpc-gain 40 --tpc-integral 5").unwrap();
+        assert_eq!(ControlMode::Tpc, args.config.mode);
+        assert_eq!(Duration::from_secs(15 * 60), args.config.tpc.period);
+        assert_eq!((0.4, 0.05), (args.config.tpc.gain, args.config.tpc.integral_gain));
+        let args = parse_str("--tpc-gain 25 --input-units f").unwrap();
+        assert_eq!(0.45, args.config.tpc.gain);
+        // The default minimum intervals just fit the default window, longer ones don't.
+        assert!(parse_str("--mode tpc").is_ok());
+        let error = parse_str("--mode tpc --min-off 9").err().unwrap();
+        assert!(error.to_string().contains("don't fit"), "{}", error);
+        assert!(parse_str("--min-off 9").is_ok());
+        assert!(parse_str("--mode pwm").is_err());
+    }
+
+    #[test]
+    fn fault_injection() {
+        let args =
+            parse_str("--demo-fault-seed 42 --demo-read-failures 5 --demo-failure-bursts 0.1 --demo-burst-length 30")
+                .unwrap();
+        assert_eq!(42, args.demo.faults.seed);
+        assert_eq!(0.05, args.demo.faults.failure);
+        assert_eq!(0.001, args.demo.faults.burst);
+        assert_eq!(30, args.demo.faults.burst_length);
+        let args = parse_str(
+            "--demo-spikes 1 --demo-spike-size 20 --demo-reset-values 2 --demo-stuck-values 0.5 --demo-stuck-length 45",
+        )
+        .unwrap();
+        assert_eq!(0.01, args.demo.faults.spike);
+        assert_eq!(20.0, args.demo.faults.spike_size);
+        assert_eq!(0.02, args.demo.faults.reset);
+        assert_eq!(0.005, args.demo.faults.stuck);
+        assert_eq!(Duration::from_secs(45 * 60), args.demo.faults.stuck_length);
+        assert!(parse_str("--demo-read-failures 150").is_err());
+        assert!(parse_str("--demo-fault-seed -1").is_err());
+    }
+
+    #[test]
+    fn missing_value_and_unknown_option() {
+        assert!(parse_str("--run-as").is_err());
+        assert!(parse_str("--bogus 1").is_err());
+    }
+}
@@ -0,0 +1,407 @@
+//! What-if replays: one recorded trace through the control loop under two configurations, summarized side by side.
+//! The trace plays back as recorded whatever the relay does, so time in range and excursions describe the trace the
+//! configurations were judged against while starts and on time are what each would have done with it.
+
+use crate::{
+    replay_world::{ReplayParameters, ReplayWorld, Trace},
+    restore_seed, run,
+    shutdown::Shutdown,
+    stall_monitor::LoopProgress,
+    supervisor::Supervisor,
+    World, WorldState,
+};
+use anyhow::Result;
+use log::*;
+use picool_core::{
+    boost::Boost,
+    config::{Band, Config},
+    controller::CycleRecord,
+    energy::{EnergyTotals, LocalTime},
+    failover::Output,
+    heartbeat::HeartbeatStatus,
+    notify::LogNotifier,
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{Celsius, DegreesDelta},
+    termination::RunOutcome,
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals,
+};
+use std::{
+    cell::RefCell,
+    fmt, io,
+    rc::Rc,
+    sync::mpsc::channel,
+    time::{Duration, Instant},
+};
+
+/// How one configuration controlled the trace.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Summary {
+    pub starts: u32,
+    pub on_time: Duration,
+    /// Fraction of successful readings inside the target range.
+    pub time_in_range: f32,
+    /// Furthest a reading got outside the target range, in degrees.
+    pub worst_excursion: f32,
+}
+
+impl Summary {
+    fn json(&self) -> String {
+        format!(
+            "{{\"starts\":{},\"on_time_seconds\":{},\"time_in_range\":{:.4},\"worst_excursion\":{:.3}}}",
+            self.starts,
+            self.on_time.as_secs(),
+            self.time_in_range,
+            self.worst_excursion
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Comparison {
+    pub a: Summary,
+    pub b: Summary,
+}
+
+impl Comparison {
+    pub fn json(&self) -> String {
+        format!("{{\"a\":{},\"b\":{}}}", self.a.json(), self.b.json())
+    }
+}
+
+/// A table with the change from `a` to `b`.
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (a, b) = (self.a, self.b);
+        let hours = |d: Duration| d.as_secs_f64() / 3600.0;
+        writeln!(f, "{:<16}{:>14}{:>14}{:>14}", "", "a", "b", "change")?;
+        writeln!(
+            f,
+            "{:<16}{:>14}{:>14}{:>14}",
+            "starts",
+            a.starts,
+            b.starts,
+            percent_change(a.starts as f64, b.starts as f64)
+        )?;
+        writeln!(
+            f,
+            "{:<16}{:>13.1}h{:>13.1}h{:>14}",
+            "on time",
+            hours(a.on_time),
+            hours(b.on_time),
+            percent_change(hours(a.on_time), hours(b.on_time))
+        )?;
+        writeln!(
+            f,
+            "{:<16}{:>13.1}%{:>13.1}%{:>+12.1}pt",
+            "time in range",
+            a.time_in_range * 100.0,
+            b.time_in_range * 100.0,
+            (b.time_in_range - a.time_in_range) * 100.0
+        )?;
+        writeln!(
+            f,
+            "{:<16}{:>14}{:>14}{:>+14}",
+            "worst excursion",
+            DegreesDelta(a.worst_excursion),
+            DegreesDelta(b.worst_excursion),
+            DegreesDelta(b.worst_excursion - a.worst_excursion)
+        )
+    }
+}
+
+// Pure
+fn percent_change(a: f64, b: f64) -> String {
+    match a == 0.0 {
+        true => "-".into(),
+        false => format!("{:+.1}%", (b - a) / a * 100.0),
+    }
+}
+
+/// Replays `trace` under both configurations.
+pub fn compare(trace: &Trace, parameters: &ReplayParameters, a: &Config, b: &Config) -> Result<Comparison> {
+    Ok(Comparison {
+        a: replay(trace, parameters, a)?,
+        b: replay(trace, parameters, b)?,
+    })
+}
+
+/// Runs the control loop over the whole trace the way `picool replay` would, as fast as possible.
+pub fn replay(trace: &Trace, parameters: &ReplayParameters, config: &Config) -> Result<Summary> {
+    let shutdown = Shutdown::default();
+    let parameters = ReplayParameters {
+        time_warp: None,
+        ..parameters.clone()
+    };
+    let world = ReplayWorld::new(trace.clone(), parameters, Box::new(io::sink()), shutdown.clone())?;
+    let tally = Rc::new(RefCell::new(Tally::default()));
+    let world = Measured {
+        inner: world,
+        band: config.band.clone(),
+        tally: tally.clone(),
+    };
+    let seed = restore_seed(&world, config.intervals);
+    let (_, commands) = channel();
+    let outcome = run(
+        seed,
+        config,
+        world,
+        LogNotifier,
+        commands,
+        Supervisor::new(LoopProgress::default()),
+        &shutdown,
+    );
+    if let RunOutcome::Fatal(_) = outcome {
+        warn!("The replay ended early. {}", outcome);
+    }
+    let tally = tally.borrow();
+    Ok(Summary {
+        starts: tally.starts,
+        on_time: tally.on_time,
+        time_in_range: match tally.readings {
+            0 => 0.0,
+            readings => tally.in_range as f32 / readings as f32,
+        },
+        worst_excursion: tally.worst_excursion,
+    })
+}
+
+#[derive(Default)]
+struct Tally {
+    starts: u32,
+    on_since: Option<Instant>,
+    on_time: Duration,
+    readings: u32,
+    in_range: u32,
+    worst_excursion: f32,
+}
+
+/// Tallies what the loop reads and switches on the way through to the world.
+struct Measured<W> {
+    inner: W,
+    band: Band,
+    tally: Rc<RefCell<Tally>>,
+}
+
+impl<W: World> World for Measured<W> {
+    fn get_temperature(&self) -> Result<Celsius, SensorError> {
+        let temperature = self.inner.get_temperature();
+        if let Ok(t) = temperature {
+            let mut tally = self.tally.borrow_mut();
+            tally.readings += 1;
+            let target = &self.band.target;
+            let excursion = (target.start.0 - t.0).max(t.0 - target.end.0);
+            match excursion > 0.0 {
+                true => tally.worst_excursion = tally.worst_excursion.max(excursion),
+                false => tally.in_range += 1,
+            }
+        }
+        temperature
+    }
+
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+        self.inner.get_ambient_temperature()
+    }
+
+    fn get_current(&self) -> Option<Result<f32, SensorError>> {
+        self.inner.get_current()
+    }
+
+    fn set_power_state(&mut self, state: bool) {
+        let now = self.inner.now();
+        let mut tally = self.tally.borrow_mut();
+        match (tally.on_since, state) {
+            (None, true) => {
+                tally.starts += 1;
+                tally.on_since = Some(now);
+            }
+            (Some(since), false) => {
+                tally.on_time += now - since;
+                tally.on_since = None;
+            }
+            _ => {}
+        }
+        drop(tally);
+        self.inner.set_power_state(state)
+    }
+
+    fn set_fan_state(&mut self, state: bool) {
+        self.inner.set_fan_state(state)
+    }
+
+    fn set_stage2_state(&mut self, state: bool) {
+        self.inner.set_stage2_state(state)
+    }
+
+    fn set_pump_state(&mut self, state: bool) {
+        self.inner.set_pump_state(state)
+    }
+
+    fn pump_state(&self) -> Option<bool> {
+        self.inner.pump_state()
+    }
+
+    fn select_output(&mut self, output: Output) {
+        self.inner.select_output(output)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.inner.sleep(duration)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn local_time(&self) -> LocalTime {
+        self.inner.local_time()
+    }
+
+    fn reinitialize(&mut self) -> Result<(), SensorError> {
+        self.inner.reinitialize()
+    }
+
+    fn reacquire_sensor(&mut self) -> bool {
+        self.inner.reacquire_sensor()
+    }
+
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+        self.inner.write_heartbeat(status)
+    }
+
+    fn restore_state(&self) -> Result<WorldState> {
+        self.inner.restore_state()
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        self.inner.persist_last_off_transition()
+    }
+
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+        self.inner.persist_compensation(cooling, heating)
+    }
+
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+        self.inner.persist_cooling_rates(rates)
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        self.inner.persist_energy(totals)
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        self.inner.persist_runtime(runtime)
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        self.inner.persist_starts(counts)
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        self.inner.persist_boost(boost)
+    }
+
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        self.inner.persist_intervals(intervals)
+    }
+
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+        self.inner.persist_overshoots(overshoots)
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        self.inner.persist_rest(rest)
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        self.inner.persist_shed(shed)
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        self.inner.persist_extremes(extremes)
+    }
+
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        self.inner.record_cycle(cycle)
+    }
+
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+        self.inner.persist_failover(faulted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn fixture() -> Trace {
+        Trace::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("traces/stc1000.csv")).unwrap()
+    }
+
+    #[test]
+    fn longer_minimum_off_saves_starts() {
+        let trace = fixture();
+        let parameters = ReplayParameters::default();
+        let patient = Config {
+            intervals: MinimumIntervals {
+                off: Duration::from_secs(60 * 90),
+                ..MinimumIntervals::default()
+            },
+            ..Config::default()
+        };
+        let comparison = compare(&trace, &parameters, &Config::default(), &patient).unwrap();
+        let (a, b) = (comparison.a, comparison.b);
+        assert_eq!((4, 3), (a.starts, b.starts), "{:?}", comparison);
+        assert!(
+            b.on_time < a.on_time && a.on_time <= trace.duration(),
+            "{:?}",
+            comparison
+        );
+        // Same readings either way.
+        assert!((a.time_in_range - 0.759).abs() < 1e-3, "{:?}", comparison);
+        assert_eq!(
+            (a.time_in_range, a.worst_excursion),
+            (b.time_in_range, b.worst_excursion)
+        );
+        // Deterministic, the same configuration replays identically.
+        assert_eq!(a, replay(&trace, &parameters, &Config::default()).unwrap());
+    }
+
+    #[test]
+    fn report_formats() {
+        let comparison = Comparison {
+            a: Summary {
+                starts: 40,
+                on_time: Duration::from_secs(36_000),
+                time_in_range: 0.95,
+                worst_excursion: 0.5,
+            },
+            b: Summary {
+                starts: 30,
+                on_time: Duration::from_secs(32_400),
+                time_in_range: 0.9,
+                worst_excursion: 0.75,
+            },
+        };
+        assert_eq!(
+            "{\"a\":{\"starts\":40,\"on_time_seconds\":36000,\"time_in_range\":0.9500,\"worst_excursion\":0.500},\
+             \"b\":{\"starts\":30,\"on_time_seconds\":32400,\"time_in_range\":0.9000,\"worst_excursion\":0.750}}",
+            comparison.json()
+        );
+        let table = comparison.to_string();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(5, lines.len());
+        assert!(
+            lines[1].starts_with("starts") && lines[1].ends_with("-25.0%"),
+            "{}",
+            table
+        );
+        assert!(lines[2].ends_with("-10.0%"), "{}", table);
+        assert!(lines[3].ends_with("-5.0pt"), "{}", table);
+        assert!(lines[4].ends_with("0.75C 1.35F +0.25C +0.45F"), "{}", table);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::scenario::{parse_finite, parse_positive_duration};
+use anyhow::{anyhow, bail, Context, Result};
+use picool_core::{
+    config::Config,
+    temperature::{Celsius, DegreesDelta, Units},
+};
+use std::{fs, path::Path, path::PathBuf, time::Duration};
+
+const TARGET_TABLE: &str = "[target]";
+
+/// The tunables read from `--config`, each None where the file leaves it out.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigFile {
+    pub target_start: Option<Celsius>,
+    pub target_end: Option<Celsius>,
+    pub max_compensation: Option<DegreesDelta>,
+    pub min_on: Option<Duration>,
+    pub min_off: Option<Duration>,
+    pub poll_interval: Option<Duration>,
+    pub sensor_path: Option<PathBuf>,
+    pub power_pin: Option<u8>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}. {}", path.display(), e))?;
+        text.parse()
+            .map_err(|e| anyhow!("Invalid config {}. {:#}", path.display(), e))
+    }
+
+    /// Sets what the file gives, the rest keeps its current value.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(start) = self.target_start {
+            config.band.target.start = start;
+        }
+        if let Some(end) = self.target_end {
+            config.band.target.end = end;
+        }
+        if let Some(max_compensation) = self.max_compensation {
+            config.band.max_compensation = max_compensation;
+        }
+        if let Some(on) = self.min_on {
+            config.intervals.on = on;
+        }
+        if let Some(off) = self.min_off {
+            config.intervals.off = off;
+        }
+        if let Some(interval) = self.poll_interval {
+            config.sampling.interval = interval;
+        }
+    }
+}
+
+/// Parses a TOML subset, top level keys and an optional `[target]` table. Temperatures are in Celsius, or in Fahrenheit
+/// with `units = "f"` or quoted with a unit like `"38F"`:
+///
+/// ```toml
+/// sensor_path = "/sys/bus/w1/devices/28-0316a2795cff/temperature"
+/// power_pin = 17
+/// max_compensation = 1.0
+/// min_on = "2m"
+/// min_off = "8m"
+/// poll_interval = "10s"
+///
+/// [target]
+/// start = 2.0
+/// end = 4.5
+/// ```
+impl std::str::FromStr for ConfigFile {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut file = Self::default();
+        let mut in_target = false;
+        // Read as written, converted once the file's units are known.
+        let (mut start, mut end, mut compensation) = (None, None, None);
+        let mut units = FileUnits::default();
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                if line != TARGET_TABLE {
+                    bail!("Line {}: unknown table {}, expected {}.", number, line, TARGET_TABLE);
+                }
+                if in_target || start.is_some() || end.is_some() {
+                    bail!("Line {}: only one {} table is allowed.", number, TARGET_TABLE);
+                }
+                in_target = true;
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => bail!("Line {}: expected key = value, got '{}'.", number, line),
+            };
+            let context = || format!("Line {}: invalid {}", number, key);
+            match (in_target, key) {
+                (true, "start") => start = Some(units.read(number, parse_degrees(value).with_context(context)?)?),
+                (true, "end") => end = Some(units.read(number, parse_degrees(value).with_context(context)?)?),
+                (false, "max_compensation") => {
+                    compensation = Some(units.read(number, parse_degrees(value).with_context(context)?)?)
+                }
+                (false, "units") => units.set(number, parse_units(value).with_context(context)?)?,
+                (false, "min_on") => file.min_on = Some(parse_positive_duration(value).with_context(context)?),
+                (false, "min_off") => file.min_off = Some(parse_positive_duration(value).with_context(context)?),
+                (false, "poll_interval") => {
+                    file.poll_interval = Some(parse_positive_duration(value).with_context(context)?)
+                }
+                (false, "sensor_path") => {
+                    file.sensor_path = Some(PathBuf::from(parse_string(value).with_context(context)?))
+                }
+                (false, "power_pin") => {
+                    file.power_pin = Some(
+                        value
+                            .parse()
+                            .map_err(|_| anyhow!("Expected a GPIO pin number, got {}.", value))
+                            .with_context(context)?,
+                    )
+                }
+                (true, _) => bail!("Line {}: unknown key '{}' in {}.", number, key, TARGET_TABLE),
+                (false, _) => bail!("Line {}: unknown key '{}'.", number, key),
+            }
+        }
+        let fahrenheit = units.0.is_some_and(|(_, units)| units == Units::Fahrenheit);
+        let temperature = |degrees: f32| match fahrenheit {
+            true => Celsius::from_fahrenheit(degrees),
+            false => Celsius(degrees),
+        };
+        file.target_start = start.map(temperature);
+        file.target_end = end.map(temperature);
+        file.max_compensation = compensation.map(|degrees| match fahrenheit {
+            true => DegreesDelta::from_fahrenheit(degrees),
+            false => DegreesDelta(degrees),
+        });
+        if let (Some(start), Some(end)) = (file.target_start, file.target_end) {
+            if start >= end {
+                bail!("The target start has to be below its end, got {} to {}.", start, end);
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// The units the file's temperatures are in, and the line that said so. Celsius unless a `units` key or a suffix
+/// says otherwise, and everything that says has to agree.
+#[derive(Default)]
+struct FileUnits(Option<(usize, Units)>);
+
+impl FileUnits {
+    fn set(&mut self, number: usize, units: Units) -> Result<()> {
+        match self.0 {
+            Some((line, set)) if set != units => bail!(
+                "Line {}: {} mixes units, line {} is in {}.",
+                number,
+                name(units),
+                line,
+                name(set)
+            ),
+            Some(_) => Ok(()),
+            None => {
+                self.0 = Some((number, units));
+                Ok(())
+            }
+        }
+    }
+
+    fn read(&mut self, number: usize, (degrees, units): (f32, Option<Units>)) -> Result<f32> {
+        if let Some(units) = units {
+            self.set(number, units)?;
+        }
+        Ok(degrees)
+    }
+}
+
+/// A bare number, or quoted with a unit like "38F" or "3.5C".
+fn parse_degrees(value: &str) -> Result<(f32, Option<Units>)> {
+    let value = value.trim_matches('"');
+    let (degrees, units) = match value.char_indices().last() {
+        Some((i, 'F' | 'f')) => (&value[..i], Some(Units::Fahrenheit)),
+        Some((i, 'C' | 'c')) => (&value[..i], Some(Units::Celsius)),
+        _ => (value, None),
+    };
+    Ok((parse_finite(degrees)?, units))
+}
+
+fn parse_units(value: &str) -> Result<Units> {
+    match parse_string(value)? {
+        "c" => Ok(Units::Celsius),
+        "f" => Ok(Units::Fahrenheit),
+        other => bail!("Expected \"c\" or \"f\", got '{}'.", other),
+    }
+}
+
+fn name(units: Units) -> &'static str {
+    match units {
+        Units::Fahrenheit => "Fahrenheit",
+        _ => "Celsius",
+    }
+}
+
+/// Everything from a `#` outside quotes, a path may contain one.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_string(value: &str) -> Result<&str> {
+    match value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        true => Ok(&value[1..value.len() - 1]),
+        false => bail!("Expected a quoted string, got {}.", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        format!("{:#}", text.parse::<ConfigFile>().unwrap_err())
+    }
+
+    #[test]
+    fn parses_every_key() {
+        let file: ConfigFile = r#"
+            # The keezer in the garage.
+            sensor_path = "/sys/bus/w1/devices/28-0316a2795cff/temperature" # the chamber
+            power_pin = 17
+            max_compensation = 1.0
+            min_on = "3m"
+            min_off = "10m"
+            poll_interval = "30s"
+
+            [target]
+            start = 2.0
+            end = 4.5
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            ConfigFile {
+                target_start: Some(Celsius(2.0)),
+                target_end: Some(Celsius(4.5)),
+                max_compensation: Some(DegreesDelta(1.0)),
+                min_on: Some(Duration::from_secs(3 * 60)),
+                min_off: Some(Duration::from_secs(10 * 60)),
+                poll_interval: Some(Duration::from_secs(30)),
+                sensor_path: Some(PathBuf::from("/sys/bus/w1/devices/28-0316a2795cff/temperature")),
+                power_pin: Some(17),
+            },
+            file
+        );
+        let mut config = Config::default();
+        file.apply(&mut config);
+        assert_eq!(Celsius(2.0)..Celsius(4.5), config.band.target);
+        assert_eq!(DegreesDelta(1.0), config.band.max_compensation);
+        assert_eq!(Duration::from_secs(3 * 60), config.intervals.on);
+        assert_eq!(Duration::from_secs(10 * 60), config.intervals.off);
+        assert_eq!(Duration::from_secs(30), config.sampling.interval);
+    }
+
+    #[test]
+    fn omitted_keys_keep_the_defaults() {
+        let file: ConfigFile = "min_off = \"12m\"\n".parse().unwrap();
+        let mut config = Config::default();
+        file.apply(&mut config);
+        assert_eq!(
+            Config {
+                intervals: picool_core::MinimumIntervals {
+                    off: Duration::from_secs(12 * 60),
+                    ..Config::default().intervals
+                },
+                ..Config::default()
+            },
+            config
+        );
+        assert_eq!(ConfigFile::default(), "".parse().unwrap());
+        let path: ConfigFile = "sensor_path = \"/mnt/probe#2/temperature\"".parse().unwrap();
+        assert_eq!(Some(PathBuf::from("/mnt/probe#2/temperature")), path.sensor_path);
+    }
+
+    #[test]
+    fn reads_fahrenheit() {
+        let file: ConfigFile = "[target]\nstart = \"38F\"\nend = \"40F\"\n".parse().unwrap();
+        let (start, end) = (file.target_start.unwrap(), file.target_end.unwrap());
+        assert!((start.0 - 3.333).abs() < 0.001, "{}", start);
+        assert!((end.0 - 4.444).abs() < 0.001, "{}", end);
+
+        let file: ConfigFile = "units = \"f\"\nmax_compensation = 1.8\n[target]\nstart = 38\nend = \"40f\"\n"
+            .parse()
+            .unwrap();
+        assert_eq!(start, file.target_start.unwrap());
+        assert_eq!(end, file.target_end.unwrap());
+        assert!((file.max_compensation.unwrap().0 - 1.0).abs() < 0.001);
+
+        let celsius: ConfigFile = "[target]\nstart = \"2C\"\nend = 4.5\n".parse().unwrap();
+        assert_eq!(Some(Celsius(2.0)), celsius.target_start);
+        assert_eq!(Some(Celsius(4.5)), celsius.target_end);
+    }
+
+    #[test]
+    fn rejects_mixed_units() {
+        assert_eq!(
+            "Line 3: Celsius mixes units, line 2 is in Fahrenheit.",
+            error("[target]\nstart = \"38F\"\nend = \"4C\"")
+        );
+        assert_eq!(
+            "Line 3: Fahrenheit mixes units, line 1 is in Celsius.",
+            error("units = \"c\"\n[target]\nstart = \"38F\"")
+        );
+        assert_eq!(
+            "Line 2: invalid units: Expected \"c\" or \"f\", got 'k'.",
+            error("max_compensation = 1.0\nunits = \"k\"")
+        );
+        assert!(error("[target]\nstart = \"F\"").starts_with("Line 2: invalid start"));
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        assert!(error("[target]\nstart = 4.5\nend = 2.0").starts_with("The target start has to be below its end"));
+        assert!(error("[target]\nstart = 3\nend = 3").contains("has to be below its end"));
+        assert_eq!(
+            "Line 1: invalid poll_interval: Duration must be positive.",
+            error("poll_interval = \"0s\"")
+        );
+        assert!(error("poll_interval = 10").contains("Expected a quoted duration"));
+        assert_eq!("Line 1: unknown key 'target_start'.", error("target_start = 2.0"));
+        assert_eq!(
+            "Line 2: unknown key 'min_on' in [target].",
+            error("[target]\nmin_on = \"2m\"")
+        );
+        assert_eq!("Line 1: unknown table [band], expected [target].", error("[band]"));
+        assert!(error("power_pin = seventeen").contains("Expected a GPIO pin number"));
+        assert!(error("sensor_path = /dev/null").contains("Expected a quoted string"));
+        assert!(error("max_compensation").contains("expected key = value"));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::{
+    net_runtime::{Runtime, Task, Wait},
+    status_board::StatusBoard,
+    POLL_DURATION,
+};
+use anyhow::{bail, Context, Result};
+use log::*;
+use picool_core::{boost::BoostRequest, config::Config};
+use std::{
+    fs,
+    io::{ErrorKind, Read, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    str::FromStr,
+    sync::mpsc::Sender,
+    task::Poll,
+    time::Instant,
+};
+
+/// Commands accepted on the control socket, one per line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    AcknowledgeFault,
+    /// Zero the compressor runtime counter, e.g. after replacing the compressor.
+    ResetRuntime,
+    Boost(BoostRequest),
+    /// Back to the configured minimum intervals, the timing advisor only recommends for the rest of the run.
+    RevertTiming,
+    /// The primary output was repaired, switch back to it from the backup.
+    ResetFailover,
+    /// The configuration re-read on SIGHUP, as far as it applies without a restart.
+    Reload(Box<Config>),
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "ack" => Ok(Command::AcknowledgeFault),
+            "reset-runtime" => Ok(Command::ResetRuntime),
+            "revert-timing" => Ok(Command::RevertTiming),
+            "reset-failover" => Ok(Command::ResetFailover),
+            other => match other.strip_prefix(BOOST) {
+                Some(request) => Ok(Command::Boost(request.parse()?)),
+                None => bail!("Unknown command '{}'.", other),
+            },
+        }
+    }
+}
+
+/// Asks for the latest status rather than the control loop to do anything, it's answered from the board.
+const STATUS: &str = "status";
+const BOOST: &str = "boost ";
+
+/// Sends one line to the daemon behind `socket`, its reply without the trailing `ok`.
+pub fn request(socket: &Path, line: &str) -> Result<String> {
+    let mut stream =
+        UnixStream::connect(socket).with_context(|| format!("Failed connecting to {}.", socket.display()))?;
+    stream.set_read_timeout(Some(POLL_DURATION))?;
+    writeln!(stream, "{}", line)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    match reply.strip_suffix("ok\n") {
+        Some(reply) => Ok(reply.into()),
+        None => bail!("picool replied '{}'.", reply.trim()),
+    }
+}
+
+/// Asks the daemon behind `socket` to start or cancel a boost.
+pub fn boost(socket: &Path, boost: &BoostRequest) -> Result<()> {
+    request(socket, &format!("{}{}", BOOST, boost)).map(|_| ())
+}
+
+/// Bind the control socket and serve it on the runtime. Commands are queued for the control loop which drains them
+/// once per iteration.
+pub fn serve(path: &Path, board: StatusBoard, sender: Sender<Command>, runtime: &mut Runtime) -> Result<()> {
+    // The instance lock guarantees nobody else is serving this path, anything left over is from a crash.
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed removing stale socket {}.", path.display()))
+        }
+        _ => {}
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed binding control socket {}.", path.display()))?;
+    listener.set_nonblocking(true)?;
+    let server = ControlServer {
+        listener,
+        connections: vec![],
+        sender,
+        board,
+    };
+    runtime
+        .spawn("control", |_| server)
+        .context("Failed starting the control socket.")?;
+    Ok(())
+}
+
+struct ControlServer {
+    listener: UnixListener,
+    connections: Vec<Connection>,
+    sender: Sender<Command>,
+    board: StatusBoard,
+}
+
+/// A client's lines read so far and the replies it hasn't taken yet.
+struct Connection {
+    stream: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// The client is done sending, the connection closes once the replies are out.
+    finished: bool,
+}
+
+impl Task for ControlServer {
+    fn poll(&mut self, _now: Instant, wait: &mut Wait) -> Poll<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.connections.push(Connection {
+                        stream,
+                        input: vec![],
+                        output: vec![],
+                        finished: false,
+                    }),
+                    Err(e) => warn!("Control connection failed. {:?}", e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Control socket accept failed. {:?}", e);
+                    break;
+                }
+            }
+        }
+        wait.readable(&self.listener);
+        let (sender, board) = (&self.sender, &self.board);
+        self.connections
+            .retain_mut(|connection| match connection.serve(sender, board) {
+                Ok(true) => {
+                    if !connection.finished {
+                        wait.readable(&connection.stream);
+                    }
+                    if !connection.output.is_empty() {
+                        wait.writable(&connection.stream);
+                    }
+                    true
+                }
+                Ok(false) => false,
+                Err(e) => {
+                    warn!("Control connection failed. {:?}", e);
+                    false
+                }
+            });
+        Poll::Pending
+    }
+}
+
+impl Connection {
+    /// Answers the complete lines read so far, whether the connection is still open.
+    fn serve(&mut self, sender: &Sender<Command>, board: &StatusBoard) -> Result<bool> {
+        let mut buffer = [0; 1024];
+        while !self.finished {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.finished = true,
+                Ok(read) => self.input.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        while let Some(end) = self.input.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=end).collect();
+            let reply = answer(
+                String::from_utf8_lossy(&line[..end]).trim_end_matches('\r'),
+                sender,
+                board,
+            )?;
+            self.output.extend_from_slice(reply.as_bytes());
+        }
+        if self.finished && !self.input.is_empty() {
+            let line = String::from_utf8_lossy(&self.input).into_owned();
+            self.input.clear();
+            self.output.extend_from_slice(answer(&line, sender, board)?.as_bytes());
+        }
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(written) => {
+                    self.output.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(!self.finished)
+    }
+}
+
+fn answer(line: &str, sender: &Sender<Command>, board: &StatusBoard) -> Result<String> {
+    if line.trim() == STATUS {
+        return Ok(match board.snapshot() {
+            Some(snapshot) => format!("{}ok\n", snapshot),
+            None => "error: No reading yet.\n".into(),
+        });
+    }
+    match line.parse::<Command>() {
+        Ok(command) => {
+            info!("Control command received: {:?}", command);
+            sender.send(command).context("Control loop has stopped.")?;
+            Ok("ok\n".into())
+        }
+        Err(e) => Ok(format!("error: {}\n", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use picool_core::{boost::Boost, temperature::Celsius};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    fn serve(path: &Path, sender: Sender<Command>) -> Runtime {
+        let mut runtime = Runtime::default();
+        super::serve(path, StatusBoard::default(), sender, &mut runtime).unwrap();
+        runtime
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::AcknowledgeFault, "ack\n".parse().unwrap());
+        assert_eq!(Command::ResetRuntime, "reset-runtime".parse().unwrap());
+        assert_eq!(Command::RevertTiming, "revert-timing".parse().unwrap());
+        assert_eq!(Command::ResetFailover, "reset-failover".parse().unwrap());
+        assert!("reboot".parse::<Command>().is_err());
+        assert_eq!(
+            Command::Boost(BoostRequest::Start(Boost {
+                band: Celsius(-20.0)..Celsius(-18.5),
+                remaining: Duration::from_secs(3600)
+            })),
+            "boost -20 -18.5 3600".parse().unwrap()
+        );
+        assert_eq!(Command::Boost(BoostRequest::Cancel), "boost cancel".parse().unwrap());
+        assert!("boost 4 2 3600".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn boost_is_sent_as_a_command() {
+        let dir = TempDir::new();
+        let path = dir.path().join("control.sock");
+        let (sender, receiver) = channel();
+        let _runtime = serve(&path, sender);
+        boost(&path, &BoostRequest::Cancel).unwrap();
+        assert_eq!(
+            Command::Boost(BoostRequest::Cancel),
+            receiver.recv_timeout(Duration::from_secs(1)).unwrap()
+        );
+        assert!(request(&path, "status").is_err());
+    }
+
+    #[test]
+    fn commands_are_queued_and_acknowledged() {
+        let dir = TempDir::new();
+        let path = dir.path().join("control.sock");
+        fs::write(&path, "stale").unwrap();
+        let (sender, receiver) = channel();
+        let _runtime = serve(&path, sender);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"ack\nbogus\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert_eq!("ok\nerror: Unknown command 'bogus'.\n", response);
+        assert_eq!(
+            Command::AcknowledgeFault,
+            receiver.recv_timeout(Duration::from_secs(1)).unwrap()
+        );
+    }
+
+    #[test]
+    fn status_is_answered_without_the_control_loop() {
+        let dir = TempDir::new();
+        let path = dir.path().join("control.sock");
+        let (sender, receiver) = channel();
+        let _runtime = serve(&path, sender);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"status\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert_eq!("error: No reading yet.\n", response);
+        assert!(receiver.try_recv().is_err());
+    }
+}
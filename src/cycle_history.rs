@@ -0,0 +1,356 @@
+//! The history of on cycles the daemon keeps next to its other state, one line per cycle, and `picool cycles` to
+//! read it back.
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use picool_core::{
+    controller::CycleRecord,
+    temperature::{Celsius, DegreesDelta, Rate, Units},
+};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+/// Cycles kept unless `--cycle-history` says otherwise, months of ordinary cycling.
+pub const DEFAULT_KEEP: usize = 2000;
+
+/// A cycle as kept, with when it ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Since the epoch.
+    pub ended: Duration,
+    pub cycle: CycleRecord,
+}
+
+// Pure
+/// `ended on off min max overshoot rate reason abnormal`, seconds and millidegrees, `-` for what's missing. The
+/// abnormal reason may have spaces and comes last.
+pub fn format_entry(ended: Duration, cycle: &CycleRecord) -> String {
+    let seconds = |d: Option<Duration>| d.map_or("-".into(), |d| d.as_secs().to_string());
+    let millidegrees = |t: Option<f32>| t.map_or("-".into(), |t| DegreesDelta(t).millidegrees().0.to_string());
+    format!(
+        "{} {} {} {} {} {} {} {} {}",
+        ended.as_secs(),
+        cycle.on.as_secs(),
+        seconds(cycle.off),
+        millidegrees(cycle.min.map(|min| min.0)),
+        millidegrees(cycle.max.map(|max| max.0)),
+        millidegrees(cycle.overshoot.map(|overshoot| overshoot.0)),
+        cycle.cooling_rate.map_or("-".into(), |rate| format!("{:.4}", rate)),
+        cycle.reason,
+        cycle.abnormal.as_deref().unwrap_or("-")
+    )
+}
+
+impl FromStr for HistoryEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.splitn(9, ' ').collect();
+        let [ended, on, off, min, max, overshoot, rate, reason, abnormal] = fields[..] else {
+            bail!("expected 9 fields, got {}", fields.len());
+        };
+        Ok(HistoryEntry {
+            ended: Duration::from_secs(ended.parse()?),
+            cycle: CycleRecord {
+                on: Duration::from_secs(on.parse()?),
+                off: optional(off)?.map(Duration::from_secs),
+                min: degrees(min)?.map(Celsius),
+                max: degrees(max)?.map(Celsius),
+                overshoot: degrees(overshoot)?.map(DegreesDelta),
+                cooling_rate: optional(rate)?,
+                reason: reason.parse()?,
+                abnormal: match abnormal {
+                    "-" => None,
+                    abnormal => Some(abnormal.into()),
+                },
+            },
+        })
+    }
+}
+
+fn optional<T: FromStr>(field: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(match field {
+        "-" => None,
+        field => Some(field.parse()?),
+    })
+}
+
+fn degrees(field: &str) -> Result<Option<f32>> {
+    Ok(optional::<i32>(field)?.map(|millidegrees| millidegrees as f32 / 1000.0))
+}
+
+/// Adds `line` to the history at `path`, dropping the oldest beyond `keep`.
+pub fn append(path: &Path, line: &str, keep: usize) -> io::Result<()> {
+    let kept = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut lines: Vec<&str> = kept.lines().collect();
+    lines.push(line);
+    let from = lines.len().saturating_sub(keep);
+    let mut data = lines[from..].join("\n");
+    data.push('\n');
+    fs::write(path, data)
+}
+
+/// The history at `path`, oldest first. A line that doesn't parse is skipped with a warning.
+pub fn read(path: &Path) -> Result<Vec<HistoryEntry>> {
+    let data = fs::read_to_string(path).with_context(|| format!("Could not read {}.", path.display()))?;
+    Ok(data
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| match line.parse() {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("Skipping line {} of {}, {}.", i + 1, path.display(), e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Which cycles `picool cycles` shows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CycleFilter {
+    /// Only those that ended within this long.
+    pub since: Option<Duration>,
+    /// Only runs at least this long.
+    pub min_duration: Option<Duration>,
+    /// Only cycles something made unfit to learn from.
+    pub abnormal_only: bool,
+}
+
+impl CycleFilter {
+    // Pure
+    /// `now` since the epoch.
+    pub fn matches(&self, entry: &HistoryEntry, now: Duration) -> bool {
+        self.since.is_none_or(|since| entry.ended >= now.saturating_sub(since))
+            && self.min_duration.is_none_or(|min| entry.cycle.on >= min)
+            && (!self.abnormal_only || entry.cycle.abnormal.is_some())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum CycleFormat {
+    /// Aligned columns in the display units.
+    #[default]
+    Table,
+    /// A header row, then a row per cycle, temperatures in Celsius.
+    Csv,
+    /// One object per line, temperatures in Celsius.
+    Json,
+}
+
+// Pure
+pub fn render(entries: &[HistoryEntry], format: CycleFormat, units: Units) -> String {
+    let mut out = String::new();
+    let text = |value: Option<String>| value.unwrap_or_default();
+    match format {
+        CycleFormat::Table => {
+            let shown = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+            let _ = writeln!(
+                out,
+                "{:<20} {:>6} {:>6} {:>14} {:>14} {:>14} {:>18}  {:<16} abnormal",
+                "ended", "on", "off", "min", "max", "overshoot", "cooling", "reason"
+            );
+            for entry in entries {
+                let cycle = &entry.cycle;
+                let _ = writeln!(
+                    out,
+                    "{:<20} {:>5}m {:>6} {:>14} {:>14} {:>14} {:>18}  {:<16} {}",
+                    timestamp(entry.ended),
+                    cycle.on.as_secs() / 60,
+                    shown(cycle.off.map(|off| format!("{}m", off.as_secs() / 60))),
+                    shown(cycle.min.map(|min| format!("{:.2}", units.show(min)))),
+                    shown(cycle.max.map(|max| format!("{:.2}", units.show(max)))),
+                    shown(cycle.overshoot.map(|overshoot| format!("{:.2}", units.show(overshoot)))),
+                    shown(
+                        cycle
+                            .cooling_rate
+                            .map(|rate| format!("{:.3}", units.show(Rate::new(rate, "/min"))))
+                    ),
+                    cycle.reason,
+                    shown(cycle.abnormal.clone())
+                );
+            }
+        }
+        CycleFormat::Csv => {
+            out.push_str("ended,on_seconds,off_seconds,min,max,overshoot,cooling_rate,reason,abnormal\n");
+            for entry in entries {
+                let cycle = &entry.cycle;
+                let _ = writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{},{}",
+                    timestamp(entry.ended),
+                    cycle.on.as_secs(),
+                    text(cycle.off.map(|off| off.as_secs().to_string())),
+                    text(cycle.min.map(|min| format!("{:.3}", min.0))),
+                    text(cycle.max.map(|max| format!("{:.3}", max.0))),
+                    text(cycle.overshoot.map(|overshoot| format!("{:.3}", overshoot.0))),
+                    text(cycle.cooling_rate.map(|rate| format!("{:.4}", rate))),
+                    cycle.reason,
+                    cycle.abnormal.as_deref().unwrap_or("")
+                );
+            }
+        }
+        CycleFormat::Json => {
+            let number = |value: Option<String>| value.unwrap_or_else(|| "null".into());
+            for entry in entries {
+                let cycle = &entry.cycle;
+                let _ = writeln!(
+                    out,
+                    "{{\"ended\":{},\"on_seconds\":{},\"off_seconds\":{},\"min\":{},\"max\":{},\"overshoot\":{},\
+                     \"cooling_rate\":{},\"reason\":{},\"abnormal\":{}}}",
+                    quote(&timestamp(entry.ended)),
+                    cycle.on.as_secs(),
+                    number(cycle.off.map(|off| off.as_secs().to_string())),
+                    number(cycle.min.map(|min| format!("{:.3}", min.0))),
+                    number(cycle.max.map(|max| format!("{:.3}", max.0))),
+                    number(cycle.overshoot.map(|overshoot| format!("{:.3}", overshoot.0))),
+                    number(cycle.cooling_rate.map(|rate| format!("{:.4}", rate))),
+                    quote(&cycle.reason.to_string()),
+                    number(cycle.abnormal.as_deref().map(quote))
+                );
+            }
+        }
+    }
+    out
+}
+
+/// As a JSON string.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn timestamp(since_epoch: Duration) -> String {
+    humantime::format_rfc3339_seconds(SystemTime::UNIX_EPOCH + since_epoch).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use picool_core::controller::TransitionReason;
+
+    /// Ended an hour apart from 2024-06-01, the second tainted by a boost and the third short.
+    const FIXTURE: &str = "\
+1717200000 1200 2400 3900 8100 100 0.2100 threshold -
+1717203600 1500 2100 2500 8000 0 - minimum-interval boost
+not a cycle
+1717207200 300 - - - - - sensor-failsafe sensor failsafe
+";
+
+    fn fixture() -> Vec<HistoryEntry> {
+        let dir = TempDir::new();
+        let path = dir.path().join("cycles_28-0011");
+        fs::write(&path, FIXTURE).unwrap();
+        read(&path).unwrap()
+    }
+
+    #[test]
+    fn round_trips_a_line() {
+        let entries = fixture();
+        assert_eq!(3, entries.len());
+        let first = &entries[0];
+        assert_eq!(
+            CycleRecord {
+                on: Duration::from_secs(1200),
+                off: Some(Duration::from_secs(2400)),
+                min: Some(Celsius(3.9)),
+                max: Some(Celsius(8.1)),
+                overshoot: Some(DegreesDelta(0.1)),
+                cooling_rate: Some(0.21),
+                reason: TransitionReason::Threshold,
+                abnormal: None,
+            },
+            first.cycle
+        );
+        assert_eq!(FIXTURE.lines().next().unwrap(), format_entry(first.ended, &first.cycle));
+        assert_eq!(Some("sensor failsafe"), entries[2].cycle.abnormal.as_deref());
+        assert!("1 2 3".parse::<HistoryEntry>().is_err());
+    }
+
+    #[test]
+    fn keeps_the_newest_lines() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cycles");
+        for i in 0..5 {
+            append(&path, &i.to_string(), 3).unwrap();
+        }
+        assert_eq!("2\n3\n4\n", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn filters_by_age_length_and_abnormality() {
+        let entries = fixture();
+        let now = Duration::from_secs(1_717_207_200 + 60);
+        let shown = |filter: CycleFilter| -> Vec<u64> {
+            entries
+                .iter()
+                .filter(|entry| filter.matches(entry, now))
+                .map(|entry| entry.cycle.on.as_secs())
+                .collect()
+        };
+        assert_eq!(vec![1200, 1500, 300], shown(CycleFilter::default()));
+        let since = CycleFilter {
+            since: Some(Duration::from_secs(2 * 3600)),
+            ..CycleFilter::default()
+        };
+        assert_eq!(vec![1500, 300], shown(since));
+        let long = CycleFilter {
+            min_duration: Some(Duration::from_secs(10 * 60)),
+            ..CycleFilter::default()
+        };
+        assert_eq!(vec![1200, 1500], shown(long));
+        let abnormal = CycleFilter {
+            abnormal_only: true,
+            min_duration: Some(Duration::from_secs(10 * 60)),
+            ..CycleFilter::default()
+        };
+        assert_eq!(vec![1500], shown(abnormal));
+    }
+
+    #[test]
+    fn renders_a_table_csv_and_json() {
+        let entries = fixture();
+        let table = render(&entries, CycleFormat::Table, Units::Celsius);
+        let rows: Vec<&str> = table.lines().collect();
+        assert_eq!(4, rows.len());
+        assert!(rows[0].starts_with("ended "), "{}", table);
+        assert!(rows[1].starts_with("2024-06-01T00:00:00Z    20m    40m"), "{}", table);
+        assert!(rows[2].contains("minimum-interval"), "{}", table);
+        assert!(rows[2].ends_with(" boost"), "{}", table);
+        assert!(rows[1].ends_with("threshold        -"), "{}", table);
+        assert!(rows[3].contains("  -  "), "{}", table);
+        assert_eq!(
+            "ended,on_seconds,off_seconds,min,max,overshoot,cooling_rate,reason,abnormal\n\
+             2024-06-01T00:00:00Z,1200,2400,3.900,8.100,0.100,0.2100,threshold,\n",
+            render(&entries[..1], CycleFormat::Csv, Units::Celsius)
+        );
+        assert_eq!(
+            "{\"ended\":\"2024-06-01T02:00:00Z\",\"on_seconds\":300,\"off_seconds\":null,\"min\":null,\"max\":null,\
+             \"overshoot\":null,\"cooling_rate\":null,\"reason\":\"sensor-failsafe\",\"abnormal\":\"sensor failsafe\"}\n",
+            render(&entries[2..], CycleFormat::Json, Units::Celsius)
+        );
+    }
+}
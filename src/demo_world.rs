@@ -1,107 +1,883 @@
-use crate::{c_to_f, RestoredPowerState, World, WorldState};
-use anyhow::Result;
+use crate::{
+    fault_injection::{FaultInjector, SensorFaults},
+    scenario::{Action, Scenario},
+    sim_controls::{Playback, SimControls},
+    store_faults::FaultyStore,
+    thermal_model::{RcModel, ThermalModel},
+    world::{local_now, Clock, CompositeWorld, Sensor, Store, Switch},
+};
+use anyhow::{bail, Result};
+use picool_core::{
+    boost::Boost,
+    config::Preset,
+    controller::CycleRecord,
+    energy::{EnergyTotals, LocalTime},
+    failover::Output,
+    heartbeat::HeartbeatStatus,
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{Celsius, DegreesDelta, Rate},
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals, RestoredPowerState,
+};
 use std::{
-    cell::Cell, cmp::min, ffi::OsString, fs, io::ErrorKind, path::PathBuf, thread::sleep, time::Duration,
-    time::Instant, time::SystemTime,
+    cell::{Cell, RefCell},
+    cmp::{max, min},
+    fmt, io,
+    path::PathBuf,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
 };
 
-const HEAT_DEGC_PER_SEC: f32 = 0.00263139325;
-const COOL_DEGC_PER_SEC: f32 = -0.00206762063;
-const TIME_WARP: f32 = 200.0;
-const LATENT_COOL: Duration = Duration::from_secs(300);
+const SECONDS_PER_HOUR: f32 = 3600.0;
+
+/// The simulated fridge's thermal behavior, rates in degrees per hour.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DemoParameters {
+    pub initial_temperature: Celsius,
+    pub model: ThermalModel,
+    /// While the compressor is off, linear model only.
+    pub warming_rate: f32,
+    /// While the compressor runs and for the latent period after it stops, linear model only.
+    pub cooling_rate: f32,
+    pub rc: RcModel,
+    /// Degrees per hour a second compressor removes while it runs, on top of either model.
+    pub stage2_capacity: f32,
+    /// Amps the compressor draws while it runs, for a current sensor. 0 plays a compressor that never starts.
+    pub compressor_current: f32,
+    pub latent_cooling: Duration,
+    /// Simulated time per real time, None runs as fast as possible.
+    pub time_warp: Option<f32>,
+    /// End the simulation after this many cooling cycles.
+    pub cycle_limit: Option<u32>,
+    pub scenario: Scenario,
+    pub faults: SensorFaults,
+}
+
+impl Default for DemoParameters {
+    fn default() -> Self {
+        Self {
+            initial_temperature: Celsius(4.6),
+            model: ThermalModel::Linear,
+            warming_rate: 9.473016,
+            cooling_rate: -7.443434,
+            rc: RcModel::default(),
+            stage2_capacity: 10.0,
+            compressor_current: 2.5,
+            latent_cooling: Duration::from_secs(300),
+            time_warp: None,
+            cycle_limit: Some(10),
+            scenario: Scenario::default(),
+            faults: SensorFaults::default(),
+        }
+    }
+}
+
+impl DemoParameters {
+    /// A chamber that starts just above the preset's range.
+    pub fn for_preset(preset: Preset) -> Self {
+        match preset {
+            Preset::Fridge => Self::default(),
+            Preset::Freezer => Self {
+                initial_temperature: Celsius(-16.0),
+                ..Self::default()
+            },
+            Preset::Keezer => Self {
+                initial_temperature: Celsius(5.5),
+                ..Self::default()
+            },
+            Preset::Fermenter => Self {
+                initial_temperature: Celsius(21.0),
+                ..Self::default()
+            },
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.initial_temperature.is_finite() {
+            bail!("Initial temperature must be finite.");
+        }
+        match self.model {
+            ThermalModel::Linear => {
+                if !(self.warming_rate > 0.0 && self.warming_rate.is_finite()) {
+                    bail!("Warming rate must be positive, got {}.", self.warming_rate);
+                }
+                if !(self.cooling_rate < 0.0 && self.cooling_rate.is_finite()) {
+                    bail!("Cooling rate must be negative, got {}.", self.cooling_rate);
+                }
+            }
+            ThermalModel::Rc => self.rc.validate()?,
+        }
+        if !(self.stage2_capacity >= 0.0 && self.stage2_capacity.is_finite()) {
+            bail!("Stage 2 capacity can't be negative, got {}.", self.stage2_capacity);
+        }
+        if !(self.compressor_current >= 0.0 && self.compressor_current.is_finite()) {
+            bail!("Compressor current can't be negative, got {}.", self.compressor_current);
+        }
+        if let Some(warp) = self.time_warp {
+            if !(warp > 0.0 && warp.is_finite()) {
+                bail!("Time warp must be positive, got {}.", warp);
+            }
+        }
+        self.faults.validate()?;
+        let ambient_step = self
+            .scenario
+            .events
+            .iter()
+            .find(|e| matches!(e.action, Action::AmbientStep(_)));
+        if let (Some(event), ThermalModel::Linear) = (ambient_step, self.model) {
+            bail!("Scenario event {} needs the rc model.", event);
+        }
+        Ok(())
+    }
+}
 
-pub struct DemoWorld {
+impl fmt::Display for DemoParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "from {}, ", self.initial_temperature)?;
+        match self.model {
+            ThermalModel::Linear => write!(
+                f,
+                "warming {}, cooling {}",
+                Rate::new(self.warming_rate, "/h"),
+                Rate::new(self.cooling_rate, "/h")
+            )?,
+            ThermalModel::Rc => write!(f, "{}", self.rc)?,
+        }
+        write!(f, ", latent cooling {}s", self.latent_cooling.as_secs())?;
+        if let Some(warp) = self.time_warp {
+            write!(f, ", time warp {}x", warp)?;
+        }
+        if let Some(cycles) = self.cycle_limit {
+            write!(f, ", {} cycles", cycles)?;
+        }
+        if !self.scenario.is_empty() {
+            write!(f, ", {} scripted events", self.scenario.events.len())?;
+        }
+        if self.faults.any() {
+            write!(f, ", {}", self.faults)?;
+        }
+        if self.scenario.store.any() {
+            write!(f, ", {}", self.scenario.store)?;
+        }
+        Ok(())
+    }
+}
+
+/// The simulated fridge every demo backend shares, time only passes when the clock sleeps.
+struct Simulation {
     current_temp: Cell<f32>,
-    power_state: bool,
+    power_state: Cell<bool>,
+    stage2_state: Cell<bool>,
+    pump_state: Cell<bool>,
+    start: Instant,
+    /// The wall clock when the simulation started.
+    local_start: Cell<LocalTime>,
     fake_time: Cell<Instant>,
-    cycles: u32,
     latent_cooling: Cell<Duration>,
+    /// Scenario events already started.
+    started: Cell<usize>,
+    ambient_shift: Cell<f32>,
+    injected_reading: Cell<Option<Celsius>>,
+    faults: RefCell<FaultInjector>,
+    /// Keyboard controls, taken out while a sleep is paced.
+    controls: RefCell<Option<SimControls>>,
+    parameters: DemoParameters,
 }
 
-impl DemoWorld {
-    pub fn new() -> Self {
-        Self {
-            current_temp: Cell::new(4.6),
-            power_state: false,
-            fake_time: Cell::new(Instant::now()),
-            cycles: 0,
-            latent_cooling: Cell::new(Duration::from_secs(0)),
+impl Simulation {
+    fn elapsed(&self) -> Duration {
+        self.fake_time.get() - self.start
+    }
+
+    /// The configured model with any scripted ambient steps applied.
+    fn rc(&self) -> RcModel {
+        let mut rc = self.parameters.rc.clone();
+        rc.ambient.mean = Celsius(rc.ambient.mean.0 + self.ambient_shift.get());
+        rc
+    }
+
+    /// Only the RC model is coupled to the ambient.
+    fn ambient(&self) -> Option<Celsius> {
+        match self.parameters.model {
+            ThermalModel::Linear => None,
+            ThermalModel::Rc => Some(self.rc().ambient.at(self.elapsed())),
+        }
+    }
+
+    /// Starts the scenario events that are due, at the first sleep boundary after their offset.
+    fn start_due_events(&self) {
+        let events = &self.parameters.scenario.events;
+        while let Some(event) = events.get(self.started.get()).filter(|e| e.at <= self.elapsed()) {
+            self.log(&format!("SCENARIO: {}", event));
+            match event.action {
+                Action::AmbientStep(delta) => self.ambient_shift.set(self.ambient_shift.get() + delta),
+                Action::Reading(temperature) => self.injected_reading.set(Some(temperature)),
+                Action::FailureBurst(reads) => self.faults.borrow_mut().start_burst(reads),
+                Action::Stuck(_) => {
+                    let value = Celsius(self.current_temp.get());
+                    self.faults.borrow_mut().stick(value, event.end());
+                }
+                Action::Spike(delta) => self.faults.borrow_mut().spike(DegreesDelta(delta)),
+                Action::SensorErrors(_) | Action::DoorOpen { .. } | Action::FailureProbability { .. } => {}
+            }
+            self.started.set(self.started.get() + 1);
+        }
+    }
+
+    fn sensor_failing(&self) -> bool {
+        let elapsed = self.elapsed();
+        self.parameters.scenario.events.iter().any(|event| {
+            matches!(event.action, Action::SensorErrors(_)) && event.at <= elapsed && elapsed < event.end()
+        })
+    }
+
+    /// The highest scripted failure probability in effect.
+    fn scripted_failure_probability(&self) -> f32 {
+        let elapsed = self.elapsed();
+        let active = self
+            .parameters
+            .scenario
+            .events
+            .iter()
+            .filter(|e| e.at <= elapsed && elapsed < e.end());
+        active.fold(0.0, |highest, event| match event.action {
+            Action::FailureProbability { probability, .. } => probability.max(highest),
+            _ => highest,
+        })
+    }
+
+    /// Heat let in by open doors between the two offsets.
+    fn door_heat(&self, from: Duration, to: Duration) -> f32 {
+        let mut heat = 0.0;
+        for event in &self.parameters.scenario.events {
+            if let Action::DoorOpen { heat: total, over } = event.action {
+                let open = min(to, event.end()).saturating_sub(max(from, event.at));
+                heat += total * open.as_secs_f32() / over.as_secs_f32();
+            }
         }
+        heat
+    }
+
+    fn advance(&self, cooling: bool, elapsed: Duration, duration: Duration) {
+        let parameters = &self.parameters;
+        let temperature = Celsius(self.current_temp.get());
+        let stage2 = match self.stage2_state.get() {
+            true => parameters.stage2_capacity,
+            false => 0.0,
+        };
+        let next = match parameters.model {
+            ThermalModel::Linear => {
+                let rate = match cooling {
+                    true => parameters.cooling_rate,
+                    false => parameters.warming_rate,
+                };
+                Celsius(temperature.0 + duration.as_secs_f32() * (rate - stage2) / SECONDS_PER_HOUR)
+            }
+            ThermalModel::Rc => {
+                let mut rc = self.rc();
+                rc.capacity = if cooling { rc.capacity } else { 0.0 } + stage2;
+                rc.advance(temperature, rc.capacity > 0.0, elapsed, duration)
+            }
+        };
+        self.current_temp.set(next.0);
     }
 
     fn log(&self, message: &str) {
-        let power_state = match self.power_state {
+        let playback = self.controls.borrow().as_ref().map(SimControls::playback);
+        self.log_with(playback, message)
+    }
+
+    fn log_with(&self, playback: Option<Playback>, message: &str) {
+        let power_state = match self.power_state.get() {
             true => "ON",
             false => "OFF",
         };
+        let ambient = match self.ambient() {
+            Some(ambient) => format!("[ambient {}]", ambient),
+            None => String::new(),
+        };
+        let playback = match playback {
+            Some(playback) => format!("[{}]", playback),
+            None => String::new(),
+        };
         println!(
-            ">>[{:.2}F][{}] {}",
-            c_to_f(self.current_temp.get()),
+            ">>[{}][{}]{}{} {}",
+            Celsius(self.current_temp.get()),
             power_state,
+            ambient,
+            playback,
             message
         );
     }
 }
 
-impl World for DemoWorld {
-    fn get_temperature(&self) -> Result<f32> {
-        self.log("GET_TEMPERATURE");
-        Ok(self.current_temp.get())
+pub struct DemoSensor(Rc<Simulation>);
+
+impl Sensor for DemoSensor {
+    fn get_temperature(&self) -> Result<Celsius, SensorError> {
+        let sim = &self.0;
+        sim.log("GET_TEMPERATURE");
+        if sim.sensor_failing() {
+            sim.log("FAULT: scripted sensor error");
+            return Err(injected_error("scripted sensor error"));
+        }
+        let actual = sim
+            .injected_reading
+            .take()
+            .unwrap_or_else(|| Celsius(sim.current_temp.get()));
+        let read = sim
+            .faults
+            .borrow_mut()
+            .read(actual, sim.elapsed(), sim.scripted_failure_probability());
+        match read {
+            Ok((reading, None)) => Ok(reading),
+            Ok((reading, Some(fault))) => {
+                sim.log(&format!("FAULT: {}, reporting {}", fault, reading));
+                Ok(reading)
+            }
+            Err(fault) => {
+                sim.log(&format!("FAULT: {}", fault));
+                Err(injected_error(fault))
+            }
+        }
+    }
+
+    /// Read exactly, the faults only apply to the chamber sensor.
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+        self.0.ambient().map(Ok)
+    }
+
+    /// The simulated compressor draws its current as soon as the relay is on.
+    fn get_current(&self) -> Option<Result<f32, SensorError>> {
+        Some(Ok(match self.0.power_state.get() {
+            true => self.0.parameters.compressor_current,
+            false => 0.0,
+        }))
+    }
+
+    fn reinitialize(&mut self) -> Result<(), SensorError> {
+        self.0.log("REINITIALIZE");
+        Ok(())
+    }
+
+    fn reacquire(&mut self) -> Option<String> {
+        None
     }
+}
+
+fn injected_error(cause: impl fmt::Display) -> SensorError {
+    SensorError::Unreadable(PathBuf::from("simulated sensor"), io::Error::other(cause.to_string()))
+}
+
+pub struct DemoSwitch(Rc<Simulation>);
 
+impl Switch for DemoSwitch {
     fn set_power_state(&mut self, state: bool) {
-        self.log(&format!("SET_POWERSTATE: {}", state));
-        self.power_state = state;
-        if state == false {
-            self.cycles += 1;
-            if self.cycles == 10 {
-                panic!("End of the world.");
-            }
-            self.latent_cooling.set(LATENT_COOL);
+        let sim = &self.0;
+        sim.log(&format!("SET_POWERSTATE: {}", state));
+        sim.power_state.set(state);
+        if !state {
+            sim.latent_cooling.set(sim.parameters.latent_cooling);
         } else {
-            self.latent_cooling.set(Duration::from_secs(0));
+            sim.latent_cooling.set(Duration::from_secs(0));
         }
     }
 
+    /// The models have no stratification for the fan to mix.
+    fn set_fan_state(&mut self, state: bool) {
+        self.0.log(&format!("SET_FANSTATE: {}", state));
+    }
+
+    fn set_stage2_state(&mut self, state: bool) {
+        self.0.log(&format!("SET_STAGE2STATE: {}", state));
+        self.0.stage2_state.set(state);
+    }
+
+    /// The simulated pump always comes on.
+    fn set_pump_state(&mut self, state: bool) {
+        self.0.log(&format!("SET_PUMPSTATE: {}", state));
+        self.0.pump_state.set(state);
+    }
+
+    fn pump_state(&self) -> Option<bool> {
+        Some(self.0.pump_state.get())
+    }
+
+    /// Either relay switches the simulated compressor.
+    fn select_output(&mut self, output: Output) {
+        self.0.log(&format!("SELECT_OUTPUT: {}", output));
+    }
+
+    fn is_on(&self) -> bool {
+        self.0.power_state.get()
+    }
+}
+
+pub struct DemoClock(Rc<Simulation>);
+
+impl Clock for DemoClock {
     fn sleep(&self, duration: Duration) {
-        self.log(&format!("SLEEP: {} sec", duration.as_secs()));
-        self.fake_time.set(self.fake_time.get() + duration);
-        let change_temp = match self.power_state {
-            true => COOL_DEGC_PER_SEC,
-            false => HEAT_DEGC_PER_SEC,
-        };
+        let sim = &self.0;
+        sim.log(&format!("SLEEP: {} sec", duration.as_secs()));
+        let mut controls = sim.controls.take();
+        match &mut controls {
+            Some(controls) => controls.pace(duration, |playback| sim.log_with(Some(*playback), "CONTROLS")),
+            None => {
+                if let Some(warp) = sim.parameters.time_warp {
+                    thread::sleep(duration.div_f32(warp));
+                }
+            }
+        }
+        sim.controls.replace(controls);
+        let mut elapsed = sim.elapsed();
+        let door_heat = sim.door_heat(elapsed, elapsed + duration);
+        sim.fake_time.set(sim.fake_time.get() + duration);
         let mut duration = duration;
-        if self.latent_cooling.get() > Duration::from_secs(0) {
-            let cool_duration = min(duration, self.latent_cooling.get());
-            self.current_temp
-                .set(self.current_temp.get() + cool_duration.as_secs_f32() * COOL_DEGC_PER_SEC);
+        if sim.latent_cooling.get() > Duration::from_secs(0) {
+            let cool_duration = min(duration, sim.latent_cooling.get());
+            sim.advance(true, elapsed, cool_duration);
+            elapsed += cool_duration;
             duration -= cool_duration;
-            self.latent_cooling.set(self.latent_cooling.get() - cool_duration);
+            sim.latent_cooling.set(sim.latent_cooling.get() - cool_duration);
         }
-        self.current_temp
-            .set(self.current_temp.get() + duration.as_secs_f32() * change_temp);
+        sim.advance(sim.power_state.get(), elapsed, duration);
+        sim.current_temp.set(sim.current_temp.get() + door_heat);
+        sim.start_due_events();
     }
 
     fn now(&self) -> Instant {
-        self.fake_time.get()
+        self.0.fake_time.get()
     }
 
-    fn restore_state(&self) -> Result<WorldState> {
-        self.log("GET_SINCE_LAST_OFF");
-        Ok(WorldState {
-            power_state: RestoredPowerState::OffForUnknownDuration,
-            heating_compensation: 0.0,
-            cooling_compensation: 0.5,
-        })
+    fn local_time(&self) -> LocalTime {
+        self.0.local_start.get().plus(self.0.elapsed())
+    }
+}
+
+pub struct DemoStore(Rc<Simulation>);
+
+impl Store for DemoStore {
+    fn restore_power_state(&self) -> Result<RestoredPowerState> {
+        self.0.log("GET_SINCE_LAST_OFF");
+        Ok(RestoredPowerState::OffForUnknownDuration)
+    }
+
+    fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+        (DegreesDelta(0.5), DegreesDelta::ZERO)
+    }
+
+    fn restore_cooling_rates(&self) -> Vec<f32> {
+        vec![]
     }
 
-    fn persist_last_off_transition(&mut self) -> Result<()> {
-        self.log("PERSIST_LAST_OFF");
+    fn restore_energy(&self) -> EnergyTotals {
+        EnergyTotals::default()
+    }
+
+    fn restore_runtime(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    fn restore_starts(&self) -> StartCounts {
+        StartCounts::default()
+    }
+
+    fn restore_boost(&self) -> Option<Boost> {
+        None
+    }
+
+    fn restore_intervals(&self) -> Option<MinimumIntervals> {
+        None
+    }
+
+    fn restore_overshoots(&self) -> Overshoots {
+        Overshoots::default()
+    }
+
+    fn restore_rest(&self) -> Option<RestPeriod> {
+        None
+    }
+
+    fn restore_shed(&self) -> Option<ShedPeriod> {
+        None
+    }
+
+    fn restore_extremes(&self) -> Option<CycleExtremes> {
+        None
+    }
+
+    fn restore_failover(&self) -> bool {
+        false
+    }
+
+    fn write_heartbeat(&mut self, _status: HeartbeatStatus) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        self.0.log("PERSIST_LAST_OFF");
         Ok(())
     }
 
-    fn persist_compensation(&mut self, _cooling: f32, _heating: f32) -> Result<()> {
-        self.log("PERSIST_COMPENSATION");
+    fn persist_compensation(&mut self, _cooling: DegreesDelta, _heating: DegreesDelta) -> Result<(), PersistError> {
+        self.0.log("PERSIST_COMPENSATION");
         Ok(())
     }
+
+    fn persist_cooling_rates(&mut self, _rates: &[f32]) -> Result<(), PersistError> {
+        self.0.log("PERSIST_COOLING_RATES");
+        Ok(())
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        self.0.log(&format!("PERSIST_ENERGY: {}", totals));
+        Ok(())
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        self.0.log(&format!("PERSIST_RUNTIME: {}s", runtime.as_secs()));
+        Ok(())
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        self.0.log(&format!("PERSIST_STARTS: {}", counts));
+        Ok(())
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        match boost {
+            Some(boost) => self.0.log(&format!(
+                "PERSIST_BOOST: {} to {} for {}s",
+                boost.band.start,
+                boost.band.end,
+                boost.remaining.as_secs()
+            )),
+            None => self.0.log("PERSIST_BOOST: none"),
+        }
+        Ok(())
+    }
+
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        match intervals {
+            Some(intervals) => self.0.log(&format!(
+                "PERSIST_INTERVALS: {}s on, {}s off",
+                intervals.on.as_secs(),
+                intervals.off.as_secs()
+            )),
+            None => self.0.log("PERSIST_INTERVALS: none"),
+        }
+        Ok(())
+    }
+
+    fn persist_overshoots(&mut self, _overshoots: &Overshoots) -> Result<(), PersistError> {
+        self.0.log("PERSIST_OVERSHOOTS");
+        Ok(())
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        match rest {
+            Some(rest) => self.0.log(&format!(
+                "PERSIST_REST: {}s after a {}s run",
+                rest.remaining.as_secs(),
+                rest.run.as_secs()
+            )),
+            None => self.0.log("PERSIST_REST: none"),
+        }
+        Ok(())
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        match shed {
+            Some(shed) => self.0.log(&format!(
+                "PERSIST_SHED: {}s left, {}s in",
+                shed.remaining.as_secs(),
+                shed.since.as_secs()
+            )),
+            None => self.0.log("PERSIST_SHED: none"),
+        }
+        Ok(())
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        match extremes {
+            Some(extremes) => self.0.log(&format!(
+                "PERSIST_EXTREMES: {} to {} {}s into the cycle",
+                extremes.min,
+                extremes.max,
+                extremes.running.as_secs()
+            )),
+            None => self.0.log("PERSIST_EXTREMES: none"),
+        }
+        Ok(())
+    }
+
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        self.0.log(&format!(
+            "RECORD_CYCLE: on for {}s, stopped by {}",
+            cycle.on.as_secs(),
+            cycle.reason
+        ));
+        Ok(())
+    }
+
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+        self.0.log(&format!("PERSIST_FAILOVER: {}", faulted));
+        Ok(())
+    }
+
+    fn switch_sensor(&mut self, _name: &str) {}
+}
+
+pub type DemoWorld = CompositeWorld<DemoSensor, DemoSwitch, DemoClock, FaultyStore<DemoStore>>;
+
+impl DemoWorld {
+    pub fn new(parameters: DemoParameters) -> Result<Self> {
+        parameters.validate()?;
+        let start = Instant::now();
+        let store_faults = parameters.scenario.store.clone();
+        let sim = Rc::new(Simulation {
+            current_temp: Cell::new(parameters.initial_temperature.0),
+            power_state: Cell::new(false),
+            stage2_state: Cell::new(false),
+            pump_state: Cell::new(false),
+            start,
+            local_start: Cell::new(local_now()),
+            fake_time: Cell::new(start),
+            latent_cooling: Cell::new(Duration::from_secs(0)),
+            started: Cell::new(0),
+            ambient_shift: Cell::new(0.0),
+            injected_reading: Cell::new(None),
+            faults: RefCell::new(FaultInjector::new(parameters.faults.clone())),
+            controls: RefCell::new(None),
+            parameters,
+        });
+        sim.start_due_events();
+        Ok(Self {
+            sensor: DemoSensor(sim.clone()),
+            switch: DemoSwitch(sim.clone()),
+            clock: DemoClock(sim.clone()),
+            store: FaultyStore::new(DemoStore(sim), store_faults),
+        })
+    }
+
+    /// Paces the simulation from the keyboard instead of the fixed time warp.
+    pub fn with_controls(self, controls: SimControls) -> Self {
+        self.clock.0.controls.replace(Some(controls));
+        self
+    }
+
+    /// Pins the wall clock the simulation starts at, rather than now.
+    #[cfg(test)]
+    pub fn starting_at(self, local: LocalTime) -> Self {
+        self.clock.0.local_start.set(local);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault_injection::RESET_VALUE;
+
+    #[test]
+    fn parameters_validated() {
+        assert!(DemoParameters::default().validate().is_ok());
+        let warming_down = DemoParameters {
+            warming_rate: -1.0,
+            ..Default::default()
+        };
+        assert!(warming_down.validate().is_err());
+        let cooling_up = DemoParameters {
+            cooling_rate: 1.0,
+            ..Default::default()
+        };
+        assert!(DemoWorld::new(cooling_up).is_err());
+        let frozen = DemoParameters {
+            time_warp: Some(0.0),
+            ..Default::default()
+        };
+        assert!(frozen.validate().is_err());
+    }
+
+    #[test]
+    fn integrates_rates_over_sleep() {
+        let parameters = DemoParameters {
+            initial_temperature: Celsius(4.0),
+            warming_rate: 6.0,
+            cooling_rate: -3.0,
+            latent_cooling: Duration::from_secs(60 * 10),
+            ..Default::default()
+        };
+        let mut world = DemoWorld::new(parameters).unwrap();
+        world.clock.sleep(Duration::from_secs(60 * 30));
+        assert!((world.sensor.get_temperature().unwrap().0 - 7.0).abs() < 0.001);
+        world.switch.set_power_state(true);
+        world.switch.set_power_state(false);
+        // Ten minutes of latent cooling, then twenty warming.
+        world.clock.sleep(Duration::from_secs(60 * 30));
+        assert!((world.sensor.get_temperature().unwrap().0 - 8.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn rc_model_keeps_latent_cooling() {
+        let parameters = DemoParameters {
+            initial_temperature: Celsius(4.0),
+            model: ThermalModel::Rc,
+            latent_cooling: Duration::from_secs(60 * 10),
+            ..Default::default()
+        };
+        let rc = parameters.rc.clone();
+        let mut world = DemoWorld::new(parameters).unwrap();
+        world.switch.set_power_state(true);
+        world.switch.set_power_state(false);
+        world.clock.sleep(Duration::from_secs(60 * 30));
+        let (start, latent) = (Duration::from_secs(0), Duration::from_secs(60 * 10));
+        let tail = rc.advance(Celsius(4.0), true, start, latent);
+        let expected = rc.advance(tail, false, latent, Duration::from_secs(60 * 20));
+        assert_eq!(expected, world.sensor.get_temperature().unwrap());
+    }
+    #[test]
+    fn ambient_follows_fake_clock() {
+        let mut parameters = DemoParameters {
+            model: ThermalModel::Rc,
+            ..Default::default()
+        };
+        parameters.rc.ambient.swing = 5.0;
+        parameters.rc.ambient.drift = 2.0;
+        let world = DemoWorld::new(parameters).unwrap();
+        assert_eq!(Some(Celsius(20.0)), world.sensor.0.ambient());
+        world.clock.sleep(Duration::from_secs(60 * 60 * 6));
+        assert!((world.sensor.0.ambient().unwrap().0 - 25.5).abs() < 0.001);
+        let linear = DemoWorld::new(DemoParameters::default()).unwrap();
+        assert_eq!(None, linear.sensor.0.ambient());
+    }
+
+    #[test]
+    fn scenario_events_apply_at_their_offsets() {
+        let parameters = DemoParameters {
+            initial_temperature: Celsius(4.0),
+            model: ThermalModel::Rc,
+            latent_cooling: Duration::from_secs(0),
+            scenario: "
+                [[event]]
+                at = \"2m\"
+                sensor_errors = \"2m\"
+
+                [[event]]
+                at = \"5m\"
+                door_open = 2.0
+                over = \"4m\"
+
+                [[event]]
+                at = \"5m\"
+                ambient_step = -10
+
+                [[event]]
+                at = \"6m\"
+                reading = 85.0
+            "
+            .parse()
+            .unwrap(),
+            ..Default::default()
+        };
+        let world = DemoWorld::new(parameters).unwrap();
+        let minute = Duration::from_secs(60);
+        world.clock.sleep(minute * 2);
+        assert!(world.sensor.get_temperature().is_err());
+        world.clock.sleep(minute * 2);
+        assert!(world.sensor.get_temperature().is_ok());
+        world.clock.sleep(minute);
+        assert_eq!(Some(Celsius(10.0)), world.sensor.0.ambient());
+        let before = world.sensor.get_temperature().unwrap();
+        world.clock.sleep(minute);
+        assert_eq!(Celsius(85.0), world.sensor.get_temperature().unwrap());
+        // Half the door's heat on top of a minute's slow warming, and only the next reading was replaced.
+        let after = world.sensor.get_temperature().unwrap();
+        assert!((after.0 - before.0 - 0.5).abs() < 0.1, "{} {}", before, after);
+    }
+
+    #[test]
+    fn scripted_faults_alter_readings() {
+        let parameters = DemoParameters {
+            initial_temperature: Celsius(4.0),
+            latent_cooling: Duration::from_secs(0),
+            scenario: "
+                [[event]]
+                at = \"1m\"
+                failure_burst = 2
+
+                [[event]]
+                at = \"2m\"
+                stuck = \"2m\"
+
+                [[event]]
+                at = \"5m\"
+                spike = -3.0
+            "
+            .parse()
+            .unwrap(),
+            ..Default::default()
+        };
+        let world = DemoWorld::new(parameters).unwrap();
+        let minute = Duration::from_secs(60);
+        world.clock.sleep(minute);
+        assert!(world.sensor.get_temperature().is_err());
+        assert!(world.sensor.get_temperature().is_err());
+        assert!(world.sensor.get_temperature().is_ok());
+        world.clock.sleep(minute);
+        let stuck = world.sensor.get_temperature().unwrap();
+        world.clock.sleep(minute);
+        assert_eq!(stuck, world.sensor.get_temperature().unwrap());
+        world.clock.sleep(minute);
+        let actual = world.sensor.get_temperature().unwrap();
+        assert!(actual > stuck);
+        world.clock.sleep(minute);
+        let spiked = world.sensor.get_temperature().unwrap();
+        assert!((actual.0 - 3.0 - spiked.0).abs() < 0.2, "{} {}", actual, spiked);
+    }
+
+    #[test]
+    fn random_faults_follow_the_seed() {
+        let readings = |seed| {
+            let parameters = DemoParameters {
+                faults: SensorFaults {
+                    seed,
+                    failure: 0.2,
+                    reset: 0.2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let world = DemoWorld::new(parameters).unwrap();
+            (0..50)
+                .map(|_| {
+                    world.clock.sleep(Duration::from_secs(10));
+                    world.sensor.get_temperature().ok()
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = readings(7);
+        assert_eq!(first, readings(7));
+        assert_ne!(first, readings(8));
+        assert!(first.contains(&None));
+        assert!(first.contains(&Some(RESET_VALUE)));
+    }
+
+    #[test]
+    fn ambient_steps_need_the_rc_model() {
+        let parameters = DemoParameters {
+            scenario: "[[event]]\nat = \"1h\"\nambient_step = 5".parse().unwrap(),
+            ..Default::default()
+        };
+        assert!(DemoWorld::new(parameters.clone()).is_err());
+        assert!(DemoWorld::new(DemoParameters {
+            model: ThermalModel::Rc,
+            ..parameters
+        })
+        .is_ok());
+    }
 }
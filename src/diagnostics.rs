@@ -0,0 +1,98 @@
+//! `kill -USR1` dumps the controller's internals to the log and a file, for debugging a live controller.
+
+use crate::status_board::Snapshot;
+use anyhow::{bail, Result};
+use picool_core::{config::Config, controller::ControllerState};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+static SIGNALLED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+const DUMP_FILE_PREFIX: &str = "diagnostics_";
+
+/// Asks the control loop for a dump at its next iteration. The signal handler only sets the flag, the control loop
+/// reads its own state.
+#[derive(Clone, Default)]
+pub struct DumpRequest {
+    requested: Arc<AtomicBool>,
+}
+
+impl DumpRequest {
+    /// As SIGUSR1 would.
+    #[cfg(test)]
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a dump was asked for since the last call.
+    pub fn take(&self) -> bool {
+        self.requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Requests a dump on every SIGUSR1.
+    pub fn on_signal(&self) -> Result<()> {
+        if SIGNALLED.set(self.requested.clone()).is_err() {
+            bail!("The SIGUSR1 handler is already installed.");
+        }
+        // SAFETY: The handler only performs an atomic store.
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            bail!("Failed to install handler for SIGUSR1.");
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    if let Some(requested) = SIGNALLED.get() {
+        requested.store(true, Ordering::Relaxed);
+    }
+}
+
+// Pure
+/// The status as `status` on the control socket serves it, then the controller's internals and the effective
+/// configuration, all as `<key> <value>` lines.
+pub fn render(now: Instant, status: Option<&Snapshot>, controller: &ControllerState, config: &Config) -> String {
+    let status = status.map_or_else(|| format!("state {}\n", controller.state()), Snapshot::to_string);
+    format!("{}{}\nconfig {:?}\n", status, controller.diagnostics(now), config)
+}
+
+/// Writes `dump` to `diagnostics_<seconds since the epoch>` in `dir`.
+pub fn write(dir: &Path, dump: &str) -> io::Result<PathBuf> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let path = dir.join(format!("{}{}", DUMP_FILE_PREFIX, since_epoch.as_secs()));
+    fs::write(&path, dump)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_requests_a_dump_once() {
+        let request = DumpRequest::default();
+        request.on_signal().unwrap();
+        assert!(!request.take());
+        unsafe { libc::raise(libc::SIGUSR1) };
+        assert!(request.take());
+        assert!(!request.take());
+        // The handler stays installed for the next one.
+        unsafe { libc::raise(libc::SIGUSR1) };
+        assert!(request.take());
+        assert!(DumpRequest::default().on_signal().is_err());
+    }
+}
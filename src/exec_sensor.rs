@@ -0,0 +1,206 @@
+//! A temperature printed by an external command, for sensors picool has no backend for.
+
+use crate::child_process::{self, RunError};
+use anyhow::{bail, Context, Result};
+use picool_core::{temperature::Celsius, world_error::SensorError};
+use std::{cell::Cell, io, path::PathBuf, process::Command, str::FromStr, time::Duration};
+
+/// The command and timeout of a `--sensor exec:<command>?timeout=<ms>` spec.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecConfig {
+    pub command: PathBuf,
+    pub timeout: Duration,
+}
+
+impl ExecConfig {
+    /// The command's file name, unique enough per sensor.
+    pub fn name(&self) -> String {
+        let command = self.command.file_name().unwrap_or(self.command.as_os_str());
+        command.to_string_lossy().into_owned()
+    }
+}
+
+/// The part after `exec:`, e.g. `/usr/local/bin/read-temp.sh?timeout=2000`.
+impl FromStr for ExecConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (command, query) = match s.find('?') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        if !command.starts_with('/') {
+            bail!("Sensor command '{}' must be an absolute path.", command);
+        }
+        let mut config = ExecConfig {
+            command: PathBuf::from(command),
+            timeout: Duration::from_millis(2000),
+        };
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            match parameter.strip_prefix("timeout=") {
+                Some(value) => {
+                    config.timeout = Duration::from_millis(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid sensor command timeout '{}'.", value))?,
+                    )
+                }
+                None => bail!("Unknown sensor command parameter '{}'.", parameter),
+            }
+        }
+        if config.timeout.as_millis() == 0 {
+            bail!("The sensor command timeout must be positive.");
+        }
+        Ok(config)
+    }
+}
+
+/// Runs the command for each reading, with `PICOOL_INSTANCE` and `PICOOL_POLL` in its environment.
+pub struct ExecSensor {
+    config: ExecConfig,
+    instance: String,
+    polls: Cell<u64>,
+}
+
+impl ExecSensor {
+    /// Fails unless the command can be executed, `instance` names the state files.
+    pub fn open(config: ExecConfig, instance: String) -> io::Result<Self> {
+        child_process::executable(&config.command)?;
+        Ok(Self {
+            config,
+            instance,
+            polls: Cell::new(0),
+        })
+    }
+
+    pub fn read_temperature(&self) -> Result<Celsius, SensorError> {
+        let poll = self.polls.get() + 1;
+        self.polls.set(poll);
+        let path = &self.config.command;
+        let mut command = Command::new(path);
+        command
+            .env("PICOOL_INSTANCE", &self.instance)
+            .env("PICOOL_POLL", poll.to_string());
+        let finished = match child_process::run(command, self.config.timeout) {
+            Ok(finished) => finished,
+            Err(RunError::Spawn(e)) => return Err(SensorError::from_io(path.clone(), e)),
+            Err(e) => return Err(SensorError::Unreadable(path.clone(), e.into_io())),
+        };
+        if let Some(failure) = finished.failure() {
+            return Err(SensorError::Unreadable(path.clone(), io::Error::other(failure)));
+        }
+        parse_reading(&finished.stdout)
+    }
+
+    /// Nothing stays open between readings, this only confirms the command is still there.
+    pub fn reinitialize(&mut self) -> Result<(), SensorError> {
+        child_process::executable(&self.config.command)
+            .map_err(|e| SensorError::from_io(self.config.command.clone(), e))
+    }
+}
+
+// Pure
+/// A single number in Celsius, surrounding whitespace allowed.
+fn parse_reading(stdout: &str) -> Result<Celsius, SensorError> {
+    stdout
+        .trim()
+        .parse()
+        .map(Celsius)
+        .map_err(|_| SensorError::Malformed(stdout.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{script, TempDir};
+    use std::{fs, thread, time::Instant};
+
+    fn sensor(dir: &TempDir, name: &str, body: &str) -> ExecSensor {
+        let config = format!("{}?timeout=500", script(dir, name, body).display())
+            .parse()
+            .unwrap();
+        ExecSensor::open(config, "exec_test".into()).unwrap()
+    }
+
+    /// Retries while another test thread's fork still holds a script open for writing.
+    fn read(sensor: &ExecSensor) -> Result<Celsius, SensorError> {
+        loop {
+            match sensor.read_temperature() {
+                Err(SensorError::Unreadable(_, e)) if e.kind() == io::ErrorKind::ExecutableFileBusy => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn parses_sensor_specs() {
+        let config: ExecConfig = "/usr/local/bin/read-temp.sh".parse().unwrap();
+        assert_eq!(PathBuf::from("/usr/local/bin/read-temp.sh"), config.command);
+        assert_eq!(Duration::from_millis(2000), config.timeout);
+        assert_eq!("read-temp.sh", config.name());
+        let config: ExecConfig = "/opt/probe?timeout=250".parse().unwrap();
+        assert_eq!(Duration::from_millis(250), config.timeout);
+        assert!("read-temp.sh".parse::<ExecConfig>().is_err());
+        assert!("/opt/probe?timeout=0".parse::<ExecConfig>().is_err());
+        assert!("/opt/probe?retries=2".parse::<ExecConfig>().is_err());
+    }
+
+    #[test]
+    fn reads_the_printed_value_with_the_poll_in_the_environment() {
+        let dir = TempDir::new();
+        let sensor = sensor(&dir, "value", "echo \"  3.$PICOOL_POLL\"; echo $PICOOL_INSTANCE >&2");
+        assert_eq!(Celsius(3.1), read(&sensor).unwrap());
+        assert_eq!(Celsius(3.2), read(&sensor).unwrap());
+    }
+
+    #[test]
+    fn maps_failures_onto_sensor_errors() {
+        let dir = TempDir::new();
+        let failing = sensor(&dir, "failing", "echo 'bus error' >&2; exit 3");
+        match read(&failing) {
+            Err(SensorError::Unreadable(_, e)) => assert_eq!("exited with code 3: bus error", e.to_string()),
+            other => panic!("Read {:?}.", other),
+        }
+        let garbage = sensor(&dir, "garbage", "echo 'no sensor'");
+        assert!(matches!(read(&garbage), Err(SensorError::Malformed(output)) if output == "no sensor"));
+
+        let slow = sensor(&dir, "slow", "sleep 5; echo 3.0");
+        let started = Instant::now();
+        match read(&slow) {
+            Err(SensorError::Unreadable(_, e)) => assert_eq!(io::ErrorKind::TimedOut, e.kind()),
+            other => panic!("Read {:?}.", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn does_not_wait_for_what_the_command_left_running() {
+        let dir = TempDir::new();
+        let sensor = sensor(&dir, "background", "sleep 5 & echo 4.5");
+        let started = Instant::now();
+        assert_eq!(Celsius(4.5), read(&sensor).unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn missing_or_not_executable_command() {
+        let dir = TempDir::new();
+        let missing = ExecConfig {
+            command: dir.path().join("missing"),
+            timeout: Duration::from_secs(1),
+        };
+        let e = ExecSensor::open(missing, "exec_missing".into()).err().unwrap();
+        assert_eq!(io::ErrorKind::NotFound, e.kind());
+        let path = dir.path().join("plain");
+        fs::write(&path, "4.0").unwrap();
+        let plain = ExecConfig {
+            command: path,
+            timeout: Duration::from_secs(1),
+        };
+        // Root may execute anything with an execute bit, and this one has none.
+        let e = ExecSensor::open(plain, "exec_plain".into()).err().unwrap();
+        assert_eq!(io::ErrorKind::PermissionDenied, e.kind());
+    }
+}
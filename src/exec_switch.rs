@@ -0,0 +1,232 @@
+//! The relay and fan switched by an external command, for smart plugs and relay boards picool has no backend for.
+
+use crate::{
+    child_process,
+    gpio::{GpioBackend, OutputLine},
+    startup_error::StartupError,
+};
+use anyhow::{bail, Context, Result};
+use log::*;
+use std::{path::PathBuf, process::Command, str::FromStr, thread, time::Duration};
+
+/// Between a failed attempt and the next, long enough for a flaky network plug to answer again.
+const RETRY_GAP: Duration = Duration::from_millis(250);
+
+/// The part after `exec:` of `--switch exec:<command>?timeout=<ms>&retries=<n>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchCommandConfig {
+    pub command: PathBuf,
+    pub timeout: Duration,
+    /// Attempts after the first failed one.
+    pub retries: u32,
+}
+
+impl FromStr for SwitchCommandConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (command, query) = match s.find('?') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        if !command.starts_with('/') {
+            bail!("Switch command '{}' must be an absolute path.", command);
+        }
+        let mut config = SwitchCommandConfig {
+            command: PathBuf::from(command),
+            timeout: Duration::from_millis(2000),
+            retries: 2,
+        };
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = parameter.split_at(parameter.find('=').unwrap_or(parameter.len()));
+            let value = value.trim_start_matches('=');
+            match name {
+                "timeout" => {
+                    config.timeout = Duration::from_millis(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid switch command timeout '{}'.", value))?,
+                    )
+                }
+                "retries" => {
+                    config.retries = value
+                        .parse()
+                        .with_context(|| format!("Invalid switch command retries '{}'.", value))?
+                }
+                _ => bail!("Unknown switch command parameter '{}'.", parameter),
+            }
+        }
+        if config.timeout.as_millis() == 0 {
+            bail!("The switch command timeout must be positive.");
+        }
+        Ok(config)
+    }
+}
+
+/// Runs `<command> on|off <line>`, the line being the relay or fan pin as given on the command line. Active low is
+/// left to the command.
+pub struct ExecSwitch {
+    config: SwitchCommandConfig,
+}
+
+impl ExecSwitch {
+    pub fn new(config: SwitchCommandConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl GpioBackend for ExecSwitch {
+    /// Turns the line off, as the character device claims lines inactive.
+    fn output(&self, line: u8) -> Result<Box<dyn OutputLine>, StartupError> {
+        child_process::executable(&self.config.command)
+            .map_err(|e| StartupError::from_command(self.config.command.clone(), e))?;
+        let output = ExecLine {
+            config: self.config.clone(),
+            line,
+            active: false,
+        };
+        output
+            .switch(false)
+            .map_err(|e| StartupError::SwitchCommandFailed(line, e))?;
+        Ok(Box::new(output))
+    }
+}
+
+struct ExecLine {
+    config: SwitchCommandConfig,
+    line: u8,
+    /// What the command last confirmed, it can't be asked.
+    active: bool,
+}
+
+impl ExecLine {
+    /// Tries the command up to `retries` more times, the last failure if none succeeds.
+    fn switch(&self, active: bool) -> Result<(), String> {
+        let state = if active { "on" } else { "off" };
+        let mut attempt = 0;
+        loop {
+            let mut command = Command::new(&self.config.command);
+            command.arg(state).arg(self.line.to_string());
+            let failure = match child_process::run(command, self.config.timeout) {
+                Ok(finished) => finished.failure(),
+                Err(e) => Some(e.to_string()),
+            };
+            match failure {
+                None => return Ok(()),
+                Some(failure) if attempt == self.config.retries => return Err(failure),
+                Some(failure) => debug!("Switch command {} {} failed, retrying. {}", state, self.line, failure),
+            }
+            attempt += 1;
+            thread::sleep(RETRY_GAP);
+        }
+    }
+}
+
+impl OutputLine for ExecLine {
+    fn set_active(&mut self, active: bool) {
+        match self.switch(active) {
+            Ok(()) => self.active = active,
+            Err(e) => error!(
+                "Failed to switch line {} {} with {}. {}",
+                self.line,
+                if active { "on" } else { "off" },
+                self.config.command.display(),
+                e
+            ),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{script, TempDir};
+    use std::{fs, io, path::Path, time::Instant};
+
+    fn config(command: PathBuf, query: &str) -> SwitchCommandConfig {
+        format!("{}{}", command.display(), query).parse().unwrap()
+    }
+
+    /// Retries while another test thread's fork still holds a script open for writing.
+    fn claim(switch: &ExecSwitch, line: u8) -> Result<Box<dyn OutputLine>, StartupError> {
+        loop {
+            match switch.output(line) {
+                Err(StartupError::SwitchCommandFailed(_, e)) if e.contains("busy") => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn calls(log: &Path) -> Vec<String> {
+        fs::read_to_string(log).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_switch_specs() {
+        let config: SwitchCommandConfig = "/usr/local/bin/set-power.sh".parse().unwrap();
+        assert_eq!(Duration::from_millis(2000), config.timeout);
+        assert_eq!(2, config.retries);
+        let config: SwitchCommandConfig = "/opt/plug?retries=0&timeout=500".parse().unwrap();
+        assert_eq!(Duration::from_millis(500), config.timeout);
+        assert_eq!(0, config.retries);
+        assert!("set-power.sh".parse::<SwitchCommandConfig>().is_err());
+        assert!("/opt/plug?timeout=0".parse::<SwitchCommandConfig>().is_err());
+        assert!("/opt/plug?retries=-1".parse::<SwitchCommandConfig>().is_err());
+        assert!("/opt/plug?pin=4".parse::<SwitchCommandConfig>().is_err());
+    }
+
+    #[test]
+    fn passes_the_state_and_line_as_arguments() {
+        let dir = TempDir::new();
+        let log = dir.path().join("calls");
+        let body = format!("echo \"$1 $2\" >> {}", log.display());
+        let switch = ExecSwitch::new(config(script(&dir, "plug", &body), ""));
+        let mut relay = claim(&switch, 17).unwrap();
+        assert!(!relay.is_active());
+        relay.set_active(true);
+        assert!(relay.is_active());
+        relay.set_active(false);
+        assert_eq!(vec!["off 17", "on 17", "off 17"], calls(&log));
+    }
+
+    #[test]
+    fn retries_and_keeps_the_last_confirmed_state() {
+        let dir = TempDir::new();
+        let log = dir.path().join("calls");
+        // Succeeds turning off only, so claiming works and switching on never does.
+        let body = format!("echo \"$1\" >> {}; [ \"$1\" = off ]", log.display());
+        let switch = ExecSwitch::new(config(script(&dir, "flaky", &body), "?retries=2"));
+        let mut relay = claim(&switch, 4).unwrap();
+        relay.set_active(true);
+        assert!(!relay.is_active());
+        assert_eq!(vec!["off", "on", "on", "on"], calls(&log));
+    }
+
+    #[test]
+    fn times_out_a_hung_command() {
+        let dir = TempDir::new();
+        let switch = ExecSwitch::new(config(script(&dir, "hung", "sleep 5"), "?timeout=100&retries=1"));
+        let started = Instant::now();
+        match claim(&switch, 4) {
+            Err(StartupError::SwitchCommandFailed(4, e)) => assert_eq!("no answer within 100ms", e),
+            other => panic!("Claimed {:?}.", other.err()),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn missing_command() {
+        let dir = TempDir::new();
+        let switch = ExecSwitch::new(config(dir.path().join("missing"), ""));
+        match switch.output(4) {
+            Err(StartupError::CommandMissing(_, e)) => assert_eq!(io::ErrorKind::NotFound, e.kind()),
+            other => panic!("Claimed {:?}.", other.err()),
+        }
+    }
+}
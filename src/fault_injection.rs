@@ -0,0 +1,275 @@
+use anyhow::{bail, Result};
+use picool_core::{
+    rng::Rng,
+    temperature::{Celsius, DegreesDelta},
+};
+use std::{fmt, time::Duration};
+
+/// What a DS18B20 reports when it has reset and not completed a conversion since.
+pub const RESET_VALUE: Celsius = Celsius(85.0);
+
+/// Random faults on the simulated sensor, probabilities per reading. All off by default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorFaults {
+    pub seed: u64,
+    /// A single failed read.
+    pub failure: f32,
+    /// Starts `burst_length` consecutive failed reads.
+    pub burst: f32,
+    pub burst_length: u32,
+    /// A reading off by `spike_size` in either direction.
+    pub spike: f32,
+    pub spike_size: f32,
+    /// The 85C reset value.
+    pub reset: f32,
+    /// The reading sticks at its current value for `stuck_length`.
+    pub stuck: f32,
+    pub stuck_length: Duration,
+}
+
+impl Default for SensorFaults {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            failure: 0.0,
+            burst: 0.0,
+            burst_length: 10,
+            spike: 0.0,
+            spike_size: 10.0,
+            reset: 0.0,
+            stuck: 0.0,
+            stuck_length: Duration::from_secs(60 * 30),
+        }
+    }
+}
+
+impl SensorFaults {
+    pub fn validate(&self) -> Result<()> {
+        for &(name, probability) in &[
+            ("failure", self.failure),
+            ("burst", self.burst),
+            ("spike", self.spike),
+            ("reset", self.reset),
+            ("stuck", self.stuck),
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                bail!(
+                    "Sensor {} probability must be between 0 and 1, got {}.",
+                    name,
+                    probability
+                );
+            }
+        }
+        if !self.spike_size.is_finite() {
+            bail!("Spike size must be finite.");
+        }
+        Ok(())
+    }
+
+    pub fn any(&self) -> bool {
+        self.failure > 0.0 || self.burst > 0.0 || self.spike > 0.0 || self.reset > 0.0 || self.stuck > 0.0
+    }
+}
+
+impl fmt::Display for SensorFaults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sensor faults seed {}: failure {}, burst {} of {}, spike {} of {}C, reset {}, stuck {} for {}m",
+            self.seed,
+            self.failure,
+            self.burst,
+            self.burst_length,
+            self.spike,
+            self.spike_size,
+            self.reset,
+            self.stuck,
+            self.stuck_length.as_secs() / 60
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Fault {
+    Failure,
+    Burst { remaining: u32 },
+    Stuck(Celsius),
+    Spike(DegreesDelta),
+    Reset,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Failure => write!(f, "read failure"),
+            Fault::Burst { remaining } => write!(f, "burst failure, {} more", remaining),
+            Fault::Stuck(value) => write!(f, "stuck at {}", value),
+            Fault::Spike(delta) => write!(f, "spike of {}", delta),
+            Fault::Reset => write!(f, "reset value {}", RESET_VALUE),
+        }
+    }
+}
+
+/// Applies random faults and scripted ones to readings, in a fixed order so a seed reproduces a run.
+pub struct FaultInjector {
+    faults: SensorFaults,
+    rng: Rng,
+    burst_remaining: u32,
+    stuck: Option<(Celsius, Duration)>,
+    pending_spike: Option<DegreesDelta>,
+}
+
+impl FaultInjector {
+    pub fn new(faults: SensorFaults) -> Self {
+        Self {
+            rng: Rng::new(faults.seed),
+            faults,
+            burst_remaining: 0,
+            stuck: None,
+            pending_spike: None,
+        }
+    }
+
+    /// The next `reads` readings fail.
+    pub fn start_burst(&mut self, reads: u32) {
+        self.burst_remaining += reads;
+    }
+
+    /// Readings return `value` until `until` into the run.
+    pub fn stick(&mut self, value: Celsius, until: Duration) {
+        self.stuck = Some((value, until));
+    }
+
+    /// The next good reading is off by `delta`.
+    pub fn spike(&mut self, delta: DegreesDelta) {
+        self.pending_spike = Some(delta);
+    }
+
+    /// What the sensor reports for `actual` taken `elapsed` into the run, with `extra_failure` scripted on top of the
+    /// configured failure probability. The fault that prevented the reading, or the one that altered it.
+    pub fn read(
+        &mut self,
+        actual: Celsius,
+        elapsed: Duration,
+        extra_failure: f32,
+    ) -> Result<(Celsius, Option<Fault>), Fault> {
+        let faults = &self.faults;
+        // Every draw happens on every read, so changing one probability doesn't shift the others' sequence.
+        let fail = self.rng.chance(faults.failure);
+        let scripted_fail = self.rng.chance(extra_failure);
+        let burst = self.rng.chance(faults.burst);
+        let stick = self.rng.chance(faults.stuck);
+        let spike = self.rng.chance(faults.spike);
+        let spike_up = self.rng.chance(0.5);
+        let reset = self.rng.chance(faults.reset);
+
+        if self.burst_remaining == 0 && burst {
+            self.burst_remaining = faults.burst_length;
+        }
+        if self.burst_remaining > 0 {
+            self.burst_remaining -= 1;
+            return Err(Fault::Burst {
+                remaining: self.burst_remaining,
+            });
+        }
+        if fail || scripted_fail {
+            return Err(Fault::Failure);
+        }
+        if self.stuck.is_some_and(|(_, until)| elapsed >= until) {
+            self.stuck = None;
+        }
+        if self.stuck.is_none() && stick {
+            self.stuck = Some((actual, elapsed + faults.stuck_length));
+        }
+        if let Some((value, _)) = self.stuck {
+            return Ok((value, Some(Fault::Stuck(value))));
+        }
+        if reset {
+            return Ok((RESET_VALUE, Some(Fault::Reset)));
+        }
+        let spike = match (self.pending_spike.take(), spike, spike_up) {
+            (Some(delta), _, _) => Some(delta),
+            (None, true, true) => Some(DegreesDelta(faults.spike_size)),
+            (None, true, false) => Some(DegreesDelta(-faults.spike_size)),
+            (None, false, _) => None,
+        };
+        match spike {
+            Some(delta) => Ok((actual + delta, Some(Fault::Spike(delta)))),
+            None => Ok((actual, None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reads(faults: SensorFaults, n: usize) -> Vec<Result<(Celsius, Option<Fault>), Fault>> {
+        let mut injector = FaultInjector::new(faults);
+        (0..n)
+            .map(|i| injector.read(Celsius(4.0), Duration::from_secs(10 * i as u64), 0.0))
+            .collect()
+    }
+
+    #[test]
+    fn no_faults_by_default() {
+        assert!(reads(SensorFaults::default(), 1000)
+            .iter()
+            .all(|r| *r == Ok((Celsius(4.0), None))));
+    }
+
+    #[test]
+    fn seed_reproduces_faults() {
+        let faults = SensorFaults {
+            failure: 0.1,
+            spike: 0.05,
+            reset: 0.01,
+            ..Default::default()
+        };
+        let first = reads(faults.clone(), 1000);
+        assert_eq!(first, reads(faults.clone(), 1000));
+        assert_ne!(first, reads(SensorFaults { seed: 2, ..faults }, 1000));
+        let failures = first.iter().filter(|r| **r == Err(Fault::Failure)).count();
+        assert!((60..140).contains(&failures), "{}", failures);
+        assert!(first.contains(&Ok((RESET_VALUE, Some(Fault::Reset)))));
+        assert!(first.contains(&Ok((Celsius(14.0), Some(Fault::Spike(DegreesDelta(10.0)))))));
+    }
+
+    #[test]
+    fn scripted_faults() {
+        let mut injector = FaultInjector::new(SensorFaults::default());
+        let at = |s: u64| Duration::from_secs(s);
+        injector.start_burst(2);
+        assert_eq!(
+            Err(Fault::Burst { remaining: 1 }),
+            injector.read(Celsius(4.0), at(0), 0.0)
+        );
+        assert_eq!(
+            Err(Fault::Burst { remaining: 0 }),
+            injector.read(Celsius(4.0), at(10), 0.0)
+        );
+        injector.stick(Celsius(3.0), at(40));
+        assert_eq!(
+            Ok((Celsius(3.0), Some(Fault::Stuck(Celsius(3.0))))),
+            injector.read(Celsius(4.0), at(30), 0.0)
+        );
+        assert_eq!(Ok((Celsius(4.0), None)), injector.read(Celsius(4.0), at(40), 0.0));
+        injector.spike(DegreesDelta(-2.0));
+        assert_eq!(
+            Ok((Celsius(2.0), Some(Fault::Spike(DegreesDelta(-2.0))))),
+            injector.read(Celsius(4.0), at(50), 0.0)
+        );
+        assert_eq!(Err(Fault::Failure), injector.read(Celsius(4.0), at(60), 1.0));
+    }
+
+    #[test]
+    fn probabilities_validated() {
+        assert!(SensorFaults::default().validate().is_ok());
+        assert!(SensorFaults {
+            failure: 1.5,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,461 @@
+//! Golden traces: a scenario's full decision sequence serialized to canonical JSON and compared against a committed
+//! copy in `golden/`. Set PICOOL_UPDATE_GOLDEN=1 to rewrite the committed copies after an intended behavior change.
+
+use crate::{cycle_history::quote, World, POLL_DURATION, SENSOR_RETRY_DURATION};
+use picool_core::{
+    config::Config,
+    controller::{step, ControllerState, Effect, Input},
+    determine_initial_state,
+    temperature::DegreesDelta,
+    validate_temperature, Seed, State,
+};
+use std::{
+    env, fmt, fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+const UPDATE_VAR: &str = "PICOOL_UPDATE_GOLDEN";
+/// A scenario that hasn't finished by then never will.
+const MAX_SIMULATED: Duration = Duration::from_secs(60 * 60 * 24 * 60);
+const DIFF_LINES: usize = 5;
+
+enum Value {
+    Number(f32),
+    Integer(u64),
+    Bool(bool),
+    Text(String),
+    Numbers(Vec<f32>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write_number(f, *n),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Text(s) => write!(f, "{}", quote(s)),
+            Value::Numbers(ns) => {
+                write!(f, "[")?;
+                for (i, n) in ns.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_number(f, *n)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Fixed precision so float noise below it can't churn the goldens, and no negative zero.
+fn write_number(f: &mut fmt::Formatter<'_>, n: f32) -> fmt::Result {
+    match n {
+        n if !n.is_finite() => write!(f, "null"),
+        0.0 => write!(f, "0.0000"),
+        n => write!(f, "{:.4}", n),
+    }
+}
+
+/// One JSON object per line, seconds since the scenario started rather than wall-clock time.
+struct Recorder {
+    start: Instant,
+    lines: Vec<String>,
+}
+
+impl Recorder {
+    fn record(&mut self, now: Instant, kind: &str, fields: Vec<(&str, Value)>) {
+        let mut line = format!("{{\"t\":{},\"kind\":{}", (now - self.start).as_secs(), quote(kind));
+        for (name, value) in fields {
+            line.push_str(&format!(",{}:{}", quote(name), value));
+        }
+        line.push('}');
+        self.lines.push(line);
+    }
+
+    fn into_json(self) -> String {
+        format!("[\n  {}\n]\n", self.lines.join(",\n  "))
+    }
+}
+
+/// Drives the controller through `world` the way `run` does until `cycles` cooling cycles complete or it terminates,
+/// recording every state transition, threshold update, relay switch, persisted value and notification.
+pub fn trace(mut world: impl World, config: &Config, cycles: u32) -> String {
+    let start = world.now();
+    let restored = world.restore_state().expect("Scenario worlds restore.");
+    let seed = Seed {
+        state: determine_initial_state(Ok(restored.power_state), start, config.intervals),
+        compensation: (restored.cooling_compensation, restored.heating_compensation),
+        cooling_rates: restored.cooling_rates,
+        energy: restored.energy,
+        runtime: restored.runtime,
+        starts: restored.starts,
+        boost: restored.boost,
+        intervals: restored.intervals,
+        overshoots: restored.overshoots,
+        rest: restored.rest,
+        shed: restored.shed,
+        extremes: restored.extremes,
+        failover: restored.failover,
+    };
+    let mut controller = ControllerState::new(seed, config, start);
+    let mut recorder = Recorder {
+        start,
+        lines: Vec::new(),
+    };
+    let (mut state, mut thresholds) = (controller.state(), controller.thresholds());
+    recorder.record(
+        start,
+        "start",
+        vec![
+            ("state", Value::Text(state.to_string())),
+            ("low", Value::Number(thresholds.0 .0)),
+            ("high", Value::Number(thresholds.1 .0)),
+        ],
+    );
+
+    let mut failed = false;
+    while controller.completed_cycles() < cycles {
+        if failed {
+            world.sleep(SENSOR_RETRY_DURATION);
+        } else if controller.state() != State::InitiallyOff {
+            world.sleep(POLL_DURATION);
+        }
+        let now = world.now();
+        assert!(now - start < MAX_SIMULATED, "Scenario didn't finish {} cycles.", cycles);
+        let temperature = world.get_temperature().and_then(validate_temperature);
+        failed = temperature.is_err();
+        let input = Input {
+            temperature,
+            now,
+            local: world.local_time(),
+            ambient: world.get_ambient_temperature().and_then(Result::ok),
+            acknowledged: false,
+            reset_runtime: false,
+            sensor_replaced: false,
+            boost: None,
+            revert_timing: false,
+            shed: false,
+            pump: world.pump_state(),
+            current: world.get_current().and_then(Result::ok),
+            reset_failover: false,
+            reload: None,
+        };
+        let (next, effects) = step(controller, input);
+        controller = next;
+
+        if controller.state() != state {
+            let fields = vec![
+                ("from", Value::Text(state.to_string())),
+                ("to", Value::Text(controller.state().to_string())),
+            ];
+            recorder.record(now, "state", fields);
+            state = controller.state();
+        }
+        if controller.thresholds() != thresholds {
+            thresholds = controller.thresholds();
+            let fields = vec![
+                ("low", Value::Number(thresholds.0 .0)),
+                ("high", Value::Number(thresholds.1 .0)),
+            ];
+            recorder.record(now, "thresholds", fields);
+        }
+        for effect in effects {
+            match effect {
+                Effect::SetPower(on) => {
+                    world.set_power_state(on);
+                    recorder.record(now, "power", vec![("on", Value::Bool(on))]);
+                }
+                Effect::SetFan(on) => {
+                    world.set_fan_state(on);
+                    recorder.record(now, "fan", vec![("on", Value::Bool(on))]);
+                }
+                Effect::SetStage2(on) => {
+                    world.set_stage2_state(on);
+                    recorder.record(now, "stage2", vec![("on", Value::Bool(on))]);
+                }
+                Effect::SetPump(on) => {
+                    world.set_pump_state(on);
+                    recorder.record(now, "pump", vec![("on", Value::Bool(on))]);
+                }
+                Effect::SelectOutput(output) => {
+                    world.select_output(output);
+                    recorder.record(now, "output", vec![("output", Value::Text(output.to_string()))]);
+                }
+                Effect::PersistLastOff => {
+                    world.persist_last_off_transition().expect("Scenario worlds persist.");
+                    recorder.record(now, "persist_last_off", vec![]);
+                }
+                Effect::PersistCompensation { cooling, heating } => {
+                    world
+                        .persist_compensation(cooling, heating)
+                        .expect("Scenario worlds persist.");
+                    let fields = vec![
+                        ("cooling", Value::Number(cooling.0)),
+                        ("heating", Value::Number(heating.0)),
+                    ];
+                    recorder.record(now, "persist_compensation", fields);
+                }
+                Effect::PersistCoolingRates(rates) => {
+                    world.persist_cooling_rates(&rates).expect("Scenario worlds persist.");
+                    recorder.record(now, "persist_cooling_rates", vec![("rates", Value::Numbers(rates))]);
+                }
+                Effect::PersistEnergy(totals) => {
+                    world.persist_energy(totals).expect("Scenario worlds persist.");
+                    let fields = vec![
+                        ("today", Value::Number(totals.today as f32)),
+                        ("lifetime", Value::Number(totals.lifetime as f32)),
+                    ];
+                    recorder.record(now, "persist_energy", fields);
+                }
+                Effect::PersistRuntime(runtime) => {
+                    world.persist_runtime(runtime).expect("Scenario worlds persist.");
+                    recorder.record(
+                        now,
+                        "persist_runtime",
+                        vec![("seconds", Value::Integer(runtime.as_secs()))],
+                    );
+                }
+                Effect::PersistStarts(counts) => {
+                    world.persist_starts(&counts).expect("Scenario worlds persist.");
+                    let fields = vec![
+                        ("today", Value::Integer(counts.today as u64)),
+                        ("lifetime", Value::Integer(counts.lifetime)),
+                    ];
+                    recorder.record(now, "persist_starts", fields);
+                }
+                Effect::PersistBoost(boost) => {
+                    world.persist_boost(boost.as_ref()).expect("Scenario worlds persist.");
+                    let fields = match boost {
+                        Some(boost) => vec![
+                            ("bottom", Value::Number(boost.band.start.0)),
+                            ("top", Value::Number(boost.band.end.0)),
+                            ("seconds", Value::Integer(boost.remaining.as_secs())),
+                        ],
+                        None => vec![],
+                    };
+                    recorder.record(now, "persist_boost", fields);
+                }
+                Effect::PersistIntervals(intervals) => {
+                    world.persist_intervals(intervals).expect("Scenario worlds persist.");
+                    let fields = match intervals {
+                        Some(intervals) => vec![
+                            ("on", Value::Integer(intervals.on.as_secs())),
+                            ("off", Value::Integer(intervals.off.as_secs())),
+                        ],
+                        None => vec![],
+                    };
+                    recorder.record(now, "persist_intervals", fields);
+                }
+                Effect::PersistOvershoots(overshoots) => {
+                    world.persist_overshoots(&overshoots).expect("Scenario worlds persist.");
+                    let degrees = |misses: &[DegreesDelta]| misses.iter().map(|miss| miss.0).collect();
+                    let fields = vec![
+                        ("over", Value::Numbers(degrees(&overshoots.over))),
+                        ("under", Value::Numbers(degrees(&overshoots.under))),
+                    ];
+                    recorder.record(now, "persist_overshoots", fields);
+                }
+                Effect::PersistRest(rest) => {
+                    world.persist_rest(rest.as_ref()).expect("Scenario worlds persist.");
+                    let fields = match rest {
+                        Some(rest) => vec![
+                            ("run", Value::Integer(rest.run.as_secs())),
+                            ("seconds", Value::Integer(rest.remaining.as_secs())),
+                        ],
+                        None => vec![],
+                    };
+                    recorder.record(now, "persist_rest", fields);
+                }
+                Effect::PersistShed(shed) => {
+                    world.persist_shed(shed.as_ref()).expect("Scenario worlds persist.");
+                    let fields = match shed {
+                        Some(shed) => vec![
+                            ("since", Value::Integer(shed.since.as_secs())),
+                            ("seconds", Value::Integer(shed.remaining.as_secs())),
+                        ],
+                        None => vec![],
+                    };
+                    recorder.record(now, "persist_shed", fields);
+                }
+                Effect::PersistFailover(faulted) => {
+                    world.persist_failover(faulted).expect("Scenario worlds persist.");
+                    recorder.record(now, "persist_failover", vec![("faulted", Value::Bool(faulted))]);
+                }
+                Effect::RecordCycle(cycle) => {
+                    world.record_cycle(&cycle).expect("Scenario worlds persist.");
+                    let mut fields = vec![
+                        ("on", Value::Integer(cycle.on.as_secs())),
+                        ("reason", Value::Text(cycle.reason.to_string())),
+                    ];
+                    if let Some(abnormal) = cycle.abnormal {
+                        fields.push(("abnormal", Value::Text(abnormal)));
+                    }
+                    recorder.record(now, "record_cycle", fields);
+                }
+                Effect::PersistExtremes(extremes) => {
+                    world
+                        .persist_extremes(extremes.as_ref())
+                        .expect("Scenario worlds persist.");
+                    let fields = match extremes {
+                        Some(extremes) => vec![
+                            ("on", Value::Bool(extremes.on)),
+                            ("min", Value::Number(extremes.min.0)),
+                            ("max", Value::Number(extremes.max.0)),
+                            ("running", Value::Integer(extremes.running.as_secs())),
+                        ],
+                        None => vec![],
+                    };
+                    recorder.record(now, "persist_extremes", fields);
+                }
+                Effect::Notify(event) => {
+                    recorder.record(now, "notify", vec![("message", Value::Text(event.to_string()))]);
+                }
+                Effect::ReinitializeSensor => {
+                    let result = world.reinitialize();
+                    recorder.record(now, "reinitialize", vec![("ok", Value::Bool(result.is_ok()))]);
+                }
+                Effect::Terminate(termination) => {
+                    let fields = vec![
+                        ("exit_code", Value::Integer(termination.exit_code() as u64)),
+                        ("message", Value::Text(termination.to_string())),
+                    ];
+                    recorder.record(now, "terminate", fields);
+                    return recorder.into_json();
+                }
+                Effect::Status(_) | Effect::Heartbeat(_) | Effect::Transition(_) => {}
+            }
+        }
+    }
+    recorder.into_json()
+}
+
+/// Panics with the first differences unless `actual` matches `golden/<name>.json`.
+pub fn assert_golden(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("golden")
+        .join(format!("{}.json", name));
+    if env::var_os(UPDATE_VAR).is_some() {
+        fs::write(&path, actual).unwrap_or_else(|e| panic!("Failed to write {}. {}", path.display(), e));
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Failed to read {}, run with {}=1 to create it. {}",
+            path.display(),
+            UPDATE_VAR,
+            e
+        )
+    });
+    if let Some(diff) = diff(&expected, actual) {
+        panic!(
+            "{} no longer matches {}, run with {}=1 if the change is intended.\n{}",
+            name,
+            path.display(),
+            UPDATE_VAR,
+            diff
+        );
+    }
+}
+
+/// The first few differing lines, None if the texts match.
+fn diff(expected: &str, actual: &str) -> Option<String> {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let first = (0..expected.len().max(actual.len())).find(|&i| expected.get(i) != actual.get(i))?;
+    let mut report = format!(
+        "First difference at line {} ({} lines expected, {} actual):\n",
+        first + 1,
+        expected.len(),
+        actual.len()
+    );
+    for i in first..(first + DIFF_LINES) {
+        if let Some(line) = expected.get(i) {
+            report.push_str(&format!("- {}\n", line));
+        }
+        if let Some(line) = actual.get(i) {
+            report.push_str(&format!("+ {}\n", line));
+        }
+    }
+    Some(report)
+}
+
+mod tests {
+    use super::*;
+    use crate::{
+        demo_world::{DemoParameters, DemoWorld},
+        thermal_model::ThermalModel,
+    };
+    use picool_core::energy::LocalTime;
+
+    /// Scenarios start at 2023-11-14 22:13:20 so day boundaries don't move with the wall clock.
+    fn scenario(parameters: DemoParameters) -> DemoWorld {
+        DemoWorld::new(parameters)
+            .unwrap()
+            .starting_at(LocalTime::from_local_seconds(1_700_000_000))
+    }
+
+    #[test]
+    fn serialization_is_canonical() {
+        let values = [
+            Value::Number(-0.0),
+            Value::Number(1.0 / 3.0),
+            Value::Number(f32::NAN),
+            Value::Integer(7),
+            Value::Bool(false),
+            Value::Text("a \"b\"\\\n".into()),
+            Value::Numbers(vec![0.5, -2.0]),
+        ];
+        let rendered: Vec<String> = values.iter().map(Value::to_string).collect();
+        assert_eq!(
+            vec![
+                "0.0000",
+                "0.3333",
+                "null",
+                "7",
+                "false",
+                "\"a \\\"b\\\"\\\\\\u000a\"",
+                "[0.5000,-2.0000]"
+            ],
+            rendered
+        );
+    }
+
+    #[test]
+    fn diff_reports_first_difference() {
+        assert_eq!(None, diff("a\nb\n", "a\nb\n"));
+        assert_eq!(
+            Some("First difference at line 2 (2 lines expected, 3 actual):\n- b\n+ c\n+ d\n".to_string()),
+            diff("a\nb\n", "a\nc\nd\n")
+        );
+    }
+
+    #[test]
+    fn nominal_cycling() {
+        let world = scenario(DemoParameters::default());
+        assert_golden("nominal-cycling", &trace(world, &Config::default(), 10));
+    }
+
+    #[test]
+    fn sensor_failure_recovery() {
+        let parameters = DemoParameters {
+            scenario: "[[event]]\nat = \"2h\"\nsensor_errors = \"20m\"".parse().unwrap(),
+            ..Default::default()
+        };
+        let world = scenario(parameters);
+        assert_golden("sensor-failure-recovery", &trace(world, &Config::default(), 6));
+    }
+
+    #[test]
+    fn compensation_convergence() {
+        // A long latent period overshoots the low threshold, the compensator has to learn to switch off early.
+        let parameters = DemoParameters {
+            model: ThermalModel::Rc,
+            latent_cooling: Duration::from_secs(60 * 12),
+            ..Default::default()
+        };
+        let world = scenario(parameters);
+        assert_golden("compensation-convergence", &trace(world, &Config::default(), 30));
+    }
+}
@@ -0,0 +1,567 @@
+//! The relay and fan outputs, through rppal's memory-mapped registers on a Raspberry Pi, the Linux GPIO character
+//! device anywhere else, or an external command.
+
+use crate::{
+    exec_switch::{ExecSwitch, SwitchCommandConfig},
+    startup_error::StartupError,
+};
+use anyhow::{bail, Result};
+use log::*;
+use rppal::gpio::{Gpio, OutputPin};
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+const CHIP_DIRECTORY: &str = "/dev";
+const CONSUMER: &str = "picool";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum BackendKind {
+    /// /dev/gpiomem, Raspberry Pi only. Claiming a pin keeps the level it was left at.
+    #[default]
+    Rppal,
+    /// /dev/gpiochipN. Lines are claimed inactive, so a relay left on by a crash turns off at startup.
+    Cdev,
+    /// `exec:<command>`, run with on or off and the line.
+    Exec(SwitchCommandConfig),
+    /// Claims nothing, for `--shadow`.
+    Detached,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rppal" => Ok(BackendKind::Rppal),
+            "cdev" => Ok(BackendKind::Cdev),
+            _ => match s.strip_prefix("exec:") {
+                Some(command) => Ok(BackendKind::Exec(command.parse()?)),
+                None => bail!("Unknown GPIO backend '{}', expected rppal, cdev or exec:<command>.", s),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpioConfig {
+    pub backend: BackendKind,
+    /// The character device, for the cdev backend.
+    pub chip: PathBuf,
+    /// The outputs energize the relays when low.
+    pub active_low: bool,
+    /// The least time between two actuations of the relay, whatever asks for them.
+    pub spacing: Duration,
+}
+
+impl Default for GpioConfig {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::default(),
+            chip: Path::new(CHIP_DIRECTORY).join("gpiochip0"),
+            active_low: false,
+            spacing: Duration::from_secs(5),
+        }
+    }
+}
+
+impl GpioConfig {
+    /// `gpiochip0` is looked up in /dev, anything with a slash is a path.
+    pub fn set_chip(&mut self, chip: &str) {
+        self.chip = match chip.contains('/') {
+            true => PathBuf::from(chip),
+            false => Path::new(CHIP_DIRECTORY).join(chip),
+        };
+    }
+}
+
+/// One output, in terms of the relay it drives rather than the level on the wire.
+pub trait OutputLine {
+    fn set_active(&mut self, active: bool);
+    fn is_active(&self) -> bool;
+}
+
+pub trait GpioBackend {
+    /// Claims the line with BCM or chip offset `line` as an output.
+    fn output(&self, line: u8) -> Result<Box<dyn OutputLine>, StartupError>;
+}
+
+/// Opened once the pins are locked, no backend touches a line before it's claimed.
+pub fn backend(config: &GpioConfig) -> Result<Box<dyn GpioBackend>, StartupError> {
+    match &config.backend {
+        BackendKind::Rppal => Ok(Box::new(Memory {
+            pins: Gpio::new().map_err(StartupError::from_gpio)?,
+            active_low: config.active_low,
+        })),
+        BackendKind::Cdev => Ok(Box::new(Cdev {
+            chip: CharDevice::open(&config.chip)?,
+            active_low: config.active_low,
+        })),
+        BackendKind::Exec(command) => Ok(Box::new(ExecSwitch::new(command.clone()))),
+        BackendKind::Detached => Ok(Box::new(Detached)),
+    }
+}
+
+/// Pins that are driven high or low directly.
+pub trait MemoryPins {
+    fn output(&self, line: u8) -> Result<Box<dyn MemoryPin>, StartupError>;
+}
+
+pub trait MemoryPin {
+    fn set_high(&mut self, high: bool);
+    fn is_set_high(&self) -> bool;
+}
+
+impl MemoryPins for Gpio {
+    fn output(&self, line: u8) -> Result<Box<dyn MemoryPin>, StartupError> {
+        Ok(Box::new(self.get(line).map_err(StartupError::from_gpio)?.into_output()))
+    }
+}
+
+impl MemoryPin for OutputPin {
+    fn set_high(&mut self, high: bool) {
+        match high {
+            true => OutputPin::set_high(self),
+            false => self.set_low(),
+        }
+    }
+
+    fn is_set_high(&self) -> bool {
+        OutputPin::is_set_high(self)
+    }
+}
+
+/// Active low is inverted here, the registers only know levels.
+pub struct Memory<P> {
+    pins: P,
+    active_low: bool,
+}
+
+impl<P: MemoryPins> GpioBackend for Memory<P> {
+    fn output(&self, line: u8) -> Result<Box<dyn OutputLine>, StartupError> {
+        Ok(Box::new(MemoryLine {
+            pin: self.pins.output(line)?,
+            active_low: self.active_low,
+        }))
+    }
+}
+
+struct MemoryLine {
+    pin: Box<dyn MemoryPin>,
+    active_low: bool,
+}
+
+impl OutputLine for MemoryLine {
+    fn set_active(&mut self, active: bool) {
+        self.pin.set_high(active != self.active_low)
+    }
+
+    fn is_active(&self) -> bool {
+        self.pin.is_set_high() != self.active_low
+    }
+}
+
+/// A line request as picool makes it: one output line, starting inactive.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LineRequest {
+    pub offset: u8,
+    pub active_low: bool,
+}
+
+/// A GPIO chip whose lines are requested with their flags, and then driven by logical value.
+pub trait Chip {
+    fn request_output(&self, request: LineRequest) -> io::Result<Box<dyn LineValue>>;
+}
+
+pub trait LineValue {
+    fn set(&mut self, active: bool) -> io::Result<()>;
+    fn get(&self) -> io::Result<bool>;
+}
+
+/// Active low is a line flag, the kernel inverts the level.
+pub struct Cdev<C> {
+    chip: C,
+    active_low: bool,
+}
+
+impl<C: Chip> GpioBackend for Cdev<C> {
+    fn output(&self, line: u8) -> Result<Box<dyn OutputLine>, StartupError> {
+        let request = LineRequest {
+            offset: line,
+            active_low: self.active_low,
+        };
+        let value = self.chip.request_output(request).map_err(|e| match e.raw_os_error() {
+            Some(libc::EINVAL) => StartupError::PinNotAvailable(line),
+            _ => StartupError::GpioLine(line, e),
+        })?;
+        Ok(Box::new(CdevLine {
+            line,
+            value,
+            active: false,
+        }))
+    }
+}
+
+struct CdevLine {
+    line: u8,
+    value: Box<dyn LineValue>,
+    /// What was last requested, in case the line can't be read back.
+    active: bool,
+}
+
+impl OutputLine for CdevLine {
+    fn set_active(&mut self, active: bool) {
+        match self.value.set(active) {
+            Ok(()) => self.active = active,
+            Err(e) => error!("Failed to switch GPIO line {}. {}", self.line, e),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.value.get().unwrap_or(self.active)
+    }
+}
+
+/// Lines that only remember what they were asked, no pin is touched.
+struct Detached;
+
+impl GpioBackend for Detached {
+    fn output(&self, _line: u8) -> Result<Box<dyn OutputLine>, StartupError> {
+        Ok(Box::new(DetachedLine(false)))
+    }
+}
+
+struct DetachedLine(bool);
+
+impl OutputLine for DetachedLine {
+    fn set_active(&mut self, active: bool) {
+        self.0 = active
+    }
+
+    fn is_active(&self) -> bool {
+        self.0
+    }
+}
+
+// The GPIO v2 character device ABI, from linux/gpio.h.
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+const GPIO_MAX_NAME_SIZE: usize = 32;
+const GPIO_V2_LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+/// _IOWR(0xB4, 0x07, struct gpio_v2_line_request)
+const GPIO_V2_GET_LINE_IOCTL: u64 = 0xC250_B407;
+/// _IOWR(0xB4, 0x0E, struct gpio_v2_line_values)
+const GPIO_V2_LINE_GET_VALUES_IOCTL: u64 = 0xC010_B40E;
+/// _IOWR(0xB4, 0x0F, struct gpio_v2_line_values)
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u64 = 0xC010_B40F;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    /// Flags, values or a debounce period by `id`.
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct KernelLineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+const _: () = assert!(std::mem::size_of::<KernelLineRequest>() == 592);
+
+impl From<LineRequest> for KernelLineRequest {
+    fn from(request: LineRequest) -> Self {
+        let mut offsets = [0; GPIO_V2_LINES_MAX];
+        offsets[0] = request.offset as u32;
+        let mut consumer = [0; GPIO_MAX_NAME_SIZE];
+        consumer[..CONSUMER.len()].copy_from_slice(CONSUMER.as_bytes());
+        let mut attrs = [LineConfigAttribute::default(); GPIO_V2_LINE_NUM_ATTRS_MAX];
+        // Inactive from the moment the line is claimed.
+        attrs[0] = LineConfigAttribute {
+            attr: LineAttribute {
+                id: GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES,
+                padding: 0,
+                value: 0,
+            },
+            mask: 1,
+        };
+        let active_low = match request.active_low {
+            true => GPIO_V2_LINE_FLAG_ACTIVE_LOW,
+            false => 0,
+        };
+        Self {
+            offsets,
+            consumer,
+            config: LineConfig {
+                flags: GPIO_V2_LINE_FLAG_OUTPUT | active_low,
+                num_attrs: 1,
+                padding: [0; 5],
+                attrs,
+            },
+            num_lines: 1,
+            event_buffer_size: 0,
+            padding: [0; 5],
+            fd: -1,
+        }
+    }
+}
+
+/// /dev/gpiochipN.
+pub struct CharDevice {
+    file: File,
+}
+
+impl CharDevice {
+    fn open(path: &Path) -> Result<Self, StartupError> {
+        match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => Ok(Self { file }),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                Err(StartupError::GpioPermissionDenied(path.display().to_string()))
+            }
+            Err(e) => Err(StartupError::GpioChipMissing(path.to_path_buf(), e)),
+        }
+    }
+}
+
+impl Chip for CharDevice {
+    fn request_output(&self, request: LineRequest) -> io::Result<Box<dyn LineValue>> {
+        let mut request = KernelLineRequest::from(request);
+        // SAFETY: The request matches the kernel's layout and outlives the call.
+        let result = unsafe { libc::ioctl(self.file.as_raw_fd(), GPIO_V2_GET_LINE_IOCTL as _, &mut request) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: The kernel returned a new descriptor that nothing else owns.
+        Ok(Box::new(CdevValue(unsafe { File::from_raw_fd(request.fd) })))
+    }
+}
+
+/// The line request descriptor, releasing it releases the line.
+struct CdevValue(File);
+
+impl CdevValue {
+    fn values(&self, request: u64, values: &mut LineValues) -> io::Result<()> {
+        // SAFETY: The values match the kernel's layout and outlive the call.
+        match unsafe { libc::ioctl(self.0.as_raw_fd(), request as _, values as *mut LineValues) } {
+            result if result < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl LineValue for CdevValue {
+    fn set(&mut self, active: bool) -> io::Result<()> {
+        let mut values = LineValues {
+            bits: active as u64,
+            mask: 1,
+        };
+        self.values(GPIO_V2_LINE_SET_VALUES_IOCTL, &mut values)
+    }
+
+    fn get(&self) -> io::Result<bool> {
+        let mut values = LineValues { bits: 0, mask: 1 };
+        self.values(GPIO_V2_LINE_GET_VALUES_IOCTL, &mut values)?;
+        Ok(values.bits & 1 == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+    /// The level on the wire of each line, shared by a loopback and the test looking at it.
+    type Levels = Rc<RefCell<HashMap<u8, bool>>>;
+
+    struct LoopbackPins(Levels);
+
+    struct LoopbackPin(u8, Levels);
+
+    impl MemoryPins for LoopbackPins {
+        fn output(&self, line: u8) -> Result<Box<dyn MemoryPin>, StartupError> {
+            match line {
+                0..=27 => Ok(Box::new(LoopbackPin(line, self.0.clone()))),
+                _ => Err(StartupError::PinNotAvailable(line)),
+            }
+        }
+    }
+
+    impl MemoryPin for LoopbackPin {
+        fn set_high(&mut self, high: bool) {
+            self.1.borrow_mut().insert(self.0, high);
+        }
+
+        fn is_set_high(&self) -> bool {
+            self.1.borrow().get(&self.0).copied().unwrap_or(false)
+        }
+    }
+
+    /// Applies the request flags the way the kernel does.
+    struct LoopbackChip(Levels);
+
+    struct LoopbackValue(LineRequest, Levels);
+
+    impl Chip for LoopbackChip {
+        fn request_output(&self, request: LineRequest) -> io::Result<Box<dyn LineValue>> {
+            if request.offset > 27 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            let kernel = KernelLineRequest::from(request);
+            let initial = kernel.config.attrs[0].attr.value & 1 == 1;
+            let mut value = LoopbackValue(request, self.0.clone());
+            value.set(initial)?;
+            Ok(Box::new(value))
+        }
+    }
+
+    impl LineValue for LoopbackValue {
+        fn set(&mut self, active: bool) -> io::Result<()> {
+            self.1.borrow_mut().insert(self.0.offset, active != self.0.active_low);
+            Ok(())
+        }
+
+        fn get(&self) -> io::Result<bool> {
+            Ok(self.1.borrow()[&self.0.offset] != self.0.active_low)
+        }
+    }
+
+    /// What every backend has to do, `restores` whether a relay left on is found on.
+    fn behaves_as_relay_outputs(backend: &dyn GpioBackend, levels: &Levels, active_low: bool, restores: bool) {
+        let level = |line| levels.borrow().get(&line).copied();
+        // The relay was left on, the fan off.
+        levels.borrow_mut().insert(17, !active_low);
+        levels.borrow_mut().insert(27, active_low);
+
+        let mut relay = backend.output(17).unwrap();
+        assert_eq!(restores, relay.is_active());
+        assert_eq!(Some(restores != active_low), level(17));
+        let mut fan = backend.output(27).unwrap();
+        assert!(!fan.is_active());
+
+        relay.set_active(true);
+        assert!(relay.is_active());
+        assert_eq!(Some(!active_low), level(17));
+        assert_eq!(Some(active_low), level(27));
+        fan.set_active(true);
+        relay.set_active(false);
+        assert!(!relay.is_active() && fan.is_active());
+        assert_eq!((Some(active_low), Some(!active_low)), (level(17), level(27)));
+
+        assert!(matches!(backend.output(99), Err(StartupError::PinNotAvailable(99))));
+    }
+
+    #[test]
+    fn memory_mapped_backend() {
+        for active_low in [false, true] {
+            let levels = Levels::default();
+            let backend = Memory {
+                pins: LoopbackPins(levels.clone()),
+                active_low,
+            };
+            behaves_as_relay_outputs(&backend, &levels, active_low, true);
+        }
+    }
+
+    #[test]
+    fn character_device_backend() {
+        for active_low in [false, true] {
+            let levels = Levels::default();
+            let backend = Cdev {
+                chip: LoopbackChip(levels.clone()),
+                active_low,
+            };
+            behaves_as_relay_outputs(&backend, &levels, active_low, false);
+        }
+    }
+
+    #[test]
+    fn line_requests_match_the_kernel_abi() {
+        let request = KernelLineRequest::from(LineRequest {
+            offset: 17,
+            active_low: true,
+        });
+        assert_eq!(17, request.offsets[0]);
+        assert_eq!(1, request.num_lines);
+        assert_eq!(b"picool\0", &request.consumer[..7]);
+        assert_eq!(
+            GPIO_V2_LINE_FLAG_OUTPUT | GPIO_V2_LINE_FLAG_ACTIVE_LOW,
+            request.config.flags
+        );
+        assert_eq!(1, request.config.num_attrs);
+        assert_eq!(
+            LineConfigAttribute {
+                attr: LineAttribute {
+                    id: GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES,
+                    padding: 0,
+                    value: 0,
+                },
+                mask: 1,
+            },
+            request.config.attrs[0]
+        );
+        let high = KernelLineRequest::from(LineRequest {
+            offset: 4,
+            active_low: false,
+        });
+        assert_eq!(GPIO_V2_LINE_FLAG_OUTPUT, high.config.flags);
+        // The sizes encoded in the ioctl numbers.
+        assert_eq!(0x250, (GPIO_V2_GET_LINE_IOCTL >> 16) & 0x3fff);
+        assert_eq!(
+            std::mem::size_of::<LineValues>() as u64,
+            (GPIO_V2_LINE_SET_VALUES_IOCTL >> 16) & 0x3fff
+        );
+    }
+
+    #[test]
+    fn chips_by_name_or_path() {
+        let mut config = GpioConfig::default();
+        assert_eq!(PathBuf::from("/dev/gpiochip0"), config.chip);
+        config.set_chip("gpiochip4");
+        assert_eq!(PathBuf::from("/dev/gpiochip4"), config.chip);
+        config.set_chip("/dev/gpiochip1");
+        assert_eq!(PathBuf::from("/dev/gpiochip1"), config.chip);
+        assert!(matches!(
+            CharDevice::open(Path::new("/nonexistent/gpiochip9")),
+            Err(StartupError::GpioChipMissing(..))
+        ));
+    }
+}
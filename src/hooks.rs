@@ -0,0 +1,173 @@
+//! A user command run on every state change, e.g. to log to a home automation system or blink an LED.
+
+use crate::child_process;
+use log::*;
+use picool_core::controller::Transition;
+use std::{
+    path::PathBuf,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// A hook still running after this is killed, none should take anywhere near a poll interval.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the hook detached with `PICOOL_OLD_STATE`, `PICOOL_NEW_STATE`, `PICOOL_REASON` and `PICOOL_TEMPERATURE`
+/// (empty without a reading) in its environment. A transition while the previous invocation still runs is
+/// skipped rather than queued, the hook only ever lags the state and a stuck one doesn't pile up processes.
+pub struct TransitionHook {
+    command: PathBuf,
+    timeout: Duration,
+    running: Arc<AtomicBool>,
+}
+
+impl TransitionHook {
+    pub fn new(command: PathBuf) -> Self {
+        Self {
+            command,
+            timeout: HOOK_TIMEOUT,
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns immediately, failures are only logged and never reach the control loop.
+    pub fn run(&self, transition: &Transition) {
+        if self.running.swap(true, Ordering::AcqRel) {
+            warn!(
+                "Transition hook {} still running, skipped {} -> {}.",
+                self.command.display(),
+                transition.from,
+                transition.to
+            );
+            return;
+        }
+        let mut command = Command::new(&self.command);
+        command
+            .env("PICOOL_OLD_STATE", transition.from.to_string())
+            .env("PICOOL_NEW_STATE", transition.to.to_string())
+            .env("PICOOL_REASON", transition.reason.to_string())
+            .env(
+                "PICOOL_TEMPERATURE",
+                transition
+                    .temperature
+                    .map(|t| format!("{:.3}", t.0))
+                    .unwrap_or_default(),
+            );
+        let path = self.command.clone();
+        let timeout = self.timeout;
+        let running = self.running.clone();
+        let spawned = thread::Builder::new().name("transition-hook".into()).spawn(move || {
+            match child_process::run(command, timeout) {
+                Ok(finished) => {
+                    if let Some(failure) = finished.failure() {
+                        warn!("Transition hook {} {}.", path.display(), failure);
+                    }
+                }
+                Err(e) => warn!("Transition hook {} {}.", path.display(), e),
+            }
+            running.store(false, Ordering::Release);
+        });
+        if let Err(e) = spawned {
+            warn!("Failed to start transition hook {}. {}", self.command.display(), e);
+            self.running.store(false, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{script, TempDir};
+    use picool_core::{controller::TransitionReason, temperature::Celsius, State};
+    use std::{fs, path::Path, time::Instant};
+
+    fn transition(temperature: Option<Celsius>) -> Transition {
+        Transition {
+            from: State::Off,
+            to: State::MinimumIntervalOn(Instant::now()),
+            reason: TransitionReason::Threshold,
+            temperature,
+        }
+    }
+
+    /// What the hook wrote once it appears, retrying while another test thread's fork holds the script busy.
+    fn wait_for(hook: &TransitionHook, transition: &Transition, output: &Path) -> String {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            if let Ok(written) = fs::read_to_string(output) {
+                if written.ends_with('\n') {
+                    return written;
+                }
+            }
+            if !hook.running.load(Ordering::Acquire) {
+                hook.run(transition);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("The hook never wrote {}.", output.display());
+    }
+
+    #[test]
+    fn passes_the_transition_in_the_environment() {
+        let dir = TempDir::new();
+        let output = dir.path().join("env");
+        let body = format!(
+            "echo \"$PICOOL_OLD_STATE|$PICOOL_NEW_STATE|$PICOOL_REASON|$PICOOL_TEMPERATURE\" > {}",
+            output.display()
+        );
+        let hook = TransitionHook::new(script(&dir, "hook", &body));
+        let reading = transition(Some(Celsius(4.25)));
+        assert_eq!(
+            "Off|MinimumIntervalOn|threshold|4.250\n",
+            wait_for(&hook, &reading, &output)
+        );
+        fs::remove_file(&output).unwrap();
+        let mut failed = transition(None);
+        failed.reason = TransitionReason::SensorFailsafe;
+        while hook.running.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            "Off|MinimumIntervalOn|sensor-failsafe|\n",
+            wait_for(&hook, &failed, &output)
+        );
+    }
+
+    #[test]
+    fn does_not_block_and_skips_while_running() {
+        let dir = TempDir::new();
+        let output = dir.path().join("runs");
+        let body = format!("echo run >> {}; sleep 1", output.display());
+        let hook = TransitionHook::new(script(&dir, "slow", &body));
+        // Tried again when the script was busy and never ran.
+        while !output.exists() {
+            let started = Instant::now();
+            hook.run(&transition(None));
+            hook.run(&transition(None));
+            hook.run(&transition(None));
+            assert!(started.elapsed() < Duration::from_millis(500));
+            while hook.running.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        assert_eq!("run\n", fs::read_to_string(&output).unwrap());
+    }
+
+    #[test]
+    fn kills_a_hook_that_outlasts_its_timeout() {
+        let dir = TempDir::new();
+        let mut hook = TransitionHook::new(script(&dir, "stuck", "sleep 30"));
+        hook.timeout = Duration::from_millis(100);
+        let started = Instant::now();
+        hook.run(&transition(None));
+        while hook.running.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
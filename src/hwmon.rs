@@ -0,0 +1,318 @@
+//! A temperature from the kernel's hwmon framework, found by the chip's name rather than its `hwmonN` directory,
+//! which is numbered again when a driver is reloaded.
+
+use crate::w1::{read_w1, SensorFormat};
+use anyhow::{bail, Result};
+use log::*;
+use picool_core::{temperature::Celsius, world_error::SensorError};
+use std::{
+    cell::RefCell,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const HWMON_ROOT: &str = "/sys/class/hwmon";
+
+/// Which of a chip's temperatures, by its `tempM_label` or its `M`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Channel {
+    Label(String),
+    Index(u32),
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Label(label) => write!(f, "{}", label),
+            Channel::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+/// The chip and channel of a `--sensor hwmon:<chip>/<label or index>` spec.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HwmonConfig {
+    pub chip: String,
+    pub channel: Channel,
+    /// Where the `hwmonN` directories are listed.
+    pub root: PathBuf,
+}
+
+impl HwmonConfig {
+    /// The chip and channel, with anything but letters and digits replaced so a label makes a file name.
+    pub fn name(&self) -> String {
+        format!("{}_{}", self.chip, self.channel)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    }
+}
+
+impl fmt::Display for HwmonConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.chip, self.channel)
+    }
+}
+
+/// The part after `hwmon:`, e.g. `cpu_thermal/1` or `nct6775/SYSTIN`.
+impl FromStr for HwmonConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (chip, channel) = match s.split_once('/') {
+            Some((chip, channel)) if !chip.is_empty() && !channel.is_empty() => (chip, channel),
+            _ => bail!("Invalid hwmon sensor '{}', expected <chip>/<label or index>.", s),
+        };
+        let channel = match channel.parse() {
+            Ok(index) => Channel::Index(index),
+            Err(_) => Channel::Label(channel.to_string()),
+        };
+        Ok(HwmonConfig {
+            chip: chip.to_string(),
+            channel,
+            root: PathBuf::from(HWMON_ROOT),
+        })
+    }
+}
+
+/// A chip's directory and name, and its temperature channels with their labels.
+struct Chip {
+    dir: PathBuf,
+    name: String,
+    channels: Vec<(u32, Option<String>)>,
+}
+
+impl Chip {
+    fn read(dir: PathBuf) -> Option<Self> {
+        let name = fs::read_to_string(dir.join("name")).ok()?.trim().to_string();
+        let mut channels: Vec<(u32, Option<String>)> = fs::read_dir(&dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter_map(|file| file.strip_prefix("temp")?.strip_suffix("_input")?.parse().ok())
+            .map(|index| {
+                let label = fs::read_to_string(dir.join(format!("temp{}_label", index)));
+                (index, label.ok().map(|l| l.trim().to_string()))
+            })
+            .collect();
+        channels.sort();
+        Some(Chip { dir, name, channels })
+    }
+
+    fn input(&self, channel: &Channel) -> Option<PathBuf> {
+        let index = self.channels.iter().find_map(|(index, label)| match channel {
+            Channel::Index(wanted) => Some(*index).filter(|i| i == wanted),
+            Channel::Label(wanted) => Some(*index).filter(|_| label.as_ref() == Some(wanted)),
+        })?;
+        Some(self.dir.join(format!("temp{}_input", index)))
+    }
+
+    /// Each channel as it would be given, by its label when it has one.
+    fn specs(&self) -> impl Iterator<Item = String> + '_ {
+        self.channels.iter().map(move |(index, label)| match label {
+            Some(label) => format!("{}/{}", self.name, label),
+            None => format!("{}/{}", self.name, index),
+        })
+    }
+}
+
+fn list_chips(root: &Path) -> Vec<Chip> {
+    let mut dirs: Vec<PathBuf> = match fs::read_dir(root) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect(),
+        Err(e) => {
+            debug!("Listing hwmon chips in {} failed: {}", root.display(), e);
+            vec![]
+        }
+    };
+    dirs.sort();
+    dirs.into_iter().filter_map(Chip::read).collect()
+}
+
+/// The channel's input file, or why it can't be found along with every chip and channel that can.
+pub fn resolve(config: &HwmonConfig) -> Result<PathBuf, String> {
+    let chips = list_chips(&config.root);
+    let found = chips
+        .iter()
+        .filter(|chip| chip.name == config.chip)
+        .find_map(|chip| chip.input(&config.channel));
+    if let Some(input) = found {
+        return Ok(input);
+    }
+    let available: Vec<String> = chips.iter().flat_map(Chip::specs).collect();
+    let missing = match chips.iter().any(|chip| chip.name == config.chip) {
+        true => format!("chip {} has no temperature {}", config.chip, config.channel),
+        false => format!("no chip is named {}", config.chip),
+    };
+    match available.is_empty() {
+        true => Err(format!("{} and no hwmon temperatures are listed", missing)),
+        false => Err(format!("{}, available are {}", missing, available.join(", "))),
+    }
+}
+
+/// Reads the input file found for the chip and channel, found again when it goes missing.
+pub struct HwmonSensor {
+    config: HwmonConfig,
+    input: RefCell<PathBuf>,
+}
+
+impl HwmonSensor {
+    pub fn open(config: HwmonConfig) -> Result<Self, String> {
+        let input = resolve(&config)?;
+        info!("Reading hwmon {} from {}.", config, input.display());
+        Ok(Self {
+            config,
+            input: RefCell::new(input),
+        })
+    }
+
+    pub fn read_temperature(&self) -> Result<Celsius, SensorError> {
+        let reading = read_w1(&self.input.borrow(), SensorFormat::Millidegrees);
+        match reading {
+            Err(SensorError::Missing(_)) if self.resolve_again() => {
+                read_w1(&self.input.borrow(), SensorFormat::Millidegrees)
+            }
+            reading => reading,
+        }
+    }
+
+    /// Looks for the chip again, its driver may have been reloaded under another `hwmonN`.
+    pub fn reinitialize(&mut self) -> Result<(), SensorError> {
+        match self.resolve_again() {
+            true => Ok(()),
+            false => Err(SensorError::Missing(self.input.borrow().clone())),
+        }
+    }
+
+    /// Whether the input file was found, logged when it moved.
+    fn resolve_again(&self) -> bool {
+        match resolve(&self.config) {
+            Ok(input) => {
+                if input != *self.input.borrow() {
+                    info!("Hwmon {} moved to {}.", self.config, input.display());
+                    self.input.replace(input);
+                }
+                true
+            }
+            Err(e) => {
+                debug!("Hwmon {} still missing, {}.", self.config, e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// An `hwmonN` directory with the given name and temperatures, labelled when given one.
+    fn chip(root: &Path, dir: &str, name: &str, temperatures: &[(u32, Option<&str>, i32)]) {
+        let dir = root.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("name"), format!("{}\n", name)).unwrap();
+        for (index, label, millidegrees) in temperatures.iter().copied() {
+            fs::write(dir.join(format!("temp{}_input", index)), format!("{}\n", millidegrees)).unwrap();
+            if let Some(label) = label {
+                fs::write(dir.join(format!("temp{}_label", index)), format!("{}\n", label)).unwrap();
+            }
+        }
+    }
+
+    fn config(root: &Path, spec: &str) -> HwmonConfig {
+        HwmonConfig {
+            root: root.to_path_buf(),
+            ..spec.parse().unwrap()
+        }
+    }
+
+    #[test]
+    fn parses_sensor_specs() {
+        let config: HwmonConfig = "nct6775/SYSTIN".parse().unwrap();
+        assert_eq!("nct6775", config.chip);
+        assert_eq!(Channel::Label("SYSTIN".into()), config.channel);
+        assert_eq!(PathBuf::from(HWMON_ROOT), config.root);
+        assert_eq!(
+            Channel::Index(2),
+            "cpu_thermal/2".parse::<HwmonConfig>().unwrap().channel
+        );
+        assert_eq!(
+            "coretemp_Package_id_0",
+            "coretemp/Package id 0".parse::<HwmonConfig>().unwrap().name()
+        );
+        assert!("cpu_thermal".parse::<HwmonConfig>().is_err());
+        assert!("/1".parse::<HwmonConfig>().is_err());
+        assert!("cpu_thermal/".parse::<HwmonConfig>().is_err());
+    }
+
+    #[test]
+    fn resolves_by_name_label_and_index() {
+        let dir = TempDir::new();
+        let root = dir.path();
+        chip(root, "hwmon0", "cpu_thermal", &[(1, None, 48312)]);
+        chip(
+            root,
+            "hwmon1",
+            "nct6775",
+            &[(1, Some("SYSTIN"), 4500), (2, Some("CPUTIN"), 3100)],
+        );
+        assert_eq!(
+            root.join("hwmon0/temp1_input"),
+            resolve(&config(root, "cpu_thermal/1")).unwrap()
+        );
+        assert_eq!(
+            root.join("hwmon1/temp2_input"),
+            resolve(&config(root, "nct6775/CPUTIN")).unwrap()
+        );
+        assert_eq!(
+            root.join("hwmon1/temp1_input"),
+            resolve(&config(root, "nct6775/1")).unwrap()
+        );
+
+        let sensor = HwmonSensor::open(config(root, "nct6775/SYSTIN")).unwrap();
+        assert_eq!(Celsius(4.5), sensor.read_temperature().unwrap());
+    }
+
+    #[test]
+    fn lists_what_is_available_when_unresolved() {
+        let dir = TempDir::new();
+        let root = dir.path();
+        assert_eq!(
+            Err("no chip is named nct6775 and no hwmon temperatures are listed".to_string()),
+            resolve(&config(root, "nct6775/SYSTIN"))
+        );
+        chip(root, "hwmon0", "cpu_thermal", &[(1, None, 48312)]);
+        chip(root, "hwmon1", "nct6775", &[(1, Some("SYSTIN"), 4500), (2, None, 3100)]);
+        assert_eq!(
+            Err(
+                "chip nct6775 has no temperature AUXTIN, available are cpu_thermal/1, nct6775/SYSTIN, nct6775/2"
+                    .to_string()
+            ),
+            resolve(&config(root, "nct6775/AUXTIN"))
+        );
+        assert!(resolve(&config(root, "coretemp/1"))
+            .unwrap_err()
+            .starts_with("no chip is named coretemp, "));
+    }
+
+    #[test]
+    fn follows_the_chip_when_it_is_enumerated_again() {
+        let dir = TempDir::new();
+        let root = dir.path();
+        chip(root, "hwmon0", "cpu_thermal", &[(1, None, 48312)]);
+        chip(root, "hwmon1", "nct6775", &[(1, Some("SYSTIN"), 4500)]);
+        let mut sensor = HwmonSensor::open(config(root, "nct6775/SYSTIN")).unwrap();
+
+        // The driver is reloaded, the chip comes back as hwmon2.
+        fs::remove_dir_all(root.join("hwmon1")).unwrap();
+        assert!(matches!(sensor.reinitialize(), Err(SensorError::Missing(_))));
+        chip(root, "hwmon2", "nct6775", &[(1, Some("SYSTIN"), 3250)]);
+        assert_eq!(Celsius(3.25), sensor.read_temperature().unwrap());
+        assert_eq!(root.join("hwmon2/temp1_input"), *sensor.input.borrow());
+        sensor.reinitialize().unwrap();
+    }
+}
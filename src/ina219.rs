@@ -0,0 +1,155 @@
+//! The compressor's current from an INA219 on an I2C bus, measuring across a shunt in its low voltage control
+//! circuit.
+
+use crate::modbus::parse_integer;
+use anyhow::{bail, Context, Result};
+use log::*;
+use picool_core::world_error::SensorError;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// From linux/i2c-dev.h, addresses the slave for the reads and writes that follow.
+const I2C_SLAVE: libc::c_ulong = 0x0703;
+const SHUNT_VOLTAGE_REGISTER: u8 = 0x01;
+/// Volts per bit of the shunt voltage register, whatever the gain.
+const SHUNT_VOLTAGE_LSB: f32 = 10e-6;
+
+/// The bus and shunt of a `--current-sensor ina219:<device>?<parameters>` spec.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ina219Config {
+    pub device: PathBuf,
+    pub address: u8,
+    /// Ohms.
+    pub shunt: f32,
+}
+
+impl Ina219Config {
+    fn new(device: PathBuf) -> Self {
+        Self {
+            device,
+            address: 0x40,
+            shunt: 0.1,
+        }
+    }
+}
+
+/// The part after `ina219:`, e.g. `/dev/i2c-1?addr=0x41&shunt=0.05`.
+impl FromStr for Ina219Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (device, query) = match s.find('?') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        if device.is_empty() {
+            bail!("INA219 sensor '{}' names no I2C device.", s);
+        }
+        let mut config = Ina219Config::new(PathBuf::from(device));
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match parameter.find('=') {
+                Some(i) => (&parameter[..i], &parameter[i + 1..]),
+                None => bail!("INA219 parameter '{}' has no value.", parameter),
+            };
+            let invalid = || format!("Invalid INA219 {} '{}'.", key, value);
+            match key {
+                "addr" => config.address = parse_integer(value).with_context(invalid)?,
+                "shunt" => config.shunt = value.parse().with_context(invalid)?,
+                _ => bail!("Unknown INA219 parameter '{}'.", key),
+            }
+        }
+        if !(0x40..=0x4F).contains(&config.address) {
+            bail!("INA219 address 0x{:02x} is outside 0x40-0x4f.", config.address);
+        }
+        if !(config.shunt.is_finite() && config.shunt > 0.0) {
+            bail!("The INA219 shunt must be a positive resistance, got {}.", config.shunt);
+        }
+        Ok(config)
+    }
+}
+
+/// The current sensor spec, only the INA219 so far.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CurrentSpec {
+    Ina219(Ina219Config),
+}
+
+impl FromStr for CurrentSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("ina219:") {
+            Some(ina219) => Ok(CurrentSpec::Ina219(ina219.parse()?)),
+            None => bail!("Current sensor '{}' is not ina219:<device>.", s),
+        }
+    }
+}
+
+// Pure
+/// Amps through the shunt from the shunt voltage register, either way round.
+pub fn decode_current(register: [u8; 2], shunt: f32) -> f32 {
+    (i16::from_be_bytes(register) as f32 * SHUNT_VOLTAGE_LSB / shunt).abs()
+}
+
+/// The bus with the INA219 addressed, its power-on configuration measures the shunt continuously.
+pub struct Ina219 {
+    config: Ina219Config,
+    bus: File,
+}
+
+impl Ina219 {
+    pub fn open(config: Ina219Config) -> io::Result<Self> {
+        let bus = OpenOptions::new().read(true).write(true).open(&config.device)?;
+        // SAFETY: I2C_SLAVE takes the address by value on the bus's own descriptor.
+        if unsafe { libc::ioctl(bus.as_raw_fd(), I2C_SLAVE as _, config.address as libc::c_ulong) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        info!(
+            "Reading compressor current from the INA219 at 0x{:02x} on {}.",
+            config.address,
+            config.device.display()
+        );
+        Ok(Self { config, bus })
+    }
+
+    pub fn read_current(&self) -> Result<f32, SensorError> {
+        let mut register = [0; 2];
+        (&self.bus)
+            .write_all(&[SHUNT_VOLTAGE_REGISTER])
+            .and_then(|_| (&self.bus).read_exact(&mut register))
+            .map_err(|e| SensorError::from_io(self.config.device.clone(), e))?;
+        Ok(decode_current(register, self.config.shunt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sensor_specs() {
+        let CurrentSpec::Ina219(config) = "ina219:/dev/i2c-1".parse().unwrap();
+        assert_eq!(Ina219Config::new(PathBuf::from("/dev/i2c-1")), config);
+        let CurrentSpec::Ina219(config) = "ina219:/dev/i2c-0?addr=0x45&shunt=0.05".parse().unwrap();
+        assert_eq!((0x45, 0.05), (config.address, config.shunt));
+        assert!("adc:/dev/i2c-1".parse::<CurrentSpec>().is_err());
+        assert!("ina219:?addr=0x40".parse::<CurrentSpec>().is_err());
+        assert!("ina219:/dev/i2c-1?addr=0x20".parse::<CurrentSpec>().is_err());
+        assert!("ina219:/dev/i2c-1?shunt=0".parse::<CurrentSpec>().is_err());
+        assert!("ina219:/dev/i2c-1?gain=8".parse::<CurrentSpec>().is_err());
+    }
+
+    #[test]
+    fn decodes_the_shunt_voltage() {
+        // 25mV across 0.1 ohm.
+        assert!((decode_current([0x09, 0xC4], 0.1) - 0.25).abs() < 1e-6);
+        // Wired the other way round.
+        assert!((decode_current([0xF6, 0x3C], 0.1) - 0.25).abs() < 1e-6);
+        assert_eq!(0.0, decode_current([0, 0], 0.1));
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// An advisory flock(2) held for the life of the process. The kernel drops the lock when the
+/// descriptor is closed, so a crashed instance never leaves a stale lock behind.
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct LockHeld {
+    pub path: PathBuf,
+    pub pid: Option<u32>,
+}
+
+impl fmt::Display for LockHeld {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(
+                f,
+                "picool is already running (pid {}), holding {}.",
+                pid,
+                self.path.display()
+            ),
+            None => write!(f, "picool is already running, holding {}.", self.path.display()),
+        }
+    }
+}
+
+impl std::error::Error for LockHeld {}
+
+impl InstanceLock {
+    pub fn acquire(dir: &Path, name: &str) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed creating lock directory {}.", dir.display()))?;
+        let path = dir.join(format!("{}.lock", name));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed opening lock file {}.", path.display()))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let pid = fs::read_to_string(&path).ok().and_then(|s| s.trim().parse().ok());
+                return Err(LockHeld { path, pid }.into());
+            }
+            return Err(e).with_context(|| format!("Failed locking {}.", path.display()));
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .with_context(|| format!("Failed writing PID to {}.", path.display()))?;
+
+        Ok(Self { _file: file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn second_instance_fails_fast() {
+        let dir = TempDir::new();
+        let _first = InstanceLock::acquire(dir.path(), "pin_17").unwrap();
+        let second = InstanceLock::acquire(dir.path(), "pin_17")
+            .err()
+            .expect("second lock must fail");
+        let held = second.downcast_ref::<LockHeld>().expect("error must be LockHeld");
+        assert_eq!(Some(std::process::id()), held.pid);
+    }
+
+    #[test]
+    fn independent_keys_do_not_conflict() {
+        let dir = TempDir::new();
+        let _pin = InstanceLock::acquire(dir.path(), "pin_17").unwrap();
+        assert!(InstanceLock::acquire(dir.path(), "pin_18").is_ok());
+    }
+
+    #[test]
+    fn released_on_drop() {
+        let dir = TempDir::new();
+        drop(InstanceLock::acquire(dir.path(), "pin_17").unwrap());
+        assert!(InstanceLock::acquire(dir.path(), "pin_17").is_ok());
+    }
+
+    #[test]
+    fn stale_file_is_not_a_lock() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("pin_17.lock"), "999999").unwrap();
+        assert!(InstanceLock::acquire(dir.path(), "pin_17").is_ok());
+    }
+}
@@ -0,0 +1,450 @@
+//! `--log-file`, for systems without journald. The file is rotated by size, and reopened on SIGHUP so an external
+//! logrotate can move it away instead. `--log-format json` writes a JSON object per line for a log shipper.
+
+use crate::{cycle_history::quote, reload};
+use anyhow::{bail, Result};
+use log::*;
+use std::{
+    cell::Cell,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static LABEL: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// When the log file is rotated and how many rotated files are kept, as `<path>.1` (newest) to `<path>.<keep>`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub keep: u32,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+/// How each line is written, `--log-format`.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum LogFormat {
+    /// `[<time> <level> <module>] <message>`, env_logger's own format on stderr.
+    #[default]
+    Text,
+    /// `{"time":...,"level":...,"module":...,"message":...}`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("Unknown log format '{}', expected text or json.", s),
+        }
+    }
+}
+
+impl LogFormat {
+    /// The record logged at `at` as one line, newline included.
+    fn line(self, at: SystemTime, record: &Record) -> String {
+        let time = humantime::format_rfc3339_seconds(at);
+        let module = record.module_path().unwrap_or_default();
+        match self {
+            LogFormat::Text => format!("[{} {:<5} {}] {}\n", time, record.level(), module, record.args()),
+            LogFormat::Json => format!(
+                "{{\"time\":\"{}\",\"level\":\"{}\",\"module\":{},\"message\":{}}}\n",
+                time,
+                record.level(),
+                quote(module),
+                quote(&record.args().to_string())
+            ),
+        }
+    }
+}
+
+/// Logs to `path` when given, otherwise to stderr as before. A file that can't be opened falls back to stderr
+/// with a warning.
+pub fn init(path: Option<&Path>, rotation: Rotation, format: LogFormat) {
+    let env = env_logger::Env::new().default_filter_or("info");
+    let stderr = |env| {
+        let mut builder = env_logger::Builder::from_env(env);
+        if format == LogFormat::Json {
+            builder.format(|out, record| out.write_all(LogFormat::Json.line(SystemTime::now(), record).as_bytes()));
+        }
+        let logger = builder.build();
+        log::set_max_level(logger.filter());
+        log::set_boxed_logger(Box::new(Labelled(logger))).expect("Logging is initialized once.");
+    };
+    let path = match path {
+        Some(path) => path,
+        None => return stderr(env),
+    };
+    let file = match RotatingFile::open(path, rotation) {
+        Ok(file) => file,
+        Err(e) => {
+            stderr(env);
+            warn!("Logging to stderr, {} can't be opened. {}", path.display(), e);
+            return;
+        }
+    };
+    let filter = env_logger::Builder::from_env(env).build();
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(Labelled(FileLogger::new(filter, file, format))))
+        .expect("Logging is initialized once.");
+    if let Err(e) = reload::handle_sighup() {
+        warn!("The log file won't be reopened for logrotate. {}", e);
+    }
+}
+
+/// Runs `f` with every line it logs on this thread prefixed with `[label]`, to tell apart what it logs from the same
+/// code running for something else.
+pub fn labelled<T>(label: &'static str, f: impl FnOnce() -> T) -> T {
+    let outer = LABEL.with(|current| current.replace(Some(label)));
+    let result = f();
+    LABEL.with(|current| current.set(outer));
+    result
+}
+
+/// Prefixes the lines logged inside `labelled` before they reach the logger.
+struct Labelled<L>(L);
+
+impl<L: Log> Log for Labelled<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        match LABEL.with(Cell::get) {
+            Some(label) => self.0.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", label, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.0.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// The next line is written to a freshly opened file, the current one may have been moved. Only an atomic store, the
+/// SIGHUP handler calls it.
+pub fn request_reopen() {
+    REOPEN.store(true, Ordering::Relaxed);
+}
+
+/// A log file that moves itself aside once writing a line would take it past the maximum size.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = append(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            size: file.metadata()?.len(),
+            file,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.rotation.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Renames shift each file one number up, the oldest is replaced. Without any to keep the file starts over.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.rotation.keep == 0 {
+            self.file = File::create(&self.path)?;
+            self.size = 0;
+            return Ok(());
+        }
+        for n in (1..self.rotation.keep).rev() {
+            match fs::rename(numbered(&self.path, n), numbered(&self.path, n + 1)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, numbered(&self.path, 1))?;
+        self.reopen()
+    }
+
+    /// Picks up the file at the path again, creating it if it was moved away.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.file = append(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `picool.log.2` for `picool.log`.
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// env_logger's filtering, written to a RotatingFile in the chosen format.
+struct FileLogger {
+    filter: env_logger::Logger,
+    file: Mutex<RotatingFile>,
+    format: LogFormat,
+}
+
+impl FileLogger {
+    fn new(filter: env_logger::Logger, file: RotatingFile, format: LogFormat) -> Self {
+        Self {
+            filter,
+            file: Mutex::new(file),
+            format,
+        }
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let line = self.format.line(SystemTime::now(), record);
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if REOPEN.swap(false, Ordering::Relaxed) {
+            if let Err(e) = file.reopen() {
+                eprintln!("Reopening log file {} failed. {}", file.path.display(), e);
+            }
+        }
+        if let Err(e) = file.write_line(&line) {
+            eprintln!(
+                "Writing log file {} failed. {}\n{}",
+                file.path.display(),
+                e,
+                line.trim_end()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _ = file.file.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, SIGNALS};
+
+    const LINE: &str = "0123456789\n";
+
+    fn rotation(keep: u32) -> Rotation {
+        Rotation {
+            max_size: LINE.len() as u64 * 3,
+            keep,
+        }
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read_to_string(path).map_or(0, |data| data.lines().count())
+    }
+
+    #[test]
+    fn rotates_by_size_keeping_the_newest() {
+        let dir = TempDir::new();
+        let path = dir.path().join("picool.log");
+        let mut file = RotatingFile::open(&path, rotation(2)).unwrap();
+        for _ in 0..3 {
+            file.write_line(LINE).unwrap();
+        }
+        assert_eq!((3, false), (lines(&path), numbered(&path, 1).exists()));
+        file.write_line("first of the second file\n").unwrap();
+        assert_eq!((1, 3), (lines(&path), lines(&numbered(&path, 1))));
+        // Two more rotations, the oldest file is replaced.
+        for _ in 0..5 {
+            file.write_line(LINE).unwrap();
+        }
+        assert_eq!(
+            vec![2, 3, 1],
+            vec![lines(&path), lines(&numbered(&path, 1)), lines(&numbered(&path, 2))]
+        );
+        assert!(fs::read_to_string(numbered(&path, 2))
+            .unwrap()
+            .starts_with("first of the second file"));
+        assert!(!numbered(&path, 3).exists());
+
+        // Without any to keep, the file starts over in place.
+        let path = dir.path().join("unkept.log");
+        let mut file = RotatingFile::open(&path, rotation(0)).unwrap();
+        for _ in 0..4 {
+            file.write_line(LINE).unwrap();
+        }
+        assert_eq!((1, false), (lines(&path), numbered(&path, 1).exists()));
+    }
+
+    #[test]
+    fn continues_an_existing_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("picool.log");
+        fs::write(&path, LINE.repeat(2)).unwrap();
+        let mut file = RotatingFile::open(&path, rotation(1)).unwrap();
+        file.write_line(LINE).unwrap();
+        assert_eq!(3, lines(&path));
+        file.write_line(LINE).unwrap();
+        assert_eq!((1, 3), (lines(&path), lines(&numbered(&path, 1))));
+        assert!(RotatingFile::open(dir.path(), rotation(1)).is_err());
+    }
+
+    #[test]
+    fn logger_rotates_mid_run_and_reopens_on_sighup() {
+        let dir = TempDir::new();
+        let path = dir.path().join("picool.log");
+        let filter = env_logger::Builder::new().filter_level(LevelFilter::Info).build();
+        let rotation = Rotation { max_size: 150, keep: 3 };
+        let logger = FileLogger::new(filter, RotatingFile::open(&path, rotation).unwrap(), LogFormat::Text);
+        let log = |level: Level, message: &str| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .module_path(Some("picool::log_file"))
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+        log(Level::Info, "Starting picool control.");
+        log(Level::Debug, "Filtered out.");
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(
+            written.ends_with(" INFO  picool::log_file] Starting picool control.\n"),
+            "{}",
+            written
+        );
+        assert_eq!(1, lines(&path));
+        // Two of these lines fit under the maximum.
+        for i in 0..4 {
+            log(Level::Warn, &format!("Reading {}", i));
+        }
+        assert_eq!(
+            vec![1, 2, 2],
+            vec![lines(&path), lines(&numbered(&path, 1)), lines(&numbered(&path, 2))]
+        );
+
+        // logrotate moves the file away and sends SIGHUP.
+        let _signals = SIGNALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        reload::handle_sighup().unwrap();
+        fs::rename(&path, dir.path().join("moved.log")).unwrap();
+        unsafe { libc::raise(libc::SIGHUP) };
+        log(Level::Info, "After logrotate.");
+        assert_eq!(1, lines(&path));
+        assert_eq!(1, lines(&dir.path().join("moved.log")));
+    }
+
+    /// The string values of a flat JSON object, in order.
+    fn fields(line: &str) -> Vec<(String, String)> {
+        let mut chars = line
+            .strip_prefix('{')
+            .and_then(|l| l.strip_suffix('}'))
+            .unwrap()
+            .chars();
+        let mut string = || {
+            assert_eq!(Some('"'), chars.next());
+            let mut s = String::new();
+            loop {
+                match chars.next().unwrap() {
+                    '"' => break,
+                    '\\' => match chars.next().unwrap() {
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            s.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                        }
+                        c => s.push(c),
+                    },
+                    c => s.push(c),
+                }
+            }
+            let separator = chars.next();
+            (s, separator)
+        };
+        let mut fields = Vec::new();
+        loop {
+            let (key, colon) = string();
+            assert_eq!(Some(':'), colon);
+            let (value, comma) = string();
+            fields.push((key, value));
+            if comma.is_none() {
+                return fields;
+            }
+            assert_eq!(Some(','), comma);
+        }
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        assert_eq!(LogFormat::Text, "text".parse().unwrap());
+        assert_eq!(LogFormat::Json, "json".parse().unwrap());
+        assert!("yaml".parse::<LogFormat>().is_err());
+
+        let dir = TempDir::new();
+        let path = dir.path().join("picool.log");
+        let filter = env_logger::Builder::new().filter_level(LevelFilter::Info).build();
+        let file = RotatingFile::open(&path, Rotation::default()).unwrap();
+        let logger = Labelled(FileLogger::new(filter, file, LogFormat::Json));
+        labelled("b", || {
+            logger.log(
+                &Record::builder()
+                    .level(Level::Warn)
+                    .module_path(Some("picool::sensor"))
+                    .args(format_args!("Reading \"{}\" failed.\n\tC:\\w1", "28-0416"))
+                    .build(),
+            )
+        });
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(1, written.lines().count(), "{}", written);
+        let fields = fields(written.trim_end());
+        let keys: Vec<_> = fields.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(vec!["time", "level", "module", "message"], keys);
+        assert!(humantime::parse_rfc3339(&fields[0].1).is_ok(), "{}", fields[0].1);
+        assert_eq!("WARN", fields[1].1);
+        assert_eq!("picool::sensor", fields[2].1);
+        assert_eq!("[b] Reading \"28-0416\" failed.\n\tC:\\w1", fields[3].1);
+    }
+}
@@ -0,0 +1,255 @@
+//! Where the time of each control loop iteration goes, to tell a stalling SD card from a slow sensor.
+
+use log::*;
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// An hour of iterations at the poll interval.
+const WINDOW: usize = 360;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Phase {
+    /// Reading the chamber and ambient sensors.
+    Sensor,
+    /// Writing state files and the heartbeat.
+    Persist,
+    /// Notifications, the status, the hook, StatsD and the timeline.
+    Report,
+}
+
+pub const PHASES: [Phase; 3] = [Phase::Sensor, Phase::Persist, Phase::Report];
+
+impl Phase {
+    fn index(self) -> usize {
+        match self {
+            Phase::Sensor => 0,
+            Phase::Persist => 1,
+            Phase::Report => 2,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Sensor => "sensor",
+            Phase::Persist => "persist",
+            Phase::Report => "report",
+        }
+    }
+}
+
+/// One iteration being timed, what is left of it once the phases are taken out went to the controller, the relay
+/// and the commands.
+pub struct Iteration {
+    started: Instant,
+    phases: [Duration; 3],
+}
+
+impl Iteration {
+    pub fn start(now: Instant) -> Self {
+        Self {
+            started: now,
+            phases: [Duration::from_secs(0); 3],
+        }
+    }
+
+    /// Charges the time from `since` to `now` to `phase`, a phase may be entered more than once.
+    pub fn add(&mut self, phase: Phase, since: Instant, now: Instant) {
+        self.phases[phase.index()] += now.saturating_duration_since(since);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Latency {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+/// `12 40 95`, the percentiles and the maximum in milliseconds.
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.p50.as_millis(),
+            self.p95.as_millis(),
+            self.max.as_millis()
+        )
+    }
+}
+
+/// The recent iterations, each phase and the whole, as published on the status board.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct LoopLatency {
+    pub sensor: Latency,
+    pub persist: Latency,
+    pub report: Latency,
+    pub iteration: Latency,
+    /// Iterations that took longer than the poll interval since the daemon started.
+    pub overruns: u64,
+}
+
+impl LoopLatency {
+    pub fn phase(&self, phase: Phase) -> Latency {
+        match phase {
+            Phase::Sensor => self.sensor,
+            Phase::Persist => self.persist,
+            Phase::Report => self.report,
+        }
+    }
+
+    pub fn phase_mut(&mut self, phase: Phase) -> &mut Latency {
+        match phase {
+            Phase::Sensor => &mut self.sensor,
+            Phase::Persist => &mut self.persist,
+            Phase::Report => &mut self.report,
+        }
+    }
+}
+
+/// The last `WINDOW` iterations. Recording one is a push, the percentiles are only worked out when someone asks.
+pub struct LoopTimings {
+    interval: Duration,
+    phases: [VecDeque<Duration>; 3],
+    iterations: VecDeque<Duration>,
+    overruns: u64,
+}
+
+impl LoopTimings {
+    /// Iterations longer than `interval` are overruns.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            phases: Default::default(),
+            iterations: VecDeque::new(),
+            overruns: 0,
+        }
+    }
+
+    /// A reloaded poll interval, the iterations so far stay counted against the old one.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Ends `iteration` at `now`, true when it overran.
+    pub fn finish(&mut self, iteration: Iteration, now: Instant) -> bool {
+        let total = now.saturating_duration_since(iteration.started);
+        for (window, spent) in self.phases.iter_mut().zip(iteration.phases) {
+            record(window, spent);
+        }
+        record(&mut self.iterations, total);
+        if total <= self.interval {
+            return false;
+        }
+        self.overruns += 1;
+        let breakdown: Vec<String> = PHASES
+            .iter()
+            .map(|phase| format!("{} {}ms", phase.name(), iteration.phases[phase.index()].as_millis()))
+            .collect();
+        warn!(
+            "Control loop iteration took {}ms, longer than the {}s poll interval ({}), {} overruns so far.",
+            total.as_millis(),
+            self.interval.as_secs(),
+            breakdown.join(", "),
+            self.overruns
+        );
+        true
+    }
+
+    pub fn latency(&self) -> LoopLatency {
+        let mut latency = LoopLatency {
+            iteration: latency(&self.iterations),
+            overruns: self.overruns,
+            ..LoopLatency::default()
+        };
+        for phase in PHASES {
+            *latency.phase_mut(phase) = self::latency(&self.phases[phase.index()]);
+        }
+        latency
+    }
+}
+
+fn record(window: &mut VecDeque<Duration>, spent: Duration) {
+    if window.len() == WINDOW {
+        window.pop_front();
+    }
+    window.push_back(spent);
+}
+
+fn latency(window: &VecDeque<Duration>) -> Latency {
+    let mut sorted: Vec<Duration> = window.iter().copied().collect();
+    sorted.sort();
+    Latency {
+        p50: percentile(&sorted, 50),
+        p95: percentile(&sorted, 95),
+        max: sorted.last().copied().unwrap_or_default(),
+    }
+}
+
+// Pure
+/// The nearest rank `percent` percentile of `sorted`, zero when empty.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    match sorted.len() {
+        0 => Duration::from_secs(0),
+        n => sorted[(n * percent).div_ceil(100).max(1) - 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted: Vec<Duration> = (1..=100).map(ms).collect();
+        assert_eq!((ms(50), ms(95)), (percentile(&sorted, 50), percentile(&sorted, 95)));
+        assert_eq!(ms(7), percentile(&[ms(7)], 95));
+        assert_eq!(
+            (ms(2), ms(3)),
+            (
+                percentile(&[ms(1), ms(2), ms(3)], 50),
+                percentile(&[ms(1), ms(2), ms(3)], 95)
+            )
+        );
+        assert_eq!(Duration::from_secs(0), percentile(&[], 50));
+    }
+
+    #[test]
+    fn keeps_the_last_window_and_counts_overruns() {
+        let start = Instant::now();
+        let mut timings = LoopTimings::new(Duration::from_secs(10));
+        for i in 0..WINDOW as u64 + 100 {
+            let mut iteration = Iteration::start(start);
+            // Two sensor reads of an iteration add up, the oldest hundred iterations were slow.
+            iteration.add(Phase::Sensor, start, start + ms(400));
+            iteration.add(Phase::Sensor, start, start + ms(350));
+            let persisted = start + ms(750) + if i < 100 { ms(20_000) } else { ms(i) };
+            iteration.add(Phase::Persist, start + ms(750), persisted);
+            assert_eq!(i < 100, timings.finish(iteration, persisted));
+        }
+        let latency = timings.latency();
+        assert_eq!(100, latency.overruns);
+        assert_eq!(
+            Latency {
+                p50: ms(750),
+                p95: ms(750),
+                max: ms(750)
+            },
+            latency.sensor
+        );
+        assert_eq!(
+            (ms(279), ms(441), ms(459)),
+            (latency.persist.p50, latency.persist.p95, latency.persist.max)
+        );
+        assert_eq!(ms(459 + 750), latency.iteration.max);
+        assert_eq!(Latency::default(), latency.report);
+        assert_eq!("279 441 459", latency.phase(Phase::Persist).to_string());
+    }
+}
@@ -1,572 +1,3368 @@
 use anyhow::Result;
 use log::*;
 use std::{
-    collections::VecDeque, env, mem::replace, num::FpCategory, ops::Range, path::PathBuf, time::Duration, time::Instant,
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    process::exit,
+    sync::mpsc::{channel, Receiver},
+    time::Duration,
+    time::Instant,
+    time::SystemTime,
 };
-use strum_macros::Display;
 
-cfg_if::cfg_if! {
-    if #[cfg(feature = "demo-mode")] {
-        mod demo_world;
-        use demo_world::DemoWorld;
-    } else {
-        mod real_world;
-        use real_world::RealWorld;
-    }
-}
+mod actuation;
+mod candidate;
+mod characterize;
+mod child_process;
+mod cli;
+mod compare;
+mod config_file;
+mod control;
+mod cycle_history;
+mod demo_world;
+mod diagnostics;
+mod exec_sensor;
+mod exec_switch;
+mod fault_injection;
+mod gpio;
+mod hooks;
+mod hwmon;
+mod ina219;
+mod instance_lock;
+mod log_file;
+mod loop_timing;
+mod modbus;
+mod net_runtime;
+mod privileges;
+mod pushgateway;
+mod real_world;
+mod reload;
+mod replay_world;
+mod scenario;
+mod sd_notify;
+mod self_test;
+mod shadow;
+mod shed_signal;
+mod shutdown;
+mod sim_controls;
+mod stall_monitor;
+mod startup_error;
+mod state_report;
+mod statsd;
+mod status_board;
+mod store_faults;
+mod store_verify;
+mod supervisor;
+mod thermal_model;
+mod timeline;
+mod w1;
+mod watch;
+mod websocket;
+mod world;
+
+use candidate::Candidate;
+use cli::WorldKind;
+use control::Command;
+use cycle_history::{CycleFilter, CycleFormat, HistoryEntry};
+use demo_world::{DemoParameters, DemoWorld};
+use diagnostics::DumpRequest;
+use gpio::{BackendKind, GpioConfig};
+use hooks::TransitionHook;
+use instance_lock::LockHeld;
+use loop_timing::{Iteration, LoopTimings, Phase};
+use net_runtime::Runtime;
+use picool_core::{
+    boost::{Boost, BoostRequest},
+    config::{Config, Preset},
+    controller::{step, ControllerState, CycleRecord, Effect, Input},
+    energy::{EnergyTotals, LocalTime},
+    failover::Output,
+    heartbeat::{HeartbeatStatus, WarningLimiter},
+    initial_state,
+    notify::{LogNotifier, Notifier},
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{self, Celsius, DegreesDelta},
+    termination::{PersistenceHealth, RunOutcome, Termination},
+    validate_temperature,
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals, RestoredPowerState, Seed, StartMode, State,
+};
+use pushgateway::{Pusher, Pushgateway};
+use real_world::{RealWorld, SensorSpec, StoredCompensation};
+use reload::Reloader;
+use replay_world::{ReplayParameters, ReplayWorld, Trace};
+use scenario::Scenario;
+use sd_notify::SdNotify;
+use shadow::Shadow;
+use shed_signal::ShedSignal;
+use shutdown::Shutdown;
+use sim_controls::SimControls;
+use stall_monitor::{LoopProgress, StallMonitor};
+use startup_error::StartupError;
+use statsd::StatsdEmitter;
+use status_board::StatusBoard;
+use supervisor::Supervisor;
+use timeline::Timeline;
+use websocket::WsServer;
+
+#[cfg(test)]
+mod golden;
+#[cfg(test)]
+mod test_util;
+#[cfg(test)]
+mod test_world;
 
-const TARGET_RANGE: Range<f32> = 0.555556..4.333333; // 33.0 to 39.8F
-const LOW_COMPENSATION_RESET: f32 = 4.444444; // 40.0F
-const MAX_COMPENSATION: f32 = 1.888888;
-const MINIMUM_ON_DURATION: Duration = Duration::from_secs(60 * 2);
-const MINIMUM_OFF_DURATION: Duration = Duration::from_secs(60 * 8);
 const POLL_DURATION: Duration = Duration::from_secs(10);
+const SENSOR_RETRY_DURATION: Duration = Duration::from_secs(10);
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_CONFIG: i32 = 2;
+const EXIT_HARDWARE: i32 = 3;
+const EXIT_LOCK_HELD: i32 = 6;
+const EXIT_PERMISSION: i32 = 7;
+const EXIT_SELF_TEST: i32 = 8;
 
 trait World {
-    fn get_temperature(&self) -> Result<f32>;
+    fn get_temperature(&self) -> Result<Celsius, SensorError>;
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>>;
+    fn get_current(&self) -> Option<Result<f32, SensorError>>;
     fn set_power_state(&mut self, state: bool);
+    fn set_fan_state(&mut self, state: bool);
+    fn set_stage2_state(&mut self, state: bool);
+    fn set_pump_state(&mut self, state: bool);
+    fn pump_state(&self) -> Option<bool>;
+    fn select_output(&mut self, output: Output);
     fn sleep(&self, duration: Duration);
     fn now(&self) -> Instant;
+    fn local_time(&self) -> LocalTime;
+    fn reinitialize(&mut self) -> Result<(), SensorError>;
+    /// True once a different sensor took the place of a missing one, its state is then kept apart.
+    fn reacquire_sensor(&mut self) -> bool;
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError>;
 
     fn restore_state(&self) -> Result<WorldState>;
-    fn persist_last_off_transition(&mut self) -> Result<()>;
-    fn persist_compensation(&mut self, cooling: f32, heating: f32) -> Result<()>;
-}
-
-#[derive(Eq, PartialEq, Copy, Clone, Display)]
-enum State {
-    InitiallyOff,
-    MinimumIntervalOn(Instant),
-    MinimumIntervalOff(Instant),
-    On,
-    Off,
-}
-
-#[derive(Eq, PartialEq, Copy, Clone, Display)]
-enum RestoredPowerState {
-    CurrentlyOn,
-    OffFor(Duration),
-    OffForUnknownDuration,
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError>;
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError>;
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError>;
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError>;
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError>;
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError>;
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError>;
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError>;
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError>;
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError>;
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError>;
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError>;
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError>;
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError>;
 }
 
 struct WorldState {
     power_state: RestoredPowerState,
-    heating_compensation: f32,
-    cooling_compensation: f32,
+    heating_compensation: DegreesDelta,
+    cooling_compensation: DegreesDelta,
+    cooling_rates: Vec<f32>,
+    energy: EnergyTotals,
+    runtime: Duration,
+    starts: StartCounts,
+    boost: Option<Boost>,
+    intervals: Option<MinimumIntervals>,
+    overshoots: Overshoots,
+    rest: Option<RestPeriod>,
+    shed: Option<ShedPeriod>,
+    extremes: Option<CycleExtremes>,
+    /// The primary output is faulted, starting on the backup.
+    failover: bool,
 }
 
 fn main() {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let args = cli::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        log_file::init(None, Default::default(), Default::default());
+        error!("{:#}", e);
+        eprint!("\n{}", cli::USAGE);
+        exit(EXIT_CONFIG);
+    });
+    if args.help {
+        print!("{}", cli::USAGE);
+        exit(0);
+    }
+    log_file::init(args.log_file.as_deref(), args.log_rotation, args.log_format);
     info!("Starting picool control.");
-    let args: Vec<String> = env::args().collect();
-
-    cfg_if::cfg_if! {
-        if #[cfg(feature = "demo-mode")] {
-            let world = DemoWorld::new();
-        } else {
-            let world = RealWorld::new(
-                PathBuf::from(&args[1]),
-                args[2].parse().expect("NEED VALIDATION"),
-            )
-            .expect("NEED VALIDATION");
+    temperature::set_display_units(args.units);
+    if let Some(path) = &args.config_file {
+        log_config(path, &args);
+    }
+    if let Some((a, b)) = &args.compare {
+        exit(compare_configs(&args, a, b));
+    }
+    if args.watch {
+        exit(watch_daemon(&args));
+    }
+    if let Some(filter) = &args.cycles {
+        exit(print_cycles(&args, filter));
+    }
+    if args.status {
+        exit(print_state(&args));
+    }
+    if args.reset_compensation {
+        exit(reset_compensation(&args));
+    }
+    if let Some(request) = &args.boost {
+        exit(boost_daemon(&args, request));
+    }
+    if args.list_presets {
+        list_presets();
+        exit(0);
+    }
+    let cycle_limit = match args.world {
+        WorldKind::Real | WorldKind::Replay => args.max_cycles,
+        WorldKind::Demo => args.max_cycles.or(args.demo.cycle_limit),
+    };
+    let shutdown = Shutdown::default().with_cycle_limit(cycle_limit);
+    if let Err(e) = shutdown.on_signals() {
+        warn!("Graceful shutdown unavailable. {:?}", e);
+    }
+    let mut runtime = Runtime::default();
+    if args.characterize {
+        exit(characterize_chamber(&args, &shutdown, &mut runtime));
+    }
+
+    let outcome = match args.world {
+        WorldKind::Real => {
+            let board = StatusBoard::default();
+            let (world, commands) = start_real_world(&args, &shutdown, board.clone(), &mut runtime);
+            control(&args, world, commands, Some(board), &shutdown, &mut runtime)
+        }
+        WorldKind::Demo => {
+            if args.self_test || args.check_config {
+                error!("The self-test needs the real world.");
+                exit(EXIT_FAILURE);
+            }
+            let parameters = DemoParameters {
+                cycle_limit,
+                scenario: load_scenario(&args),
+                ..args.demo.clone()
+            };
+            info!("Simulating {}.", parameters);
+            let warp = parameters.time_warp;
+            let world = DemoWorld::new(parameters).unwrap_or_else(|e| {
+                error!("Invalid simulation parameters. {}", e);
+                exit(EXIT_CONFIG);
+            });
+            let world = match SimControls::start(warp, &shutdown) {
+                Some(controls) => world.with_controls(controls),
+                None => world,
+            };
+            control(&args, world, channel().1, None, &shutdown, &mut runtime)
         }
+        WorldKind::Replay => {
+            let world = start_replay_world(&args, &shutdown);
+            control(&args, world, channel().1, None, &shutdown, &mut runtime)
+        }
+    };
+    match outcome {
+        RunOutcome::Fatal(_) => error!("{}", outcome),
+        _ => info!("{}", outcome),
     }
+    exit(outcome.exit_code());
+}
 
-    let restored_world_state = world.restore_state();
-    let seed_compensation = restored_world_state
-        .as_ref()
-        .map(|s| (s.cooling_compensation, s.heating_compensation))
-        .unwrap_or_default();
-    let initial_state = determine_initial_state(restored_world_state.map(|s| s.power_state), world.now());
-    run(initial_state, seed_compensation, world);
+/// Runs the experiment on the chamber and prints what it says about it, the exit code.
+fn characterize_chamber(args: &cli::Args, shutdown: &Shutdown, runtime: &mut Runtime) -> i32 {
+    let band = &args.config.band.target;
+    let interval = args.config.sampling.interval;
+    let curves = match args.world {
+        WorldKind::Real => {
+            let (mut world, _) = start_real_world(args, shutdown, StatusBoard::default(), runtime);
+            characterize::run(&mut world, &args.experiment, band, interval, shutdown)
+        }
+        WorldKind::Demo => {
+            info!("Characterizing a simulation, {}.", args.demo);
+            let mut world = DemoWorld::new(args.demo.clone()).unwrap_or_else(|e| {
+                error!("Invalid simulation parameters. {}", e);
+                exit(EXIT_CONFIG);
+            });
+            characterize::run(&mut world, &args.experiment, band, interval, shutdown)
+        }
+        WorldKind::Replay => {
+            error!("A replay can't switch the relay, characterize the real chamber or a simulation.");
+            return EXIT_CONFIG;
+        }
+    };
+    let found = match curves.and_then(|curves| characterize::fit(&curves)) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("Characterization failed. {:#}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    println!("{}", found);
+    println!("Suggested: {}", found.suggest(band, args.config.intervals));
+    println!("Simulate it with: picool simulate {}", found.demo_options());
+    0
 }
 
-// Pure w.r.t. World
-fn run(initial_state: State, initial_compensation: (f32, f32), mut world: impl World) {
-    info!(
-        "Initial state: {} Cooling Comp: {}C Heating Comp: {}C",
-        initial_state, initial_compensation.0, initial_compensation.1
-    );
-    let mut state = initial_state;
+/// The --scenario file if given, exiting with EXIT_CONFIG if it doesn't parse.
+fn load_scenario(args: &cli::Args) -> Scenario {
+    match &args.scenario {
+        Some(path) => Scenario::load(path).unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(EXIT_CONFIG);
+        }),
+        None => Scenario::default(),
+    }
+}
 
-    let (seed_low_compensation, seed_high_compensation) = initial_compensation;
-    let mut low_compensator = Compensator::new(TARGET_RANGE.start, seed_low_compensation, MAX_COMPENSATION);
-    let mut high_compensator = Compensator::new(TARGET_RANGE.end, seed_high_compensation, -1.0 * MAX_COMPENSATION);
+/// The trace named by the first positional argument, exiting with EXIT_CONFIG if it can't be loaded.
+fn load_trace(args: &cli::Args) -> Trace {
+    let path = args.positional.first().unwrap_or_else(|| {
+        error!("Replay needs a trace file.");
+        exit(EXIT_CONFIG);
+    });
+    let trace = Trace::load(Path::new(path)).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(EXIT_CONFIG);
+    });
+    if trace.out_of_order > 0 {
+        warn!("Dropped {} readings that went back in time.", trace.out_of_order);
+    }
+    trace
+}
 
-    let mut low_threshold = low_compensator.get_threshold();
-    let mut high_threshold = high_compensator.get_threshold();
+/// Replays the trace under both configurations and prints the comparison, the exit code.
+fn compare_configs(args: &cli::Args, a: &Config, b: &Config) -> i32 {
+    let trace = load_trace(args);
+    info!(
+        "Comparing configurations over {} readings spanning {}h.",
+        trace.samples.len(),
+        trace.duration().as_secs() / 3600
+    );
+    match compare::compare(&trace, &args.replay, a, b) {
+        Ok(comparison) if args.json => println!("{}", comparison.json()),
+        Ok(comparison) => print!("{}", comparison),
+        Err(e) => {
+            error!("Invalid replay parameters. {}", e);
+            return EXIT_CONFIG;
+        }
+    }
+    0
+}
 
-    let mut extremes = ExtremeTracker::new();
-    let mut cycles: u64 = 0;
+/// What's in effect after the --config file and the options on top of it.
+fn log_config(path: &Path, args: &cli::Args) {
+    let config = &args.config;
+    let sensor = match &args.sensor {
+        Some(SensorSpec::W1(path)) => path.display().to_string(),
+        Some(spec) => format!("{:?}", spec),
+        None => "not set".to_string(),
+    };
+    info!(
+        "Config from {}: band {} to {}, compensation up to {}, minimum on {} off {}, polling every {}, sensor {}, \
+         relay pin {}.",
+        path.display(),
+        config.band.target.start,
+        config.band.target.end,
+        config.band.max_compensation,
+        humantime::format_duration(config.intervals.on),
+        humantime::format_duration(config.intervals.off),
+        humantime::format_duration(config.sampling.interval),
+        sensor,
+        args.power_pin
+            .map_or_else(|| "not set".to_string(), |pin| pin.to_string()),
+    );
+}
 
-    loop {
-        if state != State::InitiallyOff {
-            trace!("Sleeping: {:?}", POLL_DURATION);
-            world.sleep(POLL_DURATION);
-        }
-
-        let temperature = loop {
-            match world.get_temperature() {
-                Ok(t) => break t,
-                Err(e) => {
-                    error!("Could not read temperature. {:?}", e);
-                    world.sleep(Duration::from_secs(10));
-                    continue;
-                }
-            }
-        };
-        trace!("Read temperature: {}", format_c_and_f(temperature));
-        extremes.push(temperature);
+/// Each preset's settings, one per line.
+fn list_presets() {
+    for preset in &Preset::ALL {
+        let config = preset.config();
+        println!(
+            "{:<9} band {} to {}, compensation up to {}, minimum on {} off {}, runaway after {}, duty alert over {:.0}% \
+             after {} pulling down, {} control",
+            preset.to_string(),
+            config.band.target.start,
+            config.band.target.end,
+            config.band.max_compensation,
+            humantime::format_duration(config.intervals.on),
+            humantime::format_duration(config.intervals.off),
+            humantime::format_duration(config.runaway.max_on),
+            config.duty_alert.threshold * 100.0,
+            humantime::format_duration(config.duty_alert.pull_down_grace),
+            config.mode,
+        );
+    }
+}
 
-        if temperature > LOW_COMPENSATION_RESET {
-            info!(
-                "Temperature {} exceeded low compensation reset threshold",
-                format_c_and_f(temperature)
-            );
-            if !low_compensator.is_zero() {
-                info!("Low compensator and threshold reset");
-                low_compensator.reset();
-                low_threshold = low_compensator.get_threshold();
-                if let Err(e) =
-                    world.persist_compensation(low_compensator.get_compensation(), high_compensator.get_compensation())
-                {
-                    warn!("Failed to persist compensations. {:?}", e);
-                }
-            }
+/// Shows the status of the daemon whose control socket is the first positional argument until interrupted, the exit
+/// code.
+fn watch_daemon(args: &cli::Args) -> i32 {
+    let socket = match args.positional.first() {
+        Some(socket) => Path::new(socket),
+        None => {
+            error!("Watch needs the daemon's control socket, /var/lib/picool/control_<sensor>.sock.");
+            return EXIT_CONFIG;
         }
-
-        let transition_thresholds = low_threshold..high_threshold;
-        let new_state = transition(state, temperature, transition_thresholds, world.now());
-        let previous_state = replace(&mut state, new_state);
-
-        if previous_state != new_state {
-            info!("State changed: {} -> {}", previous_state, new_state);
+    };
+    let shutdown = Shutdown::default();
+    if let Err(e) = shutdown.on_signals() {
+        warn!("Interrupting the watch won't restore the terminal. {:?}", e);
+    }
+    match watch::watch(socket, args.tui, args.units, &shutdown) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{:#}", e);
+            EXIT_FAILURE
         }
+    }
+}
 
-        if previous_state.is_on() != new_state.is_on() {
-            debug!("Updating power state: {}", new_state.is_on());
-            world.set_power_state(new_state.is_on());
-            if new_state.is_off() {
-                // On -> Off
-                debug!("Persisting last off transition.");
-                if let Err(e) = world.persist_last_off_transition() {
-                    warn!("Failed to persist last off transition. {:?}", e);
-                }
-            }
-
-            cycles += 1;
+/// Prints the cycles in the history that is the first positional argument, the exit code.
+fn print_cycles(args: &cli::Args, filter: &CycleFilter) -> i32 {
+    let entries = match cycle_history::read(Path::new(&args.positional[0])) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("{:#}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let shown: Vec<HistoryEntry> = entries.into_iter().filter(|entry| filter.matches(entry, now)).collect();
+    let format = match (args.json, args.csv) {
+        (true, _) => CycleFormat::Json,
+        (false, true) => CycleFormat::Csv,
+        (false, false) => CycleFormat::Table,
+    };
+    print!("{}", cycle_history::render(&shown, format, args.units));
+    0
+}
 
-            if cycles > 2 {
-                let mut updated: bool = false;
-                if new_state.is_off() {
-                    // On -> Off
-                    if let Some(max_temp_during_on_cycle) = extremes.max() {
-                        trace!(
-                            "Max temp seen during on cycle: {}",
-                            format_c_and_f(max_temp_during_on_cycle)
-                        );
-                        high_compensator.push_observation(max_temp_during_on_cycle);
-                        if high_compensator.is_capped() {
-                            warn!("Heating compenstation is capped at maximum compensation.");
-                        }
-                        let old_threshold = replace(&mut high_threshold, high_compensator.get_threshold());
-                        if old_threshold != high_threshold {
-                            debug!(
-                                "Updated heating threshold: {} -> {} (target: {})",
-                                format_c_and_f(old_threshold),
-                                format_c_and_f(high_threshold),
-                                format_c_and_f(TARGET_RANGE.end)
-                            );
-                            updated = true;
-                        }
-                    }
-                } else {
-                    // Off -> On
-                    if let Some(min_temp_during_off_cycle) = extremes.min() {
-                        trace!(
-                            "Min temp seen during off cycle: {}",
-                            format_c_and_f(min_temp_during_off_cycle)
-                        );
-                        low_compensator.push_observation(min_temp_during_off_cycle);
-                        let old_threshold = replace(&mut low_threshold, low_compensator.get_threshold());
-                        if low_compensator.is_capped() {
-                            warn!("Cooling compenstation is capped at maximum compensation.");
-                        }
-                        if old_threshold != low_threshold {
-                            debug!(
-                                "Updated cooling threshold: {} -> {} (target: {})",
-                                format_c_and_f(old_threshold),
-                                format_c_and_f(low_threshold),
-                                format_c_and_f(TARGET_RANGE.start)
-                            );
-                            updated = true;
-                        }
-                    }
-                }
-                if updated {
-                    if let Err(e) = world
-                        .persist_compensation(low_compensator.get_compensation(), high_compensator.get_compensation())
-                    {
-                        warn!("Failed to persist compensations. {:?}", e);
-                    }
-                }
-                extremes.reset();
-            }
+/// Prints what the daemon would restore for the chamber sensor, failing when a state file is damaged.
+fn print_state(args: &cli::Args) -> i32 {
+    let sensor = match &args.sensor {
+        Some(sensor) => sensor,
+        None => missing_argument("the chamber sensor, --sensor-path or --sensor"),
+    };
+    let name = match sensor.name() {
+        Ok(name) => name,
+        Err(e) => {
+            error!("{:#}", e);
+            return EXIT_CONFIG;
         }
+    };
+    let (last_off, compensation) = real_world::restored_paths(&real_world::state_dir(args.state_dir.as_deref()), &name);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let floor = args.clock_floor.unwrap_or(real_world::CLOCK_FLOOR);
+    let report = state_report::report(&last_off, &compensation, &args.config, now, floor);
+    print!("{}", report.text);
+    match report.damaged {
+        true => EXIT_FAILURE,
+        false => 0,
     }
 }
 
-impl State {
-    fn is_on(&self) -> bool {
-        match self {
-            State::InitiallyOff => false,
-            State::MinimumIntervalOn(_) => true,
-            State::MinimumIntervalOff(_) => false,
-            State::On => true,
-            State::Off => false,
+/// Clears or seeds the chamber sensor's learned compensation, printing what was stored before.
+fn reset_compensation(args: &cli::Args) -> i32 {
+    let sensor = match &args.sensor {
+        Some(sensor) => sensor,
+        None => missing_argument("the chamber sensor, --sensor-path or --sensor"),
+    };
+    let dir = real_world::state_dir(args.state_dir.as_deref());
+    let (path, stored) = match real_world::reset_compensation(&dir, sensor, args.seed_compensation) {
+        Ok(reset) => reset,
+        Err(e) => {
+            if let Some(held) = e.downcast_ref::<LockHeld>() {
+                error!("Stop picool first, it would write its compensation back. {}", held);
+                return EXIT_LOCK_HELD;
+            }
+            error!("{:#}", e);
+            return EXIT_FAILURE;
         }
+    };
+    match stored {
+        StoredCompensation::Missing => println!("No compensation was stored in {}.", path.display()),
+        StoredCompensation::Learned(cooling, heating) => println!(
+            "Cleared cooling {}, heating {} from {}.",
+            cooling,
+            heating,
+            path.display()
+        ),
+        StoredCompensation::Corrupt(e) => println!("Cleared a corrupt {}, {:#}", path.display(), e),
     }
-
-    fn is_off(&self) -> bool {
-        !self.is_on()
+    if let Some((cooling, heating)) = args.seed_compensation {
+        println!("Seeded cooling {}, heating {}.", cooling, heating);
     }
+    0
 }
 
-// Pure
-fn determine_initial_state(maybe_restored_state: Result<RestoredPowerState>, now: Instant) -> State {
-    match maybe_restored_state {
-        Ok(restored_state) => {
-            debug!("Restored state: {}", restored_state);
-            match restored_state {
-                RestoredPowerState::CurrentlyOn => State::MinimumIntervalOn(now),
-                RestoredPowerState::OffFor(duration) => match duration > MINIMUM_OFF_DURATION {
-                    true => State::InitiallyOff,
-                    false => State::MinimumIntervalOff(now - duration),
-                },
-                RestoredPowerState::OffForUnknownDuration => State::MinimumIntervalOff(now),
+/// Sends a boost to the daemon whose control socket is the first positional argument, the exit code.
+fn boost_daemon(args: &cli::Args, request: &BoostRequest) -> i32 {
+    let socket = Path::new(&args.positional[0]);
+    match control::boost(socket, request) {
+        Ok(()) => {
+            match request {
+                BoostRequest::Start(boost) => info!(
+                    "Boosting to {} to {} for {}m.",
+                    boost.band.start,
+                    boost.band.end,
+                    boost.remaining.as_secs() / 60
+                ),
+                BoostRequest::Cancel => info!("Boost cancelled."),
             }
+            0
         }
         Err(e) => {
-            warn!("Failed get last off transition: {:?}", e);
-            State::MinimumIntervalOff(now)
+            error!("{:#}", e);
+            EXIT_FAILURE
         }
     }
 }
 
-// Pure
-fn transition(initial: State, current_temperature: f32, threshold_range: Range<f32>, now: Instant) -> State {
-    match initial {
-        State::MinimumIntervalOn(s) if now - s < MINIMUM_ON_DURATION => State::MinimumIntervalOn(s),
-        State::MinimumIntervalOff(s) if now - s < MINIMUM_OFF_DURATION => State::MinimumIntervalOff(s),
-        State::On | State::MinimumIntervalOn(_) => match is_too_cold(current_temperature, threshold_range.start) {
-            true => State::MinimumIntervalOff(now),
-            false => State::On,
-        },
-        State::Off | State::InitiallyOff | State::MinimumIntervalOff(_) => {
-            match is_too_hot(current_temperature, threshold_range.end) {
-                true => State::MinimumIntervalOn(now),
-                false => State::Off,
+/// Loads the trace named by the first positional argument, exiting with EXIT_CONFIG if it can't be replayed.
+fn start_replay_world(args: &cli::Args, shutdown: &Shutdown) -> ReplayWorld {
+    if args.self_test || args.check_config {
+        error!("The self-test needs the real world.");
+        exit(EXIT_FAILURE);
+    }
+    let trace = load_trace(args);
+    info!(
+        "Replaying {} readings over {}h.",
+        trace.samples.len(),
+        trace.duration().as_secs() / 3600
+    );
+    let decisions: Box<dyn Write> = match &args.replay_output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => {
+                error!("Failed to create {}. {}", path.display(), e);
+                exit(EXIT_CONFIG);
             }
-        }
+        },
+        None => Box::new(io::stdout()),
+    };
+    let scenario = load_scenario(args);
+    if !scenario.is_empty() {
+        error!("Replay only takes a scenario's [store] table, the trace supplies the readings.");
+        exit(EXIT_CONFIG);
     }
+    let parameters = ReplayParameters {
+        store: scenario.store,
+        ..args.replay.clone()
+    };
+    ReplayWorld::new(trace, parameters, decisions, shutdown.clone()).unwrap_or_else(|e| {
+        error!("Invalid replay parameters. {}", e);
+        exit(EXIT_CONFIG);
+    })
 }
 
-// Pure
-fn is_too_cold(temperature: f32, threshold: f32) -> bool {
-    temperature < threshold
+/// The chamber sensor and the relay pin, as given on the command line, exiting with the usage without either.
+fn chamber(args: &cli::Args) -> (SensorSpec, u8) {
+    match (&args.sensor, args.power_pin) {
+        (Some(sensor), Some(pin)) => (sensor.clone(), pin),
+        (None, _) => missing_argument("the chamber sensor, --sensor-path or --sensor"),
+        (_, None) => missing_argument("the relay pin, --power-pin"),
+    }
 }
 
-// Pure
-fn is_too_hot(temperature: f32, threshold: f32) -> bool {
-    temperature > threshold
+fn missing_argument(what: &str) -> ! {
+    error!("The real world needs {}.", what);
+    eprint!("\n{}", cli::USAGE);
+    exit(EXIT_CONFIG);
 }
 
-// Pure
-fn c_to_f(c: f32) -> f32 {
-    (c * 9.0 / 5.0) + 32.0
+/// How outside systems tell controllers apart, the sensor as in the state file names.
+fn instance_name(args: &cli::Args) -> String {
+    match args.world {
+        WorldKind::Real => chamber(args).0.name().unwrap_or_else(|_| "picool".into()),
+        WorldKind::Demo => "demo".into(),
+        WorldKind::Replay => "replay".into(),
+    }
 }
 
-// Pure
-fn format_c_and_f(c: f32) -> String {
-    format!("{:.2}C {:.2}F", c, c_to_f(c))
+/// Acquires the sensor, pin and state files, exiting with the startup error's code if any are unavailable.
+fn start_real_world(
+    args: &cli::Args,
+    shutdown: &Shutdown,
+    board: StatusBoard,
+    runtime: &mut Runtime,
+) -> (RealWorld, Receiver<Command>) {
+    let (sensor, pin) = chamber(args);
+    if args.config.differential.is_enabled() && args.ambient_sensor.is_none() {
+        error!("Differential mode follows the ambient, it needs --ambient-sensor.");
+        exit(EXIT_CONFIG);
+    }
+    if args.export_timeline.is_some() {
+        error!("A timeline is only exported from simulate and replay.");
+        exit(EXIT_CONFIG);
+    }
+    // A shadow run claims no pin, the relay stays with whatever is in charge of it.
+    let gpio = match args.shadow {
+        true => GpioConfig {
+            backend: BackendKind::Detached,
+            ..args.gpio.clone()
+        },
+        false => args.gpio.clone(),
+    };
+    let world = RealWorld::new(
+        sensor,
+        pin,
+        real_world::state_dir(args.state_dir.as_deref()),
+        &gpio,
+        shutdown.clone(),
+    )
+    .and_then(|world| world.with_sensor_format(args.sensor_format))
+    .and_then(|world| match args.sensor_resolution {
+        Some(resolution) => world.with_sensor_resolution(resolution),
+        None => Ok(world),
+    })
+    .and_then(|world| match &args.ambient_sensor {
+        Some(path) => world.with_ambient_sensor(path.clone()),
+        None => Ok(world),
+    })
+    .and_then(|world| match args.fan_pin {
+        Some(pin) => world.with_fan_pin(pin),
+        None => Ok(world),
+    })
+    .and_then(|world| match args.stage2_pin {
+        Some(pin) => world.with_stage2_pin(pin),
+        None => Ok(world),
+    })
+    .and_then(|world| match args.pump_pin {
+        Some(pin) => world.with_pump_pin(pin),
+        None => Ok(world),
+    })
+    .and_then(|world| match args.backup_pin {
+        Some(pin) => world.with_backup_pin(pin),
+        None => Ok(world),
+    })
+    .and_then(|world| match &args.current_sensor {
+        Some(spec) => world.with_current_sensor(spec.clone()),
+        None => Ok(world),
+    })
+    .unwrap_or_else(|e| {
+        if let Some(held) = e.downcast_ref::<LockHeld>() {
+            error!("{}", held);
+            exit(EXIT_LOCK_HELD);
+        }
+        if let Some(startup_error) = e.downcast_ref::<StartupError>() {
+            error!("{}", startup_error);
+            exit(startup_error.exit_code());
+        }
+        error!("Failed to initialize. {:?}", e);
+        exit(EXIT_FAILURE);
+    });
+    let mut world = match &args.heartbeat_file {
+        Some(path) => world.with_heartbeat_path(path.clone()),
+        None => world,
+    };
+    if let Some(keep) = args.cycle_history {
+        world = world.with_cycle_history(keep);
+    }
+    if let Some(floor) = args.clock_floor {
+        world = world.with_clock_floor(floor);
+    }
+    board.count_deferrals(world.relay_deferrals());
+    let (sender, commands) = channel();
+    if let Err(e) = control::serve(world.control_socket_path(), board, sender.clone(), runtime) {
+        warn!("Control socket unavailable. {:?}", e);
+    }
+    if let Err(e) = Reloader::new(env::args().skip(1).collect(), args).on_sighup(sender, runtime) {
+        warn!("Reloading on SIGHUP unavailable. {:?}", e);
+    }
+    if let Some(spec) = &args.run_as {
+        if let Err(e) = privileges::drop_privileges(spec, &world.state_paths()) {
+            error!("Failed to drop privileges. {:?}", e);
+            exit(EXIT_FAILURE);
+        }
+    }
+    if args.self_test || args.check_config {
+        let pulse = if args.check_config { None } else { args.pulse_relay };
+        let results = self_test::run_checks(&mut world, pulse);
+        for result in &results {
+            info!("Self-test {}", result);
+        }
+        if !self_test::all_mandatory_passed(&results) {
+            error!("Self-test failed, refusing to enter control.");
+            exit(EXIT_SELF_TEST);
+        }
+        if args.check_config {
+            exit(0);
+        }
+    }
+    (world, commands)
 }
 
-struct Compensator {
-    target: f32,
-    observations: VecDeque<f32>,
-    compensation: f32,
-    max_compensation: f32,
+/// What the controller starts from, the safe defaults if the power state can't be restored.
+fn restore_seed(world: &impl World, intervals: MinimumIntervals) -> Seed {
+    restore_seed_with(world, intervals, StartMode::Auto)
 }
 
-impl Compensator {
-    pub fn new(target: f32, mut seed_compensation: f32, max_compensation: f32) -> Self {
-        if max_compensation.classify() == FpCategory::Zero {
-            panic!("max_compensation can not be 0.");
+/// Starting where `mode` says rather than where the previous run left the relay.
+fn restore_seed_with(world: &impl World, intervals: MinimumIntervals, mode: StartMode) -> Seed {
+    let now = world.now();
+    match world.restore_state() {
+        Ok(restored) => {
+            let power_state = restored.power_state;
+            Seed {
+                state: initial_state(mode, Ok(power_state), now, intervals),
+                compensation: (restored.cooling_compensation, restored.heating_compensation),
+                cooling_rates: restored.cooling_rates,
+                energy: restored.energy,
+                runtime: restored.runtime,
+                starts: restored.starts,
+                boost: restored.boost,
+                intervals: restored.intervals,
+                overshoots: restored.overshoots,
+                rest: restored.rest,
+                shed: restored.shed,
+                extremes: restored.extremes.filter(|extremes| {
+                    let continues = extremes.continues(power_state);
+                    if !continues {
+                        info!(
+                            "Checkpointed cycle extremes dropped, the relay was left {:?} since",
+                            power_state
+                        );
+                    }
+                    continues
+                }),
+                failover: restored.failover,
+            }
         }
-        if seed_compensation.is_nan() {
-            error!("Compensator ignoring invalid seed compensation.");
-            seed_compensation = 0.0;
+        Err(e) => Seed::new(initial_state(mode, Err(e), now, intervals)),
+    }
+}
+
+/// A start forced off finds the relay as the previous run left it, a relay left on is turned off to match.
+fn turn_off_left_on(world: &mut impl World, seed: &Seed) {
+    let left_on = matches!(
+        world.restore_state().map(|restored| restored.power_state),
+        Ok(RestoredPowerState::CurrentlyOn)
+    );
+    if !left_on || seed.state.is_on() {
+        return;
+    }
+    warn!("The relay was left on, turning it off to start {}.", seed.state);
+    world.set_power_state(false);
+    if let Err(e) = world.persist_last_off_transition() {
+        warn!("Failed to persist the last off transition. {}", e);
+    }
+}
+
+fn control(
+    args: &cli::Args,
+    world: impl World,
+    commands: Receiver<Command>,
+    board: Option<StatusBoard>,
+    shutdown: &Shutdown,
+    runtime: &mut Runtime,
+) -> RunOutcome {
+    let progress = LoopProgress::default();
+    let _stall_monitor = match args.config.stall.polls {
+        0 => None,
+        polls => StallMonitor::spawn(
+            progress.clone(),
+            args.config.sampling.interval * polls,
+            args.config.stall.abort,
+            LogNotifier,
+        )
+        .map_err(|e| warn!("Stall monitor unavailable. {:?}", e))
+        .ok(),
+    };
+    let mut supervisor = Supervisor::new(progress);
+    // A simulation has no control socket, the board is only there to push from.
+    let board = board.or_else(|| args.push_gateway.as_ref().map(|_| StatusBoard::default()));
+    let pusher = match (&args.push_gateway, &board) {
+        (Some(gateway), Some(board)) => start_pusher(args, gateway, board.clone(), runtime),
+        _ => None,
+    };
+    if let Some(board) = board {
+        supervisor = supervisor.with_board(board);
+    }
+    if let Some(address) = &args.statsd {
+        match StatsdEmitter::new(address, args.statsd_dialect, &instance_name(args)) {
+            Ok(statsd) => supervisor = supervisor.with_statsd(statsd),
+            Err(e) => warn!("StatsD unavailable. {}", e),
         }
-        let observations = VecDeque::new();
-        Self {
-            target,
-            observations,
-            compensation: seed_compensation,
-            max_compensation,
+    }
+    let ws = args.ws_listen.as_ref().and_then(|address| {
+        WsServer::bind(address, runtime)
+            .map_err(|e| warn!("WebSocket stream unavailable. {:#}", e))
+            .ok()
+    });
+    if let Some(ws) = &ws {
+        info!("Streaming the status on ws://{}/ws.", ws.local_addr());
+        supervisor = supervisor.with_stream(ws.stream());
+    }
+    match SdNotify::from_env() {
+        Ok(Some(systemd)) => {
+            let watchdog = sd_notify::watchdog_interval_from_env();
+            if watchdog.is_some_and(|interval| interval / 2 < args.config.sampling.interval) {
+                warn!("WatchdogSec is shorter than two poll intervals, systemd may restart picool spuriously.");
+            }
+            supervisor = supervisor.with_systemd(systemd, watchdog);
         }
+        Ok(None) => {}
+        Err(e) => warn!("systemd notification unavailable. {:?}", e),
     }
-
-    pub fn get_compensation(&self) -> f32 {
-        if self.is_capped() {
-            return self.max_compensation;
+    let timeline = args.export_timeline.as_ref().map(|_| Timeline::default());
+    if let Some(timeline) = &timeline {
+        supervisor = supervisor.with_timeline(timeline.clone());
+    }
+    let dumps = DumpRequest::default();
+    match dumps.on_signal() {
+        Ok(()) => {
+            let dir = match args.world {
+                WorldKind::Real => Some(real_world::state_dir(args.state_dir.as_deref())),
+                WorldKind::Demo | WorldKind::Replay => None,
+            };
+            supervisor = supervisor.with_dumps(dumps, dir);
         }
-        if self.is_inverted() {
-            return 0.0;
+        Err(e) => warn!("Diagnostic dumps unavailable. {:?}", e),
+    }
+    if let Some(command) = &args.transition_hook {
+        supervisor = supervisor.with_hook(TransitionHook::new(command.clone()));
+    }
+    if let Some(source) = &args.shed_signal {
+        match ShedSignal::open(source) {
+            Ok(shed) => supervisor = supervisor.with_shed(shed),
+            Err(e) => warn!("Demand response signal unavailable. {:#}", e),
         }
-        self.compensation
     }
-
-    pub fn is_capped(&self) -> bool {
-        if self.max_compensation < 0.0 {
-            self.compensation < self.max_compensation
-        } else {
-            self.compensation > self.max_compensation
+    if args.shadow {
+        supervisor = supervisor.with_shadow();
+    }
+    supervisor.ready();
+    let outcome = match args.shadow {
+        true => {
+            warn!("Shadow mode, the relay is never switched, only what picool would do is logged.");
+            let mut world = Shadow::new(world, open_shadow_log(args));
+            let seed = restore_seed_with(&world, args.config.intervals, args.initial_state);
+            turn_off_left_on(&mut world, &seed);
+            let supervisor = with_candidate(supervisor, args, &world);
+            run(seed, &args.config, world, LogNotifier, commands, supervisor, shutdown)
+        }
+        false => {
+            let mut world = world;
+            let seed = restore_seed_with(&world, args.config.intervals, args.initial_state);
+            turn_off_left_on(&mut world, &seed);
+            let supervisor = with_candidate(supervisor, args, &world);
+            run(seed, &args.config, world, LogNotifier, commands, supervisor, shutdown)
+        }
+    };
+    if let (Some(timeline), Some(path)) = (timeline, &args.export_timeline) {
+        match timeline.export(path) {
+            Ok(()) => info!("Timeline written to {}.", path.display()),
+            Err(e) => error!("{:#}", e),
         }
     }
+    // The clients hear about the end before the group is deleted, the control socket and the reload handler go with
+    // the runtime.
+    if let Some(ws) = ws {
+        ws.stop();
+    }
+    if let Some(pusher) = pusher {
+        pusher.stop(!matches!(outcome, RunOutcome::Fatal(_)));
+    }
+    outcome
+}
+
+/// Pushes from the runtime, a Pushgateway that can't be set up is only warned about like StatsD.
+fn start_pusher(
+    args: &cli::Args,
+    gateway: &pushgateway::Gateway,
+    board: StatusBoard,
+    runtime: &mut Runtime,
+) -> Option<Pusher> {
+    let interval = args.push_interval.unwrap_or(pushgateway::DEFAULT_INTERVAL);
+    Pushgateway::new(gateway, &instance_name(args), args.push_auth_file.as_deref())
+        .and_then(|pushgateway| Ok(Pusher::spawn(pushgateway, board, interval, runtime)?))
+        .map_err(|e| warn!("Pushgateway unavailable. {:#}", e))
+        .ok()
+}
+
+/// The --candidate controller starts from the same restored state as the primary.
+fn with_candidate(supervisor: Supervisor, args: &cli::Args, world: &impl World) -> Supervisor {
+    let config = match &args.candidate {
+        Some(config) => config,
+        None => return supervisor,
+    };
+    info!("Comparing a candidate configuration, it never switches the relay.");
+    let seed = restore_seed_with(world, config.intervals, args.initial_state);
+    let reports = args.candidate_report.as_ref().map(|path| open_report(path));
+    supervisor.with_candidate(Candidate::new(seed, config, world.now(), reports))
+}
 
-    fn is_inverted(&self) -> bool {
-        if self.max_compensation < 0.0 {
-            self.compensation > 0.0
-        } else {
-            self.compensation < 0.0
+/// A file to write as it goes, exiting with EXIT_CONFIG if it can't be created.
+fn open_report(path: &Path) -> Box<dyn Write> {
+    match File::create(path) {
+        Ok(file) => Box::new(file),
+        Err(e) => {
+            error!("Failed to create {}. {}", path.display(), e);
+            exit(EXIT_CONFIG);
         }
     }
+}
 
-    pub fn get_threshold(&self) -> f32 {
-        self.target + self.get_compensation()
-    }
+/// The --shadow-log file if given, exiting with EXIT_CONFIG if it can't be created.
+fn open_shadow_log(args: &cli::Args) -> Option<Box<dyn Write>> {
+    args.shadow_log.as_ref().map(|path| open_report(path))
+}
 
-    pub fn reset(&mut self) {
-        self.compensation = 0.0;
-        self.observations.clear();
+/// A failed ambient read only loses the trim, so it is warned about now and then rather than failing anything.
+fn read_ambient(world: &impl World, warnings: &mut WarningLimiter) -> Option<Celsius> {
+    match world.get_ambient_temperature()?.and_then(validate_temperature) {
+        Ok(ambient) => Some(ambient),
+        Err(e) => {
+            if warnings.should_warn(world.now()) {
+                warn!("Could not read the ambient temperature ({}). {}", e.class(), e);
+            }
+            None
+        }
     }
+}
 
-    pub fn is_zero(&self) -> bool {
-        self.compensation.classify() == FpCategory::Zero
+fn read_current(world: &impl World, warnings: &mut WarningLimiter) -> Option<f32> {
+    match world.get_current()? {
+        Ok(amps) => Some(amps),
+        Err(e) => {
+            if warnings.should_warn(world.now()) {
+                warn!("Could not read the compressor current ({}). {}", e.class(), e);
+            }
+            None
+        }
     }
+}
 
-    pub fn push_observation(&mut self, value: f32) {
-        const MAX_OBSERVATIONS: u8 = 4;
-        const MIN_UPDATE: f32 = 0.01;
-
-        if value.classify() == FpCategory::Nan {
-            error!("Compensator discarded invalid observation.");
-            return;
-        }
+// Pure w.r.t. World
+fn run(
+    seed: Seed,
+    config: &Config,
+    mut world: impl World,
+    mut notifier: impl Notifier,
+    commands: Receiver<Command>,
+    mut supervisor: Supervisor,
+    shutdown: &Shutdown,
+) -> RunOutcome {
+    let mut controller = ControllerState::new(seed, config, world.now());
+    // Reloads move the band, the hysteresis, the intervals and the sampling.
+    let mut config = config.clone();
+    if let Some((Output::Backup, _)) = controller.output() {
+        world.select_output(Output::Backup);
+    }
+    let mut persistence = PersistenceHealth::new(config.storage);
+    let mut heartbeat_warnings = WarningLimiter::default();
+    let mut ambient_warnings = WarningLimiter::default();
+    let mut current_warnings = WarningLimiter::default();
+    let mut timings = LoopTimings::new(config.sampling.interval);
 
-        let delta = self.get_threshold() - value;
-        self.observations.push_back(delta);
-        if self.observations.len() > MAX_OBSERVATIONS as usize {
-            self.observations.pop_front();
+    loop {
+        if let Some(outcome) = stop_reason(&persistence, shutdown, controller.completed_cycles()) {
+            shut_down(&mut world, &mut controller, &mut persistence);
+            return outcome;
         }
-        let mut sorted_observations: Vec<f32> = self.observations.iter().copied().collect();
-        sorted_observations.sort_by(|a, b| a.partial_cmp(b).expect("Invariant: Never contains NaN observations."));
-        let median_delta = match sorted_observations.len() {
-            1 => sorted_observations[0],
-            len if len % 2 == 0 => {
-                let m1 = sorted_observations[(len / 2) - 1];
-                let m2 = sorted_observations[len / 2];
-                (m1 + m2) / 2.0
+        if controller.state() != State::InitiallyOff {
+            trace!("Sleeping: {:?}", config.sampling.interval);
+            world.sleep(config.sampling.interval);
+            if shutdown.is_requested() {
+                continue;
             }
-            len => sorted_observations[len / 2],
-        };
-        let update = median_delta - self.compensation;
+        }
 
-        if update.abs() > MIN_UPDATE {
-            self.compensation = median_delta;
+        loop {
+            let mut iteration = Iteration::start(world.now());
+            supervisor.tick(world.now());
+            let since = world.now();
+            let temperature = world.get_temperature().and_then(validate_temperature);
+            let reading = temperature.as_ref().ok().copied();
+            let ambient = read_ambient(&world, &mut ambient_warnings);
+            let current = read_current(&world, &mut current_warnings);
+            let sensor_replaced = world.reacquire_sensor();
+            iteration.add(Phase::Sensor, since, world.now());
+            let failed = temperature.is_err();
+            let received = match failed {
+                true => vec![],
+                false => commands.try_iter().collect::<Vec<_>>(),
+            };
+            let input = Input {
+                temperature,
+                now: world.now(),
+                local: world.local_time(),
+                ambient,
+                acknowledged: received.contains(&Command::AcknowledgeFault),
+                reset_runtime: received.contains(&Command::ResetRuntime),
+                sensor_replaced,
+                boost: received.iter().rev().find_map(|command| match command {
+                    Command::Boost(request) => Some(request.clone()),
+                    _ => None,
+                }),
+                revert_timing: received.contains(&Command::RevertTiming),
+                shed: supervisor.shed_asserted(),
+                pump: world.pump_state(),
+                current,
+                reset_failover: received.contains(&Command::ResetFailover),
+                reload: received.into_iter().rev().find_map(|command| match command {
+                    Command::Reload(config) => Some(*config),
+                    _ => None,
+                }),
+            };
+            supervisor.step_candidate(&input);
+            if let Some(reloaded) = &input.reload {
+                timings.set_interval(reloaded.sampling.interval);
+                config = reloaded.clone();
+            }
+            let (next, effects) = step(controller, input);
+            controller = next;
+            for effect in effects {
+                let phase = phase(&effect);
+                let since = world.now();
+                let termination = execute(
+                    effect,
+                    &mut world,
+                    &mut notifier,
+                    &mut supervisor,
+                    &mut persistence,
+                    &mut heartbeat_warnings,
+                );
+                if let Some(phase) = phase {
+                    iteration.add(phase, since, world.now());
+                }
+                if let Some(termination) = termination {
+                    shut_down(&mut world, &mut controller, &mut persistence);
+                    return RunOutcome::Fatal(termination);
+                }
+            }
+            let since = world.now();
+            supervisor.publish(world.now(), reading, &controller);
+            supervisor.dump_if_requested(world.now(), &controller, &config);
+            iteration.add(Phase::Report, since, world.now());
+            timings.finish(iteration, world.now());
+            supervisor.loop_latency(&timings);
+            if !failed {
+                break;
+            }
+            world.sleep(SENSOR_RETRY_DURATION);
+            if shutdown.is_requested() {
+                shut_down(&mut world, &mut controller, &mut persistence);
+                return RunOutcome::ShutdownRequested;
+            }
         }
     }
 }
 
-struct ExtremeTracker {
-    min: f32,
-    max: f32,
-    measured: bool,
+fn stop_reason(persistence: &PersistenceHealth, shutdown: &Shutdown, completed_cycles: u32) -> Option<RunOutcome> {
+    if let Some(termination) = persistence.termination() {
+        return Some(RunOutcome::Fatal(termination));
+    }
+    if shutdown.is_requested() {
+        return Some(RunOutcome::ShutdownRequested);
+    }
+    match shutdown.cycle_limit() {
+        Some(limit) if completed_cycles >= limit => Some(RunOutcome::CycleLimitReached {
+            cycles: completed_cycles,
+        }),
+        _ => None,
+    }
 }
 
-impl ExtremeTracker {
-    pub fn new() -> Self {
-        Self {
-            min: f32::MAX,
-            max: f32::MIN,
-            measured: false,
-        }
+// Pure
+/// What carrying out `effect` is timed as, switching, re-initializing the sensor and stopping only as the iteration.
+fn phase(effect: &Effect) -> Option<Phase> {
+    match effect {
+        Effect::Heartbeat(_) => Some(Phase::Persist),
+        Effect::Notify(_) | Effect::Transition(_) | Effect::Status(_) => Some(Phase::Report),
+        effect if effect.persists() => Some(Phase::Persist),
+        _ => None,
     }
+}
 
-    pub fn reset(&mut self) {
-        *self = Self::new()
+/// Carries out one of the controller's effects, the termination if it asks to stop.
+fn execute(
+    effect: Effect,
+    world: &mut impl World,
+    notifier: &mut impl Notifier,
+    supervisor: &mut Supervisor,
+    persistence: &mut PersistenceHealth,
+    heartbeat_warnings: &mut WarningLimiter,
+) -> Option<Termination> {
+    if effect.persists() && !persistence.should_persist(world.now()) {
+        trace!("Storage unreliable, keeping {:?} in memory.", effect);
+        return None;
     }
-
-    pub fn push(&mut self, value: f32) {
-        if value < self.min {
-            self.min = value;
+    match effect {
+        Effect::SetPower(on) => world.set_power_state(on),
+        Effect::SetFan(on) => world.set_fan_state(on),
+        Effect::SetStage2(on) => world.set_stage2_state(on),
+        Effect::SetPump(on) => world.set_pump_state(on),
+        Effect::SelectOutput(output) => world.select_output(output),
+        Effect::PersistLastOff => persistence.record(world.persist_last_off_transition(), "last off transition"),
+        Effect::PersistCompensation { cooling, heating } => {
+            persistence.record(world.persist_compensation(cooling, heating), "compensations")
+        }
+        Effect::PersistCoolingRates(rates) => persistence.record(world.persist_cooling_rates(&rates), "cooling rates"),
+        Effect::PersistEnergy(totals) => persistence.record(world.persist_energy(totals), "energy"),
+        Effect::PersistRuntime(runtime) => persistence.record(world.persist_runtime(runtime), "runtime"),
+        Effect::PersistStarts(counts) => persistence.record(world.persist_starts(&counts), "starts"),
+        Effect::PersistBoost(boost) => persistence.record(world.persist_boost(boost.as_ref()), "boost"),
+        Effect::PersistIntervals(intervals) => persistence.record(world.persist_intervals(intervals), "intervals"),
+        Effect::PersistOvershoots(overshoots) => {
+            persistence.record(world.persist_overshoots(&overshoots), "overshoots")
         }
-        if value > self.max {
-            self.max = value;
+        Effect::PersistRest(rest) => persistence.record(world.persist_rest(rest.as_ref()), "rest"),
+        Effect::PersistShed(shed) => persistence.record(world.persist_shed(shed.as_ref()), "shed"),
+        Effect::PersistExtremes(extremes) => {
+            persistence.record(world.persist_extremes(extremes.as_ref()), "cycle extremes")
         }
-        self.measured = true;
+        Effect::RecordCycle(cycle) => persistence.record(world.record_cycle(&cycle), "cycle history"),
+        Effect::PersistFailover(faulted) => persistence.record(world.persist_failover(faulted), "failover"),
+        Effect::Notify(event) => {
+            supervisor.event(&event);
+            notifier.notify(&event)
+        }
+        Effect::Transition(transition) => supervisor.transition(&transition),
+        Effect::Status(status) => supervisor.status(&status),
+        Effect::Heartbeat(status) => beat(world, heartbeat_warnings, status),
+        Effect::ReinitializeSensor => {
+            if let Err(e) = world.reinitialize() {
+                warn!("Sensor re-initialization failed. {}", e);
+            }
+        }
+        Effect::Terminate(termination) => return Some(termination),
+    }
+    for event in persistence.take_events() {
+        supervisor.event(&event);
+        notifier.notify(&event);
     }
+    None
+}
 
-    pub fn min(&self) -> Option<f32> {
-        match self.measured {
-            true => Some(self.min),
-            false => None,
+fn beat(world: &mut impl World, warnings: &mut WarningLimiter, status: HeartbeatStatus) {
+    if let Err(e) = world.write_heartbeat(status) {
+        if warnings.should_warn(world.now()) {
+            warn!("Failed to write heartbeat. {}", e);
         }
     }
+}
 
-    pub fn max(&self) -> Option<f32> {
-        match self.measured {
-            true => Some(self.max),
-            false => None,
-        }
+/// Leave the relay off on the way out so a restart begins from the conservative path, and keep the energy used,
+/// compressor runtime and the extremes of an off cycle since the last checkpoint.
+fn shut_down(world: &mut impl World, controller: &mut ControllerState, persistence: &mut PersistenceHealth) {
+    if let Some(totals) = controller.energy_checkpoint(world.now(), world.local_time()) {
+        persistence.record(world.persist_energy(totals), "energy");
+    }
+    if let Some(extremes) = controller.cycle_extremes(world.now()).filter(|extremes| !extremes.on) {
+        persistence.record(world.persist_extremes(Some(&extremes)), "cycle extremes");
+    }
+    if controller.power_on() {
+        info!("Turning relay off before exiting.");
+        world.set_power_state(false);
+        persistence.record(world.persist_last_off_transition(), "last off transition");
+        persistence.record(world.persist_runtime(controller.runtime(world.now())), "runtime");
+    }
+    if controller.fan_on() {
+        world.set_fan_state(false);
+    }
+    if controller.stage2(world.now()).is_some_and(|(on, _, _)| on) {
+        info!("Turning stage 2 off before exiting.");
+        world.set_stage2_state(false);
+    }
+    if controller.pump().is_some_and(|(on, _)| on) {
+        info!("Turning pump off before exiting.");
+        world.set_pump_state(false);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{TempDir, WsClient};
+    use crate::test_world::{
+        run_to_end, run_to_end_with, run_to_outcome, Load,
+        Power::{self, Off, On},
+        Reading, Recording, RecordingNotifier, TestWorld, TestWorldBuilder,
+    };
+    use crate::websocket::Frame;
+    use picool_core::{
+        current::LoadFault,
+        energy::{DailyEnergy, EnergyConfig},
+        failsafe::{FailsafeConfig, FailsafePolicy},
+        notify::Event,
+        runaway::RunawayConfig,
+        shed::ShedEnd,
+        starts::DailyStarts,
+        termination::{StorageConfig, EXIT_PERSISTENCE, EXIT_SENSOR},
+        timing_advisor::TimingAdvice,
+        world_error::{PersistErrorKind, SensorErrorCounts, SensorErrorKind},
+        MAX_COMPENSATION, TARGET_RANGE,
+    };
+    use std::{
+        cell::RefCell,
+        fs, io,
+        panic::{catch_unwind, AssertUnwindSafe},
+        rc::Rc,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::Sender,
+            Arc,
+        },
+    };
+
+    type Recorded<T> = Rc<RefCell<Vec<T>>>;
+
+    /// Runs the script to completion, returning what happened to the world and the notifications.
+    fn run_script(initial_state: State, readings: Vec<Option<f32>>, config: Config) -> (Recording, Vec<Event>) {
+        let world = TestWorld::builder().readings(readings).build();
+        let recording = world.recording();
+        let events = run_to_end(world, Seed::new(initial_state), &config);
+        (recording, events)
+    }
+
+    /// One good reading, `failures` failed reads 10s apart starting at t=20s, then good readings.
+    fn outage(failures: usize) -> Vec<Option<f32>> {
+        let mut readings = vec![Some(3.0)];
+        readings.extend(std::iter::repeat_n(None, failures));
+        readings.extend(std::iter::repeat_n(Some(3.0), 5));
+        readings
+    }
+
+    fn failsafe_config(policy: FailsafePolicy) -> Config {
+        Config {
+            sensor_failsafe: FailsafeConfig {
+                after: Duration::from_secs(300),
+                policy,
+                ..FailsafeConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn failsafe_off_policy() {
+        let (recording, events) = run_script(State::On, outage(90), failsafe_config(FailsafePolicy::Off));
+        recording.assert_transitions(&[(320, Off)]);
+        assert_eq!(
+            vec![
+                Event::SensorFailsafeEngaged {
+                    failing_for: Duration::from_secs(300),
+                    policy: FailsafePolicy::Off,
+                    cause: SensorErrorKind::Unreadable
+                },
+                Event::SensorRecovered {
+                    outage: Duration::from_secs(900),
+                    errors: error_counts(SensorErrorKind::Unreadable, 90)
+                }
+            ],
+            events
+        );
+    }
+
+    #[test]
+    fn failsafe_on_policy() {
+        let (recording, events) = run_script(State::Off, outage(90), failsafe_config(FailsafePolicy::On));
+        // Recovery resumes in MinimumIntervalOn, so the relay stays on at a mid-band temperature.
+        recording.assert_transitions(&[(320, On)]);
+        assert_eq!(2, events.len());
+    }
+
+    #[test]
+    fn failsafe_duty_cycle_policy() {
+        let policy = FailsafePolicy::DutyCycle(Duration::from_secs(15 * 60));
+        let (recording, _) = run_script(State::On, outage(540), failsafe_config(policy));
+        recording.assert_transitions(&[(320, Off), (3020, On), (3920, Off)]);
+    }
+
+    #[test]
+    fn heartbeat_reports_failsafe() {
+        let (recording, _) = run_script(State::On, outage(60), failsafe_config(FailsafePolicy::Off));
+        recording.assert_transitions(&[(320, Off)]);
+        let heartbeats = recording.heartbeats();
+        // One good poll, 30 failed reads from engagement at 320s to 610s, then the recovered polls.
+        assert_eq!(HeartbeatStatus::Controlling, heartbeats[0]);
+        assert_eq!(
+            30,
+            heartbeats.iter().filter(|s| **s == HeartbeatStatus::Failsafe).count()
+        );
+        assert_eq!(Some(&HeartbeatStatus::Controlling), heartbeats.last());
+    }
+
+    struct ChannelNotifier(Sender<Event>);
+
+    impl Notifier for ChannelNotifier {
+        fn notify(&mut self, event: &Event) {
+            let _ = self.0.send(event.clone());
+        }
+    }
+
+    #[test]
+    fn stall_monitor_reacts_to_wedged_sensor() {
+        let progress = LoopProgress::default();
+        let (events, stalls) = channel();
+        let _monitor = StallMonitor::spawn(
+            progress.clone(),
+            Duration::from_millis(100),
+            false,
+            ChannelNotifier(events),
+        )
+        .unwrap();
+        let world = TestWorld::builder()
+            .readings(ramp(3.0, 0.0, 5))
+            .wedge_until(stalls)
+            .build();
+        let outcome = catch_unwind(AssertUnwindSafe(|| {
+            let (_, commands) = channel();
+            run(
+                Seed::new(State::Off),
+                &Config::default(),
+                world,
+                LogNotifier,
+                commands,
+                Supervisor::new(progress),
+                &Shutdown::default(),
+            )
+        }));
+        let message = outcome.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("Wedged until Ok(LoopStalled"), "{}", message);
+    }
+
+    fn error_counts(kind: SensorErrorKind, n: u32) -> SensorErrorCounts {
+        let mut counts = SensorErrorCounts::default();
+        (0..n).for_each(|_| counts.record(kind));
+        counts
+    }
+
+    #[test]
+    fn missing_sensor_engages_failsafe_immediately() {
+        let world = TestWorld::builder()
+            .readings(vec![3.0])
+            .readings(vec![Reading::Error(libc::ENOENT); 10])
+            .readings(vec![3.0; 5])
+            .build();
+        let recording = world.recording();
+        let events = run_to_end(world, Seed::new(State::On), &failsafe_config(FailsafePolicy::Off));
+        recording.assert_transitions(&[(20, Off)]);
+        assert_eq!(
+            Event::SensorFailsafeEngaged {
+                failing_for: Duration::from_secs(0),
+                policy: FailsafePolicy::Off,
+                cause: SensorErrorKind::Missing
+            },
+            events[0]
+        );
+    }
+
+    #[test]
+    fn injected_burst_engages_failsafe_and_recovers() {
+        let parameters = DemoParameters {
+            scenario: "[[event]]\nat = \"1h\"\nfailure_burst = 60".parse().unwrap(),
+            ..Default::default()
+        };
+        let world = DemoWorld::new(parameters).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let (_, commands) = channel();
+        let outcome = run(
+            restore_seed(&world, MinimumIntervals::default()),
+            &failsafe_config(FailsafePolicy::Off),
+            world,
+            RecordingNotifier(events.clone()),
+            commands,
+            Supervisor::new(LoopProgress::default()),
+            &Shutdown::default().with_cycle_limit(Some(3)),
+        );
+        assert_eq!(RunOutcome::CycleLimitReached { cycles: 3 }, outcome);
+        assert_eq!(
+            vec![
+                Event::SensorFailsafeEngaged {
+                    failing_for: Duration::from_secs(300),
+                    policy: FailsafePolicy::Off,
+                    cause: SensorErrorKind::Unreadable
+                },
+                Event::SensorRecovered {
+                    outage: Duration::from_secs(600),
+                    errors: error_counts(SensorErrorKind::Unreadable, 60)
+                }
+            ],
+            *events.borrow()
+        );
+    }
+
+    /// Runs the demo with a `[store]` table against it.
+    fn run_with_store_faults(store: &str, cycles: u32) -> RunOutcome {
+        let parameters = DemoParameters {
+            scenario: format!("[store]\n{}", store).parse().unwrap(),
+            ..Default::default()
+        };
+        let world = DemoWorld::new(parameters).unwrap();
+        let (_, commands) = channel();
+        run(
+            restore_seed(&world, MinimumIntervals::default()),
+            &Config::default(),
+            world,
+            LogNotifier,
+            commands,
+            Supervisor::new(LoopProgress::default()),
+            &Shutdown::default().with_cycle_limit(Some(cycles)),
+        )
+    }
 
     #[test]
-    fn compensate_default() {
-        let compensator = Compensator::new(40.0, 0.0, -3.0);
-        assert_eq!(0.0, compensator.get_compensation());
+    fn times_each_phase_and_counts_the_iterations_that_overran() {
+        // A sensor that takes 750ms and a card that stalls every write for 6s.
+        let world = TestWorld::builder()
+            .readings([6.0; 5])
+            .readings([12.0; 3])
+            .slow_reads(Duration::from_millis(750))
+            .slow_writes(Duration::from_secs(6))
+            .build();
+        let board = StatusBoard::default();
+        let supervisor = Supervisor::new(LoopProgress::default()).with_board(board.clone());
+        let outcome = catch_unwind(AssertUnwindSafe(|| {
+            let (_, commands) = channel();
+            run(
+                Seed::new(State::Off),
+                &Config::default(),
+                world,
+                LogNotifier,
+                commands,
+                supervisor,
+                &Shutdown::default(),
+            )
+        }));
+        assert!(outcome.is_err());
+        let ms = Duration::from_millis;
+        let latency = |p50, p95, max| loop_timing::Latency {
+            p50: ms(p50),
+            p95: ms(p95),
+            max: ms(max),
+        };
+        // Published with the last reading, up to the iteration before. Turning on wrote the starts besides the
+        // heartbeat and took longer than the poll interval.
+        assert_eq!(
+            loop_timing::LoopLatency {
+                sensor: latency(750, 750, 750),
+                persist: latency(6000, 12_000, 12_000),
+                report: latency(0, 0, 0),
+                iteration: latency(6750, 12_750, 12_750),
+                overruns: 1,
+            },
+            board.snapshot().unwrap().loop_latency.unwrap()
+        );
     }
 
     #[test]
-    fn heat_compensate_one_exact_measure() {
-        let mut compensator = Compensator::new(40.0, 0.0, -3.0);
-        compensator.push_observation(40.0);
-        assert_eq!(0.0, compensator.get_compensation());
-        assert_eq!(40.0, compensator.get_threshold());
+    fn requested_dump_is_written_from_the_control_loop() {
+        let dir = TempDir::new();
+        let world = DemoWorld::new(DemoParameters::default()).unwrap();
+        let (_, commands) = channel();
+        let dumps = DumpRequest::default();
+        dumps.request();
+        let supervisor = Supervisor::new(LoopProgress::default())
+            .with_board(StatusBoard::default())
+            .with_dumps(dumps.clone(), Some(dir.path().to_path_buf()));
+        run(
+            restore_seed(&world, MinimumIntervals::default()),
+            &Config::default(),
+            world,
+            LogNotifier,
+            commands,
+            supervisor,
+            &Shutdown::default().with_cycle_limit(Some(1)),
+        );
+        assert!(!dumps.take());
+        let written: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(1, written.len());
+        let dump = fs::read_to_string(&written[0]).unwrap();
+        for key in [
+            "\nhistory ",
+            "\nstate_for ",
+            "\nlow_compensator applied ",
+            "\nhigh_compensator applied ",
+            "\ntransitions 0",
+            "\nconfig Config { ",
+        ] {
+            assert!(dump.contains(key), "{} missing from {}", key, dump);
+        }
+        // The status part reads back like the control socket's.
+        assert!(dump.parse::<status_board::Snapshot>().is_ok());
     }
 
     #[test]
-    fn heat_compensate_one_high_measure() {
-        let mut compensator = Compensator::new(40.0, 0.0, -3.0);
-        compensator.push_observation(41.0);
-        assert_eq!(-1.0, compensator.get_compensation());
-        assert_eq!(39.0, compensator.get_threshold());
+    fn streams_each_status_and_what_happened_to_a_websocket_client() {
+        let mut runtime = Runtime::default();
+        let server = WsServer::bind("127.0.0.1:0", &mut runtime).unwrap();
+        let mut client = WsClient::connect(server.local_addr());
+        // Warms up enough to start, then the sensor fails for long enough to engage the failsafe, and recovers.
+        let mut readings = vec![Some(2.0), Some(6.0)];
+        readings.extend(std::iter::repeat_n(None, 31));
+        readings.push(Some(6.0));
+        let world = TestWorld::builder().readings(readings.clone()).build();
+        let recording = world.recording();
+        let supervisor = Supervisor::new(LoopProgress::default()).with_stream(server.stream());
+        let events = run_to_end_with(
+            world,
+            Seed::new(State::Off),
+            &failsafe_config(FailsafePolicy::Off),
+            supervisor,
+        );
+        recording.assert_transitions(&[(20, On), (330, Off)]);
+        let stopping = std::thread::spawn(move || server.stop());
+        let (messages, code) = client.messages_until_close();
+        stopping.join().unwrap();
+        assert_eq!(1001, code);
+        let (statuses, happened): (Vec<_>, Vec<_>) = messages
+            .iter()
+            .partition(|message| message.starts_with("{\"type\":\"status\","));
+        assert_eq!(readings.len(), statuses.len());
+        assert!(statuses[1]
+            .contains("\"state\":\"MinimumIntervalOn\",\"shadow\":false,\"temperature\":6.000,\"relay\":true,"));
+        assert!(statuses[2].contains("\"temperature\":null,"), "{}", statuses[2]);
+        assert_eq!(
+            vec![
+                "{\"type\":\"transition\",\"from\":\"Off\",\"to\":\"MinimumIntervalOn\",\"reason\":\"threshold\",\"temperature\":6.000}",
+                "{\"type\":\"transition\",\"from\":\"MinimumIntervalOn\",\"to\":\"Fault\",\"reason\":\"sensor-failsafe\",\"temperature\":null}",
+                "{\"type\":\"fault\",\"message\":\"Sensor failing (unreadable) for 300s, failsafe engaged with policy off.\"}",
+                "{\"type\":\"event\",\"message\":\"Sensor recovered after a 310s outage (31 unreadable).\"}",
+                "{\"type\":\"transition\",\"from\":\"Fault\",\"to\":\"MinimumIntervalOff\",\"reason\":\"sensor-recovered\",\"temperature\":6.000}",
+            ],
+            happened
+        );
+        assert_eq!(2, events.len());
     }
 
     #[test]
-    fn heat_compensate_two_high_measure() {
-        let mut compensator = Compensator::new(40.0, 0.0, -3.0);
-        compensator.push_observation(42.0);
-        assert_eq!(-2.0, compensator.get_compensation());
-        assert_eq!(38.0, compensator.get_threshold());
-        compensator.push_observation(40.0);
-        assert_eq!(-2.0, compensator.get_compensation());
-        assert_eq!(38.0, compensator.get_threshold());
+    fn backed_up_network_backends_leave_the_control_loop_on_time() {
+        let readings: Vec<_> = (0..2000).map(|i| Some(if i % 200 < 100 { 9.0 } else { 2.0 })).collect();
+        let run = |supervisor| {
+            let world = TestWorld::builder().readings(readings.clone()).build();
+            let recording = world.recording();
+            let started = Instant::now();
+            run_to_end_with(world, Seed::new(State::Off), &Config::default(), supervisor);
+            (recording.transitions(), started.elapsed())
+        };
+        let (expected, alone) = run(Supervisor::new(LoopProgress::default()));
+
+        // On one thread: a client that never reads, one pinging as fast as it can and a Pushgateway that never
+        // answers.
+        let mut runtime = Runtime::shared();
+        let server = WsServer::bind("127.0.0.1:0", &mut runtime).unwrap();
+        let _stuck = WsClient::connect(server.local_addr());
+        let mut pinging = WsClient::connect(server.local_addr());
+        let pinged = Arc::new(AtomicBool::new(false));
+        let pinger = {
+            let pinged = pinged.clone();
+            std::thread::spawn(move || {
+                while !pinged.load(Ordering::Relaxed) {
+                    pinging.send(&Frame::Ping(vec![0; 100]));
+                }
+            })
+        };
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let gateway = format!("http://{}", silent.local_addr().unwrap()).parse().unwrap();
+        let board = StatusBoard::default();
+        let pusher = Pusher::spawn(
+            Pushgateway::new(&gateway, "busy", None).unwrap(),
+            board.clone(),
+            Duration::ZERO,
+            &mut runtime,
+        )
+        .unwrap();
+        let supervisor = Supervisor::new(LoopProgress::default())
+            .with_board(board)
+            .with_stream(server.stream());
+        let (transitions, busy) = run(supervisor);
+        pinged.store(true, Ordering::Relaxed);
+
+        assert_eq!(expected, transitions);
+        assert!(
+            busy < alone * 3 + Duration::from_secs(1),
+            "{:?} against {:?}",
+            busy,
+            alone
+        );
+        pusher.stop(false);
+        server.stop();
+        let _ = pinger.join();
     }
 
     #[test]
-    fn heat_compensate_one_high_measure_capped() {
-        let mut compensator = Compensator::new(40.0, 0.0, -0.5);
-        compensator.push_observation(41.0);
-        assert_eq!(-0.5, compensator.get_compensation());
-        assert_eq!(39.5, compensator.get_threshold());
-        assert_eq!(true, compensator.is_capped());
+    fn intermittent_persist_failures_only_warn() {
+        assert_eq!(
+            RunOutcome::CycleLimitReached { cycles: 10 },
+            run_with_store_faults("persist_failures = 0.3", 10)
+        );
     }
 
     #[test]
-    fn heat_compensate_one_inverted_measure() {
-        let mut compensator = Compensator::new(40.0, 0.0, -0.5);
-        compensator.push_observation(39.5);
-        assert_eq!(0.0, compensator.get_compensation());
-        assert_eq!(40.0, compensator.get_threshold());
-        assert_eq!(false, compensator.is_capped());
+    fn persist_failures_terminate() {
+        // The writes in the poll that crossed the limit and the one on the way out count too.
+        assert!(matches!(
+            run_with_store_faults("persist_failures = \"always\"", 10),
+            RunOutcome::Fatal(Termination::PersistenceUnusable {
+                failures,
+                cause: PersistErrorKind::Io
+            }) if failures >= 5
+        ));
+        assert!(matches!(
+            run_with_store_faults("persist_failures = \"after 4\"\npersist_error = \"storage full\"", 10),
+            RunOutcome::Fatal(Termination::PersistenceUnusable {
+                cause: PersistErrorKind::StorageFull,
+                ..
+            })
+        ));
     }
 
     #[test]
-    fn heat_compensate_one_high_measure_adjust() {
-        let mut compensator = Compensator::new(40.0, -1.0, -3.0);
-        compensator.push_observation(40.5);
-        assert_eq!(-1.5, compensator.get_compensation());
-        assert_eq!(38.5, compensator.get_threshold());
+    fn damaged_restore_starts_safely() {
+        let world = |store: &str| {
+            let parameters = DemoParameters {
+                scenario: format!("[store]\n{}", store).parse().unwrap(),
+                ..Default::default()
+            };
+            DemoWorld::new(parameters).unwrap()
+        };
+        let corrupt = world("corrupt_restore = true");
+        let seed = restore_seed(&corrupt, MinimumIntervals::default());
+        assert!(seed.state == State::MinimumIntervalOff(corrupt.now()));
+        assert_eq!((DegreesDelta::ZERO, DegreesDelta::ZERO), seed.compensation);
+        assert!(seed.cooling_rates.is_empty());
+        // A stale record lets the compressor start straight away, only the switch readback guards against it.
+        let stale = world("stale_restore = \"1d\"");
+        assert!(restore_seed(&stale, MinimumIntervals::default()).state == State::InitiallyOff);
+        assert_eq!(
+            RunOutcome::CycleLimitReached { cycles: 3 },
+            run_with_store_faults("corrupt_restore = true", 3)
+        );
     }
 
     #[test]
-    fn heat_compensate_one_low_measure_adjust() {
-        let mut compensator = Compensator::new(40.0, -3.0, -3.0);
-        compensator.push_observation(39.0);
-        assert_eq!(-2.0, compensator.get_compensation());
-        assert_eq!(38.0, compensator.get_threshold());
+    fn a_start_forced_off_turns_off_a_relay_left_on() {
+        let left_on = || TestWorld::builder().restored(RestoredPowerState::CurrentlyOn).build();
+        let mut world = left_on();
+        let seed = restore_seed_with(&world, MinimumIntervals::default(), StartMode::OffLocked);
+        assert!(seed.state == State::MinimumIntervalOff(world.now()));
+        turn_off_left_on(&mut world, &seed);
+        world.recording().assert_transitions(&[(0, Off)]);
+        assert_eq!(vec![0], world.recording().persisted_off());
+        // Following the relay, or the previous run, keeps it on.
+        for mode in [StartMode::Auto, StartMode::FollowRelay] {
+            let mut world = left_on();
+            let seed = restore_seed_with(&world, MinimumIntervals::default(), mode);
+            turn_off_left_on(&mut world, &seed);
+            world.recording().assert_transitions(&[]);
+        }
+        let mut world = TestWorld::builder().build();
+        let seed = restore_seed_with(&world, MinimumIntervals::default(), StartMode::OffFree);
+        assert!(seed.state == State::InitiallyOff);
+        turn_off_left_on(&mut world, &seed);
+        assert!(world.recording().persisted_off().is_empty());
     }
 
     #[test]
-    fn cool_compensate_one_low_measure() {
-        let mut compensator = Compensator::new(33.0, 0.0, 3.0);
-        compensator.push_observation(32.0);
-        assert_eq!(1.0, compensator.get_compensation());
-        assert_eq!(34.0, compensator.get_threshold());
+    fn restored_state_seeds_the_controller() {
+        let seeded = |world: TestWorld| (restore_seed(&world, MinimumIntervals::default()), world.now());
+        let (on, now) = seeded(TestWorld::builder().restored(RestoredPowerState::CurrentlyOn).build());
+        assert!(on.state == State::MinimumIntervalOn(now));
+        let recently = Duration::from_secs(2 * 60);
+        let (off, now) = seeded(
+            TestWorld::builder()
+                .restored(RestoredPowerState::OffFor(recently))
+                .restored_compensation(DegreesDelta(0.5), DegreesDelta(-0.25))
+                .restored_cooling_rates(vec![3.0])
+                .build(),
+        );
+        assert!(off.state == State::MinimumIntervalOff(now - recently));
+        assert_eq!((DegreesDelta(0.5), DegreesDelta(-0.25)), off.compensation);
+        assert_eq!(vec![3.0], off.cooling_rates);
+        let long_ago = RestoredPowerState::OffFor(Duration::from_secs(60 * 60));
+        let (long_off, _) = seeded(TestWorld::builder().restored(long_ago).build());
+        assert!(long_off.state == State::InitiallyOff);
+        let (failed, now) = seeded(
+            TestWorld::builder()
+                .restore_fails("Failed parsing stored last off transition.")
+                .restored_compensation(DegreesDelta(0.5), DegreesDelta::ZERO)
+                .build(),
+        );
+        assert!(failed.state == State::MinimumIntervalOff(now));
+        assert_eq!((DegreesDelta::ZERO, DegreesDelta::ZERO), failed.compensation);
+    }
+
+    /// Restarts with `world`'s restore results and runs its script, the relay changes.
+    fn restart(world: TestWorldBuilder) -> Recording {
+        let world = world.build();
+        let recording = world.recording();
+        let seed = restore_seed(&world, MinimumIntervals::default());
+        run_to_end(world, seed, &Config::default());
+        recording
     }
 
     #[test]
-    fn cool_compensate_two_low_measure() {
-        let mut compensator = Compensator::new(33.0, 0.0, 3.0);
-        compensator.push_observation(32.0);
-        assert_eq!(1.0, compensator.get_compensation());
-        assert_eq!(34.0, compensator.get_threshold());
-        compensator.push_observation(33.0);
-        assert_eq!(1.0, compensator.get_compensation());
-        assert_eq!(34.0, compensator.get_threshold());
+    fn restart_into_warm_fridge_holds_restored_off_interval() {
+        let warm = || TestWorld::builder().readings(vec![6.0; 60]);
+        // Off for 2 of the 8 minimum minutes before the restart.
+        restart(warm().restored(RestoredPowerState::OffFor(Duration::from_secs(2 * 60))))
+            .assert_transitions(&[(360, On)]);
+        restart(warm().restored(RestoredPowerState::OffFor(Duration::from_secs(60 * 60))))
+            .assert_transitions(&[(0, On)]);
+        restart(warm().restored(RestoredPowerState::OffForUnknownDuration)).assert_transitions(&[(480, On)]);
+        restart(warm().restore_fails("Failed parsing stored last off transition.")).assert_transitions(&[(480, On)]);
     }
 
     #[test]
-    fn cool_compensate_five_low_measure_adjust() {
-        let mut compensator = Compensator::new(33.0, 0.0, 3.0);
-        // Start 1 true swing
-        compensator.push_observation(32.0);
-        assert_eq!(1.0, compensator.get_compensation());
-        assert_eq!(34.0, compensator.get_threshold());
-        compensator.push_observation(33.0);
-        assert_eq!(1.0, compensator.get_compensation());
-        assert_eq!(34.0, compensator.get_threshold());
-        // Swing changes to 1.5
-        compensator.push_observation(32.5);
-        assert_eq!(1.0, compensator.get_compensation());
-        assert_eq!(34.0, compensator.get_threshold());
-        compensator.push_observation(32.5);
-        assert_eq!(1.25, compensator.get_compensation());
-        assert_eq!(34.25, compensator.get_threshold());
-        compensator.push_observation(32.75);
-        assert_eq!(1.5, compensator.get_compensation());
-        assert_eq!(34.5, compensator.get_threshold());
+    fn restart_into_cold_fridge_holds_restored_on_interval() {
+        let cold = TestWorld::builder()
+            .readings(vec![0.0; 60])
+            .restored(RestoredPowerState::CurrentlyOn);
+        restart(cold).assert_transitions(&[(120, Off)]);
     }
 
     #[test]
-    fn cool_compensate_one_low_measure_capped() {
-        let mut compensator = Compensator::new(33.0, 0.0, 0.5);
-        compensator.push_observation(32.0);
-        assert_eq!(0.5, compensator.get_compensation());
-        assert_eq!(33.5, compensator.get_threshold());
-        assert_eq!(true, compensator.is_capped());
+    fn runtime_accumulates_across_restarts() {
+        let long_off = RestoredPowerState::OffFor(Duration::from_secs(60 * 60));
+        let first = restart(TestWorld::builder().readings(cycling(2)).restored(long_off));
+        first.assert_transitions(&[(0, On), (300, Off), (900, On), (1200, Off)]);
+        let secs = Duration::from_secs;
+        assert_eq!(vec![(300, secs(300)), (1200, secs(600))], first.runtime());
+
+        // Shut down 100s into the next run's first on interval.
+        let shutdown = Shutdown::default();
+        let world = TestWorld::builder()
+            .readings(cycling(1))
+            .restored(long_off)
+            .restored_runtime(first.runtime().last().unwrap().1)
+            .shutdown_at(secs(100), shutdown.clone())
+            .build();
+        let second = world.recording();
+        let seed = restore_seed(&world, MinimumIntervals::default());
+        run_to_outcome(world, seed, &Config::default(), &shutdown);
+        assert_eq!(vec![(100, secs(700))], second.runtime());
     }
 
     #[test]
-    fn cool_compensate_one_inverted_measure() {
-        let mut compensator = Compensator::new(33.0, 0.0, 0.5);
-        compensator.push_observation(33.5);
-        assert_eq!(0.0, compensator.get_compensation());
-        assert_eq!(33.0, compensator.get_threshold());
-        assert_eq!(false, compensator.is_capped());
+    fn restart_holds_tuned_intervals_only_while_applying_them() {
+        let tuned = MinimumIntervals {
+            on: Duration::from_secs(5 * 60),
+            off: Duration::from_secs(15 * 60),
+        };
+        let first_on = |auto_apply| {
+            let world = TestWorld::builder()
+                .readings(vec![6.0; 100])
+                .restored_intervals(tuned)
+                .build();
+            let recording = world.recording();
+            let mut config = Config::default();
+            config.timing.cycles = Some(10);
+            config.timing.auto_apply = auto_apply;
+            let seed = restore_seed(&world, config.intervals);
+            run_to_end(world, seed, &config);
+            recording.transitions()
+        };
+        assert_eq!(vec![(900, On)], first_on(true));
+        assert_eq!(vec![(480, On)], first_on(false));
+    }
+
+    /// The timing advice the demo chamber gets over `cycles` cycles from `intervals`.
+    fn advise_demo(intervals: MinimumIntervals, cycles: u32) -> Vec<TimingAdvice> {
+        let world = DemoWorld::new(DemoParameters::default()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut config = Config {
+            intervals,
+            ..Config::default()
+        };
+        config.timing.cycles = Some(5);
+        config.timing.auto_apply = true;
+        let (_, commands) = channel();
+        run(
+            restore_seed(&world, intervals),
+            &config,
+            world,
+            RecordingNotifier(events.clone()),
+            commands,
+            Supervisor::new(LoopProgress::default()),
+            &Shutdown::default().with_cycle_limit(Some(cycles)),
+        );
+        let advice = events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                Event::TimingAdvice { advice, applied: true } => Some(*advice),
+                _ => None,
+            })
+            .collect();
+        advice
     }
 
     #[test]
-    fn cool_compensate_one_high_measure_adjust() {
-        let mut compensator = Compensator::new(33.0, 3.0, 3.0);
-        compensator.push_observation(33.5);
-        assert_eq!(2.5, compensator.get_compensation());
-        assert_eq!(35.5, compensator.get_threshold());
+    fn timing_advisor_converges_on_the_demo_chamber() {
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        for start in [
+            MinimumIntervals::default(),
+            MinimumIntervals {
+                on: minutes(25),
+                off: minutes(30),
+            },
+        ] {
+            // Eight rounds, the advisor settles within two and has nothing more to say after.
+            let advice = advise_demo(start, 40);
+            assert!((1..=2).contains(&advice.len()), "{:#?}", advice);
+            let last = advice.last().unwrap();
+            assert_eq!(
+                MinimumIntervals {
+                    on: minutes(12),
+                    off: minutes(14),
+                },
+                last.recommended
+            );
+            // Under the natural phases, so only a short cycle ever meets them.
+            assert!(last.on.median.unwrap() > last.recommended.on);
+            assert!(last.off.median.unwrap() > last.recommended.off);
+            assert_eq!(0, last.on.bound + last.off.bound);
+        }
+    }
+
+    #[test]
+    fn restart_mid_boost_holds_the_boost_band_until_it_runs_out() {
+        let world = TestWorld::builder()
+            .readings(vec![2.5; 130])
+            .restored_boost(Boost {
+                band: Celsius(1.0)..Celsius(2.0),
+                remaining: Duration::from_secs(20 * 60),
+            })
+            .build();
+        let recording = world.recording();
+        let seed = restore_seed(&world, MinimumIntervals::default());
+        let events = run_to_end(world, seed, &Config::default());
+        // Too warm for the boost band once the minimum off time is up, not for the configured one.
+        recording.assert_transitions(&[(480, On)]);
+        assert_eq!(vec![(1200, None)], recording.boosts());
+        assert_eq!(
+            vec![Event::BoostEnded {
+                band: picool_core::TARGET_RANGE,
+                cancelled: false
+            }],
+            events
+        );
+    }
+
+    #[test]
+    fn restart_mid_rest_holds_the_relay_off_until_it_runs_out() {
+        let world = TestWorld::builder()
+            .readings(vec![6.0; 130])
+            .restored(RestoredPowerState::OffFor(Duration::from_secs(60 * 60)))
+            .restored_rest(RestPeriod {
+                run: Duration::from_secs(3 * 60 * 60),
+                remaining: Duration::from_secs(20 * 60),
+            })
+            .build();
+        let recording = world.recording();
+        let seed = restore_seed(&world, MinimumIntervals::default());
+        let mut config = Config::default();
+        config.rest.after = Some(Duration::from_secs(2 * 60 * 60));
+        run_to_end(world, seed, &config);
+        // Warm from the start, the minimum off time long over.
+        recording.assert_transitions(&[(1200, On)]);
+        assert_eq!(vec![(1200, None)], recording.rests());
+    }
+
+    #[test]
+    fn restart_mid_shed_holds_the_relay_off_until_it_runs_out() {
+        let restart = |asserted: bool| {
+            let world = TestWorld::builder()
+                .readings(vec![6.0; 130])
+                .restored(RestoredPowerState::OffFor(Duration::from_secs(60 * 60)))
+                .restored_shed(ShedPeriod {
+                    since: Duration::from_secs(30 * 60),
+                    remaining: Duration::from_secs(20 * 60),
+                })
+                .build();
+            let recording = world.recording();
+            let seed = restore_seed(&world, MinimumIntervals::default());
+            let dir = TempDir::new();
+            let path = dir.path().join("shed");
+            if asserted {
+                fs::write(&path, "").unwrap();
+            }
+            let supervisor = Supervisor::new(LoopProgress::default()).with_shed(ShedSignal::File(path));
+            let events = run_to_end_with(world, seed, &Config::default(), supervisor);
+            (recording, events)
+        };
+        let (held, events) = restart(true);
+        held.assert_transitions(&[(1200, On)]);
+        assert_eq!(
+            vec![(
+                1200,
+                Some(ShedPeriod {
+                    since: Duration::from_secs(50 * 60),
+                    remaining: Duration::ZERO,
+                })
+            )],
+            held.sheds()
+        );
+        assert!(events.contains(&Event::ShedEnded {
+            lasted: Duration::from_secs(50 * 60),
+            end: ShedEnd::TimedOut,
+        }));
+        // Released while it was down.
+        let (released, _) = restart(false);
+        released.assert_transitions(&[(0, On)]);
+        assert_eq!(vec![(0, None)], released.sheds());
+    }
+
+    #[test]
+    fn restart_mid_off_cycle_learns_from_the_checkpointed_extremes() {
+        let restart = |off_for: u64| {
+            let world = TestWorld::builder()
+                .readings(vec![6.0; 20])
+                .restored(RestoredPowerState::OffFor(Duration::from_secs(off_for * 60)))
+                .restored_extremes(CycleExtremes {
+                    on: false,
+                    min: Celsius(-1.0),
+                    max: Celsius(4.0),
+                    running: Duration::from_secs(20 * 60),
+                    age: Duration::from_secs(2 * 60),
+                })
+                .build();
+            let recording = world.recording();
+            let seed = restore_seed(&world, MinimumIntervals::default());
+            run_to_end(world, seed, &Config::default());
+            recording
+        };
+        let resumed = restart(20);
+        resumed.assert_transitions(&[(0, On)]);
+        // The bottom from before the restart, as the relay came on.
+        assert_eq!(
+            Some(&(DegreesDelta(1.556), DegreesDelta::ZERO)),
+            resumed.compensations().first()
+        );
+        assert_eq!(vec![(0, None)], resumed.extremes());
+        // The relay went off again since the checkpoint, a different off cycle.
+        let later = restart(5);
+        assert!(later.compensations().is_empty());
+        assert!(later.extremes().is_empty());
+    }
+
+    #[test]
+    fn daily_starts_flag_an_increase_against_restored_history() {
+        // A month at 20 starts a day, then six days at 40.
+        let history = [vec![Some(20); 28], vec![Some(40); 6]].concat();
+        let world = TestWorld::builder()
+            .readings(cycling(6))
+            .restored(RestoredPowerState::OffFor(Duration::from_secs(60 * 60)))
+            .starting_at(LocalTime::from_local_seconds(100 * 24 * 60 * 60 - 60 * 60))
+            .restored_starts(StartCounts {
+                lifetime: 1000,
+                day: 99,
+                today: 30,
+                history,
+            })
+            .build();
+        let recording = world.recording();
+        let seed = restore_seed(&world, MinimumIntervals::default());
+        let events = run_to_end(world, seed, &Config::default());
+        // 15 minute cycles, four of them before midnight.
+        let daily: Vec<DailyStarts> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::DailyStarts(daily) => Some(*daily),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(1, daily.len());
+        assert_eq!(34, daily[0].starts);
+        let trend = daily[0].trend.unwrap();
+        assert!((trend.week - (6.0 * 40.0 + 34.0) / 7.0).abs() < 1e-4);
+        assert!(trend.is_significant_increase());
+        let last = recording.starts().pop().unwrap();
+        assert_eq!((1006, 100, 2), (last.lifetime, last.day, last.today));
+        assert_eq!(Some(&Some(34)), last.history.last());
+    }
+
+    #[test]
+    fn energy_accumulates_across_days_from_restored_totals() {
+        let config = Config {
+            energy: EnergyConfig {
+                compressor_watts: Some(100.0),
+                standby_watts: 0.0,
+                price_per_kwh: Some(0.2),
+            },
+            ..Config::default()
+        };
+        // 15 minute cycles, 5 of them on, for two days from an hour before midnight.
+        let before_midnight = LocalTime::from_local_seconds(60 * 60 * 23);
+        let world = TestWorld::builder()
+            .readings(cycling(2 * 24 * 4))
+            .starting_at(before_midnight)
+            .restored_energy(EnergyTotals {
+                day: 0,
+                today: 0.5,
+                yesterday: 0.0,
+                lifetime: 10.0,
+            })
+            .build();
+        let recording = world.recording();
+        let seed = restore_seed(&world, config.intervals);
+        let events = run_to_end(world, seed, &config);
+        let daily: Vec<DailyEnergy> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::DailyEnergy(daily) => Some(*daily),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(2, daily.len());
+        // Four of the hour's cycles before the first midnight, on for a third of each.
+        let close = |expected: f64, actual: f64| (expected - actual).abs() < 0.01;
+        assert!(close(0.5 + 0.1 / 3.0, daily[0].kwh), "{:?}", daily);
+        assert!(close(2.4 / 3.0, daily[1].kwh), "{:?}", daily);
+        assert!(close(daily[1].kwh * 0.2, daily[1].cost.unwrap()));
+        let last = *recording.energy().last().unwrap();
+        assert_eq!(2, last.day);
+        assert!(close(daily[1].kwh, last.yesterday));
+        assert!(
+            close(10.0 - 0.5 + daily[0].kwh + daily[1].kwh + last.today, last.lifetime),
+            "{:?}",
+            last
+        );
+    }
+
+    #[test]
+    fn short_outage_does_not_engage_failsafe() {
+        let (recording, events) = run_script(State::On, outage(20), failsafe_config(FailsafePolicy::Off));
+        recording.assert_transitions(&[]);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn fan_stirs_while_idle_and_stops_on_shutdown() {
+        use picool_core::fan::FanConfig;
+        let config = Config {
+            fan: FanConfig {
+                enabled: true,
+                stir_every: Some(Duration::from_secs(30 * 60)),
+                ..FanConfig::default()
+            },
+            ..Config::default()
+        };
+        // Idle in the band, then warm enough to start the compressor, shutting down during the third stir.
+        let shutdown = Shutdown::default();
+        let mut readings = vec![2.0; 6 * 45];
+        readings.extend(vec![6.0; 6 * 5]);
+        readings.extend(vec![0.0; 6]);
+        readings.extend(vec![2.0; 6 * 60]);
+        let world = TestWorld::builder()
+            .readings(readings)
+            .shutdown_at(Duration::from_secs(6500), shutdown.clone())
+            .build();
+        let recording = world.recording();
+        run_to_outcome(world, Seed::new(State::Off), &config, &shutdown);
+        recording.assert_transitions(&[(2710, On), (3010, Off)]);
+        // With the compressor, and for two minutes of every idle half hour counted from when the fan last stopped.
+        assert_eq!(
+            vec![
+                (1680, On),
+                (1800, Off),
+                (2710, On),
+                (3010, Off),
+                (4690, On),
+                (4810, Off),
+                (6490, On),
+                (6500, Off)
+            ],
+            recording.fan()
+        );
+    }
+
+    #[test]
+    fn stage2_joins_far_above_the_band_and_stops_on_shutdown() {
+        use picool_core::staging::StagingConfig;
+        let config = Config {
+            staging: StagingConfig {
+                enabled: true,
+                ..StagingConfig::default()
+            },
+            ..Config::default()
+        };
+        // Well past the high threshold, then in the band, then warm again, shutting down with both running.
+        let shutdown = Shutdown::default();
+        let mut readings = vec![7.0; 6 * 10];
+        readings.extend(vec![2.5; 6 * 10]);
+        readings.extend(vec![7.0; 6 * 10]);
+        let world = TestWorld::builder()
+            .readings(readings)
+            .shutdown_at(Duration::from_secs(1300), shutdown.clone())
+            .build();
+        let recording = world.recording();
+        run_to_outcome(world, Seed::new(State::Off), &config, &shutdown);
+        recording.assert_transitions(&[(10, On), (1300, Off)]);
+        // After its own minimum off interval from the start, dropping out well ahead of stage 1.
+        assert_eq!(vec![(480, On), (610, Off), (1210, On), (1300, Off)], recording.stage2());
+    }
+
+    fn pump_config() -> Config {
+        use picool_core::pump::PumpConfig;
+        Config {
+            pump: PumpConfig {
+                enabled: true,
+                ..PumpConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn pump_leads_the_compressor_and_overruns_it() {
+        let mut readings = vec![7.0; 6 * 5];
+        readings.extend(vec![0.0; 6 * 10]);
+        let world = TestWorld::builder().readings(readings).build();
+        let recording = world.recording();
+        run_to_end(world, Seed::new(State::Off), &pump_config());
+        // The start waits out the lead, the stop holds the pump for the overrun.
+        recording.assert_transitions(&[(40, On), (310, Off)]);
+        assert_eq!(vec![(10, On), (430, Off)], recording.pump());
+    }
+
+    #[test]
+    fn pump_interlock_holds_the_compressor_off_until_it_reads_back() {
+        let world = TestWorld::builder()
+            .readings(vec![7.0; 6 * 5])
+            .pump_fails(Duration::from_secs(0)..Duration::from_secs(100))
+            .build();
+        let recording = world.recording();
+        let events = run_to_end(world, Seed::new(State::Off), &pump_config());
+        recording.assert_transitions(&[(100, On)]);
+        assert_eq!(vec![(10, On)], recording.pump());
+        assert_eq!(
+            vec![Event::PumpFailed { commanded: true }, Event::PumpRecovered],
+            events
+        );
+    }
+
+    fn current_config() -> Config {
+        use picool_core::current::CurrentConfig;
+        Config {
+            current: CurrentConfig {
+                enabled: true,
+                ..CurrentConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn a_compressor_drawing_its_load_raises_nothing() {
+        let world = TestWorld::builder()
+            .readings(cycling(2))
+            .load(Load::Follows(2.5))
+            .build();
+        let recording = world.recording();
+        let events = run_to_end(world, Seed::new(State::Off), &current_config());
+        recording.assert_transitions(&[(10, On), (310, Off), (910, On), (1210, Off)]);
+        assert_eq!(Vec::<Event>::new(), events);
+    }
+
+    #[test]
+    fn records_each_cycle_in_the_history() {
+        let world = TestWorld::builder().readings(cycling(3)).build();
+        let recording = world.recording();
+        run_to_end(world, Seed::new(State::Off), &Config::default());
+        let cycles: Vec<(u64, u64, Option<u64>, Option<String>)> = recording
+            .cycles()
+            .into_iter()
+            .map(|(at, cycle)| {
+                (
+                    at,
+                    cycle.on.as_secs(),
+                    cycle.off.map(|off| off.as_secs()),
+                    cycle.abnormal,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (310, 300, None, Some("first cycles".to_string())),
+                (1210, 300, Some(600), None),
+                (2110, 300, Some(600), None),
+            ],
+            cycles
+        );
+    }
+
+    #[test]
+    fn a_compressor_that_doesnt_start_raises_no_load() {
+        let world = TestWorld::builder().readings(cycling(1)).load(Load::Absent).build();
+        let events = run_to_end(world, Seed::new(State::Off), &current_config());
+        assert_eq!(
+            vec![
+                Event::LoadFault {
+                    fault: LoadFault::NoLoad,
+                    amps: 0.0
+                },
+                Event::LoadFaultCleared {
+                    fault: LoadFault::NoLoad
+                }
+            ],
+            events
+        );
+    }
+
+    #[test]
+    fn a_welded_relay_raises_unexpected_load() {
+        let world = TestWorld::builder().readings(cycling(1)).load(Load::Stuck(2.5)).build();
+        let events = run_to_end(world, Seed::new(State::Off), &current_config());
+        assert_eq!(
+            vec![Event::LoadFault {
+                fault: LoadFault::Unexpected,
+                amps: 2.5
+            }],
+            events
+        );
+    }
+
+    fn failover_config() -> Config {
+        use picool_core::failover::FailoverConfig;
+        Config {
+            failover: FailoverConfig {
+                enabled: true,
+                ..FailoverConfig::default()
+            },
+            ..current_config()
+        }
+    }
+
+    #[test]
+    fn fails_over_when_the_primary_ignores_its_commands() {
+        let world = TestWorld::builder()
+            .readings(cycling(2))
+            .load(Load::Follows(2.5))
+            .primary_fails()
+            .build();
+        let recording = world.recording();
+        let events = run_to_end(world, Seed::new(State::Off), &failover_config());
+        assert_eq!(vec![(60, Output::Backup)], recording.outputs());
+        assert_eq!(vec![(60, true)], recording.failovers());
+        // The backup carries on through the next cycle.
+        recording.assert_transitions(&[(10, On), (310, Off), (910, On), (1210, Off)]);
+        assert_eq!(
+            vec![
+                Event::LoadFault {
+                    fault: LoadFault::NoLoad,
+                    amps: 0.0
+                },
+                Event::FailedOver { failures: 3 },
+                Event::LoadFaultCleared {
+                    fault: LoadFault::NoLoad
+                }
+            ],
+            events
+        );
+    }
+
+    #[test]
+    fn a_reload_applies_without_resetting_the_state() {
+        let world = TestWorld::builder().readings(vec![6.0]).readings(vec![0.0; 40]).build();
+        let recording = world.recording();
+        let mut reloaded = Config::default();
+        reloaded.intervals.on = Duration::from_secs(5 * 60);
+        let (sender, commands) = channel();
+        sender.send(Command::Reload(Box::new(reloaded))).unwrap();
+        let outcome = catch_unwind(AssertUnwindSafe(|| {
+            run(
+                Seed::new(State::Off),
+                &Config::default(),
+                world,
+                LogNotifier,
+                commands,
+                Supervisor::new(LoopProgress::default()),
+                &Shutdown::default(),
+            )
+        }));
+        assert!(outcome.is_err());
+        // On for the reloaded five minutes, not the configured two.
+        recording.assert_transitions(&[(10, On), (310, Off)]);
+    }
+
+    #[test]
+    fn a_restart_after_failing_over_starts_on_the_backup() {
+        let world = TestWorld::builder()
+            .readings(cycling(1))
+            .load(Load::Follows(2.5))
+            .primary_fails()
+            .restored_failover()
+            .build();
+        let recording = world.recording();
+        let config = failover_config();
+        let seed = restore_seed(&world, config.intervals);
+        let events = run_to_end(world, seed, &config);
+        assert_eq!(vec![(0, Output::Backup)], recording.outputs());
+        assert_eq!(Vec::<(u64, bool)>::new(), recording.failovers());
+        assert_eq!(Vec::<Event>::new(), events);
+    }
+
+    /// Runs from Off until the loop gives up.
+    fn run_to_termination(world: TestWorld, config: Config) -> Termination {
+        match run_to_outcome(world, Seed::new(State::Off), &config, &Shutdown::default()) {
+            RunOutcome::Fatal(termination) => termination,
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn shutdown_mid_cycle_turns_relay_off() {
+        let shutdown = Shutdown::default();
+        let world = TestWorld::builder()
+            .readings(cycling(2))
+            .shutdown_at(Duration::from_secs(100), shutdown.clone())
+            .build();
+        let recording = world.recording();
+        let outcome = run_to_outcome(world, Seed::new(State::Off), &Config::default(), &shutdown);
+        assert_eq!(RunOutcome::ShutdownRequested, outcome);
+        assert_eq!(0, outcome.exit_code());
+        recording.assert_transitions(&[(10, On), (100, Off)]);
+        assert_eq!(vec![100], recording.persisted_off());
+    }
+
+    #[test]
+    fn shutdown_mid_cycle_keeps_energy_since_checkpoint() {
+        let shutdown = Shutdown::default();
+        let world = TestWorld::builder()
+            .readings(cycling(2))
+            .shutdown_at(Duration::from_secs(100), shutdown.clone())
+            .build();
+        let recording = world.recording();
+        let config = Config {
+            energy: EnergyConfig {
+                compressor_watts: Some(120.0),
+                ..EnergyConfig::default()
+            },
+            ..Config::default()
+        };
+        run_to_outcome(world, Seed::new(State::Off), &config, &shutdown);
+        // On from 10s to 100s, well short of the first checkpoint.
+        let persisted = recording.energy();
+        assert_eq!(1, persisted.len());
+        assert!((persisted[0].today - 0.12 * 90.0 / 3600.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn shutdown_during_sensor_outage() {
+        let shutdown = Shutdown::default();
+        let world = TestWorld::builder()
+            .readings(outage(100))
+            .shutdown_at(Duration::from_secs(400), shutdown.clone())
+            .build();
+        let recording = world.recording();
+        let config = failsafe_config(FailsafePolicy::On);
+        let outcome = run_to_outcome(world, Seed::new(State::Off), &config, &shutdown);
+        assert_eq!(RunOutcome::ShutdownRequested, outcome);
+        recording.assert_transitions(&[(320, On), (400, Off)]);
+    }
+
+    #[test]
+    fn stops_at_cycle_limit() {
+        let shutdown = Shutdown::default().with_cycle_limit(Some(2));
+        let world = TestWorld::builder().readings(cycling(5)).build();
+        let recording = world.recording();
+        let outcome = run_to_outcome(world, Seed::new(State::Off), &Config::default(), &shutdown);
+        assert_eq!(RunOutcome::CycleLimitReached { cycles: 2 }, outcome);
+        recording.assert_transitions(&[(10, On), (310, Off), (910, On), (1210, Off)]);
+    }
+
+    #[test]
+    fn sensor_failure_terminates_after_reinitializations() {
+        let config = Config {
+            sensor_failsafe: FailsafeConfig {
+                after: Duration::from_secs(300),
+                policy: FailsafePolicy::On,
+                budget: Duration::from_secs(600),
+                reinitialize_attempts: 2,
+            },
+            ..Config::default()
+        };
+        let world = TestWorld::builder().readings(outage(1000)).build();
+        let recording = world.recording();
+        let termination = run_to_termination(world, config);
+        assert_eq!(
+            Termination::SensorFailed {
+                failing_for: Duration::from_secs(2100),
+                reinitializations: 2
+            },
+            termination
+        );
+        assert_eq!(EXIT_SENSOR, termination.exit_code());
+        // Failsafe on at engagement, forced off on the way out.
+        recording.assert_transitions(&[(320, On), (2120, Off)]);
+        assert_eq!(vec![920, 1520], recording.reinitializations());
+    }
+
+    /// Warm for 5 minutes then cold for 10, so the relay cycles and persists at each off transition.
+    fn cycling(cycles: usize) -> Vec<Option<f32>> {
+        let pattern: Vec<Option<f32>> = std::iter::repeat_n(Some(6.0), 30)
+            .chain(std::iter::repeat_n(Some(0.0), 60))
+            .collect();
+        pattern.repeat(cycles)
+    }
+
+    /// Records the calls that change the world, with seconds since the first call.
+    struct Tracing<W> {
+        inner: W,
+        start: Instant,
+        calls: Recorded<String>,
+    }
+
+    impl<W: World> Tracing<W> {
+        fn new(inner: W) -> (Self, Recorded<String>) {
+            let calls = Rc::new(RefCell::new(Vec::new()));
+            let start = inner.now();
+            (
+                Self {
+                    inner,
+                    start,
+                    calls: calls.clone(),
+                },
+                calls,
+            )
+        }
+
+        fn record(&self, call: String) {
+            let at = (self.inner.now() - self.start).as_secs();
+            self.calls.borrow_mut().push(format!("{} {}", at, call));
+        }
+    }
+
+    impl<W: World> World for Tracing<W> {
+        fn get_temperature(&self) -> Result<Celsius, SensorError> {
+            self.inner.get_temperature()
+        }
+
+        fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+            self.inner.get_ambient_temperature()
+        }
+
+        fn get_current(&self) -> Option<Result<f32, SensorError>> {
+            self.inner.get_current()
+        }
+
+        fn set_power_state(&mut self, state: bool) {
+            self.record(format!("power {}", state));
+            self.inner.set_power_state(state)
+        }
+
+        fn set_fan_state(&mut self, state: bool) {
+            self.record(format!("fan {}", state));
+            self.inner.set_fan_state(state)
+        }
+
+        fn set_stage2_state(&mut self, state: bool) {
+            self.record(format!("stage2 {}", state));
+            self.inner.set_stage2_state(state)
+        }
+
+        fn set_pump_state(&mut self, state: bool) {
+            self.record(format!("pump {}", state));
+            self.inner.set_pump_state(state)
+        }
+
+        fn pump_state(&self) -> Option<bool> {
+            self.inner.pump_state()
+        }
+
+        fn select_output(&mut self, output: Output) {
+            self.record(format!("output {}", output));
+            self.inner.select_output(output)
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.inner.sleep(duration)
+        }
+
+        fn now(&self) -> Instant {
+            self.inner.now()
+        }
+
+        fn local_time(&self) -> LocalTime {
+            self.inner.local_time()
+        }
+
+        fn reinitialize(&mut self) -> Result<(), SensorError> {
+            self.record("reinitialize".into());
+            self.inner.reinitialize()
+        }
+
+        fn reacquire_sensor(&mut self) -> bool {
+            self.inner.reacquire_sensor()
+        }
+
+        fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+            self.record(format!("heartbeat {}", status));
+            self.inner.write_heartbeat(status)
+        }
+
+        fn restore_state(&self) -> Result<WorldState> {
+            self.inner.restore_state()
+        }
+
+        fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+            self.record("last off".into());
+            self.inner.persist_last_off_transition()
+        }
+
+        fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+            self.record(format!("compensation {} {}", cooling.0, heating.0));
+            self.inner.persist_compensation(cooling, heating)
+        }
+
+        fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+            self.record(format!("cooling rates {:?}", rates));
+            self.inner.persist_cooling_rates(rates)
+        }
+
+        fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+            self.record(format!("energy {}", totals));
+            self.inner.persist_energy(totals)
+        }
+
+        fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+            self.record(format!("runtime {}", runtime.as_secs()));
+            self.inner.persist_runtime(runtime)
+        }
+
+        fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+            self.record(format!("starts {}", counts));
+            self.inner.persist_starts(counts)
+        }
+
+        fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+            self.record(format!("boost {:?}", boost));
+            self.inner.persist_boost(boost)
+        }
+
+        fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+            self.record(format!("intervals {:?}", intervals));
+            self.inner.persist_intervals(intervals)
+        }
+
+        fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+            self.record(format!("overshoots {:?}", overshoots));
+            self.inner.persist_overshoots(overshoots)
+        }
+
+        fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+            self.record(format!("rest {:?}", rest));
+            self.inner.persist_rest(rest)
+        }
+
+        fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+            self.record(format!("shed {:?}", shed));
+            self.inner.persist_shed(shed)
+        }
+
+        fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+            self.record(format!("extremes {:?}", extremes));
+            self.inner.persist_extremes(extremes)
+        }
+
+        fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+            self.record(format!("cycle {}s", cycle.on.as_secs()));
+            self.inner.record_cycle(cycle)
+        }
+
+        fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+            self.record(format!("failover {}", faulted));
+            self.inner.persist_failover(faulted)
+        }
+    }
+
+    fn run_demo(world: impl World, cycles: u32) -> RunOutcome {
+        run_demo_with(world, &Config::default(), cycles)
+    }
+
+    fn run_demo_with(world: impl World, config: &Config, cycles: u32) -> RunOutcome {
+        let (_, commands) = channel();
+        run(
+            restore_seed(&world, config.intervals),
+            config,
+            world,
+            LogNotifier,
+            commands,
+            Supervisor::new(LoopProgress::default()),
+            &Shutdown::default().with_cycle_limit(Some(cycles)),
+        )
+    }
+
+    /// Drives the controller through step() the way run() does, without the supervision and persistence health
+    /// around it. The successful readings.
+    fn step_demo(world: &mut impl World, config: &Config, cycles: u32) -> Vec<Celsius> {
+        let mut readings = vec![];
+        observe_demo(world, config, cycles, |_, _, temperature| readings.extend(temperature));
+        readings
+    }
+
+    /// Steps the controller through `cycles`, showing `observe` each reading with the controller after it.
+    fn observe_demo(
+        world: &mut impl World,
+        config: &Config,
+        cycles: u32,
+        mut observe: impl FnMut(&ControllerState, LocalTime, Option<Celsius>),
+    ) {
+        let mut controller = ControllerState::new(restore_seed(world, config.intervals), config, world.now());
+        while controller.completed_cycles() < cycles {
+            if controller.state() != State::InitiallyOff {
+                world.sleep(POLL_DURATION);
+            }
+            let temperature = world.get_temperature();
+            let reading = temperature.as_ref().ok().copied();
+            let local = world.local_time();
+            let input = Input {
+                temperature,
+                now: world.now(),
+                local,
+                ambient: world.get_ambient_temperature().and_then(Result::ok),
+                acknowledged: false,
+                reset_runtime: false,
+                sensor_replaced: false,
+                boost: None,
+                revert_timing: false,
+                shed: false,
+                pump: world.pump_state(),
+                current: world.get_current().and_then(Result::ok),
+                reset_failover: false,
+                reload: None,
+            };
+            let (next, effects) = step(controller, input);
+            controller = next;
+            for effect in effects {
+                let _ = match effect {
+                    Effect::SetPower(on) => {
+                        world.set_power_state(on);
+                        Ok(())
+                    }
+                    Effect::SetStage2(on) => {
+                        world.set_stage2_state(on);
+                        Ok(())
+                    }
+                    Effect::SetPump(on) => {
+                        world.set_pump_state(on);
+                        Ok(())
+                    }
+                    Effect::SelectOutput(output) => {
+                        world.select_output(output);
+                        Ok(())
+                    }
+                    Effect::PersistLastOff => world.persist_last_off_transition(),
+                    Effect::PersistCompensation { cooling, heating } => world.persist_compensation(cooling, heating),
+                    Effect::PersistCoolingRates(rates) => world.persist_cooling_rates(&rates),
+                    Effect::PersistEnergy(totals) => world.persist_energy(totals),
+                    Effect::PersistRuntime(runtime) => world.persist_runtime(runtime),
+                    Effect::PersistStarts(counts) => world.persist_starts(&counts),
+                    Effect::PersistOvershoots(overshoots) => world.persist_overshoots(&overshoots),
+                    Effect::PersistRest(rest) => world.persist_rest(rest.as_ref()),
+                    Effect::PersistShed(shed) => world.persist_shed(shed.as_ref()),
+                    Effect::PersistExtremes(extremes) => world.persist_extremes(extremes.as_ref()),
+                    Effect::RecordCycle(cycle) => world.record_cycle(&cycle),
+                    Effect::PersistFailover(faulted) => world.persist_failover(faulted),
+                    Effect::Heartbeat(status) => world.write_heartbeat(status),
+                    _ => Ok(()),
+                };
+            }
+            observe(&controller, local, reading);
+        }
+    }
+
+    #[test]
+    fn demo_scenario_identical_through_step() {
+        let (world, through_run) = Tracing::new(DemoWorld::new(DemoParameters::default()).unwrap());
+        assert_eq!(RunOutcome::CycleLimitReached { cycles: 10 }, run_demo(world, 10));
+
+        let (mut world, through_step) = Tracing::new(DemoWorld::new(DemoParameters::default()).unwrap());
+        step_demo(&mut world, &Config::default(), 10);
+
+        let through_run = through_run.borrow();
+        assert!(through_run.iter().filter(|c| c.ends_with("power false")).count() == 10);
+        assert!(through_run.iter().any(|c| c.contains("compensation")));
+        assert_eq!(*through_run, *through_step.borrow());
+    }
+
+    #[test]
+    fn demo_cycle_lengths_follow_parameters() {
+        let parameters = DemoParameters {
+            initial_temperature: Celsius(5.0),
+            warming_rate: 12.0,
+            cooling_rate: -6.0,
+            latent_cooling: Duration::from_secs(0),
+            ..Default::default()
+        };
+        let (world, calls) = Tracing::new(DemoWorld::new(parameters).unwrap());
+        run_demo(world, 5);
+        let switched: Vec<u64> = calls
+            .borrow()
+            .iter()
+            .filter(|c| c.contains(" power "))
+            .map(|c| c.split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(10, switched.len());
+        // Without latent cooling there is no overshoot to compensate, each swing crosses the whole target range.
+        let band = (TARGET_RANGE.end - TARGET_RANGE.start).0;
+        let on = band / 6.0 * 3600.0;
+        let off = band / 12.0 * 3600.0;
+        // Skip the initial pull-down.
+        for (i, period) in switched[1..].windows(2).map(|w| (w[1] - w[0]) as f32).enumerate() {
+            let expected = if i % 2 == 0 { off } else { on };
+            assert!(
+                (period - expected).abs() <= 2.0 * POLL_DURATION.as_secs_f32(),
+                "{:?}",
+                switched
+            );
+        }
+    }
+
+    #[test]
+    fn ambient_trim_reduces_overshoot_on_hot_days() {
+        use crate::thermal_model::ThermalModel;
+        use picool_core::ambient_trim::AmbientTrimConfig;
+        let mut parameters = DemoParameters {
+            model: ThermalModel::Rc,
+            ..DemoParameters::default()
+        };
+        parameters.rc.ambient.mean = Celsius(28.0);
+        parameters.rc.capacity = 30.0;
+        parameters.rc.ambient.swing = 4.0;
+        parameters.rc.ambient.period = Duration::from_secs(60 * 60 * 24);
+        let peak = |config: &Config| {
+            let mut world = DemoWorld::new(parameters.clone()).unwrap();
+            let readings = step_demo(&mut world, config, 60);
+            // Past the initial pull-down.
+            let settled = &readings[readings.len() / 4..];
+            Celsius(settled.iter().fold(f32::MIN, |peak, t| peak.max(t.0)))
+        };
+        let baseline = peak(&Config::default());
+        let trimmed = peak(&Config {
+            ambient_trim: AmbientTrimConfig {
+                gain: 0.05,
+                ..AmbientTrimConfig::default()
+            },
+            ..Config::default()
+        });
+        // A day that never cools below the reference, the trim only ever starts cooling earlier.
+        assert!(baseline > TARGET_RANGE.end, "{}", baseline);
+        assert!(trimmed < TARGET_RANGE.end, "{}", trimmed);
+    }
+
+    #[test]
+    fn differential_band_tracks_the_ambient_up_to_the_ceiling() {
+        use crate::thermal_model::ThermalModel;
+        use picool_core::differential::DifferentialConfig;
+        let config = Config {
+            differential: DifferentialConfig {
+                below: Some((DegreesDelta(4.0), DegreesDelta(2.0))),
+                floor: Celsius(4.0),
+                ceiling: Celsius(18.0),
+            },
+            ..Config::default()
+        };
+        let settled_mean = |ambient: f32| {
+            let mut parameters = DemoParameters {
+                model: ThermalModel::Rc,
+                ..DemoParameters::default()
+            };
+            parameters.rc.ambient.mean = Celsius(ambient);
+            let mut world = DemoWorld::new(parameters).unwrap();
+            let readings = step_demo(&mut world, &config, 30);
+            let settled = &readings[readings.len() / 2..];
+            settled.iter().map(|t| t.0).sum::<f32>() / settled.len() as f32
+        };
+        // The middle of the band is 3 degrees below the ambient, until a heat wave holds it under the ceiling.
+        for (ambient, middle) in [(15.0, 12.0), (19.0, 16.0), (30.0, 17.0)] {
+            let mean = settled_mean(ambient);
+            assert!((mean - middle).abs() < 0.5, "ambient {} settled at {}", ambient, mean);
+        }
+    }
+
+    #[test]
+    fn peak_windows_shift_duty_ahead_of_the_peak() {
+        use crate::thermal_model::ThermalModel;
+        use picool_core::peak::PeakConfig;
+        let mut parameters = DemoParameters {
+            model: ThermalModel::Rc,
+            ..DemoParameters::default()
+        };
+        // A chamber full of beer warming a couple of degrees an hour.
+        parameters.rc.ambient.mean = Celsius(20.0);
+        parameters.rc.ambient.swing = 3.0;
+        parameters.rc.ambient.period = Duration::from_secs(60 * 60 * 24);
+        parameters.rc.time_constant = Duration::from_secs(60 * 60 * 10);
+        parameters.rc.capacity = 10.0;
+        // The afternoon is the warmest at 17:00, when the peak starts.
+        let start = LocalTime::from_local_seconds(19_000 * 24 * 60 * 60 + 11 * 60 * 60);
+        let peak = PeakConfig {
+            windows: vec!["17:00-19:00".parse().unwrap()],
+            lead: Duration::from_secs(30 * 60),
+            excursion: DegreesDelta(1.0),
+        };
+        // Relay on readings and readings before and during the peak, and the warmest during it, past the first day.
+        let duty = |config: &Config| {
+            let mut world = DemoWorld::new(parameters.clone()).unwrap().starting_at(start);
+            let (mut before, mut during, mut warmest) = ((0, 0), (0, 0), f32::MIN);
+            observe_demo(&mut world, config, 150, |controller, local, temperature| {
+                if local.day == start.day {
+                    return;
+                }
+                let on = controller.power_on() as u32;
+                if peak.windows[0].contains(local) {
+                    during = (during.0 + on, during.1 + 1);
+                    warmest = warmest.max(temperature.map_or(f32::MIN, |t| t.0));
+                } else if peak.windows[0].until_start(local) <= peak.lead {
+                    before = (before.0 + on, before.1 + 1);
+                }
+            });
+            let fraction = |(on, all): (u32, u32)| on as f32 / all as f32;
+            (fraction(before), fraction(during), Celsius(warmest))
+        };
+        let (normal_before, normal_during, _) = duty(&Config::default());
+        let shifted = Config {
+            peak: peak.clone(),
+            ..Config::default()
+        };
+        let (before, during, warmest) = duty(&shifted);
+        // Around a sixth of the time either side without, most of the half hour before and next to none during with.
+        assert!(before > 0.4 && normal_before < 0.2, "{} {}", before, normal_before);
+        assert!(during < 0.05 && normal_during > 0.1, "{} {}", during, normal_during);
+        // Back to normal control once the excursion is used up.
+        assert!(warmest < TARGET_RANGE.end + DegreesDelta(1.5), "{}", warmest);
+    }
+
+    #[test]
+    fn stage2_carries_an_undersized_stage1() {
+        use crate::thermal_model::ThermalModel;
+        use picool_core::staging::StagingConfig;
+        // A compressor that only just holds a warm room, pulling down from well above the band.
+        let mut parameters = DemoParameters {
+            model: ThermalModel::Rc,
+            initial_temperature: Celsius(12.0),
+            stage2_capacity: 20.0,
+            ..DemoParameters::default()
+        };
+        parameters.rc.ambient.mean = Celsius(25.0);
+        parameters.rc.capacity = 20.0;
+        // The seconds stage 1 first turned off, and each stage 2 run as (start, end, temperature at the end).
+        let pull_down = |staging: StagingConfig| {
+            let mut world = DemoWorld::new(parameters.clone()).unwrap();
+            let config = Config {
+                staging,
+                ..Config::default()
+            };
+            let (mut polls, mut started, mut first_off, mut runs) = (0, false, None, vec![]);
+            let mut stage2_since = None;
+            observe_demo(&mut world, &config, 10, |controller, _, temperature| {
+                let at = polls * 10;
+                polls += 1;
+                let stage2 = controller.stage2(Instant::now()).is_some_and(|(on, _, _)| on);
+                assert!(controller.power_on() || !stage2, "stage 2 alone at {}s", at);
+                started |= controller.power_on();
+                if started && !controller.power_on() && first_off.is_none() {
+                    first_off = Some(at);
+                }
+                match (stage2_since, stage2) {
+                    (None, true) => stage2_since = Some(at),
+                    (Some(since), false) => {
+                        runs.push((since, at, temperature.unwrap()));
+                        stage2_since = None;
+                    }
+                    _ => {}
+                }
+            });
+            (first_off.unwrap(), runs)
+        };
+        let staged = StagingConfig {
+            enabled: true,
+            ..StagingConfig::default()
+        };
+        let (alone, runs) = pull_down(StagingConfig::default());
+        assert!(runs.is_empty());
+        // Far past the high threshold, stage 2 joins with stage 1 and drops out a degree under the high threshold,
+        // leaving stage 1 to hold the band alone from then on.
+        let (offset, runs) = pull_down(staged);
+        assert_eq!(1, runs.len(), "{:?}", runs);
+        let (start, end, temperature) = runs[0];
+        assert_eq!(470, start);
+        assert!(end < offset && (temperature.0 - 3.3).abs() < 0.1, "{:?}", runs);
+        // Left to the runtime, it joins once stage 1 has run half an hour without reaching the band.
+        let (runtime, runs) = pull_down(StagingConfig {
+            offset: DegreesDelta(20.0),
+            after: Some(Duration::from_secs(30 * 60)),
+            ..staged
+        });
+        assert_eq!(1, runs.len(), "{:?}", runs);
+        assert_eq!(470 + 30 * 60, runs[0].0);
+        assert!(offset < runtime && runtime < alone, "{} {} {}", offset, runtime, alone);
+    }
+
+    #[test]
+    fn early_shutoff_reduces_undershoot() {
+        use picool_core::early_shutoff::TailEstimate;
+        let parameters = DemoParameters {
+            latent_cooling: Duration::from_secs(60 * 12),
+            scenario: "
+                [[event]]
+                at = \"6h\"
+                door_open = 4.0
+                over = \"2m\"
+            "
+            .parse()
+            .unwrap(),
+            ..DemoParameters::default()
+        };
+        // Below the band over the whole run and over the half after the door, which resets the cooling compensation.
+        let undershoot = |early_shutoff: TailEstimate| {
+            let mut world = DemoWorld::new(parameters.clone()).unwrap();
+            let config = Config {
+                early_shutoff,
+                ..Config::default()
+            };
+            let readings = step_demo(&mut world, &config, 12);
+            let below =
+                |readings: &[Celsius]| TARGET_RANGE.start.0 - readings.iter().fold(f32::MAX, |low, t| low.min(t.0));
+            (below(&readings), below(&readings[readings.len() / 2..]))
+        };
+        let (baseline, baseline_after_door) = undershoot(TailEstimate::Off);
+        assert!(
+            baseline > 1.0 && baseline_after_door > 1.0,
+            "{} {}",
+            baseline,
+            baseline_after_door
+        );
+        // Close to the real tail from the first cycle.
+        let (fixed, _) = undershoot(TailEstimate::Fixed(DegreesDelta(1.4)));
+        assert!(fixed < 0.2, "{}", fixed);
+        // Learning takes a few cycles, but the tail survives the compensation reset.
+        let (learned, learned_after_door) = undershoot(TailEstimate::Learned);
+        assert!(learned > 1.0, "{}", learned);
+        assert!(learned_after_door < 0.1, "{}", learned_after_door);
+    }
+
+    #[test]
+    fn time_proportional_settles_mid_band() {
+        use crate::thermal_model::ThermalModel;
+        use picool_core::config::{Band, ControlMode};
+        let mut world = DemoWorld::new(DemoParameters {
+            model: ThermalModel::Rc,
+            ..DemoParameters::default()
+        })
+        .unwrap();
+        let config = Config {
+            mode: ControlMode::Tpc,
+            ..Config::default()
+        };
+        let readings = step_demo(&mut world, &config, 100);
+        let settled = &readings[readings.len() * 3 / 4..];
+        let mean = settled.iter().map(|t| t.0).sum::<f32>() / settled.len() as f32;
+        assert!((mean - Band::default().middle().0).abs() < 0.25, "{}", mean);
+        assert!(settled.iter().all(|t| TARGET_RANGE.contains(t)));
+    }
+
+    /// The persisted (cooling, heating) compensations of a demo run.
+    fn compensations(parameters: DemoParameters, config: &Config, cycles: u32) -> Vec<(f32, f32)> {
+        let (world, calls) = Tracing::new(DemoWorld::new(parameters).unwrap());
+        assert_eq!(
+            RunOutcome::CycleLimitReached { cycles },
+            run_demo_with(world, config, cycles)
+        );
+        let calls = calls.borrow();
+        let persisted = calls.iter().filter_map(|c| c.split_once(" compensation "));
+        persisted
+            .map(|(_, values)| {
+                let (cooling, heating) = values.split_once(' ').unwrap();
+                (cooling.parse().unwrap(), heating.parse().unwrap())
+            })
+            .collect()
+    }
+
+    /// Puts notifications among the world calls, at the time of the call before them.
+    struct TracingNotifier(Recorded<String>);
+
+    impl Notifier for TracingNotifier {
+        fn notify(&mut self, event: &Event) {
+            let mut calls = self.0.borrow_mut();
+            let at = calls
+                .last()
+                .and_then(|c| c.split(' ').next())
+                .unwrap_or("0")
+                .to_string();
+            calls.push(format!("{} notify {}", at, event));
+        }
+    }
+
+    #[test]
+    fn capped_compensation_alerts_once_then_daily() {
+        // The demo chamber carries on well over a degree past the threshold, more than this cap covers.
+        let mut config = Config::default();
+        config.band.max_compensation = DegreesDelta(0.3);
+        let (world, calls) = Tracing::new(DemoWorld::new(DemoParameters::default()).unwrap());
+        let cycles = 80;
+        let outcome = run(
+            restore_seed(&world, config.intervals),
+            &config,
+            world,
+            TracingNotifier(calls.clone()),
+            channel().1,
+            Supervisor::new(LoopProgress::default()),
+            &Shutdown::default().with_cycle_limit(Some(cycles)),
+        );
+        assert_eq!(RunOutcome::CycleLimitReached { cycles }, outcome);
+        let calls = calls.borrow();
+        let end: u64 = calls.last().unwrap().split(' ').next().unwrap().parse().unwrap();
+        let alerts: Vec<(u64, &str)> = calls
+            .iter()
+            .filter_map(|c| c.split_once(" notify "))
+            .filter(|(_, message)| message.contains("compensation"))
+            .map(|(at, message)| (at.parse().unwrap(), message))
+            .collect();
+        assert!(
+            alerts
+                .iter()
+                .all(|(_, message)| message.starts_with("Cooling compensation held at its 0.30C")),
+            "{:?}",
+            alerts
+        );
+        assert!(alerts[0].1.contains("Raise --max-compensation to"), "{}", alerts[0].1);
+        // Raised on the tenth capped cycle, then once a day, at the first capped cycle a day on.
+        let day = 24 * 60 * 60;
+        assert_eq!((end - alerts[0].0) / day + 1, alerts.len() as u64, "{:?}", alerts);
+        for pair in alerts.windows(2) {
+            assert!(
+                pair[1].0 - pair[0].0 >= day && pair[1].0 - pair[0].0 < day + 3600,
+                "{:?}",
+                alerts
+            );
+        }
+    }
+
+    #[test]
+    fn presets_hold_their_chambers_in_band() {
+        for preset in &Preset::ALL {
+            let config = preset.config();
+            let mut world = DemoWorld::new(DemoParameters::for_preset(*preset)).unwrap();
+            let readings = step_demo(&mut world, &config, 20);
+            // Once pulled down, the latent cooling carries a little under the band until compensated.
+            let settled = &readings[readings.len() / 2..];
+            let target = &config.band.target;
+            let (low, high) = settled
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(low, high), t| (low.min(t.0), high.max(t.0)));
+            assert!(
+                low > target.start.0 - 0.5 && high < target.end.0 + 0.5,
+                "{} held {} to {} for {:?}",
+                preset,
+                low,
+                high,
+                target
+            );
+            let world = DemoWorld::new(DemoParameters::for_preset(*preset)).unwrap();
+            assert_eq!(
+                RunOutcome::CycleLimitReached { cycles: 20 },
+                run_demo_with(world, &config, 20)
+            );
+        }
+    }
+
+    #[test]
+    fn freezer_compensation_converges_below_zero() {
+        let freezer = compensations(
+            DemoParameters::for_preset(Preset::Freezer),
+            &Preset::Freezer.config(),
+            20,
+        );
+        let fridge = compensations(DemoParameters::default(), &Config::default(), 20);
+        // The demo chamber cools and warms at the same rate either side of zero, so the latent cooling overshoot
+        // settles to the same compensation.
+        let (cooling, heating) = *freezer.last().unwrap();
+        assert!(cooling > 0.5 && cooling < MAX_COMPENSATION.0, "{:?}", freezer);
+        assert_eq!(0.0, heating);
+        assert!(
+            (cooling - fridge.last().unwrap().0).abs() < 0.01,
+            "{:?} {:?}",
+            freezer,
+            fridge
+        );
+    }
+
+    /// The relay switches and sensor reinitializations of a demo run, one per line.
+    fn transitions(parameters: DemoParameters, cycles: u32) -> String {
+        let (world, calls) = Tracing::new(DemoWorld::new(parameters).unwrap());
+        assert_eq!(RunOutcome::CycleLimitReached { cycles }, run_demo(world, cycles));
+        let calls = calls.borrow();
+        let transitions = calls
+            .iter()
+            .filter(|c| c.contains(" power ") || c.ends_with(" reinitialize"));
+        transitions.map(|c| format!("{}\n", c)).collect()
+    }
+
+    #[test]
+    fn scenarios_match_expected_transitions() {
+        use crate::thermal_model::ThermalModel;
+        let scenarios = [
+            (
+                "sensor-outage",
+                ThermalModel::Linear,
+                include_str!("../scenarios/sensor-outage.toml"),
+                include_str!("../scenarios/sensor-outage.expected"),
+            ),
+            (
+                "heat-wave",
+                ThermalModel::Rc,
+                include_str!("../scenarios/heat-wave.toml"),
+                include_str!("../scenarios/heat-wave.expected"),
+            ),
+        ];
+        for &(name, model, scenario, expected) in &scenarios {
+            let parameters = DemoParameters {
+                model,
+                scenario: scenario.parse().unwrap(),
+                ..Default::default()
+            };
+            let actual = transitions(parameters, 8);
+            assert!(actual == expected, "{} transitions changed:\n{}", name, actual);
+        }
+    }
+
+    /// Shares what a world writes with the test.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replay_matches_expected_decisions() {
+        let trace: Trace = include_str!("../traces/stc1000.csv").parse().unwrap();
+        assert_eq!(1, trace.out_of_order);
+        let shutdown = Shutdown::default();
+        let decisions = SharedBuffer::default();
+        let world = ReplayWorld::new(
+            trace,
+            ReplayParameters::default(),
+            Box::new(decisions.clone()),
+            shutdown.clone(),
+        )
+        .unwrap();
+        let outcome = run(
+            restore_seed(&world, MinimumIntervals::default()),
+            &Config::default(),
+            world,
+            LogNotifier,
+            channel().1,
+            Supervisor::new(LoopProgress::default()),
+            &shutdown,
+        );
+        assert_eq!(RunOutcome::ShutdownRequested, outcome);
+        let decisions = String::from_utf8(decisions.0.borrow().clone()).unwrap();
+        assert!(
+            decisions == include_str!("../traces/stc1000.decisions.csv"),
+            "Decisions changed:\n{}",
+            decisions
+        );
+    }
+
+    #[test]
+    fn shadow_decides_and_learns_without_switching() {
+        let world = TestWorld::builder().readings(cycling(4)).build();
+        let recording = world.recording();
+        let decisions = SharedBuffer::default();
+        run_to_end(
+            Shadow::new(world, Some(Box::new(decisions.clone()))),
+            Seed::new(State::Off),
+            &Config::default(),
+        );
+        assert_eq!(Vec::<(u64, Power)>::new(), recording.transitions());
+        assert_eq!(Vec::<u64>::new(), recording.persisted_off());
+
+        let (direct, _) = run_script(State::Off, cycling(4), Config::default());
+        assert!(direct.transitions().len() >= 6);
+        assert_eq!(direct.compensations(), recording.compensations());
+        let decisions = String::from_utf8(decisions.0.borrow().clone()).unwrap();
+        let shadowed: Vec<&str> = decisions
+            .lines()
+            .skip(1)
+            .filter_map(|row| row.rsplit(',').next())
+            .collect();
+        let switched: Vec<&str> = direct
+            .transitions()
+            .into_iter()
+            .map(|(_, power)| if power == On { "on" } else { "off" })
+            .collect();
+        assert_eq!(switched, shadowed);
+    }
+
+    /// Runs the script to completion, returning the relay states in order and the persisted compensations.
+    fn control_trace(readings: Vec<Option<f32>>) -> (Vec<Power>, Vec<(DegreesDelta, DegreesDelta)>) {
+        let (recording, _) = run_script(State::Off, readings, Config::default());
+        let power = recording.transitions().into_iter().map(|(_, power)| power).collect();
+        (power, recording.compensations())
+    }
+
+    #[test]
+    fn non_finite_readings_are_discarded() {
+        let clean = cycling(4);
+        let dirty = clean
+            .iter()
+            .enumerate()
+            .flat_map(|(i, reading)| {
+                let junk = [f32::NAN, f32::INFINITY, f32::NEG_INFINITY][i % 3];
+                vec![*reading, Some(junk)]
+            })
+            .collect();
+        let (clean_power, clean_compensations) = control_trace(clean);
+        let (dirty_power, dirty_compensations) = control_trace(dirty);
+        assert!(!clean_compensations.is_empty());
+        assert_eq!(clean_power, dirty_power);
+        assert_eq!(clean_compensations, dirty_compensations);
+    }
+
+    #[test]
+    fn persistent_nan_engages_failsafe() {
+        let mut readings = vec![Some(3.0)];
+        readings.extend(std::iter::repeat_n(Some(f32::NAN), 40));
+        let (recording, events) = run_script(State::On, readings, failsafe_config(FailsafePolicy::Off));
+        recording.assert_transitions(&[(320, Off)]);
+        assert!(matches!(
+            events.as_slice(),
+            [Event::SensorFailsafeEngaged {
+                cause: SensorErrorKind::NonFinite,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn read_only_persistence_terminates_at_first_failure() {
+        let world = TestWorld::builder()
+            .readings(cycling(2))
+            .fail_persistence(libc::EROFS)
+            .build();
+        let recording = world.recording();
+        let termination = run_to_termination(world, Config::default());
+        assert!(matches!(
+            termination,
+            Termination::PersistenceUnusable {
+                cause: PersistErrorKind::ReadOnly,
+                ..
+            }
+        ));
+        // Terminated persisting the start count of the first on transition.
+        recording.assert_transitions(&[(10, On), (10, Off)]);
+    }
+
+    #[test]
+    fn unusable_persistence_terminates() {
+        let world = TestWorld::builder()
+            .readings(cycling(10))
+            .fail_persistence(libc::EIO)
+            .build();
+        let recording = world.recording();
+        let termination = run_to_termination(world, Config::default());
+        // Checked once per poll, a single off transition persists several files.
+        assert!(matches!(
+            termination,
+            Termination::PersistenceUnusable { failures, cause: PersistErrorKind::Io } if failures >= 5
+        ));
+        assert_eq!(EXIT_PERSISTENCE, termination.exit_code());
+        assert_eq!(Some(Off), recording.transitions().last().map(|(_, power)| *power));
+    }
+
+    #[test]
+    fn unreliable_storage_runs_from_memory_until_a_retry_verifies() {
+        let world = TestWorld::builder()
+            .readings(cycling(16))
+            .unverified_until(Duration::from_secs(2 * 3600))
+            .build();
+        let recording = world.recording();
+        let config = Config {
+            storage: StorageConfig {
+                unreliable_after: 3,
+                retry: Duration::from_secs(30 * 60),
+                ..StorageConfig::default()
+            },
+            ..Config::default()
+        };
+        let events = run_to_end(world, Seed::new(State::Off), &config);
+        let storage: Vec<_> = events
+            .into_iter()
+            .filter(|event| matches!(event, Event::StorageUnreliable { .. } | Event::StorageRecovered { .. }))
+            .collect();
+        assert!(
+            matches!(
+                storage.as_slice(),
+                [
+                    Event::StorageUnreliable { failures: 3, .. },
+                    Event::StorageRecovered { skipped }
+                ] if *skipped > 0
+            ),
+            "{:?}",
+            storage
+        );
+        // Control carried on through it, only the retries reached the store while it was unreliable.
+        assert_eq!(32, recording.transitions().len());
+        let off_persisted = recording.persisted_off();
+        assert!(off_persisted.len() < 16, "{:?}", off_persisted);
+        assert!(off_persisted.iter().any(|&at| at > 2 * 3600));
+    }
+
+    /// Readings every poll starting at `start` and changing by `per_poll`.
+    fn ramp(start: f32, per_poll: f32, polls: usize) -> Vec<Option<f32>> {
+        (0..polls).map(|i| Some(start + per_poll * i as f32)).collect()
+    }
+
+    #[test]
+    fn thermal_runaway_locks_out() {
+        let (recording, events) = run_script(State::On, ramp(5.0, 0.01, 480), Config::default());
+        recording.assert_transitions(&[(3610, Off)]);
+        match events.as_slice() {
+            [Event::ThermalRunaway(evidence)] => {
+                assert_eq!(Duration::from_secs(3600), evidence.on_for);
+                assert!((evidence.slope_per_hour - 3.6).abs() < 0.01);
+            }
+            other => panic!("unexpected events {:?}", other),
+        }
+    }
+
+    #[test]
+    fn slow_but_working_fridge_does_not_trip() {
+        let (recording, events) = run_script(State::On, ramp(8.0, -0.001, 1080), Config::default());
+        recording.assert_transitions(&[]);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn thermal_runaway_auto_retry() {
+        let config = Config {
+            runaway: RunawayConfig {
+                auto_retry_after: Some(Duration::from_secs(30 * 60)),
+                ..RunawayConfig::default()
+            },
+            ..Config::default()
+        };
+        let (recording, events) = run_script(State::On, ramp(5.0, 0.01, 680), config);
+        recording.assert_transitions(&[(3610, Off), (5410, On)]);
+        assert_eq!(
+            Event::RunawayCleared {
+                locked_out_for: Duration::from_secs(1800),
+                acknowledged: false
+            },
+            events[1]
+        );
     }
 }
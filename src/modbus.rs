@@ -0,0 +1,522 @@
+//! A temperature register read over Modbus RTU on an RS-485 serial port, for industrial transmitters and PT100
+//! converters that don't speak 1-Wire.
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use picool_core::{temperature::Celsius, world_error::SensorError};
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const EXCEPTION_FLAG: u8 = 0x80;
+/// Address, function, byte count or exception code.
+const HEADER_LEN: usize = 3;
+const CRC_LEN: usize = 2;
+/// Quiet time between a failed attempt and the next, well over the 3.5 characters that end a frame.
+const RETRY_GAP: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RegisterKind {
+    Holding,
+    Input,
+}
+
+impl RegisterKind {
+    fn function(self) -> u8 {
+        match self {
+            RegisterKind::Holding => READ_HOLDING_REGISTERS,
+            RegisterKind::Input => READ_INPUT_REGISTERS,
+        }
+    }
+}
+
+/// How the register contents encode the value.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ValueFormat {
+    Int16,
+    Uint16,
+    /// Two registers, high word first (`abcd`) or low word first (`cdab`).
+    Float32 {
+        swapped: bool,
+    },
+}
+
+impl ValueFormat {
+    fn registers(self) -> u16 {
+        match self {
+            ValueFormat::Int16 | ValueFormat::Uint16 => 1,
+            ValueFormat::Float32 { .. } => 2,
+        }
+    }
+}
+
+/// The device and register of a `--sensor modbus:<device>?<parameters>` spec.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModbusConfig {
+    pub device: PathBuf,
+    pub baud: u32,
+    pub address: u8,
+    pub register: u16,
+    pub kind: RegisterKind,
+    pub format: ValueFormat,
+    /// Celsius is the register value times the scale plus the offset.
+    pub scale: f32,
+    pub offset: f32,
+    pub timeout: Duration,
+    /// Further attempts after a timeout or a corrupted frame.
+    pub retries: u32,
+}
+
+impl ModbusConfig {
+    fn new(device: PathBuf) -> Self {
+        Self {
+            device,
+            baud: 9600,
+            address: 1,
+            register: 0,
+            kind: RegisterKind::Holding,
+            format: ValueFormat::Int16,
+            scale: 1.0,
+            offset: 0.0,
+            timeout: Duration::from_millis(500),
+            retries: 2,
+        }
+    }
+
+    /// The device file and slave address, unique per sensor.
+    pub fn name(&self) -> String {
+        let device = self.device.file_name().unwrap_or(self.device.as_os_str());
+        format!("{}_{}", device.to_string_lossy(), self.address)
+    }
+}
+
+/// The part after `modbus:`, e.g. `/dev/ttyUSB0?addr=1&reg=0x0000&format=float32&order=cdab`.
+impl FromStr for ModbusConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (device, query) = match s.find('?') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        if device.is_empty() {
+            bail!("Modbus sensor '{}' names no serial device.", s);
+        }
+        let mut config = ModbusConfig::new(PathBuf::from(device));
+        let mut order = None;
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match parameter.find('=') {
+                Some(i) => (&parameter[..i], &parameter[i + 1..]),
+                None => bail!("Modbus parameter '{}' has no value.", parameter),
+            };
+            let invalid = || format!("Invalid Modbus {} '{}'.", key, value);
+            match key {
+                "baud" => config.baud = value.parse().with_context(invalid)?,
+                "addr" => config.address = parse_integer(value).with_context(invalid)?,
+                "reg" => config.register = parse_integer(value).with_context(invalid)?,
+                "function" => {
+                    config.kind = match value {
+                        "holding" | "3" => RegisterKind::Holding,
+                        "input" | "4" => RegisterKind::Input,
+                        _ => bail!("Modbus function '{}' is neither holding nor input.", value),
+                    }
+                }
+                "format" => {
+                    config.format = match value {
+                        "int16" => ValueFormat::Int16,
+                        "uint16" => ValueFormat::Uint16,
+                        "float32" => ValueFormat::Float32 { swapped: false },
+                        _ => bail!("Modbus format '{}' is not int16, uint16 or float32.", value),
+                    }
+                }
+                "order" => {
+                    order = match value {
+                        "abcd" => Some(false),
+                        "cdab" => Some(true),
+                        _ => bail!("Modbus word order '{}' is not abcd or cdab.", value),
+                    }
+                }
+                "scale" => config.scale = value.parse().with_context(invalid)?,
+                "offset" => config.offset = value.parse().with_context(invalid)?,
+                "timeout" => config.timeout = Duration::from_millis(value.parse().with_context(invalid)?),
+                "retries" => config.retries = value.parse().with_context(invalid)?,
+                _ => bail!("Unknown Modbus parameter '{}'.", key),
+            }
+        }
+        match (config.format, order) {
+            (ValueFormat::Float32 { .. }, Some(swapped)) => config.format = ValueFormat::Float32 { swapped },
+            (_, Some(_)) => bail!("A Modbus word order only applies to float32."),
+            _ => {}
+        }
+        if !(1..=247).contains(&config.address) {
+            bail!("Modbus slave address {} is outside 1-247.", config.address);
+        }
+        if baud_rate(config.baud).is_none() {
+            bail!("Unsupported Modbus baud rate {}.", config.baud);
+        }
+        if !(config.scale.is_finite() && config.scale != 0.0 && config.offset.is_finite()) {
+            bail!("The Modbus scale must be finite and non-zero, and the offset finite.");
+        }
+        if config.timeout.as_millis() == 0 {
+            bail!("The Modbus timeout must be positive.");
+        }
+        Ok(config)
+    }
+}
+
+// Pure
+/// Decimal, or hexadecimal with a `0x` prefix.
+pub fn parse_integer<T: TryFrom<u32>>(value: &str) -> Result<T> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => value.parse()?,
+    };
+    T::try_from(parsed).ok().context("Out of range.")
+}
+
+/// A response that can't be used.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FrameError {
+    Crc,
+    /// The slave answered with an exception code, retrying won't change its mind.
+    Exception(u8),
+    /// A well-formed frame that doesn't answer the request, from another slave or a stale exchange.
+    Unexpected,
+}
+
+impl FrameError {
+    fn is_retryable(self) -> bool {
+        !matches!(self, FrameError::Exception(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Crc => write!(f, "CRC mismatch"),
+            FrameError::Exception(1) => write!(f, "exception 1, illegal function"),
+            FrameError::Exception(2) => write!(f, "exception 2, illegal data address"),
+            FrameError::Exception(code) => write!(f, "exception {}", code),
+            FrameError::Unexpected => write!(f, "response doesn't match the request"),
+        }
+    }
+}
+
+// Pure
+/// CRC-16/MODBUS, sent low byte first.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u16, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xA001,
+            _ => crc >> 1,
+        })
+    })
+}
+
+// Pure
+pub fn encode_read_request(address: u8, function: u8, register: u16, count: u16) -> [u8; 8] {
+    let [register_high, register_low] = register.to_be_bytes();
+    let [count_high, count_low] = count.to_be_bytes();
+    let mut frame = [
+        address,
+        function,
+        register_high,
+        register_low,
+        count_high,
+        count_low,
+        0,
+        0,
+    ];
+    let [crc_low, crc_high] = crc16(&frame[..6]).to_le_bytes();
+    frame[6] = crc_low;
+    frame[7] = crc_high;
+    frame
+}
+
+// Pure
+/// The length of the whole response once its header is in.
+pub fn response_len(header: &[u8; HEADER_LEN]) -> usize {
+    match header[1] & EXCEPTION_FLAG {
+        0 => HEADER_LEN + header[2] as usize + CRC_LEN,
+        _ => HEADER_LEN + CRC_LEN,
+    }
+}
+
+// Pure
+/// The registers of a response to a read request.
+pub fn decode_read_response(request: &[u8; 8], frame: &[u8]) -> Result<Vec<u16>, FrameError> {
+    if frame.len() < HEADER_LEN + CRC_LEN {
+        return Err(FrameError::Unexpected);
+    }
+    let (body, crc) = frame.split_at(frame.len() - CRC_LEN);
+    if crc16(body).to_le_bytes() != crc {
+        return Err(FrameError::Crc);
+    }
+    let (address, function) = (request[0], request[1]);
+    if body[0] != address {
+        return Err(FrameError::Unexpected);
+    }
+    if body[1] == function | EXCEPTION_FLAG {
+        return Err(FrameError::Exception(body[2]));
+    }
+    let count = u16::from_be_bytes([request[4], request[5]]) as usize;
+    if body[1] != function || body[2] as usize != count * 2 || body.len() != HEADER_LEN + count * 2 {
+        return Err(FrameError::Unexpected);
+    }
+    Ok(body[HEADER_LEN..]
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect())
+}
+
+// Pure
+pub fn decode_value(format: ValueFormat, registers: &[u16]) -> f32 {
+    match (format, registers) {
+        (ValueFormat::Int16, [value]) => *value as i16 as f32,
+        (ValueFormat::Uint16, [value]) => *value as f32,
+        (ValueFormat::Float32 { swapped: false }, [high, low])
+        | (ValueFormat::Float32 { swapped: true }, [low, high]) => f32::from_bits((*high as u32) << 16 | *low as u32),
+        _ => panic!("Invariant: Decoded registers match the format."),
+    }
+}
+
+/// Why an exchange failed, worth retrying unless the slave answered.
+enum ExchangeError {
+    Io(io::Error),
+    Frame(FrameError),
+}
+
+/// A serial port with the register it polls, reopened by reinitialize.
+pub struct ModbusSensor {
+    config: ModbusConfig,
+    port: File,
+}
+
+impl ModbusSensor {
+    pub fn open(config: ModbusConfig) -> io::Result<Self> {
+        let port = open_port(&config.device, config.baud)?;
+        Ok(Self { config, port })
+    }
+
+    fn exchange(&self, request: &[u8; 8]) -> Result<Vec<u16>, ExchangeError> {
+        // SAFETY: tcflush only discards queued bytes on a descriptor the port owns.
+        if unsafe { libc::tcflush(self.port.as_raw_fd(), libc::TCIOFLUSH) } != 0 {
+            return Err(ExchangeError::Io(io::Error::last_os_error()));
+        }
+        (&self.port).write_all(request).map_err(ExchangeError::Io)?;
+        let deadline = Instant::now() + self.config.timeout;
+        let mut header = [0; HEADER_LEN];
+        self.read_until(&mut header, deadline).map_err(ExchangeError::Io)?;
+        let mut frame = header.to_vec();
+        frame.resize(response_len(&header), 0);
+        self.read_until(&mut frame[HEADER_LEN..], deadline)
+            .map_err(ExchangeError::Io)?;
+        decode_read_response(request, &frame).map_err(ExchangeError::Frame)
+    }
+
+    /// Fills `buffer` from the port, timing out at `deadline`. The port returns from a read after 100ms of silence.
+    fn read_until(&self, buffer: &mut [u8], deadline: Instant) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no response"));
+            }
+            filled += (&self.port).read(&mut buffer[filled..])?;
+        }
+        Ok(())
+    }
+
+    pub fn read_temperature(&self) -> Result<Celsius, SensorError> {
+        let config = &self.config;
+        let request = encode_read_request(
+            config.address,
+            config.kind.function(),
+            config.register,
+            config.format.registers(),
+        );
+        let mut attempt = 0;
+        loop {
+            let error = match self.exchange(&request) {
+                Ok(registers) => {
+                    let value = decode_value(config.format, &registers);
+                    return Ok(Celsius(value * config.scale + config.offset));
+                }
+                Err(ExchangeError::Io(e)) => e,
+                Err(ExchangeError::Frame(e)) if e.is_retryable() => {
+                    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+                }
+                Err(ExchangeError::Frame(e)) => return Err(SensorError::Malformed(e.to_string())),
+            };
+            if attempt == config.retries || error.kind() == io::ErrorKind::NotFound {
+                return Err(SensorError::from_io(config.device.clone(), error));
+            }
+            attempt += 1;
+            debug!("Modbus read attempt {} failed, retrying. {}", attempt, error);
+            thread::sleep(RETRY_GAP);
+        }
+    }
+
+    pub fn reinitialize(&mut self) -> Result<(), SensorError> {
+        self.port = open_port(&self.config.device, self.config.baud)
+            .map_err(|e| SensorError::from_io(self.config.device.clone(), e))?;
+        Ok(())
+    }
+}
+
+// Pure
+fn baud_rate(baud: u32) -> Option<libc::speed_t> {
+    match baud {
+        1200 => Some(libc::B1200),
+        2400 => Some(libc::B2400),
+        4800 => Some(libc::B4800),
+        9600 => Some(libc::B9600),
+        19200 => Some(libc::B19200),
+        38400 => Some(libc::B38400),
+        57600 => Some(libc::B57600),
+        115200 => Some(libc::B115200),
+        _ => None,
+    }
+}
+
+/// Raw 8N1 at `baud`, with reads returning after 100ms without a byte.
+fn open_port(device: &Path, baud: u32) -> io::Result<File> {
+    let speed = baud_rate(baud).expect("Invariant: Baud rate validated when parsed.");
+    let port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(device)?;
+    // SAFETY: termios is plain data and only passed to the terminal calls on the port's own descriptor.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(port.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag &= !(libc::PARENB | libc::CSTOPB | libc::CSIZE | libc::CRTSCTS);
+        termios.c_cflag |= libc::CS8 | libc::CLOCAL | libc::CREAD;
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 1;
+        if libc::cfsetispeed(&mut termios, speed) != 0
+            || libc::cfsetospeed(&mut termios, speed) != 0
+            || libc::tcsetattr(port.as_raw_fd(), libc::TCSANOW, &termios) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    info!("Opened Modbus port {} at {} baud.", device.display(), baud);
+    Ok(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        hex.split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn encodes_read_requests() {
+        assert_eq!(
+            bytes("01 03 00 00 00 01 84 0A"),
+            encode_read_request(1, READ_HOLDING_REGISTERS, 0, 1)
+        );
+        assert_eq!(
+            bytes("11 04 00 10 00 02 72 9E"),
+            encode_read_request(0x11, READ_INPUT_REGISTERS, 0x10, 2)
+        );
+    }
+
+    #[test]
+    fn decodes_int16_responses() {
+        let request = encode_read_request(1, READ_HOLDING_REGISTERS, 0, 1);
+        let frame = bytes("01 03 02 00 FA 38 07");
+        assert_eq!(HEADER_LEN + 4, response_len(&[frame[0], frame[1], frame[2]]));
+        let registers = decode_read_response(&request, &frame).unwrap();
+        assert_eq!(25.0, decode_value(ValueFormat::Int16, &registers) * 0.1);
+        // Negative temperatures are two's complement.
+        let registers = decode_read_response(&request, &bytes("01 03 02 FF 9C F9 DD")).unwrap();
+        assert_eq!(-100.0, decode_value(ValueFormat::Int16, &registers));
+        assert_eq!(65436.0, decode_value(ValueFormat::Uint16, &registers));
+    }
+
+    #[test]
+    fn decodes_float32_in_either_word_order() {
+        let request = encode_read_request(0x11, READ_INPUT_REGISTERS, 0x10, 2);
+        let registers = decode_read_response(&request, &bytes("11 04 04 41 AC 00 00 3E 58")).unwrap();
+        assert_eq!(21.5, decode_value(ValueFormat::Float32 { swapped: false }, &registers));
+        let registers = decode_read_response(&request, &bytes("11 04 04 00 00 41 AC DA 68")).unwrap();
+        assert_eq!(21.5, decode_value(ValueFormat::Float32 { swapped: true }, &registers));
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let request = encode_read_request(1, READ_HOLDING_REGISTERS, 0, 1);
+        assert_eq!(
+            Err(FrameError::Crc),
+            decode_read_response(&request, &bytes("01 03 02 00 FB 38 07"))
+        );
+        let exception = bytes("01 83 02 C0 F1");
+        assert_eq!(
+            HEADER_LEN + CRC_LEN,
+            response_len(&[exception[0], exception[1], exception[2]])
+        );
+        let e = decode_read_response(&request, &exception).unwrap_err();
+        assert_eq!(FrameError::Exception(2), e);
+        assert!(!e.is_retryable());
+        // A valid frame from another slave.
+        let request = encode_read_request(2, READ_HOLDING_REGISTERS, 0, 1);
+        assert_eq!(
+            Err(FrameError::Unexpected),
+            decode_read_response(&request, &bytes("01 03 02 00 FA 38 07"))
+        );
+        // Asked for two registers, got one.
+        let request = encode_read_request(1, READ_HOLDING_REGISTERS, 0, 2);
+        assert_eq!(
+            Err(FrameError::Unexpected),
+            decode_read_response(&request, &bytes("01 03 02 00 FA 38 07"))
+        );
+    }
+
+    #[test]
+    fn parses_sensor_specs() {
+        let config: ModbusConfig = "/dev/ttyUSB0?addr=1&reg=0x0000".parse().unwrap();
+        assert_eq!(ModbusConfig::new(PathBuf::from("/dev/ttyUSB0")), config);
+        assert_eq!("ttyUSB0_1", config.name());
+        let config: ModbusConfig = "/dev/ttyAMA0?addr=17&reg=16&function=input&format=float32&order=cdab&baud=19200"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            (17, 0x10, RegisterKind::Input, 19200),
+            (config.address, config.register, config.kind, config.baud)
+        );
+        assert_eq!(ValueFormat::Float32 { swapped: true }, config.format);
+        let config: ModbusConfig = "/dev/ttyUSB0?scale=0.1&offset=-40&timeout=200&retries=0"
+            .parse()
+            .unwrap();
+        assert_eq!((0.1, -40.0), (config.scale, config.offset));
+        assert_eq!((Duration::from_millis(200), 0), (config.timeout, config.retries));
+        assert!("?addr=1".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?addr=0".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?addr=248".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?reg=0x10000".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?baud=1234".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?order=cdab".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?scale=0".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?parity=even".parse::<ModbusConfig>().is_err());
+        assert!("/dev/ttyUSB0?addr".parse::<ModbusConfig>().is_err());
+    }
+}
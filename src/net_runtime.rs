@@ -0,0 +1,477 @@
+//! The control socket, the reload handler, the WebSocket stream and the Pushgateway pusher as tasks doing non-blocking
+//! I/O on `poll(2)`, all on one thread with the `shared-io` feature and on a thread each without.
+
+use log::*;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::Poll,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// A backend's I/O, polled whenever anything it or another task on its thread waits for happens.
+pub trait Task: Send {
+    /// Does whatever can be done without blocking and says in `wait` what to wait for next, Ready once the task is
+    /// over. Nothing may be ready, a task on the same thread may have been what woke the runtime.
+    fn poll(&mut self, now: Instant, wait: &mut Wait) -> Poll<()>;
+
+    /// Stop taking new work and finish what's queued, the task is polled until it's over.
+    fn shut_down(&mut self) {}
+}
+
+/// The descriptors and the time a task waits for.
+#[derive(Default)]
+pub struct Wait {
+    fds: Vec<libc::pollfd>,
+    deadline: Option<Instant>,
+}
+
+impl Wait {
+    pub fn readable(&mut self, fd: &impl AsRawFd) {
+        self.watch(fd.as_raw_fd(), libc::POLLIN);
+    }
+
+    pub fn writable(&mut self, fd: &impl AsRawFd) {
+        self.watch(fd.as_raw_fd(), libc::POLLOUT);
+    }
+
+    /// The earliest of the deadlines given is kept.
+    pub fn until(&mut self, deadline: Instant) {
+        self.deadline = Some(self.deadline.map_or(deadline, |current| current.min(deadline)));
+    }
+
+    fn watch(&mut self, fd: RawFd, events: libc::c_short) {
+        self.fds.push(libc::pollfd { fd, events, revents: 0 });
+    }
+
+    fn merge(&mut self, other: Wait) {
+        self.fds.extend(other.fds);
+        if let Some(deadline) = other.deadline {
+            self.until(deadline);
+        }
+    }
+
+    /// Blocks until something waited for happens or the deadline passes.
+    fn block(&mut self) {
+        let timeout = match self.deadline {
+            // Rounded up, waking a millisecond early would only spin.
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .as_micros()
+                .div_ceil(1000)
+                .min(i32::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfds for the duration of the call.
+        let polled = unsafe { libc::poll(self.fds.as_mut_ptr(), self.fds.len() as libc::nfds_t, timeout) };
+        if polled < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                warn!("Polling the network backends failed. {}", e);
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+/// Runs `task` on this thread until it's over, for what is done once rather than in the background.
+#[cfg(test)]
+pub fn block_on<T>(mut task: impl FnMut(Instant, &mut Wait) -> Poll<T>) -> T {
+    loop {
+        let mut wait = Wait::default();
+        if let Poll::Ready(result) = task(Instant::now(), &mut wait) {
+            return result;
+        }
+        wait.block();
+    }
+}
+
+/// Wakes a runtime thread to poll its tasks, for what a task waits on that isn't a descriptor, such as a queue the
+/// control loop fills.
+#[derive(Clone)]
+pub struct Waker(Arc<File>);
+
+impl Waker {
+    pub fn wake(&self) {
+        // A full pipe already wakes the thread.
+        let _ = (&*self.0).write(&[0]);
+    }
+}
+
+/// The threads the tasks run on.
+pub struct Runtime {
+    shared: bool,
+    threads: Vec<RuntimeThread>,
+}
+
+struct RuntimeThread {
+    control: Arc<Control>,
+    waker: Waker,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Control {
+    state: Mutex<ControlState>,
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct ControlState {
+    spawned: Vec<(u64, Box<dyn Task>)>,
+    shut_down: Vec<u64>,
+    running: HashSet<u64>,
+    next_id: u64,
+    /// The runtime was dropped, the tasks are dropped with it.
+    stopped: bool,
+}
+
+impl Control {
+    fn lock(&self) -> MutexGuard<'_, ControlState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for Runtime {
+    /// One thread for every task unless built with `shared-io`.
+    fn default() -> Self {
+        match cfg!(feature = "shared-io") {
+            true => Self::shared(),
+            false => Self::per_task(),
+        }
+    }
+}
+
+impl Runtime {
+    /// Every task on one thread, started with the first.
+    pub fn shared() -> Self {
+        Self {
+            shared: true,
+            threads: vec![],
+        }
+    }
+
+    /// A thread for each task.
+    pub fn per_task() -> Self {
+        Self {
+            shared: false,
+            threads: vec![],
+        }
+    }
+
+    /// Starts the task `start` makes, given the waker of the thread it runs on.
+    pub fn spawn<T: Task + 'static>(&mut self, name: &str, start: impl FnOnce(Waker) -> T) -> io::Result<TaskHandle> {
+        if !self.shared || self.threads.is_empty() {
+            let name = if self.shared { "network" } else { name };
+            self.threads.push(RuntimeThread::start(name)?);
+        }
+        let thread = self.threads.last().expect("A thread was just started.");
+        let task = start(thread.waker.clone());
+        let id = {
+            let mut state = thread.control.lock();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.running.insert(id);
+            state.spawned.push((id, Box::new(task)));
+            id
+        };
+        thread.waker.wake();
+        Ok(TaskHandle {
+            id,
+            control: thread.control.clone(),
+            waker: thread.waker.clone(),
+        })
+    }
+}
+
+/// Drops whatever is still running, the tasks that need to finish up are shut down through their handles first.
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        for thread in &mut self.threads {
+            thread.control.lock().stopped = true;
+            thread.control.finished.notify_all();
+            thread.waker.wake();
+            if let Some(thread) = thread.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl RuntimeThread {
+    fn start(name: &str) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: Both ends were just opened, each is owned by one File from here.
+        let (woken, wake) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let control = Arc::new(Control::default());
+        let thread = {
+            let control = control.clone();
+            thread::Builder::new()
+                .name(name.into())
+                .spawn(move || run(&control, woken))?
+        };
+        Ok(Self {
+            control,
+            waker: Waker(Arc::new(wake)),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Polls every task each time one of them may have something to do, there are only a handful.
+fn run(control: &Control, mut woken: File) {
+    let mut tasks: Vec<(u64, Box<dyn Task>)> = vec![];
+    loop {
+        {
+            let mut state = control.lock();
+            if state.stopped {
+                return;
+            }
+            tasks.append(&mut state.spawned);
+            for id in std::mem::take(&mut state.shut_down) {
+                if let Some((_, task)) = tasks.iter_mut().find(|(running, _)| *running == id) {
+                    task.shut_down();
+                }
+            }
+        }
+        let now = Instant::now();
+        let mut wait = Wait::default();
+        wait.readable(&woken);
+        let mut finished = vec![];
+        tasks.retain_mut(|(id, task)| {
+            let mut waits_for = Wait::default();
+            match task.poll(now, &mut waits_for) {
+                Poll::Ready(()) => {
+                    finished.push(*id);
+                    false
+                }
+                Poll::Pending => {
+                    wait.merge(waits_for);
+                    true
+                }
+            }
+        });
+        if !finished.is_empty() {
+            let mut state = control.lock();
+            for id in finished {
+                state.running.remove(&id);
+            }
+            control.finished.notify_all();
+        }
+        wait.block();
+        // Woken or not, every task is polled next.
+        let _ = woken.read(&mut [0; 64]);
+    }
+}
+
+/// Shuts a task down in the order the caller needs, such as the WebSocket clients told about the end before the
+/// Pushgateway group is deleted.
+pub struct TaskHandle {
+    id: u64,
+    control: Arc<Control>,
+    waker: Waker,
+}
+
+impl TaskHandle {
+    /// Waits for the task to finish what it has queued, bounded by the task's own timeouts.
+    pub fn shut_down(self) {
+        self.control.lock().shut_down.push(self.id);
+        self.waker.wake();
+        let state = self.control.lock();
+        drop(
+            self.control
+                .finished
+                .wait_while(state, |state| state.running.contains(&self.id) && !state.stopped)
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+    }
+}
+
+/// Connects without waiting, the connection is made once the stream is writable and `connected` says so.
+pub fn connect(address: &SocketAddr) -> io::Result<TcpStream> {
+    // SAFETY: The sockaddr is zeroed and filled in for its family, its length passed along.
+    unsafe {
+        let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+        let length = match address {
+            SocketAddr::V4(v4) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = v4.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(v6) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = v6.port().to_be();
+                sin6.sin6_addr.s6_addr = v6.ip().octets();
+                sin6.sin6_flowinfo = v6.flowinfo();
+                sin6.sin6_scope_id = v6.scope_id();
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        let fd = libc::socket(
+            storage.ss_family as libc::c_int,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stream = TcpStream::from_raw_fd(fd);
+        if libc::connect(
+            fd,
+            &storage as *const _ as *const libc::sockaddr,
+            length as libc::socklen_t,
+        ) != 0
+        {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINPROGRESS) {
+                return Err(e);
+            }
+        }
+        Ok(stream)
+    }
+}
+
+/// Whether a stream from `connect` is connected yet, an error if connecting failed.
+pub fn connected(stream: &TcpStream) -> io::Result<bool> {
+    if let Some(e) = stream.take_error()? {
+        return Err(e);
+    }
+    match stream.peer_addr() {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    /// Never waits, always has something to do.
+    struct Saturating(Arc<AtomicU64>);
+
+    impl Task for Saturating {
+        fn poll(&mut self, now: Instant, wait: &mut Wait) -> Poll<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            wait.until(now);
+            Poll::Pending
+        }
+    }
+
+    /// Ticks every 10ms, until shut down after a last tick.
+    struct Ticker {
+        ticks: Arc<Mutex<Vec<Instant>>>,
+        next: Instant,
+        stopping: bool,
+    }
+
+    impl Task for Ticker {
+        fn poll(&mut self, now: Instant, wait: &mut Wait) -> Poll<()> {
+            if now >= self.next {
+                self.ticks.lock().unwrap().push(now);
+                if self.stopping {
+                    return Poll::Ready(());
+                }
+                self.next = now + Duration::from_millis(10);
+            }
+            wait.until(self.next);
+            Poll::Pending
+        }
+
+        fn shut_down(&mut self) {
+            self.stopping = true;
+        }
+    }
+
+    fn ticker(ticks: &Arc<Mutex<Vec<Instant>>>) -> Ticker {
+        Ticker {
+            ticks: ticks.clone(),
+            next: Instant::now(),
+            stopping: false,
+        }
+    }
+
+    #[test]
+    fn a_saturating_task_leaves_the_others_on_time() {
+        let mut runtime = Runtime::shared();
+        let polls = Arc::new(AtomicU64::new(0));
+        let saturating = runtime.spawn("saturating", |_| Saturating(polls.clone())).unwrap();
+        let ticks = Arc::new(Mutex::new(vec![]));
+        let ticking = runtime.spawn("ticker", |_| ticker(&ticks)).unwrap();
+        assert_eq!(1, runtime.threads.len());
+        thread::sleep(Duration::from_millis(200));
+        ticking.shut_down();
+        let ticks = ticks.lock().unwrap();
+        assert!(ticks.len() >= 10, "{} ticks", ticks.len());
+        let late = ticks.windows(2).map(|pair| pair[1] - pair[0]).max().unwrap();
+        assert!(late < Duration::from_millis(50), "{:?}", late);
+        assert!(polls.load(Ordering::Relaxed) > ticks.len() as u64);
+        // Its thread goes on with the saturating task.
+        let before = polls.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(20));
+        assert!(polls.load(Ordering::Relaxed) > before);
+        drop(saturating);
+    }
+
+    #[test]
+    fn a_thread_for_each_task_without_sharing() {
+        let mut runtime = Runtime::per_task();
+        let ticks = Arc::new(Mutex::new(vec![]));
+        let first = runtime.spawn("first", |_| ticker(&ticks)).unwrap();
+        let second = runtime.spawn("second", |_| ticker(&ticks)).unwrap();
+        assert_eq!(2, runtime.threads.len());
+        thread::sleep(Duration::from_millis(50));
+        first.shut_down();
+        second.shut_down();
+        assert!(ticks.lock().unwrap().len() >= 4);
+    }
+
+    #[test]
+    fn connects_without_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = connect(&listener.local_addr().unwrap()).unwrap();
+        let made = block_on(|now, wait| match connected(&stream).unwrap() {
+            true => Poll::Ready(true),
+            false => {
+                wait.writable(&stream);
+                wait.until(now + Duration::from_secs(5));
+                Poll::Pending
+            }
+        });
+        assert!(made);
+        assert!(listener.accept().is_ok());
+
+        // Nobody listening on a port just closed.
+        let closed = TcpListener::bind("[::1]:0")
+            .or_else(|_| TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let address = closed.local_addr().unwrap();
+        drop(closed);
+        let stream = connect(&address).unwrap();
+        let refused = block_on(|now, wait| match connected(&stream) {
+            Err(e) => Poll::Ready(e),
+            Ok(_) => {
+                wait.writable(&stream);
+                wait.until(now + Duration::from_millis(100));
+                Poll::Pending
+            }
+        });
+        assert_eq!(ErrorKind::ConnectionRefused, refused.kind());
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use std::{ffi::CString, io, mem::MaybeUninit, os::unix::ffi::OsStrExt, path::PathBuf, ptr};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Identity {
+    pub user: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+pub trait Credentials {
+    fn set_groups(&mut self, gid: libc::gid_t) -> io::Result<()>;
+    fn set_gid(&mut self, gid: libc::gid_t) -> io::Result<()>;
+    fn set_uid(&mut self, uid: libc::uid_t) -> io::Result<()>;
+    /// Real and effective (uid, euid, gid, egid).
+    fn ids(&self) -> (libc::uid_t, libc::uid_t, libc::gid_t, libc::gid_t);
+}
+
+pub struct ProcessCredentials;
+
+impl Credentials for ProcessCredentials {
+    fn set_groups(&mut self, gid: libc::gid_t) -> io::Result<()> {
+        check(unsafe { libc::setgroups(1, &gid) })
+    }
+
+    fn set_gid(&mut self, gid: libc::gid_t) -> io::Result<()> {
+        check(unsafe { libc::setgid(gid) })
+    }
+
+    fn set_uid(&mut self, uid: libc::uid_t) -> io::Result<()> {
+        check(unsafe { libc::setuid(uid) })
+    }
+
+    fn ids(&self) -> (libc::uid_t, libc::uid_t, libc::gid_t, libc::gid_t) {
+        unsafe { (libc::getuid(), libc::geteuid(), libc::getgid(), libc::getegid()) }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Chown the state files to the target identity and permanently switch the process to it. Must run after
+/// the pin, state directory, and any sockets have been acquired.
+pub fn drop_privileges(spec: &str, state_paths: &[PathBuf]) -> Result<Identity> {
+    let identity = resolve(spec)?;
+    chown_all(state_paths, &identity)?;
+    drop_to(&mut ProcessCredentials, &identity)?;
+    info!(
+        "Dropped privileges to {} (uid {} gid {}).",
+        identity.user, identity.uid, identity.gid
+    );
+    Ok(identity)
+}
+
+/// Resolve `user[:group]`. Without a group the user's primary group is used.
+pub fn resolve(spec: &str) -> Result<Identity> {
+    let (user, group) = match spec.find(':') {
+        Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+        None => (spec, None),
+    };
+    if user.is_empty() || group == Some("") {
+        bail!("Invalid --run-as value '{}', expected user:group.", spec);
+    }
+    let (uid, primary_gid) = lookup_user(user)?.ok_or_else(|| anyhow!("User '{}' does not exist.", user))?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?.ok_or_else(|| anyhow!("Group '{}' does not exist.", group))?,
+        None => primary_gid,
+    };
+    Ok(Identity {
+        user: user.to_string(),
+        uid,
+        gid,
+    })
+}
+
+fn lookup_user(name: &str) -> Result<Option<(libc::uid_t, libc::gid_t)>> {
+    let name = CString::new(name).context("User name contains a NUL.")?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry = MaybeUninit::<libc::passwd>::uninit();
+    let mut result: *mut libc::passwd = ptr::null_mut();
+    let rc = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            entry.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc)).context("Failed looking up user.");
+    }
+    Ok(match result.is_null() {
+        true => None,
+        false => {
+            let entry = unsafe { entry.assume_init() };
+            Some((entry.pw_uid, entry.pw_gid))
+        }
+    })
+}
+
+fn lookup_group(name: &str) -> Result<Option<libc::gid_t>> {
+    let name = CString::new(name).context("Group name contains a NUL.")?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry = MaybeUninit::<libc::group>::uninit();
+    let mut result: *mut libc::group = ptr::null_mut();
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            entry.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc)).context("Failed looking up group.");
+    }
+    Ok(match result.is_null() {
+        true => None,
+        false => Some(unsafe { entry.assume_init() }.gr_gid),
+    })
+}
+
+/// Chown every existing path. Paths that don't exist yet are skipped, they will be created by the target user
+/// inside the (chowned) state directory.
+pub fn chown_all(paths: &[PathBuf], identity: &Identity) -> Result<()> {
+    for path in paths {
+        let c_path = CString::new(path.as_os_str().as_bytes()).context("Path contains a NUL.")?;
+        if unsafe { libc::chown(c_path.as_ptr(), identity.uid, identity.gid) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e).with_context(|| format!("Failed to chown {}.", path.display()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Supplementary groups and gid must be dropped while still privileged, so the order is fixed:
+/// setgroups, setgid, then setuid.
+pub fn drop_to(credentials: &mut impl Credentials, identity: &Identity) -> Result<()> {
+    credentials
+        .set_groups(identity.gid)
+        .context("Failed to drop supplementary groups.")?;
+    credentials.set_gid(identity.gid).context("Failed to set gid.")?;
+    credentials.set_uid(identity.uid).context("Failed to set uid.")?;
+
+    let (uid, euid, gid, egid) = credentials.ids();
+    if uid != identity.uid || euid != identity.uid || gid != identity.gid || egid != identity.gid {
+        bail!(
+            "Identity after dropping privileges is uid {}/{} gid {}/{}, expected uid {} gid {}.",
+            uid,
+            euid,
+            gid,
+            egid,
+            identity.uid,
+            identity.gid
+        );
+    }
+    if identity.uid != 0 && credentials.set_uid(0).is_ok() {
+        bail!("Privileges could be re-acquired after dropping them.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    #[derive(Default)]
+    struct MockCredentials {
+        calls: Vec<String>,
+        ids: (libc::uid_t, libc::uid_t, libc::gid_t, libc::gid_t),
+        fail_on: Option<&'static str>,
+        allow_regain: bool,
+    }
+
+    impl MockCredentials {
+        fn record(&mut self, call: &'static str, id: u32) -> io::Result<()> {
+            self.calls.push(format!("{}({})", call, id));
+            match self.fail_on == Some(call) {
+                true => Err(io::Error::from_raw_os_error(libc::EPERM)),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl Credentials for MockCredentials {
+        fn set_groups(&mut self, gid: libc::gid_t) -> io::Result<()> {
+            self.record("setgroups", gid)
+        }
+
+        fn set_gid(&mut self, gid: libc::gid_t) -> io::Result<()> {
+            self.record("setgid", gid)?;
+            self.ids.2 = gid;
+            self.ids.3 = gid;
+            Ok(())
+        }
+
+        fn set_uid(&mut self, uid: libc::uid_t) -> io::Result<()> {
+            if uid == 0 && self.ids.1 != 0 && !self.allow_regain {
+                return self
+                    .record("setuid", uid)
+                    .and(Err(io::Error::from_raw_os_error(libc::EPERM)));
+            }
+            self.record("setuid", uid)?;
+            self.ids.0 = uid;
+            self.ids.1 = uid;
+            Ok(())
+        }
+
+        fn ids(&self) -> (libc::uid_t, libc::uid_t, libc::gid_t, libc::gid_t) {
+            self.ids
+        }
+    }
+
+    fn picool() -> Identity {
+        Identity {
+            user: "picool".into(),
+            uid: 1001,
+            gid: 997,
+        }
+    }
+
+    #[test]
+    fn drops_in_order_and_verifies() {
+        let mut credentials = MockCredentials::default();
+        drop_to(&mut credentials, &picool()).unwrap();
+        assert_eq!(
+            vec!["setgroups(997)", "setgid(997)", "setuid(1001)", "setuid(0)"],
+            credentials.calls
+        );
+    }
+
+    #[test]
+    fn failed_setgid_aborts_before_setuid() {
+        let mut credentials = MockCredentials {
+            fail_on: Some("setgid"),
+            ..Default::default()
+        };
+        assert!(drop_to(&mut credentials, &picool()).is_err());
+        assert_eq!(vec!["setgroups(997)", "setgid(997)"], credentials.calls);
+    }
+
+    #[test]
+    fn regainable_privileges_are_an_error() {
+        let mut credentials = MockCredentials {
+            allow_regain: true,
+            ..Default::default()
+        };
+        let e = drop_to(&mut credentials, &picool()).unwrap_err();
+        assert!(e.to_string().contains("re-acquired"));
+    }
+
+    #[test]
+    fn unknown_user_is_a_clear_error() {
+        let e = resolve("no-such-picool-user:root").unwrap_err();
+        assert_eq!("User 'no-such-picool-user' does not exist.", e.to_string());
+        assert!(resolve("root:no-such-picool-group").is_err());
+        assert!(resolve(":root").is_err());
+    }
+
+    #[test]
+    fn resolves_primary_group() {
+        let root = resolve("root").unwrap();
+        assert_eq!((0, 0), (root.uid, root.gid));
+    }
+
+    #[test]
+    fn chown_skips_missing_files() {
+        let dir = TempDir::new();
+        let existing = dir.path().join("comp_28-00");
+        fs::write(&existing, "0.0 0.0").unwrap();
+        let metadata = fs::metadata(&existing).unwrap();
+        let me = Identity {
+            user: "me".into(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        };
+        chown_all(&[existing.clone(), dir.path().join("last_off_28-00")], &me).unwrap();
+        assert_eq!(me.uid, fs::metadata(&existing).unwrap().uid());
+    }
+}
@@ -1,141 +1,1322 @@
-use crate::{RestoredPowerState, World, WorldState};
-use anyhow::{anyhow, Context, Result};
-use log::warn;
-use rppal::gpio::{Gpio, OutputPin};
+use crate::{
+    actuation::{ActuationDeferrals, ActuationGuard},
+    cycle_history,
+    exec_sensor::{ExecConfig, ExecSensor},
+    gpio::{self, GpioBackend, GpioConfig, OutputLine},
+    hwmon::{HwmonConfig, HwmonSensor},
+    ina219::{CurrentSpec, Ina219},
+    instance_lock::InstanceLock,
+    modbus::{ModbusConfig, ModbusSensor},
+    self_test::SelfTestWorld,
+    shutdown::Shutdown,
+    startup_error::StartupError,
+    store_verify::VerifiedStore,
+    w1::{self, read_w1, BulkRead, Resolution, SensorFormat, W1Device},
+    world::{Clock, CompositeWorld, Sensor, Store, Switch, SystemClock},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use picool_core::{
+    boost::Boost,
+    controller::CycleRecord,
+    energy::EnergyTotals,
+    failover::Output,
+    heartbeat::{format_heartbeat, HeartbeatStatus},
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{Celsius, DegreesDelta, Millidegrees},
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals, RestoredPowerState,
+};
 use std::{
+    env,
     ffi::OsString,
-    fs,
+    fs::{self, DirBuilder},
     io::{self, ErrorKind},
-    path::PathBuf,
-    thread::sleep,
+    ops::Range,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
     time::Duration,
-    time::Instant,
-    time::SystemTime,
+    time::{Instant, SystemTime},
 };
 
-const PICOOL_PERSIST_BASE_PATH: &str = "/var/lib/picool";
+pub const PICOOL_PERSIST_BASE_PATH: &str = "/var/lib/picool";
+/// Set by systemd for `StateDirectory=`, colon separated when the unit names several.
+const STATE_DIRECTORY_ENV: &str = "STATE_DIRECTORY";
+const STATE_DIR_MODE: u32 = 0o750;
+const STATE_DIR_PROBE: &str = ".picool_probe";
 const LAST_OFF_TRANSITION_PERSIST_FILE_PREFIX: &str = "last_off_";
 const COMPENSATION_PERSIST_FILE_PREFIX: &str = "comp_";
+const COOLING_RATES_PERSIST_FILE_PREFIX: &str = "cooling_";
+const ENERGY_PERSIST_FILE_PREFIX: &str = "energy_";
+const RUNTIME_PERSIST_FILE_PREFIX: &str = "runtime_";
+const STARTS_PERSIST_FILE_PREFIX: &str = "starts_";
+const BOOST_PERSIST_FILE_PREFIX: &str = "boost_";
+const INTERVALS_PERSIST_FILE_PREFIX: &str = "intervals_";
+const OVERSHOOTS_PERSIST_FILE_PREFIX: &str = "overshoot_";
+const REST_PERSIST_FILE_PREFIX: &str = "rest_";
+const SHED_PERSIST_FILE_PREFIX: &str = "shed_";
+const EXTREMES_PERSIST_FILE_PREFIX: &str = "extremes_";
+const CYCLES_PERSIST_FILE_PREFIX: &str = "cycles_";
+const FAILOVER_PERSIST_FILE_PREFIX: &str = "failover_";
+const CONTROL_SOCKET_FILE_PREFIX: &str = "control_";
+const HEARTBEAT_FILE_PREFIX: &str = "heartbeat_";
+const CLOCK_SKEW_FILE_PREFIX: &str = "clock_skew_";
+/// 2024-01-01, no last off transition persisted by a clock that was set is older.
+pub const CLOCK_FLOOR: Duration = Duration::from_secs(1_704_067_200);
 
-pub struct RealWorld {
-    temperature_sensor_path: PathBuf,
-    power_state: OutputPin,
-    last_off_persist_path: PathBuf,
-    compensation_persist_path: PathBuf,
+/// Where the chamber temperature comes from, a w1 sysfs file unless prefixed with `modbus:`, `exec:` or `hwmon:`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SensorSpec {
+    W1(PathBuf),
+    Modbus(ModbusConfig),
+    Exec(ExecConfig),
+    Hwmon(HwmonConfig),
 }
 
-impl RealWorld {
-    pub fn new(temperature_sensor_path: PathBuf, power_state_pin_number: u8) -> Result<Self> {
-        let gpio = Gpio::new()?;
-        let pin = gpio.get(power_state_pin_number)?.into_output();
-
-        let sensor_name = temperature_sensor_path
-            .parent()
-            .and_then(|p| p.file_name())
-            .context("Invalid temperature path.")?;
-        let picool_persist_path = PathBuf::from(PICOOL_PERSIST_BASE_PATH);
-        let mut last_off_file_name = OsString::from(LAST_OFF_TRANSITION_PERSIST_FILE_PREFIX);
-        last_off_file_name.push(sensor_name);
-        let mut compensation_file_name = OsString::from(COMPENSATION_PERSIST_FILE_PREFIX);
-        compensation_file_name.push(sensor_name);
+impl SensorSpec {
+    /// Suffixes the lock and the state files, so each sensor keeps its own.
+    pub fn name(&self) -> Result<String, StartupError> {
+        match self {
+            SensorSpec::W1(path) => w1::device_id(path).ok_or_else(|| StartupError::InvalidSensorPath(path.clone())),
+            SensorSpec::Modbus(config) => Ok(format!("modbus_{}", config.name())),
+            SensorSpec::Exec(config) => Ok(format!("exec_{}", config.name())),
+            SensorSpec::Hwmon(config) => Ok(format!("hwmon_{}", config.name())),
+        }
+    }
+
+    /// The lock held for the sensor. Only one master may poll a Modbus bus, whichever slave it asks.
+    pub fn lock_name(&self) -> Result<String, StartupError> {
+        match self {
+            SensorSpec::W1(_) | SensorSpec::Exec(_) | SensorSpec::Hwmon(_) => Ok(format!("sensor_{}", self.name()?)),
+            SensorSpec::Modbus(config) => Ok(format!(
+                "serial_{}",
+                config.device.file_name().unwrap_or_default().to_string_lossy()
+            )),
+        }
+    }
+
+    fn open(self) -> Result<ChamberSensor, StartupError> {
+        match self {
+            SensorSpec::W1(path) => {
+                fs::File::open(&path).map_err(|e| StartupError::from_sensor(path.clone(), e))?;
+                W1Device::new(path.clone())
+                    .map(ChamberSensor::W1)
+                    .ok_or(StartupError::InvalidSensorPath(path))
+            }
+            SensorSpec::Modbus(config) => {
+                let device = config.device.clone();
+                ModbusSensor::open(config)
+                    .map(ChamberSensor::Modbus)
+                    .map_err(|e| StartupError::from_serial(device, e))
+            }
+            SensorSpec::Exec(config) => {
+                let instance = SensorSpec::Exec(config.clone()).name()?;
+                let command = config.command.clone();
+                ExecSensor::open(config, instance)
+                    .map(ChamberSensor::Exec)
+                    .map_err(|e| StartupError::from_command(command, e))
+            }
+            SensorSpec::Hwmon(config) => {
+                let spec = config.to_string();
+                HwmonSensor::open(config)
+                    .map(ChamberSensor::Hwmon)
+                    .map_err(|e| StartupError::HwmonUnresolved(spec, e))
+            }
+        }
+    }
+}
+
+impl FromStr for SensorSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(command) = s.strip_prefix("exec:") {
+            return Ok(SensorSpec::Exec(command.parse()?));
+        }
+        if let Some(hwmon) = s.strip_prefix("hwmon:") {
+            return Ok(SensorSpec::Hwmon(hwmon.parse()?));
+        }
+        match s.strip_prefix("modbus:") {
+            Some(modbus) => Ok(SensorSpec::Modbus(modbus.parse()?)),
+            None => Ok(SensorSpec::W1(PathBuf::from(s))),
+        }
+    }
+}
+
+pub enum ChamberSensor {
+    W1(W1Device),
+    Modbus(ModbusSensor),
+    Exec(ExecSensor),
+    Hwmon(HwmonSensor),
+}
+
+/// The chamber sensor, and optionally a w1 sensor outside the chamber and a current sensor on the compressor.
+pub struct RealSensor {
+    chamber: ChamberSensor,
+    ambient: Option<PathBuf>,
+    current: Option<Ina219>,
+    /// Converts the chamber and ambient sensors together when they share a bus.
+    bulk: Option<BulkRead>,
+}
+
+impl Sensor for RealSensor {
+    fn get_temperature(&self) -> Result<Celsius, SensorError> {
+        // The ambient is read right after the chamber, its conversion is done by then.
+        if let Some(bulk) = &self.bulk {
+            bulk.trigger();
+        }
+        match &self.chamber {
+            ChamberSensor::W1(device) => device.read(),
+            ChamberSensor::Modbus(sensor) => sensor.read_temperature(),
+            ChamberSensor::Exec(sensor) => sensor.read_temperature(),
+            ChamberSensor::Hwmon(sensor) => sensor.read_temperature(),
+        }
+    }
 
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+        self.ambient
+            .as_deref()
+            .map(|path| read_w1(path, SensorFormat::Millidegrees))
+    }
+
+    fn get_current(&self) -> Option<Result<f32, SensorError>> {
+        self.current.as_ref().map(Ina219::read_current)
+    }
+
+    fn reinitialize(&mut self) -> Result<(), SensorError> {
+        match &mut self.chamber {
+            // The w1 sysfs file is reopened on every read, so this only confirms the device came back.
+            ChamberSensor::W1(device) => fs::File::open(device.path())
+                .map(|_| ())
+                .map_err(|e| SensorError::from_io(device.path().to_path_buf(), e)),
+            ChamberSensor::Modbus(sensor) => sensor.reinitialize(),
+            ChamberSensor::Exec(sensor) => sensor.reinitialize(),
+            ChamberSensor::Hwmon(sensor) => sensor.reinitialize(),
+        }
+    }
+
+    fn reacquire(&mut self) -> Option<String> {
+        match &mut self.chamber {
+            ChamberSensor::W1(device) => device.rediscover(),
+            ChamberSensor::Modbus(_) | ChamberSensor::Exec(_) | ChamberSensor::Hwmon(_) => None,
+        }
+    }
+}
+
+pub struct GpioSwitch {
+    backend: Box<dyn GpioBackend>,
+    pin: Box<dyn OutputLine>,
+    /// Spaces the relay's actuations, the fan can't hurt the compressor.
+    guard: ActuationGuard,
+    fan: Option<Box<dyn OutputLine>>,
+    /// The second compressor, spaced like the first.
+    stage2: Option<(Box<dyn OutputLine>, ActuationGuard)>,
+    /// The glycol pump, read back before the compressor starts against it.
+    pump: Option<Box<dyn OutputLine>>,
+    /// A second relay in parallel with the first, switching the compressor once the primary failed over.
+    backup: Option<Box<dyn OutputLine>>,
+    output: Output,
+    spacing: Duration,
+}
+
+impl GpioSwitch {
+    /// Fails without the configured GPIO device, so the binary still builds and runs the demo world anywhere.
+    pub fn acquire(config: &GpioConfig, pin_number: u8) -> Result<Self, StartupError> {
+        let backend = gpio::backend(config)?;
         Ok(Self {
-            temperature_sensor_path,
-            power_state: pin,
-            last_off_persist_path: picool_persist_path.join(last_off_file_name),
-            compensation_persist_path: picool_persist_path.join(compensation_file_name),
+            pin: backend.output(pin_number)?,
+            backend,
+            guard: ActuationGuard::new(config.spacing),
+            fan: None,
+            stage2: None,
+            pump: None,
+            backup: None,
+            output: Output::Primary,
+            spacing: config.spacing,
         })
     }
+}
+
+impl Switch for GpioSwitch {
+    /// Only a change actuates the relay and is spaced from the last one. Off drives both relays off, a primary that
+    /// came back to life mustn't keep the compressor running.
+    fn set_power_state(&mut self, state: bool) {
+        let guard = &mut self.guard;
+        let (pin, standby) = match (self.output, &mut self.backup) {
+            (Output::Backup, Some(backup)) => (backup, Some(&mut self.pin)),
+            (_, backup) => (&mut self.pin, backup.as_mut()),
+        };
+        match pin.is_active() == state {
+            true => pin.set_active(state),
+            false => guard.actuate(Instant::now, thread::sleep, || pin.set_active(state)),
+        }
+        if let Some(standby) = standby.filter(|_| !state) {
+            standby.set_active(false)
+        }
+    }
+
+    fn set_fan_state(&mut self, state: bool) {
+        if let Some(fan) = &mut self.fan {
+            fan.set_active(state)
+        }
+    }
+
+    fn set_stage2_state(&mut self, state: bool) {
+        if let Some((pin, guard)) = &mut self.stage2 {
+            match pin.is_active() == state {
+                true => pin.set_active(state),
+                false => guard.actuate(Instant::now, thread::sleep, || pin.set_active(state)),
+            }
+        }
+    }
+
+    fn set_pump_state(&mut self, state: bool) {
+        if let Some(pump) = &mut self.pump {
+            pump.set_active(state)
+        }
+    }
+
+    fn pump_state(&self) -> Option<bool> {
+        self.pump.as_ref().map(|pump| pump.is_active())
+    }
+
+    /// A running compressor moves to the new relay, the old one is released first and the new one is spaced from
+    /// it.
+    fn select_output(&mut self, output: Output) {
+        let on = self.is_on();
+        let backup = match &mut self.backup {
+            Some(backup) if self.output != output => backup,
+            _ => return,
+        };
+        match self.output {
+            Output::Primary => self.pin.set_active(false),
+            Output::Backup => backup.set_active(false),
+        }
+        self.output = output;
+        if on {
+            self.set_power_state(true);
+        }
+    }
 
+    /// With the cdev backend a relay is never found on, the line is claimed inactive. Either relay counts.
+    fn is_on(&self) -> bool {
+        self.pin.is_active() || self.backup.as_ref().is_some_and(|backup| backup.is_active())
+    }
+}
+
+/// One file per value under the persist directory, suffixed with the sensor name.
+pub struct FileStore {
+    persist_path: PathBuf,
+    last_off_persist_path: PathBuf,
+    compensation_persist_path: PathBuf,
+    cooling_rates_persist_path: PathBuf,
+    energy_persist_path: PathBuf,
+    runtime_persist_path: PathBuf,
+    starts_persist_path: PathBuf,
+    boost_persist_path: PathBuf,
+    intervals_persist_path: PathBuf,
+    overshoots_persist_path: PathBuf,
+    rest_persist_path: PathBuf,
+    shed_persist_path: PathBuf,
+    extremes_persist_path: PathBuf,
+    cycles_persist_path: PathBuf,
+    failover_persist_path: PathBuf,
+    /// How many cycles the history keeps.
+    cycle_history: usize,
+    control_socket_path: PathBuf,
+    heartbeat_path: PathBuf,
+    clock_skew_path: PathBuf,
+    /// Last off transitions before this are rejected.
+    clock_floor: Duration,
+    /// The wall clock since the epoch, the last off transition is timed by.
+    clock: fn() -> Duration,
+    // Held for the life of the store.
+    locks: Vec<InstanceLock>,
+}
+
+impl Store for FileStore {
     fn restore_power_state(&self) -> Result<RestoredPowerState> {
-        if self.power_state.is_set_high() {
-            return Ok(RestoredPowerState::CurrentlyOn);
+        let off_at = match read_last_off(&self.last_off_persist_path)? {
+            StoredLastOff::Missing => return Ok(RestoredPowerState::OffForUnknownDuration),
+            StoredLastOff::At(off_at) => off_at,
+            StoredLastOff::Written { data, at } => {
+                warn!(
+                    "Stored last off transition '{}' is unreadable, using when the file was written instead.",
+                    data
+                );
+                at
+            }
+        };
+        let since_epoch = (self.clock)();
+        let rfc3339 = |since_epoch| humantime::format_rfc3339_seconds(SystemTime::UNIX_EPOCH + since_epoch);
+        Ok(match classify_last_off(off_at, since_epoch, self.clock_floor) {
+            LastOff::Ago(off_for) => RestoredPowerState::OffFor(off_for),
+            LastOff::Ahead(by) => {
+                // Counted before logging, the arguments aren't evaluated when the warning is filtered out.
+                let times = self.count_clock_skew();
+                warn!(
+                    "Last off transition at {} is {}s ahead of the clock at {}, the relay has been off for an unknown \
+                     duration. Clock trouble {} times so far, a Pi without a real time clock starts from a stale time \
+                     until it syncs.",
+                    rfc3339(off_at),
+                    by.as_secs(),
+                    rfc3339(since_epoch),
+                    times
+                );
+                RestoredPowerState::OffForUnknownDuration
+            }
+            LastOff::BeforeFloor => {
+                let times = self.count_clock_skew();
+                warn!(
+                    "Last off transition at {} is before {}, the relay has been off for an unknown duration. Clock \
+                     trouble {} times so far, a clock that wasn't set when it was written?",
+                    rfc3339(off_at),
+                    rfc3339(self.clock_floor),
+                    times
+                );
+                RestoredPowerState::OffForUnknownDuration
+            }
+        })
+    }
+
+    fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+        read_compensation(&self.compensation_persist_path)
+            .map_err(|e| {
+                warn!("Restoring compensation failed: {}", e);
+                e
+            })
+            .unwrap_or_default()
+    }
+
+    fn restore_cooling_rates(&self) -> Vec<f32> {
+        // A missing or damaged baseline just means relearning it.
+        fs::read_to_string(&self.cooling_rates_persist_path)
+            .map(|d| d.split_whitespace().filter_map(|r| r.parse().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    fn restore_energy(&self) -> EnergyTotals {
+        let data = match fs::read_to_string(&self.energy_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return EnergyTotals::default(),
+            Err(e) => {
+                warn!("Restoring energy totals failed: {}", e);
+                return EnergyTotals::default();
+            }
+        };
+        parse_energy(&data).unwrap_or_else(|| {
+            warn!("Restoring energy totals failed: unparseable '{}'.", data.trim());
+            EnergyTotals::default()
+        })
+    }
+
+    fn restore_runtime(&self) -> Duration {
+        let data = match fs::read_to_string(&self.runtime_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Duration::from_secs(0),
+            Err(e) => {
+                warn!("Restoring compressor runtime failed: {}", e);
+                return Duration::from_secs(0);
+            }
+        };
+        match data.trim().parse() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => {
+                warn!("Restoring compressor runtime failed: unparseable '{}'.", data.trim());
+                Duration::from_secs(0)
+            }
         }
-        let data = fs::read_to_string(&self.last_off_persist_path);
-        if let Err(e) = &data {
-            if e.kind() == io::ErrorKind::NotFound {
-                return Ok(RestoredPowerState::OffForUnknownDuration);
+    }
+
+    fn restore_starts(&self) -> StartCounts {
+        let data = match fs::read_to_string(&self.starts_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return StartCounts::default(),
+            Err(e) => {
+                warn!("Restoring start counts failed: {}", e);
+                return StartCounts::default();
+            }
+        };
+        parse_starts(&data).unwrap_or_else(|| {
+            warn!("Restoring start counts failed: unparseable '{}'.", data.trim());
+            StartCounts::default()
+        })
+    }
+
+    /// What is left of a boost against the wall clock, one that ran out while stopped is over.
+    fn restore_boost(&self) -> Option<Boost> {
+        let data = match fs::read_to_string(&self.boost_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Restoring boost failed: {}", e);
+                return None;
+            }
+        };
+        let (band, until) = match parse_boost(&data) {
+            Some(boost) => boost,
+            None => {
+                warn!("Restoring boost failed: unparseable '{}'.", data.trim());
+                return None;
             }
+        };
+        let remaining = until.saturating_sub(sec_since_epoch());
+        if remaining == Duration::from_secs(0) {
+            info!("Boost to {} to {} ran out while stopped.", band.start, band.end);
+            return None;
         }
+        Some(Boost { band, remaining })
+    }
+
+    fn restore_intervals(&self) -> Option<MinimumIntervals> {
+        let data = match fs::read_to_string(&self.intervals_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Restoring tuned intervals failed: {}", e);
+                return None;
+            }
+        };
+        parse_intervals(&data).or_else(|| {
+            warn!("Restoring tuned intervals failed: unparseable '{}'.", data.trim());
+            None
+        })
+    }
+
+    fn restore_overshoots(&self) -> Overshoots {
+        // Like the cooling baseline, a missing or damaged history is just relearned.
+        fs::read_to_string(&self.overshoots_persist_path)
+            .map(|d| parse_overshoots(&d))
+            .unwrap_or_default()
+    }
+
+    /// What is left of a rest against the wall clock, like a boost.
+    fn restore_rest(&self) -> Option<RestPeriod> {
+        let data = match fs::read_to_string(&self.rest_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Restoring rest failed: {}", e);
+                return None;
+            }
+        };
+        let (run, until) = match parse_rest(&data) {
+            Some(rest) => rest,
+            None => {
+                warn!("Restoring rest failed: unparseable '{}'.", data.trim());
+                return None;
+            }
+        };
+        let remaining = until.saturating_sub(sec_since_epoch());
+        if remaining == Duration::from_secs(0) {
+            info!("Rest after a {}m run ran out while stopped.", run.as_secs() / 60);
+            return None;
+        }
+        Some(RestPeriod { run, remaining })
+    }
+
+    /// Both ends against the wall clock, a shed that ran out while stopped comes back spent.
+    fn restore_shed(&self) -> Option<ShedPeriod> {
+        let data = match fs::read_to_string(&self.shed_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Restoring shed failed: {}", e);
+                return None;
+            }
+        };
+        let (started, until) = match parse_shed(&data) {
+            Some(shed) => shed,
+            None => {
+                warn!("Restoring shed failed: unparseable '{}'.", data.trim());
+                return None;
+            }
+        };
+        let now = sec_since_epoch();
+        Some(ShedPeriod {
+            since: now.saturating_sub(started),
+            remaining: until.saturating_sub(now),
+        })
+    }
 
+    /// How long the cycle has been going and how old the checkpoint is against the wall clock, whether it is still
+    /// worth resuming is for the controller.
+    fn restore_extremes(&self) -> Option<CycleExtremes> {
+        let data = match fs::read_to_string(&self.extremes_persist_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Restoring cycle extremes failed: {}", e);
+                return None;
+            }
+        };
+        let (on, min, max, started, checkpointed) = match parse_extremes(&data) {
+            Some(extremes) => extremes,
+            None => {
+                warn!("Restoring cycle extremes failed: unparseable '{}'.", data.trim());
+                return None;
+            }
+        };
         let since_epoch = sec_since_epoch();
-        data.context("Failed reading last off transition storage.")
-            .and_then(|d| d.parse().context("Failed parsing stored last off transition."))
-            .map(|last_transit_sec_since_epoch| {
-                RestoredPowerState::OffFor(
-                    since_epoch
-                        .checked_sub(Duration::from_secs(last_transit_sec_since_epoch))
-                        .unwrap_or_default(),
-                )
-            })
+        Some(CycleExtremes {
+            on,
+            min: min.celsius(),
+            max: max.celsius(),
+            running: since_epoch.saturating_sub(started),
+            age: since_epoch.saturating_sub(checkpointed),
+        })
+    }
+
+    /// Only the presence of the file counts, whatever is in it.
+    fn restore_failover(&self) -> bool {
+        match fs::metadata(&self.failover_persist_path) {
+            Ok(_) => true,
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => {
+                // Starting on the backup is the safe guess, the primary already failed once.
+                warn!("Restoring failover failed, assuming the primary is faulted: {}", e);
+                true
+            }
+        }
+    }
+
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+        // No fsync, only the mtime and contents matter to a watchdog and this runs every poll.
+        Ok(fs::write(
+            &self.heartbeat_path,
+            format_heartbeat(sec_since_epoch(), status),
+        )?)
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        let since_epoch = (self.clock)();
+        Ok(fs::write(
+            &self.last_off_persist_path,
+            since_epoch.as_secs().to_string(),
+        )?)
+    }
+
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+        Ok(fs::write(
+            &self.compensation_persist_path,
+            format!("{} {}", cooling.millidegrees().0, heating.millidegrees().0),
+        )?)
+    }
+
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+        let data = rates.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(" ");
+        Ok(fs::write(&self.cooling_rates_persist_path, data)?)
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        Ok(fs::write(
+            &self.energy_persist_path,
+            format!(
+                "{} {} {} {}",
+                totals.day, totals.today, totals.yesterday, totals.lifetime
+            ),
+        )?)
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        Ok(fs::write(&self.runtime_persist_path, runtime.as_secs().to_string())?)
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        let mut data = format!("{} {} {}", counts.lifetime, counts.day, counts.today);
+        for day in &counts.history {
+            match day {
+                Some(starts) => data.push_str(&format!(" {}", starts)),
+                None => data.push_str(" -"),
+            }
+        }
+        Ok(fs::write(&self.starts_persist_path, data)?)
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        let boost = match boost {
+            Some(boost) => boost,
+            None => {
+                return match fs::remove_file(&self.boost_persist_path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                    _ => Ok(()),
+                }
+            }
+        };
+        let until = sec_since_epoch() + boost.remaining;
+        Ok(fs::write(
+            &self.boost_persist_path,
+            format!(
+                "{} {} {}",
+                boost.band.start.millidegrees().0,
+                boost.band.end.millidegrees().0,
+                until.as_secs()
+            ),
+        )?)
+    }
+
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        match intervals {
+            Some(intervals) => Ok(fs::write(
+                &self.intervals_persist_path,
+                format!("{} {}", intervals.on.as_secs(), intervals.off.as_secs()),
+            )?),
+            None => match fs::remove_file(&self.intervals_persist_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+        let line = |misses: &[DegreesDelta]| {
+            let misses: Vec<String> = misses.iter().map(|m| m.millidegrees().0.to_string()).collect();
+            misses.join(" ")
+        };
+        Ok(fs::write(
+            &self.overshoots_persist_path,
+            format!("{}\n{}\n", line(&overshoots.over), line(&overshoots.under)),
+        )?)
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        match rest {
+            Some(rest) => Ok(fs::write(
+                &self.rest_persist_path,
+                format!(
+                    "{} {}",
+                    rest.run.as_secs(),
+                    (sec_since_epoch() + rest.remaining).as_secs()
+                ),
+            )?),
+            None => match fs::remove_file(&self.rest_persist_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        match shed {
+            Some(shed) => {
+                let now = sec_since_epoch();
+                Ok(fs::write(
+                    &self.shed_persist_path,
+                    format!(
+                        "{} {}",
+                        now.saturating_sub(shed.since).as_secs(),
+                        (now + shed.remaining).as_secs()
+                    ),
+                )?)
+            }
+            None => match fs::remove_file(&self.shed_persist_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        let since_epoch = sec_since_epoch();
+        match extremes {
+            Some(extremes) => Ok(fs::write(
+                &self.extremes_persist_path,
+                format!(
+                    "{} {} {} {} {}",
+                    match extremes.on {
+                        true => "on",
+                        false => "off",
+                    },
+                    extremes.min.millidegrees().0,
+                    extremes.max.millidegrees().0,
+                    since_epoch.saturating_sub(extremes.running).as_secs(),
+                    since_epoch.saturating_sub(extremes.age).as_secs()
+                ),
+            )?),
+            None => match fs::remove_file(&self.extremes_persist_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// The history stays under the configured sensor's name, it describes the compressor.
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        let line = cycle_history::format_entry(sec_since_epoch(), cycle);
+        Ok(cycle_history::append(
+            &self.cycles_persist_path,
+            &line,
+            self.cycle_history,
+        )?)
+    }
+
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+        match faulted {
+            true => Ok(fs::write(&self.failover_persist_path, Output::Backup.to_string())?),
+            false => match fs::remove_file(&self.failover_persist_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// The compensation starts over under the new name, the cooling baseline and the counters describe the
+    /// compressor and carry on.
+    fn switch_sensor(&mut self, name: &str) {
+        let mut compensation_file_name = OsString::from(COMPENSATION_PERSIST_FILE_PREFIX);
+        compensation_file_name.push(name);
+        self.compensation_persist_path = self.persist_path.join(compensation_file_name);
+    }
+}
+
+/// The stored (cooling, heating) compensation, none learned yet when the file is missing.
+pub fn read_compensation(path: &Path) -> Result<(DegreesDelta, DegreesDelta)> {
+    match fs::read_to_string(path) {
+        Ok(data) => parse_compensation(&data),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Default::default()),
+        Err(e) => Err(anyhow!(e)),
+    }
+}
+
+// Pure
+/// `cooling heating` in millidegrees, as persisted. Files from before are in degrees and still read, a value that
+/// doesn't parse either way is zero.
+fn parse_compensation(data: &str) -> Result<(DegreesDelta, DegreesDelta)> {
+    let value = |part: &str| match part.parse() {
+        Ok(millidegrees) => Millidegrees(millidegrees).degrees(),
+        Err(_) => DegreesDelta(part.parse().unwrap_or_default()),
+    };
+    match data.split(' ').collect::<Vec<_>>().as_slice() {
+        [cooling, heating] => Ok((value(cooling), value(heating))),
+        _ => Err(anyhow!("Failed to parse compensation file.")),
+    }
+}
+
+// Pure
+/// `day today yesterday lifetime`, as persisted.
+fn parse_energy(data: &str) -> Option<EnergyTotals> {
+    let parts = data.split_whitespace().collect::<Vec<_>>();
+    match parts.as_slice() {
+        [day, today, yesterday, lifetime] => Some(EnergyTotals {
+            day: day.parse().ok()?,
+            today: today.parse().ok()?,
+            yesterday: yesterday.parse().ok()?,
+            lifetime: lifetime.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+// Pure
+/// `lifetime day today` then the history oldest first, `-` for a missing day, as persisted.
+fn parse_starts(data: &str) -> Option<StartCounts> {
+    let mut parts = data.split_whitespace();
+    let lifetime = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    let today = parts.next()?.parse().ok()?;
+    let history = parts
+        .map(|day| match day {
+            "-" => Some(None),
+            starts => starts.parse().ok().map(Some),
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(StartCounts {
+        lifetime,
+        day,
+        today,
+        history,
+    })
+}
+
+// Pure
+/// `bottom top` in millidegrees then the wall clock expiry in seconds since the epoch, as persisted.
+fn parse_boost(data: &str) -> Option<(Range<Celsius>, Duration)> {
+    let parts = data.split_whitespace().collect::<Vec<_>>();
+    match parts.as_slice() {
+        [bottom, top, until] => Some((
+            Millidegrees(bottom.parse().ok()?).celsius()..Millidegrees(top.parse().ok()?).celsius(),
+            Duration::from_secs(until.parse().ok()?),
+        )),
+        _ => None,
+    }
+}
+
+// Pure
+/// `on off` in seconds, as persisted.
+fn parse_intervals(data: &str) -> Option<MinimumIntervals> {
+    match data.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [on, off] => Some(MinimumIntervals {
+            on: Duration::from_secs(on.parse().ok()?),
+            off: Duration::from_secs(off.parse().ok()?),
+        }),
+        _ => None,
+    }
+}
+
+// Pure
+/// The overshoots then the undershoots in millidegrees, a line each, as persisted. Values that don't parse are left
+/// out.
+fn parse_overshoots(data: &str) -> Overshoots {
+    let mut lines = data.lines().map(|line| {
+        line.split_whitespace()
+            .filter_map(|m| m.parse().ok())
+            .map(|m| Millidegrees(m).degrees())
+            .collect()
+    });
+    Overshoots {
+        over: lines.next().unwrap_or_default(),
+        under: lines.next().unwrap_or_default(),
+    }
+}
+
+// Pure
+/// The run that earned it in seconds then the wall clock end in seconds since the epoch, as persisted.
+fn parse_rest(data: &str) -> Option<(Duration, Duration)> {
+    match data.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [run, until] => Some((
+            Duration::from_secs(run.parse().ok()?),
+            Duration::from_secs(until.parse().ok()?),
+        )),
+        _ => None,
     }
 }
 
-impl World for RealWorld {
-    fn get_temperature(&self) -> Result<f32> {
-        fs::read_to_string(&self.temperature_sensor_path)
-            .context("Reading temperature file failed.")
-            .and_then(|s| {
-                s.trim()
-                    .parse::<i32>()
-                    .context("Parsing temperature value failed.")
-                    .map(|i| i as f32 / 1000.0)
+// Pure
+/// The wall clock start then end in seconds since the epoch, as persisted, laid out like a rest.
+fn parse_shed(data: &str) -> Option<(Duration, Duration)> {
+    parse_rest(data)
+}
+
+// Pure
+/// `on` or `off`, the lowest and highest reading in millidegrees, then when the cycle started and when it was
+/// checkpointed in seconds since the epoch, as persisted.
+fn parse_extremes(data: &str) -> Option<(bool, Millidegrees, Millidegrees, Duration, Duration)> {
+    match data.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [relay, min, max, started, checkpointed] => Some((
+            match *relay {
+                "on" => true,
+                "off" => false,
+                _ => return None,
+            },
+            Millidegrees(min.parse().ok()?),
+            Millidegrees(max.parse().ok()?),
+            Duration::from_secs(started.parse().ok()?),
+            Duration::from_secs(checkpointed.parse().ok()?),
+        )),
+        _ => None,
+    }
+}
+
+/// A last off transition as the file has it.
+#[derive(Debug, PartialEq)]
+pub enum StoredLastOff {
+    Missing,
+    At(Duration),
+    /// Unreadable, the write that left it so still happened when the relay went off.
+    Written {
+        data: String,
+        at: Duration,
+    },
+}
+
+pub fn read_last_off(path: &Path) -> Result<StoredLastOff> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(StoredLastOff::Missing),
+        Err(e) => return Err(e).context("Failed reading last off transition storage."),
+    };
+    match data.parse() {
+        Ok(off_at) => Ok(StoredLastOff::At(Duration::from_secs(off_at))),
+        Err(e) => {
+            let at = fs::metadata(path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .ok_or(e)
+                .context("Failed parsing stored last off transition.")?;
+            Ok(StoredLastOff::Written {
+                data: data.trim().to_string(),
+                at,
             })
+        }
     }
+}
 
-    fn set_power_state(&mut self, state: bool) {
-        match state {
-            true => self.power_state.set_high(),
-            false => self.power_state.set_low(),
+/// Where a persisted last off transition falls against the clock.
+#[derive(Debug, PartialEq)]
+pub enum LastOff {
+    Ago(Duration),
+    /// After the clock, by this much. The clock was set back, or hasn't caught up yet after a boot.
+    Ahead(Duration),
+    /// Written by a clock that wasn't set, or corrupt.
+    BeforeFloor,
+}
+
+// Pure
+pub fn classify_last_off(off_at: Duration, since_epoch: Duration, floor: Duration) -> LastOff {
+    if off_at < floor {
+        return LastOff::BeforeFloor;
+    }
+    match since_epoch.checked_sub(off_at) {
+        Some(ago) => LastOff::Ago(ago),
+        None => LastOff::Ahead(off_at - since_epoch),
+    }
+}
+
+impl FileStore {
+    /// Counts a last off transition the clock can't account for, across restarts since a missing real time clock
+    /// does it on most boots. The count so far.
+    fn count_clock_skew(&self) -> u64 {
+        let count = fs::read_to_string(&self.clock_skew_path)
+            .ok()
+            .and_then(|d| d.trim().parse::<u64>().ok())
+            .unwrap_or(0)
+            + 1;
+        if let Err(e) = fs::write(&self.clock_skew_path, count.to_string()) {
+            warn!("Counting clock trouble failed: {}", e);
         }
+        count
     }
 
-    fn sleep(&self, duration: Duration) {
-        sleep(duration)
-    }
-
-    fn now(&self) -> Instant {
-        Instant::now()
-    }
-
-    fn restore_state(&self) -> Result<WorldState> {
-        self.restore_power_state().map(|power_state| {
-            let compensation_data = fs::read_to_string(&self.compensation_persist_path).or_else(|e| match e.kind() {
-                ErrorKind::NotFound => Ok(String::from("0.0 0.0")),
-                _ => Err(anyhow!(e)),
-            });
-            let (cooling_compensation, heating_compensation) = compensation_data
-                .and_then(|d| {
-                    let parts = d.split(' ').collect::<Vec<_>>();
-                    match parts.len() {
-                        2 => Ok((
-                            parts[0].parse().unwrap_or_default(),
-                            parts[1].parse().unwrap_or_default(),
-                        )),
-                        _ => Err(anyhow!("Failed to parse compensation file.")),
-                    }
-                })
-                .or_else(|e| {
-                    warn!("Restoring compensation failed: {}", e);
-                    Err(e)
-                })
-                .unwrap_or_default();
-
-            WorldState {
-                power_state,
-                heating_compensation,
-                cooling_compensation,
+    /// The state files of `sensor_name` under `persist_path`, each named by its prefix and the sensor.
+    fn new(persist_path: PathBuf, sensor_name: &str, locks: Vec<InstanceLock>) -> Self {
+        let file = |prefix: &str, suffix: &str| {
+            let mut name = OsString::from(prefix);
+            name.push(sensor_name);
+            name.push(suffix);
+            persist_path.join(name)
+        };
+        Self {
+            last_off_persist_path: file(LAST_OFF_TRANSITION_PERSIST_FILE_PREFIX, ""),
+            compensation_persist_path: file(COMPENSATION_PERSIST_FILE_PREFIX, ""),
+            cooling_rates_persist_path: file(COOLING_RATES_PERSIST_FILE_PREFIX, ""),
+            energy_persist_path: file(ENERGY_PERSIST_FILE_PREFIX, ""),
+            runtime_persist_path: file(RUNTIME_PERSIST_FILE_PREFIX, ""),
+            starts_persist_path: file(STARTS_PERSIST_FILE_PREFIX, ""),
+            boost_persist_path: file(BOOST_PERSIST_FILE_PREFIX, ""),
+            intervals_persist_path: file(INTERVALS_PERSIST_FILE_PREFIX, ""),
+            overshoots_persist_path: file(OVERSHOOTS_PERSIST_FILE_PREFIX, ""),
+            rest_persist_path: file(REST_PERSIST_FILE_PREFIX, ""),
+            shed_persist_path: file(SHED_PERSIST_FILE_PREFIX, ""),
+            extremes_persist_path: file(EXTREMES_PERSIST_FILE_PREFIX, ""),
+            cycles_persist_path: file(CYCLES_PERSIST_FILE_PREFIX, ""),
+            failover_persist_path: file(FAILOVER_PERSIST_FILE_PREFIX, ""),
+            cycle_history: cycle_history::DEFAULT_KEEP,
+            control_socket_path: file(CONTROL_SOCKET_FILE_PREFIX, ".sock"),
+            heartbeat_path: file(HEARTBEAT_FILE_PREFIX, ""),
+            clock_skew_path: file(CLOCK_SKEW_FILE_PREFIX, ""),
+            clock_floor: CLOCK_FLOOR,
+            clock: sec_since_epoch,
+            persist_path,
+            locks,
+        }
+    }
+}
+
+/// Where the state files go: `--state-dir`, else systemd's `StateDirectory=`, else /var/lib/picool.
+pub fn state_dir(given: Option<&Path>) -> PathBuf {
+    resolve_state_dir(given, env::var_os(STATE_DIRECTORY_ENV))
+}
+
+fn resolve_state_dir(given: Option<&Path>, state_directory: Option<OsString>) -> PathBuf {
+    if let Some(dir) = given {
+        return dir.to_path_buf();
+    }
+    state_directory
+        .and_then(|dirs| env::split_paths(&dirs).find(|dir| !dir.as_os_str().is_empty()))
+        .unwrap_or_else(|| PathBuf::from(PICOOL_PERSIST_BASE_PATH))
+}
+
+/// Creates `dir` for the owner and group only when it's missing, and makes sure a file can be written in it, so
+/// state isn't silently lost on every transition.
+pub fn prepare_state_dir(dir: &Path) -> Result<(), StartupError> {
+    let probe = dir.join(STATE_DIR_PROBE);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(STATE_DIR_MODE)
+        .create(dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| StartupError::from_state_dir(dir.to_path_buf(), e))
+}
+
+/// The last off transition and compensation files of `sensor_name` in `dir`, as the daemon names them.
+pub fn restored_paths(dir: &Path, sensor_name: &str) -> (PathBuf, PathBuf) {
+    let store = FileStore::new(dir.to_path_buf(), sensor_name, vec![]);
+    (store.last_off_persist_path, store.compensation_persist_path)
+}
+
+/// The compensation `reset_compensation` found before clearing it.
+#[derive(Debug)]
+pub enum StoredCompensation {
+    Missing,
+    /// Cooling, heating.
+    Learned(DegreesDelta, DegreesDelta),
+    Corrupt(anyhow::Error),
+}
+
+/// Clears the learned compensation of `sensor` in `dir`, or seeds it with (cooling, heating). Holds the sensor's
+/// lock meanwhile, a running daemon would write what it learned back over it. The file, and what it held.
+pub fn reset_compensation(
+    dir: &Path,
+    sensor: &SensorSpec,
+    seed: Option<(DegreesDelta, DegreesDelta)>,
+) -> Result<(PathBuf, StoredCompensation)> {
+    let lock = InstanceLock::acquire(dir, &sensor.lock_name()?)?;
+    let mut store = FileStore::new(dir.to_path_buf(), &sensor.name()?, vec![lock]);
+    let path = store.compensation_persist_path.clone();
+    let stored = match path.exists() {
+        false => StoredCompensation::Missing,
+        true => match read_compensation(&path) {
+            Ok((cooling, heating)) => StoredCompensation::Learned(cooling, heating),
+            Err(e) => StoredCompensation::Corrupt(e),
+        },
+    };
+    match seed {
+        Some((cooling, heating)) => store
+            .persist_compensation(cooling, heating)
+            .with_context(|| format!("Failed writing {}.", path.display()))?,
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed deleting {}.", path.display()))
             }
+            _ => {}
+        },
+    }
+    Ok((path, stored))
+}
+
+/// The relay on GPIO unless another switch backend is given.
+pub type RealWorld<S = GpioSwitch> = CompositeWorld<RealSensor, S, SystemClock, VerifiedStore<FileStore>>;
+
+impl RealWorld {
+    pub fn new(
+        sensor: SensorSpec,
+        power_state_pin_number: u8,
+        state_dir: PathBuf,
+        gpio: &GpioConfig,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        Self::open(
+            sensor,
+            power_state_pin_number,
+            state_dir,
+            |pin| GpioSwitch::acquire(gpio, pin),
+            shutdown,
+        )
+    }
+
+    /// Counts the relay changes held back to keep actuations apart.
+    pub fn relay_deferrals(&self) -> ActuationDeferrals {
+        self.switch.guard.deferrals()
+    }
+
+    /// Switches a circulation fan on a second pin, locked like the compressor's.
+    pub fn with_fan_pin(mut self, pin_number: u8) -> Result<Self> {
+        let lock = InstanceLock::acquire(&self.store.inner().persist_path, &format!("pin_{}", pin_number))?;
+        self.store.inner_mut().locks.push(lock);
+        self.switch.fan = Some(self.switch.backend.output(pin_number)?);
+        Ok(self)
+    }
+
+    /// Switches a second compressor on another pin, locked like the first.
+    pub fn with_stage2_pin(mut self, pin_number: u8) -> Result<Self> {
+        let lock = InstanceLock::acquire(&self.store.inner().persist_path, &format!("pin_{}", pin_number))?;
+        self.store.inner_mut().locks.push(lock);
+        let pin = self.switch.backend.output(pin_number)?;
+        self.switch.stage2 = Some((pin, ActuationGuard::new(self.switch.spacing)));
+        Ok(self)
+    }
+
+    /// Switches a glycol pump on another pin, locked like the compressor's.
+    pub fn with_pump_pin(mut self, pin_number: u8) -> Result<Self> {
+        let lock = InstanceLock::acquire(&self.store.inner().persist_path, &format!("pin_{}", pin_number))?;
+        self.store.inner_mut().locks.push(lock);
+        self.switch.pump = Some(self.switch.backend.output(pin_number)?);
+        Ok(self)
+    }
+
+    /// Switches the compressor through a backup relay on another pin once the primary fails, locked like the
+    /// primary's.
+    pub fn with_backup_pin(mut self, pin_number: u8) -> Result<Self> {
+        let lock = InstanceLock::acquire(&self.store.inner().persist_path, &format!("pin_{}", pin_number))?;
+        self.store.inner_mut().locks.push(lock);
+        self.switch.backup = Some(self.switch.backend.output(pin_number)?);
+        Ok(self)
+    }
+}
+
+impl<S: Switch> RealWorld<S> {
+    /// Locks and keeps its state in `persist_path`, and switches the relay through whatever `acquire` returns for
+    /// the pin once it's locked.
+    pub fn open(
+        sensor: SensorSpec,
+        power_state_pin_number: u8,
+        persist_path: PathBuf,
+        acquire: impl FnOnce(u8) -> Result<S, StartupError>,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        let sensor_name = sensor.name()?;
+        prepare_state_dir(&persist_path)?;
+
+        // Locks must be held before touching the pin or the serial port, a losing instance would otherwise reset
+        // them.
+        let locks = vec![
+            InstanceLock::acquire(&persist_path, &format!("pin_{}", power_state_pin_number))?,
+            InstanceLock::acquire(&persist_path, &sensor.lock_name()?)?,
+        ];
+        let chamber = sensor.open()?;
+
+        let switch = acquire(power_state_pin_number)?;
+
+        Ok(Self {
+            sensor: RealSensor {
+                chamber,
+                ambient: None,
+                current: None,
+                bulk: None,
+            },
+            switch,
+            clock: SystemClock::new(shutdown),
+            store: VerifiedStore::new(FileStore::new(persist_path, &sensor_name, locks)),
         })
     }
 
-    fn persist_last_off_transition(&mut self) -> Result<()> {
-        let since_epoch = sec_since_epoch();
-        fs::write(&self.last_off_persist_path, since_epoch.as_secs().to_string()).map_err(|e| anyhow!(e))
+    /// Reads a chamber file in `format`, or the format its reading shows without one. Logged either way, as a wrong
+    /// format reads a thousandth or a thousand times the temperature.
+    pub fn with_sensor_format(mut self, format: Option<SensorFormat>) -> Result<Self> {
+        let device = match &mut self.sensor.chamber {
+            ChamberSensor::W1(device) => device,
+            ChamberSensor::Modbus(_) | ChamberSensor::Exec(_) | ChamberSensor::Hwmon(_) => match format {
+                Some(_) => bail!("--sensor-format only applies to a temperature file."),
+                None => return Ok(self),
+            },
+        };
+        let (format, how) = match format {
+            Some(format) => (format, "as given"),
+            None => (device.detect_format()?, "detected"),
+        };
+        info!("Reading {} in {}, {}.", device.path().display(), format, how);
+        device.set_format(format);
+        Ok(self)
+    }
+
+    /// Sets a DS18B20's resolution, logged and left alone where the kernel can't.
+    pub fn with_sensor_resolution(mut self, resolution: Resolution) -> Result<Self> {
+        match &mut self.sensor.chamber {
+            ChamberSensor::W1(device) => {
+                device.set_resolution(resolution);
+                Ok(self)
+            }
+            ChamberSensor::Modbus(_) | ChamberSensor::Exec(_) | ChamberSensor::Hwmon(_) => {
+                bail!("--sensor-resolution only applies to a 1-Wire sensor.")
+            }
+        }
+    }
+
+    pub fn with_ambient_sensor(mut self, ambient_sensor_path: PathBuf) -> Result<Self> {
+        fs::File::open(&ambient_sensor_path).map_err(|e| StartupError::from_sensor(ambient_sensor_path.clone(), e))?;
+        if let (ChamberSensor::W1(device), Some(id)) = (&mut self.sensor.chamber, w1::device_id(&ambient_sensor_path)) {
+            device.exclude(id);
+            self.sensor.bulk = BulkRead::find(&[device.path(), &ambient_sensor_path]);
+        }
+        if let Some(bulk) = &self.sensor.bulk {
+            info!(
+                "Converting the chamber and ambient sensors together through {}.",
+                bulk.trigger_path().display()
+            );
+        }
+        self.sensor.ambient = Some(ambient_sensor_path);
+        Ok(self)
+    }
+
+    /// Reads the compressor's current, locked like the chamber sensor's bus.
+    pub fn with_current_sensor(mut self, spec: CurrentSpec) -> Result<Self> {
+        let CurrentSpec::Ina219(config) = spec;
+        let lock = format!(
+            "i2c_{}_{:02x}",
+            config.device.file_name().unwrap_or_default().to_string_lossy(),
+            config.address
+        );
+        let lock = InstanceLock::acquire(&self.store.inner().persist_path, &lock)?;
+        self.store.inner_mut().locks.push(lock);
+        let device = config.device.clone();
+        self.sensor.current = Some(Ina219::open(config).map_err(|e| StartupError::from_sensor(device, e))?);
+        Ok(self)
+    }
+
+    pub fn with_heartbeat_path(mut self, heartbeat_path: PathBuf) -> Self {
+        self.store.inner_mut().heartbeat_path = heartbeat_path;
+        self
+    }
+
+    /// Keeps the last `keep` cycles in the history.
+    pub fn with_cycle_history(mut self, keep: usize) -> Self {
+        self.store.inner_mut().cycle_history = keep;
+        self
+    }
+
+    /// Last off transitions persisted before `floor`, since the epoch, are rejected.
+    pub fn with_clock_floor(mut self, floor: Duration) -> Self {
+        self.store.inner_mut().clock_floor = floor;
+        self
+    }
+
+    pub fn control_socket_path(&self) -> &Path {
+        &self.store.inner().control_socket_path
+    }
+
+    /// Everything an unprivileged user needs to own to keep persisting.
+    pub fn state_paths(&self) -> Vec<PathBuf> {
+        let store = self.store.inner();
+        let mut paths = vec![
+            store.persist_path.clone(),
+            store.last_off_persist_path.clone(),
+            store.compensation_persist_path.clone(),
+            store.cooling_rates_persist_path.clone(),
+            store.energy_persist_path.clone(),
+            store.runtime_persist_path.clone(),
+            store.starts_persist_path.clone(),
+            store.boost_persist_path.clone(),
+            store.intervals_persist_path.clone(),
+            store.overshoots_persist_path.clone(),
+            store.rest_persist_path.clone(),
+            store.shed_persist_path.clone(),
+            store.extremes_persist_path.clone(),
+            store.failover_persist_path.clone(),
+            store.control_socket_path.clone(),
+            store.heartbeat_path.clone(),
+            store.clock_skew_path.clone(),
+        ];
+        paths.extend(store.locks.iter().map(|l| l.path().to_path_buf()));
+        paths
+    }
+}
+
+impl<S: Switch> SelfTestWorld for RealWorld<S> {
+    fn read_temperature(&self) -> Result<f32> {
+        Ok(self.sensor.get_temperature()?.0)
+    }
+
+    fn persistence_dir(&self) -> &Path {
+        &self.store.inner().persist_path
     }
 
-    fn persist_compensation(&mut self, cooling: f32, heating: f32) -> Result<()> {
-        fs::write(&self.compensation_persist_path, format!("{} {}", cooling, heating)).map_err(|e| anyhow!(e))
+    fn set_output(&mut self, on: bool) {
+        self.switch.set_power_state(on)
+    }
+
+    fn output_readback(&self) -> bool {
+        self.switch.is_on()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration)
     }
 }
 
@@ -144,3 +1325,651 @@ fn sec_since_epoch() -> Duration {
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("Now is never before the epoch.")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance_lock::LockHeld, test_util::TempDir, World};
+
+    const SENSOR: &str = "28-0123456789ab";
+
+    struct LatchedSwitch(bool);
+
+    impl Switch for LatchedSwitch {
+        fn set_power_state(&mut self, state: bool) {
+            self.0 = state;
+        }
+
+        fn set_fan_state(&mut self, _state: bool) {}
+
+        fn set_stage2_state(&mut self, _state: bool) {}
+
+        fn set_pump_state(&mut self, _state: bool) {}
+
+        fn pump_state(&self) -> Option<bool> {
+            None
+        }
+
+        fn select_output(&mut self, _output: Output) {}
+
+        fn is_on(&self) -> bool {
+            self.0
+        }
+    }
+
+    /// A world over a w1 value file and state files in `dir`, with the relay left `on`.
+    fn world(dir: &TempDir, on: bool) -> RealWorld<LatchedSwitch> {
+        let device = dir.path().join("w1").join(SENSOR);
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("temperature"), "12345\n").unwrap();
+        let sensor = SensorSpec::W1(device.join("temperature"));
+        let state = dir.path().join("state");
+        fs::create_dir_all(&state).unwrap();
+        RealWorld::open(sensor, 17, state, |_| Ok(LatchedSwitch(on)), Shutdown::default()).unwrap()
+    }
+
+    #[test]
+    fn converts_sensors_on_one_bus_together() {
+        let dir = TempDir::new();
+        let ambient = dir.path().join("w1/28-0a/temperature");
+        fs::create_dir_all(ambient.parent().unwrap()).unwrap();
+        fs::write(&ambient, "21000\n").unwrap();
+        {
+            // A kernel without bulk reads.
+            let world = world(&dir, false).with_ambient_sensor(ambient.clone()).unwrap();
+            assert!(world.sensor.bulk.is_none());
+            assert_eq!(Celsius(12.345), world.get_temperature().unwrap());
+        }
+
+        let trigger = dir.path().join("w1/therm_bulk_read");
+        fs::write(&trigger, "0\n").unwrap();
+        let world = world(&dir, false).with_ambient_sensor(ambient).unwrap();
+        assert_eq!(Celsius(12.345), world.get_temperature().unwrap());
+        assert_eq!("trigger\n", fs::read_to_string(&trigger).unwrap());
+        assert_eq!(Celsius(21.0), world.get_ambient_temperature().unwrap().unwrap());
+    }
+
+    #[test]
+    fn state_dir_from_the_option_systemd_or_the_default() {
+        let given = Path::new("/home/pi/.picool");
+        let systemd = Some(OsString::from("/var/lib/private/picool:/var/lib/other"));
+        assert_eq!(given, resolve_state_dir(Some(given), systemd.clone()));
+        assert_eq!(Path::new("/var/lib/private/picool"), resolve_state_dir(None, systemd));
+        assert_eq!(Path::new(PICOOL_PERSIST_BASE_PATH), resolve_state_dir(None, None));
+        assert_eq!(
+            Path::new(PICOOL_PERSIST_BASE_PATH),
+            resolve_state_dir(None, Some(OsString::new()))
+        );
+    }
+
+    #[test]
+    fn prepares_the_state_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let state = dir.path().join("var/lib/picool");
+        prepare_state_dir(&state).unwrap();
+        assert_eq!(
+            STATE_DIR_MODE,
+            fs::metadata(&state).unwrap().permissions().mode() & 0o777
+        );
+        assert!(!state.join(STATE_DIR_PROBE).exists());
+        prepare_state_dir(&state).unwrap();
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let e = prepare_state_dir(&file.join("state")).unwrap_err();
+        assert!(matches!(e, StartupError::StateDirUnusable(..)), "{}", e);
+        assert!(e.to_string().contains("Can not keep state in"));
+    }
+
+    #[test]
+    fn resets_compensation() {
+        let dir = TempDir::new();
+        let sensor = SensorSpec::W1(dir.path().join("w1").join(SENSOR).join("temperature"));
+        let state = dir.path().join("state");
+        let (path, stored) = reset_compensation(&state, &sensor, None).unwrap();
+        assert_eq!(state_file(&dir, COMPENSATION_PERSIST_FILE_PREFIX), path);
+        assert!(matches!(stored, StoredCompensation::Missing), "{:?}", stored);
+
+        let seed = (DegreesDelta(0.5), DegreesDelta(-0.25));
+        reset_compensation(&state, &sensor, Some(seed)).unwrap();
+        assert_eq!("500 -250", fs::read_to_string(&path).unwrap());
+        assert_eq!(seed, read_compensation(&path).unwrap());
+        match reset_compensation(&state, &sensor, None).unwrap().1 {
+            StoredCompensation::Learned(cooling, heating) => assert_eq!(seed, (cooling, heating)),
+            stored => panic!("{:?}", stored),
+        }
+        assert!(!path.exists());
+
+        fs::write(&path, "garbage").unwrap();
+        let (_, stored) = reset_compensation(&state, &sensor, None).unwrap();
+        assert!(matches!(stored, StoredCompensation::Corrupt(_)), "{:?}", stored);
+        assert!(!path.exists());
+
+        // Not while a daemon holds the sensor.
+        let _daemon = InstanceLock::acquire(&state, &sensor.lock_name().unwrap()).unwrap();
+        let e = reset_compensation(&state, &sensor, Some(seed)).unwrap_err();
+        assert!(e.downcast_ref::<LockHeld>().is_some(), "{:#}", e);
+        assert!(!path.exists());
+    }
+
+    fn state_file(dir: &TempDir, prefix: &str) -> PathBuf {
+        dir.path().join("state").join(format!("{}{}", prefix, SENSOR))
+    }
+
+    #[test]
+    fn names_state_files_after_the_sensor() {
+        let dir = TempDir::new();
+        let world = world(&dir, false);
+        let state = dir.path().join("state");
+        assert_eq!(state.join("control_28-0123456789ab.sock"), world.control_socket_path());
+        let paths = world.state_paths();
+        for name in [
+            "last_off_28-0123456789ab",
+            "comp_28-0123456789ab",
+            "cooling_28-0123456789ab",
+            "energy_28-0123456789ab",
+            "runtime_28-0123456789ab",
+            "starts_28-0123456789ab",
+            "boost_28-0123456789ab",
+            "intervals_28-0123456789ab",
+            "overshoot_28-0123456789ab",
+            "rest_28-0123456789ab",
+            "shed_28-0123456789ab",
+            "extremes_28-0123456789ab",
+            "heartbeat_28-0123456789ab",
+            "clock_skew_28-0123456789ab",
+        ] {
+            assert!(paths.contains(&state.join(name)), "{} missing from {:?}", name, paths);
+        }
+        assert_eq!(2, world.store.inner().locks.len());
+        // Millidegrees, as the w1 driver writes them.
+        assert_eq!(Celsius(12.345), world.get_temperature().unwrap());
+    }
+
+    #[test]
+    fn restores_defaults_without_state_files() {
+        let dir = TempDir::new();
+        let restored = world(&dir, false).restore_state().unwrap();
+        assert_eq!(RestoredPowerState::OffForUnknownDuration, restored.power_state);
+        assert_eq!(
+            (DegreesDelta::ZERO, DegreesDelta::ZERO),
+            (restored.cooling_compensation, restored.heating_compensation)
+        );
+        assert!(restored.cooling_rates.is_empty());
+        assert_eq!(EnergyTotals::default(), restored.energy);
+        assert_eq!(Duration::from_secs(0), restored.runtime);
+        assert_eq!(StartCounts::default(), restored.starts);
+        assert_eq!(None, restored.boost);
+        assert_eq!(None, restored.intervals);
+        assert_eq!(Overshoots::default(), restored.overshoots);
+        assert_eq!(None, restored.rest);
+        assert_eq!(None, restored.shed);
+        assert_eq!(None, restored.extremes);
+    }
+
+    #[test]
+    fn restores_valid_state_files() {
+        let dir = TempDir::new();
+        let world = world(&dir, false);
+        let off_at = sec_since_epoch().as_secs() - 600;
+        fs::write(state_file(&dir, "last_off_"), off_at.to_string()).unwrap();
+        fs::write(state_file(&dir, "comp_"), "500 -250").unwrap();
+        fs::write(state_file(&dir, "cooling_"), "0.5 0.25").unwrap();
+        fs::write(state_file(&dir, "energy_"), "19000 1.25 2.5 140.75").unwrap();
+        fs::write(state_file(&dir, "runtime_"), "7200").unwrap();
+        fs::write(state_file(&dir, "starts_"), "1200 19000 4 31 - 28").unwrap();
+        let restored = world.restore_state().unwrap();
+        match restored.power_state {
+            RestoredPowerState::OffFor(off) => assert!(off.as_secs() >= 600 && off.as_secs() < 660, "{:?}", off),
+            other => panic!("Restored {:?}.", other),
+        }
+        assert_eq!(
+            (DegreesDelta(0.5), DegreesDelta(-0.25)),
+            (restored.cooling_compensation, restored.heating_compensation)
+        );
+        assert_eq!(vec![0.5, 0.25], restored.cooling_rates);
+        assert_eq!(140.75, restored.energy.lifetime);
+        assert_eq!(Duration::from_secs(7200), restored.runtime);
+        assert_eq!(vec![Some(31), None, Some(28)], restored.starts.history);
+    }
+
+    #[test]
+    fn last_off_transitions_the_clock_cant_account_for_are_counted() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        // 2027-01-15T08:00:00Z, well past the floor.
+        world.store.inner_mut().clock = || Duration::from_secs(1_800_000_000);
+        let restore = |off_at: u64| {
+            fs::write(state_file(&dir, "last_off_"), off_at.to_string()).unwrap();
+            world.store.restore_power_state().unwrap()
+        };
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(600)),
+            restore(1_800_000_000 - 600)
+        );
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(0)),
+            restore(1_800_000_000)
+        );
+        assert!(!state_file(&dir, "clock_skew_").exists());
+        // Written before a reboot by a clock that was ahead of this one.
+        assert_eq!(RestoredPowerState::OffForUnknownDuration, restore(1_800_003_600));
+        // Written by a clock still at the epoch, and older than the floor.
+        assert_eq!(RestoredPowerState::OffForUnknownDuration, restore(120));
+        assert_eq!(
+            RestoredPowerState::OffForUnknownDuration,
+            restore(CLOCK_FLOOR.as_secs() - 1)
+        );
+        assert_eq!("3", fs::read_to_string(state_file(&dir, "clock_skew_")).unwrap());
+        let mut world = world.with_clock_floor(Duration::from_secs(60));
+        world.store.inner_mut().clock = || Duration::from_secs(1_800_000_000);
+        fs::write(state_file(&dir, "last_off_"), "120").unwrap();
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(1_800_000_000 - 120)),
+            world.store.restore_power_state().unwrap()
+        );
+    }
+
+    #[test]
+    fn falls_back_to_when_an_unreadable_last_off_transition_was_written() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        world.store.inner_mut().clock = || Duration::from_secs(1_800_000_000);
+        let restore = |contents: &str, written: u64| {
+            let path = state_file(&dir, "last_off_");
+            fs::write(&path, contents).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(written))
+                .unwrap();
+            world.store.restore_power_state().unwrap()
+        };
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(600)),
+            restore("yesterday", 1_800_000_000 - 600)
+        );
+        // Torn by a power cut part way through the write.
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(90)),
+            restore("18000\0\0\0\0\0", 1_800_000_000 - 90)
+        );
+        // What parses is still authoritative.
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(300)),
+            restore("1799999700", 1_800_000_000 - 90)
+        );
+        // And a write time the clock can't account for is treated like a stored one.
+        assert_eq!(RestoredPowerState::OffForUnknownDuration, restore("", 1_800_003_600));
+        assert_eq!(RestoredPowerState::OffForUnknownDuration, restore("\0\0", 120));
+        assert_eq!("2", fs::read_to_string(state_file(&dir, "clock_skew_")).unwrap());
+    }
+
+    #[test]
+    fn classifies_the_last_off_transition_against_the_clock() {
+        let secs = Duration::from_secs;
+        assert_eq!(
+            LastOff::Ago(secs(60)),
+            classify_last_off(secs(1000), secs(1060), secs(500))
+        );
+        assert_eq!(
+            LastOff::Ahead(secs(40)),
+            classify_last_off(secs(1100), secs(1060), secs(500))
+        );
+        assert_eq!(
+            LastOff::BeforeFloor,
+            classify_last_off(secs(499), secs(1060), secs(500))
+        );
+        // A clock that hasn't been set since boot is behind any real last off transition.
+        assert_eq!(
+            LastOff::Ahead(secs(900)),
+            classify_last_off(secs(1000), secs(100), secs(500))
+        );
+    }
+
+    #[test]
+    fn survives_corrupt_and_future_state_files() {
+        let dir = TempDir::new();
+        let world = world(&dir, false);
+        // A clock that went backwards since the last off can't tell how long ago that was.
+        let future = sec_since_epoch().as_secs() + 3600;
+        fs::write(state_file(&dir, "last_off_"), future.to_string()).unwrap();
+        assert_eq!(
+            RestoredPowerState::OffForUnknownDuration,
+            world.store.restore_power_state().unwrap()
+        );
+
+        fs::write(state_file(&dir, "comp_"), "500").unwrap();
+        fs::write(state_file(&dir, "cooling_"), "fast 0.25").unwrap();
+        fs::write(state_file(&dir, "energy_"), "19000 1.25").unwrap();
+        fs::write(state_file(&dir, "runtime_"), "-1").unwrap();
+        fs::write(state_file(&dir, "starts_"), "many").unwrap();
+        let restored = world.restore_state().unwrap();
+        assert_eq!(
+            (DegreesDelta::ZERO, DegreesDelta::ZERO),
+            (restored.cooling_compensation, restored.heating_compensation)
+        );
+        assert_eq!(vec![0.25], restored.cooling_rates);
+        assert_eq!(EnergyTotals::default(), restored.energy);
+        assert_eq!(Duration::from_secs(0), restored.runtime);
+        assert_eq!(StartCounts::default(), restored.starts);
+
+        // Both fields, in degrees from before millidegrees.
+        fs::write(state_file(&dir, "comp_"), "1.0109 -0.5").unwrap();
+        assert_eq!(
+            (DegreesDelta(1.0109), DegreesDelta(-0.5)),
+            world.store.restore_compensation()
+        );
+    }
+
+    #[test]
+    fn relay_left_on_takes_precedence_over_a_corrupt_last_off() {
+        let dir = TempDir::new();
+        let world = world(&dir, true);
+        fs::write(state_file(&dir, "last_off_"), "yesterday").unwrap();
+        assert_eq!(
+            RestoredPowerState::CurrentlyOn,
+            world.restore_state().unwrap().power_state
+        );
+    }
+
+    #[test]
+    fn persists_in_the_same_format() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        world
+            .persist_compensation(DegreesDelta(0.5), DegreesDelta(-0.25))
+            .unwrap();
+        world.persist_cooling_rates(&[0.5, 0.25]).unwrap();
+        world
+            .persist_energy(EnergyTotals {
+                day: 19000,
+                today: 1.25,
+                yesterday: 2.5,
+                lifetime: 140.75,
+            })
+            .unwrap();
+        world.persist_runtime(Duration::from_secs(7200)).unwrap();
+        world
+            .persist_starts(&StartCounts {
+                lifetime: 1200,
+                day: 19000,
+                today: 4,
+                history: vec![Some(31), None, Some(28)],
+            })
+            .unwrap();
+        world.persist_last_off_transition().unwrap();
+        let read = |prefix| fs::read_to_string(state_file(&dir, prefix)).unwrap();
+        assert_eq!("500 -250", read("comp_"));
+        assert_eq!("0.5 0.25", read("cooling_"));
+        assert_eq!("19000 1.25 2.5 140.75", read("energy_"));
+        assert_eq!("7200", read("runtime_"));
+        assert_eq!("1200 19000 4 31 - 28", read("starts_"));
+        assert!(read("last_off_").chars().all(|c| c.is_ascii_digit()));
+        let restored = world.restore_state().unwrap();
+        assert_eq!(Duration::from_secs(7200), restored.runtime);
+        assert_eq!(19000, restored.starts.day);
+    }
+
+    #[test]
+    fn persists_a_boost_against_the_wall_clock() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        let boost = Boost {
+            band: Celsius(-1.5)..Celsius(0.5),
+            remaining: Duration::from_secs(3600),
+        };
+        world.persist_boost(Some(&boost)).unwrap();
+        let read = || fs::read_to_string(state_file(&dir, "boost_")).unwrap();
+        let until = sec_since_epoch().as_secs() + 3600;
+        assert!(read().starts_with("-1500 500 "));
+        let restored = world.restore_state().unwrap().boost.unwrap();
+        assert_eq!(boost.band, restored.band);
+        assert!(restored.remaining.as_secs() > 3500, "{:?}", restored.remaining);
+        // Ran out while stopped.
+        fs::write(state_file(&dir, "boost_"), format!("-1500 500 {}", until - 7200)).unwrap();
+        assert_eq!(None, world.restore_state().unwrap().boost);
+        fs::write(state_file(&dir, "boost_"), "soon").unwrap();
+        assert_eq!(None, world.restore_state().unwrap().boost);
+        world.persist_boost(None).unwrap();
+        assert!(!state_file(&dir, "boost_").exists());
+        world.persist_boost(None).unwrap();
+    }
+
+    #[test]
+    fn persists_tuned_intervals_until_reverted() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        let tuned = MinimumIntervals {
+            on: Duration::from_secs(5 * 60),
+            off: Duration::from_secs(15 * 60),
+        };
+        world.persist_intervals(Some(tuned)).unwrap();
+        assert_eq!("300 900", fs::read_to_string(state_file(&dir, "intervals_")).unwrap());
+        assert_eq!(Some(tuned), world.restore_state().unwrap().intervals);
+        fs::write(state_file(&dir, "intervals_"), "300").unwrap();
+        assert_eq!(None, world.restore_state().unwrap().intervals);
+        world.persist_intervals(None).unwrap();
+        assert!(!state_file(&dir, "intervals_").exists());
+        world.persist_intervals(None).unwrap();
+    }
+
+    #[test]
+    fn persists_overshoots_a_line_per_end() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        let overshoots = Overshoots {
+            over: vec![DegreesDelta(0.5), DegreesDelta::ZERO],
+            under: vec![DegreesDelta(0.25)],
+        };
+        world.persist_overshoots(&overshoots).unwrap();
+        assert_eq!(
+            "500 0\n250\n",
+            fs::read_to_string(state_file(&dir, "overshoot_")).unwrap()
+        );
+        assert_eq!(overshoots, world.restore_state().unwrap().overshoots);
+        fs::write(state_file(&dir, "overshoot_"), "500 lots").unwrap();
+        assert_eq!(
+            Overshoots {
+                over: vec![DegreesDelta(0.5)],
+                under: vec![],
+            },
+            world.restore_state().unwrap().overshoots
+        );
+    }
+
+    #[test]
+    fn keeps_a_bounded_cycle_history() {
+        use crate::cycle_history::{self, HistoryEntry};
+        use picool_core::controller::TransitionReason;
+        let dir = TempDir::new();
+        let mut world = world(&dir, false).with_cycle_history(2);
+        let cycle = |minutes: u64| CycleRecord {
+            on: Duration::from_secs(minutes * 60),
+            off: None,
+            min: Some(Celsius(3.5)),
+            max: Some(Celsius(8.25)),
+            overshoot: Some(DegreesDelta(0.25)),
+            cooling_rate: None,
+            reason: TransitionReason::Threshold,
+            abnormal: Some("load shed".into()),
+        };
+        for minutes in 1..=3 {
+            world.record_cycle(&cycle(minutes)).unwrap();
+        }
+        let history = cycle_history::read(&state_file(&dir, "cycles_")).unwrap();
+        let kept: Vec<&CycleRecord> = history.iter().map(|entry: &HistoryEntry| &entry.cycle).collect();
+        assert_eq!(vec![&cycle(2), &cycle(3)], kept);
+        assert!(history[1].ended >= CLOCK_FLOOR);
+    }
+
+    #[test]
+    fn persists_a_rest_against_the_wall_clock() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        let rest = RestPeriod {
+            run: Duration::from_secs(7200),
+            remaining: Duration::from_secs(1800),
+        };
+        world.persist_rest(Some(&rest)).unwrap();
+        let until = sec_since_epoch().as_secs() + 1800;
+        assert!(fs::read_to_string(state_file(&dir, "rest_"))
+            .unwrap()
+            .starts_with("7200 "));
+        let restored = world.restore_state().unwrap().rest.unwrap();
+        assert_eq!(rest.run, restored.run);
+        assert!(restored.remaining.as_secs() > 1700, "{:?}", restored.remaining);
+        // Ran out while stopped.
+        fs::write(state_file(&dir, "rest_"), format!("7200 {}", until - 3600)).unwrap();
+        assert_eq!(None, world.restore_state().unwrap().rest);
+        world.persist_rest(None).unwrap();
+        assert!(!state_file(&dir, "rest_").exists());
+        world.persist_rest(None).unwrap();
+    }
+
+    #[test]
+    fn persists_a_shed_against_the_wall_clock() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        let shed = ShedPeriod {
+            since: Duration::from_secs(600),
+            remaining: Duration::from_secs(1800),
+        };
+        world.persist_shed(Some(&shed)).unwrap();
+        let restored = world.restore_state().unwrap().shed.unwrap();
+        assert!(restored.since.as_secs() >= 600, "{:?}", restored.since);
+        assert!(restored.remaining.as_secs() > 1700, "{:?}", restored.remaining);
+        // Ran out while stopped, it comes back spent.
+        let now = sec_since_epoch().as_secs();
+        fs::write(state_file(&dir, "shed_"), format!("{} {}", now - 7200, now - 60)).unwrap();
+        let restored = world.restore_state().unwrap().shed.unwrap();
+        assert_eq!(Duration::from_secs(0), restored.remaining);
+        assert!(restored.since.as_secs() >= 7200, "{:?}", restored.since);
+        world.persist_shed(None).unwrap();
+        assert!(!state_file(&dir, "shed_").exists());
+        assert_eq!(None, world.restore_state().unwrap().shed);
+    }
+
+    #[test]
+    fn checkpoints_cycle_extremes_against_the_wall_clock() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        let extremes = CycleExtremes {
+            on: false,
+            min: Celsius(-0.75),
+            max: Celsius(4.5),
+            running: Duration::from_secs(1200),
+            age: Duration::from_secs(0),
+        };
+        world.persist_extremes(Some(&extremes)).unwrap();
+        assert!(fs::read_to_string(state_file(&dir, "extremes_"))
+            .unwrap()
+            .starts_with("off -750 4500 "));
+        let restored = world.restore_state().unwrap().extremes.unwrap();
+        assert_eq!(
+            (false, Celsius(-0.75), Celsius(4.5)),
+            (restored.on, restored.min, restored.max)
+        );
+        assert!(restored.running.as_secs() >= 1200 && restored.running.as_secs() < 1260);
+        // Written a while before the restart.
+        let now = sec_since_epoch().as_secs();
+        fs::write(
+            state_file(&dir, "extremes_"),
+            format!("on 500 3000 {} {}", now - 3000, now - 2400),
+        )
+        .unwrap();
+        let restored = world.restore_state().unwrap().extremes.unwrap();
+        assert!(restored.on);
+        assert!(
+            restored.age.as_secs() >= 2400 && restored.age.as_secs() < 2460,
+            "{:?}",
+            restored.age
+        );
+        fs::write(state_file(&dir, "extremes_"), "sideways 500 3000 1 2").unwrap();
+        assert_eq!(None, world.restore_state().unwrap().extremes);
+        world.persist_extremes(None).unwrap();
+        assert!(!state_file(&dir, "extremes_").exists());
+        world.persist_extremes(None).unwrap();
+    }
+
+    #[test]
+    fn persists_the_failover_until_cleared() {
+        let dir = TempDir::new();
+        let mut world = world(&dir, false);
+        assert!(!world.restore_state().unwrap().failover);
+        world.persist_failover(true).unwrap();
+        assert_eq!("backup", fs::read_to_string(state_file(&dir, "failover_")).unwrap());
+        assert!(world.restore_state().unwrap().failover);
+        world.persist_failover(false).unwrap();
+        assert!(!state_file(&dir, "failover_").exists());
+        world.persist_failover(false).unwrap();
+    }
+
+    #[test]
+    fn moves_the_compressor_to_the_backup_relay() {
+        let config = GpioConfig {
+            backend: gpio::BackendKind::Detached,
+            spacing: Duration::from_secs(0),
+            ..GpioConfig::default()
+        };
+        let mut switch = GpioSwitch::acquire(&config, 17).unwrap();
+        switch.backup = Some(switch.backend.output(24).unwrap());
+        switch.set_power_state(true);
+        switch.select_output(Output::Backup);
+        let backup = |switch: &GpioSwitch| switch.backup.as_ref().unwrap().is_active();
+        assert_eq!((false, true), (switch.pin.is_active(), backup(&switch)));
+        assert!(switch.is_on());
+        // A primary that came back to life is driven off with the backup.
+        switch.pin.set_active(true);
+        switch.set_power_state(false);
+        assert_eq!((false, false), (switch.pin.is_active(), backup(&switch)));
+        switch.select_output(Output::Primary);
+        assert!(!switch.is_on());
+    }
+
+    #[test]
+    fn gpio_acquisition_is_a_runtime_error() {
+        // No board has BCM pin 99, and off a Pi Gpio::new already fails.
+        let e = GpioSwitch::acquire(&GpioConfig::default(), 99).err().unwrap();
+        assert_ne!(0, e.exit_code());
+    }
+
+    #[test]
+    fn parses_persisted_compensation() {
+        assert_eq!(
+            (DegreesDelta(0.5), DegreesDelta(-0.25)),
+            parse_compensation("500 -250").unwrap()
+        );
+        assert_eq!(
+            (DegreesDelta(1.0109), DegreesDelta::ZERO),
+            parse_compensation("1.0109 0").unwrap()
+        );
+        assert_eq!(
+            (DegreesDelta::ZERO, DegreesDelta(-0.25)),
+            parse_compensation("x -250").unwrap()
+        );
+        assert!(parse_compensation("500").is_err());
+    }
+
+    #[test]
+    fn parses_persisted_energy() {
+        let totals = parse_energy("19000 1.25 2.5 140.75\n").unwrap();
+        assert_eq!(19000, totals.day);
+        assert_eq!((1.25, 2.5, 140.75), (totals.today, totals.yesterday, totals.lifetime));
+        assert_eq!(None, parse_energy("19000 1.25 2.5"));
+        assert_eq!(None, parse_energy("today 1.25 2.5 140.75"));
+    }
+
+    #[test]
+    fn parses_persisted_starts() {
+        let counts = parse_starts("1200 19000 4 31 - 28\n").unwrap();
+        assert_eq!((1200, 19000, 4), (counts.lifetime, counts.day, counts.today));
+        assert_eq!(vec![Some(31), None, Some(28)], counts.history);
+        assert_eq!(Vec::<Option<u32>>::new(), parse_starts("0 19000 0").unwrap().history);
+        assert_eq!(None, parse_starts("1200 19000"));
+        assert_eq!(None, parse_starts("1200 19000 4 31 x"));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{
+    cli::{self, Args},
+    control::Command,
+    log_file,
+    net_runtime::{Runtime, Task, Wait},
+    real_world::SensorSpec,
+};
+use anyhow::{bail, Context, Result};
+use log::*;
+use picool_core::config::Config;
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read},
+    os::unix::io::FromRawFd,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc::Sender,
+    },
+    task::Poll,
+    time::Instant,
+};
+
+/// The write end of the pipe the handler wakes the reload task through, -1 until it's installed.
+static WAKE: AtomicI32 = AtomicI32::new(-1);
+
+/// Re-reads the command line, and the `--config` file with it, and keeps what can change without a restart.
+pub struct Reloader {
+    args: Vec<String>,
+    sensor: Option<SensorSpec>,
+    power_pin: Option<u8>,
+    running: Config,
+}
+
+impl Reloader {
+    /// `args` are the command line `running` was parsed from.
+    pub fn new(args: Vec<String>, running: &Args) -> Self {
+        Self {
+            args,
+            sensor: running.sensor.clone(),
+            power_pin: running.power_pin,
+            running: running.config.clone(),
+        }
+    }
+
+    /// The configuration to run with from now on, None when nothing changed or the reload failed.
+    pub fn reload(&mut self) -> Option<Config> {
+        info!("Reloading the configuration.");
+        let parsed = match cli::parse(self.args.clone()) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Reload failed, keeping the running configuration. {:#}", e);
+                return None;
+            }
+        };
+        if parsed.sensor != self.sensor || parsed.power_pin != self.power_pin {
+            warn!("The sensor and the relay pin only change with a restart, keeping the running ones.");
+        }
+        let reloaded = match self.running.reload(&parsed.config) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                warn!("Reload rejected, keeping the running configuration. {:#}", e);
+                return None;
+            }
+        };
+        if reloaded.needs_restart {
+            warn!("Only the band, hysteresis, intervals and sampling reload, other changes wait for a restart.");
+        }
+        if reloaded.changes.is_empty() {
+            info!("Reloaded, nothing to change.");
+            return None;
+        }
+        info!("Reloaded: {}.", reloaded.changes.join(", "));
+        self.running = reloaded.config.clone();
+        Some(reloaded.config)
+    }
+
+    /// Reloads on every SIGHUP from a task on the runtime, sending what changed to the control loop.
+    pub fn on_sighup(self, sender: Sender<Command>, runtime: &mut Runtime) -> Result<()> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to create the reload pipe.");
+        }
+        if WAKE
+            .compare_exchange(-1, fds[1], Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            // SAFETY: Both ends were just opened and aren't shared.
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            bail!("The reload handler is already installed.");
+        }
+        handle_sighup()?;
+        // SAFETY: The read end is ours alone from here.
+        let wake = unsafe { File::from_raw_fd(fds[0]) };
+        let task = ReloadTask {
+            reloader: self,
+            wake,
+            sender,
+        };
+        runtime
+            .spawn("reload", |_| task)
+            .context("Failed starting the reload handler.")?;
+        Ok(())
+    }
+}
+
+struct ReloadTask {
+    reloader: Reloader,
+    wake: File,
+    sender: Sender<Command>,
+}
+
+impl Task for ReloadTask {
+    fn poll(&mut self, _now: Instant, wait: &mut Wait) -> Poll<()> {
+        // Signals that came in together are one reload.
+        let mut hangups = 0;
+        let mut bytes = [0; 16];
+        loop {
+            match self.wake.read(&mut bytes) {
+                Ok(0) => return Poll::Ready(()),
+                Ok(read) => hangups += read,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("Reloading on SIGHUP stopped. {}", e);
+                    return Poll::Ready(());
+                }
+            }
+        }
+        if hangups > 0 {
+            if let Some(config) = self.reloader.reload() {
+                if self.sender.send(Command::Reload(Box::new(config))).is_err() {
+                    return Poll::Ready(());
+                }
+            }
+        }
+        wait.readable(&self.wake);
+        Poll::Pending
+    }
+}
+
+/// Reopens the log file on SIGHUP, and wakes the reload task once there is one. One handler does both, installing
+/// another would replace it.
+pub fn handle_sighup() -> Result<()> {
+    // SAFETY: The handler only stores a flag and writes to the pipe, which stays open for the rest of the process.
+    let result = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_hangup as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut())
+    };
+    if result != 0 {
+        bail!("Failed to install handler for signal {}.", libc::SIGHUP);
+    }
+    Ok(())
+}
+
+extern "C" fn on_hangup(_signal: libc::c_int) {
+    log_file::request_reopen();
+    let fd = WAKE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // SAFETY: write is async-signal-safe, a full pipe already has a reload pending.
+        unsafe { libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, SIGNALS};
+    use picool_core::temperature::Celsius;
+    use std::{fs, path::Path, sync::mpsc::channel, time::Duration};
+
+    fn reloader(path: &Path) -> Reloader {
+        let args = vec!["--config".to_string(), path.display().to_string()];
+        let running = cli::parse(args.clone()).unwrap();
+        Reloader::new(args, &running)
+    }
+
+    #[test]
+    fn applies_what_changed_in_the_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("picool.toml");
+        fs::write(&path, "sensor_path = \"/sensor\"\npower_pin = 17\nmin_off = \"8m\"\n").unwrap();
+        let mut reloader = reloader(&path);
+        assert_eq!(None, reloader.reload());
+
+        fs::write(
+            &path,
+            "sensor_path = \"/sensor\"\npower_pin = 17\nmin_off = \"10m\"\n[target]\nstart = 18.0\nend = 20.0\n",
+        )
+        .unwrap();
+        let config = reloader.reload().unwrap();
+        assert_eq!(Celsius(18.0)..Celsius(20.0), config.band.target);
+        assert_eq!(Duration::from_secs(10 * 60), config.intervals.off);
+        assert_eq!(None, reloader.reload());
+
+        // A new pin is warned about and kept, the rest still applies.
+        fs::write(&path, "sensor_path = \"/sensor\"\npower_pin = 22\nmin_off = \"12m\"\n").unwrap();
+        let config = reloader.reload().unwrap();
+        assert_eq!(Duration::from_secs(12 * 60), config.intervals.off);
+        assert_eq!(Config::default().band, config.band);
+
+        // Nonsense keeps the running configuration.
+        fs::write(&path, "[target]\nstart = 20.0\nend = 18.0\n").unwrap();
+        assert_eq!(None, reloader.reload());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(None, reloader.reload());
+    }
+
+    #[test]
+    fn reloads_on_sighup() {
+        let dir = TempDir::new();
+        let path = dir.path().join("picool.toml");
+        fs::write(&path, "min_on = \"2m\"\n").unwrap();
+        let reloader = reloader(&path);
+        let _signals = SIGNALS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (sender, receiver) = channel();
+        let mut runtime = Runtime::default();
+        reloader.on_sighup(sender, &mut runtime).unwrap();
+        fs::write(&path, "min_on = \"3m\"\n").unwrap();
+        unsafe { libc::raise(libc::SIGHUP) };
+        match receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Command::Reload(config) => assert_eq!(Duration::from_secs(3 * 60), config.intervals.on),
+            command => panic!("{:?}", command),
+        }
+        assert!(Reloader::new(vec![], &Args::default())
+            .on_sighup(channel().0, &mut runtime)
+            .is_err());
+    }
+}
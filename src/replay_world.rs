@@ -0,0 +1,511 @@
+use crate::{
+    shutdown::Shutdown,
+    store_faults::{FaultyStore, StoreFaults},
+    world::{Clock, CompositeWorld, Sensor, Store, Switch},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use picool_core::{
+    boost::Boost,
+    controller::CycleRecord,
+    energy::{EnergyTotals, LocalTime},
+    failover::Output,
+    heartbeat::HeartbeatStatus,
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{Celsius, DegreesDelta},
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals, RestoredPowerState,
+};
+use std::{
+    cell::{Cell, RefCell},
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sample {
+    /// Unix seconds.
+    pub timestamp: f64,
+    pub temperature: Celsius,
+}
+
+/// A recorded temperature log in timestamp order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub samples: Vec<Sample>,
+    /// Rows dropped for not moving forward in time.
+    pub out_of_order: usize,
+}
+
+impl Trace {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}. {}", path.display(), e))?;
+        text.parse()
+            .map_err(|e| anyhow!("Invalid trace {}. {:#}", path.display(), e))
+    }
+
+    pub fn duration(&self) -> Duration {
+        let first = self.samples[0].timestamp;
+        let last = self.samples[self.samples.len() - 1].timestamp;
+        Duration::from_secs_f64(last - first)
+    }
+}
+
+/// `timestamp,temperature` rows, Unix seconds and Celsius. A header row, blank lines and `#` comments are skipped.
+/// A row that doesn't move forward in time is dropped and counted rather than reordered, a clock that jumped back
+/// says nothing reliable about when the reading was taken.
+impl FromStr for Trace {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut samples: Vec<Sample> = Vec::new();
+        let mut out_of_order = 0;
+        let mut seen_row = false;
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let header = !seen_row;
+            seen_row = true;
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != 2 {
+                bail!("Line {}: expected timestamp,temperature, got '{}'.", number, line);
+            }
+            let timestamp = match fields[0].parse::<f64>() {
+                Ok(timestamp) if timestamp.is_finite() => timestamp,
+                _ if header => continue,
+                _ => bail!("Line {}: invalid timestamp '{}'.", number, fields[0]),
+            };
+            let temperature = match fields[1].parse::<f32>() {
+                Ok(temperature) if temperature.is_finite() => Celsius(temperature),
+                _ => bail!("Line {}: invalid temperature '{}'.", number, fields[1]),
+            };
+            match samples.last() {
+                Some(last) if timestamp <= last.timestamp => out_of_order += 1,
+                _ => samples.push(Sample { timestamp, temperature }),
+            }
+        }
+        if samples.is_empty() {
+            bail!("Trace has no readings.");
+        }
+        Ok(Self { samples, out_of_order })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayParameters {
+    /// Readings further apart than this aren't interpolated, the sensor reads as failed in between.
+    pub max_gap: Duration,
+    /// Simulated time per real time, None runs as fast as possible.
+    pub time_warp: Option<f32>,
+    pub store: StoreFaults,
+}
+
+impl Default for ReplayParameters {
+    fn default() -> Self {
+        Self {
+            max_gap: Duration::from_secs(60 * 10),
+            time_warp: None,
+            store: StoreFaults::default(),
+        }
+    }
+}
+
+impl ReplayParameters {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(warp) = self.time_warp {
+            if !(warp > 0.0 && warp.is_finite()) {
+                bail!("Time warp must be positive, got {}.", warp);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The replay every backend shares, time only passes when the clock sleeps.
+struct Replay {
+    trace: Trace,
+    parameters: ReplayParameters,
+    start: Instant,
+    fake_time: Cell<Instant>,
+    /// The last sample at or before the fake time.
+    cursor: Cell<usize>,
+    power_state: Cell<bool>,
+    decisions: RefCell<Box<dyn Write>>,
+    shutdown: Shutdown,
+}
+
+impl Replay {
+    fn timestamp(&self) -> f64 {
+        self.trace.samples[0].timestamp + (self.fake_time.get() - self.start).as_secs_f64()
+    }
+
+    /// Linear between the samples either side, the last reading once the trace has run out.
+    fn temperature(&self) -> Result<Celsius, SensorError> {
+        let samples = &self.trace.samples;
+        let now = self.timestamp();
+        let mut i = self.cursor.get();
+        while i + 1 < samples.len() && samples[i + 1].timestamp <= now {
+            i += 1;
+        }
+        self.cursor.set(i);
+        let (before, after) = match samples.get(i + 1) {
+            Some(after) => (samples[i], after),
+            None => return Ok(samples[i].temperature),
+        };
+        let gap = after.timestamp - before.timestamp;
+        if gap > self.parameters.max_gap.as_secs_f64() {
+            let error = io::Error::other(format!("no readings for {}s", gap));
+            return Err(SensorError::Unreadable(PathBuf::from("trace"), error));
+        }
+        let fraction = ((now - before.timestamp) / gap) as f32;
+        Ok(before.temperature + (after.temperature - before.temperature) * fraction)
+    }
+
+    fn record(&self, state: bool) {
+        let temperature = self.temperature().map(|t| format!("{:.2}", t.0)).unwrap_or_default();
+        let power = match state {
+            true => "on",
+            false => "off",
+        };
+        if let Err(e) = writeln!(
+            self.decisions.borrow_mut(),
+            "{},{},{}",
+            self.timestamp(),
+            temperature,
+            power
+        ) {
+            warn!("Failed to record decision. {}", e);
+        }
+    }
+}
+
+pub struct ReplaySensor(Rc<Replay>);
+
+impl Sensor for ReplaySensor {
+    fn get_temperature(&self) -> Result<Celsius, SensorError> {
+        self.0.temperature()
+    }
+
+    /// Traces only record the chamber.
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+        None
+    }
+
+    fn get_current(&self) -> Option<Result<f32, SensorError>> {
+        None
+    }
+
+    fn reinitialize(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+
+    fn reacquire(&mut self) -> Option<String> {
+        None
+    }
+}
+
+pub struct ReplaySwitch(Rc<Replay>);
+
+impl Switch for ReplaySwitch {
+    fn set_power_state(&mut self, state: bool) {
+        self.0.record(state);
+        self.0.power_state.set(state);
+    }
+
+    /// A trace only has the compressor's relay.
+    fn set_fan_state(&mut self, _state: bool) {}
+
+    fn set_stage2_state(&mut self, _state: bool) {}
+
+    fn set_pump_state(&mut self, _state: bool) {}
+
+    fn pump_state(&self) -> Option<bool> {
+        None
+    }
+
+    fn select_output(&mut self, _output: Output) {}
+
+    fn is_on(&self) -> bool {
+        self.0.power_state.get()
+    }
+}
+
+pub struct ReplayClock(Rc<Replay>);
+
+impl Clock for ReplayClock {
+    /// Requests shutdown once the fake time passes the last reading.
+    fn sleep(&self, duration: Duration) {
+        let replay = &self.0;
+        if let Some(warp) = replay.parameters.time_warp {
+            thread::sleep(duration.div_f32(warp));
+        }
+        replay.fake_time.set(replay.fake_time.get() + duration);
+        if replay.fake_time.get() - replay.start > replay.trace.duration() && !replay.shutdown.is_requested() {
+            info!("Reached the end of the trace.");
+            replay.shutdown.request();
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.0.fake_time.get()
+    }
+
+    /// The trace's timestamps, taken as local time.
+    fn local_time(&self) -> LocalTime {
+        let replay = &self.0;
+        let first = LocalTime::from_local_seconds(replay.trace.samples[0].timestamp as i64);
+        first.plus(replay.fake_time.get() - replay.start)
+    }
+}
+
+/// Nothing survives a replay, every run starts from an unknown off state.
+pub struct ReplayStore;
+
+impl Store for ReplayStore {
+    fn restore_power_state(&self) -> Result<RestoredPowerState> {
+        Ok(RestoredPowerState::OffForUnknownDuration)
+    }
+
+    fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+        (DegreesDelta::ZERO, DegreesDelta::ZERO)
+    }
+
+    fn restore_cooling_rates(&self) -> Vec<f32> {
+        vec![]
+    }
+
+    fn restore_energy(&self) -> EnergyTotals {
+        EnergyTotals::default()
+    }
+
+    fn restore_runtime(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    fn restore_starts(&self) -> StartCounts {
+        StartCounts::default()
+    }
+
+    fn restore_boost(&self) -> Option<Boost> {
+        None
+    }
+
+    fn restore_intervals(&self) -> Option<MinimumIntervals> {
+        None
+    }
+
+    fn restore_overshoots(&self) -> Overshoots {
+        Overshoots::default()
+    }
+
+    fn restore_rest(&self) -> Option<RestPeriod> {
+        None
+    }
+
+    fn restore_shed(&self) -> Option<ShedPeriod> {
+        None
+    }
+
+    fn restore_extremes(&self) -> Option<CycleExtremes> {
+        None
+    }
+
+    fn restore_failover(&self) -> bool {
+        false
+    }
+
+    fn write_heartbeat(&mut self, _status: HeartbeatStatus) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_compensation(&mut self, _cooling: DegreesDelta, _heating: DegreesDelta) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_cooling_rates(&mut self, _rates: &[f32]) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_energy(&mut self, _totals: EnergyTotals) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_runtime(&mut self, _runtime: Duration) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_starts(&mut self, _counts: &StartCounts) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_boost(&mut self, _boost: Option<&Boost>) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_intervals(&mut self, _intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_overshoots(&mut self, _overshoots: &Overshoots) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_rest(&mut self, _rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_shed(&mut self, _shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_extremes(&mut self, _extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn record_cycle(&mut self, _cycle: &CycleRecord) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_failover(&mut self, _faulted: bool) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn switch_sensor(&mut self, _name: &str) {}
+}
+
+pub type ReplayWorld = CompositeWorld<ReplaySensor, ReplaySwitch, ReplayClock, FaultyStore<ReplayStore>>;
+
+impl ReplayWorld {
+    /// Writes each relay decision to `decisions` as `timestamp,temperature,power` and requests shutdown at the end
+    /// of the trace.
+    pub fn new(
+        trace: Trace,
+        parameters: ReplayParameters,
+        mut decisions: Box<dyn Write>,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        parameters.validate()?;
+        writeln!(decisions, "timestamp,temperature,power").context("Failed to write decisions.")?;
+        let start = Instant::now();
+        let store = FaultyStore::new(ReplayStore, parameters.store.clone());
+        let replay = Rc::new(Replay {
+            trace,
+            parameters,
+            start,
+            fake_time: Cell::new(start),
+            cursor: Cell::new(0),
+            power_state: Cell::new(false),
+            decisions: RefCell::new(decisions),
+            shutdown,
+        });
+        Ok(Self {
+            sensor: ReplaySensor(replay.clone()),
+            switch: ReplaySwitch(replay.clone()),
+            clock: ReplayClock(replay),
+            store,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::World;
+
+    fn world(trace: &str) -> ReplayWorld {
+        let trace = trace.parse().unwrap();
+        ReplayWorld::new(
+            trace,
+            ReplayParameters::default(),
+            Box::new(io::sink()),
+            Shutdown::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_rows() {
+        let trace: Trace = "timestamp,temperature\n# comment\n\n100, 4.5\n160,5\n130,9\n160,9\n220,5.5"
+            .parse()
+            .unwrap();
+        let timestamps: Vec<f64> = trace.samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(vec![100.0, 160.0, 220.0], timestamps);
+        assert_eq!(2, trace.out_of_order);
+        assert_eq!(Duration::from_secs(120), trace.duration());
+
+        let error = |text: &str| format!("{:#}", text.parse::<Trace>().unwrap_err());
+        assert_eq!("Line 3: invalid timestamp 'noon'.", error("100,4\n200,4\nnoon,4"));
+        assert_eq!("Line 2: invalid temperature 'NaN'.", error("100,4\n200,NaN"));
+        assert_eq!("Line 1: expected timestamp,temperature, got '100'.", error("100"));
+        assert_eq!("Trace has no readings.", error("timestamp,temperature\n"));
+    }
+
+    #[test]
+    fn interpolates_between_readings() {
+        let world = world("1000,4\n1060,7\n1120,1");
+        assert_eq!(Celsius(4.0), world.get_temperature().unwrap());
+        world.sleep(Duration::from_secs(20));
+        assert_eq!(Celsius(5.0), world.get_temperature().unwrap());
+        world.sleep(Duration::from_secs(70));
+        assert_eq!(Celsius(4.0), world.get_temperature().unwrap());
+    }
+
+    #[test]
+    fn gaps_read_as_sensor_errors() {
+        let world = world("0,4\n60,4\n3660,4");
+        world.sleep(Duration::from_secs(30));
+        assert!(world.get_temperature().is_ok());
+        world.sleep(Duration::from_secs(60));
+        assert!(world.get_temperature().is_err());
+        world.sleep(Duration::from_secs(3600));
+        assert_eq!(Celsius(4.0), world.get_temperature().unwrap());
+    }
+
+    #[test]
+    fn store_faults_apply() {
+        use crate::store_faults::PersistFailures;
+        let parameters = ReplayParameters {
+            store: StoreFaults {
+                persist_failures: PersistFailures::Always,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let trace = "0,4\n60,4".parse().unwrap();
+        let mut world = ReplayWorld::new(trace, parameters, Box::new(io::sink()), Shutdown::default()).unwrap();
+        assert!(world.persist_last_off_transition().is_err());
+        assert!(world.write_heartbeat(HeartbeatStatus::Controlling).is_ok());
+    }
+
+    #[test]
+    fn shutdown_at_end_of_trace() {
+        let shutdown = Shutdown::default();
+        let trace = "0,4\n60,4".parse().unwrap();
+        let world = ReplayWorld::new(
+            trace,
+            ReplayParameters::default(),
+            Box::new(io::sink()),
+            shutdown.clone(),
+        )
+        .unwrap();
+        world.sleep(Duration::from_secs(60));
+        assert!(!shutdown.is_requested());
+        world.sleep(Duration::from_secs(10));
+        assert!(shutdown.is_requested());
+    }
+}
@@ -0,0 +1,511 @@
+use crate::store_faults::StoreFaults;
+use anyhow::{anyhow, bail, Context, Result};
+use picool_core::{temperature::Celsius, world_error::PersistErrorKind};
+use std::{fmt, fs, path::Path, time::Duration};
+
+const EVENT_TABLE: &str = "[[event]]";
+const STORE_TABLE: &str = "[store]";
+
+/// (line, key, value)
+type Entry<'a> = (usize, &'a str, &'a str);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    /// Every reading fails for this long.
+    SensorErrors(Duration),
+    /// Shifts the ambient for the rest of the run, rc model only.
+    AmbientStep(f32),
+    /// Heat let in at a constant rate over the duration.
+    DoorOpen { heat: f32, over: Duration },
+    /// Replaces the next reading, the way a DS18B20 reports 85C after a brown-out.
+    Reading(Celsius),
+    /// The next this many reads fail.
+    FailureBurst(u32),
+    /// Each read fails with this probability over the duration.
+    FailureProbability { probability: f32, over: Duration },
+    /// The reading sticks at its current value for this long.
+    Stuck(Duration),
+    /// The next reading is off by this much.
+    Spike(f32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Event {
+    pub at: Duration,
+    pub action: Action,
+}
+
+impl Event {
+    /// When the event stops affecting the simulation.
+    pub fn end(&self) -> Duration {
+        match self.action {
+            Action::SensorErrors(duration) => self.at + duration,
+            Action::DoorOpen { over, .. } | Action::FailureProbability { over, .. } => self.at + over,
+            Action::Stuck(duration) => self.at + duration,
+            Action::AmbientStep(_) | Action::Reading(_) | Action::FailureBurst(_) | Action::Spike(_) => self.at,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {}s ", self.at.as_secs())?;
+        match self.action {
+            Action::SensorErrors(duration) => write!(f, "sensor errors for {}s", duration.as_secs()),
+            Action::AmbientStep(delta) => write!(f, "ambient steps {}C", delta),
+            Action::DoorOpen { heat, over } => write!(f, "door open adds {}C over {}s", heat, over.as_secs()),
+            Action::Reading(temperature) => write!(f, "reading {}", temperature),
+            Action::FailureBurst(reads) => write!(f, "next {} reads fail", reads),
+            Action::FailureProbability { probability, over } => {
+                write!(f, "reads fail with probability {} for {}s", probability, over.as_secs())
+            }
+            Action::Stuck(duration) => write!(f, "reading stuck for {}s", duration.as_secs()),
+            Action::Spike(delta) => write!(f, "reading spikes {}C", delta),
+        }
+    }
+}
+
+/// Timed events applied to the demo world, ordered by time, and faults on its store.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Scenario {
+    pub events: Vec<Event>,
+    pub store: StoreFaults,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}. {}", path.display(), e))?;
+        text.parse()
+            .map_err(|e| anyhow!("Invalid scenario {}. {:#}", path.display(), e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+/// Parses a TOML subset, one `[[event]]` table per event with an `at` offset and exactly one action, and an optional
+/// `[store]` table:
+///
+/// ```toml
+/// [[event]]
+/// at = "2h"
+/// sensor_errors = "10m"
+///
+/// [[event]]
+/// at = "6h"
+/// door_open = 3.0
+/// over = "2m"
+///
+/// [store]
+/// persist_failures = "after 5"
+/// persist_error = "storage full"
+/// ```
+impl std::str::FromStr for Scenario {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        // (line, header, entries)
+        let mut tables: Vec<(usize, &str, Vec<Entry>)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == STORE_TABLE && tables.iter().any(|&(_, header, _)| header == STORE_TABLE) {
+                bail!("Line {}: only one {} table is allowed.", number, STORE_TABLE);
+            }
+            if line == EVENT_TABLE || line == STORE_TABLE {
+                tables.push((number, line, Vec::new()));
+                continue;
+            }
+            if line.starts_with('[') {
+                bail!(
+                    "Line {}: unknown table {}, expected {} or {}.",
+                    number,
+                    line,
+                    EVENT_TABLE,
+                    STORE_TABLE
+                );
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => bail!("Line {}: expected key = value, got '{}'.", number, line),
+            };
+            match tables.last_mut() {
+                Some((_, _, entries)) => entries.push((number, key, value)),
+                None => bail!("Line {}: '{}' is outside an {} table.", number, key, EVENT_TABLE),
+            }
+        }
+        let mut scenario = Self::default();
+        for (number, header, entries) in tables {
+            match header {
+                STORE_TABLE => scenario.store = parse_store(&entries)?,
+                _ => scenario.events.push(parse_event(number, &entries)?),
+            }
+        }
+        scenario.events.sort_by_key(|event| event.at);
+        Ok(scenario)
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    // Neither durations nor numbers contain '#', so quoting doesn't need tracking.
+    match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+fn parse_event(table_line: usize, entries: &[Entry]) -> Result<Event> {
+    let mut at = None;
+    let mut over = None;
+    let mut action = None;
+    for &(number, key, value) in entries {
+        let context = || format!("Line {}: invalid {}", number, key);
+        let parsed = match key {
+            "at" => {
+                at = Some(parse_duration(value).with_context(context)?);
+                continue;
+            }
+            "over" => {
+                over = Some(parse_positive_duration(value).with_context(context)?);
+                continue;
+            }
+            "sensor_errors" => Action::SensorErrors(parse_positive_duration(value).with_context(context)?),
+            "ambient_step" => Action::AmbientStep(parse_finite(value).with_context(context)?),
+            "door_open" => Action::DoorOpen {
+                heat: parse_finite(value).with_context(context)?,
+                over: Duration::from_secs(0),
+            },
+            "reading" => Action::Reading(Celsius(parse_finite(value).with_context(context)?)),
+            "failure_burst" => Action::FailureBurst(parse_positive_count(value).with_context(context)?),
+            "failure_probability" => Action::FailureProbability {
+                probability: parse_probability(value).with_context(context)?,
+                over: Duration::from_secs(0),
+            },
+            "stuck" => Action::Stuck(parse_positive_duration(value).with_context(context)?),
+            "spike" => Action::Spike(parse_finite(value).with_context(context)?),
+            _ => bail!("Line {}: unknown key '{}'.", number, key),
+        };
+        if action.replace((key, parsed)).is_some() {
+            bail!("Line {}: an event takes exactly one action.", number);
+        }
+    }
+    let at = at.ok_or_else(|| anyhow!("Line {}: event is missing 'at'.", table_line))?;
+    let action = match (action, over) {
+        (Some((_, Action::DoorOpen { heat, .. })), Some(over)) => Action::DoorOpen { heat, over },
+        (Some((_, Action::FailureProbability { probability, .. })), Some(over)) => {
+            Action::FailureProbability { probability, over }
+        }
+        (Some((key, Action::DoorOpen { .. })), None) | (Some((key, Action::FailureProbability { .. })), None) => {
+            bail!("Line {}: {} needs 'over'.", table_line, key)
+        }
+        (_, Some(_)) => bail!(
+            "Line {}: only door_open and failure_probability take 'over'.",
+            table_line
+        ),
+        (Some((_, action)), None) => action,
+        (None, None) => bail!("Line {}: event has no action.", table_line),
+    };
+    Ok(Event { at, action })
+}
+
+fn parse_store(entries: &[Entry]) -> Result<StoreFaults> {
+    let mut faults = StoreFaults::default();
+    for &(number, key, value) in entries {
+        let context = || format!("Line {}: invalid {}", number, key);
+        match key {
+            "seed" => {
+                faults.seed = value
+                    .parse()
+                    .map_err(|_| anyhow!("Expected a whole number, got {}.", value))
+                    .with_context(context)?
+            }
+            "persist_failures" => faults.persist_failures = value.trim_matches('"').parse().with_context(context)?,
+            "persist_error" => faults.persist_error = parse_error_kind(value).with_context(context)?,
+            "corrupt_restore" => faults.corrupt_restore = parse_bool(value).with_context(context)?,
+            "stale_restore" => faults.stale_restore = Some(parse_positive_duration(value).with_context(context)?),
+            _ => bail!("Line {}: unknown key '{}'.", number, key),
+        }
+    }
+    Ok(faults)
+}
+
+fn parse_error_kind(value: &str) -> Result<PersistErrorKind> {
+    let kinds = [
+        PersistErrorKind::Io,
+        PersistErrorKind::StorageFull,
+        PersistErrorKind::ReadOnly,
+        PersistErrorKind::PermissionDenied,
+    ];
+    let name = value.trim_matches('"');
+    match kinds.iter().find(|kind| kind.to_string() == name) {
+        Some(&kind) => Ok(kind),
+        None => bail!(
+            "Expected one of {}, got {}.",
+            kinds
+                .iter()
+                .map(|k| format!("\"{}\"", k))
+                .collect::<Vec<_>>()
+                .join(", "),
+            value
+        ),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => bail!("Expected true or false, got {}.", value),
+    }
+}
+
+fn unquote(value: &str) -> Result<&str> {
+    match value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        true => Ok(&value[1..value.len() - 1]),
+        false => bail!("Expected a quoted duration like \"10m\", got {}.", value),
+    }
+}
+
+/// Whole seconds, minutes, hours or days, e.g. "90s", "10m", "2h", "1d".
+pub(crate) fn parse_duration(value: &str) -> Result<Duration> {
+    let value = unquote(value)?;
+    let unit = value.chars().last().unwrap_or_default();
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        _ => bail!("Duration '{}' needs a unit of s, m, h or d.", value),
+    };
+    let count: u64 = value[..value.len() - unit.len_utf8()]
+        .parse()
+        .with_context(|| format!("Duration '{}' is not a whole number.", value))?;
+    Ok(Duration::from_secs(count * scale))
+}
+
+pub(crate) fn parse_positive_duration(value: &str) -> Result<Duration> {
+    match parse_duration(value)? {
+        duration if duration > Duration::from_secs(0) => Ok(duration),
+        _ => bail!("Duration must be positive."),
+    }
+}
+
+fn parse_positive_count(value: &str) -> Result<u32> {
+    match value.parse::<u32>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => bail!("Expected a positive count, got {}.", value),
+    }
+}
+
+fn parse_probability(value: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(probability) if (0.0..=1.0).contains(&probability) => Ok(probability),
+        _ => bail!("Expected a probability between 0 and 1, got {}.", value),
+    }
+}
+
+pub(crate) fn parse_finite(value: &str) -> Result<f32> {
+    match value.parse::<f32>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => bail!("Expected a number, got {}.", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        format!("{:#}", text.parse::<Scenario>().unwrap_err())
+    }
+
+    #[test]
+    fn parses_each_action_in_time_order() {
+        let scenario: Scenario = "
+            # Out of order on purpose.
+            [[event]]
+            at = \"8h\"
+            reading = 85.0
+
+            [[event]]
+            at = \"2h\"  # afternoon
+            sensor_errors = \"10m\"
+
+            [[event]]
+            at = \"6h\"
+            door_open = 3.0
+            over = \"2m\"
+
+            [[event]]
+            at = \"300m\"
+            ambient_step = 5
+        "
+        .parse()
+        .unwrap();
+        let hours = |h: u64| Duration::from_secs(60 * 60 * h);
+        assert_eq!(
+            vec![
+                Event {
+                    at: hours(2),
+                    action: Action::SensorErrors(Duration::from_secs(600)),
+                },
+                Event {
+                    at: hours(5),
+                    action: Action::AmbientStep(5.0),
+                },
+                Event {
+                    at: hours(6),
+                    action: Action::DoorOpen {
+                        heat: 3.0,
+                        over: Duration::from_secs(120),
+                    },
+                },
+                Event {
+                    at: hours(8),
+                    action: Action::Reading(Celsius(85.0)),
+                },
+            ],
+            scenario.events
+        );
+        assert_eq!(hours(2) + Duration::from_secs(600), scenario.events[0].end());
+        assert!("".parse::<Scenario>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_fault_actions() {
+        let scenario: Scenario = "
+            [[event]]
+            at = \"1h\"
+            failure_burst = 12
+
+            [[event]]
+            at = \"2h\"
+            failure_probability = 0.25
+            over = \"30m\"
+
+            [[event]]
+            at = \"3h\"
+            stuck = \"20m\"
+
+            [[event]]
+            at = \"4h\"
+            spike = -6.5
+        "
+        .parse()
+        .unwrap();
+        let actions: Vec<Action> = scenario.events.iter().map(|e| e.action).collect();
+        assert_eq!(
+            vec![
+                Action::FailureBurst(12),
+                Action::FailureProbability {
+                    probability: 0.25,
+                    over: Duration::from_secs(60 * 30),
+                },
+                Action::Stuck(Duration::from_secs(60 * 20)),
+                Action::Spike(-6.5),
+            ],
+            actions
+        );
+    }
+
+    #[test]
+    fn parses_store_faults() {
+        use crate::store_faults::PersistFailures;
+        let scenario: Scenario = "
+            [store]
+            seed = 9
+            persist_failures = \"after 3\"
+            persist_error = \"read-only\"
+            corrupt_restore = true
+            stale_restore = \"2d\"
+
+            [[event]]
+            at = \"1h\"
+            failure_burst = 1
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(1, scenario.events.len());
+        assert_eq!(
+            StoreFaults {
+                seed: 9,
+                persist_failures: PersistFailures::After(3),
+                persist_error: PersistErrorKind::ReadOnly,
+                corrupt_restore: true,
+                stale_restore: Some(Duration::from_secs(60 * 60 * 48)),
+            },
+            scenario.store
+        );
+        let intermittent: Scenario = "[store]\npersist_failures = 0.1".parse().unwrap();
+        assert_eq!(PersistFailures::Intermittent(0.1), intermittent.store.persist_failures);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!("Line 1: 'at' is outside an [[event]] table.", error("at = \"1h\""));
+        assert_eq!(
+            "Line 2: unknown table [events], expected [[event]] or [store].",
+            error("\n[events]")
+        );
+        assert_eq!(
+            "Line 3: unknown key 'sensor_error'.",
+            error("[[event]]\nat = \"1h\"\nsensor_error = \"1m\"")
+        );
+        assert_eq!(
+            "Line 2: invalid at: Duration '2x' needs a unit of s, m, h or d.",
+            error("[[event]]\nat = \"2x\"\nreading = 1")
+        );
+        assert_eq!(
+            "Line 3: invalid sensor_errors: Duration must be positive.",
+            error("[[event]]\nat = \"1h\"\nsensor_errors = \"0m\"")
+        );
+        assert_eq!(
+            "Line 3: invalid reading: Expected a number, got nan.",
+            error("[[event]]\nat = \"1h\"\nreading = nan")
+        );
+        assert_eq!("Line 1: event is missing 'at'.", error("[[event]]\nreading = 1"));
+        assert_eq!("Line 1: event has no action.", error("[[event]]\nat = \"1h\""));
+        assert_eq!(
+            "Line 4: an event takes exactly one action.",
+            error("[[event]]\nat = \"1h\"\nreading = 1\nambient_step = 1")
+        );
+        assert_eq!(
+            "Line 1: door_open needs 'over'.",
+            error("[[event]]\nat = \"1h\"\ndoor_open = 1")
+        );
+        assert_eq!(
+            "Line 1: only door_open and failure_probability take 'over'.",
+            error("[[event]]\nat = \"1h\"\nreading = 1\nover = \"1m\"")
+        );
+        assert_eq!("Line 2: expected key = value, got 'at'.", error("[[event]]\nat"));
+        assert_eq!(
+            "Line 1: failure_probability needs 'over'.",
+            error("[[event]]\nat = \"1h\"\nfailure_probability = 0.5")
+        );
+        assert_eq!(
+            "Line 3: invalid failure_probability: Expected a probability between 0 and 1, got 2.",
+            error("[[event]]\nat = \"1h\"\nfailure_probability = 2\nover = \"1m\"")
+        );
+        assert_eq!(
+            "Line 3: invalid failure_burst: Expected a positive count, got 0.",
+            error("[[event]]\nat = \"1h\"\nfailure_burst = 0")
+        );
+        assert_eq!(
+            "Line 3: only one [store] table is allowed.",
+            error("[store]\nseed = 1\n[store]")
+        );
+        assert_eq!(
+            "Line 2: invalid persist_error: Expected one of \"i/o\", \"storage full\", \"read-only\", \"permission denied\", got \"gone\".",
+            error("[store]\npersist_error = \"gone\"")
+        );
+        assert_eq!(
+            "Line 2: invalid corrupt_restore: Expected true or false, got yes.",
+            error("[store]\ncorrupt_restore = yes")
+        );
+        assert_eq!("Line 2: unknown key 'at'.", error("[store]\nat = \"1h\""));
+    }
+}
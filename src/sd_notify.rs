@@ -0,0 +1,145 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    env,
+    ffi::OsString,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process,
+    time::Duration,
+};
+
+/// The systemd notification protocol, newline separated assignments sent as one datagram to $NOTIFY_SOCKET.
+pub struct SdNotify {
+    socket: UnixDatagram,
+    address: Option<SocketAddr>,
+}
+
+impl SdNotify {
+    /// None when not started by systemd with Type=notify.
+    pub fn from_env() -> Result<Option<Self>> {
+        match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => Self::connect(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn connect(path: OsString) -> Result<Self> {
+        let bytes = path.as_bytes();
+        let address = match bytes.first() {
+            Some(b'@') => SocketAddr::from_abstract_name(&bytes[1..]),
+            Some(b'/') => SocketAddr::from_pathname(&path),
+            _ => bail!("Unsupported NOTIFY_SOCKET '{}'.", path.to_string_lossy()),
+        }
+        .context("Invalid NOTIFY_SOCKET address.")?;
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            address: Some(address),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn from_connected(socket: UnixDatagram) -> Self {
+        Self { socket, address: None }
+    }
+
+    pub fn notify(&self, state: &str) -> Result<()> {
+        let sent = match &self.address {
+            Some(address) => self.socket.send_to_addr(state.as_bytes(), address),
+            None => self.socket.send(state.as_bytes()),
+        }
+        .context("Failed to send to the notify socket.")?;
+        if sent != state.len() {
+            bail!("Short write to the notify socket.");
+        }
+        Ok(())
+    }
+
+    pub fn ready(&self) -> Result<()> {
+        self.notify("READY=1")
+    }
+
+    pub fn status(&self, status: &str) -> Result<()> {
+        // A newline would start a new assignment.
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")))
+    }
+
+    pub fn watchdog(&self) -> Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+}
+
+/// The WatchdogSec= interval when it applies to this process.
+pub fn watchdog_interval_from_env() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok();
+    let pid = env::var("WATCHDOG_PID").ok();
+    watchdog_interval(usec.as_deref(), pid.as_deref(), process::id())
+}
+
+// Pure
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    match usec?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (SdNotify, UnixDatagram) {
+        let (ours, systemd) = UnixDatagram::pair().unwrap();
+        (SdNotify::from_connected(ours), systemd)
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn sends_one_datagram_per_notification() {
+        let (notify, systemd) = pair();
+        notify.ready().unwrap();
+        notify.status("On at 3.2C\nextra").unwrap();
+        notify.watchdog().unwrap();
+        assert_eq!("READY=1", receive(&systemd));
+        assert_eq!("STATUS=On at 3.2C extra", receive(&systemd));
+        assert_eq!("WATCHDOG=1", receive(&systemd));
+    }
+
+    #[test]
+    fn sends_to_path_address() {
+        let dir = crate::test_util::TempDir::new();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notify = SdNotify::connect(path.into_os_string()).unwrap();
+        notify.ready().unwrap();
+        assert_eq!("READY=1", receive(&systemd));
+        assert!(SdNotify::connect("relative".into()).is_err());
+    }
+
+    #[test]
+    fn watchdog_interval_applies_to_own_pid() {
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            watchdog_interval(Some("30000000"), None, 42)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            watchdog_interval(Some("30000000"), Some("42"), 42)
+        );
+        assert_eq!(None, watchdog_interval(Some("30000000"), Some("41"), 42));
+        assert_eq!(None, watchdog_interval(Some("0"), None, 42));
+        assert_eq!(None, watchdog_interval(None, None, 42));
+    }
+}
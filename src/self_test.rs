@@ -0,0 +1,262 @@
+use anyhow::Result;
+use std::{fmt, fs, path::Path, time::Duration};
+
+const SENSOR_SAMPLES: usize = 3;
+const SENSOR_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const SENSOR_PLAUSIBLE_RANGE: (f32, f32) = (-55.0, 125.0); // DS18B20 operating range
+const SENSOR_MAX_SPREAD: f32 = 2.0;
+const PROBE_FILE_NAME: &str = ".picool_self_test";
+
+/// The pieces of a world the self-test exercises, each check only touches what it needs.
+pub trait SelfTestWorld {
+    fn read_temperature(&self) -> Result<f32>;
+    fn persistence_dir(&self) -> &Path;
+    fn set_output(&mut self, on: bool);
+    fn output_readback(&self) -> bool;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct CheckResult {
+    pub name: &'static str,
+    pub mandatory: bool,
+    pub passed: bool,
+    pub details: String,
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match (self.passed, self.mandatory) {
+            (true, _) => "PASS",
+            (false, true) => "FAIL",
+            (false, false) => "WARN",
+        };
+        write!(f, "[{}] {}: {}", status, self.name, self.details)
+    }
+}
+
+/// Run every check. The relay is only pulsed when `pulse` is given because it clicks the relay, otherwise its output
+/// is only read back.
+pub fn run_checks(world: &mut impl SelfTestWorld, pulse: Option<Duration>) -> Vec<CheckResult> {
+    let mut results = vec![check_sensor(world), check_persistence(world.persistence_dir())];
+    match pulse {
+        Some(duration) => results.push(check_relay(world, duration)),
+        None => results.push(read_relay(world)),
+    }
+    results
+}
+
+pub fn all_mandatory_passed(results: &[CheckResult]) -> bool {
+    results.iter().all(|r| r.passed || !r.mandatory)
+}
+
+pub fn check_sensor(world: &impl SelfTestWorld) -> CheckResult {
+    let mut readings = Vec::with_capacity(SENSOR_SAMPLES);
+    for i in 0..SENSOR_SAMPLES {
+        if i > 0 {
+            world.sleep(SENSOR_SAMPLE_INTERVAL);
+        }
+        match world.read_temperature() {
+            Ok(t) => readings.push(t),
+            Err(e) => return result("sensor", true, false, format!("read {} failed: {:#}", i + 1, e)),
+        }
+    }
+
+    let details = format!("readings {:?}", readings);
+    if let Some(bad) = readings.iter().find(|t| !t.is_finite()) {
+        return result("sensor", true, false, format!("{}, non-finite value {}", details, bad));
+    }
+    if let Some(bad) = readings
+        .iter()
+        .find(|&&t| t < SENSOR_PLAUSIBLE_RANGE.0 || t > SENSOR_PLAUSIBLE_RANGE.1)
+    {
+        return result("sensor", true, false, format!("{}, implausible value {}", details, bad));
+    }
+    let min = readings.iter().copied().fold(f32::MAX, f32::min);
+    let max = readings.iter().copied().fold(f32::MIN, f32::max);
+    if max - min > SENSOR_MAX_SPREAD {
+        return result(
+            "sensor",
+            true,
+            false,
+            format!("{}, inconsistent spread {:.2}C", details, max - min),
+        );
+    }
+    result("sensor", true, true, details)
+}
+
+pub fn check_persistence(dir: &Path) -> CheckResult {
+    let probe = dir.join(PROBE_FILE_NAME);
+    let outcome = fs::write(&probe, "probe")
+        .and_then(|_| fs::read_to_string(&probe))
+        .and_then(|data| match data == "probe" {
+            true => fs::remove_file(&probe),
+            false => Err(std::io::Error::other("read back mismatch")),
+        });
+    match outcome {
+        Ok(()) => result("persistence", true, true, format!("{} is writable", dir.display())),
+        Err(e) => result(
+            "persistence",
+            true,
+            false,
+            format!("{} is not writable: {}", dir.display(), e),
+        ),
+    }
+}
+
+pub fn check_relay(world: &mut impl SelfTestWorld, duration: Duration) -> CheckResult {
+    if world.output_readback() {
+        // Briefly cutting a running compressor is worse than skipping the check.
+        return result("relay", false, true, "skipped, output is currently on".into());
+    }
+    world.set_output(true);
+    let on = world.output_readback();
+    world.sleep(duration);
+    world.set_output(false);
+    let off = !world.output_readback();
+    match (on, off) {
+        (true, true) => result("relay", false, true, format!("pulsed for {:?}", duration)),
+        (false, _) => result("relay", false, false, "output did not read back on".into()),
+        (true, false) => result("relay", true, false, "output did not read back off".into()),
+    }
+}
+
+/// The pin was acquired getting this far, what it reads back is only reported.
+pub fn read_relay(world: &impl SelfTestWorld) -> CheckResult {
+    let state = match world.output_readback() {
+        true => "on",
+        false => "off",
+    };
+    result("relay", false, true, format!("acquired, reads back {}", state))
+}
+
+fn result(name: &'static str, mandatory: bool, passed: bool, details: String) -> CheckResult {
+    CheckResult {
+        name,
+        mandatory,
+        passed,
+        details,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use anyhow::anyhow;
+    use std::{
+        cell::{Cell, RefCell},
+        path::PathBuf,
+    };
+
+    struct MockWorld {
+        readings: RefCell<Vec<Result<f32>>>,
+        dir: PathBuf,
+        output: bool,
+        stuck_output: Option<bool>,
+        slept: Cell<Duration>,
+    }
+
+    impl MockWorld {
+        fn new(readings: Vec<Result<f32>>, dir: &Path) -> Self {
+            Self {
+                readings: RefCell::new(readings.into_iter().rev().collect()),
+                dir: dir.to_path_buf(),
+                output: false,
+                stuck_output: None,
+                slept: Cell::new(Duration::from_secs(0)),
+            }
+        }
+    }
+
+    impl SelfTestWorld for MockWorld {
+        fn read_temperature(&self) -> Result<f32> {
+            self.readings
+                .borrow_mut()
+                .pop()
+                .unwrap_or_else(|| Err(anyhow!("no more readings")))
+        }
+
+        fn persistence_dir(&self) -> &Path {
+            &self.dir
+        }
+
+        fn set_output(&mut self, on: bool) {
+            self.output = on;
+        }
+
+        fn output_readback(&self) -> bool {
+            self.stuck_output.unwrap_or(self.output)
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.slept.set(self.slept.get() + duration);
+        }
+    }
+
+    #[test]
+    fn sensor_consistent_readings_pass() {
+        let dir = TempDir::new();
+        let world = MockWorld::new(vec![Ok(3.0), Ok(3.1), Ok(3.0)], dir.path());
+        assert!(check_sensor(&world).passed);
+    }
+
+    #[test]
+    fn sensor_failures() {
+        let dir = TempDir::new();
+        let bad_sets = vec![
+            vec![Ok(3.0), Err(anyhow!("gone")), Ok(3.0)],
+            vec![Ok(3.0), Ok(f32::NAN), Ok(3.0)],
+            vec![Ok(3.0), Ok(3.0), Ok(85.0 + 100.0)],
+            vec![Ok(3.0), Ok(8.0), Ok(3.0)],
+        ];
+        for readings in bad_sets {
+            let world = MockWorld::new(readings, dir.path());
+            let check = check_sensor(&world);
+            assert!(!check.passed, "{}", check);
+            assert!(check.mandatory);
+        }
+    }
+
+    #[test]
+    fn persistence_probe() {
+        let dir = TempDir::new();
+        assert!(check_persistence(dir.path()).passed);
+        assert!(!dir.path().join(PROBE_FILE_NAME).exists());
+        assert!(!check_persistence(&dir.path().join("missing")).passed);
+    }
+
+    #[test]
+    fn relay_pulse() {
+        let dir = TempDir::new();
+        let mut world = MockWorld::new(vec![], dir.path());
+        let check = check_relay(&mut world, Duration::from_millis(500));
+        assert!(check.passed, "{}", check);
+        assert!(!world.output);
+        assert_eq!(Duration::from_millis(500), world.slept.get());
+    }
+
+    #[test]
+    fn relay_stuck_off_and_on() {
+        let dir = TempDir::new();
+        let mut world = MockWorld::new(vec![], dir.path());
+        world.stuck_output = Some(false);
+        assert!(!check_relay(&mut world, Duration::from_millis(1)).passed);
+
+        world.stuck_output = Some(true);
+        let check = check_relay(&mut world, Duration::from_millis(1));
+        assert!(check.passed, "on at start must be skipped: {}", check);
+        assert!(world.slept.get() < Duration::from_millis(2));
+    }
+
+    #[test]
+    fn run_checks_without_pulse_never_touches_output() {
+        let dir = TempDir::new();
+        let mut world = MockWorld::new(vec![Ok(3.0), Ok(3.0), Ok(3.0)], dir.path());
+        world.stuck_output = Some(false);
+        let results = run_checks(&mut world, None);
+        assert_eq!(3, results.len());
+        assert!(all_mandatory_passed(&results));
+        assert_eq!("[PASS] relay: acquired, reads back off", results[2].to_string());
+        assert_eq!(Duration::from_secs(2), world.slept.get());
+    }
+}
@@ -0,0 +1,226 @@
+//! `--shadow` runs the whole controller next to whatever is really in charge of the chamber, say an STC-1000,
+//! without ever switching the relay, so its decisions can be compared before it's trusted with them.
+
+use crate::{World, WorldState};
+use anyhow::Result;
+use log::*;
+use picool_core::{
+    boost::Boost,
+    controller::CycleRecord,
+    energy::{EnergyTotals, LocalTime},
+    failover::Output,
+    heartbeat::HeartbeatStatus,
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{Celsius, DegreesDelta},
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals, RestoredPowerState,
+};
+use std::{
+    cell::Cell,
+    io::Write,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Logs what the relay, fan, stage 2 and pump would do instead of doing it. Everything else goes through to `inner`.
+pub struct Shadow<W> {
+    inner: W,
+    relay: bool,
+    fan: bool,
+    stage2: bool,
+    pump: bool,
+    /// The last good reading, for the decisions.
+    reading: Cell<Option<Celsius>>,
+    /// Each relay decision as `timestamp,temperature,power`, the rows `picool replay` writes.
+    decisions: Option<Box<dyn Write>>,
+    /// Unix seconds at `started`, the world's clock may be a simulation's.
+    epoch: f64,
+    started: Instant,
+}
+
+impl<W: World> Shadow<W> {
+    pub fn new(inner: W, mut decisions: Option<Box<dyn Write>>) -> Self {
+        if let Some(decisions) = &mut decisions {
+            if let Err(e) = writeln!(decisions, "timestamp,temperature,power") {
+                warn!("Failed to record decisions. {}", e);
+            }
+        }
+        let started = inner.now();
+        Self {
+            inner,
+            relay: false,
+            fan: false,
+            stage2: false,
+            pump: false,
+            reading: Cell::new(None),
+            decisions,
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            started,
+        }
+    }
+
+    fn record(&mut self, on: bool) {
+        let timestamp = self.epoch + (self.inner.now() - self.started).as_secs_f64();
+        let temperature = self.reading.get().map(|t| format!("{:.2}", t.0)).unwrap_or_default();
+        let power = if on { "on" } else { "off" };
+        if let Some(decisions) = &mut self.decisions {
+            // Flushed as it goes, a shadow runs for days.
+            let written = writeln!(decisions, "{:.0},{},{}", timestamp, temperature, power);
+            if let Err(e) = written.and_then(|()| decisions.flush()) {
+                warn!("Failed to record decision. {}", e);
+            }
+        }
+    }
+}
+
+impl<W: World> World for Shadow<W> {
+    fn get_temperature(&self) -> Result<Celsius, SensorError> {
+        let temperature = self.inner.get_temperature();
+        if let Ok(t) = temperature {
+            self.reading.set(Some(t));
+        }
+        temperature
+    }
+
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+        self.inner.get_ambient_temperature()
+    }
+
+    fn get_current(&self) -> Option<Result<f32, SensorError>> {
+        self.inner.get_current()
+    }
+
+    fn set_power_state(&mut self, state: bool) {
+        if state != self.relay {
+            info!("Shadow: would switch the relay {}", if state { "ON" } else { "OFF" });
+            self.relay = state;
+            self.record(state);
+        }
+    }
+
+    fn set_fan_state(&mut self, state: bool) {
+        if state != self.fan {
+            info!("Shadow: would switch the fan {}", if state { "ON" } else { "OFF" });
+            self.fan = state;
+        }
+    }
+
+    fn set_stage2_state(&mut self, state: bool) {
+        if state != self.stage2 {
+            info!("Shadow: would switch stage 2 {}", if state { "ON" } else { "OFF" });
+            self.stage2 = state;
+        }
+    }
+
+    fn set_pump_state(&mut self, state: bool) {
+        if state != self.pump {
+            info!("Shadow: would switch the pump {}", if state { "ON" } else { "OFF" });
+            self.pump = state;
+        }
+    }
+
+    /// Nothing was switched to read back.
+    fn pump_state(&self) -> Option<bool> {
+        None
+    }
+
+    fn select_output(&mut self, output: Output) {
+        info!("Shadow: would switch the compressor with the {} output", output);
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.inner.sleep(duration)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn local_time(&self) -> LocalTime {
+        self.inner.local_time()
+    }
+
+    fn reinitialize(&mut self) -> Result<(), SensorError> {
+        self.inner.reinitialize()
+    }
+
+    fn reacquire_sensor(&mut self) -> bool {
+        self.inner.reacquire_sensor()
+    }
+
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+        self.inner.write_heartbeat(status)
+    }
+
+    /// Nothing is known of a relay it never drives, the conservative start as after a crash.
+    fn restore_state(&self) -> Result<WorldState> {
+        Ok(WorldState {
+            power_state: RestoredPowerState::OffForUnknownDuration,
+            failover: false,
+            ..self.inner.restore_state()?
+        })
+    }
+
+    /// The real relay's off transition is what the file is for.
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        Ok(())
+    }
+
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+        self.inner.persist_compensation(cooling, heating)
+    }
+
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+        self.inner.persist_cooling_rates(rates)
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        self.inner.persist_energy(totals)
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        self.inner.persist_runtime(runtime)
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        self.inner.persist_starts(counts)
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        self.inner.persist_boost(boost)
+    }
+
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        self.inner.persist_intervals(intervals)
+    }
+
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+        self.inner.persist_overshoots(overshoots)
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        self.inner.persist_rest(rest)
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        self.inner.persist_shed(shed)
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        self.inner.persist_extremes(extremes)
+    }
+
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        self.inner.record_cycle(cycle)
+    }
+
+    /// A fault on the real primary output is the real run's to record.
+    fn persist_failover(&mut self, _faulted: bool) -> Result<(), PersistError> {
+        Ok(())
+    }
+}
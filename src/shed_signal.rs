@@ -0,0 +1,111 @@
+//! The demand response input: a file that exists while load is to be shed, or a GPIO input pin. Read once a poll,
+//! what a shed does about it is up to the controller.
+
+use anyhow::{bail, Context, Result};
+use rppal::gpio::{Gpio, InputPin, Level};
+use std::{path::PathBuf, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShedSource {
+    /// `file:<path>`, asserted while the file exists. An MQTT subscriber or a solar controller's script creates and
+    /// removes it.
+    File(PathBuf),
+    /// `gpio:<pin>`, asserted while the pin reads high, or low with a `:low` suffix.
+    Gpio { pin: u8, active_low: bool },
+}
+
+impl FromStr for ShedSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("file:").filter(|path| !path.is_empty()) {
+            return Ok(ShedSource::File(PathBuf::from(path)));
+        }
+        let gpio = match s.strip_prefix("gpio:") {
+            Some(gpio) => gpio,
+            None => bail!("Unknown shed signal '{}', expected file:<path> or gpio:<pin>[:low].", s),
+        };
+        let (pin, active_low) = match gpio.strip_suffix(":low") {
+            Some(pin) => (pin, true),
+            None => (gpio, false),
+        };
+        Ok(ShedSource::Gpio {
+            pin: pin
+                .parse()
+                .with_context(|| format!("Shed signal expects a GPIO pin number, got '{}'.", pin))?,
+            active_low,
+        })
+    }
+}
+
+pub enum ShedSignal {
+    File(PathBuf),
+    Gpio { pin: InputPin, active_low: bool },
+}
+
+impl ShedSignal {
+    /// Claims the pin of a GPIO source, a file is only looked at when read.
+    pub fn open(source: &ShedSource) -> Result<Self> {
+        match source {
+            ShedSource::File(path) => Ok(ShedSignal::File(path.clone())),
+            ShedSource::Gpio { pin, active_low } => Ok(ShedSignal::Gpio {
+                pin: Gpio::new()
+                    .and_then(|gpio| gpio.get(*pin))
+                    .with_context(|| format!("Failed claiming shed signal pin {}.", pin))?
+                    .into_input(),
+                active_low: *active_low,
+            }),
+        }
+    }
+
+    pub fn asserted(&mut self) -> bool {
+        match self {
+            ShedSignal::File(path) => path.exists(),
+            ShedSignal::Gpio { pin, active_low } => (pin.read() == Level::High) != *active_low,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!(
+            ShedSource::File(PathBuf::from("/run/picool/shed")),
+            "file:/run/picool/shed".parse().unwrap()
+        );
+        assert_eq!(
+            ShedSource::Gpio {
+                pin: 22,
+                active_low: true
+            },
+            "gpio:22:low".parse().unwrap()
+        );
+        assert_eq!(
+            ShedSource::Gpio {
+                pin: 5,
+                active_low: false
+            },
+            "gpio:5".parse().unwrap()
+        );
+        for bad in ["file:", "gpio:x", "mqtt:shed", "gpio:22:high"] {
+            assert!(bad.parse::<ShedSource>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn a_file_is_asserted_while_it_exists() {
+        let dir = TempDir::new();
+        let path = dir.path().join("shed");
+        let mut signal = ShedSignal::open(&ShedSource::File(path.clone())).unwrap();
+        assert!(!signal.asserted());
+        fs::write(&path, "").unwrap();
+        assert!(signal.asserted());
+        fs::remove_file(&path).unwrap();
+        assert!(!signal.asserted());
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::{bail, Result};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+};
+
+static SIGNALLED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Asks the control loop to stop at its next check, shared with whatever requests it.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    cycle_limit: Option<u32>,
+}
+
+impl Shutdown {
+    /// Also stop once this many cooling cycles have completed.
+    pub fn with_cycle_limit(mut self, cycle_limit: Option<u32>) -> Self {
+        self.cycle_limit = cycle_limit;
+        self
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    pub fn cycle_limit(&self) -> Option<u32> {
+        self.cycle_limit
+    }
+
+    /// Requests shutdown on SIGTERM or SIGINT. A second signal gets the default disposition and kills us outright.
+    pub fn on_signals(&self) -> Result<()> {
+        if SIGNALLED.set(self.requested.clone()).is_err() {
+            bail!("Signal handlers are already installed.");
+        }
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            // SAFETY: The handler only performs an atomic store.
+            let result = unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESETHAND;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, std::ptr::null_mut())
+            };
+            if result != 0 {
+                bail!("Failed to install handler for signal {}.", signal);
+            }
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    if let Some(requested) = SIGNALLED.get() {
+        requested.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_requests_shutdown() {
+        let shutdown = Shutdown::default();
+        shutdown.on_signals().unwrap();
+        assert!(!shutdown.is_requested());
+        unsafe { libc::raise(libc::SIGTERM) };
+        assert!(shutdown.is_requested());
+        assert!(Shutdown::default().on_signals().is_err());
+    }
+}
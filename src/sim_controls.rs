@@ -0,0 +1,314 @@
+//! Keyboard controls for simulation runs on a terminal: space pauses and resumes, `+` and `-` double and halve the
+//! time warp, `s` lets one poll through while paused and `q` quits like a signal would.
+
+use crate::shutdown::Shutdown;
+use log::*;
+use std::{
+    fmt,
+    io::{self, Read},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Slower than real time is still useful for watching a single cycle.
+const MIN_WARP: f32 = 0.25;
+/// Faster than this is as fast as possible.
+const MAX_WARP: f32 = 4096.0;
+const WARP_STEP: f32 = 2.0;
+/// The longest a sleep or a pause goes without looking at the keys.
+const SLICE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Key {
+    Pause,
+    Faster,
+    Slower,
+    Step,
+    Quit,
+}
+
+// Pure
+pub fn parse_key(byte: u8) -> Option<Key> {
+    match byte {
+        b' ' => Some(Key::Pause),
+        b'+' | b'=' => Some(Key::Faster),
+        b'-' | b'_' => Some(Key::Slower),
+        b's' | b'S' => Some(Key::Step),
+        b'q' | b'Q' => Some(Key::Quit),
+        _ => None,
+    }
+}
+
+/// How fast simulated time passes, what the keys change.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Playback {
+    /// Simulated time per real time, None runs as fast as possible.
+    pub warp: Option<f32>,
+    pub paused: bool,
+    /// Polls let through while paused.
+    steps: u32,
+}
+
+impl Playback {
+    pub fn new(warp: Option<f32>) -> Self {
+        Self {
+            warp,
+            paused: false,
+            steps: 0,
+        }
+    }
+
+    pub fn apply(&mut self, key: Key) {
+        match key {
+            Key::Pause => {
+                self.paused = !self.paused;
+                self.steps = 0;
+            }
+            Key::Faster => {
+                self.warp = match self.warp {
+                    Some(warp) if warp * WARP_STEP <= MAX_WARP => Some(warp * WARP_STEP),
+                    _ => None,
+                }
+            }
+            Key::Slower => {
+                self.warp = match self.warp {
+                    Some(warp) => Some((warp / WARP_STEP).max(MIN_WARP)),
+                    None => Some(MAX_WARP),
+                }
+            }
+            Key::Step if self.paused => self.steps += 1,
+            Key::Step => {}
+            // Whoever reads the keys requests the shutdown, a pause shouldn't hold it up.
+            Key::Quit => self.paused = false,
+        }
+    }
+
+    /// Whether the next poll may run, using up a step if paused.
+    pub fn may_advance(&mut self) -> bool {
+        match (self.paused, self.steps) {
+            (false, _) => true,
+            (true, 0) => false,
+            (true, _) => {
+                self.steps -= 1;
+                true
+            }
+        }
+    }
+
+    // Pure
+    /// The real time `simulated` takes at the current warp.
+    pub fn real_time(&self, simulated: Duration) -> Duration {
+        match self.warp {
+            Some(warp) => simulated.div_f32(warp),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    // Pure
+    /// The simulated time that passes in `real` at the current warp.
+    pub fn simulated_time(&self, real: Duration) -> Duration {
+        match self.warp {
+            Some(warp) => real.mul_f32(warp),
+            None => Duration::MAX,
+        }
+    }
+}
+
+impl fmt::Display for Playback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.paused, self.warp) {
+            (true, _) => write!(f, "paused"),
+            (false, Some(warp)) => write!(f, "warp {}x", warp),
+            (false, None) => write!(f, "warp max"),
+        }
+    }
+}
+
+/// The keys of a terminal in raw mode, read on a thread of their own. The terminal is restored when dropped.
+pub struct SimControls {
+    keys: Receiver<Key>,
+    playback: Playback,
+    shutdown: Shutdown,
+    _terminal: RawTerminal,
+}
+
+impl SimControls {
+    /// None unless both stdin and stdout are a terminal.
+    pub fn start(warp: Option<f32>, shutdown: &Shutdown) -> Option<Self> {
+        // SAFETY: isatty only inspects the descriptors.
+        if unsafe { libc::isatty(libc::STDIN_FILENO) == 0 || libc::isatty(libc::STDOUT_FILENO) == 0 } {
+            return None;
+        }
+        let terminal = match RawTerminal::enter() {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                warn!("Simulation controls unavailable. {}", e);
+                return None;
+            }
+        };
+        let (sender, keys) = channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 16];
+            while let Ok(read @ 1..) = io::stdin().read(&mut buffer) {
+                for key in buffer[..read].iter().filter_map(|&byte| parse_key(byte)) {
+                    if sender.send(key).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        info!("Simulation controls: space pauses, + and - change speed, s steps while paused, q quits.");
+        Some(Self {
+            keys,
+            playback: Playback::new(warp),
+            shutdown: shutdown.clone(),
+            _terminal: terminal,
+        })
+    }
+
+    pub fn playback(&self) -> Playback {
+        self.playback
+    }
+
+    /// Takes the real time `simulated` needs at the warp, after waiting out a pause. Keys apply as they arrive and
+    /// `report` hears of each.
+    pub fn pace(&mut self, simulated: Duration, report: impl Fn(&Playback)) {
+        while !self.playback.may_advance() {
+            if self.shutdown.is_requested() || !self.receive(SLICE, &report) {
+                return;
+            }
+        }
+        let mut remaining = simulated;
+        while !self.shutdown.is_requested() {
+            let real = self.playback.real_time(remaining).min(SLICE);
+            if real == Duration::from_secs(0) {
+                return;
+            }
+            let started = Instant::now();
+            if !self.receive(real, &report) {
+                thread::sleep(real);
+            }
+            remaining = remaining.saturating_sub(self.playback.simulated_time(started.elapsed()));
+        }
+    }
+
+    /// Waits up to `timeout` for a key and applies it, false once the keys are gone.
+    fn receive(&mut self, timeout: Duration, report: &impl Fn(&Playback)) -> bool {
+        match self.keys.recv_timeout(timeout) {
+            Ok(key) => {
+                self.playback.apply(key);
+                if key == Key::Quit {
+                    self.shutdown.request();
+                }
+                report(&self.playback);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+}
+
+/// Unbuffered input without echo, signals from the keyboard still work.
+struct RawTerminal(libc::termios);
+
+impl RawTerminal {
+    fn enter() -> io::Result<Self> {
+        // SAFETY: termios is plain data and only passed to the terminal calls on stdin.
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(original))
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: Restores the attributes read in enter.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(Some(Key::Pause), parse_key(b' '));
+        assert_eq!(Some(Key::Faster), parse_key(b'+'));
+        assert_eq!(Some(Key::Faster), parse_key(b'='));
+        assert_eq!(Some(Key::Slower), parse_key(b'-'));
+        assert_eq!(Some(Key::Step), parse_key(b's'));
+        assert_eq!(Some(Key::Quit), parse_key(b'Q'));
+        assert_eq!(None, parse_key(b'x'));
+        assert_eq!(None, parse_key(b'\n'));
+    }
+
+    #[test]
+    fn warp_doubles_and_halves_within_bounds() {
+        let mut playback = Playback::new(Some(60.0));
+        playback.apply(Key::Faster);
+        assert_eq!(Some(120.0), playback.warp);
+        assert_eq!("warp 120x", playback.to_string());
+        for _ in 0..20 {
+            playback.apply(Key::Slower);
+        }
+        assert_eq!(Some(MIN_WARP), playback.warp);
+        for _ in 0..20 {
+            playback.apply(Key::Faster);
+        }
+        // Past the fastest warp is as fast as possible.
+        assert_eq!(None, playback.warp);
+        assert_eq!("warp max", playback.to_string());
+        playback.apply(Key::Slower);
+        assert_eq!(Some(MAX_WARP), playback.warp);
+    }
+
+    #[test]
+    fn warp_scales_sleeps() {
+        let playback = Playback::new(Some(60.0));
+        assert_eq!(Duration::from_secs(1), playback.real_time(Duration::from_secs(60)));
+        assert_eq!(
+            Duration::from_secs(30),
+            playback.simulated_time(Duration::from_millis(500))
+        );
+        let unbounded = Playback::new(None);
+        assert_eq!(Duration::from_secs(0), unbounded.real_time(Duration::from_secs(60)));
+        assert_eq!(Duration::MAX, unbounded.simulated_time(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn pause_holds_polls_except_steps() {
+        let mut playback = Playback::new(None);
+        assert!(playback.may_advance());
+        playback.apply(Key::Pause);
+        assert_eq!("paused", playback.to_string());
+        assert!(!playback.may_advance());
+        playback.apply(Key::Step);
+        playback.apply(Key::Step);
+        assert!(playback.may_advance());
+        assert!(playback.may_advance());
+        assert!(!playback.may_advance());
+        // Steps only count while paused.
+        playback.apply(Key::Pause);
+        playback.apply(Key::Step);
+        playback.apply(Key::Pause);
+        assert!(!playback.may_advance());
+        playback.apply(Key::Quit);
+        assert!(playback.may_advance());
+    }
+}
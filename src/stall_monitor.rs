@@ -0,0 +1,134 @@
+use anyhow::Result;
+use log::*;
+use picool_core::notify::{Event, Notifier};
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Bumped by the control loop on every iteration and sensor read attempt.
+#[derive(Clone, Default)]
+pub struct LoopProgress(Arc<AtomicU64>);
+
+impl LoopProgress {
+    pub fn tick(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Watches the control loop from its own thread, stopped and joined on drop.
+pub struct StallMonitor {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StallMonitor {
+    pub fn spawn(
+        progress: LoopProgress,
+        stall_after: Duration,
+        abort: bool,
+        mut notifier: impl Notifier + Send + 'static,
+    ) -> Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new().name("stall-monitor".into()).spawn(move || {
+            let check_interval = stall_after / 4;
+            let mut last_count = progress.count();
+            let mut last_progress = Instant::now();
+            let mut stalled = false;
+            while !thread_stop.load(Ordering::Relaxed) {
+                thread::park_timeout(check_interval);
+                let now = Instant::now();
+                let count = progress.count();
+                if count != last_count {
+                    if stalled {
+                        info!("Control loop resumed after {}s.", (now - last_progress).as_secs());
+                    }
+                    last_count = count;
+                    last_progress = now;
+                    stalled = false;
+                    continue;
+                }
+                let stalled_for = now - last_progress;
+                if !stalled && stalled_for >= stall_after {
+                    stalled = true;
+                    // Another thread's stack can't be captured from here, point at the tools that can.
+                    error!(
+                        "Control loop stalled for {}s. Inspect it with `eu-stack -p {}` or `gdb -p {}`.",
+                        stalled_for.as_secs(),
+                        process::id(),
+                        process::id()
+                    );
+                    notifier.notify(&Event::LoopStalled { stalled_for });
+                    if abort {
+                        error!("Aborting so the supervisor can restart picool.");
+                        process::abort();
+                    }
+                }
+            }
+        })?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for StallMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Sender};
+
+    struct ChannelNotifier(Sender<Event>);
+
+    impl Notifier for ChannelNotifier {
+        fn notify(&mut self, event: &Event) {
+            let _ = self.0.send(event.clone());
+        }
+    }
+
+    #[test]
+    fn quiet_while_progressing_and_reports_stall_once() {
+        let progress = LoopProgress::default();
+        let (events, received) = channel();
+        let monitor = StallMonitor::spawn(
+            progress.clone(),
+            Duration::from_millis(100),
+            false,
+            ChannelNotifier(events),
+        )
+        .unwrap();
+        for _ in 0..20 {
+            progress.tick();
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(received.try_recv().is_err());
+
+        match received.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::LoopStalled { stalled_for }) => assert!(stalled_for >= Duration::from_millis(100)),
+            other => panic!("unexpected {:?}", other),
+        }
+        thread::sleep(Duration::from_millis(200));
+        assert!(received.try_recv().is_err());
+        drop(monitor);
+    }
+}
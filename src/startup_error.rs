@@ -0,0 +1,302 @@
+use crate::{EXIT_CONFIG, EXIT_HARDWARE, EXIT_PERMISSION};
+use picool_core::termination::EXIT_PERSISTENCE;
+use rppal::gpio;
+use std::{fmt, io, path::PathBuf};
+
+const GPIOMEM_PATH: &str = "/dev/gpiomem";
+
+/// Failures acquiring the hardware in RealWorld::new, classified so the user gets a remediation hint and the
+/// supervisor gets an exit code that separates permission problems from configuration problems.
+#[derive(Debug)]
+pub enum StartupError {
+    InvalidSensorPath(PathBuf),
+    SensorMissing(PathBuf, io::Error),
+    SensorPermissionDenied(PathBuf, io::Error),
+    SensorUnreadable(PathBuf, io::Error),
+    /// A reading that could be degrees or millidegrees.
+    AmbiguousSensorFormat(PathBuf, String),
+    SerialPortMissing(PathBuf, io::Error),
+    SerialPortPermissionDenied(PathBuf, io::Error),
+    SerialPortUnusable(PathBuf, io::Error),
+    CommandMissing(PathBuf, io::Error),
+    CommandNotExecutable(PathBuf, io::Error),
+    /// The `<chip>/<channel>` of an hwmon sensor, and what was found instead.
+    HwmonUnresolved(String, String),
+    /// The switch command failed turning the line off when claiming it.
+    SwitchCommandFailed(u8, String),
+    GpioPermissionDenied(String),
+    GpioMissing(io::Error),
+    GpioUnsupportedModel,
+    GpioChipMissing(PathBuf, io::Error),
+    GpioLine(u8, io::Error),
+    PinNotAvailable(u8),
+    Gpio(gpio::Error),
+    StateDirPermissionDenied(PathBuf, io::Error),
+    StateDirUnusable(PathBuf, io::Error),
+}
+
+impl StartupError {
+    pub fn from_sensor(path: PathBuf, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => StartupError::SensorMissing(path, e),
+            io::ErrorKind::PermissionDenied => StartupError::SensorPermissionDenied(path, e),
+            _ => StartupError::SensorUnreadable(path, e),
+        }
+    }
+
+    pub fn from_serial(path: PathBuf, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => StartupError::SerialPortMissing(path, e),
+            io::ErrorKind::PermissionDenied => StartupError::SerialPortPermissionDenied(path, e),
+            _ => StartupError::SerialPortUnusable(path, e),
+        }
+    }
+
+    /// Anything but a missing command is a permission problem, `access` reports little else.
+    pub fn from_command(path: PathBuf, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => StartupError::CommandMissing(path, e),
+            _ => StartupError::CommandNotExecutable(path, e),
+        }
+    }
+
+    /// A read-only file system is as much the user's to fix as a directory owned by someone else.
+    pub fn from_state_dir(path: PathBuf, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                StartupError::StateDirPermissionDenied(path, e)
+            }
+            _ => StartupError::StateDirUnusable(path, e),
+        }
+    }
+
+    pub fn from_gpio(e: gpio::Error) -> Self {
+        match e {
+            gpio::Error::PermissionDenied(path) => StartupError::GpioPermissionDenied(path),
+            gpio::Error::Io(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                StartupError::GpioPermissionDenied(GPIOMEM_PATH.into())
+            }
+            gpio::Error::Io(e) if e.kind() == io::ErrorKind::NotFound => StartupError::GpioMissing(e),
+            gpio::Error::UnknownModel => StartupError::GpioUnsupportedModel,
+            gpio::Error::PinNotAvailable(pin) => StartupError::PinNotAvailable(pin),
+            e => StartupError::Gpio(e),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::SensorPermissionDenied(..)
+            | StartupError::SerialPortPermissionDenied(..)
+            | StartupError::CommandNotExecutable(..)
+            | StartupError::GpioPermissionDenied(_)
+            | StartupError::StateDirPermissionDenied(..) => EXIT_PERMISSION,
+            StartupError::InvalidSensorPath(_)
+            | StartupError::AmbiguousSensorFormat(..)
+            | StartupError::SensorMissing(..)
+            | StartupError::SerialPortMissing(..)
+            | StartupError::CommandMissing(..)
+            | StartupError::HwmonUnresolved(..)
+            | StartupError::GpioChipMissing(..)
+            | StartupError::PinNotAvailable(_) => EXIT_CONFIG,
+            StartupError::SensorUnreadable(..)
+            | StartupError::SerialPortUnusable(..)
+            | StartupError::GpioMissing(_)
+            | StartupError::GpioUnsupportedModel
+            | StartupError::GpioLine(..)
+            | StartupError::SwitchCommandFailed(..)
+            | StartupError::Gpio(_) => EXIT_HARDWARE,
+            StartupError::StateDirUnusable(..) => EXIT_PERSISTENCE,
+        }
+    }
+
+    fn hint(&self) -> String {
+        match self {
+            StartupError::InvalidSensorPath(_) => {
+                "Pass the sensor's value file, e.g. /sys/bus/w1/devices/28-00112233445566/temperature.".into()
+            }
+            StartupError::SensorMissing(..) => {
+                "Enable the 1-Wire overlay (dtoverlay=w1-gpio in /boot/config.txt) and check the sensor ID.".into()
+            }
+            StartupError::SensorPermissionDenied(path, _) => {
+                format!("Add the user to the group owning {} or run as root.", path.display())
+            }
+            StartupError::SensorUnreadable(..) => "Check the sensor wiring.".into(),
+            StartupError::AmbiguousSensorFormat(..) => {
+                "Pass --sensor-format degrees or --sensor-format millidegrees.".into()
+            }
+            StartupError::SerialPortMissing(..) => {
+                "Check the RS-485 adapter is plugged in and the device name, ls /dev/serial/by-id lists them.".into()
+            }
+            StartupError::SerialPortPermissionDenied(path, _) => format!(
+                "Add the user to the dialout group (sudo usermod -aG dialout $USER) to access {}.",
+                path.display()
+            ),
+            StartupError::SerialPortUnusable(..) => {
+                "Check the device is a serial port and not in use by another program, such as a login getty.".into()
+            }
+            StartupError::CommandMissing(..) => "Pass the command's absolute path after exec:.".into(),
+            StartupError::CommandNotExecutable(path, _) => format!(
+                "Make {} executable by the user picool runs as, e.g. chmod 755.",
+                path.display()
+            ),
+            StartupError::HwmonUnresolved(..) => {
+                "Pass one of the available temperatures as hwmon:<chip>/<label or index>.".into()
+            }
+            StartupError::SwitchCommandFailed(line, _) => {
+                format!("Run the switch command by hand with off {} to see why it fails.", line)
+            }
+            StartupError::GpioPermissionDenied(path) => format!(
+                "Add the user to the gpio group (sudo usermod -aG gpio $USER) to access {}.",
+                path
+            ),
+            StartupError::GpioMissing(_) => format!(
+                "{} does not exist. Picool must run on a Raspberry Pi with the gpiomem driver loaded.",
+                GPIOMEM_PATH
+            ),
+            StartupError::GpioUnsupportedModel => "This board is not a supported Raspberry Pi model.".into(),
+            StartupError::GpioChipMissing(..) => "Pass one of the chips ls /dev/gpiochip* lists to --gpio-chip.".into(),
+            StartupError::GpioLine(..) => {
+                "Check no other program, such as a gpioset left running, holds the line.".into()
+            }
+            StartupError::PinNotAvailable(_) => "Use a BCM GPIO number that exists on this board.".into(),
+            StartupError::Gpio(_) => "Check the GPIO configuration.".into(),
+            StartupError::StateDirPermissionDenied(path, _) => format!(
+                "Make {} writable by the user picool runs as, pass another --state-dir, or set StateDirectory= in the \
+                 systemd unit.",
+                path.display()
+            ),
+            StartupError::StateDirUnusable(..) => "Check the disk isn't full or failing.".into(),
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::InvalidSensorPath(path) => write!(f, "Invalid temperature path {}.", path.display()),
+            StartupError::SensorMissing(path, e)
+            | StartupError::SensorPermissionDenied(path, e)
+            | StartupError::SensorUnreadable(path, e) => {
+                write!(f, "Can not read temperature sensor {}: {}.", path.display(), e)
+            }
+            StartupError::AmbiguousSensorFormat(path, reading) => write!(
+                f,
+                "Can not tell whether temperature sensor {} reads '{}' in degrees or millidegrees.",
+                path.display(),
+                reading
+            ),
+            StartupError::SerialPortMissing(path, e)
+            | StartupError::SerialPortPermissionDenied(path, e)
+            | StartupError::SerialPortUnusable(path, e) => {
+                write!(f, "Can not open serial port {}: {}.", path.display(), e)
+            }
+            StartupError::CommandMissing(path, e) | StartupError::CommandNotExecutable(path, e) => {
+                write!(f, "Can not run command {}: {}.", path.display(), e)
+            }
+            StartupError::HwmonUnresolved(spec, e) => write!(f, "Can not find hwmon sensor {}: {}.", spec, e),
+            StartupError::SwitchCommandFailed(line, e) => write!(f, "Can not claim switch line {}: {}.", line, e),
+            StartupError::GpioPermissionDenied(path) => write!(f, "Permission denied opening {}.", path),
+            StartupError::GpioMissing(e) => write!(f, "Can not open GPIO memory: {}.", e),
+            StartupError::GpioUnsupportedModel => write!(f, "Unknown Raspberry Pi model."),
+            StartupError::GpioChipMissing(path, e) => write!(f, "Can not open GPIO chip {}: {}.", path.display(), e),
+            StartupError::GpioLine(line, e) => write!(f, "Can not request GPIO line {}: {}.", line, e),
+            StartupError::PinNotAvailable(pin) => write!(f, "GPIO pin {} is not available.", pin),
+            StartupError::Gpio(e) => write!(f, "GPIO initialization failed: {}.", e),
+            StartupError::StateDirPermissionDenied(path, e) | StartupError::StateDirUnusable(path, e) => {
+                write!(f, "Can not keep state in {}: {}.", path.display(), e)
+            }
+        }?;
+        write!(f, " Hint: {}", self.hint())
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error(kind: io::ErrorKind) -> io::Error {
+        io::Error::new(kind, "simulated")
+    }
+
+    #[test]
+    fn gpiomem_permission_denied() {
+        let e = StartupError::from_gpio(gpio::Error::PermissionDenied(GPIOMEM_PATH.into()));
+        assert_eq!(EXIT_PERMISSION, e.exit_code());
+        assert!(e.to_string().contains("gpio group"));
+
+        let e = StartupError::from_gpio(gpio::Error::Io(io_error(io::ErrorKind::PermissionDenied)));
+        assert_eq!(EXIT_PERMISSION, e.exit_code());
+    }
+
+    #[test]
+    fn gpiomem_missing() {
+        let e = StartupError::from_gpio(gpio::Error::Io(io_error(io::ErrorKind::NotFound)));
+        assert!(matches!(e, StartupError::GpioMissing(_)));
+        assert_eq!(EXIT_HARDWARE, e.exit_code());
+    }
+
+    #[test]
+    fn pin_not_available_is_configuration() {
+        let e = StartupError::from_gpio(gpio::Error::PinNotAvailable(99));
+        assert_eq!(EXIT_CONFIG, e.exit_code());
+    }
+
+    #[test]
+    fn sensor_errors() {
+        let path = PathBuf::from("/sys/bus/w1/devices/28-00/temperature");
+        let missing = StartupError::from_sensor(path.clone(), io_error(io::ErrorKind::NotFound));
+        assert_eq!(EXIT_CONFIG, missing.exit_code());
+        assert!(missing.to_string().contains("dtoverlay=w1-gpio"));
+
+        let denied = StartupError::from_sensor(path.clone(), io_error(io::ErrorKind::PermissionDenied));
+        assert_eq!(EXIT_PERMISSION, denied.exit_code());
+
+        let other = StartupError::from_sensor(path.clone(), io_error(io::ErrorKind::Other));
+        assert_eq!(EXIT_HARDWARE, other.exit_code());
+
+        let ambiguous = StartupError::AmbiguousSensorFormat(path, "21".into());
+        assert_eq!(EXIT_CONFIG, ambiguous.exit_code());
+        assert!(ambiguous.to_string().contains("--sensor-format"));
+    }
+
+    #[test]
+    fn command_errors() {
+        let path = PathBuf::from("/usr/local/bin/read-temp.sh");
+        let missing = StartupError::from_command(path.clone(), io_error(io::ErrorKind::NotFound));
+        assert_eq!(EXIT_CONFIG, missing.exit_code());
+        let denied = StartupError::from_command(path, io_error(io::ErrorKind::PermissionDenied));
+        assert_eq!(EXIT_PERMISSION, denied.exit_code());
+        let unresolved = StartupError::HwmonUnresolved("nct6775/SYSTIN".into(), "no chip is named nct6775".into());
+        assert_eq!(EXIT_CONFIG, unresolved.exit_code());
+        assert!(denied.to_string().contains("chmod"));
+    }
+
+    #[test]
+    fn state_dir_errors() {
+        let path = PathBuf::from("/var/lib/picool");
+        let denied = StartupError::from_state_dir(path.clone(), io_error(io::ErrorKind::PermissionDenied));
+        assert_eq!(EXIT_PERMISSION, denied.exit_code());
+        assert!(denied.to_string().contains("StateDirectory="));
+        let read_only = StartupError::from_state_dir(path.clone(), io_error(io::ErrorKind::ReadOnlyFilesystem));
+        assert_eq!(EXIT_PERMISSION, read_only.exit_code());
+        let full = StartupError::from_state_dir(path, io_error(io::ErrorKind::StorageFull));
+        assert_eq!(EXIT_PERSISTENCE, full.exit_code());
+    }
+
+    #[test]
+    fn serial_port_errors() {
+        let path = PathBuf::from("/dev/ttyUSB0");
+        let missing = StartupError::from_serial(path.clone(), io_error(io::ErrorKind::NotFound));
+        assert_eq!(EXIT_CONFIG, missing.exit_code());
+        assert!(missing.to_string().contains("/dev/serial/by-id"));
+
+        let denied = StartupError::from_serial(path.clone(), io_error(io::ErrorKind::PermissionDenied));
+        assert_eq!(EXIT_PERMISSION, denied.exit_code());
+        assert!(denied.to_string().contains("dialout"));
+
+        let other = StartupError::from_serial(path, io_error(io::ErrorKind::Other));
+        assert_eq!(EXIT_HARDWARE, other.exit_code());
+    }
+}
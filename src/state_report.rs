@@ -0,0 +1,185 @@
+//! `picool status`, what the daemon would restore from its state files and the thresholds that follows to.
+
+use crate::real_world::{classify_last_off, read_compensation, read_last_off, LastOff, StoredLastOff};
+use picool_core::{config::Config, controller::seed_compensators};
+use std::{
+    fmt::Write as _,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// The report, and whether a file was damaged enough for the daemon to fall back from it.
+#[derive(Debug)]
+pub struct StateReport {
+    pub text: String,
+    pub damaged: bool,
+}
+
+/// Reads the files the way the daemon restores them, `since_epoch` being now and `floor` the `--clock-floor`.
+pub fn report(
+    last_off_path: &Path,
+    compensation_path: &Path,
+    config: &Config,
+    since_epoch: Duration,
+    floor: Duration,
+) -> StateReport {
+    let mut text = String::new();
+    let mut damaged = false;
+    let rfc3339 = |since_epoch| humantime::format_rfc3339_seconds(SystemTime::UNIX_EPOCH + since_epoch);
+    let minimum_off = config.intervals.off.as_secs() / 60;
+    let waits = format!("The relay waits out the {}m minimum off interval.", minimum_off);
+
+    let _ = write!(text, "Last off transition, {}: ", last_off_path.display());
+    let off_at = match read_last_off(last_off_path) {
+        Ok(StoredLastOff::Missing) => {
+            let _ = writeln!(text, "missing. {}", waits);
+            None
+        }
+        Ok(StoredLastOff::At(off_at)) => Some(off_at),
+        Ok(StoredLastOff::Written { data, at }) => {
+            damaged = true;
+            let _ = write!(text, "corrupt '{}', timed by when the file was written: ", data);
+            Some(at)
+        }
+        Err(e) => {
+            damaged = true;
+            let _ = writeln!(text, "unreadable, {:#} {}", e, waits);
+            None
+        }
+    };
+    if let Some(off_at) = off_at {
+        let _ = write!(text, "{} ({}), ", off_at.as_secs(), rfc3339(off_at));
+        let _ = match classify_last_off(off_at, since_epoch, floor) {
+            LastOff::Ago(ago) if ago > config.intervals.off => {
+                writeln!(text, "{}m ago. The minimum off interval is up.", ago.as_secs() / 60)
+            }
+            LastOff::Ago(ago) => writeln!(
+                text,
+                "{}m ago. {}m of the {}m minimum off interval left.",
+                ago.as_secs() / 60,
+                (config.intervals.off - ago).as_secs().div_ceil(60),
+                minimum_off
+            ),
+            LastOff::Ahead(by) => writeln!(
+                text,
+                "{}s ahead of the clock, an unknown duration. {}",
+                by.as_secs(),
+                waits
+            ),
+            LastOff::BeforeFloor => writeln!(text, "before {}, an unknown duration. {}", rfc3339(floor), waits),
+        };
+    }
+
+    let _ = write!(text, "Compensation, {}: ", compensation_path.display());
+    let (cooling, heating) = match read_compensation(compensation_path) {
+        Ok(_) if !compensation_path.exists() => {
+            let _ = writeln!(text, "missing, none learned yet.");
+            Default::default()
+        }
+        Ok((cooling, heating)) => {
+            let _ = writeln!(text, "cooling {}, heating {}.", cooling, heating);
+            (cooling, heating)
+        }
+        Err(e) => {
+            damaged = true;
+            let _ = writeln!(text, "corrupt, {:#} None learned is restored.", e);
+            Default::default()
+        }
+    };
+
+    let (low, high) = seed_compensators(&config.band, (cooling, heating));
+    let target = &config.band.target;
+    let capped = match (low.is_capped(), high.is_capped()) {
+        (false, false) => String::new(),
+        _ => format!(", compensation capped at {}", config.band.max_compensation),
+    };
+    let _ = writeln!(
+        text,
+        "Thresholds: off at {}, on at {}, against the target {} to {}{}.",
+        low.get_threshold().celsius(),
+        high.get_threshold().celsius(),
+        target.start,
+        target.end,
+        capped
+    );
+    StateReport { text, damaged }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use picool_core::temperature::{Celsius, DegreesDelta};
+    use std::fs;
+
+    const NOW: Duration = Duration::from_secs(1_760_000_000);
+    const FLOOR: Duration = Duration::from_secs(1_704_067_200);
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.band.target = Celsius(2.0)..Celsius(4.5);
+        config.band.max_compensation = DegreesDelta(1.0);
+        config.intervals.off = Duration::from_secs(8 * 60);
+        config
+    }
+
+    #[test]
+    fn reports_what_the_daemon_restores() {
+        let dir = TempDir::new();
+        let (last_off, comp) = (dir.path().join("last_off_28-00"), dir.path().join("comp_28-00"));
+        let report = report(&last_off, &comp, &config(), NOW, FLOOR);
+        assert!(!report.damaged);
+        assert!(report
+            .text
+            .contains("missing. The relay waits out the 8m minimum off interval."));
+        assert!(report.text.contains("missing, none learned yet."));
+
+        fs::write(&last_off, (NOW.as_secs() - 3 * 60).to_string()).unwrap();
+        fs::write(&comp, "350 -1500").unwrap();
+        let report = super::report(&last_off, &comp, &config(), NOW, FLOOR);
+        assert!(!report.damaged, "{}", report.text);
+        assert!(report
+            .text
+            .contains("1759999820 (2025-10-09T08:50:20Z), 3m ago. 5m of the 8m"));
+        assert!(report.text.contains("cooling 0.35C"), "{}", report.text);
+        let thresholds = report.text.lines().last().unwrap();
+        assert!(thresholds.starts_with("Thresholds: off at 2.35C"), "{}", thresholds);
+        assert!(thresholds.contains("on at 3.50C"), "{}", thresholds);
+        assert!(thresholds.contains("capped at 1.00C"), "{}", thresholds);
+
+        fs::write(&last_off, (NOW.as_secs() - 60 * 60).to_string()).unwrap();
+        let report = super::report(&last_off, &comp, &config(), NOW, FLOOR);
+        assert!(report.text.contains("60m ago. The minimum off interval is up."));
+        let report = super::report(
+            &last_off,
+            &comp,
+            &config(),
+            NOW - Duration::from_secs(2 * 60 * 60),
+            FLOOR,
+        );
+        assert!(report.text.contains("3600s ahead of the clock"), "{}", report.text);
+    }
+
+    #[test]
+    fn flags_damaged_files() {
+        let dir = TempDir::new();
+        let (last_off, comp) = (dir.path().join("last_off_28-00"), dir.path().join("comp_28-00"));
+        fs::write(&last_off, "17600\0").unwrap();
+        fs::write(&comp, "350").unwrap();
+        let report = report(&last_off, &comp, &config(), NOW, FLOOR);
+        assert!(report.damaged);
+        assert!(report
+            .text
+            .contains("corrupt '17600\0', timed by when the file was written"));
+        assert!(report
+            .text
+            .contains("corrupt, Failed to parse compensation file. None learned is restored."));
+        assert!(report.text.contains("Thresholds: off at 2.00C"), "{}", report.text);
+
+        fs::write(&last_off, "1000").unwrap();
+        let report = super::report(&last_off, &comp, &config(), NOW, FLOOR);
+        assert!(report
+            .text
+            .contains("before 2024-01-01T00:00:00Z, an unknown duration."));
+    }
+}
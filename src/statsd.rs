@@ -0,0 +1,382 @@
+//! Gauges and counters sent once a poll to a StatsD daemon, such as Telegraf's listener.
+
+use crate::{
+    loop_timing::{LoopLatency, PHASES},
+    status_board::Snapshot,
+};
+use anyhow::{bail, Result};
+use log::*;
+use picool_core::quiet_hours::QuietStats;
+use std::{
+    fmt, io,
+    net::{ToSocketAddrs, UdpSocket},
+    str::FromStr,
+};
+
+const NAMESPACE: &str = "picool";
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Dialect {
+    /// The instance in each metric name, `picool.<instance>.temperature`.
+    #[default]
+    Plain,
+    /// DogStatsD, the instance as a tag, `picool.temperature|g|#instance:<instance>`.
+    Tagged,
+}
+
+impl FromStr for Dialect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "statsd" => Ok(Dialect::Plain),
+            "dogstatsd" => Ok(Dialect::Tagged),
+            _ => bail!("Unknown StatsD dialect '{}', expected statsd or dogstatsd.", s),
+        }
+    }
+}
+
+/// Fire and forget, a send that fails is counted and dropped, the next poll sends the gauges again.
+pub struct StatsdEmitter {
+    socket: UdpSocket,
+    instance: String,
+    dialect: Dialect,
+    /// Cycles, sensor errors and relay deferrals up to the last send, StatsD counters are increments.
+    sent: (u32, u64, u64),
+    sent_overruns: u64,
+    /// Starts quiet hours held back and the seconds they were held up to the last send.
+    sent_quiet: (u64, u64),
+    failed: u64,
+}
+
+impl StatsdEmitter {
+    /// Resolves `address` once, a daemon that moves needs a restart.
+    pub fn new(address: &str, dialect: Dialect, instance: &str) -> io::Result<Self> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let socket = UdpSocket::bind(if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            instance: sanitize(instance),
+            dialect,
+            sent: (0, 0, 0),
+            sent_overruns: 0,
+            sent_quiet: (0, 0),
+            failed: 0,
+        })
+    }
+
+    /// Never blocks, a full socket buffer fails the send like an unreachable daemon does.
+    pub fn emit(&mut self, snapshot: &Snapshot) {
+        let cycles = snapshot.cycles.saturating_sub(self.sent.0);
+        let sensor_errors = snapshot.sensor_errors.saturating_sub(self.sent.1);
+        let relay_deferrals = snapshot.relay_deferrals.saturating_sub(self.sent.2);
+        self.sent = (snapshot.cycles, snapshot.sensor_errors, snapshot.relay_deferrals);
+        let datagram = format(
+            &self.instance,
+            self.dialect,
+            snapshot,
+            (cycles, sensor_errors, relay_deferrals),
+        );
+        self.send(&datagram);
+        if let Some(latency) = &snapshot.loop_latency {
+            let overruns = latency.overruns.saturating_sub(self.sent_overruns);
+            self.sent_overruns = latency.overruns;
+            for datagram in format_latency(&self.instance, self.dialect, latency, overruns) {
+                self.send(&datagram);
+            }
+        }
+        if let Some(quiet) = &snapshot.quiet {
+            let held = quiet.held.as_secs();
+            let counts = (
+                quiet.suppressed.saturating_sub(self.sent_quiet.0),
+                held.saturating_sub(self.sent_quiet.1),
+            );
+            self.sent_quiet = (quiet.suppressed, held);
+            let datagram = format_quiet(&self.instance, self.dialect, quiet, counts);
+            self.send(&datagram);
+        }
+    }
+
+    fn send(&mut self, datagram: &str) {
+        if let Err(e) = self.socket.send(datagram.as_bytes()) {
+            self.failed += 1;
+            trace!("StatsD send failed, {} so far. {}", self.failed, e);
+        }
+    }
+}
+
+// Pure
+/// One metric per line, a single datagram well under the 512 bytes any network carries unfragmented.
+fn format(instance: &str, dialect: Dialect, snapshot: &Snapshot, counts: (u32, u64, u64)) -> String {
+    let (cycles, sensor_errors, relay_deferrals) = counts;
+    let on_off = |on: bool| if on { "1" } else { "0" }.to_string();
+    let mut metrics = vec![];
+    if let Some(temperature) = snapshot.temperature {
+        metrics.push(("temperature", format!("{:.3}", temperature.0), "g"));
+    }
+    metrics.push(("threshold.low", format!("{:.3}", snapshot.thresholds.0 .0), "g"));
+    metrics.push(("threshold.high", format!("{:.3}", snapshot.thresholds.1 .0), "g"));
+    metrics.push(("relay", on_off(snapshot.power_on), "g"));
+    metrics.push(("fan", on_off(snapshot.fan_on), "g"));
+    if let Some((on, runtime_hours, starts)) = snapshot.stage2 {
+        metrics.push(("stage2", on_off(on), "g"));
+        metrics.push(("stage2.runtime", format!("{:.2}", runtime_hours), "g"));
+        metrics.push(("stage2.starts", starts.to_string(), "g"));
+    }
+    if let Some((amps, fault)) = snapshot.current {
+        if let Some(amps) = amps {
+            metrics.push(("current", format!("{:.3}", amps), "g"));
+        }
+        metrics.push(("load_fault", on_off(fault.is_some()), "g"));
+    }
+    if let Some(rate) = snapshot.cooling_rate {
+        metrics.push(("cooling.rate", format!("{:.4}", rate), "g"));
+    }
+    if let Some(ratio) = snapshot.cooling_ratio {
+        metrics.push(("cooling.ratio", format!("{:.3}", ratio), "g"));
+    }
+    if let Some(overshoot) = snapshot.overshoot {
+        metrics.push(("overshoot", format!("{:.3}", overshoot.0), "g"));
+    }
+    if let Some(undershoot) = snapshot.undershoot {
+        metrics.push(("undershoot", format!("{:.3}", undershoot.0), "g"));
+    }
+    metrics.push(("cycles", cycles.to_string(), "c"));
+    metrics.push(("sensor_errors", sensor_errors.to_string(), "c"));
+    metrics.push(("relay_deferrals", relay_deferrals.to_string(), "c"));
+    lines(instance, dialect, metrics)
+}
+
+// Pure
+/// The median, the 95th percentile and the maximum of each phase and the whole iteration in milliseconds, a
+/// datagram each to keep them as small as the first.
+fn format_latency(instance: &str, dialect: Dialect, latency: &LoopLatency, overruns: u64) -> Vec<String> {
+    let phases = PHASES
+        .iter()
+        .map(|&phase| (phase.name(), latency.phase(phase)))
+        .chain([("loop", latency.iteration)]);
+    let mut datagrams: Vec<String> = phases
+        .map(|(phase, latency)| {
+            let metrics = [("p50", latency.p50), ("p95", latency.p95), ("max", latency.max)]
+                .iter()
+                .map(|(name, spent)| {
+                    (
+                        format!("latency.{}.{}", phase, name),
+                        spent.as_millis().to_string(),
+                        "g",
+                    )
+                })
+                .collect();
+            lines(instance, dialect, metrics)
+        })
+        .collect();
+    datagrams.push(lines(instance, dialect, vec![("overruns", overruns.to_string(), "c")]));
+    datagrams
+}
+
+// Pure
+/// The starts held back and the seconds they were held since the last send, and the furthest past the high
+/// threshold a held start let the chamber go.
+fn format_quiet(instance: &str, dialect: Dialect, quiet: &QuietStats, counts: (u64, u64)) -> String {
+    let (suppressed, held) = counts;
+    let mut metrics = vec![
+        ("quiet.suppressed", suppressed.to_string(), "c"),
+        ("quiet.held_seconds", held.to_string(), "c"),
+    ];
+    if let Some(excess) = quiet.excess {
+        metrics.push(("quiet.excess", format!("{:.3}", excess.0), "g"));
+    }
+    lines(instance, dialect, metrics)
+}
+
+fn lines<N: fmt::Display>(instance: &str, dialect: Dialect, metrics: Vec<(N, String, &str)>) -> String {
+    let lines: Vec<String> = metrics
+        .into_iter()
+        .map(|(name, value, kind)| match dialect {
+            Dialect::Plain => format!("{}.{}.{}:{}|{}", NAMESPACE, instance, name, value, kind),
+            Dialect::Tagged => format!("{}.{}:{}|{}|#instance:{}", NAMESPACE, name, value, kind, instance),
+        })
+        .collect();
+    lines.join("\n")
+}
+
+// Pure
+/// Keeps the characters StatsD or a URL path give a meaning to out of the instance.
+pub fn sanitize(instance: &str) -> String {
+    instance
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{loop_timing::Latency, test_util::snapshot};
+    use picool_core::temperature::DegreesDelta;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn formats_plain_statsd() {
+        assert_eq!(
+            "picool.28-0011.temperature:6.500|g\n\
+             picool.28-0011.threshold.low:4.100|g\n\
+             picool.28-0011.threshold.high:7.900|g\n\
+             picool.28-0011.relay:1|g\n\
+             picool.28-0011.fan:0|g\n\
+             picool.28-0011.cooling.rate:0.1250|g\n\
+             picool.28-0011.undershoot:0.250|g\n\
+             picool.28-0011.cycles:1|c\n\
+             picool.28-0011.sensor_errors:0|c\n\
+             picool.28-0011.relay_deferrals:1|c",
+            format("28-0011", Dialect::Plain, &snapshot(), (1, 0, 1))
+        );
+    }
+
+    #[test]
+    fn formats_tagged_dogstatsd_without_a_failed_reading() {
+        let failed = Snapshot {
+            temperature: None,
+            cooling_rate: None,
+            undershoot: None,
+            stage2: Some((false, 2.25, 3)),
+            current: Some((Some(2.35), None)),
+            ..snapshot()
+        };
+        assert_eq!(
+            "picool.threshold.low:4.100|g|#instance:exec_probe\n\
+             picool.threshold.high:7.900|g|#instance:exec_probe\n\
+             picool.relay:1|g|#instance:exec_probe\n\
+             picool.fan:0|g|#instance:exec_probe\n\
+             picool.stage2:0|g|#instance:exec_probe\n\
+             picool.stage2.runtime:2.25|g|#instance:exec_probe\n\
+             picool.stage2.starts:3|g|#instance:exec_probe\n\
+             picool.current:2.350|g|#instance:exec_probe\n\
+             picool.load_fault:0|g|#instance:exec_probe\n\
+             picool.cycles:0|c|#instance:exec_probe\n\
+             picool.sensor_errors:1|c|#instance:exec_probe\n\
+             picool.relay_deferrals:0|c|#instance:exec_probe",
+            format("exec_probe", Dialect::Tagged, &failed, (0, 1, 0))
+        );
+        assert_eq!("exec_read-temp_sh", sanitize("exec_read-temp.sh"));
+        assert_eq!(Dialect::Tagged, "dogstatsd".parse().unwrap());
+        assert!("graphite".parse::<Dialect>().is_err());
+    }
+
+    #[test]
+    fn formats_loop_latency_a_phase_per_datagram() {
+        let ms = Duration::from_millis;
+        let latency = LoopLatency {
+            persist: Latency {
+                p50: ms(12),
+                p95: ms(4100),
+                max: ms(9800),
+            },
+            overruns: 3,
+            ..LoopLatency::default()
+        };
+        let datagrams = format_latency("28-0011", Dialect::Plain, &latency, 1);
+        assert_eq!(5, datagrams.len());
+        assert_eq!(
+            "picool.28-0011.latency.persist.p50:12|g\n\
+             picool.28-0011.latency.persist.p95:4100|g\n\
+             picool.28-0011.latency.persist.max:9800|g",
+            datagrams[1]
+        );
+        assert_eq!(
+            "picool.overruns:1|c|#instance:x",
+            format_latency("x", Dialect::Tagged, &latency, 1)[4]
+        );
+    }
+
+    #[test]
+    fn formats_what_quiet_hours_held_back() {
+        let quiet = QuietStats {
+            suppressed: 4,
+            held: Duration::from_secs(3000),
+            excess: Some(DegreesDelta(1.2)),
+        };
+        assert_eq!(
+            "picool.quiet.suppressed:1|c|#instance:x\n\
+             picool.quiet.held_seconds:600|c|#instance:x\n\
+             picool.quiet.excess:1.200|g|#instance:x",
+            format_quiet("x", Dialect::Tagged, &quiet, (1, 600))
+        );
+    }
+
+    #[test]
+    fn sends_counter_increments_to_the_daemon() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").unwrap();
+        daemon.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = daemon.local_addr().unwrap().to_string();
+        let mut emitter = StatsdEmitter::new(&address, Dialect::Plain, "test").unwrap();
+        let received = || {
+            let mut buffer = [0; 512];
+            let length = daemon.recv(&mut buffer).unwrap();
+            String::from_utf8_lossy(&buffer[..length]).into_owned()
+        };
+        emitter.emit(&snapshot());
+        assert!(received().contains("picool.test.cycles:3|c"));
+        emitter.emit(&Snapshot {
+            cycles: 4,
+            ..snapshot()
+        });
+        let second = received();
+        assert!(second.contains("picool.test.cycles:1|c"), "{}", second);
+        assert!(second.contains("picool.test.sensor_errors:0|c"), "{}", second);
+        let timed = |overruns| Snapshot {
+            loop_latency: Some(LoopLatency {
+                overruns,
+                ..LoopLatency::default()
+            }),
+            ..snapshot()
+        };
+        emitter.emit(&timed(2));
+        let datagrams = |count| (0..count).map(|_| received()).collect::<Vec<_>>();
+        assert_eq!("picool.test.overruns:2|c", datagrams(6)[5]);
+        emitter.emit(&timed(3));
+        assert_eq!("picool.test.overruns:1|c", datagrams(6)[5]);
+        let quiet = |suppressed, held| Snapshot {
+            quiet: Some(QuietStats {
+                suppressed,
+                held: Duration::from_secs(held),
+                excess: None,
+            }),
+            ..snapshot()
+        };
+        emitter.emit(&quiet(2, 1800));
+        assert_eq!(
+            "picool.test.quiet.suppressed:2|c\npicool.test.quiet.held_seconds:1800|c",
+            datagrams(2)[1]
+        );
+        emitter.emit(&quiet(3, 2000));
+        assert_eq!(
+            "picool.test.quiet.suppressed:1|c\npicool.test.quiet.held_seconds:200|c",
+            datagrams(2)[1]
+        );
+    }
+
+    #[test]
+    fn counts_failed_sends_without_blocking() {
+        // Nothing listens there, the port unreachable answer to one send fails the next.
+        let unused = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut emitter = StatsdEmitter::new(&unused, Dialect::Plain, "test").unwrap();
+        for _ in 0..3 {
+            emitter.emit(&snapshot());
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(emitter.failed > 0);
+    }
+}
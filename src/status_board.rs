@@ -0,0 +1,558 @@
+use crate::{
+    actuation::ActuationDeferrals,
+    loop_timing::{Latency, LoopLatency, PHASES},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use picool_core::{
+    controller::ControllerState,
+    current::LoadFault,
+    failover::Output,
+    notify::Event,
+    quiet_hours::QuietStats,
+    temperature::{Celsius, DegreesDelta},
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    ops::Range,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// An hour of readings at the poll interval.
+const HISTORY: usize = 360;
+const EVENTS: usize = 5;
+
+/// What the control loop last did, as served by `status` on the control socket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub state: String,
+    /// A `--shadow` run, the relay is only what it would be.
+    pub shadow: bool,
+    pub temperature: Option<Celsius>,
+    pub power_on: bool,
+    pub fan_on: bool,
+    /// Whether stage 2 runs, with its runtime hours and starts since the daemon started, when one is configured.
+    pub stage2: Option<(bool, f64, u32)>,
+    /// Amps the compressor last drew, None for a failed read, and the load fault raised, when a current sensor is
+    /// configured.
+    pub current: Option<(Option<f32>, Option<LoadFault>)>,
+    /// Which relay switches the compressor and whether the primary is faulted, when a backup is configured.
+    pub output: Option<(Output, bool)>,
+    pub thresholds: (Celsius, Celsius),
+    pub band: Range<Celsius>,
+    /// Seconds left of a boost, the band is the boost's meanwhile.
+    pub boost: Option<u64>,
+    /// Seconds left of a rest after a long run, and how many seconds that run lasted.
+    pub rest: Option<(u64, u64)>,
+    /// Seconds left of a load shed, and how many seconds it has been going.
+    pub shed: Option<(u64, u64)>,
+    /// What quiet hours held back, when they are configured.
+    pub quiet: Option<QuietStats>,
+    pub runtime_hours: f64,
+    pub starts_today: u32,
+    pub cycles: u32,
+    /// Degrees per minute the last measurable on cycle cooled at.
+    pub cooling_rate: Option<f32>,
+    /// The cooling rate as a fraction of the baseline, once there is one.
+    pub cooling_ratio: Option<f32>,
+    /// How far the last learned on cycle went past the top of the band.
+    pub overshoot: Option<DegreesDelta>,
+    /// How far the last learned off cycle fell below the bottom of the band.
+    pub undershoot: Option<DegreesDelta>,
+    /// Failed reads since the daemon started.
+    pub sensor_errors: u64,
+    /// Relay changes held back since the daemon started, to keep actuations apart.
+    pub relay_deferrals: u64,
+    /// Where the time of the recent control loop iterations went, from the second iteration on.
+    pub loop_latency: Option<LoopLatency>,
+    /// Seconds since the control loop published this, filled in when it's served.
+    pub age: u64,
+    /// The readings of the last hour, oldest first, None for failed reads.
+    pub history: Vec<Option<Celsius>>,
+    /// The latest notifications, oldest first.
+    pub events: Vec<String>,
+}
+
+/// One `<key> <value>` line per field, temperatures in Celsius whatever the display units.
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        writeln!(f, "state {}", self.state)?;
+        if self.shadow {
+            writeln!(f, "shadow on")?;
+        }
+        writeln!(f, "temperature {}", wire(self.temperature))?;
+        writeln!(f, "relay {}", on_off(self.power_on))?;
+        writeln!(f, "fan {}", on_off(self.fan_on))?;
+        if let Some((on, runtime_hours, starts)) = self.stage2 {
+            writeln!(f, "stage2 {} {:.1} {}", on_off(on), runtime_hours, starts)?;
+        }
+        if let Some((amps, fault)) = self.current {
+            let amps = amps.map_or("-".into(), |amps| format!("{:.3}", amps));
+            let fault = fault.map_or("-".into(), |fault| fault.to_string());
+            writeln!(f, "current {} {}", amps, fault)?;
+        }
+        if let Some((output, faulted)) = self.output {
+            writeln!(f, "output {} {}", output, if faulted { "faulted" } else { "-" })?;
+        }
+        writeln!(f, "thresholds {:.3} {:.3}", self.thresholds.0 .0, self.thresholds.1 .0)?;
+        writeln!(f, "band {:.3} {:.3}", self.band.start.0, self.band.end.0)?;
+        if let Some(left) = self.boost {
+            writeln!(f, "boost {}", left)?;
+        }
+        if let Some((left, run)) = self.rest {
+            writeln!(f, "rest {} {}", left, run)?;
+        }
+        if let Some((left, since)) = self.shed {
+            writeln!(f, "shed {} {}", left, since)?;
+        }
+        if let Some(quiet) = &self.quiet {
+            writeln!(
+                f,
+                "quiet {} {} {}",
+                quiet.suppressed,
+                quiet.held.as_secs(),
+                quiet
+                    .excess
+                    .map_or_else(|| "-".into(), |excess| format!("{:.3}", excess.0))
+            )?;
+        }
+        writeln!(f, "runtime {:.1}", self.runtime_hours)?;
+        writeln!(f, "starts {}", self.starts_today)?;
+        writeln!(f, "cycles {}", self.cycles)?;
+        if let Some(rate) = self.cooling_rate {
+            writeln!(f, "cooling_rate {:.4}", rate)?;
+        }
+        if let Some(ratio) = self.cooling_ratio {
+            writeln!(f, "cooling_ratio {:.3}", ratio)?;
+        }
+        if let Some(overshoot) = self.overshoot {
+            writeln!(f, "overshoot {:.3}", overshoot.0)?;
+        }
+        if let Some(undershoot) = self.undershoot {
+            writeln!(f, "undershoot {:.3}", undershoot.0)?;
+        }
+        writeln!(f, "sensor_errors {}", self.sensor_errors)?;
+        writeln!(f, "relay_deferrals {}", self.relay_deferrals)?;
+        if let Some(latency) = &self.loop_latency {
+            for phase in PHASES {
+                writeln!(f, "latency_{} {}", phase.name(), latency.phase(phase))?;
+            }
+            writeln!(f, "latency_loop {}", latency.iteration)?;
+            writeln!(f, "overruns {}", latency.overruns)?;
+        }
+        writeln!(f, "age {}", self.age)?;
+        let history: Vec<String> = self.history.iter().map(|&reading| wire(reading)).collect();
+        writeln!(f, "history {}", history.join(" "))?;
+        for event in &self.events {
+            writeln!(f, "event {}", event)?;
+        }
+        Ok(())
+    }
+}
+
+fn wire(reading: Option<Celsius>) -> String {
+    reading.map_or_else(|| "-".into(), |temperature| format!("{:.3}", temperature.0))
+}
+
+impl FromStr for Snapshot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut snapshot = Snapshot {
+            state: String::new(),
+            shadow: false,
+            temperature: None,
+            power_on: false,
+            fan_on: false,
+            stage2: None,
+            current: None,
+            output: None,
+            thresholds: (Celsius(0.0), Celsius(0.0)),
+            band: Celsius(0.0)..Celsius(0.0),
+            boost: None,
+            rest: None,
+            shed: None,
+            quiet: None,
+            runtime_hours: 0.0,
+            starts_today: 0,
+            cycles: 0,
+            cooling_rate: None,
+            cooling_ratio: None,
+            overshoot: None,
+            undershoot: None,
+            sensor_errors: 0,
+            relay_deferrals: 0,
+            loop_latency: None,
+            age: 0,
+            history: vec![],
+            events: vec![],
+        };
+        for line in s.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let pair = || -> Result<(Celsius, Celsius)> {
+                match value.split_once(' ') {
+                    Some((a, b)) => Ok((Celsius(a.parse()?), Celsius(b.parse()?))),
+                    None => bail!("expected two temperatures"),
+                }
+            };
+            let parsed: Result<()> = (|| {
+                match key {
+                    "state" => snapshot.state = value.into(),
+                    "shadow" => snapshot.shadow = value == "on",
+                    "temperature" => snapshot.temperature = reading(value)?,
+                    "relay" => snapshot.power_on = value == "on",
+                    "fan" => snapshot.fan_on = value == "on",
+                    "stage2" => {
+                        snapshot.stage2 = match value.split_whitespace().collect::<Vec<_>>()[..] {
+                            [on, runtime_hours, starts] => Some((on == "on", runtime_hours.parse()?, starts.parse()?)),
+                            _ => bail!("expected on or off, the runtime and the starts"),
+                        }
+                    }
+                    "current" => {
+                        snapshot.current = match value.split_once(' ') {
+                            Some((amps, fault)) => Some((
+                                if amps == "-" { None } else { Some(amps.parse()?) },
+                                if fault == "-" { None } else { Some(fault.parse()?) },
+                            )),
+                            None => bail!("expected the amps and the fault"),
+                        }
+                    }
+                    "output" => {
+                        snapshot.output = match value.split_once(' ') {
+                            Some((output, faulted)) => Some((output.parse()?, faulted == "faulted")),
+                            None => bail!("expected the output and whether the primary is faulted"),
+                        }
+                    }
+                    "thresholds" => snapshot.thresholds = pair()?,
+                    "band" => {
+                        let (start, end) = pair()?;
+                        snapshot.band = start..end;
+                    }
+                    "boost" => snapshot.boost = Some(value.parse()?),
+                    "rest" => {
+                        snapshot.rest = match value.split_once(' ') {
+                            Some((left, run)) => Some((left.parse()?, run.parse()?)),
+                            None => bail!("expected seconds left and the run"),
+                        }
+                    }
+                    "shed" => {
+                        snapshot.shed = match value.split_once(' ') {
+                            Some((left, since)) => Some((left.parse()?, since.parse()?)),
+                            None => bail!("expected seconds left and since"),
+                        }
+                    }
+                    "quiet" => {
+                        snapshot.quiet = match value.split_whitespace().collect::<Vec<_>>()[..] {
+                            [suppressed, held, excess] => Some(QuietStats {
+                                suppressed: suppressed.parse()?,
+                                held: Duration::from_secs(held.parse()?),
+                                excess: reading(excess)?.map(|excess| DegreesDelta(excess.0)),
+                            }),
+                            _ => bail!("expected the held starts, the seconds held and the excess"),
+                        }
+                    }
+                    "runtime" => snapshot.runtime_hours = value.parse()?,
+                    "starts" => snapshot.starts_today = value.parse()?,
+                    "cycles" => snapshot.cycles = value.parse()?,
+                    "cooling_rate" => snapshot.cooling_rate = Some(value.parse()?),
+                    "cooling_ratio" => snapshot.cooling_ratio = Some(value.parse()?),
+                    "overshoot" => snapshot.overshoot = Some(DegreesDelta(value.parse()?)),
+                    "undershoot" => snapshot.undershoot = Some(DegreesDelta(value.parse()?)),
+                    "sensor_errors" => snapshot.sensor_errors = value.parse()?,
+                    "relay_deferrals" => snapshot.relay_deferrals = value.parse()?,
+                    "latency_loop" => {
+                        snapshot.loop_latency.get_or_insert_with(Default::default).iteration = latency(value)?
+                    }
+                    "overruns" => {
+                        snapshot.loop_latency.get_or_insert_with(Default::default).overruns = value.parse()?
+                    }
+                    key if key.starts_with("latency_") => {
+                        let latency = latency(value)?;
+                        let phase = PHASES.iter().find(|phase| key == format!("latency_{}", phase.name()));
+                        if let Some(&phase) = phase {
+                            *snapshot
+                                .loop_latency
+                                .get_or_insert_with(Default::default)
+                                .phase_mut(phase) = latency;
+                        }
+                    }
+                    "age" => snapshot.age = value.parse()?,
+                    "history" => snapshot.history = value.split_whitespace().map(reading).collect::<Result<_>>()?,
+                    "event" => snapshot.events.push(value.into()),
+                    // Newer daemons may say more.
+                    _ => {}
+                }
+                Ok(())
+            })();
+            parsed.with_context(|| format!("Invalid status line '{}'.", line))?;
+        }
+        if snapshot.state.is_empty() {
+            bail!("The status has no state.");
+        }
+        Ok(snapshot)
+    }
+}
+
+/// `12 40 95`, milliseconds.
+fn latency(value: &str) -> Result<Latency> {
+    let millis = value
+        .split_whitespace()
+        .map(|ms| ms.parse().map(Duration::from_millis))
+        .collect::<Result<Vec<_>, _>>()?;
+    match millis[..] {
+        [p50, p95, max] => Ok(Latency { p50, p95, max }),
+        _ => bail!("expected the median, the 95th percentile and the maximum"),
+    }
+}
+
+fn reading(value: &str) -> Result<Option<Celsius>> {
+    match value {
+        "-" => Ok(None),
+        value => Ok(Some(Celsius(value.parse().map_err(|e| anyhow!("{}", e))?))),
+    }
+}
+
+/// The latest snapshot, published by the control loop and read by the control socket thread.
+#[derive(Clone, Default)]
+pub struct StatusBoard {
+    inner: Arc<Mutex<Board>>,
+}
+
+#[derive(Default)]
+struct Board {
+    published: Option<(Instant, Snapshot)>,
+    history: VecDeque<Option<Celsius>>,
+    events: VecDeque<String>,
+    sensor_errors: u64,
+    relay_deferrals: ActuationDeferrals,
+    loop_latency: Option<LoopLatency>,
+    shadow: bool,
+}
+
+impl StatusBoard {
+    /// Called for every reading attempt, after its effects are carried out.
+    pub fn publish(&self, now: Instant, temperature: Option<Celsius>, controller: &ControllerState) {
+        let mut board = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if board.history.len() == HISTORY {
+            board.history.pop_front();
+        }
+        board.history.push_back(temperature);
+        if temperature.is_none() {
+            board.sensor_errors += 1;
+        }
+        let (low, high) = controller.thresholds();
+        let cooling = controller.cooling_rate();
+        let (overshoot, undershoot) = controller.overshoots();
+        let snapshot = Snapshot {
+            state: controller.state().to_string(),
+            shadow: board.shadow,
+            temperature,
+            power_on: controller.power_on(),
+            fan_on: controller.fan_on(),
+            stage2: controller
+                .stage2(now)
+                .map(|(on, runtime, starts)| (on, runtime.as_secs_f64() / 3600.0, starts)),
+            current: controller.current(),
+            output: controller.output(),
+            thresholds: (low, high),
+            band: controller.band(),
+            boost: controller.boost(now).map(|(_, left)| left.as_secs()),
+            rest: controller
+                .rest(now)
+                .map(|rest| (rest.remaining.as_secs(), rest.run.as_secs())),
+            shed: controller
+                .shed(now)
+                .map(|shed| (shed.remaining.as_secs(), shed.since.as_secs())),
+            quiet: controller.quiet_hours(now),
+            runtime_hours: controller.runtime(now).as_secs_f64() / 3600.0,
+            starts_today: controller.starts_today(),
+            cycles: controller.completed_cycles(),
+            cooling_rate: cooling.map(|(rate, _)| rate),
+            cooling_ratio: cooling.and_then(|(_, ratio)| ratio),
+            overshoot,
+            undershoot,
+            sensor_errors: board.sensor_errors,
+            relay_deferrals: board.relay_deferrals.count(),
+            loop_latency: board.loop_latency,
+            age: 0,
+            history: board.history.iter().copied().collect(),
+            events: board.events.iter().cloned().collect(),
+        };
+        board.published = Some((Instant::now(), snapshot));
+    }
+
+    /// Reports the relay changes the switch held back.
+    pub fn count_deferrals(&self, deferrals: ActuationDeferrals) {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .relay_deferrals = deferrals;
+    }
+
+    /// The iterations up to the last one, published with the next reading.
+    pub fn loop_latency(&self, latency: LoopLatency) {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .loop_latency = Some(latency);
+    }
+
+    /// Everything published from now on is a shadow run's.
+    pub fn mark_shadow(&self) {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .shadow = true;
+    }
+
+    pub fn event(&self, event: &Event) {
+        let mut board = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if board.events.len() == EVENTS {
+            board.events.pop_front();
+        }
+        board.events.push_back(event.to_string().replace('\n', " "));
+    }
+
+    /// None until the first reading.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        let board = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (published, snapshot) = board.published.as_ref()?;
+        Some(Snapshot {
+            age: published.elapsed().as_secs(),
+            ..snapshot.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::controller;
+    use std::time::Duration;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            state: "On".into(),
+            shadow: true,
+            temperature: Some(Celsius(6.5)),
+            power_on: true,
+            fan_on: false,
+            stage2: Some((true, 1.5, 4)),
+            current: Some((None, Some(LoadFault::NoLoad))),
+            output: Some((Output::Backup, true)),
+            thresholds: (Celsius(4.1), Celsius(7.9)),
+            band: Celsius(4.0)..Celsius(8.0),
+            boost: Some(5400),
+            rest: Some((600, 7200)),
+            shed: Some((3000, 600)),
+            quiet: Some(QuietStats {
+                suppressed: 3,
+                held: Duration::from_secs(2400),
+                excess: Some(DegreesDelta(1.25)),
+            }),
+            runtime_hours: 12.5,
+            starts_today: 7,
+            cycles: 3,
+            cooling_rate: Some(0.125),
+            cooling_ratio: Some(0.5),
+            overshoot: Some(DegreesDelta(0.42)),
+            undershoot: None,
+            sensor_errors: 2,
+            relay_deferrals: 1,
+            loop_latency: Some(LoopLatency {
+                sensor: Latency {
+                    p50: Duration::from_millis(750),
+                    p95: Duration::from_millis(800),
+                    max: Duration::from_millis(1500),
+                },
+                overruns: 2,
+                ..LoopLatency::default()
+            }),
+            age: 4,
+            history: vec![Some(Celsius(7.0)), None, Some(Celsius(6.5))],
+            events: vec!["Cooling degraded".into()],
+        }
+    }
+
+    #[test]
+    fn round_trips_over_the_wire() {
+        let snapshot = snapshot();
+        let wire = snapshot.to_string();
+        assert!(wire.contains("\nhistory 7.000 - 6.500\n"), "{}", wire);
+        assert!(
+            wire.contains("\nboost 5400\nrest 600 7200\nshed 3000 600\nquiet 3 2400 1.250\n"),
+            "{}",
+            wire
+        );
+        assert!(wire.contains("\nshadow on\n"), "{}", wire);
+        assert!(
+            wire.contains("\nfan off\nstage2 on 1.5 4\ncurrent - no-load\noutput backup faulted\n"),
+            "{}",
+            wire
+        );
+        assert!(wire.contains("\nsensor_errors 2\nrelay_deferrals 1\n"), "{}", wire);
+        assert!(
+            wire.contains("\nlatency_sensor 750 800 1500\nlatency_persist 0 0 0\nlatency_report 0 0 0\nlatency_loop 0 0 0\noverruns 2\n"),
+            "{}",
+            wire
+        );
+        assert!(
+            wire.contains("\ncooling_rate 0.1250\ncooling_ratio 0.500\novershoot 0.420\n"),
+            "{}",
+            wire
+        );
+        assert_eq!(snapshot, wire.parse().unwrap());
+        assert!("temperature 6.5\n".parse::<Snapshot>().is_err());
+        assert!("state On\nthresholds 4.0\n".parse::<Snapshot>().is_err());
+        assert!("state On\nlatency_loop 1 2\n".parse::<Snapshot>().is_err());
+        assert!("state On\nquiet 1 60\n".parse::<Snapshot>().is_err());
+        assert_eq!(
+            Some(QuietStats {
+                suppressed: 1,
+                held: Duration::from_secs(60),
+                excess: None
+            }),
+            "state On\nquiet 1 60 -\n".parse::<Snapshot>().unwrap().quiet
+        );
+        assert_eq!(None, "state On\n".parse::<Snapshot>().unwrap().loop_latency);
+        assert!("state On\nwhatever next\n".parse::<Snapshot>().is_ok());
+    }
+
+    #[test]
+    fn keeps_the_last_hour_and_the_latest_events() {
+        let board = StatusBoard::default();
+        assert_eq!(None, board.snapshot());
+        let start = Instant::now();
+        let controller = controller(start);
+        for i in 0..HISTORY + 10 {
+            board.publish(
+                start + Duration::from_secs(10) * i as u32,
+                Some(Celsius(i as f32)),
+                &controller,
+            );
+        }
+        for _ in 0..EVENTS + 1 {
+            board.event(&Event::RuntimeReset {
+                previous: Duration::from_secs(3600),
+            });
+        }
+        board.publish(start, None, &controller);
+        let snapshot = board.snapshot().unwrap();
+        assert_eq!("Off", snapshot.state);
+        assert_eq!(None, snapshot.temperature);
+        assert_eq!(1, snapshot.sensor_errors);
+        assert_eq!(HISTORY, snapshot.history.len());
+        assert_eq!(Some(Celsius(11.0)), snapshot.history[0]);
+        assert_eq!(EVENTS, snapshot.events.len());
+        assert_eq!(controller.band(), snapshot.band);
+        assert_eq!(None, snapshot.boost);
+        assert_eq!(None, snapshot.loop_latency);
+        assert!(!snapshot.shadow);
+        board.mark_shadow();
+        board.publish(start, None, &controller);
+        assert!(board.snapshot().unwrap().shadow);
+    }
+}
@@ -0,0 +1,534 @@
+use crate::world::Store;
+use anyhow::{anyhow, bail, Result};
+use log::*;
+use picool_core::{
+    boost::Boost,
+    controller::CycleRecord,
+    energy::EnergyTotals,
+    heartbeat::HeartbeatStatus,
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    rng::Rng,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::DegreesDelta,
+    world_error::{PersistError, PersistErrorKind},
+    CycleExtremes, MinimumIntervals, RestoredPowerState,
+};
+use std::{fmt, io, str::FromStr, time::Duration};
+
+/// When persisting fails.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PersistFailures {
+    Never,
+    Always,
+    /// Each write fails with this probability.
+    Intermittent(f32),
+    /// Writes succeed this many times, then every one fails.
+    After(u32),
+}
+
+/// `"always"`, `"after 5"` or a probability.
+impl FromStr for PersistFailures {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "always" {
+            return Ok(PersistFailures::Always);
+        }
+        if let Some(count) = s.strip_prefix("after ") {
+            return count
+                .trim()
+                .parse()
+                .map(PersistFailures::After)
+                .map_err(|_| anyhow!("Expected a count after 'after', got '{}'.", count));
+        }
+        match s.parse::<f32>() {
+            Ok(probability) if (0.0..=1.0).contains(&probability) => Ok(PersistFailures::Intermittent(probability)),
+            _ => bail!(
+                "Expected always, after N or a probability between 0 and 1, got '{}'.",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for PersistFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistFailures::Never => write!(f, "never"),
+            PersistFailures::Always => write!(f, "always"),
+            PersistFailures::Intermittent(probability) => write!(f, "with probability {}", probability),
+            PersistFailures::After(count) => write!(f, "after {} writes", count),
+        }
+    }
+}
+
+/// Faults on any store, all off by default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreFaults {
+    pub seed: u64,
+    /// Applies to the last off transition, compensations and cooling rates, not the heartbeat.
+    pub persist_failures: PersistFailures,
+    pub persist_error: PersistErrorKind,
+    /// Every restore returns garbage, the power state fails to parse.
+    pub corrupt_restore: bool,
+    /// The last off transition restores as this much older than it is.
+    pub stale_restore: Option<Duration>,
+}
+
+impl Default for StoreFaults {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            persist_failures: PersistFailures::Never,
+            persist_error: PersistErrorKind::Io,
+            corrupt_restore: false,
+            stale_restore: None,
+        }
+    }
+}
+
+impl StoreFaults {
+    pub fn any(&self) -> bool {
+        self.persist_failures != PersistFailures::Never || self.corrupt_restore || self.stale_restore.is_some()
+    }
+}
+
+impl fmt::Display for StoreFaults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "store faults seed {}: persisting fails {} ({})",
+            self.seed, self.persist_failures, self.persist_error
+        )?;
+        if self.corrupt_restore {
+            write!(f, ", corrupt restore")?;
+        }
+        if let Some(age) = self.stale_restore {
+            write!(f, ", restore stale by {}m", age.as_secs() / 60)?;
+        }
+        Ok(())
+    }
+}
+
+// Pure
+fn errno(kind: PersistErrorKind) -> i32 {
+    match kind {
+        PersistErrorKind::StorageFull => libc::ENOSPC,
+        PersistErrorKind::ReadOnly => libc::EROFS,
+        PersistErrorKind::PermissionDenied => libc::EACCES,
+        PersistErrorKind::Io | PersistErrorKind::Unverified => libc::EIO,
+    }
+}
+
+/// Wraps any store, failing its writes and damaging what it restores as configured.
+pub struct FaultyStore<St> {
+    inner: St,
+    faults: StoreFaults,
+    rng: Rng,
+    writes: u32,
+}
+
+impl<St: Store> FaultyStore<St> {
+    pub fn new(inner: St, faults: StoreFaults) -> Self {
+        Self {
+            inner,
+            rng: Rng::new(faults.seed),
+            faults,
+            writes: 0,
+        }
+    }
+
+    fn persist(
+        &mut self,
+        what: &str,
+        write: impl FnOnce(&mut St) -> Result<(), PersistError>,
+    ) -> Result<(), PersistError> {
+        self.writes += 1;
+        let fail = match self.faults.persist_failures {
+            PersistFailures::Never => false,
+            PersistFailures::Always => true,
+            PersistFailures::Intermittent(probability) => self.rng.chance(probability),
+            PersistFailures::After(count) => self.writes > count,
+        };
+        if !fail {
+            return write(&mut self.inner);
+        }
+        info!("FAULT: persisting {} fails ({}).", what, self.faults.persist_error);
+        Err(io::Error::from_raw_os_error(errno(self.faults.persist_error)).into())
+    }
+}
+
+impl<St: Store> Store for FaultyStore<St> {
+    fn restore_power_state(&self) -> Result<RestoredPowerState> {
+        if self.faults.corrupt_restore {
+            info!("FAULT: last off transition is corrupt.");
+            bail!("Failed parsing stored last off transition.");
+        }
+        let restored = self.inner.restore_power_state()?;
+        let age = match self.faults.stale_restore {
+            Some(age) => age,
+            None => return Ok(restored),
+        };
+        info!("FAULT: last off transition is {}m stale.", age.as_secs() / 60);
+        Ok(match restored {
+            RestoredPowerState::OffFor(duration) => RestoredPowerState::OffFor(duration + age),
+            RestoredPowerState::OffForUnknownDuration => RestoredPowerState::OffFor(age),
+            RestoredPowerState::CurrentlyOn => RestoredPowerState::CurrentlyOn,
+        })
+    }
+
+    fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+        if self.faults.corrupt_restore {
+            info!("FAULT: compensations are corrupt.");
+            return (DegreesDelta(f32::NAN), DegreesDelta(1e6));
+        }
+        self.inner.restore_compensation()
+    }
+
+    fn restore_cooling_rates(&self) -> Vec<f32> {
+        if self.faults.corrupt_restore {
+            info!("FAULT: cooling rates are corrupt.");
+            return vec![f32::NAN, f32::INFINITY, 1e30];
+        }
+        self.inner.restore_cooling_rates()
+    }
+
+    fn restore_energy(&self) -> EnergyTotals {
+        if self.faults.corrupt_restore {
+            info!("FAULT: energy totals are corrupt.");
+            return EnergyTotals {
+                today: f64::NAN,
+                lifetime: -1.0,
+                ..self.inner.restore_energy()
+            };
+        }
+        self.inner.restore_energy()
+    }
+
+    fn restore_runtime(&self) -> Duration {
+        self.inner.restore_runtime()
+    }
+
+    fn restore_starts(&self) -> StartCounts {
+        if self.faults.corrupt_restore {
+            info!("FAULT: start counts are corrupt.");
+            return StartCounts {
+                history: vec![Some(u32::MAX); 1000],
+                ..self.inner.restore_starts()
+            };
+        }
+        self.inner.restore_starts()
+    }
+
+    fn restore_boost(&self) -> Option<Boost> {
+        self.inner.restore_boost()
+    }
+
+    fn restore_intervals(&self) -> Option<MinimumIntervals> {
+        self.inner.restore_intervals()
+    }
+
+    fn restore_overshoots(&self) -> Overshoots {
+        self.inner.restore_overshoots()
+    }
+
+    fn restore_rest(&self) -> Option<RestPeriod> {
+        self.inner.restore_rest()
+    }
+
+    fn restore_shed(&self) -> Option<ShedPeriod> {
+        self.inner.restore_shed()
+    }
+
+    fn restore_extremes(&self) -> Option<CycleExtremes> {
+        self.inner.restore_extremes()
+    }
+
+    fn restore_failover(&self) -> bool {
+        self.inner.restore_failover()
+    }
+
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+        self.inner.write_heartbeat(status)
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        self.persist("last off transition", |inner| inner.persist_last_off_transition())
+    }
+
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+        self.persist("compensations", |inner| inner.persist_compensation(cooling, heating))
+    }
+
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+        self.persist("cooling rates", |inner| inner.persist_cooling_rates(rates))
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        self.persist("energy", |inner| inner.persist_energy(totals))
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        self.persist("runtime", |inner| inner.persist_runtime(runtime))
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        self.persist("starts", |inner| inner.persist_starts(counts))
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        self.persist("boost", |inner| inner.persist_boost(boost))
+    }
+
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        self.persist("intervals", |inner| inner.persist_intervals(intervals))
+    }
+
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+        self.persist("overshoots", |inner| inner.persist_overshoots(overshoots))
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        self.persist("rest", |inner| inner.persist_rest(rest))
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        self.persist("shed", |inner| inner.persist_shed(shed))
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        self.persist("extremes", |inner| inner.persist_extremes(extremes))
+    }
+
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        self.persist("cycle history", |inner| inner.record_cycle(cycle))
+    }
+
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+        self.persist("failover", |inner| inner.persist_failover(faulted))
+    }
+
+    fn switch_sensor(&mut self, name: &str) {
+        self.inner.switch_sensor(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the writes that reach it.
+    #[derive(Default)]
+    struct CountingStore(u32);
+
+    impl Store for CountingStore {
+        fn restore_power_state(&self) -> Result<RestoredPowerState> {
+            Ok(RestoredPowerState::OffFor(Duration::from_secs(60)))
+        }
+
+        fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+            (DegreesDelta(0.5), DegreesDelta::ZERO)
+        }
+
+        fn restore_cooling_rates(&self) -> Vec<f32> {
+            vec![10.0]
+        }
+
+        fn restore_energy(&self) -> EnergyTotals {
+            EnergyTotals::default()
+        }
+
+        fn restore_runtime(&self) -> Duration {
+            Duration::from_secs(0)
+        }
+
+        fn restore_starts(&self) -> StartCounts {
+            StartCounts::default()
+        }
+
+        fn restore_boost(&self) -> Option<Boost> {
+            None
+        }
+
+        fn restore_intervals(&self) -> Option<MinimumIntervals> {
+            None
+        }
+
+        fn restore_overshoots(&self) -> Overshoots {
+            Overshoots::default()
+        }
+
+        fn restore_rest(&self) -> Option<RestPeriod> {
+            None
+        }
+
+        fn restore_shed(&self) -> Option<ShedPeriod> {
+            None
+        }
+
+        fn restore_extremes(&self) -> Option<CycleExtremes> {
+            None
+        }
+
+        fn restore_failover(&self) -> bool {
+            false
+        }
+
+        fn write_heartbeat(&mut self, _status: HeartbeatStatus) -> Result<(), PersistError> {
+            Ok(())
+        }
+
+        fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_compensation(&mut self, _cooling: DegreesDelta, _heating: DegreesDelta) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_cooling_rates(&mut self, _rates: &[f32]) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_energy(&mut self, _totals: EnergyTotals) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_runtime(&mut self, _runtime: Duration) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_starts(&mut self, _counts: &StartCounts) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_boost(&mut self, _boost: Option<&Boost>) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_intervals(&mut self, _intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_overshoots(&mut self, _overshoots: &Overshoots) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_rest(&mut self, _rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_shed(&mut self, _shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_extremes(&mut self, _extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn record_cycle(&mut self, _cycle: &CycleRecord) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn persist_failover(&mut self, _faulted: bool) -> Result<(), PersistError> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn switch_sensor(&mut self, _name: &str) {}
+    }
+
+    fn store(faults: StoreFaults) -> FaultyStore<CountingStore> {
+        FaultyStore::new(CountingStore::default(), faults)
+    }
+
+    #[test]
+    fn passes_through_by_default() {
+        let mut store = store(StoreFaults::default());
+        assert!(store.persist_last_off_transition().is_ok());
+        assert!(store
+            .persist_compensation(DegreesDelta::ZERO, DegreesDelta::ZERO)
+            .is_ok());
+        assert_eq!(2, store.inner.0);
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(60)),
+            store.restore_power_state().unwrap()
+        );
+        assert_eq!(vec![10.0], store.restore_cooling_rates());
+    }
+
+    #[test]
+    fn fails_after_count_with_configured_kind() {
+        let mut store = store(StoreFaults {
+            persist_failures: PersistFailures::After(2),
+            persist_error: PersistErrorKind::StorageFull,
+            ..Default::default()
+        });
+        assert!(store.persist_last_off_transition().is_ok());
+        assert!(store.persist_cooling_rates(&[]).is_ok());
+        let error = store.persist_last_off_transition().unwrap_err();
+        assert_eq!(PersistErrorKind::StorageFull, error.kind());
+        assert_eq!(2, store.inner.0);
+    }
+
+    #[test]
+    fn intermittent_failures_follow_the_seed() {
+        let outcomes = |seed| {
+            let mut store = store(StoreFaults {
+                seed,
+                persist_failures: PersistFailures::Intermittent(0.5),
+                ..Default::default()
+            });
+            (0..100)
+                .map(|_| store.persist_last_off_transition().is_ok())
+                .collect::<Vec<_>>()
+        };
+        let first = outcomes(3);
+        assert_eq!(first, outcomes(3));
+        assert_ne!(first, outcomes(4));
+        assert!((30..70).contains(&first.iter().filter(|ok| **ok).count()));
+    }
+
+    #[test]
+    fn damages_restored_data() {
+        let corrupt = store(StoreFaults {
+            corrupt_restore: true,
+            ..Default::default()
+        });
+        assert!(corrupt.restore_power_state().is_err());
+        assert!(corrupt.restore_compensation().0.is_nan());
+        let stale = store(StoreFaults {
+            stale_restore: Some(Duration::from_secs(60 * 60)),
+            ..Default::default()
+        });
+        assert_eq!(
+            RestoredPowerState::OffFor(Duration::from_secs(60 * 61)),
+            stale.restore_power_state().unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_persist_failures() {
+        assert_eq!(PersistFailures::Always, "always".parse().unwrap());
+        assert_eq!(PersistFailures::After(5), "after 5".parse().unwrap());
+        assert_eq!(PersistFailures::Intermittent(0.25), "0.25".parse().unwrap());
+        assert!("sometimes".parse::<PersistFailures>().is_err());
+        assert!("after lots".parse::<PersistFailures>().is_err());
+        assert!("1.5".parse::<PersistFailures>().is_err());
+    }
+}
@@ -0,0 +1,466 @@
+use crate::world::Store;
+use anyhow::Result;
+use picool_core::{
+    boost::Boost, controller::CycleRecord, energy::EnergyTotals, heartbeat::HeartbeatStatus, overshoot::Overshoots,
+    rest::RestPeriod, shed::ShedPeriod, starts::StartCounts, temperature::DegreesDelta, world_error::PersistError,
+    CycleExtremes, MinimumIntervals, RestoredPowerState,
+};
+use std::time::Duration;
+
+/// How far a time read back may be from the one written, files keep whole seconds against the wall clock.
+const READ_BACK_SLACK: Duration = Duration::from_secs(60);
+
+/// Wraps any store, reading each write back and failing it as unverified when the store returns something else.
+/// The state files are a few bytes each, so the extra read costs next to nothing.
+pub struct VerifiedStore<St> {
+    inner: St,
+}
+
+impl<St: Store> VerifiedStore<St> {
+    pub fn new(inner: St) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &St {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut St {
+        &mut self.inner
+    }
+
+    fn verify(&self, what: &str, written: Result<(), PersistError>, read_back: bool) -> Result<(), PersistError> {
+        written?;
+        match read_back {
+            true => Ok(()),
+            false => Err(PersistError::unverified(what)),
+        }
+    }
+}
+
+// Pure
+fn close(a: Duration, b: Duration) -> bool {
+    a.max(b) - a.min(b) <= READ_BACK_SLACK
+}
+
+// Pure
+fn same_millidegrees(a: DegreesDelta, b: DegreesDelta) -> bool {
+    a.millidegrees() == b.millidegrees()
+}
+
+impl<St: Store> Store for VerifiedStore<St> {
+    fn restore_power_state(&self) -> Result<RestoredPowerState> {
+        self.inner.restore_power_state()
+    }
+
+    fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+        self.inner.restore_compensation()
+    }
+
+    fn restore_cooling_rates(&self) -> Vec<f32> {
+        self.inner.restore_cooling_rates()
+    }
+
+    fn restore_energy(&self) -> EnergyTotals {
+        self.inner.restore_energy()
+    }
+
+    fn restore_runtime(&self) -> Duration {
+        self.inner.restore_runtime()
+    }
+
+    fn restore_starts(&self) -> StartCounts {
+        self.inner.restore_starts()
+    }
+
+    fn restore_boost(&self) -> Option<Boost> {
+        self.inner.restore_boost()
+    }
+
+    fn restore_intervals(&self) -> Option<MinimumIntervals> {
+        self.inner.restore_intervals()
+    }
+
+    fn restore_overshoots(&self) -> Overshoots {
+        self.inner.restore_overshoots()
+    }
+
+    fn restore_rest(&self) -> Option<RestPeriod> {
+        self.inner.restore_rest()
+    }
+
+    fn restore_shed(&self) -> Option<ShedPeriod> {
+        self.inner.restore_shed()
+    }
+
+    fn restore_extremes(&self) -> Option<CycleExtremes> {
+        self.inner.restore_extremes()
+    }
+
+    fn restore_failover(&self) -> bool {
+        self.inner.restore_failover()
+    }
+
+    /// Not verified, it's rewritten every poll and only a watchdog reads it.
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+        self.inner.write_heartbeat(status)
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        let written = self.inner.persist_last_off_transition();
+        let read_back = matches!(
+            self.inner.restore_power_state(),
+            Ok(RestoredPowerState::OffFor(off)) if off <= READ_BACK_SLACK
+        );
+        self.verify("last off transition", written, read_back)
+    }
+
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+        let written = self.inner.persist_compensation(cooling, heating);
+        let (read_cooling, read_heating) = self.inner.restore_compensation();
+        let read_back = same_millidegrees(cooling, read_cooling) && same_millidegrees(heating, read_heating);
+        self.verify("compensations", written, read_back)
+    }
+
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+        let written = self.inner.persist_cooling_rates(rates);
+        let read_back = self.inner.restore_cooling_rates() == rates;
+        self.verify("cooling rates", written, read_back)
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        let written = self.inner.persist_energy(totals);
+        let read_back = self.inner.restore_energy() == totals;
+        self.verify("energy", written, read_back)
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        let written = self.inner.persist_runtime(runtime);
+        let read_back = self.inner.restore_runtime().as_secs() == runtime.as_secs();
+        self.verify("runtime", written, read_back)
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        let written = self.inner.persist_starts(counts);
+        let read_back = self.inner.restore_starts() == *counts;
+        self.verify("starts", written, read_back)
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        let written = self.inner.persist_boost(boost);
+        let read_back = match (boost, self.inner.restore_boost()) {
+            (None, None) => true,
+            (Some(boost), Some(read)) => {
+                boost.band.start.millidegrees() == read.band.start.millidegrees()
+                    && boost.band.end.millidegrees() == read.band.end.millidegrees()
+                    && close(boost.remaining, read.remaining)
+            }
+            _ => false,
+        };
+        self.verify("boost", written, read_back)
+    }
+
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        let written = self.inner.persist_intervals(intervals);
+        let read_back = self.inner.restore_intervals() == intervals;
+        self.verify("intervals", written, read_back)
+    }
+
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+        let written = self.inner.persist_overshoots(overshoots);
+        let read_back = self.inner.restore_overshoots() == *overshoots;
+        self.verify("overshoots", written, read_back)
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        let written = self.inner.persist_rest(rest);
+        let read_back = match (rest, self.inner.restore_rest()) {
+            (None, None) => true,
+            (Some(rest), Some(read)) => {
+                rest.run.as_secs() == read.run.as_secs() && close(rest.remaining, read.remaining)
+            }
+            _ => false,
+        };
+        self.verify("rest", written, read_back)
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        let written = self.inner.persist_shed(shed);
+        let read_back = match (shed, self.inner.restore_shed()) {
+            (None, None) => true,
+            (Some(shed), Some(read)) => close(shed.since, read.since) && close(shed.remaining, read.remaining),
+            _ => false,
+        };
+        self.verify("shed", written, read_back)
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        let written = self.inner.persist_extremes(extremes);
+        let read_back = match (extremes, self.inner.restore_extremes()) {
+            (None, None) => true,
+            (Some(extremes), Some(read)) => {
+                (extremes.on, extremes.min, extremes.max) == (read.on, read.min, read.max)
+                    && close(extremes.running, read.running)
+                    && close(extremes.age, read.age)
+            }
+            _ => false,
+        };
+        self.verify("extremes", written, read_back)
+    }
+
+    /// Nothing restores the history, it's only read back by `picool cycles`.
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        self.inner.record_cycle(cycle)
+    }
+
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+        let written = self.inner.persist_failover(faulted);
+        let read_back = self.inner.restore_failover() == faulted;
+        self.verify("failover", written, read_back)
+    }
+
+    fn switch_sensor(&mut self, name: &str) {
+        self.inner.switch_sensor(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use picool_core::{temperature::Celsius, world_error::PersistErrorKind};
+
+    /// Keeps what is written in memory, or acknowledges every write and keeps nothing once it starts lying.
+    #[derive(Default)]
+    struct MemoryStore {
+        lying: bool,
+        last_off: bool,
+        compensation: (DegreesDelta, DegreesDelta),
+        rates: Vec<f32>,
+        energy: EnergyTotals,
+        runtime: Duration,
+        starts: StartCounts,
+        boost: Option<Boost>,
+        intervals: Option<MinimumIntervals>,
+        overshoots: Overshoots,
+        rest: Option<RestPeriod>,
+        shed: Option<ShedPeriod>,
+        extremes: Option<CycleExtremes>,
+        failover: bool,
+    }
+
+    impl MemoryStore {
+        fn keep(&mut self, write: impl FnOnce(&mut Self)) -> Result<(), PersistError> {
+            if !self.lying {
+                write(self);
+            }
+            Ok(())
+        }
+    }
+
+    impl Store for MemoryStore {
+        fn restore_power_state(&self) -> Result<RestoredPowerState> {
+            Ok(match self.last_off {
+                true => RestoredPowerState::OffFor(Duration::from_secs(0)),
+                false => RestoredPowerState::OffForUnknownDuration,
+            })
+        }
+
+        fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+            self.compensation
+        }
+
+        fn restore_cooling_rates(&self) -> Vec<f32> {
+            self.rates.clone()
+        }
+
+        fn restore_energy(&self) -> EnergyTotals {
+            self.energy
+        }
+
+        fn restore_runtime(&self) -> Duration {
+            self.runtime
+        }
+
+        fn restore_starts(&self) -> StartCounts {
+            self.starts.clone()
+        }
+
+        fn restore_boost(&self) -> Option<Boost> {
+            self.boost.clone()
+        }
+
+        fn restore_intervals(&self) -> Option<MinimumIntervals> {
+            self.intervals
+        }
+
+        fn restore_overshoots(&self) -> Overshoots {
+            self.overshoots.clone()
+        }
+
+        fn restore_rest(&self) -> Option<RestPeriod> {
+            self.rest
+        }
+
+        fn restore_shed(&self) -> Option<ShedPeriod> {
+            self.shed
+        }
+
+        fn restore_extremes(&self) -> Option<CycleExtremes> {
+            self.extremes
+        }
+
+        fn restore_failover(&self) -> bool {
+            self.failover
+        }
+
+        fn write_heartbeat(&mut self, _status: HeartbeatStatus) -> Result<(), PersistError> {
+            Ok(())
+        }
+
+        fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+            self.keep(|store| store.last_off = true)
+        }
+
+        fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+            self.keep(|store| store.compensation = (cooling, heating))
+        }
+
+        fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+            self.keep(|store| store.rates = rates.to_vec())
+        }
+
+        fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+            self.keep(|store| store.energy = totals)
+        }
+
+        fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+            self.keep(|store| store.runtime = runtime)
+        }
+
+        fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+            self.keep(|store| store.starts = counts.clone())
+        }
+
+        fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+            self.keep(|store| store.boost = boost.cloned())
+        }
+
+        fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+            self.keep(|store| store.intervals = intervals)
+        }
+
+        fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+            self.keep(|store| store.overshoots = overshoots.clone())
+        }
+
+        fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+            self.keep(|store| store.rest = rest.copied())
+        }
+
+        fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+            self.keep(|store| store.shed = shed.copied())
+        }
+
+        fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+            self.keep(|store| store.extremes = extremes.copied())
+        }
+
+        fn record_cycle(&mut self, _cycle: &CycleRecord) -> Result<(), PersistError> {
+            Ok(())
+        }
+
+        fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+            self.keep(|store| store.failover = faulted)
+        }
+
+        fn switch_sensor(&mut self, _name: &str) {}
+    }
+
+    /// One of each write, in the same order.
+    fn write_everything(store: &mut impl Store) -> Vec<Result<(), PersistError>> {
+        let boost = Boost {
+            band: Celsius(0.5)..Celsius(1.5),
+            remaining: Duration::from_secs(3600),
+        };
+        vec![
+            store.persist_last_off_transition(),
+            store.persist_compensation(DegreesDelta(0.25), DegreesDelta(-0.125)),
+            store.persist_cooling_rates(&[0.5, 0.25]),
+            store.persist_energy(EnergyTotals {
+                day: 19000,
+                today: 1.5,
+                yesterday: 2.25,
+                lifetime: 140.75,
+            }),
+            store.persist_runtime(Duration::from_secs(7200)),
+            store.persist_starts(&StartCounts {
+                lifetime: 100,
+                day: 19000,
+                today: 4,
+                history: vec![Some(31), None],
+            }),
+            store.persist_boost(Some(&boost)),
+            store.persist_intervals(Some(MinimumIntervals {
+                on: Duration::from_secs(5 * 60),
+                off: Duration::from_secs(15 * 60),
+            })),
+            store.persist_overshoots(&Overshoots {
+                over: vec![DegreesDelta(0.5)],
+                under: vec![],
+            }),
+            store.persist_rest(Some(&RestPeriod {
+                run: Duration::from_secs(2 * 3600),
+                remaining: Duration::from_secs(1800),
+            })),
+            store.persist_extremes(Some(&CycleExtremes {
+                on: false,
+                min: Celsius(0.25),
+                max: Celsius(4.5),
+                running: Duration::from_secs(900),
+                age: Duration::from_secs(0),
+            })),
+            store.persist_failover(true),
+        ]
+    }
+
+    #[test]
+    fn passes_writes_that_read_back() {
+        let mut store = VerifiedStore::new(MemoryStore::default());
+        assert!(write_everything(&mut store).iter().all(|result| result.is_ok()));
+        assert!(store.persist_boost(None).is_ok());
+    }
+
+    #[test]
+    fn fails_writes_the_store_only_pretends_to_keep() {
+        let mut store = VerifiedStore::new(MemoryStore::default());
+        write_everything(&mut store);
+        store.inner_mut().lying = true;
+        // Writing the same again reads back fine, the store already had it.
+        assert!(write_everything(&mut store).iter().all(|result| result.is_ok()));
+        store.inner_mut().compensation = (DegreesDelta::ZERO, DegreesDelta::ZERO);
+        store.inner_mut().last_off = false;
+        let results = write_everything(&mut store);
+        assert_eq!(
+            vec![Some(PersistErrorKind::Unverified); 2],
+            results[..2]
+                .iter()
+                .map(|r| r.as_ref().err().map(|e| e.kind()))
+                .collect::<Vec<_>>()
+        );
+        let changed = [
+            store.persist_cooling_rates(&[1.0]),
+            store.persist_runtime(Duration::from_secs(7260)),
+            store.persist_boost(None),
+            store.persist_intervals(None),
+            store.persist_overshoots(&Overshoots::default()),
+            store.persist_rest(None),
+            store.persist_extremes(None),
+            store.persist_failover(false),
+        ];
+        assert!(changed
+            .iter()
+            .all(|r| r.as_ref().unwrap_err().kind() == PersistErrorKind::Unverified));
+        // The heartbeat isn't read back.
+        assert!(store.write_heartbeat(HeartbeatStatus::Controlling).is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{
+    candidate::Candidate,
+    diagnostics::{self, DumpRequest},
+    hooks::TransitionHook,
+    loop_timing::LoopTimings,
+    sd_notify::SdNotify,
+    shed_signal::ShedSignal,
+    stall_monitor::LoopProgress,
+    statsd::StatsdEmitter,
+    status_board::StatusBoard,
+    timeline::Timeline,
+    websocket::WsStream,
+};
+use log::*;
+use picool_core::{
+    config::Config,
+    controller::{ControllerState, Input, Transition},
+    heartbeat::WarningLimiter,
+    notify::Event,
+    temperature::Celsius,
+};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// What the control loop tells whoever supervises the process: progress for the stall monitor and, when started
+/// by systemd, readiness, status and watchdog pings, the status board behind the control socket and the timeline
+/// of a simulation or replay, the user's transition hook, a StatsD daemon, WebSocket clients, diagnostic dumps and a
+/// candidate configuration's controller. It also reads the demand response signal for the controller's input.
+pub struct Supervisor {
+    progress: LoopProgress,
+    board: Option<StatusBoard>,
+    statsd: Option<StatsdEmitter>,
+    stream: Option<WsStream>,
+    timeline: Option<Timeline>,
+    hook: Option<TransitionHook>,
+    candidate: Option<Candidate>,
+    shed: Option<ShedSignal>,
+    dumps: DumpRequest,
+    /// Where dumps are written besides the log.
+    dump_dir: Option<PathBuf>,
+    /// Says so in every status.
+    shadow: bool,
+    systemd: Option<SdNotify>,
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+    warnings: WarningLimiter,
+}
+
+impl Supervisor {
+    pub fn new(progress: LoopProgress) -> Self {
+        Self {
+            progress,
+            board: None,
+            statsd: None,
+            stream: None,
+            timeline: None,
+            hook: None,
+            candidate: None,
+            shed: None,
+            dumps: DumpRequest::default(),
+            dump_dir: None,
+            shadow: false,
+            systemd: None,
+            watchdog: None,
+            last_ping: None,
+            warnings: WarningLimiter::default(),
+        }
+    }
+
+    pub fn with_systemd(mut self, systemd: SdNotify, watchdog: Option<Duration>) -> Self {
+        self.systemd = Some(systemd);
+        self.watchdog = watchdog;
+        self
+    }
+
+    pub fn with_board(mut self, board: StatusBoard) -> Self {
+        self.board = Some(board);
+        self
+    }
+
+    /// Sends what the board publishes, so it comes with one.
+    pub fn with_statsd(mut self, statsd: StatsdEmitter) -> Self {
+        self.board.get_or_insert_with(StatusBoard::default);
+        self.statsd = Some(statsd);
+        self
+    }
+
+    /// Streams what the board publishes, so it comes with one, and every transition and event.
+    pub fn with_stream(mut self, stream: WsStream) -> Self {
+        self.board.get_or_insert_with(StatusBoard::default);
+        self.stream = Some(stream);
+        self
+    }
+
+    pub fn with_timeline(mut self, timeline: Timeline) -> Self {
+        self.timeline = Some(timeline);
+        self
+    }
+
+    pub fn with_hook(mut self, hook: TransitionHook) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    pub fn with_candidate(mut self, candidate: Candidate) -> Self {
+        self.candidate = Some(candidate);
+        self
+    }
+
+    pub fn with_shed(mut self, shed: ShedSignal) -> Self {
+        self.shed = Some(shed);
+        self
+    }
+
+    pub fn with_dumps(mut self, dumps: DumpRequest, dir: Option<PathBuf>) -> Self {
+        self.dumps = dumps;
+        self.dump_dir = dir;
+        self
+    }
+
+    /// After the board, which is marked as a shadow run's.
+    pub fn with_shadow(mut self) -> Self {
+        if let Some(board) = &self.board {
+            board.mark_shadow();
+        }
+        self.shadow = true;
+        self
+    }
+
+    /// False without a demand response signal.
+    pub fn shed_asserted(&mut self) -> bool {
+        self.shed.as_mut().is_some_and(ShedSignal::asserted)
+    }
+
+    pub fn ready(&mut self) {
+        if let Some(systemd) = &self.systemd {
+            if let Err(e) = systemd.ready() {
+                warn!("Failed to notify systemd of readiness. {:?}", e);
+            }
+        }
+    }
+
+    pub fn status(&mut self, status: &str) {
+        if let Some(systemd) = &self.systemd {
+            let status = match self.shadow {
+                true => format!("Shadow, {}", status),
+                false => status.to_string(),
+            };
+            if let Err(e) = systemd.status(&status) {
+                if self.warnings.should_warn(Instant::now()) {
+                    warn!("Failed to send status to systemd. {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// After every reading attempt, for `status` on the control socket, StatsD, WebSocket clients, the timeline and
+    /// the candidate.
+    pub fn publish(&mut self, now: Instant, temperature: Option<Celsius>, controller: &ControllerState) {
+        if let Some(board) = &self.board {
+            board.publish(now, temperature, controller);
+            let snapshot = match self.statsd.is_some() || self.stream.is_some() {
+                true => board.snapshot(),
+                false => None,
+            };
+            if let Some(snapshot) = snapshot {
+                if let Some(statsd) = &mut self.statsd {
+                    statsd.emit(&snapshot);
+                }
+                if let Some(stream) = &self.stream {
+                    stream.status(&snapshot);
+                }
+            }
+        }
+        if let Some(timeline) = &self.timeline {
+            timeline.observe(now, temperature, controller);
+        }
+        if let Some(candidate) = &mut self.candidate {
+            candidate.compare(now, controller, temperature);
+        }
+    }
+
+    /// After every iteration, the percentiles are only worked out for the board.
+    pub fn loop_latency(&self, timings: &LoopTimings) {
+        if let Some(board) = &self.board {
+            board.loop_latency(timings.latency());
+        }
+    }
+
+    /// Before the controller steps with `input`, the candidate steps with the same.
+    pub fn step_candidate(&mut self, input: &Input) {
+        if let Some(candidate) = &mut self.candidate {
+            candidate.step(input);
+        }
+    }
+
+    pub fn event(&self, event: &Event) {
+        if let Some(board) = &self.board {
+            board.event(event);
+        }
+        if let Some(stream) = &self.stream {
+            stream.event(event);
+        }
+        if let Some(timeline) = &self.timeline {
+            timeline.event(event);
+        }
+    }
+
+    /// After publishing, so the dump's status is the one just published.
+    pub fn dump_if_requested(&self, now: Instant, controller: &ControllerState, config: &Config) {
+        if !self.dumps.take() {
+            return;
+        }
+        let status = self.board.as_ref().and_then(StatusBoard::snapshot);
+        let dump = diagnostics::render(now, status.as_ref(), controller, config);
+        info!("Diagnostics:\n{}", dump);
+        if let Some(dir) = &self.dump_dir {
+            match diagnostics::write(dir, &dump) {
+                Ok(path) => info!("Diagnostics written to {}.", path.display()),
+                Err(e) => warn!("Failed to write diagnostics to {}. {}", dir.display(), e),
+            }
+        }
+    }
+
+    pub fn transition(&self, transition: &Transition) {
+        if let Some(stream) = &self.stream {
+            stream.transition(transition);
+        }
+        if let Some(hook) = &self.hook {
+            hook.run(transition);
+        }
+    }
+
+    /// Called on every loop iteration and sensor read attempt, pings the watchdog at half its interval.
+    pub fn tick(&mut self, now: Instant) {
+        self.progress.tick();
+        if let (Some(systemd), Some(watchdog)) = (&self.systemd, self.watchdog) {
+            if self.last_ping.is_some_and(|last| now - last < watchdog / 2) {
+                return;
+            }
+            self.last_ping = Some(now);
+            if let Err(e) = systemd.watchdog() {
+                if self.warnings.should_warn(now) {
+                    warn!("Failed to ping the systemd watchdog. {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn pings_watchdog_at_half_interval() {
+        let (ours, systemd) = UnixDatagram::pair().unwrap();
+        systemd.set_nonblocking(true).unwrap();
+        let progress = LoopProgress::default();
+        let mut supervisor =
+            Supervisor::new(progress).with_systemd(SdNotify::from_connected(ours), Some(Duration::from_secs(30)));
+        let start = Instant::now();
+        let pings = (0..10)
+            .filter(|i| {
+                supervisor.tick(start + Duration::from_secs(5 * i));
+                systemd.recv(&mut [0; 64]).is_ok()
+            })
+            .count();
+        // At 0s, 15s, 30s and 45s.
+        assert_eq!(4, pings);
+    }
+}
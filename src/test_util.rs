@@ -0,0 +1,190 @@
+use crate::{
+    status_board::Snapshot,
+    websocket::{decode, write_frame, Frame},
+};
+use picool_core::{
+    config::Config,
+    controller::ControllerState,
+    temperature::{Celsius, DegreesDelta},
+    Seed, State,
+};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by tests raising a signal, its handler is the whole process's.
+pub static SIGNALS: Mutex<()> = Mutex::new(());
+
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "picool-test-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).expect("Failed creating test directory.");
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An executable shell script named `name` in `dir`.
+pub fn script(dir: &TempDir, name: &str, body: &str) -> PathBuf {
+    let path = dir.path().join(name);
+    fs::write(&path, format!("#!/bin/sh\n{}\n", body)).expect("Failed writing test script.");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).expect("Failed making test script executable.");
+    path
+}
+
+/// A controller with the default configuration that has just started off, with nothing restored.
+pub fn controller(start: Instant) -> ControllerState {
+    ControllerState::new(Seed::new(State::Off), &Config::default(), start)
+}
+
+/// A status with a reading, as the metric sinks are tested with: the relay on, one optional gauge of each kind set
+/// and the rest left out.
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        state: "On".into(),
+        shadow: false,
+        temperature: Some(Celsius(6.5)),
+        power_on: true,
+        fan_on: false,
+        stage2: None,
+        current: None,
+        output: None,
+        thresholds: (Celsius(4.1), Celsius(7.9)),
+        band: Celsius(4.0)..Celsius(8.0),
+        boost: None,
+        rest: None,
+        shed: None,
+        quiet: None,
+        runtime_hours: 12.5,
+        starts_today: 7,
+        cycles: 3,
+        cooling_rate: Some(0.125),
+        cooling_ratio: None,
+        overshoot: None,
+        undershoot: Some(DegreesDelta(0.25)),
+        sensor_errors: 2,
+        relay_deferrals: 1,
+        loop_latency: None,
+        age: 0,
+        history: vec![],
+        events: vec![],
+    }
+}
+
+/// A WebSocket client of `/ws`, as a browser would be.
+pub struct WsClient {
+    connection: TcpStream,
+    /// Read past the last frame taken.
+    buffer: Vec<u8>,
+}
+
+impl WsClient {
+    /// Connects with RFC 6455's sample key, checking the server's answer to it.
+    pub fn connect(address: SocketAddr) -> Self {
+        let mut connection = TcpStream::connect(address).unwrap();
+        connection.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(
+            connection,
+            "GET /ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            address
+        )
+        .unwrap();
+        let mut client = Self {
+            connection,
+            buffer: vec![],
+        };
+        while !client.buffer.windows(4).any(|window| window == b"\r\n\r\n") {
+            assert!(
+                client.fill().unwrap() > 0,
+                "{}",
+                String::from_utf8_lossy(&client.buffer)
+            );
+        }
+        let end = client
+            .buffer
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        let response = String::from_utf8(client.buffer.drain(..end).collect()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+        assert!(
+            response.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{}",
+            response
+        );
+        client
+    }
+
+    pub fn read(&mut self) -> Frame {
+        self.try_read().unwrap()
+    }
+
+    pub fn try_read(&mut self) -> io::Result<Frame> {
+        loop {
+            if let Some((frame, len)) = decode(&self.buffer, false)? {
+                self.buffer.drain(..len);
+                return Ok(frame);
+            }
+            if self.fill()? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut bytes = [0; 4096];
+        let read = self.connection.read(&mut bytes)?;
+        self.buffer.extend_from_slice(&bytes[..read]);
+        Ok(read)
+    }
+
+    pub fn send(&mut self, frame: &Frame) {
+        write_frame(&mut self.connection, frame, Some([0x12, 0x34, 0x56, 0x78])).unwrap();
+    }
+
+    /// The text messages up to the server's close, which is answered, and its code.
+    pub fn messages_until_close(&mut self) -> (Vec<String>, u16) {
+        let mut messages = Vec::new();
+        loop {
+            match self.read() {
+                Frame::Text(message) => messages.push(message),
+                Frame::Close(code) => {
+                    self.send(&Frame::Close(code));
+                    return (messages, code);
+                }
+                Frame::Ping(data) => self.send(&Frame::Pong(data)),
+                _ => {}
+            }
+        }
+    }
+}
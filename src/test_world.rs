@@ -0,0 +1,747 @@
+//! A scripted World for testing the control loop, and the intended way to test new control features: readings come
+//! from a queue, time only passes when the loop sleeps, restore results are whatever the test says, and everything
+//! the loop does to the world is recorded against the virtual clock.
+//!
+//! ```ignore
+//! let world = TestWorld::builder().readings(cycling(2)).build();
+//! let recording = world.recording();
+//! run_to_end(world, seed(State::Off), &Config::default());
+//! recording.assert_transitions(&[(10, On), (310, Off), (910, On), (1210, Off)]);
+//! ```
+
+use crate::{run, shutdown::Shutdown, stall_monitor::LoopProgress, supervisor::Supervisor, World, WorldState};
+use anyhow::{anyhow, Result};
+use picool_core::{
+    boost::Boost,
+    config::Config,
+    controller::CycleRecord,
+    energy::{EnergyTotals, LocalTime},
+    failover::Output,
+    heartbeat::HeartbeatStatus,
+    notify::{Event, LogNotifier, Notifier},
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{Celsius, DegreesDelta},
+    termination::RunOutcome,
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals, RestoredPowerState, Seed,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    io,
+    ops::Range,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    rc::Rc,
+    sync::mpsc::{channel, Receiver},
+    time::{Duration, Instant},
+};
+
+/// One scripted sensor read.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Reading {
+    Temperature(f32),
+    /// A failed read with this errno, classified the way the real sensor's would be.
+    Error(i32),
+}
+
+impl From<f32> for Reading {
+    fn from(temperature: f32) -> Self {
+        Reading::Temperature(temperature)
+    }
+}
+
+/// None is an unreadable sensor.
+impl From<Option<f32>> for Reading {
+    fn from(reading: Option<f32>) -> Self {
+        match reading {
+            Some(temperature) => Reading::Temperature(temperature),
+            None => Reading::Error(libc::EIO),
+        }
+    }
+}
+
+/// What a scripted current sensor reads.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Load {
+    /// This many amps while the relay is on, none while it's off.
+    Follows(f32),
+    /// No current whatever the relay, a compressor that doesn't start.
+    Absent,
+    /// This many amps whatever the relay, a welded relay.
+    Stuck(f32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Power {
+    On,
+    Off,
+}
+
+#[derive(Default)]
+struct Record {
+    transitions: Vec<(u64, Power)>,
+    fan: Vec<(u64, Power)>,
+    stage2: Vec<(u64, Power)>,
+    pump: Vec<(u64, Power)>,
+    heartbeats: Vec<HeartbeatStatus>,
+    compensations: Vec<(DegreesDelta, DegreesDelta)>,
+    persisted_off: Vec<u64>,
+    reinitializations: Vec<u64>,
+    energy: Vec<EnergyTotals>,
+    runtime: Vec<(u64, Duration)>,
+    starts: Vec<StartCounts>,
+    boosts: Vec<(u64, Option<Boost>)>,
+    rests: Vec<(u64, Option<RestPeriod>)>,
+    sheds: Vec<(u64, Option<ShedPeriod>)>,
+    extremes: Vec<(u64, Option<CycleExtremes>)>,
+    cycles: Vec<(u64, CycleRecord)>,
+    outputs: Vec<(u64, Output)>,
+    failovers: Vec<(u64, bool)>,
+}
+
+/// What the loop did to a TestWorld, in seconds of virtual time. Stays with the test while the loop owns the world.
+#[derive(Clone, Default)]
+pub struct Recording(Rc<RefCell<Record>>);
+
+impl Recording {
+    pub fn transitions(&self) -> Vec<(u64, Power)> {
+        self.0.borrow().transitions.clone()
+    }
+
+    pub fn assert_transitions(&self, expected: &[(u64, Power)]) {
+        assert_eq!(
+            expected,
+            self.transitions().as_slice(),
+            "relay transitions (seconds, power)"
+        );
+    }
+
+    /// Fan output changes, (seconds, power).
+    pub fn fan(&self) -> Vec<(u64, Power)> {
+        self.0.borrow().fan.clone()
+    }
+
+    /// Stage 2 output changes, (seconds, power).
+    pub fn stage2(&self) -> Vec<(u64, Power)> {
+        self.0.borrow().stage2.clone()
+    }
+
+    /// Pump output changes, (seconds, power).
+    pub fn pump(&self) -> Vec<(u64, Power)> {
+        self.0.borrow().pump.clone()
+    }
+
+    pub fn heartbeats(&self) -> Vec<HeartbeatStatus> {
+        self.0.borrow().heartbeats.clone()
+    }
+
+    pub fn compensations(&self) -> Vec<(DegreesDelta, DegreesDelta)> {
+        self.0.borrow().compensations.clone()
+    }
+
+    pub fn persisted_off(&self) -> Vec<u64> {
+        self.0.borrow().persisted_off.clone()
+    }
+
+    pub fn reinitializations(&self) -> Vec<u64> {
+        self.0.borrow().reinitializations.clone()
+    }
+
+    pub fn energy(&self) -> Vec<EnergyTotals> {
+        self.0.borrow().energy.clone()
+    }
+
+    /// (when, runtime) for each persisted runtime.
+    pub fn runtime(&self) -> Vec<(u64, Duration)> {
+        self.0.borrow().runtime.clone()
+    }
+
+    pub fn starts(&self) -> Vec<StartCounts> {
+        self.0.borrow().starts.clone()
+    }
+
+    /// (when, boost) for each persisted boost, None when one ended.
+    pub fn boosts(&self) -> Vec<(u64, Option<Boost>)> {
+        self.0.borrow().boosts.clone()
+    }
+
+    /// (when, rest) for each persisted rest, None when one ended.
+    pub fn rests(&self) -> Vec<(u64, Option<RestPeriod>)> {
+        self.0.borrow().rests.clone()
+    }
+
+    /// (when, shed) for each persisted shed, None when the signal was released.
+    pub fn sheds(&self) -> Vec<(u64, Option<ShedPeriod>)> {
+        self.0.borrow().sheds.clone()
+    }
+
+    /// (when, extremes) for each checkpointed cycle, None when one ended.
+    pub fn extremes(&self) -> Vec<(u64, Option<CycleExtremes>)> {
+        self.0.borrow().extremes.clone()
+    }
+
+    /// (when, cycle) for each cycle added to the history.
+    pub fn cycles(&self) -> Vec<(u64, CycleRecord)> {
+        self.0.borrow().cycles.clone()
+    }
+
+    /// (when, output) for each output selected.
+    pub fn outputs(&self) -> Vec<(u64, Output)> {
+        self.0.borrow().outputs.clone()
+    }
+
+    /// (when, faulted) for each persisted failover.
+    pub fn failovers(&self) -> Vec<(u64, bool)> {
+        self.0.borrow().failovers.clone()
+    }
+}
+
+pub struct TestWorldBuilder {
+    readings: VecDeque<Reading>,
+    restored: Result<RestoredPowerState, String>,
+    compensation: (DegreesDelta, DegreesDelta),
+    cooling_rates: Vec<f32>,
+    energy: EnergyTotals,
+    runtime: Duration,
+    starts: StartCounts,
+    boost: Option<Boost>,
+    intervals: Option<MinimumIntervals>,
+    rest: Option<RestPeriod>,
+    shed: Option<ShedPeriod>,
+    extremes: Option<CycleExtremes>,
+    failover: bool,
+    local_start: LocalTime,
+    fail_persistence: Option<i32>,
+    unverified_until: Option<Duration>,
+    wedge: Option<Receiver<Event>>,
+    shutdown_at: Option<(Duration, Shutdown)>,
+    slow_reads: Duration,
+    slow_writes: Duration,
+    pump_fails: Option<Range<Duration>>,
+    load: Option<Load>,
+    primary_fails: bool,
+}
+
+impl TestWorldBuilder {
+    /// Appended to the script, one per read.
+    pub fn readings<R: Into<Reading>>(mut self, readings: impl IntoIterator<Item = R>) -> Self {
+        self.readings.extend(readings.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn restored(mut self, power_state: RestoredPowerState) -> Self {
+        self.restored = Ok(power_state);
+        self
+    }
+
+    pub fn restore_fails(mut self, why: &str) -> Self {
+        self.restored = Err(why.into());
+        self
+    }
+
+    pub fn restored_compensation(mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Self {
+        self.compensation = (cooling, heating);
+        self
+    }
+
+    pub fn restored_cooling_rates(mut self, rates: Vec<f32>) -> Self {
+        self.cooling_rates = rates;
+        self
+    }
+
+    pub fn restored_energy(mut self, totals: EnergyTotals) -> Self {
+        self.energy = totals;
+        self
+    }
+
+    pub fn restored_runtime(mut self, runtime: Duration) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn restored_starts(mut self, counts: StartCounts) -> Self {
+        self.starts = counts;
+        self
+    }
+
+    /// A boost with this much left when the process stopped.
+    pub fn restored_boost(mut self, boost: Boost) -> Self {
+        self.boost = Some(boost);
+        self
+    }
+
+    /// Intervals the timing advisor applied in the previous run.
+    pub fn restored_intervals(mut self, intervals: MinimumIntervals) -> Self {
+        self.intervals = Some(intervals);
+        self
+    }
+
+    /// A rest with this much left when the process stopped.
+    pub fn restored_rest(mut self, rest: RestPeriod) -> Self {
+        self.rest = Some(rest);
+        self
+    }
+
+    /// A load shed as it stood when the process stopped.
+    pub fn restored_shed(mut self, shed: ShedPeriod) -> Self {
+        self.shed = Some(shed);
+        self
+    }
+
+    /// The extremes of the cycle in progress when the process stopped.
+    pub fn restored_extremes(mut self, extremes: CycleExtremes) -> Self {
+        self.extremes = Some(extremes);
+        self
+    }
+
+    /// A previous run failed over from the primary output and it wasn't restored.
+    pub fn restored_failover(mut self) -> Self {
+        self.failover = true;
+        self
+    }
+
+    /// The wall clock at the start, it advances with the virtual clock.
+    pub fn starting_at(mut self, local: LocalTime) -> Self {
+        self.local_start = local;
+        self
+    }
+
+    /// Every write fails with this errno.
+    pub fn fail_persistence(mut self, errno: i32) -> Self {
+        self.fail_persistence = Some(errno);
+        self
+    }
+
+    /// Every write reads back wrong until this time, like a card that acknowledges writes it doesn't keep.
+    pub fn unverified_until(mut self, until: Duration) -> Self {
+        self.unverified_until = Some(until);
+        self
+    }
+
+    /// Block at the end of the script until an event arrives, like a sensor read that never returns.
+    pub fn wedge_until(mut self, events: Receiver<Event>) -> Self {
+        self.wedge = Some(events);
+        self
+    }
+
+    /// Request shutdown once a sleep reaches this time, like a signal arriving mid-sleep.
+    pub fn shutdown_at(mut self, at: Duration, shutdown: Shutdown) -> Self {
+        self.shutdown_at = Some((at, shutdown));
+        self
+    }
+
+    /// Every sensor read takes this long on the virtual clock.
+    pub fn slow_reads(mut self, took: Duration) -> Self {
+        self.slow_reads = took;
+        self
+    }
+
+    /// Every write, the heartbeat included, takes this long on the virtual clock, like a stalling SD card.
+    pub fn slow_writes(mut self, took: Duration) -> Self {
+        self.slow_writes = took;
+        self
+    }
+
+    /// The pump output reads back off over this span whatever it was switched to, like a contactor that dropped out.
+    pub fn pump_fails(mut self, during: Range<Duration>) -> Self {
+        self.pump_fails = Some(during);
+        self
+    }
+
+    /// A current sensor on the compressor, without one there is no current to read.
+    pub fn load(mut self, load: Load) -> Self {
+        self.load = Some(load);
+        self
+    }
+
+    /// The primary output never switches the compressor, only the backup draws the load.
+    pub fn primary_fails(mut self) -> Self {
+        self.primary_fails = true;
+        self
+    }
+
+    pub fn build(self) -> TestWorld {
+        let start = Instant::now();
+        TestWorld {
+            readings: RefCell::new(self.readings),
+            restored: self.restored,
+            compensation: self.compensation,
+            cooling_rates: self.cooling_rates,
+            energy: self.energy,
+            runtime: self.runtime,
+            starts: self.starts,
+            boost: self.boost,
+            intervals: self.intervals,
+            rest: self.rest,
+            shed: self.shed,
+            extremes: self.extremes,
+            failover: self.failover,
+            local_start: self.local_start,
+            fail_persistence: self.fail_persistence,
+            unverified_until: self.unverified_until,
+            wedge: self.wedge,
+            shutdown_at: self.shutdown_at,
+            slow_reads: self.slow_reads,
+            slow_writes: self.slow_writes,
+            pump: false,
+            pump_fails: self.pump_fails,
+            load: self.load,
+            primary_fails: self.primary_fails,
+            output: Output::Primary,
+            power: false,
+            start,
+            now: Cell::new(start),
+            recording: Recording::default(),
+        }
+    }
+}
+
+/// Panics with "End of script." when the readings run out, see [`run_to_end`].
+pub struct TestWorld {
+    readings: RefCell<VecDeque<Reading>>,
+    restored: Result<RestoredPowerState, String>,
+    compensation: (DegreesDelta, DegreesDelta),
+    cooling_rates: Vec<f32>,
+    energy: EnergyTotals,
+    runtime: Duration,
+    starts: StartCounts,
+    boost: Option<Boost>,
+    intervals: Option<MinimumIntervals>,
+    rest: Option<RestPeriod>,
+    shed: Option<ShedPeriod>,
+    extremes: Option<CycleExtremes>,
+    failover: bool,
+    local_start: LocalTime,
+    fail_persistence: Option<i32>,
+    unverified_until: Option<Duration>,
+    wedge: Option<Receiver<Event>>,
+    shutdown_at: Option<(Duration, Shutdown)>,
+    slow_reads: Duration,
+    slow_writes: Duration,
+    pump: bool,
+    pump_fails: Option<Range<Duration>>,
+    load: Option<Load>,
+    primary_fails: bool,
+    output: Output,
+    power: bool,
+    start: Instant,
+    now: Cell<Instant>,
+    recording: Recording,
+}
+
+impl TestWorld {
+    /// No readings, restores off for an unknown duration with no compensation or counters, starting at the epoch.
+    pub fn builder() -> TestWorldBuilder {
+        TestWorldBuilder {
+            readings: VecDeque::new(),
+            restored: Ok(RestoredPowerState::OffForUnknownDuration),
+            compensation: (DegreesDelta::ZERO, DegreesDelta::ZERO),
+            cooling_rates: vec![],
+            energy: EnergyTotals::default(),
+            runtime: Duration::from_secs(0),
+            starts: StartCounts::default(),
+            boost: None,
+            intervals: None,
+            rest: None,
+            shed: None,
+            extremes: None,
+            failover: false,
+            local_start: LocalTime::default(),
+            fail_persistence: None,
+            unverified_until: None,
+            wedge: None,
+            shutdown_at: None,
+            slow_reads: Duration::from_secs(0),
+            slow_writes: Duration::from_secs(0),
+            pump_fails: None,
+            load: None,
+            primary_fails: false,
+        }
+    }
+
+    pub fn recording(&self) -> Recording {
+        self.recording.clone()
+    }
+
+    fn elapsed(&self) -> u64 {
+        (self.now.get() - self.start).as_secs()
+    }
+
+    fn persisted(&self) -> Result<(), PersistError> {
+        self.now.set(self.now.get() + self.slow_writes);
+        if self
+            .unverified_until
+            .is_some_and(|until| self.now.get() - self.start < until)
+        {
+            return Err(PersistError::unverified("state"));
+        }
+        match self.fail_persistence {
+            Some(errno) => Err(io::Error::from_raw_os_error(errno).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl World for TestWorld {
+    fn get_temperature(&self) -> Result<Celsius, SensorError> {
+        self.now.set(self.now.get() + self.slow_reads);
+        match self.readings.borrow_mut().pop_front() {
+            Some(Reading::Temperature(t)) => Ok(Celsius(t)),
+            Some(Reading::Error(errno)) => Err(SensorError::from_io(
+                PathBuf::from("/scripted"),
+                io::Error::from_raw_os_error(errno),
+            )),
+            None => match &self.wedge {
+                Some(events) => panic!("Wedged until {:?}", events.recv_timeout(Duration::from_secs(5))),
+                None => panic!("End of script."),
+            },
+        }
+    }
+
+    /// Scripts only cover the chamber.
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+        None
+    }
+
+    fn get_current(&self) -> Option<Result<f32, SensorError>> {
+        Some(Ok(match self.load? {
+            Load::Follows(_) if self.primary_fails && self.output == Output::Primary => 0.0,
+            Load::Follows(amps) if self.power => amps,
+            Load::Follows(_) | Load::Absent => 0.0,
+            Load::Stuck(amps) => amps,
+        }))
+    }
+
+    fn set_power_state(&mut self, state: bool) {
+        let power = match state {
+            true => Power::On,
+            false => Power::Off,
+        };
+        let at = self.elapsed();
+        self.power = state;
+        self.recording.0.borrow_mut().transitions.push((at, power));
+    }
+
+    fn set_fan_state(&mut self, state: bool) {
+        let power = match state {
+            true => Power::On,
+            false => Power::Off,
+        };
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().fan.push((at, power));
+    }
+
+    fn set_stage2_state(&mut self, state: bool) {
+        let power = match state {
+            true => Power::On,
+            false => Power::Off,
+        };
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().stage2.push((at, power));
+    }
+
+    fn set_pump_state(&mut self, state: bool) {
+        let power = match state {
+            true => Power::On,
+            false => Power::Off,
+        };
+        let at = self.elapsed();
+        self.pump = state;
+        self.recording.0.borrow_mut().pump.push((at, power));
+    }
+
+    fn pump_state(&self) -> Option<bool> {
+        let elapsed = self.now.get() - self.start;
+        match &self.pump_fails {
+            Some(during) if during.contains(&elapsed) => Some(false),
+            _ => Some(self.pump),
+        }
+    }
+
+    fn select_output(&mut self, output: Output) {
+        let at = self.elapsed();
+        self.output = output;
+        self.recording.0.borrow_mut().outputs.push((at, output));
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+        if let Some((at, shutdown)) = &self.shutdown_at {
+            if self.now.get() - self.start >= *at {
+                shutdown.request();
+            }
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+
+    fn local_time(&self) -> LocalTime {
+        self.local_start.plus(self.now.get() - self.start)
+    }
+
+    fn reinitialize(&mut self) -> Result<(), SensorError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().reinitializations.push(at);
+        Ok(())
+    }
+
+    fn reacquire_sensor(&mut self) -> bool {
+        false
+    }
+
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+        self.recording.0.borrow_mut().heartbeats.push(status);
+        self.now.set(self.now.get() + self.slow_writes);
+        Ok(())
+    }
+
+    fn restore_state(&self) -> Result<WorldState> {
+        let power_state = self.restored.clone().map_err(|why| anyhow!(why))?;
+        Ok(WorldState {
+            power_state,
+            cooling_compensation: self.compensation.0,
+            heating_compensation: self.compensation.1,
+            cooling_rates: self.cooling_rates.clone(),
+            energy: self.energy,
+            runtime: self.runtime,
+            starts: self.starts.clone(),
+            boost: self.boost.clone(),
+            intervals: self.intervals,
+            overshoots: Overshoots::default(),
+            rest: self.rest,
+            shed: self.shed,
+            extremes: self.extremes,
+            failover: self.failover,
+        })
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().persisted_off.push(at);
+        self.persisted()
+    }
+
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+        self.recording.0.borrow_mut().compensations.push((cooling, heating));
+        self.persisted()
+    }
+
+    fn persist_cooling_rates(&mut self, _rates: &[f32]) -> Result<(), PersistError> {
+        self.persisted()
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        self.recording.0.borrow_mut().energy.push(totals);
+        self.persisted()
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().runtime.push((at, runtime));
+        self.persisted()
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        self.recording.0.borrow_mut().starts.push(counts.clone());
+        self.persisted()
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().boosts.push((at, boost.cloned()));
+        self.persisted()
+    }
+
+    fn persist_intervals(&mut self, _intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        self.persisted()
+    }
+
+    fn persist_overshoots(&mut self, _overshoots: &Overshoots) -> Result<(), PersistError> {
+        self.persisted()
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().rests.push((at, rest.copied()));
+        self.persisted()
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().sheds.push((at, shed.copied()));
+        self.persisted()
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().extremes.push((at, extremes.copied()));
+        self.persisted()
+    }
+
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().cycles.push((at, cycle.clone()));
+        self.persisted()
+    }
+
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+        let at = self.elapsed();
+        self.recording.0.borrow_mut().failovers.push((at, faulted));
+        self.persisted()
+    }
+}
+
+pub struct RecordingNotifier(pub Rc<RefCell<Vec<Event>>>);
+
+impl Notifier for RecordingNotifier {
+    fn notify(&mut self, event: &Event) {
+        self.0.borrow_mut().push(event.clone());
+    }
+}
+
+/// Runs the loop until the loop returns.
+pub fn run_to_outcome(world: TestWorld, seed: Seed, config: &Config, shutdown: &Shutdown) -> RunOutcome {
+    let (_, commands) = channel();
+    run(
+        seed,
+        config,
+        world,
+        LogNotifier,
+        commands,
+        Supervisor::new(LoopProgress::default()),
+        shutdown,
+    )
+}
+
+/// Runs the loop until the script runs out, the notifications it sent.
+pub fn run_to_end(world: impl World, seed: Seed, config: &Config) -> Vec<Event> {
+    run_to_end_with(world, seed, config, Supervisor::new(LoopProgress::default()))
+}
+
+/// As `run_to_end`, with what the supervisor brings along.
+pub fn run_to_end_with(world: impl World, seed: Seed, config: &Config, supervisor: Supervisor) -> Vec<Event> {
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let notifier = RecordingNotifier(events.clone());
+    let outcome = catch_unwind(AssertUnwindSafe(|| {
+        let (_, commands) = channel();
+        run(
+            seed,
+            config,
+            world,
+            notifier,
+            commands,
+            supervisor,
+            &Shutdown::default(),
+        )
+    }));
+    let message = outcome.expect_err("The loop only ends when the script does.");
+    assert_eq!(Some(&"End of script."), message.downcast_ref::<&str>());
+    let events = events.borrow().clone();
+    events
+}
@@ -0,0 +1,256 @@
+use anyhow::{bail, Result};
+use picool_core::temperature::{Celsius, DegreesDelta, Rate};
+use std::{f32::consts::PI, fmt, str::FromStr, time::Duration};
+
+const SECONDS_PER_HOUR: f32 = 3600.0;
+const SECONDS_PER_DAY: f32 = 86400.0;
+const SUB_STEP: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ThermalModel {
+    /// Constant warming and cooling rates.
+    Linear,
+    /// Warming slows as the chamber approaches ambient.
+    Rc,
+}
+
+impl FromStr for ThermalModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "linear" => Ok(ThermalModel::Linear),
+            "rc" => Ok(ThermalModel::Rc),
+            _ => bail!("Unknown thermal model '{}', expected linear or rc.", s),
+        }
+    }
+}
+
+/// The air around the chamber over simulated time, a daily sinusoidal swing around the mean plus a slow drift.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ambient {
+    pub mean: Celsius,
+    /// Degrees either side of the mean.
+    pub swing: f32,
+    pub period: Duration,
+    /// Degrees per simulated day, seasons under time warp.
+    pub drift: f32,
+}
+
+impl Default for Ambient {
+    fn default() -> Self {
+        Self {
+            mean: Celsius(20.0),
+            swing: 0.0,
+            period: Duration::from_secs(60 * 60 * 24),
+            drift: 0.0,
+        }
+    }
+}
+
+impl Ambient {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.mean.is_finite() && self.swing.is_finite() && self.drift.is_finite()) {
+            bail!("Ambient mean, swing and drift must be finite.");
+        }
+        if self.period == Duration::from_secs(0) {
+            bail!("Ambient period must be positive.");
+        }
+        Ok(())
+    }
+
+    // Pure
+    /// Starts at the mean on the rising half of the swing.
+    pub fn at(&self, elapsed: Duration) -> Celsius {
+        let t = elapsed.as_secs_f32();
+        let cycle = 2.0 * PI * t / self.period.as_secs_f32();
+        Celsius(self.mean.0 + self.swing * cycle.sin() + self.drift * t / SECONDS_PER_DAY)
+    }
+}
+
+impl fmt::Display for Ambient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ambient {}", self.mean)?;
+        if self.swing != 0.0 {
+            write!(
+                f,
+                " swinging {} over {}m",
+                DegreesDelta(self.swing),
+                self.period.as_secs() / 60
+            )?;
+        }
+        if self.drift != 0.0 {
+            write!(f, " drifting {}", Rate::new(self.drift, "/day"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A lumped-capacitance chamber, dT/dt = (ambient - T) / time_constant - capacity while the compressor runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RcModel {
+    pub ambient: Ambient,
+    pub time_constant: Duration,
+    /// Degrees per hour the compressor removes.
+    pub capacity: f32,
+}
+
+impl Default for RcModel {
+    fn default() -> Self {
+        // Close to the linear defaults around the target range.
+        Self {
+            ambient: Ambient::default(),
+            time_constant: Duration::from_secs(60 * 100),
+            capacity: 17.0,
+        }
+    }
+}
+
+impl RcModel {
+    pub fn validate(&self) -> Result<()> {
+        self.ambient.validate()?;
+        if self.time_constant < SUB_STEP {
+            bail!("Time constant must be at least {}s.", SUB_STEP.as_secs());
+        }
+        if !(self.capacity > 0.0 && self.capacity.is_finite()) {
+            bail!("Compressor capacity must be positive, got {}.", self.capacity);
+        }
+        Ok(())
+    }
+
+    // Pure
+    /// Degrees per second.
+    fn rate(&self, temperature: f32, cooling: bool, elapsed: Duration) -> f32 {
+        let passive = (self.ambient.at(elapsed).0 - temperature) / self.time_constant.as_secs_f32();
+        match cooling {
+            true => passive - self.capacity / SECONDS_PER_HOUR,
+            false => passive,
+        }
+    }
+
+    // Pure
+    /// Integrates the model over the duration in sub-steps, starting this far into the simulation.
+    pub fn advance(&self, temperature: Celsius, cooling: bool, elapsed: Duration, duration: Duration) -> Celsius {
+        let mut temperature = temperature.0;
+        let mut elapsed = elapsed;
+        let end = elapsed + duration;
+        while elapsed < end {
+            let step = (end - elapsed).min(SUB_STEP);
+            let dt = step.as_secs_f32();
+            // Midpoint, the first-order error of plain Euler adds up over a long sleep.
+            let midpoint = temperature + self.rate(temperature, cooling, elapsed) * dt / 2.0;
+            temperature += self.rate(midpoint, cooling, elapsed + step / 2) * dt;
+            elapsed += step;
+        }
+        Celsius(temperature)
+    }
+}
+
+impl fmt::Display for RcModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, time constant {}m, capacity {}",
+            self.ambient,
+            self.time_constant.as_secs() / 60,
+            Rate::new(self.capacity, "/h")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analytic(model: &RcModel, start: f32, cooling: bool, elapsed: Duration) -> f32 {
+        let tau = model.time_constant.as_secs_f32();
+        let equilibrium = match cooling {
+            true => model.ambient.mean.0 - model.capacity / SECONDS_PER_HOUR * tau,
+            false => model.ambient.mean.0,
+        };
+        equilibrium + (start - equilibrium) * (-elapsed.as_secs_f32() / tau).exp()
+    }
+
+    #[test]
+    fn matches_analytic_solution() {
+        let model = RcModel::default();
+        for &cooling in &[false, true] {
+            let mut temperature = Celsius(4.0);
+            for minutes in 1..=120 {
+                let elapsed = Duration::from_secs(60 * (minutes - 1));
+                temperature = model.advance(temperature, cooling, elapsed, Duration::from_secs(60));
+                let expected = analytic(&model, 4.0, cooling, Duration::from_secs(60 * minutes));
+                assert!(
+                    (temperature.0 - expected).abs() < 0.001,
+                    "{}m cooling {}: {} vs {}",
+                    minutes,
+                    cooling,
+                    temperature.0,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn warming_slows_toward_ambient() {
+        let model = RcModel::default();
+        let (start, hour) = (Duration::from_secs(0), Duration::from_secs(60 * 60));
+        let cold = model.advance(Celsius(4.0), false, start, hour) - Celsius(4.0);
+        let warm = model.advance(Celsius(15.0), false, start, hour) - Celsius(15.0);
+        assert!(warm.0 < cold.0 / 2.0);
+        assert_eq!(Celsius(20.0), model.advance(Celsius(20.0), false, start, hour));
+    }
+
+    #[test]
+    fn ambient_swings_and_drifts() {
+        let ambient = Ambient {
+            mean: Celsius(20.0),
+            swing: 5.0,
+            period: Duration::from_secs(60 * 60 * 24),
+            drift: 1.0,
+        };
+        let hours = |h: u64| Duration::from_secs(60 * 60 * h);
+        assert_eq!(Celsius(20.0), ambient.at(hours(0)));
+        assert!((ambient.at(hours(6)).0 - 25.25).abs() < 0.001);
+        assert!((ambient.at(hours(18)).0 - 15.75).abs() < 0.001);
+        assert!((ambient.at(hours(24 * 30)).0 - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn warmer_afternoons_warm_the_chamber_faster() {
+        let steady = RcModel::default();
+        let diurnal = RcModel {
+            ambient: Ambient {
+                swing: 5.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (start, hours) = (Duration::from_secs(0), Duration::from_secs(60 * 60 * 6));
+        assert!(diurnal.advance(Celsius(4.0), false, start, hours) > steady.advance(Celsius(4.0), false, start, hours));
+        let night = Duration::from_secs(60 * 60 * 12);
+        assert!(diurnal.advance(Celsius(4.0), false, night, hours) < steady.advance(Celsius(4.0), false, night, hours));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        assert!(RcModel::default().validate().is_ok());
+        let heater = RcModel {
+            capacity: -1.0,
+            ..Default::default()
+        };
+        assert!(heater.validate().is_err());
+        let instant = RcModel {
+            time_constant: Duration::from_secs(0),
+            ..Default::default()
+        };
+        assert!(instant.validate().is_err());
+        let still = Ambient {
+            period: Duration::from_secs(0),
+            ..Default::default()
+        };
+        assert!(still.validate().is_err());
+        assert!("newton".parse::<ThermalModel>().is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! `--export-timeline`, what a simulation or replay did as one file: a Graphviz state graph with dwell times and
+//! transition counts, or a self-contained HTML chart of the temperature with the relay and thresholds over it.
+
+use anyhow::{bail, Context, Result};
+use picool_core::{controller::ControllerState, notify::Event, temperature::Celsius, State};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const CHART_WIDTH: f32 = 1000.0;
+const CHART_HEIGHT: f32 = 300.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Dot,
+    Html,
+}
+
+impl Format {
+    /// From the extension of the export path.
+    pub fn of(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => extension.parse(),
+            None => bail!("Timeline {} needs a .dot or .html extension.", path.display()),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dot" | "gv" => Ok(Format::Dot),
+            "html" | "htm" => Ok(Format::Html),
+            _ => bail!("Unknown timeline format '{}', expected dot or html.", s),
+        }
+    }
+}
+
+/// One decision, as the golden traces record them, plus every reading for the chart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Reading(Option<Celsius>),
+    /// The state entered, by name.
+    State(String),
+    Thresholds(Celsius, Celsius),
+    Power(bool),
+    Notify(String),
+}
+
+/// The records of a run, with seconds since it started. Shared with the control loop, which records into it.
+#[derive(Clone, Default)]
+pub struct Timeline {
+    inner: Arc<Mutex<Recorded>>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    start: Option<Instant>,
+    records: Vec<(u64, Record)>,
+    /// Notifications wait for the time of the reading that caused them.
+    pending: Vec<String>,
+    last: Option<(State, (Celsius, Celsius), bool)>,
+}
+
+impl Timeline {
+    /// Called for every reading attempt, after its effects are carried out.
+    pub fn observe(&self, now: Instant, temperature: Option<Celsius>, controller: &ControllerState) {
+        let mut recorded = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let t = now
+            .saturating_duration_since(*recorded.start.get_or_insert(now))
+            .as_secs();
+        let (state, thresholds, power) = (controller.state(), controller.thresholds(), controller.power_on());
+        let last = recorded.last.replace((state, thresholds, power));
+        recorded.records.push((t, Record::Reading(temperature)));
+        if last.map(|last| last.0) != Some(state) {
+            recorded.records.push((t, Record::State(state.to_string())));
+        }
+        if last.map(|last| last.1) != Some(thresholds) {
+            recorded
+                .records
+                .push((t, Record::Thresholds(thresholds.0, thresholds.1)));
+        }
+        if last.map(|last| last.2) != Some(power) {
+            recorded.records.push((t, Record::Power(power)));
+        }
+        let pending = std::mem::take(&mut recorded.pending);
+        recorded
+            .records
+            .extend(pending.into_iter().map(|message| (t, Record::Notify(message))));
+    }
+
+    pub fn event(&self, event: &Event) {
+        let mut recorded = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        recorded.pending.push(event.to_string());
+    }
+
+    pub fn records(&self) -> Vec<(u64, Record)> {
+        let recorded = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        recorded.records.clone()
+    }
+
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let records = self.records();
+        let rendered = match Format::of(path)? {
+            Format::Dot => dot(&records),
+            Format::Html => html(&records),
+        };
+        fs::write(path, rendered).with_context(|| format!("Failed writing timeline {}.", path.display()))
+    }
+}
+
+/// Per state, (entries, total time in it). Per transition, its count.
+type Dwell = (BTreeMap<String, (u32, u64)>, BTreeMap<(String, String), u32>);
+
+// Pure
+fn dwell(records: &[(u64, Record)]) -> Dwell {
+    let end = records.last().map_or(0, |&(t, _)| t);
+    let mut states: BTreeMap<String, (u32, u64)> = BTreeMap::new();
+    let mut transitions = BTreeMap::new();
+    let mut current: Option<(String, u64)> = None;
+    for (t, record) in records {
+        if let Record::State(state) = record {
+            if let Some((previous, since)) = current.take() {
+                states.entry(previous.clone()).or_default().1 += t - since;
+                *transitions.entry((previous, state.clone())).or_default() += 1;
+            }
+            states.entry(state.clone()).or_default().0 += 1;
+            current = Some((state.clone(), *t));
+        }
+    }
+    if let Some((state, since)) = current {
+        states.entry(state).or_default().1 += end - since;
+    }
+    (states, transitions)
+}
+
+// Pure
+/// A node per state annotated with its entries and dwell time, an edge per transition with its count.
+pub fn dot(records: &[(u64, Record)]) -> String {
+    let (states, transitions) = dwell(records);
+    let mut dot = String::from("digraph picool {\n    rankdir=LR;\n    node [shape=box];\n");
+    for (state, (entries, seconds)) in &states {
+        writeln!(
+            dot,
+            "    \"{}\" [label=\"{}\\n{} entries, {} total\"];",
+            state,
+            state,
+            entries,
+            span(*seconds)
+        )
+        .unwrap();
+    }
+    for ((from, to), count) in &transitions {
+        writeln!(dot, "    \"{}\" -> \"{}\" [label=\"{}\"];", from, to, count).unwrap();
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// `1h05m`, `12m30s` or `40s`.
+fn span(seconds: u64) -> String {
+    let duration = Duration::from_secs(seconds);
+    match (
+        duration.as_secs() / 3600,
+        duration.as_secs() / 60 % 60,
+        duration.as_secs() % 60,
+    ) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
+
+// Pure
+/// One page, no scripts or external resources: an SVG chart of the readings with the relay's on periods as bands
+/// and the switching thresholds as dashed lines, then the notifications.
+pub fn html(records: &[(u64, Record)]) -> String {
+    let end = records.last().map_or(0, |&(t, _)| t).max(1);
+    let readings: Vec<(u64, Celsius)> = records
+        .iter()
+        .filter_map(|(t, record)| match record {
+            Record::Reading(Some(temperature)) => Some((*t, *temperature)),
+            _ => None,
+        })
+        .collect();
+    let thresholds: Vec<(u64, Celsius, Celsius)> = records
+        .iter()
+        .filter_map(|(t, record)| match record {
+            Record::Thresholds(low, high) => Some((*t, *low, *high)),
+            _ => None,
+        })
+        .collect();
+    let shown = readings
+        .iter()
+        .map(|&(_, temperature)| temperature.0)
+        .chain(thresholds.iter().flat_map(|&(_, low, high)| vec![low.0, high.0]));
+    let (low, high) = shown.fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), value| {
+        (low.min(value), high.max(value))
+    });
+    let (low, high) = match low <= high {
+        true => (low - 0.5, high + 0.5),
+        false => (0.0, 1.0),
+    };
+    let x = |t: u64| t as f32 / end as f32 * CHART_WIDTH;
+    let y = |temperature: Celsius| (high - temperature.0) / (high - low) * CHART_HEIGHT;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {} {}\" width=\"100%\">\n",
+        CHART_WIDTH, CHART_HEIGHT
+    );
+    let mut on_since = None;
+    let powers = records.iter().filter_map(|(t, record)| match record {
+        Record::Power(on) => Some((*t, *on)),
+        _ => None,
+    });
+    for (t, on) in powers.chain(std::iter::once((end, false))) {
+        match (on, on_since) {
+            (true, None) => on_since = Some(t),
+            (false, Some(since)) => {
+                writeln!(
+                    svg,
+                    "<rect class=\"relay\" x=\"{:.1}\" y=\"0\" width=\"{:.1}\" height=\"{}\" fill=\"#9cf\" \
+                     fill-opacity=\"0.4\"/>",
+                    x(since),
+                    x(t) - x(since),
+                    CHART_HEIGHT
+                )
+                .unwrap();
+                on_since = None;
+            }
+            _ => {}
+        }
+    }
+    for (edge, name) in [(0, "low"), (1, "high")] {
+        let mut points = String::new();
+        for (i, &(t, low, high)) in thresholds.iter().enumerate() {
+            let value = if edge == 0 { low } else { high };
+            let until = thresholds.get(i + 1).map_or(end, |next| next.0);
+            write!(points, "{:.1},{:.1} {:.1},{:.1} ", x(t), y(value), x(until), y(value)).unwrap();
+        }
+        writeln!(
+            svg,
+            "<polyline class=\"threshold {}\" points=\"{}\" fill=\"none\" stroke=\"#c33\" stroke-dasharray=\"6 4\"/>",
+            name,
+            points.trim_end()
+        )
+        .unwrap();
+    }
+    // Failed reads break the line.
+    let mut segment = String::new();
+    let mut segments = vec![];
+    for (t, record) in records {
+        match record {
+            Record::Reading(Some(temperature)) => {
+                write!(segment, "{:.1},{:.1} ", x(*t), y(*temperature)).unwrap();
+            }
+            Record::Reading(None) if !segment.is_empty() => segments.push(std::mem::take(&mut segment)),
+            _ => {}
+        }
+    }
+    if !segment.is_empty() {
+        segments.push(segment);
+    }
+    for segment in segments {
+        writeln!(
+            svg,
+            "<polyline class=\"temperature\" points=\"{}\" fill=\"none\" stroke=\"#333\"/>",
+            segment.trim_end()
+        )
+        .unwrap();
+    }
+    svg.push_str("</svg>\n");
+
+    let mut events = String::new();
+    for (t, record) in records {
+        if let Record::Notify(message) = record {
+            writeln!(events, "<li>{} {}</li>", span(*t), escape(message)).unwrap();
+        }
+    }
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>picool timeline</title>\n</head>\n<body>\n\
+         <h1>picool timeline, {}</h1>\n<p>{} readings. Shaded while the relay is on, dashed at the switching \
+         thresholds.</p>\n{}<h2>Notifications</h2>\n<ul>\n{}</ul>\n</body>\n</html>\n",
+        span(end),
+        readings.len(),
+        svg,
+        events
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{controller, TempDir};
+
+    /// Two cooling cycles with a failed read in the first.
+    fn records() -> Vec<(u64, Record)> {
+        let reading = |temperature: f32| Record::Reading(Some(Celsius(temperature)));
+        vec![
+            (0, reading(8.2)),
+            (0, Record::State("Off".into())),
+            (0, Record::Thresholds(Celsius(4.0), Celsius(8.0))),
+            (0, Record::Power(false)),
+            (100, reading(8.1)),
+            (100, Record::State("On".into())),
+            (100, Record::Power(true)),
+            (250, Record::Reading(None)),
+            (400, reading(3.9)),
+            (400, Record::State("Off".into())),
+            (400, Record::Power(false)),
+            (400, Record::Thresholds(Celsius(4.1), Celsius(7.9))),
+            (700, reading(8.0)),
+            (700, Record::State("On".into())),
+            (700, Record::Power(true)),
+            (700, Record::Notify("Cooling <degraded> & slow".into())),
+            (1000, reading(4.0)),
+            (1000, Record::State("Off".into())),
+            (1000, Record::Power(false)),
+            (1200, reading(6.0)),
+        ]
+    }
+
+    #[test]
+    fn state_graph_counts_dwell_and_transitions() {
+        let dot = dot(&records());
+        assert!(dot.starts_with("digraph picool {"));
+        assert_eq!(2, dot.lines().filter(|line| line.contains(" entries, ")).count());
+        assert!(
+            dot.contains("\"Off\" [label=\"Off\\n3 entries, 10m00s total\"];"),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains("\"On\" [label=\"On\\n2 entries, 10m00s total\"];"),
+            "{}",
+            dot
+        );
+        assert_eq!(2, dot.matches(" -> ").count());
+        assert!(dot.contains("\"Off\" -> \"On\" [label=\"2\"];"));
+        assert!(dot.contains("\"On\" -> \"Off\" [label=\"2\"];"));
+        assert_eq!(
+            "digraph picool {\n    rankdir=LR;\n    node [shape=box];\n}\n",
+            super::dot(&[])
+        );
+    }
+
+    #[test]
+    fn chart_has_relay_bands_thresholds_and_notifications() {
+        let html = html(&records());
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(!html.contains("<script") && !html.contains("src=") && !html.contains("href="));
+        assert_eq!(2, html.matches("<rect class=\"relay\"").count());
+        // The failed read splits the temperature line in two.
+        assert_eq!(2, html.matches("<polyline class=\"temperature\"").count());
+        assert_eq!(1, html.matches("class=\"threshold low\"").count());
+        assert_eq!(1, html.matches("class=\"threshold high\"").count());
+        assert!(
+            html.contains("<li>11m40s Cooling &lt;degraded&gt; &amp; slow</li>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("picool timeline, 20m00s"));
+        assert!(html.contains("6 readings."));
+    }
+
+    #[test]
+    fn records_changes_as_the_controller_makes_them() {
+        let start = Instant::now();
+        let controller = controller(start);
+        let timeline = Timeline::default();
+        timeline.observe(start + Duration::from_secs(5), Some(Celsius(6.0)), &controller);
+        timeline.event(&Event::RuntimeReset {
+            previous: Duration::from_secs(60),
+        });
+        timeline.observe(start + Duration::from_secs(15), None, &controller);
+        let (low, high) = controller.thresholds();
+        assert_eq!(
+            vec![
+                (0, Record::Reading(Some(Celsius(6.0)))),
+                (0, Record::State("Off".into())),
+                (0, Record::Thresholds(low, high)),
+                (0, Record::Power(false)),
+                (10, Record::Reading(None)),
+                (
+                    10,
+                    Record::Notify(
+                        Event::RuntimeReset {
+                            previous: Duration::from_secs(60)
+                        }
+                        .to_string()
+                    )
+                ),
+            ],
+            timeline.records()
+        );
+    }
+
+    #[test]
+    fn exports_by_extension() {
+        let dir = TempDir::new();
+        let timeline = Timeline::default();
+        timeline.export(&dir.path().join("run.dot")).unwrap();
+        timeline.export(&dir.path().join("run.html")).unwrap();
+        assert!(fs::read_to_string(dir.path().join("run.dot"))
+            .unwrap()
+            .starts_with("digraph"));
+        assert!(fs::read_to_string(dir.path().join("run.html"))
+            .unwrap()
+            .contains("0 readings"));
+        assert!(timeline.export(&dir.path().join("run.png")).is_err());
+        assert!(timeline.export(&dir.path().join("run")).is_err());
+    }
+}
@@ -0,0 +1,613 @@
+//! A DS18B20 on the 1-Wire bus, read through sysfs. A knocked probe cable makes the device directory vanish, so a
+//! sensor that keeps reading as missing is looked for again in the bus's device listing.
+
+use crate::startup_error::StartupError;
+use anyhow::{bail, Result};
+use log::*;
+use picool_core::{
+    temperature::{Celsius, Millidegrees},
+    world_error::SensorError,
+};
+use std::{
+    cell::Cell,
+    ffi::OsString,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// The family code of a DS18B20, its device directories are `28-<serial>`.
+const DS18B20_PREFIX: &str = "28-";
+/// Consecutive missing reads between looks at the device listing, a minute of sensor retries.
+const DISCOVERY_EVERY: u32 = 6;
+/// Written to a bus master's bulk read file, the w1 driver starts a conversion on every sensor on the bus.
+const BULK_READ_FILE: &str = "therm_bulk_read";
+/// Reads up to this many conversions long are the bus and the driver's retries, not a slow sensor.
+const READ_SLACK: u32 = 2;
+
+/// How a value file writes the temperature. The w1 driver writes millidegrees, other files may write either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorFormat {
+    Millidegrees,
+    Degrees,
+}
+
+impl FromStr for SensorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "millidegrees" => Ok(SensorFormat::Millidegrees),
+            "degrees" => Ok(SensorFormat::Degrees),
+            _ => bail!("Unknown sensor format '{}', expected millidegrees or degrees.", s),
+        }
+    }
+}
+
+impl fmt::Display for SensorFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorFormat::Millidegrees => write!(f, "millidegrees"),
+            SensorFormat::Degrees => write!(f, "degrees"),
+        }
+    }
+}
+
+/// A DS18B20's resolution in bits, 9 to 12. Each bit halves the step and doubles the conversion time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution(u8);
+
+impl Resolution {
+    /// What a DS18B20 powers up at.
+    pub const DEFAULT: Resolution = Resolution(12);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// The smallest change it reads, 0.0625C at 12 bits.
+    pub fn step(self) -> f32 {
+        0.5 / f32::from(1u8 << (self.0 - 9))
+    }
+
+    /// How long a conversion takes, 750ms at 12 bits.
+    pub fn conversion(self) -> Duration {
+        Duration::from_micros(93_750 << (self.0 - 9))
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().parse() {
+            Ok(bits @ 9..=12) => Ok(Resolution(bits)),
+            _ => bail!("Invalid sensor resolution '{}', expected 9 to 12 bits.", s.trim()),
+        }
+    }
+}
+
+/// What became of setting the resolution.
+#[derive(Debug, PartialEq)]
+pub enum ResolutionChange {
+    Set(Resolution),
+    /// The attribute read back something else after the write, the resolution it read back if any.
+    Mismatch(Option<Resolution>),
+    /// No writable `resolution` attribute, older kernels don't have one.
+    Unsupported(String),
+}
+
+// Pure
+fn verify_resolution(wanted: Resolution, read_back: &str) -> ResolutionChange {
+    match read_back.parse() {
+        Ok(active) if active == wanted => ResolutionChange::Set(active),
+        active => ResolutionChange::Mismatch(active.ok()),
+    }
+}
+
+/// The number in a value file, without the line ending or a `t=` or `temp=` prefix.
+fn value(data: &str) -> &str {
+    let value = data.trim();
+    value
+        .strip_prefix("temp=")
+        .or_else(|| value.strip_prefix("t="))
+        .unwrap_or(value)
+}
+
+// Pure
+/// A decimal point is degrees and an integer of 1000 or more either way is millidegrees. None for anything else,
+/// 21 could be either.
+pub fn detect_format(data: &str) -> Option<SensorFormat> {
+    let value = value(data);
+    if value.contains('.') {
+        return value.parse::<f32>().ok().map(|_| SensorFormat::Degrees);
+    }
+    match value.parse::<i32>() {
+        Ok(i) if i.abs() >= 1000 => Some(SensorFormat::Millidegrees),
+        _ => None,
+    }
+}
+
+// Pure
+pub fn parse_reading(data: &str, format: SensorFormat) -> Result<Celsius, SensorError> {
+    let value = value(data);
+    let temperature = match format {
+        SensorFormat::Millidegrees => value.parse::<i32>().ok().map(|i| Millidegrees(i).celsius()),
+        SensorFormat::Degrees => value.parse::<f32>().ok().map(Celsius),
+    };
+    temperature.ok_or_else(|| SensorError::Malformed(value.to_string()))
+}
+
+pub fn read_w1(path: &Path, format: SensorFormat) -> Result<Celsius, SensorError> {
+    let data = fs::read_to_string(path).map_err(|e| SensorError::from_io(path.to_path_buf(), e))?;
+    parse_reading(&data, format)
+}
+
+/// The device ID of a value file, `28-00112233445566` for `/sys/bus/w1/devices/28-00112233445566/temperature`.
+pub fn device_id(path: &Path) -> Option<String> {
+    Some(path.parent()?.file_name()?.to_string_lossy().into_owned())
+}
+
+/// The value file of one w1 device, found again by ID, or replaced by the only other DS18B20 on the bus, when it
+/// goes missing.
+pub struct W1Device {
+    path: PathBuf,
+    id: String,
+    missing_reads: Cell<u32>,
+    format: SensorFormat,
+    resolution: Resolution,
+    /// Whether the last read took longer than the resolution's conversions should.
+    slow: Cell<bool>,
+    /// Devices that may never stand in for this one, the ambient sensor sits on the same bus.
+    excluded: Vec<String>,
+}
+
+impl W1Device {
+    pub fn new(path: PathBuf) -> Option<Self> {
+        Some(Self {
+            id: device_id(&path)?,
+            path,
+            missing_reads: Cell::new(0),
+            format: SensorFormat::Millidegrees,
+            resolution: Resolution::DEFAULT,
+            slow: Cell::new(false),
+            excluded: vec![],
+        })
+    }
+
+    pub fn exclude(&mut self, id: String) {
+        self.excluded.push(id);
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_format(&mut self, format: SensorFormat) {
+        self.format = format;
+    }
+
+    /// Writes the device's `resolution` attribute and reads it back. Reads are expected to take as long as the
+    /// resolution it reads back, or the default's when it can't be set.
+    pub fn set_resolution(&mut self, wanted: Resolution) -> ResolutionChange {
+        let attribute = self.path.with_file_name("resolution");
+        let change = match fs::write(&attribute, format!("{}\n", wanted.bits())) {
+            Ok(()) => match fs::read_to_string(&attribute) {
+                Ok(read_back) => verify_resolution(wanted, &read_back),
+                Err(e) => ResolutionChange::Unsupported(e.to_string()),
+            },
+            Err(e) => ResolutionChange::Unsupported(e.to_string()),
+        };
+        match &change {
+            ResolutionChange::Set(active) | ResolutionChange::Mismatch(Some(active)) => self.resolution = *active,
+            ResolutionChange::Mismatch(None) | ResolutionChange::Unsupported(_) => {}
+        }
+        match &change {
+            ResolutionChange::Set(_) => info!(
+                "Sensor {} at {} bits, {}C steps and {}ms conversions.",
+                self.id,
+                self.resolution.bits(),
+                self.resolution.step(),
+                self.resolution.conversion().as_millis()
+            ),
+            ResolutionChange::Mismatch(_) => warn!(
+                "Sensor {} was set to {} bits but reads back otherwise, reading at {} bits with {}C steps.",
+                self.id,
+                wanted.bits(),
+                self.resolution.bits(),
+                self.resolution.step()
+            ),
+            ResolutionChange::Unsupported(e) => info!(
+                "Sensor {} resolution can't be set, {} ({}), left at its own.",
+                self.id,
+                attribute.display(),
+                e
+            ),
+        }
+        change
+    }
+
+    /// A DS18B20 is read by the w1 driver, in millidegrees. Anything else is told from its current reading.
+    pub fn detect_format(&self) -> Result<SensorFormat, StartupError> {
+        if self.id.starts_with(DS18B20_PREFIX) {
+            return Ok(SensorFormat::Millidegrees);
+        }
+        let data = fs::read_to_string(&self.path).map_err(|e| StartupError::from_sensor(self.path.clone(), e))?;
+        detect_format(&data).ok_or_else(|| StartupError::AmbiguousSensorFormat(self.path.clone(), value(&data).into()))
+    }
+
+    pub fn read(&self) -> Result<Celsius, SensorError> {
+        let started = Instant::now();
+        let temperature = read_w1(&self.path, self.format);
+        let took = started.elapsed();
+        let slow = took > self.resolution.conversion() * READ_SLACK;
+        if slow && !self.slow.get() {
+            warn!(
+                "Sensor {} took {}ms to read, a {} bit conversion takes {}ms.",
+                self.id,
+                took.as_millis(),
+                self.resolution.bits(),
+                self.resolution.conversion().as_millis()
+            );
+        }
+        self.slow.set(slow);
+        let missing = self.missing_reads.get();
+        match &temperature {
+            Err(SensorError::Missing(_)) => self.missing_reads.set(missing + 1),
+            _ if missing > 0 => {
+                info!("Sensor {} reacquired after {} missing reads.", self.id, missing);
+                self.missing_reads.set(0);
+            }
+            _ => {}
+        }
+        temperature
+    }
+
+    /// Looks through the device listing every few missing reads. The new device ID when a different sensor took
+    /// this one's place.
+    pub fn rediscover(&mut self) -> Option<String> {
+        let missing = self.missing_reads.get();
+        if missing == 0 || !missing.is_multiple_of(DISCOVERY_EVERY) {
+            return None;
+        }
+        let devices = self.path.parent()?.parent()?;
+        let value_file = self.path.file_name()?.to_owned();
+        let listed = list_ds18b20(devices);
+        if listed.contains(&self.id) {
+            debug!("Sensor {} is listed again, waiting for its value file.", self.id);
+            return None;
+        }
+        let candidates: Vec<&String> = listed.iter().filter(|id| !self.excluded.contains(id)).collect();
+        let replacement = match candidates.as_slice() {
+            [replacement] => (*replacement).clone(),
+            [] => {
+                debug!("Sensor {} still missing after {} reads.", self.id, missing);
+                return None;
+            }
+            _ => {
+                warn!(
+                    "Sensor {} still missing, {} other DS18B20s are listed and none can be told to replace it.",
+                    self.id,
+                    candidates.len()
+                );
+                return None;
+            }
+        };
+        warn!(
+            "SENSOR REPLACED: {} is gone and {} is the only other DS18B20 listed, reading it instead. \
+             Its state files are keyed by the new ID, so the compensation is learned over.",
+            self.id, replacement
+        );
+        self.path = devices.join(&replacement).join(value_file);
+        self.id = replacement.clone();
+        self.missing_reads.set(0);
+        Some(replacement)
+    }
+}
+
+/// A bus master's `therm_bulk_read`. One write converts every sensor on the bus at once, and each then reads
+/// without waiting on a conversion of its own.
+pub struct BulkRead {
+    trigger: PathBuf,
+    failed: Cell<bool>,
+}
+
+impl BulkRead {
+    /// The bulk read of the master the value files all hang off, None when they're on different masters or the
+    /// kernel has none.
+    pub fn find(values: &[&Path]) -> Option<Self> {
+        let masters = values.iter().map(|value| master(value)).collect::<Option<Vec<_>>>()?;
+        let (first, others) = masters.split_first()?;
+        if others.iter().any(|master| master != first) {
+            return None;
+        }
+        let trigger = first.join(BULK_READ_FILE);
+        match trigger.is_file() {
+            true => Some(Self {
+                trigger,
+                failed: Cell::new(false),
+            }),
+            false => None,
+        }
+    }
+
+    pub fn trigger_path(&self) -> &Path {
+        &self.trigger
+    }
+
+    /// Starts the conversions before the sensors are read. After a failed write the sensors are left to convert
+    /// one at a time, as without a bulk read.
+    pub fn trigger(&self) {
+        if self.failed.get() {
+            return;
+        }
+        if let Err(e) = fs::write(&self.trigger, "trigger\n") {
+            warn!(
+                "Bulk conversion through {} failed, converting each sensor as it's read. {}",
+                self.trigger.display(),
+                e
+            );
+            self.failed.set(true);
+        }
+    }
+}
+
+/// The bus master directory a value file's device sits in, the devices listing links into it.
+fn master(value: &Path) -> Option<PathBuf> {
+    let device = fs::canonicalize(value.parent()?).ok()?;
+    Some(device.parent()?.to_path_buf())
+}
+
+/// The DS18B20 device IDs in a w1 device directory, sorted.
+fn list_ds18b20(devices: &Path) -> Vec<String> {
+    let mut ids: Vec<String> = match fs::read_dir(devices) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .filter_map(|name: OsString| name.into_string().ok())
+            .filter(|name| name.starts_with(DS18B20_PREFIX))
+            .collect(),
+        Err(e) => {
+            debug!("Listing w1 devices in {} failed: {}", devices.display(), e);
+            vec![]
+        }
+    };
+    ids.sort();
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// A w1 devices directory with the given sensors and their readings in millidegrees.
+    fn plug(devices: &Path, id: &str, millidegrees: i32) {
+        let device = devices.join(id);
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("temperature"), format!("{}\n", millidegrees)).unwrap();
+    }
+
+    fn unplug(devices: &Path, id: &str) {
+        fs::remove_dir_all(devices.join(id)).unwrap();
+    }
+
+    /// Reads until the sensor is looked for again, the reading that prompted it.
+    fn read_until_discovery(device: &mut W1Device) -> (Result<Celsius, SensorError>, Option<String>) {
+        for _ in 0..DISCOVERY_EVERY - 1 {
+            assert!(matches!(device.read(), Err(SensorError::Missing(_))));
+            assert_eq!(None, device.rediscover());
+        }
+        let reading = device.read();
+        (reading, device.rediscover())
+    }
+
+    #[test]
+    fn detects_the_format() {
+        assert_eq!(Some(SensorFormat::Millidegrees), detect_format("21437\n"));
+        assert_eq!(Some(SensorFormat::Millidegrees), detect_format("-1062"));
+        assert_eq!(Some(SensorFormat::Degrees), detect_format("21.4"));
+        assert_eq!(Some(SensorFormat::Degrees), detect_format("21.4\n"));
+        assert_eq!(Some(SensorFormat::Degrees), detect_format("temp=21.4"));
+        assert_eq!(Some(SensorFormat::Millidegrees), detect_format("t=21437"));
+        // Small integers could be either.
+        assert_eq!(None, detect_format("21\n"));
+        assert_eq!(None, detect_format("-999"));
+        assert_eq!(None, detect_format("temp=warm"));
+        assert_eq!(None, detect_format(""));
+    }
+
+    #[test]
+    fn parses_each_format() {
+        assert_eq!(
+            Celsius(21.437),
+            parse_reading("21437\n", SensorFormat::Millidegrees).unwrap()
+        );
+        assert_eq!(
+            Celsius(21.4),
+            parse_reading("temp=21.4\n", SensorFormat::Degrees).unwrap()
+        );
+        assert_eq!(Celsius(21.0), parse_reading("21", SensorFormat::Degrees).unwrap());
+        assert!(matches!(
+            parse_reading("21.4", SensorFormat::Millidegrees),
+            Err(SensorError::Malformed(v)) if v == "21.4"
+        ));
+        assert_eq!(SensorFormat::Degrees, "degrees".parse().unwrap());
+        assert!("celsius".parse::<SensorFormat>().is_err());
+    }
+
+    #[test]
+    fn other_files_are_detected_from_their_reading() {
+        let dir = TempDir::new();
+        let devices = dir.path();
+        plug(devices, "28-01", 21);
+        // The w1 driver's files are millidegrees however cold.
+        assert_eq!(
+            SensorFormat::Millidegrees,
+            W1Device::new(devices.join("28-01/temperature"))
+                .unwrap()
+                .detect_format()
+                .unwrap()
+        );
+        fs::create_dir_all(devices.join("chamber")).unwrap();
+        let path = devices.join("chamber/temperature");
+        fs::write(&path, "21.4\n").unwrap();
+        let mut device = W1Device::new(path.clone()).unwrap();
+        let format = device.detect_format().unwrap();
+        assert_eq!(SensorFormat::Degrees, format);
+        device.set_format(format);
+        assert_eq!(Celsius(21.4), device.read().unwrap());
+        fs::write(&path, "21\n").unwrap();
+        assert!(matches!(device.detect_format(), Err(StartupError::AmbiguousSensorFormat(_, v)) if v == "21"));
+    }
+
+    #[test]
+    fn resolution_steps_and_conversions() {
+        assert_eq!(0.0625, Resolution::DEFAULT.step());
+        assert_eq!(Duration::from_millis(750), Resolution::DEFAULT.conversion());
+        let low: Resolution = "10".parse().unwrap();
+        assert_eq!(0.25, low.step());
+        assert_eq!(Duration::from_micros(187_500), low.conversion());
+        assert_eq!(
+            Duration::from_micros(93_750),
+            "9".parse::<Resolution>().unwrap().conversion()
+        );
+        assert!("8".parse::<Resolution>().is_err());
+        assert!("13".parse::<Resolution>().is_err());
+    }
+
+    #[test]
+    fn sets_the_resolution() {
+        let dir = TempDir::new();
+        let devices = dir.path();
+        plug(devices, "28-01", 4500);
+        fs::write(devices.join("28-01/resolution"), "12\n").unwrap();
+        let mut device = W1Device::new(devices.join("28-01/temperature")).unwrap();
+        let low = "10".parse().unwrap();
+        assert_eq!(ResolutionChange::Set(low), device.set_resolution(low));
+        assert_eq!("10\n", fs::read_to_string(devices.join("28-01/resolution")).unwrap());
+        assert_eq!(low, device.resolution);
+    }
+
+    #[test]
+    fn keeps_what_the_resolution_reads_back() {
+        let low = "10".parse().unwrap();
+        assert_eq!(
+            ResolutionChange::Mismatch(Some(Resolution::DEFAULT)),
+            verify_resolution(low, "12\n")
+        );
+        assert_eq!(ResolutionChange::Mismatch(None), verify_resolution(low, ""));
+        assert_eq!(ResolutionChange::Set(low), verify_resolution(low, "10\n"));
+    }
+
+    #[test]
+    fn degrades_without_a_resolution_attribute() {
+        let dir = TempDir::new();
+        let devices = dir.path();
+        plug(devices, "28-01", 4500);
+        // A kernel without the attribute, sysfs refuses to create it.
+        fs::create_dir(devices.join("28-01/resolution")).unwrap();
+        let mut device = W1Device::new(devices.join("28-01/temperature")).unwrap();
+        assert!(matches!(
+            device.set_resolution("9".parse().unwrap()),
+            ResolutionChange::Unsupported(_)
+        ));
+        assert_eq!(Resolution::DEFAULT, device.resolution);
+        assert_eq!(Celsius(4.5), device.read().unwrap());
+    }
+
+    #[test]
+    fn finds_a_bulk_read_on_a_shared_master() {
+        let dir = TempDir::new();
+        let master = dir.path().join("w1_bus_master1");
+        plug(&master, "28-01", 4500);
+        plug(&master, "28-0a", 21000);
+        let chamber = master.join("28-01/temperature");
+        let ambient = master.join("28-0a/temperature");
+        // A kernel without bulk reads.
+        assert!(BulkRead::find(&[&chamber, &ambient]).is_none());
+
+        fs::write(master.join(BULK_READ_FILE), "0\n").unwrap();
+        let bulk = BulkRead::find(&[&chamber, &ambient]).unwrap();
+        bulk.trigger();
+        assert_eq!("trigger\n", fs::read_to_string(master.join(BULK_READ_FILE)).unwrap());
+
+        // Sensors on different masters convert separately.
+        let other = dir.path().join("w1_bus_master2");
+        plug(&other, "28-0b", 3000);
+        assert!(BulkRead::find(&[&chamber, &other.join("28-0b/temperature")]).is_none());
+    }
+
+    #[test]
+    fn a_failed_bulk_read_is_given_up() {
+        let dir = TempDir::new();
+        let master = dir.path().join("w1_bus_master1");
+        plug(&master, "28-01", 4500);
+        fs::write(master.join(BULK_READ_FILE), "0\n").unwrap();
+        let bulk = BulkRead::find(&[&master.join("28-01/temperature")]).unwrap();
+        // The trigger is replaced by something that can't be written.
+        fs::remove_file(master.join(BULK_READ_FILE)).unwrap();
+        fs::create_dir(master.join(BULK_READ_FILE)).unwrap();
+        bulk.trigger();
+        assert!(bulk.failed.get());
+        fs::remove_dir(master.join(BULK_READ_FILE)).unwrap();
+        bulk.trigger();
+        assert!(!master.join(BULK_READ_FILE).exists());
+    }
+
+    #[test]
+    fn same_device_returns() {
+        let dir = TempDir::new();
+        let devices = dir.path();
+        plug(devices, "28-01", 4500);
+        let mut device = W1Device::new(devices.join("28-01/temperature")).unwrap();
+        assert_eq!(Celsius(4.5), device.read().unwrap());
+        unplug(devices, "28-01");
+        let (reading, replacement) = read_until_discovery(&mut device);
+        assert!(matches!(reading, Err(SensorError::Missing(_))));
+        assert_eq!(None, replacement);
+        plug(devices, "28-01", 3250);
+        assert_eq!(Celsius(3.25), device.read().unwrap());
+        assert_eq!(None, device.rediscover());
+        assert_eq!(devices.join("28-01/temperature"), device.path());
+    }
+
+    #[test]
+    fn single_other_device_replaces_it() {
+        let dir = TempDir::new();
+        let devices = dir.path();
+        plug(devices, "28-01", 4500);
+        plug(devices, "28-0a", 21000);
+        let mut device = W1Device::new(devices.join("28-01/temperature")).unwrap();
+        // The ambient sensor never stands in for the chamber.
+        device.exclude("28-0a".into());
+        // Bus masters are listed alongside the devices.
+        fs::create_dir_all(devices.join("w1_bus_master1")).unwrap();
+        unplug(devices, "28-01");
+        assert_eq!(None, read_until_discovery(&mut device).1);
+        plug(devices, "28-02", 3000);
+        plug(devices, "28-03", 3100);
+        // Two candidates, neither can be told to be the replacement.
+        assert_eq!(None, read_until_discovery(&mut device).1);
+        unplug(devices, "28-03");
+        assert_eq!(Some("28-02".to_string()), read_until_discovery(&mut device).1);
+        assert_eq!(Celsius(3.0), device.read().unwrap());
+        assert_eq!(devices.join("28-02/temperature"), device.path());
+    }
+
+    #[test]
+    fn other_errors_do_not_prompt_discovery() {
+        let dir = TempDir::new();
+        let devices = dir.path();
+        plug(devices, "28-01", 4500);
+        plug(devices, "28-02", 3000);
+        let mut device = W1Device::new(devices.join("28-01/temperature")).unwrap();
+        fs::write(devices.join("28-01/temperature"), "garbage").unwrap();
+        for _ in 0..DISCOVERY_EVERY * 2 {
+            assert!(matches!(device.read(), Err(SensorError::Malformed(_))));
+            assert_eq!(None, device.rediscover());
+        }
+        assert!(W1Device::new(PathBuf::from("temperature")).is_none());
+    }
+}
@@ -0,0 +1,595 @@
+//! `picool watch`, a live view of a running daemon from `status` on its control socket. A terminal dashboard with
+//! `--tui`, otherwise a line per poll.
+
+use crate::{control, shutdown::Shutdown, status_board::Snapshot, POLL_DURATION};
+use anyhow::{bail, Result};
+use picool_core::{
+    current::LoadFault,
+    failover::Output,
+    temperature::{Celsius, DegreesDelta, Rate, Units},
+};
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+/// A snapshot older than this means the control loop isn't making progress.
+const STALE_AFTER: u64 = 3 * POLL_DURATION.as_secs();
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+/// Left of the sparkline and the band bar.
+const LABEL: usize = 11;
+
+pub fn fetch(socket: &Path) -> Result<Snapshot> {
+    control::request(socket, "status")?.parse()
+}
+
+/// Refreshes every poll until shutdown. Only the first status has to arrive, the dashboard rides out later failures.
+pub fn watch(socket: &Path, tui: bool, units: Units, shutdown: &Shutdown) -> Result<()> {
+    let mut snapshot = fetch(socket)?;
+    // SAFETY: isatty only inspects the descriptor.
+    if !tui || unsafe { libc::isatty(libc::STDOUT_FILENO) } == 0 {
+        loop {
+            println!("{}", plain(&snapshot, units));
+            if pause(shutdown) {
+                return Ok(());
+            }
+            snapshot = fetch(socket)?;
+        }
+    }
+    on_resize()?;
+    let mut out = io::stdout();
+    // The alternate screen, without a cursor.
+    write!(out, "\x1b[?1049h\x1b[?25l")?;
+    let mut error = None;
+    loop {
+        let (width, height) = terminal_size();
+        let mut lines = render(&snapshot, units, width, height);
+        if let Some(e) = &error {
+            lines.truncate(height.saturating_sub(1));
+            lines.push(truncate(&format!("Not updating: {}", e), width));
+        }
+        write!(out, "\x1b[H\x1b[2J{}", lines.join("\r\n"))?;
+        out.flush()?;
+        if pause(shutdown) {
+            break;
+        }
+        if RESIZED.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        match fetch(socket) {
+            Ok(fetched) => {
+                snapshot = fetched;
+                error = None;
+            }
+            Err(e) => error = Some(format!("{:#}", e)),
+        }
+    }
+    write!(out, "\x1b[?25h\x1b[?1049l")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Sleeps for a poll, cut short by a resize. Whether shutdown was requested.
+fn pause(shutdown: &Shutdown) -> bool {
+    let slice = Duration::from_millis(100);
+    let mut slept = Duration::from_secs(0);
+    while slept < POLL_DURATION && !shutdown.is_requested() && !RESIZED.load(Ordering::Relaxed) {
+        thread::sleep(slice);
+        slept += slice;
+    }
+    shutdown.is_requested()
+}
+
+fn on_resize() -> Result<()> {
+    // SAFETY: The handler only performs an atomic store.
+    let result = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigwinch as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut())
+    };
+    if result != 0 {
+        bail!("Failed to install handler for SIGWINCH.");
+    }
+    Ok(())
+}
+
+extern "C" fn on_sigwinch(_signal: libc::c_int) {
+    RESIZED.store(true, Ordering::Relaxed);
+}
+
+/// (columns, rows), 80x24 when the terminal won't say.
+fn terminal_size() -> (usize, usize) {
+    // SAFETY: TIOCGWINSZ only writes the winsize it's given.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    match result == 0 && size.ws_col > 0 && size.ws_row > 0 {
+        true => (size.ws_col as usize, size.ws_row as usize),
+        false => (80, 24),
+    }
+}
+
+// Pure
+/// The whole dashboard, no line wider than `width` and no more than `height` lines.
+pub fn render(snapshot: &Snapshot, units: Units, width: usize, height: usize) -> Vec<String> {
+    let on_off = |on: bool| if on { "ON" } else { "off" };
+    let freshness = match snapshot.age {
+        age if age > STALE_AFTER => format!("STALE, {}s old", age),
+        age => format!("{}s ago", age),
+    };
+    let stage2 = match snapshot.stage2 {
+        Some((on, _, _)) => format!("  stage 2 {}", on_off(on)),
+        None => String::new(),
+    };
+    let mut lines = vec![
+        format!(
+            "picool{}  {}  relay {}{}  fan {}  {}",
+            if snapshot.shadow { " SHADOW" } else { "" },
+            snapshot.state,
+            on_off(snapshot.power_on),
+            stage2,
+            on_off(snapshot.fan_on),
+            freshness
+        ),
+        String::new(),
+    ];
+    let shown = match snapshot.temperature {
+        Some(temperature) => format!("{:.1}", big_units(units).show(temperature)),
+        None => "--".into(),
+    };
+    lines.extend(big(&shown).iter().map(|row| format!("  {}", row)));
+    lines.push(String::new());
+    let graph = width.saturating_sub(LABEL).max(1);
+    lines.push(format!(
+        "{:<2$}{}",
+        "Last hour",
+        sparkline(&snapshot.history, graph),
+        LABEL
+    ));
+    if let Some((low, high)) = extent(&snapshot.history) {
+        lines.push(format!("{:<3$}{} to {}", "", units.show(low), units.show(high), LABEL));
+    }
+    lines.push(format!("{:<2$}{}", "Band", band_bar(snapshot, graph), LABEL));
+    lines.push(format!(
+        "{:<5$}{} to {}, switching at {} and {}",
+        "",
+        units.show(snapshot.band.start),
+        units.show(snapshot.band.end),
+        units.show(snapshot.thresholds.0),
+        units.show(snapshot.thresholds.1),
+        LABEL
+    ));
+    if let Some(left) = snapshot.boost {
+        lines.push(format!("{:<2$}boosted, {}m left", "", left / 60, LABEL));
+    }
+    if let Some((left, run)) = snapshot.rest {
+        lines.push(format!(
+            "{:<3$}resting, {}m left after a {}m run",
+            "",
+            left / 60,
+            run / 60,
+            LABEL
+        ));
+    }
+    if let Some((left, since)) = snapshot.shed {
+        lines.push(format!(
+            "{:<3$}shedding load, {}m left of {}m",
+            "",
+            left / 60,
+            (left + since) / 60,
+            LABEL
+        ));
+    }
+    lines.push(String::new());
+    lines.push(format!(
+        "Runtime {:.1}h  starts today {}  cycles {}",
+        snapshot.runtime_hours, snapshot.starts_today, snapshot.cycles
+    ));
+    if let Some((_, runtime_hours, starts)) = snapshot.stage2 {
+        lines.push(format!("Stage 2 {:.1}h  starts {}", runtime_hours, starts));
+    }
+    if let Some((amps, fault)) = snapshot.current {
+        lines.push(format!("Current {}{}", drawing(amps), fault_suffix(fault)));
+    }
+    if let Some((output, faulted)) = snapshot.output {
+        let primary = if faulted { ", primary faulted" } else { "" };
+        lines.push(format!("Output {}{}", output, primary));
+    }
+    if let Some(rate) = snapshot.cooling_rate {
+        let baseline = match snapshot.cooling_ratio {
+            Some(ratio) => format!("{:.0}% of baseline", ratio * 100.0),
+            None => "no baseline yet".into(),
+        };
+        lines.push(format!(
+            "Cooling {:.3}, {}",
+            units.show(Rate::new(rate, "/min")),
+            baseline
+        ));
+    }
+    if snapshot.overshoot.is_some() || snapshot.undershoot.is_some() {
+        let shown = |miss: Option<DegreesDelta>| match miss {
+            Some(miss) => format!("{:.2}", units.show(miss)),
+            None => "-".into(),
+        };
+        lines.push(format!(
+            "Overshoot {}, undershoot {}",
+            shown(snapshot.overshoot),
+            shown(snapshot.undershoot)
+        ));
+    }
+    if !snapshot.events.is_empty() {
+        lines.push(String::new());
+        lines.push("Events".into());
+        let room = height.saturating_sub(lines.len()).min(snapshot.events.len());
+        let newest = &snapshot.events[snapshot.events.len() - room..];
+        lines.extend(newest.iter().rev().map(|event| format!("  {}", event)));
+    }
+    lines.truncate(height);
+    lines.iter().map(|line| truncate(line, width)).collect()
+}
+
+// Pure
+/// One line for logs and pipes.
+pub fn plain(snapshot: &Snapshot, units: Units) -> String {
+    let temperature = match snapshot.temperature {
+        Some(temperature) => units.show(temperature).to_string(),
+        None => "no reading".into(),
+    };
+    let boost = match snapshot.boost {
+        Some(left) => format!(" boosted for {}m more", left / 60),
+        None => String::new(),
+    };
+    let rest = match (snapshot.rest, snapshot.shed) {
+        (_, Some((left, _))) => format!(" shedding load {}m more", left / 60),
+        (Some((left, _)), None) => format!(" resting {}m more", left / 60),
+        (None, None) => String::new(),
+    };
+    let stage2 = match snapshot.stage2 {
+        Some((on, _, _)) => format!(", stage 2 {}", if on { "on" } else { "off" }),
+        None => String::new(),
+    };
+    let current = match snapshot.current {
+        Some((amps, fault)) => format!(" drawing {}{}", drawing(amps), fault_suffix(fault)),
+        None => String::new(),
+    };
+    let current = match snapshot.output {
+        Some((Output::Backup, _)) => format!("{} on the backup", current),
+        _ => current,
+    };
+    format!(
+        "{}{} at {}, relay {}{}{}{}, fan {}, band {} to {}{}, switching at {} and {}, {:.1}h runtime, {} starts today, {}s ago",
+        if snapshot.shadow { "Shadow, " } else { "" },
+        snapshot.state,
+        temperature,
+        if snapshot.power_on { "on" } else { "off" },
+        rest,
+        current,
+        stage2,
+        if snapshot.fan_on { "on" } else { "off" },
+        units.show(snapshot.band.start),
+        units.show(snapshot.band.end),
+        boost,
+        units.show(snapshot.thresholds.0),
+        units.show(snapshot.thresholds.1),
+        snapshot.runtime_hours,
+        snapshot.starts_today,
+        snapshot.age
+    )
+}
+
+fn drawing(amps: Option<f32>) -> String {
+    match amps {
+        Some(amps) => format!("{:.2}A", amps),
+        None => "--".into(),
+    }
+}
+
+fn fault_suffix(fault: Option<LoadFault>) -> String {
+    match fault {
+        Some(fault) => format!(", {}", fault),
+        None => String::new(),
+    }
+}
+
+/// The big digits only have room for one unit.
+fn big_units(units: Units) -> Units {
+    match units {
+        Units::Fahrenheit => Units::Fahrenheit,
+        _ => Units::Celsius,
+    }
+}
+
+fn extent(readings: &[Option<Celsius>]) -> Option<(Celsius, Celsius)> {
+    let mut readings = readings.iter().flatten().copied();
+    let first = readings.next()?;
+    Some(readings.fold((first, first), |(low, high), reading| {
+        (
+            if reading < low { reading } else { low },
+            if reading > high { reading } else { high },
+        )
+    }))
+}
+
+// Pure
+/// `width` columns, each the mean of its share of the readings and scaled between the lowest and the highest. A
+/// column without any readings is blank.
+pub fn sparkline(history: &[Option<Celsius>], width: usize) -> String {
+    let (low, high) = match extent(history) {
+        Some(extent) => extent,
+        None => return " ".repeat(width),
+    };
+    let columns = width.min(history.len());
+    (0..columns)
+        .map(|column| {
+            let share = &history[column * history.len() / columns..(column + 1) * history.len() / columns];
+            let readings: Vec<f32> = share.iter().flatten().map(|reading| reading.0).collect();
+            if readings.is_empty() {
+                return ' ';
+            }
+            let mean = readings.iter().sum::<f32>() / readings.len() as f32;
+            match high.0 - low.0 {
+                span if span > 0.0 => {
+                    let level = ((mean - low.0) / span * (SPARKS.len() - 1) as f32).round() as usize;
+                    SPARKS[level.min(SPARKS.len() - 1)]
+                }
+                _ => SPARKS[SPARKS.len() / 2 - 1],
+            }
+        })
+        .collect()
+}
+
+// Pure
+/// The target band as `=` on a scale a degree wider than everything shown, the thresholds as `|` and the
+/// temperature as `*`.
+pub fn band_bar(snapshot: &Snapshot, width: usize) -> String {
+    let (low, high) = snapshot.thresholds;
+    let marks = [snapshot.band.start, snapshot.band.end, low, high]
+        .iter()
+        .copied()
+        .chain(snapshot.temperature)
+        .map(|temperature| temperature.0)
+        .collect::<Vec<_>>();
+    let start = marks.iter().copied().fold(f32::INFINITY, f32::min) - 1.0;
+    let end = marks.iter().copied().fold(f32::NEG_INFINITY, f32::max) + 1.0;
+    let column = |temperature: Celsius| {
+        let position = (temperature.0 - start) / (end - start) * (width - 1) as f32;
+        (position.round().max(0.0) as usize).min(width - 1)
+    };
+    let mut bar = vec!['-'; width];
+    for cell in &mut bar[column(snapshot.band.start)..=column(snapshot.band.end)] {
+        *cell = '=';
+    }
+    bar[column(low)] = '|';
+    bar[column(high)] = '|';
+    if let Some(temperature) = snapshot.temperature {
+        bar[column(temperature)] = '*';
+    }
+    bar.into_iter().collect()
+}
+
+/// Three rows of seven-segment-like digits.
+pub fn big(text: &str) -> [String; 3] {
+    let mut rows = [String::new(), String::new(), String::new()];
+    for c in text.chars() {
+        let glyph = match c {
+            '0' => [" _ ", "| |", "|_|"],
+            '1' => ["   ", "  |", "  |"],
+            '2' => [" _ ", " _|", "|_ "],
+            '3' => [" _ ", " _|", " _|"],
+            '4' => ["   ", "|_|", "  |"],
+            '5' => [" _ ", "|_ ", " _|"],
+            '6' => [" _ ", "|_ ", "|_|"],
+            '7' => [" _ ", "  |", "  |"],
+            '8' => [" _ ", "|_|", "|_|"],
+            '9' => [" _ ", "|_|", " _|"],
+            '-' => ["   ", " _ ", "   "],
+            '.' => [" ", " ", "."],
+            'C' => [" _ ", "|  ", "|_ "],
+            'F' => [" _ ", "|_ ", "|  "],
+            _ => ["  ", "  ", "  "],
+        };
+        for (row, part) in rows.iter_mut().zip(glyph.iter()) {
+            row.push_str(part);
+        }
+    }
+    rows
+}
+
+fn truncate(line: &str, width: usize) -> String {
+    line.chars().take(width).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{control, net_runtime::Runtime, status_board::StatusBoard, test_util::TempDir};
+    use std::sync::mpsc::channel;
+
+    const STATUS: &str = "state On
+temperature 6.500
+relay on
+fan off
+thresholds 4.100 7.900
+band 4.000 8.000
+runtime 12.5
+starts 7
+cycles 3
+cooling_rate 0.1250
+cooling_ratio 0.500
+overshoot 0.420
+age 4
+history 7.000 7.500 - 6.000 6.500 8.000
+event Cooling recovered
+event Runtime reset
+";
+
+    fn snapshot() -> Snapshot {
+        STATUS.parse().unwrap()
+    }
+
+    #[test]
+    fn sparkline_scales_between_the_extremes() {
+        let history = snapshot().history;
+        assert_eq!("▅▆ ▁▃█", sparkline(&history, 6));
+        // Wider than the history draws one column per reading, narrower averages.
+        assert_eq!("▅▆ ▁▃█", sparkline(&history, 40));
+        assert_eq!("▅▁▅", sparkline(&history, 3));
+        assert_eq!("▄▄", sparkline(&[Some(Celsius(5.0)), Some(Celsius(5.0))], 2));
+        assert_eq!("   ", sparkline(&[None, None], 3));
+    }
+
+    #[test]
+    fn band_bar_marks_the_band_thresholds_and_temperature() {
+        let snapshot = snapshot();
+        // 3C to 9C over 13 columns, two per degree.
+        assert_eq!("--|====*==|--", band_bar(&snapshot, 13));
+        let warm = Snapshot {
+            temperature: Some(Celsius(11.0)),
+            ..snapshot.clone()
+        };
+        assert_eq!("-|====|---*-", band_bar(&warm, 12));
+        let unread = Snapshot {
+            temperature: None,
+            ..snapshot
+        };
+        assert_eq!("--|=======|--", band_bar(&unread, 13));
+    }
+
+    #[test]
+    fn big_digits() {
+        assert_eq!(
+            [" _      _  _ ", "|_   | |_ |  ", "|_|  |. _||_ "],
+            big("61.5C").map(|row| row)
+        );
+    }
+
+    #[test]
+    fn renders_the_dashboard_snapshot() {
+        let lines = render(&snapshot(), Units::Celsius, 40, 24);
+        assert_eq!(
+            vec![
+                "picool  On  relay ON  fan off  4s ago",
+                "",
+                "   _   _  _ ",
+                "  |_  |_ |  ",
+                "  |_|. _||_ ",
+                "",
+                "Last hour  ▅▆ ▁▃█",
+                "           6.00C to 8.00C",
+                "Band       -----|==========*======|-----",
+                "           4.00C to 8.00C, switching at ",
+                "",
+                "Runtime 12.5h  starts today 7  cycles 3",
+                "Cooling 0.125C/min, 50% of baseline",
+                "Overshoot 0.42C, undershoot -",
+                "",
+                "Events",
+                "  Runtime reset",
+                "  Cooling recovered",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn dashboard_fits_small_terminals_and_flags_stale_status() {
+        let stale = Snapshot { age: 60, ..snapshot() };
+        let lines = render(&stale, Units::Both, 20, 8);
+        assert_eq!(8, lines.len());
+        assert!(lines.iter().all(|line| line.chars().count() <= 20));
+        assert_eq!("picool  On  relay ON", lines[0]);
+        assert!(render(&stale, Units::Both, 80, 24)[0].ends_with("STALE, 60s old"));
+    }
+
+    #[test]
+    fn plain_line_when_not_a_terminal() {
+        assert_eq!(
+            "On at 6.50C, relay on, fan off, band 4.00C to 8.00C, switching at 4.10C and 7.90C, 12.5h runtime, 7 \
+             starts today, 4s ago",
+            plain(&snapshot(), Units::Celsius)
+        );
+        let boosted = Snapshot {
+            boost: Some(5400),
+            ..snapshot()
+        };
+        assert!(plain(&boosted, Units::Celsius).contains("band 4.00C to 8.00C boosted for 90m more, switching"));
+        let shadow = Snapshot {
+            shadow: true,
+            ..snapshot()
+        };
+        assert!(plain(&shadow, Units::Celsius).starts_with("Shadow, On at 6.50C, relay on"));
+        assert!(render(&shadow, Units::Celsius, 80, 24)[0].starts_with("picool SHADOW  On  relay ON"));
+        assert!(render(&boosted, Units::Celsius, 40, 24).contains(&"           boosted, 90m left".to_string()));
+        let resting = Snapshot {
+            power_on: false,
+            rest: Some((1200, 7500)),
+            ..snapshot()
+        };
+        assert!(plain(&resting, Units::Celsius).contains("relay off resting 20m more, fan off"));
+        assert!(render(&resting, Units::Celsius, 60, 24)
+            .contains(&"           resting, 20m left after a 125m run".to_string()));
+        let shedding = Snapshot {
+            power_on: false,
+            shed: Some((2400, 1200)),
+            ..snapshot()
+        };
+        assert!(plain(&shedding, Units::Celsius).contains("relay off shedding load 40m more, fan off"));
+        assert!(render(&shedding, Units::Celsius, 60, 24)
+            .contains(&"           shedding load, 40m left of 60m".to_string()));
+        let staged = Snapshot {
+            stage2: Some((true, 1.5, 3)),
+            ..snapshot()
+        };
+        assert!(plain(&staged, Units::Celsius).contains("relay on, stage 2 on, fan off"));
+        let lines = render(&staged, Units::Celsius, 80, 24);
+        assert!(
+            lines[0].starts_with("picool  On  relay ON  stage 2 ON  fan off"),
+            "{:?}",
+            lines
+        );
+        assert!(lines.contains(&"Stage 2 1.5h  starts 3".to_string()), "{:?}", lines);
+        let welded = Snapshot {
+            power_on: false,
+            current: Some((Some(2.4), Some(LoadFault::Unexpected))),
+            ..snapshot()
+        };
+        assert!(plain(&welded, Units::Celsius).contains("relay off drawing 2.40A, unexpected-load, fan off"));
+        let lines = render(&welded, Units::Celsius, 80, 24);
+        assert!(
+            lines.contains(&"Current 2.40A, unexpected-load".to_string()),
+            "{:?}",
+            lines
+        );
+        let failed_over = Snapshot {
+            current: Some((Some(2.5), None)),
+            output: Some((Output::Backup, true)),
+            ..snapshot()
+        };
+        assert!(plain(&failed_over, Units::Celsius).contains("relay on drawing 2.50A on the backup, fan off"));
+        let lines = render(&failed_over, Units::Celsius, 80, 24);
+        assert!(
+            lines.contains(&"Output backup, primary faulted".to_string()),
+            "{:?}",
+            lines
+        );
+    }
+
+    #[test]
+    fn fetches_from_the_control_socket() {
+        let dir = TempDir::new();
+        let path = dir.path().join("control.sock");
+        assert!(fetch(&path).is_err());
+        let board = StatusBoard::default();
+        let (sender, _commands) = channel();
+        let mut runtime = Runtime::default();
+        control::serve(&path, board, sender, &mut runtime).unwrap();
+        let error = fetch(&path).unwrap_err();
+        assert_eq!("picool replied 'error: No reading yet.'.", error.to_string());
+    }
+}
@@ -0,0 +1,983 @@
+//! `/ws`, a WebSocket stream of the status and events for a dashboard, served with `--ws-listen <host:port>`. Each
+//! client has a bounded queue: one too slow to keep up loses messages, counted in a message of their own, one that
+//! stops reading or answering pings is disconnected. The control loop never waits on a client.
+
+use crate::{
+    cycle_history::quote,
+    net_runtime::{Runtime, Task, TaskHandle, Wait, Waker},
+    pushgateway::base64,
+    status_board::Snapshot,
+};
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use picool_core::{controller::Transition, notify::Event, temperature::Celsius};
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+/// Messages waiting for a client, past this the newest are dropped.
+const QUEUE: usize = 64;
+/// A ping is sent after this long without sending anything.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// A client that sent nothing, not even a pong, for this long is gone.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+/// For the handshake and each write, a client that stops reading is disconnected rather than queued for.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a close waits for the client's own close before the connection is dropped.
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+/// Frames from a client are pongs and closes, anything it sends past this ends the connection.
+const MAX_PAYLOAD: u64 = 64 * 1024;
+/// Appended to the client's key for the accept hash, from RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The client went away, as the close code on shutdown.
+const GOING_AWAY: u16 = 1001;
+const NORMAL_CLOSURE: u16 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The close code, 1005 when the close had none.
+    Close(u16),
+    /// Binary data or a continuation, nothing a client needs to send.
+    Data,
+}
+
+/// Writes `frame` unfragmented, masked with `mask` as a client must.
+pub fn write_frame(out: &mut impl Write, frame: &Frame, mask: Option<[u8; 4]>) -> io::Result<()> {
+    let (opcode, payload) = match frame {
+        Frame::Text(text) => (0x1, text.as_bytes().to_vec()),
+        Frame::Ping(data) => (0x9, data.clone()),
+        Frame::Pong(data) => (0xa, data.clone()),
+        Frame::Close(code) => (0x8, code.to_be_bytes().to_vec()),
+        Frame::Data => (0x2, vec![]),
+    };
+    let mut bytes = vec![0x80 | opcode];
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => bytes.push(masked | len as u8),
+        len if len <= 0xffff => {
+            bytes.push(masked | 126);
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            bytes.push(masked | 127);
+            bytes.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            bytes.extend_from_slice(&mask);
+            bytes.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => bytes.extend_from_slice(&payload),
+    }
+    out.write_all(&bytes)
+}
+
+/// The next frame in `input` and how many bytes of it it took, None until all of it is there. It must be `masked`
+/// when it's from a client and must not be from the server.
+pub fn decode(input: &[u8], masked: bool) -> io::Result<Option<(Frame, usize)>> {
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    let header = match input {
+        [first, second, ..] => [*first, *second],
+        _ => return Ok(None),
+    };
+    if (header[1] & 0x80 != 0) != masked {
+        return Err(invalid("frame masked the wrong way"));
+    }
+    let (len, mut at) = match header[1] & 0x7f {
+        126 => match input.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match input.get(2..10) {
+            Some(len) => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(len);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if len > MAX_PAYLOAD {
+        return Err(invalid("frame too long"));
+    }
+    let mut mask = [0; 4];
+    if masked {
+        match input.get(at..at + 4) {
+            Some(bytes) => mask.copy_from_slice(bytes),
+            None => return Ok(None),
+        }
+        at += 4;
+    }
+    let mut payload = match input.get(at..at + len as usize) {
+        Some(payload) => payload.to_vec(),
+        None => return Ok(None),
+    };
+    if masked {
+        payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+    }
+    let frame = match header[0] & 0x0f {
+        0x1 => String::from_utf8(payload)
+            .map(Frame::Text)
+            .map_err(|_| invalid("text frame isn't UTF-8"))?,
+        0x0 | 0x2 => Frame::Data,
+        0x8 => Frame::Close(match payload[..] {
+            [high, low, ..] => u16::from_be_bytes([high, low]),
+            _ => 1005,
+        }),
+        0x9 => Frame::Ping(payload),
+        0xa => Frame::Pong(payload),
+        _ => return Err(invalid("unknown opcode")),
+    };
+    Ok(Some((frame, at + len as usize)))
+}
+
+// Pure
+/// `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+// Pure
+/// Sent after every reading, temperatures in Celsius whatever the display units.
+pub fn status_message(snapshot: &Snapshot) -> String {
+    format!(
+        "{{\"type\":\"status\",\"state\":{},\"shadow\":{},\"temperature\":{},\"relay\":{},\"fan\":{},\
+         \"thresholds\":[{:.3},{:.3}],\"band\":[{:.3},{:.3}],\"runtime_hours\":{:.2},\"starts_today\":{},\
+         \"cycles\":{},\"sensor_errors\":{},\"relay_deferrals\":{}}}",
+        quote(&snapshot.state),
+        snapshot.shadow,
+        number(snapshot.temperature),
+        snapshot.power_on,
+        snapshot.fan_on,
+        snapshot.thresholds.0 .0,
+        snapshot.thresholds.1 .0,
+        snapshot.band.start.0,
+        snapshot.band.end.0,
+        snapshot.runtime_hours,
+        snapshot.starts_today,
+        snapshot.cycles,
+        snapshot.sensor_errors,
+        snapshot.relay_deferrals
+    )
+}
+
+// Pure
+pub fn transition_message(transition: &Transition) -> String {
+    format!(
+        "{{\"type\":\"transition\",\"from\":{},\"to\":{},\"reason\":{},\"temperature\":{}}}",
+        quote(&transition.from.to_string()),
+        quote(&transition.to.to_string()),
+        quote(&transition.reason.to_string()),
+        number(transition.temperature)
+    )
+}
+
+// Pure
+/// A `fault` when the controller or the hardware is in trouble, an `alarm` for the advisory ones, otherwise an
+/// `event`.
+pub fn event_message(event: &Event) -> String {
+    let kind = match event {
+        Event::SensorFailsafeEngaged { .. }
+        | Event::ThermalRunaway(_)
+        | Event::LoopStalled { .. }
+        | Event::StorageUnreliable { .. }
+        | Event::PumpFailed { .. }
+        | Event::LoadFault { .. }
+        | Event::FailedOver { .. } => "fault",
+        Event::DutyCycleHigh { .. } | Event::CoolingDegraded { .. } | Event::CompensationCapped { .. } => "alarm",
+        _ => "event",
+    };
+    format!(
+        "{{\"type\":\"{}\",\"message\":{}}}",
+        kind,
+        quote(&event.to_string().replace('\n', " "))
+    )
+}
+
+fn dropped_message(dropped: u64) -> String {
+    format!("{{\"type\":\"dropped\",\"count\":{}}}", dropped)
+}
+
+fn number(temperature: Option<Celsius>) -> String {
+    temperature.map_or_else(|| "null".into(), |temperature| format!("{:.3}", temperature.0))
+}
+
+/// The connected clients, cheap to clone for the control loop to send to.
+#[derive(Clone, Default)]
+pub struct WsStream {
+    clients: Arc<Mutex<Clients>>,
+}
+
+#[derive(Default)]
+struct Clients {
+    connected: Vec<Arc<Client>>,
+    /// Shutting down, a client connecting now is closed straight away.
+    closed: bool,
+    /// Wakes the server's task for what was queued, None until it runs.
+    waker: Option<Waker>,
+}
+
+impl WsStream {
+    pub fn status(&self, snapshot: &Snapshot) {
+        self.broadcast(status_message(snapshot));
+    }
+
+    pub fn transition(&self, transition: &Transition) {
+        self.broadcast(transition_message(transition));
+    }
+
+    pub fn event(&self, event: &Event) {
+        self.broadcast(event_message(event));
+    }
+
+    fn broadcast(&self, message: String) {
+        let message: Arc<str> = message.into();
+        let mut clients = self.lock();
+        clients.connected.retain(|client| !client.outbox().closed);
+        for client in &clients.connected {
+            client.send(message.clone());
+        }
+        if let (false, Some(waker)) = (clients.connected.is_empty(), &clients.waker) {
+            waker.wake();
+        }
+    }
+
+    fn register(&self, client: Arc<Client>) {
+        let mut clients = self.lock();
+        if clients.closed {
+            client.close(GOING_AWAY, true);
+        }
+        clients.connected.push(client);
+    }
+
+    fn close_all(&self) {
+        let mut clients = self.lock();
+        clients.closed = true;
+        for client in &clients.connected {
+            client.close(GOING_AWAY, true);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Clients> {
+        self.clients.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// What is waiting to be sent to one client.
+#[derive(Default)]
+struct Client {
+    outbox: Mutex<Outbox>,
+}
+
+#[derive(Default)]
+struct Outbox {
+    messages: VecDeque<Arc<str>>,
+    /// Pongs, sent ahead of the messages.
+    control: VecDeque<Frame>,
+    /// Messages lost since the client was last told.
+    dropped: u64,
+    /// The close code, sent once the control frames and the messages before it are out.
+    close: Option<u16>,
+    /// A close was queued, nothing more is.
+    closing: bool,
+    /// The connection is over.
+    closed: bool,
+}
+
+impl Client {
+    fn outbox(&self) -> MutexGuard<'_, Outbox> {
+        self.outbox.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues `message`, or counts it as dropped when the queue is full.
+    fn send(&self, message: Arc<str>) {
+        let mut outbox = self.outbox();
+        if outbox.closing {
+            return;
+        }
+        match outbox.messages.len() < QUEUE {
+            true => outbox.messages.push_back(message),
+            false => outbox.dropped += 1,
+        }
+    }
+
+    fn pong(&self, data: Vec<u8>) {
+        let mut outbox = self.outbox();
+        if !outbox.closing {
+            outbox.control.push_back(Frame::Pong(data));
+        }
+    }
+
+    /// Closes with `code`, after the queued messages when `drain`, otherwise they are left.
+    fn close(&self, code: u16, drain: bool) {
+        let mut outbox = self.outbox();
+        if !outbox.closing {
+            outbox.closing = true;
+            if !drain {
+                outbox.messages.clear();
+                outbox.dropped = 0;
+            }
+            outbox.close = Some(code);
+        }
+    }
+
+    fn finished(&self) {
+        self.outbox().closed = true;
+    }
+
+    /// The next frame to write, a ping when `ping` and there is nothing else, None when there is nothing to send.
+    fn next(&self, ping: bool) -> Option<Frame> {
+        let mut outbox = self.outbox();
+        if let Some(frame) = outbox.control.pop_front() {
+            return Some(frame);
+        }
+        if outbox.closed {
+            return None;
+        }
+        if outbox.dropped > 0 {
+            return Some(Frame::Text(dropped_message(std::mem::take(&mut outbox.dropped))));
+        }
+        if let Some(message) = outbox.messages.pop_front() {
+            return Some(Frame::Text(message.to_string()));
+        }
+        if let Some(code) = outbox.close.take() {
+            return Some(Frame::Close(code));
+        }
+        match ping {
+            true => Some(Frame::Ping(vec![])),
+            false => None,
+        }
+    }
+}
+
+/// How long a client may go quiet or leave a write waiting, shorter in tests.
+#[derive(Debug, Copy, Clone)]
+struct Timeouts {
+    ping: Duration,
+    idle: Duration,
+    write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            ping: PING_INTERVAL,
+            idle: IDLE_TIMEOUT,
+            write: WRITE_TIMEOUT,
+        }
+    }
+}
+
+/// Accepts clients and serves them from a task on the runtime.
+pub struct WsServer {
+    stream: WsStream,
+    address: SocketAddr,
+    task: Option<TaskHandle>,
+}
+
+impl WsServer {
+    pub fn bind(address: &str, runtime: &mut Runtime) -> Result<Self> {
+        Self::bind_with(address, Timeouts::default(), runtime)
+    }
+
+    fn bind_with(address: &str, timeouts: Timeouts, runtime: &mut Runtime) -> Result<Self> {
+        let listener = TcpListener::bind(address).with_context(|| format!("Failed to listen on {}.", address))?;
+        listener.set_nonblocking(true)?;
+        let local = listener.local_addr()?;
+        let stream = WsStream::default();
+        let task = runtime.spawn("websocket", |waker| {
+            stream.lock().waker = Some(waker);
+            ServerTask {
+                listener: Some(listener),
+                stream: stream.clone(),
+                connections: vec![],
+                timeouts,
+            }
+        })?;
+        Ok(Self {
+            address: local,
+            stream,
+            task: Some(task),
+        })
+    }
+
+    pub fn stream(&self) -> WsStream {
+        self.stream.clone()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Closes every connection once what's queued for it is sent, each client has a second to close in turn.
+    pub fn stop(mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.shut_down();
+        }
+    }
+}
+
+impl Drop for WsServer {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+struct ServerTask {
+    /// None once shutting down, nobody new connects.
+    listener: Option<TcpListener>,
+    stream: WsStream,
+    connections: Vec<Connection>,
+    timeouts: Timeouts,
+}
+
+impl Task for ServerTask {
+    fn poll(&mut self, now: Instant, wait: &mut Wait) -> Poll<()> {
+        while let Some(listener) = &self.listener {
+            match listener.accept() {
+                Ok((socket, peer)) => match socket.set_nonblocking(true) {
+                    Ok(()) => self.connections.push(Connection::new(socket, peer, now)),
+                    Err(e) => warn!("WebSocket connection from {} failed. {}", peer, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    wait.readable(listener);
+                    break;
+                }
+                Err(e) => {
+                    warn!("WebSocket accept failed. {}", e);
+                    wait.readable(listener);
+                    break;
+                }
+            }
+        }
+        let (stream, timeouts) = (&self.stream, self.timeouts);
+        self.connections.retain_mut(|connection| {
+            let open = connection.serve(now, stream, timeouts, wait);
+            if !open {
+                connection.client.finished();
+                let _ = connection.socket.shutdown(Shutdown::Both);
+                debug!("WebSocket client {} disconnected.", connection.peer);
+            }
+            open
+        });
+        match self.listener.is_none() && self.connections.is_empty() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+
+    fn shut_down(&mut self) {
+        self.listener = None;
+        self.stream.close_all();
+    }
+}
+
+/// One client's socket and how far it got.
+struct Connection {
+    socket: TcpStream,
+    peer: SocketAddr,
+    stage: Stage,
+    client: Arc<Client>,
+    /// Read and not yet taken as the request or a frame.
+    input: Vec<u8>,
+    /// The response or the frame being written.
+    output: Vec<u8>,
+    /// When the client last sent anything.
+    heard: Instant,
+    /// When a frame was last written, a ping is due an interval after.
+    sent: Instant,
+    /// Since when the output has been waiting on a full socket.
+    stalled: Option<Instant>,
+}
+
+enum Stage {
+    /// Reading the HTTP request, until the deadline.
+    Request(Instant),
+    Open,
+    /// Our close is out, waiting for the client's until the deadline.
+    Closing(Instant),
+    /// The client closed, our close goes out before the connection ends.
+    ClosedByClient,
+    /// The connection ends once the output is out, an HTTP error or the last close.
+    Ending,
+}
+
+impl Connection {
+    fn new(socket: TcpStream, peer: SocketAddr, now: Instant) -> Self {
+        Self {
+            socket,
+            peer,
+            stage: Stage::Request(now),
+            client: Arc::default(),
+            input: vec![],
+            output: vec![],
+            heard: now,
+            sent: now,
+            stalled: None,
+        }
+    }
+
+    /// Reads and writes what it can, whether the connection stays open.
+    fn serve(&mut self, now: Instant, stream: &WsStream, timeouts: Timeouts, wait: &mut Wait) -> bool {
+        match self.read(now) {
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(e) => {
+                debug!("WebSocket client {} read ended. {}", self.peer, e);
+                return false;
+            }
+        }
+        if let Err(e) = self.take_input(now, stream) {
+            debug!("WebSocket connection from {} refused. {:#}", self.peer, e);
+        }
+        if !self.write(now, timeouts) {
+            return false;
+        }
+        match self.stage {
+            Stage::Request(accepted) if now >= accepted + timeouts.write => return false,
+            Stage::Request(accepted) => wait.until(accepted + timeouts.write),
+            Stage::Open if now >= self.heard + timeouts.idle => {
+                info!(
+                    "WebSocket client {} disconnected, it sent nothing for {}s.",
+                    self.peer,
+                    timeouts.idle.as_secs()
+                );
+                return false;
+            }
+            Stage::Open => {
+                wait.until(self.heard + timeouts.idle);
+                if self.output.is_empty() {
+                    wait.until(self.sent + timeouts.ping);
+                }
+            }
+            Stage::Closing(until) if now >= until => return false,
+            Stage::Closing(until) => wait.until(until),
+            Stage::ClosedByClient | Stage::Ending => {}
+        }
+        if let Some(stalled) = self.stalled {
+            if now >= stalled + timeouts.write {
+                // A full socket buffer for longer than the write timeout, the client isn't reading.
+                info!("WebSocket client {} disconnected, it stopped reading.", self.peer);
+                return false;
+            }
+            wait.until(stalled + timeouts.write);
+        }
+        wait.readable(&self.socket);
+        if !self.output.is_empty() {
+            wait.writable(&self.socket);
+        }
+        true
+    }
+
+    /// Reads what the client sent so far, false once it hung up.
+    fn read(&mut self, now: Instant) -> io::Result<bool> {
+        let mut bytes = [0; 4096];
+        loop {
+            match self.socket.read(&mut bytes) {
+                Ok(0) => return Ok(false),
+                Ok(read) => {
+                    self.input.extend_from_slice(&bytes[..read]);
+                    self.heard = now;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+            if self.input.len() as u64 > 2 * MAX_PAYLOAD {
+                return Err(io::Error::new(ErrorKind::InvalidData, "too much sent"));
+            }
+        }
+    }
+
+    /// The request once it's all there, then the client's frames.
+    fn take_input(&mut self, now: Instant, stream: &WsStream) -> Result<()> {
+        if let Stage::Request(_) = self.stage {
+            let end = match self.input.windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(end) => end + 4,
+                None => return Ok(()),
+            };
+            let request: Vec<u8> = self.input.drain(..end).collect();
+            match upgrade(&String::from_utf8_lossy(&request)) {
+                Ok(accept) => {
+                    stream.register(self.client.clone());
+                    self.output.extend_from_slice(
+                        format!(
+                            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                             Sec-WebSocket-Accept: {}\r\n\r\n",
+                            accept
+                        )
+                        .as_bytes(),
+                    );
+                    self.stage = Stage::Open;
+                    self.heard = now;
+                    self.sent = now;
+                    debug!("WebSocket client {} connected.", self.peer);
+                }
+                Err((response, e)) => {
+                    self.output.extend_from_slice(response);
+                    self.stage = Stage::Ending;
+                    return Err(e);
+                }
+            }
+        }
+        if let Stage::Open | Stage::Closing(_) = self.stage {
+            while let Some((frame, len)) = decode(&self.input, true)? {
+                self.input.drain(..len);
+                match frame {
+                    Frame::Ping(data) => self.client.pong(data),
+                    Frame::Close(_) => {
+                        self.client.close(NORMAL_CLOSURE, false);
+                        self.stage = match self.stage {
+                            Stage::Open => Stage::ClosedByClient,
+                            _ => Stage::Ending,
+                        };
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes what's queued until the socket is full, false once the connection is over.
+    fn write(&mut self, now: Instant, timeouts: Timeouts) -> bool {
+        loop {
+            if self.output.is_empty() {
+                if let Stage::Ending = self.stage {
+                    return false;
+                }
+                if let Stage::Request(_) | Stage::Closing(_) = self.stage {
+                    return true;
+                }
+                match self.client.next(now >= self.sent + timeouts.ping) {
+                    Some(frame) => {
+                        // Writing to a Vec doesn't fail.
+                        let _ = write_frame(&mut self.output, &frame, None);
+                        self.sent = now;
+                        if let Frame::Close(_) = frame {
+                            self.stage = match self.stage {
+                                Stage::ClosedByClient => Stage::Ending,
+                                _ => Stage::Closing(now + CLOSE_GRACE),
+                            };
+                        }
+                    }
+                    None => return true,
+                }
+            }
+            match self.socket.write(&self.output) {
+                Ok(written) => {
+                    self.output.drain(..written);
+                    self.stalled = None;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.stalled.get_or_insert(now);
+                    return true;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    info!("WebSocket client {} disconnected, it stopped reading. {}", self.peer, e);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Accept` for an HTTP upgrade of `/ws`, otherwise the HTTP error to answer with and why.
+fn upgrade(request: &str) -> std::result::Result<String, (&'static [u8], anyhow::Error)> {
+    let mut lines = request.lines();
+    let first = lines.next().unwrap_or_default();
+    let (mut upgrade, mut key, mut version) = (false, None, None);
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.to_string()),
+                "sec-websocket-version" => version = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    let mut words = first.split_whitespace();
+    if (words.next(), words.next()) != (Some("GET"), Some("/ws")) {
+        return Err((
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            anyhow!("Not a request for /ws: {}", first),
+        ));
+    }
+    let key = match (upgrade, key) {
+        (true, Some(key)) => key,
+        _ => {
+            return Err((
+                b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                anyhow!("Not a WebSocket upgrade."),
+            ))
+        }
+    };
+    if version.as_deref() != Some("13") {
+        return Err((
+            b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            anyhow!("Unsupported WebSocket version {:?}.", version),
+        ));
+    }
+    Ok(accept_key(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{snapshot, WsClient};
+    use picool_core::{controller::TransitionReason, State};
+
+    #[test]
+    fn hashes_the_key_as_the_rfc_does() {
+        let hex: String = sha1(b"abc").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", hex);
+        // Two blocks once padded.
+        let hex: String = sha1(&[b'a'; 56]).iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!("c2db330f6083854c99d4b5bfb6e8f29f201be699", hex);
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[test]
+    fn frames_round_trip_masked_from_clients_only() {
+        for frame in [
+            Frame::Text("{}".into()),
+            Frame::Text("x".repeat(300)),
+            Frame::Ping(vec![1, 2, 3]),
+            Frame::Pong(vec![]),
+            Frame::Close(GOING_AWAY),
+        ] {
+            let mut server = Vec::new();
+            write_frame(&mut server, &frame, None).unwrap();
+            assert_eq!(Some((frame.clone(), server.len())), decode(&server, false).unwrap());
+            let mut client = Vec::new();
+            write_frame(&mut client, &frame, Some([1, 2, 3, 4])).unwrap();
+            assert_eq!(Some((frame.clone(), client.len())), decode(&client, true).unwrap());
+            assert!(decode(&server, true).is_err());
+            assert!(decode(&client, false).is_err());
+            // Nothing until all of it is there, whatever follows is left.
+            for len in 0..client.len() {
+                assert_eq!(None, decode(&client[..len], true).unwrap());
+            }
+            client.extend_from_slice(&server);
+            assert_eq!(frame, decode(&client, true).unwrap().unwrap().0);
+        }
+        assert_eq!(Some((Frame::Close(1005), 2)), decode(&[0x88, 0], false).unwrap());
+        // A 64 bit length, past what a client may send.
+        let mut long = Vec::new();
+        write_frame(&mut long, &Frame::Text("y".repeat(70_000)), Some([1, 2, 3, 4])).unwrap();
+        assert_eq!([0x81, 0x80 | 127], long[..2]);
+        assert_eq!(70_000u64.to_be_bytes(), long[2..10]);
+        assert!(decode(&long[..10], true).is_err());
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(
+            "{\"type\":\"status\",\"state\":\"On\",\"shadow\":false,\"temperature\":6.500,\"relay\":true,\
+             \"fan\":false,\"thresholds\":[4.100,7.900],\"band\":[4.000,8.000],\"runtime_hours\":12.50,\
+             \"starts_today\":7,\"cycles\":3,\"sensor_errors\":2,\"relay_deferrals\":1}",
+            status_message(&snapshot())
+        );
+        let transition = Transition {
+            from: State::Off,
+            to: State::On,
+            reason: TransitionReason::Threshold,
+            temperature: None,
+        };
+        assert_eq!(
+            "{\"type\":\"transition\",\"from\":\"Off\",\"to\":\"On\",\"reason\":\"threshold\",\"temperature\":null}",
+            transition_message(&transition)
+        );
+        let fault = event_message(&Event::LoopStalled {
+            stalled_for: Duration::from_secs(60),
+        });
+        assert!(fault.starts_with("{\"type\":\"fault\",\"message\":\""), "{}", fault);
+        let alarm = event_message(&Event::DutyCycleHigh {
+            duty: 0.9,
+            window: Duration::from_secs(3600),
+        });
+        assert!(alarm.starts_with("{\"type\":\"alarm\","), "{}", alarm);
+        let event = event_message(&Event::PumpRecovered);
+        assert!(event.starts_with("{\"type\":\"event\","), "{}", event);
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_newest_and_says_how_many() {
+        let client = Client::default();
+        for i in 0..QUEUE + 3 {
+            client.send(i.to_string().into());
+        }
+        let mut sent = Vec::new();
+        while let Some(Frame::Text(message)) = client.next(false) {
+            sent.push(message);
+        }
+        assert_eq!(dropped_message(3), sent[0]);
+        assert_eq!(QUEUE + 1, sent.len());
+        assert_eq!((QUEUE - 1).to_string(), sent[QUEUE]);
+        // Nothing left, a ping when one is due.
+        assert_eq!(None, client.next(false));
+        assert_eq!(Some(Frame::Ping(vec![])), client.next(true));
+        client.send("last".to_string().into());
+        client.close(GOING_AWAY, true);
+        client.send("after the close".to_string().into());
+        assert_eq!(Some(Frame::Text("last".into())), client.next(true));
+        assert_eq!(Some(Frame::Close(GOING_AWAY)), client.next(true));
+        client.finished();
+        assert_eq!(None, client.next(true));
+    }
+
+    #[test]
+    fn refuses_anything_but_an_upgrade_of_ws() {
+        let mut runtime = Runtime::default();
+        let server = WsServer::bind("127.0.0.1:0", &mut runtime).unwrap();
+        for (request, status) in [
+            ("GET /status HTTP/1.1\r\n\r\n", "404"),
+            ("GET /ws HTTP/1.1\r\n\r\n", "400"),
+            (
+                "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: a2V5\r\nSec-WebSocket-Version: 8\r\n\r\n",
+                "426",
+            ),
+        ] {
+            let mut connection = TcpStream::connect(server.local_addr()).unwrap();
+            connection.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            connection.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {} ", status)), "{}", response);
+        }
+        assert!(server.stream().lock().connected.is_empty());
+    }
+
+    #[test]
+    fn pings_answers_pings_and_disconnects_a_quiet_client() {
+        let timeouts = Timeouts {
+            ping: Duration::from_millis(50),
+            idle: Duration::from_millis(300),
+            ..Timeouts::default()
+        };
+        let mut runtime = Runtime::default();
+        let server = WsServer::bind_with("127.0.0.1:0", timeouts, &mut runtime).unwrap();
+        let mut client = WsClient::connect(server.local_addr());
+        assert_eq!(Frame::Ping(vec![]), client.read());
+        client.send(&Frame::Pong(vec![]));
+        client.send(&Frame::Ping(vec![7]));
+        let mut frames = Vec::new();
+        while !frames.contains(&Frame::Pong(vec![7])) {
+            frames.push(client.read());
+        }
+        // Only pongs are sent from here, the server hears nothing more and hangs up.
+        let started = Instant::now();
+        loop {
+            match client.try_read() {
+                Ok(Frame::Ping(_)) => {}
+                Ok(frame) => panic!("unexpected {:?}", frame),
+                Err(_) => break,
+            }
+        }
+        assert!(started.elapsed() < Duration::from_secs(3));
+        server.stream().status(&snapshot());
+        assert!(server.stream().lock().connected.is_empty());
+    }
+
+    #[test]
+    fn disconnects_a_client_that_stops_reading() {
+        let timeouts = Timeouts {
+            write: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let mut runtime = Runtime::default();
+        let server = WsServer::bind_with("127.0.0.1:0", timeouts, &mut runtime).unwrap();
+        let _stuck = WsClient::connect(server.local_addr());
+        let stream = server.stream();
+        let started = Instant::now();
+        // Until the socket's buffers fill up and a write times out.
+        while !stream.lock().connected.is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            stream.status(&snapshot());
+        }
+    }
+
+    #[test]
+    fn closes_clients_on_stop_after_what_is_queued() {
+        let mut runtime = Runtime::default();
+        let server = WsServer::bind("127.0.0.1:0", &mut runtime).unwrap();
+        let (stream, address) = (server.stream(), server.local_addr());
+        let mut first = WsClient::connect(server.local_addr());
+        let mut second = WsClient::connect(server.local_addr());
+        stream.status(&snapshot());
+        let stopping = std::thread::spawn(move || server.stop());
+        for client in [&mut first, &mut second] {
+            assert_eq!(
+                (vec![status_message(&snapshot())], GOING_AWAY),
+                client.messages_until_close()
+            );
+        }
+        stopping.join().unwrap();
+        assert!(stream.lock().closed);
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
@@ -0,0 +1,556 @@
+use crate::{shutdown::Shutdown, World, WorldState};
+use anyhow::Result;
+use picool_core::{
+    boost::Boost,
+    controller::CycleRecord,
+    energy::{EnergyTotals, LocalTime},
+    failover::Output,
+    heartbeat::HeartbeatStatus,
+    overshoot::Overshoots,
+    rest::RestPeriod,
+    shed::ShedPeriod,
+    starts::StartCounts,
+    temperature::{Celsius, DegreesDelta},
+    world_error::{PersistError, SensorError},
+    CycleExtremes, MinimumIntervals, RestoredPowerState,
+};
+use std::time::{Duration, Instant, SystemTime};
+
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+pub trait Sensor {
+    fn get_temperature(&self) -> Result<Celsius, SensorError>;
+    /// None without an ambient sensor.
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>>;
+    /// Amps the compressor draws, None without a current sensor.
+    fn get_current(&self) -> Option<Result<f32, SensorError>>;
+    fn reinitialize(&mut self) -> Result<(), SensorError>;
+    /// Looks for a sensor that went missing, the new sensor's name if a different one took its place.
+    fn reacquire(&mut self) -> Option<String>;
+}
+
+pub trait Switch {
+    fn set_power_state(&mut self, state: bool);
+    /// The circulation fan output, nothing happens without one.
+    fn set_fan_state(&mut self, state: bool);
+    /// The second compressor, nothing happens without one.
+    fn set_stage2_state(&mut self, state: bool);
+    /// The glycol pump, nothing happens without one.
+    fn set_pump_state(&mut self, state: bool);
+    /// Read back from the pump output, None without one.
+    fn pump_state(&self) -> Option<bool>;
+    /// Which relay switches the compressor from now on, nothing happens without a backup.
+    fn select_output(&mut self, output: Output);
+    /// Read back from the output, a previous run may have left it on.
+    fn is_on(&self) -> bool;
+}
+
+pub trait Clock {
+    fn sleep(&self, duration: Duration);
+    fn now(&self) -> Instant;
+    fn local_time(&self) -> LocalTime;
+}
+
+pub trait Store {
+    /// Only consulted while the switch is off.
+    fn restore_power_state(&self) -> Result<RestoredPowerState>;
+    /// (cooling, heating), a store that can't restore them starts from zero.
+    fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta);
+    fn restore_cooling_rates(&self) -> Vec<f32>;
+    fn restore_energy(&self) -> EnergyTotals;
+    fn restore_runtime(&self) -> Duration;
+    fn restore_starts(&self) -> StartCounts;
+    fn restore_boost(&self) -> Option<Boost>;
+    fn restore_intervals(&self) -> Option<MinimumIntervals>;
+    fn restore_overshoots(&self) -> Overshoots;
+    /// With what was left of it when persisted less the time since, None once that ran out.
+    fn restore_rest(&self) -> Option<RestPeriod>;
+    /// With how long it had been shedding and what was left of it, both moved on by the time since. A shed that ran
+    /// out meanwhile is restored spent.
+    fn restore_shed(&self) -> Option<ShedPeriod>;
+    /// With how long the cycle has been going and how old the checkpoint is, as of now.
+    fn restore_extremes(&self) -> Option<CycleExtremes>;
+    /// Whether a previous run left the primary output faulted.
+    fn restore_failover(&self) -> bool;
+
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError>;
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError>;
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError>;
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError>;
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError>;
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError>;
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError>;
+    /// None once the boost is over.
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError>;
+    /// None when the configured intervals are back in effect.
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError>;
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError>;
+    /// None once the rest is over.
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError>;
+    /// None once the signal is released.
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError>;
+    /// None once the cycle ends.
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError>;
+    /// Appended to the cycle history, stamped with the time it ended.
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError>;
+    /// False once the primary is restored or reset.
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError>;
+    /// What was learned from the sensor's readings is kept under `name` from now on.
+    fn switch_sensor(&mut self, name: &str);
+}
+
+/// A World assembled from independent backends.
+pub struct CompositeWorld<S, P, C, St> {
+    pub sensor: S,
+    pub switch: P,
+    pub clock: C,
+    pub store: St,
+}
+
+impl<S: Sensor, P: Switch, C: Clock, St: Store> World for CompositeWorld<S, P, C, St> {
+    fn get_temperature(&self) -> Result<Celsius, SensorError> {
+        self.sensor.get_temperature()
+    }
+
+    fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+        self.sensor.get_ambient_temperature()
+    }
+
+    fn get_current(&self) -> Option<Result<f32, SensorError>> {
+        self.sensor.get_current()
+    }
+
+    fn set_power_state(&mut self, state: bool) {
+        self.switch.set_power_state(state)
+    }
+
+    fn set_fan_state(&mut self, state: bool) {
+        self.switch.set_fan_state(state)
+    }
+
+    fn set_stage2_state(&mut self, state: bool) {
+        self.switch.set_stage2_state(state)
+    }
+
+    fn set_pump_state(&mut self, state: bool) {
+        self.switch.set_pump_state(state)
+    }
+
+    fn pump_state(&self) -> Option<bool> {
+        self.switch.pump_state()
+    }
+
+    fn select_output(&mut self, output: Output) {
+        self.switch.select_output(output)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration)
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn local_time(&self) -> LocalTime {
+        self.clock.local_time()
+    }
+
+    fn reinitialize(&mut self) -> Result<(), SensorError> {
+        self.sensor.reinitialize()
+    }
+
+    fn reacquire_sensor(&mut self) -> bool {
+        match self.sensor.reacquire() {
+            Some(name) => {
+                self.store.switch_sensor(&name);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write_heartbeat(&mut self, status: HeartbeatStatus) -> Result<(), PersistError> {
+        self.store.write_heartbeat(status)
+    }
+
+    fn restore_state(&self) -> Result<WorldState> {
+        let power_state = match self.switch.is_on() {
+            true => RestoredPowerState::CurrentlyOn,
+            false => self.store.restore_power_state()?,
+        };
+        let (cooling_compensation, heating_compensation) = self.store.restore_compensation();
+        Ok(WorldState {
+            power_state,
+            heating_compensation,
+            cooling_compensation,
+            cooling_rates: self.store.restore_cooling_rates(),
+            energy: self.store.restore_energy(),
+            runtime: self.store.restore_runtime(),
+            starts: self.store.restore_starts(),
+            boost: self.store.restore_boost(),
+            intervals: self.store.restore_intervals(),
+            overshoots: self.store.restore_overshoots(),
+            rest: self.store.restore_rest(),
+            shed: self.store.restore_shed(),
+            extremes: self.store.restore_extremes(),
+            failover: self.store.restore_failover(),
+        })
+    }
+
+    fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+        self.store.persist_last_off_transition()
+    }
+
+    fn persist_compensation(&mut self, cooling: DegreesDelta, heating: DegreesDelta) -> Result<(), PersistError> {
+        self.store.persist_compensation(cooling, heating)
+    }
+
+    fn persist_cooling_rates(&mut self, rates: &[f32]) -> Result<(), PersistError> {
+        self.store.persist_cooling_rates(rates)
+    }
+
+    fn persist_energy(&mut self, totals: EnergyTotals) -> Result<(), PersistError> {
+        self.store.persist_energy(totals)
+    }
+
+    fn persist_runtime(&mut self, runtime: Duration) -> Result<(), PersistError> {
+        self.store.persist_runtime(runtime)
+    }
+
+    fn persist_starts(&mut self, counts: &StartCounts) -> Result<(), PersistError> {
+        self.store.persist_starts(counts)
+    }
+
+    fn persist_boost(&mut self, boost: Option<&Boost>) -> Result<(), PersistError> {
+        self.store.persist_boost(boost)
+    }
+
+    fn persist_intervals(&mut self, intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+        self.store.persist_intervals(intervals)
+    }
+
+    fn persist_overshoots(&mut self, overshoots: &Overshoots) -> Result<(), PersistError> {
+        self.store.persist_overshoots(overshoots)
+    }
+
+    fn persist_rest(&mut self, rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+        self.store.persist_rest(rest)
+    }
+
+    fn persist_shed(&mut self, shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+        self.store.persist_shed(shed)
+    }
+
+    fn persist_extremes(&mut self, extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+        self.store.persist_extremes(extremes)
+    }
+
+    fn record_cycle(&mut self, cycle: &CycleRecord) -> Result<(), PersistError> {
+        self.store.record_cycle(cycle)
+    }
+
+    fn persist_failover(&mut self, faulted: bool) -> Result<(), PersistError> {
+        self.store.persist_failover(faulted)
+    }
+}
+
+/// The wall clock, sleeping the thread in slices so a shutdown request cuts a sleep short.
+pub struct SystemClock {
+    shutdown: Shutdown,
+}
+
+impl SystemClock {
+    pub fn new(shutdown: Shutdown) -> Self {
+        Self { shutdown }
+    }
+}
+
+impl Clock for SystemClock {
+    fn sleep(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while !self.shutdown.is_requested() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            std::thread::sleep(remaining.min(SHUTDOWN_CHECK_INTERVAL));
+        }
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_time(&self) -> LocalTime {
+        local_now()
+    }
+}
+
+/// The system's wall clock in the local zone.
+pub fn local_now() -> LocalTime {
+    let seconds = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Now is never before the epoch.")
+        .as_secs() as libc::time_t;
+    // Safe: localtime_r only writes the tm it is given.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let offset = match unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        true => 0,
+        false => tm.tm_gmtoff,
+    };
+    LocalTime::from_local_seconds(seconds as i64 + offset as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::{cell::Cell, io};
+
+    struct FixedSensor(Celsius);
+
+    impl Sensor for FixedSensor {
+        fn get_temperature(&self) -> Result<Celsius, SensorError> {
+            Ok(self.0)
+        }
+
+        fn get_ambient_temperature(&self) -> Option<Result<Celsius, SensorError>> {
+            None
+        }
+
+        fn get_current(&self) -> Option<Result<f32, SensorError>> {
+            None
+        }
+
+        fn reinitialize(&mut self) -> Result<(), SensorError> {
+            Ok(())
+        }
+
+        fn reacquire(&mut self) -> Option<String> {
+            None
+        }
+    }
+
+    struct LatchedSwitch(bool);
+
+    impl Switch for LatchedSwitch {
+        fn set_power_state(&mut self, state: bool) {
+            self.0 = state;
+        }
+
+        fn set_fan_state(&mut self, _state: bool) {}
+
+        fn set_stage2_state(&mut self, _state: bool) {}
+
+        fn set_pump_state(&mut self, _state: bool) {}
+
+        fn pump_state(&self) -> Option<bool> {
+            None
+        }
+
+        fn select_output(&mut self, _output: Output) {}
+
+        fn is_on(&self) -> bool {
+            self.0
+        }
+    }
+
+    struct ManualClock(Cell<Instant>);
+
+    impl Clock for ManualClock {
+        fn sleep(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+
+        fn local_time(&self) -> LocalTime {
+            LocalTime::default()
+        }
+    }
+
+    /// Fails every persist, restores whatever it was built with.
+    struct BrokenStore {
+        power_state: Option<RestoredPowerState>,
+        power_state_reads: Cell<u32>,
+    }
+
+    impl Store for BrokenStore {
+        fn restore_power_state(&self) -> Result<RestoredPowerState> {
+            self.power_state_reads.set(self.power_state_reads.get() + 1);
+            self.power_state.ok_or_else(|| anyhow!("Unreadable."))
+        }
+
+        fn restore_compensation(&self) -> (DegreesDelta, DegreesDelta) {
+            (DegreesDelta(0.5), DegreesDelta(0.25))
+        }
+
+        fn restore_cooling_rates(&self) -> Vec<f32> {
+            vec![1.0]
+        }
+
+        fn restore_energy(&self) -> EnergyTotals {
+            EnergyTotals::default()
+        }
+
+        fn restore_runtime(&self) -> Duration {
+            Duration::from_secs(3600)
+        }
+
+        fn restore_starts(&self) -> StartCounts {
+            StartCounts::default()
+        }
+
+        fn restore_boost(&self) -> Option<Boost> {
+            None
+        }
+
+        fn restore_intervals(&self) -> Option<MinimumIntervals> {
+            None
+        }
+
+        fn restore_overshoots(&self) -> Overshoots {
+            Overshoots::default()
+        }
+
+        fn restore_rest(&self) -> Option<RestPeriod> {
+            None
+        }
+
+        fn restore_shed(&self) -> Option<ShedPeriod> {
+            None
+        }
+
+        fn restore_extremes(&self) -> Option<CycleExtremes> {
+            None
+        }
+
+        fn restore_failover(&self) -> bool {
+            false
+        }
+
+        fn write_heartbeat(&mut self, _status: HeartbeatStatus) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_last_off_transition(&mut self) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_compensation(&mut self, _cooling: DegreesDelta, _heating: DegreesDelta) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_cooling_rates(&mut self, _rates: &[f32]) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_energy(&mut self, _totals: EnergyTotals) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_runtime(&mut self, _runtime: Duration) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_starts(&mut self, _counts: &StartCounts) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_boost(&mut self, _boost: Option<&Boost>) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_intervals(&mut self, _intervals: Option<MinimumIntervals>) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_overshoots(&mut self, _overshoots: &Overshoots) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_rest(&mut self, _rest: Option<&RestPeriod>) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_shed(&mut self, _shed: Option<&ShedPeriod>) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_extremes(&mut self, _extremes: Option<&CycleExtremes>) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn record_cycle(&mut self, _cycle: &CycleRecord) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn persist_failover(&mut self, _faulted: bool) -> Result<(), PersistError> {
+            Err(io::Error::from_raw_os_error(libc::EROFS).into())
+        }
+
+        fn switch_sensor(&mut self, _name: &str) {}
+    }
+
+    fn world(
+        on: bool,
+        power_state: Option<RestoredPowerState>,
+    ) -> CompositeWorld<FixedSensor, LatchedSwitch, ManualClock, BrokenStore> {
+        CompositeWorld {
+            sensor: FixedSensor(Celsius(3.0)),
+            switch: LatchedSwitch(on),
+            clock: ManualClock(Cell::new(Instant::now())),
+            store: BrokenStore {
+                power_state,
+                power_state_reads: Cell::new(0),
+            },
+        }
+    }
+
+    #[test]
+    fn delegates_to_each_backend() {
+        let mut world = world(false, None);
+        let start = world.now();
+        world.sleep(Duration::from_secs(10));
+        assert_eq!(Duration::from_secs(10), world.now() - start);
+        assert_eq!(Celsius(3.0), world.get_temperature().unwrap());
+        world.set_power_state(true);
+        assert!(world.switch.is_on());
+        assert!(world.persist_last_off_transition().is_err());
+        assert!(world.write_heartbeat(HeartbeatStatus::Controlling).is_err());
+    }
+
+    #[test]
+    fn switch_left_on_takes_precedence_over_store() {
+        let world = world(true, None);
+        let restored = world.restore_state().unwrap();
+        assert!(restored.power_state == RestoredPowerState::CurrentlyOn);
+        assert_eq!(0, world.store.power_state_reads.get());
+        assert_eq!(
+            (DegreesDelta(0.5), DegreesDelta(0.25)),
+            (restored.cooling_compensation, restored.heating_compensation)
+        );
+        assert_eq!(vec![1.0], restored.cooling_rates);
+        assert_eq!(Duration::from_secs(3600), restored.runtime);
+    }
+
+    #[test]
+    fn store_restores_power_state_while_off() {
+        let world = world(false, Some(RestoredPowerState::OffFor(Duration::from_secs(60))));
+        assert!(world.restore_state().unwrap().power_state == RestoredPowerState::OffFor(Duration::from_secs(60)));
+        assert!(self::world(false, None).restore_state().is_err());
+    }
+
+    #[test]
+    fn system_clock_sleep_cut_short_by_shutdown() {
+        let shutdown = Shutdown::default();
+        let clock = SystemClock::new(shutdown.clone());
+        shutdown.request();
+        let start = Instant::now();
+        clock.sleep(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
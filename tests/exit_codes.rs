@@ -0,0 +1,88 @@
+//! The exit codes a supervisor acts on, from the built binary.
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+fn picool(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_picool"))
+        .args(args)
+        .output()
+        .expect("Failed running picool.")
+}
+
+#[test]
+fn a_bad_option_is_a_configuration_error() {
+    for (pin, message) in [
+        ("abc", "Option --power-pin expects a GPIO pin number, got 'abc'.\n"),
+        (
+            "99",
+            "Option --power-pin has GPIO 99, the Pi's header only has BCM 0 to 27.\n",
+        ),
+    ] {
+        let output = picool(&[
+            "--sensor-path",
+            "/sys/bus/w1/devices/28-00/temperature",
+            "--power-pin",
+            pin,
+        ]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(Some(2), output.status.code(), "{}", stderr);
+        assert!(stderr.contains(message), "{}", stderr);
+        assert!(stderr.contains("Usage:"), "{}", stderr);
+    }
+}
+
+#[test]
+fn a_second_instance_on_the_same_state_directory_exits_with_the_lock_held() {
+    let dir = std::env::temp_dir().join(format!("picool-exit-codes-{}", std::process::id()));
+    let state = dir.join("state");
+    fs::create_dir_all(&state).unwrap();
+    fs::write(dir.join("temperature"), "4.5\n").unwrap();
+    let switch = dir.join("switch.sh");
+    fs::write(&switch, "#!/bin/sh\nexit 0\n").unwrap();
+    fs::set_permissions(&switch, fs::Permissions::from_mode(0o755)).unwrap();
+    let sensor = dir.join("temperature");
+    let switch = format!("exec:{}", switch.display());
+    let args = [
+        "--sensor-path",
+        sensor.to_str().unwrap(),
+        "--power-pin",
+        "17",
+        "--switch",
+        &switch,
+        "--state-dir",
+        state.to_str().unwrap(),
+    ];
+    let mut first = Command::new(env!("CARGO_BIN_EXE_picool"))
+        .args(args)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let held = state.join("pin_17.lock");
+    let started = Instant::now();
+    while fs::read_to_string(&held).ok() != Some(first.id().to_string()) {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "The first instance never took its lock."
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let second = picool(&args);
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert_eq!(Some(6), second.status.code(), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("picool is already running (pid {})", first.id())),
+        "{}",
+        stderr
+    );
+
+    unsafe { libc::kill(first.id() as libc::pid_t, libc::SIGTERM) };
+    assert_eq!(Some(0), first.wait().unwrap().code());
+    let _ = fs::remove_dir_all(&dir);
+}